@@ -0,0 +1,58 @@
+// Copyright (C) 2020 Alibaba Cloud Computing. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Measure the cost of sending vhost-user messages, with a SET_MEM_TABLE request carrying the
+//! largest number of memory regions.
+//!
+//! Run with `cargo bench --features vhost-user-master`.
+
+extern crate vhost;
+extern crate vmm_sys_util;
+
+use std::io::Read;
+use std::os::unix::io::AsRawFd;
+use std::os::unix::net::UnixStream;
+use std::thread;
+use std::time::Instant;
+
+use vhost::vhost_user::message::MAX_ATTACHED_FD_ENTRIES;
+use vhost::vhost_user::Master;
+use vhost::{VhostMemory, VhostUserMemoryRegionInfo};
+use vmm_sys_util::tempfile::TempFile;
+
+const ITERATIONS: u32 = 100_000;
+
+fn main() {
+    let (master, mut slave) = UnixStream::pair().unwrap();
+    let mut master = Master::from_stream(master, 1);
+    // Drain the requests, the attached file descriptors being closed by the kernel.
+    let drain = thread::spawn(move || {
+        let mut buf = [0u8; 0x10000];
+        while slave.read(&mut buf).unwrap() > 0 {}
+    });
+
+    let file = TempFile::new().unwrap().into_file();
+    let regions: Vec<VhostUserMemoryRegionInfo> = (0..MAX_ATTACHED_FD_ENTRIES as u64)
+        .map(|i| VhostUserMemoryRegionInfo {
+            guest_phys_addr: i << 30,
+            memory_size: 1 << 30,
+            userspace_addr: 0x7f00_0000_0000 + (i << 30),
+            mmap_offset: 0,
+            mmap_handle: file.as_raw_fd(),
+        })
+        .collect();
+
+    let start = Instant::now();
+    for _ in 0..ITERATIONS {
+        master.set_mem_table(&regions).unwrap();
+    }
+    let elapsed = start.elapsed();
+    println!(
+        "set_mem_table with {} regions: {:?} per request",
+        regions.len(),
+        elapsed / ITERATIONS
+    );
+
+    drop(master);
+    drain.join().unwrap();
+}
@@ -0,0 +1,136 @@
+// Copyright (C) 2020 Alibaba Cloud Computing. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! vhost-user-blk slave serving a raw disk image.
+//!
+//! Usage: vhost_user_block --socket <path> --image <path> [--queues <num>] [--readonly]
+//! [--serial <serial>] [--io-uring]
+//!
+//! The slave listens on the socket for a connection from the master, and exits once the master
+//! has gone. With `--io-uring`, reads and writes are submitted through io_uring when the kernel
+//! supports it, which requires the `vhost-user-io-uring` feature.
+
+extern crate vhost;
+
+use std::fs::OpenOptions;
+use std::process;
+use std::sync::{Arc, RwLock};
+
+use vhost::vhost_user::block_backend::{BlockBackend, RawFile};
+use vhost::vhost_user::{Daemon, Listener};
+
+struct Config {
+    socket: String,
+    image: String,
+    num_queues: usize,
+    read_only: bool,
+    serial: Option<String>,
+    io_uring: bool,
+}
+
+fn parse_args() -> Result<Config, String> {
+    let mut config = Config {
+        socket: String::new(),
+        image: String::new(),
+        num_queues: 1,
+        read_only: false,
+        serial: None,
+        io_uring: false,
+    };
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        if arg == "--readonly" {
+            config.read_only = true;
+            continue;
+        }
+        if arg == "--io-uring" {
+            config.io_uring = true;
+            continue;
+        }
+        let value = args
+            .next()
+            .ok_or_else(|| format!("missing value for {}", arg))?;
+        match arg.as_str() {
+            "--socket" => config.socket = value,
+            "--image" => config.image = value,
+            "--queues" => {
+                config.num_queues = value
+                    .parse()
+                    .map_err(|_| format!("invalid number of queues {}", value))?
+            }
+            "--serial" => config.serial = Some(value),
+            _ => return Err(format!("unknown option {}", arg)),
+        }
+    }
+    if config.socket.is_empty() || config.image.is_empty() {
+        return Err("--socket and --image are mandatory".to_string());
+    }
+    Ok(config)
+}
+
+fn run(config: Config) -> Result<(), String> {
+    let file = OpenOptions::new()
+        .read(true)
+        .write(!config.read_only)
+        .open(&config.image)
+        .map_err(|e| format!("failed to open image {}: {}", config.image, e))?;
+    let storage = RawFile::new(file).map_err(|e| format!("failed to open image: {}", e))?;
+    let mut backend = BlockBackend::new(storage, config.num_queues, config.read_only)
+        .map_err(|e| format!("failed to create backend: {}", e))?;
+    if let Some(ref serial) = config.serial {
+        backend.set_serial(serial);
+    }
+    let backend = Arc::new(RwLock::new(backend));
+
+    let mut daemon = Daemon::new("vhost-user-block".to_string(), backend.clone())
+        .map_err(|e| format!("failed to create daemon: {}", e))?;
+    if config.io_uring {
+        register_io_uring(&daemon, &backend)?;
+    }
+    let listener = Listener::new(&config.socket, true)
+        .map_err(|e| format!("failed to listen on {}: {}", config.socket, e))?;
+    daemon.start(listener).map_err(|e| e.to_string())?;
+    daemon.wait().map_err(|e| e.to_string())
+}
+
+#[cfg(feature = "vhost-user-io-uring")]
+fn register_io_uring(
+    daemon: &Daemon<BlockBackend<RawFile>>,
+    backend: &RwLock<BlockBackend<RawFile>>,
+) -> Result<(), String> {
+    let enabled = backend
+        .write()
+        .unwrap()
+        .register_io_uring(&daemon.get_vring_workers())
+        .map_err(|e| format!("failed to set up io_uring: {}", e))?;
+    if !enabled {
+        eprintln!("vhost_user_block: io_uring is unavailable, serving requests synchronously");
+    }
+    Ok(())
+}
+
+#[cfg(not(feature = "vhost-user-io-uring"))]
+fn register_io_uring(
+    _daemon: &Daemon<BlockBackend<RawFile>>,
+    _backend: &RwLock<BlockBackend<RawFile>>,
+) -> Result<(), String> {
+    Err("io_uring support requires the vhost-user-io-uring feature".to_string())
+}
+
+fn main() {
+    let config = match parse_args() {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("{}", e);
+            eprintln!(
+                "usage: vhost_user_block --socket <path> --image <path> [--queues <num>] \
+                 [--readonly] [--serial <serial>] [--io-uring]"
+            );
+            process::exit(1);
+        }
+    };
+    if let Err(e) = run(config) {
+        eprintln!("vhost_user_block: {}", e);
+        process::exit(1);
+    }
+}
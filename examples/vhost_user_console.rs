@@ -0,0 +1,73 @@
+// Copyright (C) 2020 Alibaba Cloud Computing. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! vhost-user-console slave connected to a new PTY.
+//!
+//! Usage: vhost_user_console --socket <path>
+//!
+//! The slave prints the path of the PTY terminals can be attached to, listens on the socket for
+//! a connection from the master, and exits once the master has gone.
+
+extern crate vhost;
+
+use std::process;
+use std::sync::{Arc, RwLock};
+
+use vhost::vhost_user::console_backend::{ConsoleBackend, ConsolePort};
+use vhost::vhost_user::{Daemon, Listener};
+
+// Size of the console until it is resized.
+const COLS: u16 = 80;
+const ROWS: u16 = 25;
+
+fn parse_args() -> Result<String, String> {
+    let mut socket = String::new();
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        let value = args
+            .next()
+            .ok_or_else(|| format!("missing value for {}", arg))?;
+        match arg.as_str() {
+            "--socket" => socket = value,
+            _ => return Err(format!("unknown option {}", arg)),
+        }
+    }
+    if socket.is_empty() {
+        return Err("--socket is mandatory".to_string());
+    }
+    Ok(socket)
+}
+
+fn run(socket: String) -> Result<(), String> {
+    let (port, path) = ConsolePort::open_pty().map_err(|e| format!("failed to open PTY: {}", e))?;
+    println!("console available on {}", path);
+    let backend = Arc::new(RwLock::new(ConsoleBackend::new(port, COLS, ROWS)));
+
+    let mut daemon = Daemon::new("vhost-user-console".to_string(), backend.clone())
+        .map_err(|e| format!("failed to create daemon: {}", e))?;
+    backend
+        .read()
+        .unwrap()
+        .register_port(&daemon.get_vring_workers())
+        .map_err(|e| format!("failed to register PTY: {}", e))?;
+
+    let listener = Listener::new(&socket, true)
+        .map_err(|e| format!("failed to listen on {}: {}", socket, e))?;
+    daemon.start(listener).map_err(|e| e.to_string())?;
+    daemon.wait().map_err(|e| e.to_string())
+}
+
+fn main() {
+    let socket = match parse_args() {
+        Ok(socket) => socket,
+        Err(e) => {
+            eprintln!("{}", e);
+            eprintln!("usage: vhost_user_console --socket <path>");
+            process::exit(1);
+        }
+    };
+    if let Err(e) = run(socket) {
+        eprintln!("vhost_user_console: {}", e);
+        process::exit(1);
+    }
+}
@@ -0,0 +1,85 @@
+// Copyright (C) 2020 Alibaba Cloud Computing. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! vhost-user-input slave passing an evdev device through.
+//!
+//! Usage: vhost_user_input --socket <path> --evdev <path> [--nograb]
+//!
+//! The slave listens on the socket for a connection from the master, and exits once the master
+//! has gone. The evdev device is grabbed unless --nograb is given, so its events only reach the
+//! guest.
+
+extern crate vhost;
+
+use std::process;
+use std::sync::{Arc, RwLock};
+
+use vhost::vhost_user::input_backend::{Evdev, InputBackend};
+use vhost::vhost_user::{Daemon, Listener};
+
+struct Config {
+    socket: String,
+    evdev: String,
+    grab: bool,
+}
+
+fn parse_args() -> Result<Config, String> {
+    let mut config = Config {
+        socket: String::new(),
+        evdev: String::new(),
+        grab: true,
+    };
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        if arg == "--nograb" {
+            config.grab = false;
+            continue;
+        }
+        let value = args
+            .next()
+            .ok_or_else(|| format!("missing value for {}", arg))?;
+        match arg.as_str() {
+            "--socket" => config.socket = value,
+            "--evdev" => config.evdev = value,
+            _ => return Err(format!("unknown option {}", arg)),
+        }
+    }
+    if config.socket.is_empty() || config.evdev.is_empty() {
+        return Err("--socket and --evdev are mandatory".to_string());
+    }
+    Ok(config)
+}
+
+fn run(config: Config) -> Result<(), String> {
+    let device = Evdev::open(&config.evdev, config.grab)
+        .map_err(|e| format!("failed to open evdev device {}: {}", config.evdev, e))?;
+    let backend = Arc::new(RwLock::new(InputBackend::new(device)));
+
+    let mut daemon = Daemon::new("vhost-user-input".to_string(), backend.clone())
+        .map_err(|e| format!("failed to create daemon: {}", e))?;
+    backend
+        .read()
+        .unwrap()
+        .register_device(&daemon.get_vring_workers())
+        .map_err(|e| format!("failed to register evdev device: {}", e))?;
+
+    let listener = Listener::new(&config.socket, true)
+        .map_err(|e| format!("failed to listen on {}: {}", config.socket, e))?;
+    daemon.start(listener).map_err(|e| e.to_string())?;
+    daemon.wait().map_err(|e| e.to_string())
+}
+
+fn main() {
+    let config = match parse_args() {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("{}", e);
+            eprintln!("usage: vhost_user_input --socket <path> --evdev <path> [--nograb]");
+            process::exit(1);
+        }
+    };
+    if let Err(e) = run(config) {
+        eprintln!("vhost_user_input: {}", e);
+        process::exit(1);
+    }
+}
@@ -0,0 +1,131 @@
+// Copyright (C) 2020 Alibaba Cloud Computing. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! vhost-user-net slave forwarding packets to a TAP interface.
+//!
+//! Usage: vhost_user_net --socket <path> --tap <name> [--queues <pairs>] [--mac <mac>]
+//! [--mtu <mtu>] [--busy-poll <usecs>]
+//!
+//! The slave listens on the socket for a connection from the master, and exits once the master
+//! has gone. With `--busy-poll`, kicked virtqueues are polled for new buffers for the given
+//! number of microseconds before waiting for the next kick.
+
+extern crate vhost;
+
+use std::process;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use vhost::vhost_user::net_backend::{NetBackend, NetTap};
+use vhost::vhost_user::{Daemon, Listener};
+
+struct Config {
+    socket: String,
+    tap: String,
+    queue_pairs: usize,
+    mac: [u8; 6],
+    mtu: u16,
+    busy_poll: Option<Duration>,
+}
+
+fn parse_mac(mac: &str) -> Option<[u8; 6]> {
+    let bytes: Vec<&str> = mac.split(':').collect();
+    if bytes.len() != 6 {
+        return None;
+    }
+    let mut addr = [0u8; 6];
+    for (i, byte) in bytes.iter().enumerate() {
+        addr[i] = u8::from_str_radix(byte, 16).ok()?;
+    }
+    Some(addr)
+}
+
+fn parse_args() -> Result<Config, String> {
+    let mut config = Config {
+        socket: String::new(),
+        tap: String::new(),
+        queue_pairs: 1,
+        mac: [0x52, 0x54, 0x00, 0x12, 0x34, 0x56],
+        mtu: 1500,
+        busy_poll: None,
+    };
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        let value = args
+            .next()
+            .ok_or_else(|| format!("missing value for {}", arg))?;
+        match arg.as_str() {
+            "--socket" => config.socket = value,
+            "--tap" => config.tap = value,
+            "--queues" => {
+                config.queue_pairs = value
+                    .parse()
+                    .map_err(|_| format!("invalid number of queue pairs {}", value))?
+            }
+            "--mac" => {
+                config.mac = parse_mac(&value).ok_or_else(|| format!("invalid MAC {}", value))?
+            }
+            "--mtu" => {
+                config.mtu = value
+                    .parse()
+                    .map_err(|_| format!("invalid MTU {}", value))?
+            }
+            "--busy-poll" => {
+                let usecs = value
+                    .parse()
+                    .map_err(|_| format!("invalid busy poll duration {}", value))?;
+                config.busy_poll = Some(Duration::from_micros(usecs));
+            }
+            _ => return Err(format!("unknown option {}", arg)),
+        }
+    }
+    if config.socket.is_empty() || config.tap.is_empty() {
+        return Err("--socket and --tap are mandatory".to_string());
+    }
+    Ok(config)
+}
+
+fn run(config: Config) -> Result<(), String> {
+    let multi_queue = config.queue_pairs > 1;
+    let mut taps = Vec::with_capacity(config.queue_pairs);
+    for _ in 0..config.queue_pairs {
+        let tap = NetTap::open(&config.tap, multi_queue)
+            .map_err(|e| format!("failed to open TAP interface {}: {}", config.tap, e))?;
+        taps.push(tap);
+    }
+    let mut backend = NetBackend::new(taps, config.mac, config.mtu)
+        .map_err(|e| format!("failed to create backend: {}", e))?;
+    backend.set_busy_poll_duration(config.busy_poll);
+    let backend = Arc::new(RwLock::new(backend));
+
+    let mut daemon = Daemon::new("vhost-user-net".to_string(), backend.clone())
+        .map_err(|e| format!("failed to create daemon: {}", e))?;
+    backend
+        .read()
+        .unwrap()
+        .register_taps(&daemon.get_vring_workers())
+        .map_err(|e| format!("failed to register TAP interface: {}", e))?;
+
+    let listener = Listener::new(&config.socket, true)
+        .map_err(|e| format!("failed to listen on {}: {}", config.socket, e))?;
+    daemon.start(listener).map_err(|e| e.to_string())?;
+    daemon.wait().map_err(|e| e.to_string())
+}
+
+fn main() {
+    let config = match parse_args() {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("{}", e);
+            eprintln!(
+                "usage: vhost_user_net --socket <path> --tap <name> [--queues <pairs>] \
+                 [--mac <mac>] [--mtu <mtu>] [--busy-poll <usecs>]"
+            );
+            process::exit(1);
+        }
+    };
+    if let Err(e) = run(config) {
+        eprintln!("vhost_user_net: {}", e);
+        process::exit(1);
+    }
+}
@@ -0,0 +1,77 @@
+// Copyright (C) 2020 Alibaba Cloud Computing. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! vhost-user-rng slave feeding the guest with the entropy of the host.
+//!
+//! Usage: vhost_user_rng --socket <path> [--source <path>]
+//!
+//! The slave listens on the socket for a connection from the master, and exits once the master
+//! has gone. The buffers of the guest are filled with bytes read from the source, /dev/urandom
+//! by default. QEMU connects to the slave with:
+//!
+//! ```text
+//! -chardev socket,id=rng0,path=<path> -device vhost-user-rng-pci,chardev=rng0
+//! ```
+
+extern crate vhost;
+
+use std::process;
+use std::sync::{Arc, RwLock};
+
+use vhost::vhost_user::rng_backend::RngBackend;
+use vhost::vhost_user::{Daemon, Listener};
+
+struct Config {
+    socket: String,
+    source: String,
+}
+
+fn parse_args() -> Result<Config, String> {
+    let mut config = Config {
+        socket: String::new(),
+        source: "/dev/urandom".to_string(),
+    };
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        let value = args
+            .next()
+            .ok_or_else(|| format!("missing value for {}", arg))?;
+        match arg.as_str() {
+            "--socket" => config.socket = value,
+            "--source" => config.source = value,
+            _ => return Err(format!("unknown option {}", arg)),
+        }
+    }
+    if config.socket.is_empty() {
+        return Err("--socket is mandatory".to_string());
+    }
+    Ok(config)
+}
+
+fn run(config: Config) -> Result<(), String> {
+    let backend = RngBackend::open(&config.source)
+        .map_err(|e| format!("failed to open {}: {}", config.source, e))?;
+    let backend = Arc::new(RwLock::new(backend));
+
+    let mut daemon = Daemon::new("vhost-user-rng".to_string(), backend)
+        .map_err(|e| format!("failed to create daemon: {}", e))?;
+    let listener = Listener::new(&config.socket, true)
+        .map_err(|e| format!("failed to listen on {}: {}", config.socket, e))?;
+    daemon.start(listener).map_err(|e| e.to_string())?;
+    daemon.wait().map_err(|e| e.to_string())
+}
+
+fn main() {
+    let config = match parse_args() {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("{}", e);
+            eprintln!("usage: vhost_user_rng --socket <path> [--source <path>]");
+            process::exit(1);
+        }
+    };
+    if let Err(e) = run(config) {
+        eprintln!("vhost_user_rng: {}", e);
+        process::exit(1);
+    }
+}
@@ -0,0 +1,88 @@
+// Copyright (C) 2020 Alibaba Cloud Computing. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! vhost-user-vsock slave connecting the guest to Unix sockets of the host.
+//!
+//! Usage: vhost_user_vsock --socket <path> --guest-cid <cid> --uds-path <path>
+//!
+//! Connections of the guest to the port P of the host are forwarded to `<uds-path>_P`, and host
+//! applications connect to the guest through `<uds-path>`. The slave listens on the socket for a
+//! connection from the master, and exits once the master has gone.
+
+extern crate vhost;
+
+use std::process;
+use std::sync::{Arc, RwLock};
+
+use vhost::vhost_user::vsock_backend::VsockBackend;
+use vhost::vhost_user::{Daemon, Listener};
+
+struct Config {
+    socket: String,
+    guest_cid: u64,
+    uds_path: String,
+}
+
+fn parse_args() -> Result<Config, String> {
+    let mut config = Config {
+        socket: String::new(),
+        guest_cid: 0,
+        uds_path: String::new(),
+    };
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        let value = args
+            .next()
+            .ok_or_else(|| format!("missing value for {}", arg))?;
+        match arg.as_str() {
+            "--socket" => config.socket = value,
+            "--guest-cid" => {
+                config.guest_cid = value
+                    .parse()
+                    .map_err(|_| format!("invalid guest CID {}", value))?
+            }
+            "--uds-path" => config.uds_path = value,
+            _ => return Err(format!("unknown option {}", arg)),
+        }
+    }
+    if config.socket.is_empty() || config.guest_cid == 0 || config.uds_path.is_empty() {
+        return Err("--socket, --guest-cid and --uds-path are mandatory".to_string());
+    }
+    Ok(config)
+}
+
+fn run(config: Config) -> Result<(), String> {
+    let backend = VsockBackend::new(config.guest_cid, &config.uds_path)
+        .map_err(|e| format!("failed to create backend: {}", e))?;
+    let backend = Arc::new(RwLock::new(backend));
+
+    let mut daemon = Daemon::new("vhost-user-vsock".to_string(), backend.clone())
+        .map_err(|e| format!("failed to create daemon: {}", e))?;
+    backend
+        .read()
+        .unwrap()
+        .register_sockets(&daemon.get_vring_workers())
+        .map_err(|e| format!("failed to register Unix sockets: {}", e))?;
+
+    let listener = Listener::new(&config.socket, true)
+        .map_err(|e| format!("failed to listen on {}: {}", config.socket, e))?;
+    daemon.start(listener).map_err(|e| e.to_string())?;
+    daemon.wait().map_err(|e| e.to_string())
+}
+
+fn main() {
+    let config = match parse_args() {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("{}", e);
+            eprintln!(
+                "usage: vhost_user_vsock --socket <path> --guest-cid <cid> --uds-path <path>"
+            );
+            process::exit(1);
+        }
+    };
+    if let Err(e) = run(config) {
+        eprintln!("vhost_user_vsock: {}", e);
+        process::exit(1);
+    }
+}
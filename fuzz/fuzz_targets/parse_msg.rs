@@ -0,0 +1,24 @@
+// Copyright (C) 2020 Alibaba Cloud Computing. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Feed arbitrary messages to the vhost-user message parser.
+//!
+//! The first byte of the input selects the direction of the message with its lowest bit, and the
+//! number of attached file descriptors with the next ones. The rest of the input is the message,
+//! the header followed by the body, as received from the socket.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use vhost::vhost_user::parser::{parse_raw_msg, parse_raw_slave_msg};
+
+fuzz_target!(|data: &[u8]| {
+    if let Some((&selector, msg)) = data.split_first() {
+        let num_fds = (selector >> 1) as usize;
+        if selector & 0x1 == 0 {
+            let _ = parse_raw_msg(msg, num_fds);
+        } else {
+            let _ = parse_raw_slave_msg(msg, num_fds);
+        }
+    }
+});
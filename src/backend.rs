@@ -9,14 +9,18 @@
 
 //! Common traits and structs for vhost-kern and vhost-user backend drivers.
 
-use super::Result;
+use super::features::{check_acked_features, DeviceFeatures, VirtioFeatures};
+use super::{Error, Result};
 use std::os::unix::io::RawFd;
-use vmm_sys_util::eventfd::EventFd;
+#[cfg(feature = "vm-memory")]
+use vm_memory::{GuestAddress, GuestMemory, GuestMemoryRegion};
+use vmm_sys_util::eventfd::{EventFd, EFD_NONBLOCK};
 
 /// Maximum number of memory regions supported.
 pub const VHOST_MAX_MEMORY_REGIONS: usize = 255;
 
 /// Vring/virtque configuration data.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
 pub struct VringConfigData {
     /// Maximum queue size supported by the driver.
     pub queue_max_size: u16,
@@ -46,16 +50,198 @@ impl VringConfigData {
 
     /// Get the log address, default to zero if not available.
     pub fn get_log_addr(&self) -> u64 {
-        if self.flags & 0x1 != 0 && self.log_addr.is_some() {
-            self.log_addr.unwrap()
-        } else {
-            0
+        match self.log_addr {
+            Some(addr) if self.flags & 0x1 != 0 => addr,
+            _ => 0,
+        }
+    }
+}
+
+/// Virtqueue set up by the guest driver, as tracked by the VMM, such as the `Queue` of the
+/// vm-virtio crates, to be handed over to a vhost backend with `VhostVring::setup_queue()`.
+#[cfg(feature = "vm-memory")]
+pub trait VirtioQueue {
+    /// Get the maximum size of the queue offered by the device.
+    fn max_size(&self) -> u16;
+
+    /// Get the size of the queue negotiated by the driver.
+    fn size(&self) -> u16;
+
+    /// Get the guest physical address of the descriptor table.
+    fn desc_table(&self) -> GuestAddress;
+
+    /// Get the guest physical address of the available ring.
+    fn avail_ring(&self) -> GuestAddress;
+
+    /// Get the guest physical address of the used ring.
+    fn used_ring(&self) -> GuestAddress;
+
+    /// Get the index of the next available descriptor to be processed.
+    fn next_avail(&self) -> u16;
+}
+
+/// Builder of the `VringConfigData` of a vring, from the guest physical addresses of its
+/// descriptor table, available and used rings.
+///
+/// `build()` checks the size and the alignment of the vring, translates its addresses into the
+/// virtual addresses of the VMM mapping the guest memory, and sets the log address to the guest
+/// physical address of the used ring when logging is enabled.
+#[cfg(feature = "vm-memory")]
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct VringConfigBuilder {
+    queue_max_size: u16,
+    queue_size: u16,
+    desc_table: GuestAddress,
+    avail_ring: GuestAddress,
+    used_ring: GuestAddress,
+    packed: bool,
+    log: bool,
+}
+
+#[cfg(feature = "vm-memory")]
+impl VringConfigBuilder {
+    /// Create a builder for a vring of at most `queue_max_size` descriptors, which is also its
+    /// size unless set with `queue_size()`.
+    pub fn new(queue_max_size: u16) -> Self {
+        VringConfigBuilder {
+            queue_max_size,
+            queue_size: queue_max_size,
+            ..Default::default()
         }
     }
+
+    /// Create a builder for the vring of the split virtqueue `queue`.
+    pub fn from_queue<Q: VirtioQueue + ?Sized>(queue: &Q) -> Self {
+        VringConfigBuilder::new(queue.max_size())
+            .queue_size(queue.size())
+            .desc_table(queue.desc_table())
+            .avail_ring(queue.avail_ring())
+            .used_ring(queue.used_ring())
+    }
+
+    /// Set the size of the vring negotiated by the driver.
+    pub fn queue_size(mut self, queue_size: u16) -> Self {
+        self.queue_size = queue_size;
+        self
+    }
+
+    /// Set the guest physical address of the descriptor table, or of the descriptor ring of a
+    /// packed ring.
+    pub fn desc_table(mut self, addr: GuestAddress) -> Self {
+        self.desc_table = addr;
+        self
+    }
+
+    /// Set the guest physical address of the available ring, or of the driver event suppression
+    /// area of a packed ring.
+    pub fn avail_ring(mut self, addr: GuestAddress) -> Self {
+        self.avail_ring = addr;
+        self
+    }
+
+    /// Set the guest physical address of the used ring, or of the device event suppression area
+    /// of a packed ring.
+    pub fn used_ring(mut self, addr: GuestAddress) -> Self {
+        self.used_ring = addr;
+        self
+    }
+
+    /// Set whether the vring is a packed ring, once VIRTIO_F_RING_PACKED has been negotiated.
+    pub fn packed(mut self, packed: bool) -> Self {
+        self.packed = packed;
+        self
+    }
+
+    /// Set whether the writes to the used ring are logged, during live migration.
+    pub fn log(mut self, log: bool) -> Self {
+        self.log = log;
+        self
+    }
+
+    /// Build the configuration of the vring laid out in `mem`.
+    pub fn build<M: GuestMemory>(&self, mem: &M) -> Result<VringConfigData> {
+        let size = u64::from(self.queue_size);
+        if size == 0 || self.queue_size > self.queue_max_size {
+            return Err(Error::InvalidQueue);
+        }
+        // Sizes and alignments of the descriptors, available and used areas.
+        let layout = if self.packed {
+            if size > 0x8000 {
+                return Err(Error::InvalidQueue);
+            }
+            [(16 * size, 16), (4, 4), (4, 4)]
+        } else {
+            if !self.queue_size.is_power_of_two() {
+                return Err(Error::InvalidQueue);
+            }
+            [(16 * size, 16), (6 + 2 * size, 2), (6 + 8 * size, 4)]
+        };
+        let desc_table_addr =
+            translate(mem, self.desc_table, layout[0]).ok_or(Error::DescriptorTableAddress)?;
+        let avail_ring_addr =
+            translate(mem, self.avail_ring, layout[1]).ok_or(Error::AvailAddress)?;
+        let used_ring_addr = translate(mem, self.used_ring, layout[2]).ok_or(Error::UsedAddress)?;
+
+        Ok(VringConfigData {
+            queue_max_size: self.queue_max_size,
+            queue_size: self.queue_size,
+            flags: self.log as u32,
+            desc_table_addr,
+            used_ring_addr,
+            avail_ring_addr,
+            log_addr: if self.log {
+                Some(self.used_ring.0)
+            } else {
+                None
+            },
+        })
+    }
+}
+
+// Translate the `size` bytes at `addr`, aligned on `align` bytes and contained in a single region
+// of `mem`, into the virtual address of their mapping.
+#[cfg(feature = "vm-memory")]
+fn translate<M: GuestMemory>(
+    mem: &M,
+    addr: GuestAddress,
+    (size, align): (u64, u64),
+) -> Option<u64> {
+    if addr.0 & (align - 1) != 0 {
+        return None;
+    }
+    let (region, region_addr) = mem.to_region_addr(addr)?;
+    region.checked_offset(region_addr, size as usize - 1)?;
+    region
+        .get_host_address(region_addr)
+        .ok()
+        .map(|host_addr| host_addr as u64)
+}
+
+/// Eventfds of a vring, to be set up with `VhostVring::set_vring_eventfds()`.
+#[derive(Debug)]
+pub struct VringEventFds {
+    /// Eventfd signaled by the guest when buffers are available.
+    pub kick: EventFd,
+    /// Eventfd signaled by the backend when buffers have been used.
+    pub call: EventFd,
+    /// Optional eventfd signaled by the backend on errors.
+    pub err: Option<EventFd>,
+}
+
+impl VringEventFds {
+    /// Create non-blocking eventfds for a vring, with an eventfd for errors if `err` is set.
+    pub fn new(err: bool) -> Result<Self> {
+        let eventfd = || EventFd::new(EFD_NONBLOCK).map_err(Error::IOError);
+        Ok(VringEventFds {
+            kick: eventfd()?,
+            call: eventfd()?,
+            err: if err { Some(eventfd()?) } else { None },
+        })
+    }
 }
 
 /// Memory region configuration data.
-#[derive(Default, Clone, Copy)]
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
 pub struct VhostUserMemoryRegionInfo {
     /// Guest physical address of the memory region.
     pub guest_phys_addr: u64,
@@ -69,17 +255,30 @@ pub struct VhostUserMemoryRegionInfo {
     pub mmap_handle: RawFd,
 }
 
-/// An interface for setting up vhost-based backend drivers.
-///
-/// Vhost devices are subset of virtio devices, which improve virtio device's performance by
-/// delegating data plane operations to dedicated IO service processes. Vhost devices use the
-/// same virtqueue layout as virtio devices to allow vhost devices to be mapped directly to
-/// virtio devices.
-/// The purpose of vhost is to implement a subset of a virtio device's functionality outside the
-/// VMM process. Typically fast paths for IO operations are delegated to the dedicated IO service
-/// processes, and slow path for device configuration are still handled by the VMM process. It may
-/// also be used to control access permissions of virtio backend devices.
-pub trait VhostBackend: std::marker::Sized {
+/// Shared memory region for dirty page logging.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct VhostUserDirtyLogRegion {
+    /// Size of the shared memory region for logging dirty pages.
+    pub mmap_size: u64,
+    /// Offset where region starts in the mapped memory.
+    pub mmap_offset: u64,
+    /// File descriptor for mmap.
+    pub mmap_handle: RawFd,
+}
+
+/// Ownership of a vhost backend.
+pub trait VhostOwner {
+    /// Set the current process as the owner of the vhost backend.
+    /// This must be run before any other vhost commands.
+    fn set_owner(&mut self) -> Result<()>;
+
+    /// Used to be sent to request disabling all rings
+    /// This is no longer used.
+    fn reset_owner(&mut self) -> Result<()>;
+}
+
+/// Negotiation of the virtio features with a vhost backend.
+pub trait VhostFeatures {
     /// Get a bitmask of supported virtio/vhost features.
     fn get_features(&mut self) -> Result<u64>;
 
@@ -90,23 +289,35 @@ pub trait VhostBackend: std::marker::Sized {
     /// * `features` - Bitmask of features to set.
     fn set_features(&mut self, features: u64) -> Result<()>;
 
-    /// Set the current process as the owner of the vhost backend.
-    /// This must be run before any other vhost commands.
-    fn set_owner(&mut self) -> Result<()>;
-
-    /// Used to be sent to request disabling all rings
-    /// This is no longer used.
-    fn reset_owner(&mut self) -> Result<()>;
+    /// Enable the device specific `device` features together with the common `features`, once
+    /// checked they are all supported by the vhost subsystem.
+    fn ack_features<D: DeviceFeatures>(&mut self, device: D, features: VirtioFeatures) -> Result<()>
+    where
+        Self: Sized,
+    {
+        let acked = device.with_common(features);
+        check_acked_features(self.get_features()?, acked)?;
+        self.set_features(acked)
+    }
+}
 
+/// Guest memory table and dirty page logging of a vhost backend.
+pub trait VhostMemory {
     /// Set the guest memory mappings for vhost to use.
     fn set_mem_table(&mut self, regions: &[VhostUserMemoryRegionInfo]) -> Result<()>;
 
     /// Set base address for page modification logging.
-    fn set_log_base(&mut self, base: u64, fd: Option<RawFd>) -> Result<()>;
+    ///
+    /// The vhost-user backend shares the log with the slave through `region` if the
+    /// VHOST_USER_PROTOCOL_F_LOG_SHMFD protocol feature has been negotiated.
+    fn set_log_base(&mut self, base: u64, region: Option<VhostUserDirtyLogRegion>) -> Result<()>;
 
     /// Specify an eventfd file descriptor to signal on log write.
     fn set_log_fd(&mut self, fd: RawFd) -> Result<()>;
+}
 
+/// Configuration of the vrings of a vhost backend.
+pub trait VhostVring {
     /// Set the number of descriptors in the vring.
     ///
     /// # Arguments
@@ -128,7 +339,12 @@ pub trait VhostBackend: std::marker::Sized {
     /// * `num` - Index where available descriptors start.
     fn set_vring_base(&mut self, queue_index: usize, base: u16) -> Result<()>;
 
-    /// Get the available vring base offset.
+    /// Stop the vring and get the index of the next available descriptor it would have
+    /// processed, to be restored with `set_vring_base()` when restarting the vring, after a reset
+    /// or a migration.
+    ///
+    /// # Arguments
+    /// * `queue_index` - Index of the queue to query.
     fn get_vring_base(&mut self, queue_index: usize) -> Result<u32>;
 
     /// Set the eventfd to trigger when buffers have been used by the host.
@@ -152,11 +368,69 @@ pub trait VhostBackend: std::marker::Sized {
     /// * `queue_index` - Index of the queue to modify.
     /// * `fd` - EventFd that will be signaled from guest.
     fn set_vring_err(&mut self, queue_index: usize, fd: &EventFd) -> Result<()>;
+
+    /// Set up the vring from the split virtqueue `queue` laid out in `mem`, with its size, its
+    /// addresses and the index to restart from.
+    ///
+    /// The eventfds of the vring are set up separately, with `set_vring_eventfds()` for instance.
+    /// Vrings whose used ring is logged are configured with `VringConfigBuilder` instead.
+    ///
+    /// # Arguments
+    /// * `queue_index` - Index of the queue to set up.
+    /// * `queue` - Virtqueue set up by the guest driver.
+    /// * `mem` - Guest memory the virtqueue lies in.
+    #[cfg(feature = "vm-memory")]
+    fn setup_queue<Q: VirtioQueue + ?Sized, M: GuestMemory>(
+        &mut self,
+        queue_index: usize,
+        queue: &Q,
+        mem: &M,
+    ) -> Result<()>
+    where
+        Self: Sized,
+    {
+        let config = VringConfigBuilder::from_queue(queue).build(mem)?;
+        self.set_vring_num(queue_index, config.queue_size)?;
+        self.set_vring_addr(queue_index, &config)?;
+        self.set_vring_base(queue_index, queue.next_avail())
+    }
+
+    /// Set all the eventfds of a vring, the kick eventfd coming last as it starts the vring.
+    ///
+    /// # Arguments
+    /// * `queue_index` - Index of the queue to modify.
+    /// * `fds` - Eventfds of the vring.
+    fn set_vring_eventfds(&mut self, queue_index: usize, fds: &VringEventFds) -> Result<()> {
+        self.set_vring_call(queue_index, &fds.call)?;
+        if let Some(ref err) = fds.err {
+            self.set_vring_err(queue_index, err)?;
+        }
+        self.set_vring_kick(queue_index, &fds.kick)
+    }
 }
 
+/// An interface for setting up vhost-based backend drivers.
+///
+/// Vhost devices are subset of virtio devices, which improve virtio device's performance by
+/// delegating data plane operations to dedicated IO service processes. Vhost devices use the
+/// same virtqueue layout as virtio devices to allow vhost devices to be mapped directly to
+/// virtio devices.
+/// The purpose of vhost is to implement a subset of a virtio device's functionality outside the
+/// VMM process. Typically fast paths for IO operations are delegated to the dedicated IO service
+/// processes, and slow path for device configuration are still handled by the VMM process. It may
+/// also be used to control access permissions of virtio backend devices.
+///
+/// The requests are split among `VhostOwner`, `VhostFeatures`, `VhostMemory` and `VhostVring`,
+/// so code which only needs some of them may require just those, and this trait is implemented
+/// for all the types implementing the four of them. All these traits may be used as trait
+/// objects.
+pub trait VhostBackend: VhostOwner + VhostFeatures + VhostMemory + VhostVring {}
+
+impl<T: VhostOwner + VhostFeatures + VhostMemory + VhostVring + ?Sized> VhostBackend for T {}
+
 #[cfg(test)]
 mod tests {
-    use VringConfigData;
+    use super::*;
 
     #[test]
     fn test_vring_config_data() {
@@ -185,4 +459,204 @@ mod tests {
         assert_eq!(config.is_log_addr_valid(), true);
         assert_eq!(config.get_log_addr(), 0);
     }
+
+    #[derive(Default)]
+    struct DummyVring {
+        sizes: Vec<(usize, u16)>,
+        configs: Vec<(usize, VringConfigData)>,
+        bases: Vec<(usize, u16)>,
+        eventfds: Vec<(&'static str, usize)>,
+    }
+
+    impl VhostVring for DummyVring {
+        fn set_vring_num(&mut self, queue_index: usize, num: u16) -> Result<()> {
+            self.sizes.push((queue_index, num));
+            Ok(())
+        }
+
+        fn set_vring_addr(&mut self, queue_index: usize, config: &VringConfigData) -> Result<()> {
+            self.configs.push((queue_index, *config));
+            Ok(())
+        }
+
+        fn set_vring_base(&mut self, queue_index: usize, base: u16) -> Result<()> {
+            self.bases.push((queue_index, base));
+            Ok(())
+        }
+
+        fn get_vring_base(&mut self, _queue_index: usize) -> Result<u32> {
+            Ok(0)
+        }
+
+        fn set_vring_call(&mut self, queue_index: usize, _fd: &EventFd) -> Result<()> {
+            self.eventfds.push(("call", queue_index));
+            Ok(())
+        }
+
+        fn set_vring_kick(&mut self, queue_index: usize, _fd: &EventFd) -> Result<()> {
+            self.eventfds.push(("kick", queue_index));
+            Ok(())
+        }
+
+        fn set_vring_err(&mut self, queue_index: usize, _fd: &EventFd) -> Result<()> {
+            self.eventfds.push(("err", queue_index));
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_vhost_vring_object() {
+        // Only the vring requests need to be implemented to set up vrings.
+        let mut dummy = DummyVring::default();
+        let vring: &mut dyn VhostVring = &mut dummy;
+        vring.set_vring_num(1, 256).unwrap();
+        assert_eq!(vring.get_vring_base(1).unwrap(), 0);
+        assert_eq!(dummy.sizes, vec![(1, 256)]);
+    }
+
+    #[test]
+    fn test_vring_eventfds() {
+        let mut dummy = DummyVring::default();
+        let fds = VringEventFds::new(false).unwrap();
+        assert!(fds.err.is_none());
+        dummy.set_vring_eventfds(0, &fds).unwrap();
+        let fds = VringEventFds::new(true).unwrap();
+        fds.kick.write(1).unwrap();
+        assert_eq!(fds.kick.read().unwrap(), 1);
+        // The eventfds are non-blocking.
+        assert!(fds.kick.read().is_err());
+        dummy.set_vring_eventfds(1, &fds).unwrap();
+        assert_eq!(
+            dummy.eventfds,
+            vec![
+                ("call", 0),
+                ("kick", 0),
+                ("call", 1),
+                ("err", 1),
+                ("kick", 1)
+            ]
+        );
+    }
+
+    #[cfg(feature = "vm-memory")]
+    #[test]
+    fn test_vring_config_builder() {
+        use vm_memory::GuestMemoryMmap;
+
+        let mem = GuestMemoryMmap::from_ranges(&[
+            (GuestAddress(0), 0x1_0000),
+            (GuestAddress(0x10_0000), 0x1000),
+        ])
+        .unwrap();
+        let host_addr = |addr| mem.get_host_address(GuestAddress(addr)).unwrap() as u64;
+        let builder = VringConfigBuilder::new(256)
+            .queue_size(128)
+            .desc_table(GuestAddress(0x1000))
+            .avail_ring(GuestAddress(0x2000))
+            .used_ring(GuestAddress(0x10_0000));
+        let config = builder.build(&mem).unwrap();
+        assert_eq!(config.queue_max_size, 256);
+        assert_eq!(config.queue_size, 128);
+        assert_eq!(config.flags, 0);
+        assert_eq!(config.desc_table_addr, host_addr(0x1000));
+        assert_eq!(config.avail_ring_addr, host_addr(0x2000));
+        assert_eq!(config.used_ring_addr, host_addr(0x10_0000));
+        assert_eq!(config.log_addr, None);
+
+        // The log address is the guest physical address of the used ring.
+        let config = builder.log(true).build(&mem).unwrap();
+        assert_eq!(config.flags, 0x1);
+        assert_eq!(config.get_log_addr(), 0x10_0000);
+
+        // Split rings are sized by a power of 2, and must fit.
+        assert!(builder.queue_size(100).build(&mem).is_err());
+        assert!(builder.queue_size(512).build(&mem).is_err());
+        assert!(builder.queue_size(0).build(&mem).is_err());
+        match builder.desc_table(GuestAddress(0x1008)).build(&mem) {
+            Err(Error::DescriptorTableAddress) => {}
+            _ => panic!("expected an unaligned descriptor table"),
+        }
+        match builder.avail_ring(GuestAddress(0x2_0000)).build(&mem) {
+            Err(Error::AvailAddress) => {}
+            _ => panic!("expected an available ring out of the guest memory"),
+        }
+        // The used ring of 128 descriptors crosses the end of its region.
+        match builder.used_ring(GuestAddress(0x10_0c00)).build(&mem) {
+            Err(Error::UsedAddress) => {}
+            _ => panic!("expected a used ring across regions"),
+        }
+
+        // Packed rings don't need to be sized by a power of 2.
+        let config = builder
+            .packed(true)
+            .queue_size(100)
+            .used_ring(GuestAddress(0x10_0ffc))
+            .build(&mem)
+            .unwrap();
+        assert_eq!(config.queue_size, 100);
+        assert!(builder
+            .packed(true)
+            .avail_ring(GuestAddress(0x2002))
+            .build(&mem)
+            .is_err());
+    }
+
+    #[cfg(feature = "vm-memory")]
+    struct DummyQueue {
+        next_avail: u16,
+    }
+
+    #[cfg(feature = "vm-memory")]
+    impl VirtioQueue for DummyQueue {
+        fn max_size(&self) -> u16 {
+            256
+        }
+
+        fn size(&self) -> u16 {
+            64
+        }
+
+        fn desc_table(&self) -> GuestAddress {
+            GuestAddress(0x1000)
+        }
+
+        fn avail_ring(&self) -> GuestAddress {
+            GuestAddress(0x2000)
+        }
+
+        fn used_ring(&self) -> GuestAddress {
+            GuestAddress(0x3000)
+        }
+
+        fn next_avail(&self) -> u16 {
+            self.next_avail
+        }
+    }
+
+    #[cfg(feature = "vm-memory")]
+    #[test]
+    fn test_setup_queue() {
+        let mem = vm_memory::GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x1_0000)]).unwrap();
+        let mut dummy = DummyVring::default();
+        dummy
+            .setup_queue(1, &DummyQueue { next_avail: 5 }, &mem)
+            .unwrap();
+        assert_eq!(dummy.sizes, vec![(1, 64)]);
+        assert_eq!(dummy.bases, vec![(1, 5)]);
+        let (index, config) = dummy.configs[0];
+        assert_eq!(index, 1);
+        assert_eq!(config.queue_max_size, 256);
+        assert_eq!(
+            config.used_ring_addr,
+            mem.get_host_address(GuestAddress(0x3000)).unwrap() as u64
+        );
+
+        // Nothing is set up for queues out of the guest memory.
+        let mem = vm_memory::GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x2000)]).unwrap();
+        let mut dummy = DummyVring::default();
+        assert!(dummy
+            .setup_queue(0, &DummyQueue { next_avail: 0 }, &mem)
+            .is_err());
+        assert!(dummy.sizes.is_empty());
+    }
 }
@@ -0,0 +1,204 @@
+// Copyright (C) 2020 Alibaba Cloud Computing. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0 or BSD-3-Clause
+
+//! Virtio feature bits, as defined by the virtio specification.
+//!
+//! The virtio feature bitmask carries both the features of the transport and the virtqueues,
+//! common to all devices, and the features specific to the device type. `VirtioFeatures` defines
+//! the former, while each device type implements `DeviceFeatures` to be combined with them into
+//! the bitmask passed to `VhostFeatures::set_features()`.
+
+use crate::{Error, Result};
+
+bitflags! {
+    /// Feature bits common to all virtio devices.
+    pub struct VirtioFeatures: u64 {
+        /// The device notifies the driver when the available ring becomes empty.
+        const NOTIFY_ON_EMPTY = 1 << 24;
+        /// The vhost backend logs the memory written to, for live migration.
+        const LOG_ALL = 1 << 26;
+        /// The device accepts arbitrary descriptor layouts.
+        const ANY_LAYOUT = 1 << 27;
+        /// The driver may use indirect descriptors.
+        const RING_INDIRECT_DESC = 1 << 28;
+        /// The used and available event index fields are enabled.
+        const RING_EVENT_IDX = 1 << 29;
+        /// The vhost-user protocol features may be negotiated.
+        const PROTOCOL_FEATURES = 1 << 30;
+        /// The device is compliant with the virtio 1.0 specification.
+        const VERSION_1 = 1 << 32;
+        /// The device accesses the memory through the platform IOMMU.
+        const ACCESS_PLATFORM = 1 << 33;
+        /// The packed virtqueue layout is used.
+        const RING_PACKED = 1 << 34;
+        /// The device uses the buffers in the order they have been made available.
+        const IN_ORDER = 1 << 35;
+        /// The memory accesses of the driver and the device are ordered by the platform.
+        const ORDER_PLATFORM = 1 << 36;
+        /// The device supports single root I/O virtualization.
+        const SR_IOV = 1 << 37;
+        /// The driver passes extra data in the device notifications.
+        const NOTIFICATION_DATA = 1 << 38;
+    }
+}
+
+bitflags! {
+    /// Feature bits specific to virtio network devices.
+    pub struct VirtioNetFeatures: u64 {
+        /// The device handles packets with partial checksum.
+        const CSUM = 1 << 0;
+        /// The driver handles packets with partial checksum.
+        const GUEST_CSUM = 1 << 1;
+        /// The offloads may be reconfigured through the control virtqueue.
+        const CTRL_GUEST_OFFLOADS = 1 << 2;
+        /// The device reports its maximum MTU in the configuration space.
+        const MTU = 1 << 3;
+        /// The device reports its MAC address in the configuration space.
+        const MAC = 1 << 5;
+        /// The driver can receive TSOv4.
+        const GUEST_TSO4 = 1 << 7;
+        /// The driver can receive TSOv6.
+        const GUEST_TSO6 = 1 << 8;
+        /// The driver can receive TSO with ECN.
+        const GUEST_ECN = 1 << 9;
+        /// The driver can receive UFO.
+        const GUEST_UFO = 1 << 10;
+        /// The device can receive TSOv4.
+        const HOST_TSO4 = 1 << 11;
+        /// The device can receive TSOv6.
+        const HOST_TSO6 = 1 << 12;
+        /// The device can receive TSO with ECN.
+        const HOST_ECN = 1 << 13;
+        /// The device can receive UFO.
+        const HOST_UFO = 1 << 14;
+        /// The driver can merge receive buffers.
+        const MRG_RXBUF = 1 << 15;
+        /// The device reports its link status in the configuration space.
+        const STATUS = 1 << 16;
+        /// The control virtqueue is available.
+        const CTRL_VQ = 1 << 17;
+        /// The receive mode may be controlled through the control virtqueue.
+        const CTRL_RX = 1 << 18;
+        /// The VLAN filtering may be controlled through the control virtqueue.
+        const CTRL_VLAN = 1 << 19;
+        /// The driver can send gratuitous packets.
+        const GUEST_ANNOUNCE = 1 << 21;
+        /// The device supports multiple queue pairs.
+        const MQ = 1 << 22;
+        /// The MAC address may be set through the control virtqueue.
+        const CTRL_MAC_ADDR = 1 << 23;
+    }
+}
+
+bitflags! {
+    /// Feature bits specific to virtio block devices.
+    pub struct VirtioBlockFeatures: u64 {
+        /// Maximum size of any single segment is in size_max.
+        const SIZE_MAX = 1 << 1;
+        /// Maximum number of segments in a request is in seg_max.
+        const SEG_MAX = 1 << 2;
+        /// Disk-style geometry is in geometry.
+        const GEOMETRY = 1 << 4;
+        /// The device is read only.
+        const RO = 1 << 5;
+        /// Block size of the device is in blk_size.
+        const BLK_SIZE = 1 << 6;
+        /// The device supports cache flush requests.
+        const FLUSH = 1 << 9;
+        /// The optimal I/O alignment is in topology.
+        const TOPOLOGY = 1 << 10;
+        /// The driver can switch the write cache mode through the writeback field.
+        const CONFIG_WCE = 1 << 11;
+        /// The device supports multiple virtqueues.
+        const MQ = 1 << 12;
+        /// The device supports discard requests.
+        const DISCARD = 1 << 13;
+        /// The device supports write zeroes requests.
+        const WRITE_ZEROES = 1 << 14;
+    }
+}
+
+bitflags! {
+    /// Feature bits specific to virtio vsock devices.
+    pub struct VirtioVsockFeatures: u64 {
+        /// Stream sockets are supported.
+        const STREAM = 1 << 0;
+        /// Sequential packet sockets are supported.
+        const SEQPACKET = 1 << 1;
+    }
+}
+
+bitflags! {
+    /// Feature bits specific to virtio console devices.
+    pub struct VirtioConsoleFeatures: u64 {
+        /// The size of the console is in cols and rows.
+        const SIZE = 1 << 0;
+        /// The device supports multiple ports and control virtqueues.
+        const MULTIPORT = 1 << 1;
+        /// The driver can output characters through emerg_wr.
+        const EMERG_WRITE = 1 << 2;
+    }
+}
+
+/// Feature bits specific to a virtio device type, sharing the virtio feature bitmask with the
+/// common `VirtioFeatures`.
+pub trait DeviceFeatures: Copy {
+    /// Get the virtio feature bitmask made of the device features and the common `features`.
+    fn with_common(self, features: VirtioFeatures) -> u64;
+
+    /// Split a virtio feature bitmask into the known device features and common features.
+    fn split(bits: u64) -> (Self, VirtioFeatures);
+}
+
+macro_rules! impl_device_features {
+    ($features:ident) => {
+        impl DeviceFeatures for $features {
+            fn with_common(self, features: VirtioFeatures) -> u64 {
+                self.bits() | features.bits()
+            }
+
+            fn split(bits: u64) -> (Self, VirtioFeatures) {
+                (
+                    $features::from_bits_truncate(bits),
+                    VirtioFeatures::from_bits_truncate(bits),
+                )
+            }
+        }
+    };
+}
+
+impl_device_features!(VirtioNetFeatures);
+impl_device_features!(VirtioBlockFeatures);
+impl_device_features!(VirtioVsockFeatures);
+impl_device_features!(VirtioConsoleFeatures);
+
+/// Check that the `acked` features are a subset of the `offered` ones, as the driver may only
+/// ack the features offered by the device.
+pub fn check_acked_features(offered: u64, acked: u64) -> Result<()> {
+    if acked & !offered != 0 {
+        return Err(Error::InvalidParam);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_device_features() {
+        let bits = (VirtioNetFeatures::MAC | VirtioNetFeatures::MQ)
+            .with_common(VirtioFeatures::VERSION_1 | VirtioFeatures::PROTOCOL_FEATURES);
+        assert_eq!(bits, 0x1_4040_0020);
+
+        let (net, common) = VirtioNetFeatures::split(bits | 1 << 63);
+        assert_eq!(net, VirtioNetFeatures::MAC | VirtioNetFeatures::MQ);
+        assert_eq!(
+            common,
+            VirtioFeatures::VERSION_1 | VirtioFeatures::PROTOCOL_FEATURES
+        );
+
+        check_acked_features(bits, VirtioFeatures::VERSION_1.bits()).unwrap();
+        assert!(check_acked_features(bits, VirtioBlockFeatures::FLUSH.bits()).is_err());
+    }
+}
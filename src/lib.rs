@@ -32,21 +32,36 @@
 
 #![deny(missing_docs)]
 
-#[cfg_attr(
-    any(feature = "vhost-user-master", feature = "vhost-user-slave"),
-    macro_use
-)]
+#[macro_use]
 extern crate bitflags;
 extern crate libc;
-#[cfg(feature = "vhost-kern")]
+#[cfg(feature = "trace")]
+#[macro_use]
+extern crate log;
+#[cfg(feature = "vm-memory")]
 extern crate vm_memory;
-#[cfg_attr(feature = "vhost-kern", macro_use)]
+#[cfg_attr(
+    any(feature = "vhost-kern-raw", feature = "vhost-user-slave"),
+    macro_use
+)]
 extern crate vmm_sys_util;
 
 mod backend;
 pub use backend::*;
 
-#[cfg(feature = "vhost-kern")]
+pub mod features;
+
+#[cfg(feature = "mock")]
+pub mod mock;
+#[cfg(feature = "vhost-net")]
+pub mod net;
+#[cfg(feature = "vhost-scsi")]
+pub mod scsi;
+#[cfg(feature = "vhost-vdpa")]
+pub mod vdpa;
+#[cfg(feature = "versionize")]
+pub mod versionize;
+#[cfg(feature = "vhost-kern-raw")]
 pub mod vhost_kern;
 #[cfg(any(feature = "vhost-user-master", feature = "vhost-user-slave"))]
 pub mod vhost_user;
@@ -55,9 +70,12 @@ pub mod vsock;
 
 /// Error codes for vhost operations
 #[derive(Debug)]
+#[non_exhaustive]
 pub enum Error {
     /// Invalid operations.
     InvalidOperation,
+    /// Invalid parameters.
+    InvalidParam,
     /// Invalid guest memory.
     InvalidGuestMemory,
     /// Invalid guest memory region.
@@ -72,10 +90,10 @@ pub enum Error {
     AvailAddress,
     /// Invalid log address.
     LogAddress,
-    #[cfg(feature = "vhost-kern")]
+    #[cfg(feature = "vhost-kern-raw")]
     /// Error opening the vhost backend driver.
     VhostOpen(std::io::Error),
-    #[cfg(feature = "vhost-kern")]
+    #[cfg(feature = "vhost-kern-raw")]
     /// Error while running ioctl.
     IoctlError(std::io::Error),
     /// Error from IO subsystem.
@@ -89,6 +107,7 @@ impl std::fmt::Display for Error {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         match self {
             Error::InvalidOperation => write!(f, "invalid vhost operations"),
+            Error::InvalidParam => write!(f, "invalid parameters"),
             Error::InvalidGuestMemory => write!(f, "invalid guest memory object"),
             Error::InvalidGuestMemoryRegion => write!(f, "invalid guest memory region"),
             Error::InvalidQueue => write!(f, "invalid virtque"),
@@ -97,9 +116,9 @@ impl std::fmt::Display for Error {
             Error::AvailAddress => write!(f, "invalid virtque available talbe address"),
             Error::LogAddress => write!(f, "invalid virtque log address"),
             Error::IOError(e) => write!(f, "IO error: {}", e),
-            #[cfg(feature = "vhost-kern")]
+            #[cfg(feature = "vhost-kern-raw")]
             Error::VhostOpen(e) => write!(f, "failure in opening vhost file: {}", e),
-            #[cfg(feature = "vhost-kern")]
+            #[cfg(feature = "vhost-kern-raw")]
             Error::IoctlError(e) => write!(f, "failure in vhost ioctl: {}", e),
             #[cfg(any(feature = "vhost-user-master", feature = "vhost-user-slave"))]
             Error::VhostUserProtocol(e) => write!(f, "vhost-user: {}", e),
@@ -107,6 +126,19 @@ impl std::fmt::Display for Error {
     }
 }
 
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::IOError(e) => Some(e),
+            #[cfg(feature = "vhost-kern-raw")]
+            Error::VhostOpen(e) | Error::IoctlError(e) => Some(e),
+            #[cfg(any(feature = "vhost-user-master", feature = "vhost-user-slave"))]
+            Error::VhostUserProtocol(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
 #[cfg(any(feature = "vhost-user-master", feature = "vhost-user-slave"))]
 impl std::convert::From<vhost_user::Error> for Error {
     fn from(err: vhost_user::Error) -> Self {
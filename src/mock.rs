@@ -0,0 +1,246 @@
+// Copyright (C) 2020 Alibaba Cloud Computing. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Fake vhost backend to unit test the vhost setup code of VMMs.
+//!
+//! `MockBackend` implements `VhostBackend` without any vhost device or slave: it records the
+//! requests it receives, and answers them with the responses programmed by the test. By default
+//! all requests succeed, GET_FEATURES returns the features the backend has been created with,
+//! and GET_VRING_BASE returns the last base set for the vring.
+
+use std::os::unix::io::{AsRawFd, RawFd};
+
+use vmm_sys_util::eventfd::EventFd;
+
+use super::{
+    Result, VhostFeatures, VhostMemory, VhostOwner, VhostUserDirtyLogRegion,
+    VhostUserMemoryRegionInfo, VhostVring, VringConfigData,
+};
+
+/// Request received by the mock backend.
+#[derive(Clone, Debug, PartialEq)]
+pub enum MockRequest {
+    /// GET_FEATURES.
+    GetFeatures,
+    /// SET_FEATURES with the acked features.
+    SetFeatures(u64),
+    /// SET_OWNER.
+    SetOwner,
+    /// RESET_OWNER.
+    ResetOwner,
+    /// SET_MEM_TABLE with the memory regions.
+    SetMemTable(Vec<VhostUserMemoryRegionInfo>),
+    /// SET_LOG_BASE with the base address and the shared log region.
+    SetLogBase(u64, Option<VhostUserDirtyLogRegion>),
+    /// SET_LOG_FD with the eventfd.
+    SetLogFd(RawFd),
+    /// SET_VRING_NUM with the queue index and size.
+    SetVringNum(usize, u16),
+    /// SET_VRING_ADDR with the queue index and configuration.
+    SetVringAddr(usize, VringConfigData),
+    /// SET_VRING_BASE with the queue index and base.
+    SetVringBase(usize, u16),
+    /// GET_VRING_BASE with the queue index.
+    GetVringBase(usize),
+    /// SET_VRING_CALL with the queue index and eventfd.
+    SetVringCall(usize, RawFd),
+    /// SET_VRING_KICK with the queue index and eventfd.
+    SetVringKick(usize, RawFd),
+    /// SET_VRING_ERR with the queue index and eventfd.
+    SetVringErr(usize, RawFd),
+}
+
+/// Handler programming the responses of the mock backend.
+///
+/// It returns `None` to let the backend answer with its default response, or the result of the
+/// request, whose value is only used by GET_FEATURES and GET_VRING_BASE.
+pub type MockHandler = Box<dyn FnMut(&MockRequest) -> Option<Result<u64>> + Send>;
+
+/// Fake vhost backend recording the requests it receives.
+pub struct MockBackend {
+    features: u64,
+    acked_features: u64,
+    vring_bases: Vec<u16>,
+    requests: Vec<MockRequest>,
+    handler: Option<MockHandler>,
+}
+
+impl MockBackend {
+    /// Create a mock backend supporting the `features`.
+    pub fn new(features: u64) -> Self {
+        MockBackend {
+            features,
+            acked_features: 0,
+            vring_bases: Vec::new(),
+            requests: Vec::new(),
+            handler: None,
+        }
+    }
+
+    /// Program the responses of the backend with `handler`.
+    pub fn set_handler(&mut self, handler: MockHandler) {
+        self.handler = Some(handler);
+    }
+
+    /// Get the features acked by the last SET_FEATURES request.
+    pub fn acked_features(&self) -> u64 {
+        self.acked_features
+    }
+
+    /// Get the requests received so far, in order.
+    pub fn requests(&self) -> &[MockRequest] {
+        &self.requests
+    }
+
+    /// Get and forget the requests received so far, in order.
+    pub fn take_requests(&mut self) -> Vec<MockRequest> {
+        std::mem::take(&mut self.requests)
+    }
+
+    // Record the request, and get its programmed response if any.
+    fn request(&mut self, req: MockRequest) -> Result<Option<u64>> {
+        let res = match self.handler {
+            Some(ref mut handler) => handler(&req),
+            None => None,
+        };
+        self.requests.push(req);
+        res.transpose()
+    }
+}
+
+impl VhostOwner for MockBackend {
+    fn set_owner(&mut self) -> Result<()> {
+        self.request(MockRequest::SetOwner).map(|_| ())
+    }
+
+    fn reset_owner(&mut self) -> Result<()> {
+        self.request(MockRequest::ResetOwner).map(|_| ())
+    }
+}
+
+impl VhostFeatures for MockBackend {
+    fn get_features(&mut self) -> Result<u64> {
+        let features = self.request(MockRequest::GetFeatures)?;
+        Ok(features.unwrap_or(self.features))
+    }
+
+    fn set_features(&mut self, features: u64) -> Result<()> {
+        self.request(MockRequest::SetFeatures(features))?;
+        self.acked_features = features;
+        Ok(())
+    }
+}
+
+impl VhostMemory for MockBackend {
+    fn set_mem_table(&mut self, regions: &[VhostUserMemoryRegionInfo]) -> Result<()> {
+        self.request(MockRequest::SetMemTable(regions.to_vec()))
+            .map(|_| ())
+    }
+
+    fn set_log_base(&mut self, base: u64, region: Option<VhostUserDirtyLogRegion>) -> Result<()> {
+        self.request(MockRequest::SetLogBase(base, region))
+            .map(|_| ())
+    }
+
+    fn set_log_fd(&mut self, fd: RawFd) -> Result<()> {
+        self.request(MockRequest::SetLogFd(fd)).map(|_| ())
+    }
+}
+
+impl VhostVring for MockBackend {
+    fn set_vring_num(&mut self, queue_index: usize, num: u16) -> Result<()> {
+        self.request(MockRequest::SetVringNum(queue_index, num))
+            .map(|_| ())
+    }
+
+    fn set_vring_addr(&mut self, queue_index: usize, config_data: &VringConfigData) -> Result<()> {
+        self.request(MockRequest::SetVringAddr(queue_index, *config_data))
+            .map(|_| ())
+    }
+
+    fn set_vring_base(&mut self, queue_index: usize, base: u16) -> Result<()> {
+        self.request(MockRequest::SetVringBase(queue_index, base))?;
+        if self.vring_bases.len() <= queue_index {
+            self.vring_bases.resize(queue_index + 1, 0);
+        }
+        self.vring_bases[queue_index] = base;
+        Ok(())
+    }
+
+    fn get_vring_base(&mut self, queue_index: usize) -> Result<u32> {
+        let base = self.request(MockRequest::GetVringBase(queue_index))?;
+        let default = self.vring_bases.get(queue_index).cloned().unwrap_or(0);
+        Ok(base.map_or(u32::from(default), |base| base as u32))
+    }
+
+    fn set_vring_call(&mut self, queue_index: usize, fd: &EventFd) -> Result<()> {
+        self.request(MockRequest::SetVringCall(queue_index, fd.as_raw_fd()))
+            .map(|_| ())
+    }
+
+    fn set_vring_kick(&mut self, queue_index: usize, fd: &EventFd) -> Result<()> {
+        self.request(MockRequest::SetVringKick(queue_index, fd.as_raw_fd()))
+            .map(|_| ())
+    }
+
+    fn set_vring_err(&mut self, queue_index: usize, fd: &EventFd) -> Result<()> {
+        self.request(MockRequest::SetVringErr(queue_index, fd.as_raw_fd()))
+            .map(|_| ())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use Error;
+
+    #[test]
+    fn test_mock_backend() {
+        let mut backend = MockBackend::new(0x1_0000_0003);
+        backend.set_owner().unwrap();
+        assert_eq!(backend.get_features().unwrap(), 0x1_0000_0003);
+        backend.set_features(0x1_0000_0001).unwrap();
+        assert_eq!(backend.acked_features(), 0x1_0000_0001);
+        backend.set_vring_base(1, 0x10).unwrap();
+        assert_eq!(backend.get_vring_base(1).unwrap(), 0x10);
+        assert_eq!(backend.get_vring_base(0).unwrap(), 0);
+
+        let kick = EventFd::new(0).unwrap();
+        backend.set_vring_kick(1, &kick).unwrap();
+        assert_eq!(
+            backend.take_requests(),
+            vec![
+                MockRequest::SetOwner,
+                MockRequest::GetFeatures,
+                MockRequest::SetFeatures(0x1_0000_0001),
+                MockRequest::SetVringBase(1, 0x10),
+                MockRequest::GetVringBase(1),
+                MockRequest::GetVringBase(0),
+                MockRequest::SetVringKick(1, kick.as_raw_fd()),
+            ]
+        );
+        assert!(backend.requests().is_empty());
+    }
+
+    #[test]
+    fn test_mock_handler() {
+        let mut backend = MockBackend::new(0x3);
+        backend.set_handler(Box::new(|req| match *req {
+            MockRequest::GetFeatures => Some(Ok(0x1)),
+            MockRequest::GetVringBase(_) => Some(Ok(0x20)),
+            MockRequest::SetVringNum(_, num) if num > 256 => Some(Err(Error::InvalidQueue)),
+            _ => None,
+        }));
+
+        assert_eq!(backend.get_features().unwrap(), 0x1);
+        assert_eq!(backend.get_vring_base(0).unwrap(), 0x20);
+        backend.set_vring_num(0, 256).unwrap();
+        match backend.set_vring_num(0, 512) {
+            Err(Error::InvalidQueue) => {}
+            _ => panic!("programmed error not returned"),
+        }
+        // Failed requests are recorded too.
+        assert_eq!(backend.requests().len(), 4);
+        assert_eq!(backend.requests()[3], MockRequest::SetVringNum(0, 512));
+    }
+}
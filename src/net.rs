@@ -0,0 +1,21 @@
+// Copyright (C) 2020 Alibaba Cloud Computing. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0 or BSD-3-Clause
+
+//! Trait to control vhost-net backend drivers.
+
+use std::fs::File;
+
+use crate::backend::VhostBackend;
+use crate::Result;
+
+/// Trait to control vhost-net backend drivers.
+pub trait VhostNet: VhostBackend {
+    /// Set the backend of a virtqueue, usually a TAP device or a raw socket, which the vhost
+    /// driver reads packets from and writes packets to.
+    ///
+    /// # Arguments
+    /// * `queue_index` - Index of the virtqueue
+    /// * `fd` - The file of the network backend, or None to detach the current backend and stop
+    ///   the virtqueue.
+    fn set_backend(&mut self, queue_index: usize, fd: Option<&File>) -> Result<()>;
+}
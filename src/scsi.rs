@@ -0,0 +1,34 @@
+// Copyright (C) 2020 Alibaba Cloud Computing. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0 or BSD-3-Clause
+
+//! Trait to control vhost-scsi backend drivers.
+
+use crate::backend::VhostBackend;
+use crate::Result;
+
+/// Trait to control vhost-scsi backend drivers.
+pub trait VhostScsi: VhostBackend {
+    /// Attach the vhost-scsi device to a target portal group of the host SCSI target subsystem.
+    ///
+    /// # Arguments
+    /// * `wwpn` - World Wide Port Name of the target
+    /// * `tpgt` - Tag of the target portal group
+    fn set_endpoint(&mut self, wwpn: &str, tpgt: u16) -> Result<()>;
+
+    /// Detach the vhost-scsi device from a target portal group.
+    ///
+    /// # Arguments
+    /// * `wwpn` - World Wide Port Name of the target
+    /// * `tpgt` - Tag of the target portal group
+    fn clear_endpoint(&mut self, wwpn: &str, tpgt: u16) -> Result<()>;
+
+    /// Get the version of the vhost-scsi ABI implemented by the driver.
+    fn get_abi_version(&mut self) -> Result<i32>;
+
+    /// Set whether events have been dropped because the event virtqueue was full, so the driver
+    /// reports missed events to the guest.
+    fn set_events_missed(&mut self, missed: bool) -> Result<()>;
+
+    /// Check whether events have been dropped because the event virtqueue was full.
+    fn get_events_missed(&mut self) -> Result<bool>;
+}
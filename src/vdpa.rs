@@ -0,0 +1,84 @@
+// Copyright (C) 2020 Alibaba Cloud Computing. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0 or BSD-3-Clause
+
+//! Trait to control vhost-vdpa backend drivers.
+
+use vmm_sys_util::eventfd::EventFd;
+
+use crate::backend::VhostBackend;
+use crate::Result;
+
+/// IOVA range supported by a vDPA device for DMA mappings.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct VhostVdpaIovaRange {
+    /// First address that can be mapped.
+    pub first: u64,
+    /// Last address that can be mapped.
+    pub last: u64,
+}
+
+/// Trait to control vhost-vdpa backend drivers.
+///
+/// vDPA devices implement the virtio data plane in hardware, while the control plane goes
+/// through the vhost interface. Unlike other vhost devices, they access guest memory by DMA
+/// through IOVAs mapped with `dma_map()` instead of the memory table.
+pub trait VhostVdpa: VhostBackend {
+    /// Get the virtio device id of the device.
+    fn get_device_id(&mut self) -> Result<u32>;
+
+    /// Get the virtio device status register.
+    fn get_status(&mut self) -> Result<u8>;
+
+    /// Set the virtio device status register.
+    ///
+    /// # Arguments
+    /// * `status` - Status bits to set, writing zero resets the device
+    fn set_status(&mut self, status: u8) -> Result<()>;
+
+    /// Read the virtio device configuration space.
+    ///
+    /// # Arguments
+    /// * `offset` - Offset in the configuration space
+    /// * `buffer` - Buffer to fill with the configuration space content
+    fn get_config(&mut self, offset: u32, buffer: &mut [u8]) -> Result<()>;
+
+    /// Write the virtio device configuration space.
+    ///
+    /// # Arguments
+    /// * `offset` - Offset in the configuration space
+    /// * `buffer` - Content to write to the configuration space
+    fn set_config(&mut self, offset: u32, buffer: &[u8]) -> Result<()>;
+
+    /// Enable or disable a virtqueue.
+    ///
+    /// # Arguments
+    /// * `queue_index` - Index of the virtqueue
+    /// * `enabled` - Whether the virtqueue should process requests
+    fn set_vring_enable(&mut self, queue_index: usize, enabled: bool) -> Result<()>;
+
+    /// Get the maximum size of the virtqueues supported by the device.
+    fn get_vring_num(&mut self) -> Result<u16>;
+
+    /// Set the eventfd to signal configuration space changes to the guest.
+    fn set_config_call(&mut self, fd: &EventFd) -> Result<()>;
+
+    /// Get the IOVA range the device is able to map.
+    fn get_iova_range(&mut self) -> Result<VhostVdpaIovaRange>;
+
+    /// Map a range of IOVAs to a range of the current process memory, so the device can access
+    /// it by DMA.
+    ///
+    /// # Arguments
+    /// * `iova` - First IOVA of the range
+    /// * `size` - Size of the range
+    /// * `vaddr` - Virtual address of the memory in the current process
+    /// * `readonly` - Whether the device may only read the memory
+    fn dma_map(&mut self, iova: u64, size: u64, vaddr: *const u8, readonly: bool) -> Result<()>;
+
+    /// Unmap a range of IOVAs previously mapped with `dma_map()`.
+    ///
+    /// # Arguments
+    /// * `iova` - First IOVA of the range
+    /// * `size` - Size of the range
+    fn dma_unmap(&mut self, iova: u64, size: u64) -> Result<()>;
+}
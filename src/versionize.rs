@@ -0,0 +1,717 @@
+// Copyright (C) 2020 Alibaba Cloud Computing. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0 or BSD-3-Clause
+
+//! Versioned serialization of the state of vhost backends, for VMM migration frameworks.
+//!
+//! The traits follow the ones of the rust-vmm migration crates: `Versionize` serializes a state
+//! in the format of a given version, so a VMM may save it in the format understood by an older
+//! destination, and `Snapshottable` takes and restores a `Snapshot` of a device, identified by
+//! its id and holding the serialized state with its version.
+//!
+//! The vhost-user `Master` saves the features it has negotiated with the slave. The kernel
+//! backends don't report what has been configured in the vhost driver, so `StateRecorder` wraps
+//! a `VhostBackend` to record the requests sent through it, and replays them when restored.
+//! Eventfds can't be part of a snapshot, and are set again once the backend has been restored.
+
+use std::ops::{Deref, DerefMut};
+use std::os::unix::io::RawFd;
+
+use vmm_sys_util::eventfd::EventFd;
+
+use super::{
+    Error, Result, VhostBackend, VhostFeatures, VhostMemory, VhostOwner, VhostUserDirtyLogRegion,
+    VhostUserMemoryRegionInfo, VhostVring, VringConfigData,
+};
+
+/// Serialization of a state in the format of a given version.
+pub trait Versionize: Sized {
+    /// Serialize the state in the format of `version` at the end of `buf`, in little endian.
+    fn serialize(&self, buf: &mut Vec<u8>, version: u16);
+
+    /// Deserialize a state in the format of `version` from the front of `input`, and advance
+    /// `input` past it.
+    fn deserialize(input: &mut &[u8], version: u16) -> Result<Self>;
+
+    /// Get the latest version of the format.
+    fn version() -> u16 {
+        1
+    }
+}
+
+macro_rules! impl_versionize_int {
+    ($($ty:ty),*) => {
+        $(
+            impl Versionize for $ty {
+                fn serialize(&self, buf: &mut Vec<u8>, _version: u16) {
+                    buf.extend_from_slice(&self.to_le_bytes());
+                }
+
+                fn deserialize(input: &mut &[u8], _version: u16) -> Result<Self> {
+                    const SIZE: usize = std::mem::size_of::<$ty>();
+                    if input.len() < SIZE {
+                        return Err(Error::InvalidParam);
+                    }
+                    let (bytes, rest) = input.split_at(SIZE);
+                    *input = rest;
+                    let mut val = [0u8; SIZE];
+                    val.copy_from_slice(bytes);
+                    Ok(<$ty>::from_le_bytes(val))
+                }
+            }
+        )*
+    };
+}
+
+impl_versionize_int!(u8, u16, u32, u64);
+
+impl Versionize for bool {
+    fn serialize(&self, buf: &mut Vec<u8>, version: u16) {
+        (*self as u8).serialize(buf, version);
+    }
+
+    fn deserialize(input: &mut &[u8], version: u16) -> Result<Self> {
+        match u8::deserialize(input, version)? {
+            0 => Ok(false),
+            1 => Ok(true),
+            _ => Err(Error::InvalidParam),
+        }
+    }
+}
+
+// Optional values are prefixed by a byte telling whether they are set.
+impl<T: Versionize> Versionize for Option<T> {
+    fn serialize(&self, buf: &mut Vec<u8>, version: u16) {
+        self.is_some().serialize(buf, version);
+        if let Some(val) = self {
+            val.serialize(buf, version);
+        }
+    }
+
+    fn deserialize(input: &mut &[u8], version: u16) -> Result<Self> {
+        if bool::deserialize(input, version)? {
+            T::deserialize(input, version).map(Some)
+        } else {
+            Ok(None)
+        }
+    }
+}
+
+// Sequences are prefixed by their number of elements, on 32 bits.
+impl<T: Versionize> Versionize for Vec<T> {
+    fn serialize(&self, buf: &mut Vec<u8>, version: u16) {
+        (self.len() as u32).serialize(buf, version);
+        for val in self.iter() {
+            val.serialize(buf, version);
+        }
+    }
+
+    fn deserialize(input: &mut &[u8], version: u16) -> Result<Self> {
+        let len = u32::deserialize(input, version)?;
+        let mut vals = Vec::new();
+        for _ in 0..len {
+            vals.push(T::deserialize(input, version)?);
+        }
+        Ok(vals)
+    }
+}
+
+impl Versionize for String {
+    fn serialize(&self, buf: &mut Vec<u8>, version: u16) {
+        self.as_bytes().to_vec().serialize(buf, version);
+    }
+
+    fn deserialize(input: &mut &[u8], version: u16) -> Result<Self> {
+        String::from_utf8(Vec::deserialize(input, version)?).map_err(|_| Error::InvalidParam)
+    }
+}
+
+impl Versionize for VringConfigData {
+    fn serialize(&self, buf: &mut Vec<u8>, version: u16) {
+        self.queue_max_size.serialize(buf, version);
+        self.queue_size.serialize(buf, version);
+        self.flags.serialize(buf, version);
+        self.desc_table_addr.serialize(buf, version);
+        self.used_ring_addr.serialize(buf, version);
+        self.avail_ring_addr.serialize(buf, version);
+        self.log_addr.serialize(buf, version);
+    }
+
+    fn deserialize(input: &mut &[u8], version: u16) -> Result<Self> {
+        Ok(VringConfigData {
+            queue_max_size: u16::deserialize(input, version)?,
+            queue_size: u16::deserialize(input, version)?,
+            flags: u32::deserialize(input, version)?,
+            desc_table_addr: u64::deserialize(input, version)?,
+            used_ring_addr: u64::deserialize(input, version)?,
+            avail_ring_addr: u64::deserialize(input, version)?,
+            log_addr: Option::deserialize(input, version)?,
+        })
+    }
+}
+
+/// Serialized state of a device, with the version of its format.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Snapshot {
+    /// Identifier of the device the state belongs to.
+    pub id: String,
+    /// Version of the format of the state.
+    pub version: u16,
+    /// State serialized by `Versionize::serialize()`.
+    pub data: Vec<u8>,
+}
+
+impl Snapshot {
+    /// Create a snapshot of `state` for the device `id`, in the format of `version`.
+    pub fn new<T: Versionize>(id: &str, state: &T, version: u16) -> Self {
+        let mut data = Vec::new();
+        state.serialize(&mut data, version);
+        Snapshot {
+            id: id.to_string(),
+            version,
+            data,
+        }
+    }
+
+    /// Deserialize the state of the device `id` from the snapshot.
+    ///
+    /// Fails with `Error::InvalidParam` if the snapshot belongs to another device, has been
+    /// taken in a version newer than the one supported, or doesn't hold exactly a state.
+    pub fn to_state<T: Versionize>(&self, id: &str) -> Result<T> {
+        if self.id != id || self.version == 0 || self.version > T::version() {
+            return Err(Error::InvalidParam);
+        }
+        let mut input = self.data.as_slice();
+        let state = T::deserialize(&mut input, self.version)?;
+        if !input.is_empty() {
+            return Err(Error::InvalidParam);
+        }
+        Ok(state)
+    }
+}
+
+// The format of snapshots themselves doesn't depend on the version of the state they hold.
+impl Versionize for Snapshot {
+    fn serialize(&self, buf: &mut Vec<u8>, version: u16) {
+        self.id.serialize(buf, version);
+        self.version.serialize(buf, version);
+        self.data.serialize(buf, version);
+    }
+
+    fn deserialize(input: &mut &[u8], version: u16) -> Result<Self> {
+        Ok(Snapshot {
+            id: String::deserialize(input, version)?,
+            version: u16::deserialize(input, version)?,
+            data: Vec::deserialize(input, version)?,
+        })
+    }
+}
+
+/// A device whose state may be saved, and restored in a new instance of the device.
+pub trait Snapshottable {
+    /// Get the identifier of the device in snapshots.
+    fn id(&self) -> String;
+
+    /// Take a snapshot of the state of the device, in the latest version of its format.
+    fn snapshot(&mut self) -> Result<Snapshot>;
+
+    /// Restore the state saved in `snapshot`.
+    fn restore(&mut self, snapshot: &Snapshot) -> Result<()>;
+}
+
+/// Memory region recorded by a `StateRecorder`, without the file descriptor backing it.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct MemoryRegionState {
+    /// Guest physical address of the memory region.
+    pub guest_phys_addr: u64,
+    /// Size of the memory region.
+    pub memory_size: u64,
+    /// Virtual address of the memory region in the VMM.
+    pub userspace_addr: u64,
+    /// Offset of the memory region in the memory mapped.
+    pub mmap_offset: u64,
+}
+
+impl Versionize for MemoryRegionState {
+    fn serialize(&self, buf: &mut Vec<u8>, version: u16) {
+        self.guest_phys_addr.serialize(buf, version);
+        self.memory_size.serialize(buf, version);
+        self.userspace_addr.serialize(buf, version);
+        self.mmap_offset.serialize(buf, version);
+    }
+
+    fn deserialize(input: &mut &[u8], version: u16) -> Result<Self> {
+        Ok(MemoryRegionState {
+            guest_phys_addr: u64::deserialize(input, version)?,
+            memory_size: u64::deserialize(input, version)?,
+            userspace_addr: u64::deserialize(input, version)?,
+            mmap_offset: u64::deserialize(input, version)?,
+        })
+    }
+}
+
+/// Configuration of a vring recorded by a `StateRecorder`.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct VringState {
+    /// Size set by SET_VRING_NUM.
+    pub size: Option<u16>,
+    /// Addresses set by SET_VRING_ADDR.
+    pub config: Option<VringConfigData>,
+    /// Base set by SET_VRING_BASE or returned by GET_VRING_BASE.
+    pub base: Option<u16>,
+}
+
+impl Versionize for VringState {
+    fn serialize(&self, buf: &mut Vec<u8>, version: u16) {
+        self.size.serialize(buf, version);
+        self.config.serialize(buf, version);
+        self.base.serialize(buf, version);
+    }
+
+    fn deserialize(input: &mut &[u8], version: u16) -> Result<Self> {
+        Ok(VringState {
+            size: Option::deserialize(input, version)?,
+            config: Option::deserialize(input, version)?,
+            base: Option::deserialize(input, version)?,
+        })
+    }
+}
+
+/// State of a vhost backend recorded by a `StateRecorder`.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct BackendState {
+    /// Whether SET_OWNER has been sent.
+    pub owned: bool,
+    /// Features acked by SET_FEATURES.
+    pub features: Option<u64>,
+    /// Memory table set by SET_MEM_TABLE.
+    pub regions: Vec<MemoryRegionState>,
+    /// Configuration of the vrings, indexed by queue.
+    pub vrings: Vec<VringState>,
+}
+
+impl BackendState {
+    fn vring(&mut self, queue_index: usize) -> &mut VringState {
+        if self.vrings.len() <= queue_index {
+            self.vrings.resize(queue_index + 1, VringState::default());
+        }
+        &mut self.vrings[queue_index]
+    }
+}
+
+impl Versionize for BackendState {
+    fn serialize(&self, buf: &mut Vec<u8>, version: u16) {
+        self.owned.serialize(buf, version);
+        self.features.serialize(buf, version);
+        self.regions.serialize(buf, version);
+        self.vrings.serialize(buf, version);
+    }
+
+    fn deserialize(input: &mut &[u8], version: u16) -> Result<Self> {
+        Ok(BackendState {
+            owned: bool::deserialize(input, version)?,
+            features: Option::deserialize(input, version)?,
+            regions: Vec::deserialize(input, version)?,
+            vrings: Vec::deserialize(input, version)?,
+        })
+    }
+}
+
+/// Vhost backend recording the state configured through it, to make it `Snapshottable`.
+///
+/// This is meant for the kernel backends, whose memory table isn't backed by file descriptors:
+/// the memory regions are restored without them. The vrings are expected to be stopped when
+/// taking a snapshot, which queries their bases with GET_VRING_BASE.
+pub struct StateRecorder<B: VhostBackend> {
+    backend: B,
+    state: BackendState,
+}
+
+impl<B: VhostBackend> StateRecorder<B> {
+    /// Wrap `backend`, which hasn't been configured yet.
+    pub fn new(backend: B) -> Self {
+        StateRecorder {
+            backend,
+            state: BackendState::default(),
+        }
+    }
+
+    /// Get the state recorded so far.
+    pub fn state(&self) -> &BackendState {
+        &self.state
+    }
+
+    /// Unwrap the backend.
+    pub fn into_inner(self) -> B {
+        self.backend
+    }
+
+    /// Restore the state saved in `snapshot`, for guest memory mapped at other addresses in the
+    /// new VMM.
+    ///
+    /// `regions` replace the memory table of the snapshot, and the vring addresses are translated
+    /// from the virtual addresses of the old regions to those of the new regions holding the same
+    /// guest physical addresses.
+    pub fn restore_with_regions(
+        &mut self,
+        snapshot: &Snapshot,
+        regions: &[VhostUserMemoryRegionInfo],
+    ) -> Result<()> {
+        let mut state: BackendState = snapshot.to_state(STATE_RECORDER_ID)?;
+        for vring in state.vrings.iter_mut() {
+            if let Some(ref mut config) = vring.config {
+                config.desc_table_addr = translate(&state.regions, regions, config.desc_table_addr)
+                    .ok_or(Error::DescriptorTableAddress)?;
+                config.used_ring_addr = translate(&state.regions, regions, config.used_ring_addr)
+                    .ok_or(Error::UsedAddress)?;
+                config.avail_ring_addr = translate(&state.regions, regions, config.avail_ring_addr)
+                    .ok_or(Error::AvailAddress)?;
+            }
+        }
+        state.regions = regions
+            .iter()
+            .map(|region| MemoryRegionState {
+                guest_phys_addr: region.guest_phys_addr,
+                memory_size: region.memory_size,
+                userspace_addr: region.userspace_addr,
+                mmap_offset: region.mmap_offset,
+            })
+            .collect();
+        self.replay(state, regions)
+    }
+
+    fn replay(&mut self, state: BackendState, regions: &[VhostUserMemoryRegionInfo]) -> Result<()> {
+        if state.owned {
+            self.backend.set_owner()?;
+        }
+        if let Some(features) = state.features {
+            self.backend.set_features(features)?;
+        }
+        if !regions.is_empty() {
+            self.backend.set_mem_table(regions)?;
+        }
+        for (index, vring) in state.vrings.iter().enumerate() {
+            if let Some(size) = vring.size {
+                self.backend.set_vring_num(index, size)?;
+            }
+            if let Some(ref config) = vring.config {
+                self.backend.set_vring_addr(index, config)?;
+            }
+            if let Some(base) = vring.base {
+                self.backend.set_vring_base(index, base)?;
+            }
+        }
+        self.state = state;
+        Ok(())
+    }
+}
+
+// Translate the virtual address `addr` of the regions `from` to the regions `to`.
+fn translate(
+    from: &[MemoryRegionState],
+    to: &[VhostUserMemoryRegionInfo],
+    addr: u64,
+) -> Option<u64> {
+    let gpa = from.iter().find_map(|region| {
+        let offset = addr.checked_sub(region.userspace_addr)?;
+        if offset < region.memory_size {
+            Some(region.guest_phys_addr + offset)
+        } else {
+            None
+        }
+    })?;
+    to.iter().find_map(|region| {
+        let offset = gpa.checked_sub(region.guest_phys_addr)?;
+        if offset < region.memory_size {
+            Some(region.userspace_addr + offset)
+        } else {
+            None
+        }
+    })
+}
+
+const STATE_RECORDER_ID: &str = "vhost-backend";
+
+impl<B: VhostBackend> Snapshottable for StateRecorder<B> {
+    fn id(&self) -> String {
+        STATE_RECORDER_ID.to_string()
+    }
+
+    fn snapshot(&mut self) -> Result<Snapshot> {
+        for index in 0..self.state.vrings.len() {
+            if self.state.vrings[index].config.is_some() {
+                self.get_vring_base(index)?;
+            }
+        }
+        Ok(Snapshot::new(
+            STATE_RECORDER_ID,
+            &self.state,
+            BackendState::version(),
+        ))
+    }
+
+    fn restore(&mut self, snapshot: &Snapshot) -> Result<()> {
+        let state: BackendState = snapshot.to_state(STATE_RECORDER_ID)?;
+        let regions: Vec<_> = state
+            .regions
+            .iter()
+            .map(|region| VhostUserMemoryRegionInfo {
+                guest_phys_addr: region.guest_phys_addr,
+                memory_size: region.memory_size,
+                userspace_addr: region.userspace_addr,
+                mmap_offset: region.mmap_offset,
+                mmap_handle: -1,
+            })
+            .collect();
+        self.replay(state, &regions)
+    }
+}
+
+impl<B: VhostBackend> Deref for StateRecorder<B> {
+    type Target = B;
+
+    fn deref(&self) -> &B {
+        &self.backend
+    }
+}
+
+// Requests sent directly to the backend aren't recorded.
+impl<B: VhostBackend> DerefMut for StateRecorder<B> {
+    fn deref_mut(&mut self) -> &mut B {
+        &mut self.backend
+    }
+}
+
+impl<B: VhostBackend> VhostOwner for StateRecorder<B> {
+    fn set_owner(&mut self) -> Result<()> {
+        self.backend.set_owner()?;
+        self.state.owned = true;
+        Ok(())
+    }
+
+    fn reset_owner(&mut self) -> Result<()> {
+        self.backend.reset_owner()?;
+        self.state = BackendState::default();
+        Ok(())
+    }
+}
+
+impl<B: VhostBackend> VhostFeatures for StateRecorder<B> {
+    fn get_features(&mut self) -> Result<u64> {
+        self.backend.get_features()
+    }
+
+    fn set_features(&mut self, features: u64) -> Result<()> {
+        self.backend.set_features(features)?;
+        self.state.features = Some(features);
+        Ok(())
+    }
+}
+
+impl<B: VhostBackend> VhostMemory for StateRecorder<B> {
+    fn set_mem_table(&mut self, regions: &[VhostUserMemoryRegionInfo]) -> Result<()> {
+        self.backend.set_mem_table(regions)?;
+        self.state.regions = regions
+            .iter()
+            .map(|region| MemoryRegionState {
+                guest_phys_addr: region.guest_phys_addr,
+                memory_size: region.memory_size,
+                userspace_addr: region.userspace_addr,
+                mmap_offset: region.mmap_offset,
+            })
+            .collect();
+        Ok(())
+    }
+
+    fn set_log_base(&mut self, base: u64, region: Option<VhostUserDirtyLogRegion>) -> Result<()> {
+        self.backend.set_log_base(base, region)
+    }
+
+    fn set_log_fd(&mut self, fd: RawFd) -> Result<()> {
+        self.backend.set_log_fd(fd)
+    }
+}
+
+impl<B: VhostBackend> VhostVring for StateRecorder<B> {
+    fn set_vring_num(&mut self, queue_index: usize, num: u16) -> Result<()> {
+        self.backend.set_vring_num(queue_index, num)?;
+        self.state.vring(queue_index).size = Some(num);
+        Ok(())
+    }
+
+    fn set_vring_addr(&mut self, queue_index: usize, config_data: &VringConfigData) -> Result<()> {
+        self.backend.set_vring_addr(queue_index, config_data)?;
+        self.state.vring(queue_index).config = Some(*config_data);
+        Ok(())
+    }
+
+    fn set_vring_base(&mut self, queue_index: usize, base: u16) -> Result<()> {
+        self.backend.set_vring_base(queue_index, base)?;
+        self.state.vring(queue_index).base = Some(base);
+        Ok(())
+    }
+
+    // Only the bases of split vrings are recorded, those of packed vrings hold the next used
+    // descriptor in bits 16-31.
+    fn get_vring_base(&mut self, queue_index: usize) -> Result<u32> {
+        let base = self.backend.get_vring_base(queue_index)?;
+        self.state.vring(queue_index).base = Some(base as u16);
+        Ok(base)
+    }
+
+    fn set_vring_call(&mut self, queue_index: usize, fd: &EventFd) -> Result<()> {
+        self.backend.set_vring_call(queue_index, fd)
+    }
+
+    fn set_vring_kick(&mut self, queue_index: usize, fd: &EventFd) -> Result<()> {
+        self.backend.set_vring_kick(queue_index, fd)
+    }
+
+    fn set_vring_err(&mut self, queue_index: usize, fd: &EventFd) -> Result<()> {
+        self.backend.set_vring_err(queue_index, fd)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_versionize() {
+        let config = VringConfigData {
+            queue_max_size: 256,
+            queue_size: 128,
+            flags: 1,
+            desc_table_addr: 0x7f00_0000_1000,
+            used_ring_addr: 0x7f00_0000_3000,
+            avail_ring_addr: 0x7f00_0000_2000,
+            log_addr: Some(0x4000),
+        };
+        let vals = vec![Some(config), None];
+        let mut buf = Vec::new();
+        vals.serialize(&mut buf, 1);
+        let mut input = buf.as_slice();
+        assert_eq!(
+            Vec::<Option<VringConfigData>>::deserialize(&mut input, 1).unwrap(),
+            vals
+        );
+        assert!(input.is_empty());
+
+        let mut input = &buf[..buf.len() - 1];
+        assert!(Vec::<Option<VringConfigData>>::deserialize(&mut input, 1).is_err());
+        let mut input: &[u8] = &[2];
+        assert!(bool::deserialize(&mut input, 1).is_err());
+    }
+
+    #[test]
+    fn test_snapshot() {
+        let state = BackendState {
+            owned: true,
+            features: Some(0x1_0000_0000),
+            ..Default::default()
+        };
+        let snapshot = Snapshot::new("test", &state, 1);
+        assert_eq!(snapshot.to_state::<BackendState>("test").unwrap(), state);
+        assert!(snapshot.to_state::<BackendState>("other").is_err());
+        let mut newer = snapshot.clone();
+        newer.version = 2;
+        assert!(newer.to_state::<BackendState>("test").is_err());
+        let mut extended = snapshot.clone();
+        extended.data.push(0);
+        assert!(extended.to_state::<BackendState>("test").is_err());
+
+        let mut buf = Vec::new();
+        snapshot.serialize(&mut buf, 1);
+        assert_eq!(
+            Snapshot::deserialize(&mut buf.as_slice(), 1).unwrap(),
+            snapshot
+        );
+    }
+
+    #[test]
+    fn test_translate() {
+        let from = [MemoryRegionState {
+            guest_phys_addr: 0x1000,
+            memory_size: 0x1000,
+            userspace_addr: 0x7f00_0000_0000,
+            mmap_offset: 0,
+        }];
+        let to = [VhostUserMemoryRegionInfo {
+            guest_phys_addr: 0,
+            memory_size: 0x2000,
+            userspace_addr: 0x7f10_0000_0000,
+            mmap_offset: 0,
+            mmap_handle: -1,
+        }];
+        assert_eq!(
+            translate(&from, &to, 0x7f00_0000_0800),
+            Some(0x7f10_0000_1800)
+        );
+        assert_eq!(translate(&from, &to, 0x7f00_0000_1000), None);
+    }
+
+    #[cfg(feature = "mock")]
+    #[test]
+    fn test_state_recorder() {
+        use crate::mock::{MockBackend, MockRequest};
+
+        let config = VringConfigData {
+            queue_max_size: 256,
+            queue_size: 256,
+            flags: 0,
+            desc_table_addr: 0x7f00_0000_1000,
+            used_ring_addr: 0x7f00_0000_3000,
+            avail_ring_addr: 0x7f00_0000_2000,
+            log_addr: None,
+        };
+        let region = VhostUserMemoryRegionInfo {
+            guest_phys_addr: 0,
+            memory_size: 0x10_0000,
+            userspace_addr: 0x7f00_0000_0000,
+            mmap_offset: 0,
+            mmap_handle: -1,
+        };
+        let mut recorder = StateRecorder::new(MockBackend::new(0x1_0000_0000));
+        recorder.set_owner().unwrap();
+        recorder.set_features(0x1_0000_0000).unwrap();
+        recorder.set_mem_table(&[region]).unwrap();
+        recorder.set_vring_num(1, 256).unwrap();
+        recorder.set_vring_addr(1, &config).unwrap();
+        recorder.set_vring_base(1, 42).unwrap();
+        let snapshot = recorder.snapshot().unwrap();
+        assert_eq!(recorder.state().vrings.len(), 2);
+
+        let mut restored = StateRecorder::new(MockBackend::new(0x1_0000_0000));
+        restored.restore(&snapshot).unwrap();
+        assert_eq!(restored.state(), recorder.state());
+        assert_eq!(
+            restored.take_requests(),
+            vec![
+                MockRequest::SetOwner,
+                MockRequest::SetFeatures(0x1_0000_0000),
+                MockRequest::SetMemTable(vec![region]),
+                MockRequest::SetVringNum(1, 256),
+                MockRequest::SetVringAddr(1, config),
+                MockRequest::SetVringBase(1, 42),
+            ]
+        );
+
+        // Guest memory mapped elsewhere in the new VMM.
+        let moved = VhostUserMemoryRegionInfo {
+            userspace_addr: 0x7f10_0000_0000,
+            ..region
+        };
+        let mut restored = StateRecorder::new(MockBackend::new(0x1_0000_0000));
+        restored.restore_with_regions(&snapshot, &[moved]).unwrap();
+        let config = restored.state().vrings[1].config.unwrap();
+        assert_eq!(config.desc_table_addr, 0x7f10_0000_1000);
+        assert_eq!(config.avail_ring_addr, 0x7f10_0000_2000);
+        let outside = VhostUserMemoryRegionInfo {
+            memory_size: 0x1000,
+            ..moved
+        };
+        let mut restored = StateRecorder::new(MockBackend::new(0x1_0000_0000));
+        assert!(restored
+            .restore_with_regions(&snapshot, &[outside])
+            .is_err());
+    }
+}
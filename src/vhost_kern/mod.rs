@@ -9,22 +9,53 @@
 //!
 //! The initial vhost implementation is a part of the Linux kernel and uses ioctl interface to
 //! communicate with userspace applications. This sub module provides ioctl based interfaces to
-//! control the in-kernel net, scsi, vsock vhost drivers.
-
+//! control the in-kernel net, scsi, vdpa, vsock vhost drivers.
+//!
+//! The ioctls common to all vhost drivers are implemented once, by the blanket implementation of
+//! `VhostBackend` for `VhostKernBackend`. A new driver only opens its device with `open_device()`
+//! and implements `VhostKernBackend` and `AsRawFd`, adding the ioctls specific to the device.
+//!
+//! The addresses of the vrings are checked against the memory of the guest, which the devices
+//! access through `VhostKernMemory`. It is implemented for the `GuestAddressSpace` of vm-memory
+//! by default, and for `RawGuestMemory` for embedders with their own memory model, which may
+//! drop the dependency on vm-memory by enabling the `vhost-kern-raw` feature instead of
+//! `vhost-kern`.
+
+#[cfg(any(
+    feature = "vhost-net",
+    feature = "vhost-scsi",
+    feature = "vhost-vdpa",
+    feature = "vhost-vsock"
+))]
+use std::fs::{File, OpenOptions};
+#[cfg(any(
+    feature = "vhost-net",
+    feature = "vhost-scsi",
+    feature = "vhost-vdpa",
+    feature = "vhost-vsock"
+))]
+use std::os::unix::fs::OpenOptionsExt;
 use std::os::unix::io::{AsRawFd, RawFd};
 
-use vm_memory::GuestAddressSpace;
+#[cfg(feature = "vm-memory")]
+use vm_memory::{Address, GuestAddressSpace, GuestMemory, GuestMemoryRegion, MemoryRegionAddress};
 use vmm_sys_util::eventfd::EventFd;
 use vmm_sys_util::ioctl::{ioctl, ioctl_with_mut_ref, ioctl_with_ptr, ioctl_with_ref};
 
 use super::{
-    Error, Result, VhostBackend, VhostUserMemoryRegionInfo, VringConfigData,
-    VHOST_MAX_MEMORY_REGIONS,
+    Error, Result, VhostFeatures, VhostOwner, VhostUserDirtyLogRegion, VhostUserMemoryRegionInfo,
+    VhostVring, VringConfigData, VHOST_MAX_MEMORY_REGIONS,
 };
 
 pub mod vhost_binding;
 use self::vhost_binding::*;
 
+#[cfg(feature = "vhost-net")]
+pub mod net;
+#[cfg(feature = "vhost-scsi")]
+pub mod scsi;
+#[cfg(feature = "vhost-vdpa")]
+pub mod vdpa;
 #[cfg(feature = "vhost-vsock")]
 pub mod vsock;
 
@@ -37,10 +68,156 @@ fn ioctl_result<T>(rc: i32, res: T) -> Result<T> {
     }
 }
 
+// Open the character device of a vhost driver.
+#[cfg(any(
+    feature = "vhost-net",
+    feature = "vhost-scsi",
+    feature = "vhost-vdpa",
+    feature = "vhost-vsock"
+))]
+fn open_device(path: &str) -> Result<File> {
+    OpenOptions::new()
+        .read(true)
+        .write(true)
+        .custom_flags(libc::O_CLOEXEC | libc::O_NONBLOCK)
+        .open(path)
+        .map_err(Error::VhostOpen)
+}
+
+/// Size of the guest pages tracked by each bit of the dirty log.
+pub const VHOST_LOG_PAGE: u64 = 0x1000;
+
+/// Get the size in bytes of the dirty log covering the guest memory `regions`.
+///
+/// Each bit of the log tracks a VHOST_LOG_PAGE sized page of guest physical memory, starting from
+/// guest physical address 0. The size is rounded up to a multiple of 8 bytes, as the logs are
+/// usually scanned 64 bits at a time.
+pub fn log_size(regions: &[VhostUserMemoryRegionInfo]) -> u64 {
+    let end = regions
+        .iter()
+        .map(|region| region.guest_phys_addr.saturating_add(region.memory_size))
+        .max()
+        .unwrap_or(0);
+    end.div_ceil(VHOST_LOG_PAGE).div_ceil(64) * 8
+}
+
+// Check the alignment of the addresses of a split ring, required by the virtio specification.
+fn check_vring_alignment(config_data: &VringConfigData) -> Result<()> {
+    if config_data.desc_table_addr & 0xf != 0 {
+        Err(Error::DescriptorTableAddress)
+    } else if config_data.avail_ring_addr & 0x1 != 0 {
+        Err(Error::AvailAddress)
+    } else if config_data.used_ring_addr & 0x3 != 0 {
+        Err(Error::UsedAddress)
+    } else {
+        Ok(())
+    }
+}
+
+// Check whether the `size` bytes at `addr` are contained in a single region of `mem`, with
+// `addr` being a virtual address of the VMM if `hva` is set, or a guest physical address.
+#[cfg(feature = "vm-memory")]
+fn is_range_in_region<M: GuestMemory>(mem: &M, addr: u64, size: u64, hva: bool) -> bool {
+    let end = match addr.checked_add(size) {
+        Some(end) => end,
+        None => return false,
+    };
+    mem.map_and_fold(
+        false,
+        |(_, region)| {
+            let base = if hva {
+                match region.get_host_address(MemoryRegionAddress(0)) {
+                    Ok(ptr) => ptr as u64,
+                    Err(_) => return false,
+                }
+            } else {
+                region.start_addr().raw_value()
+            };
+            addr >= base && base.checked_add(region.len()).is_some_and(|v| end <= v)
+        },
+        |found, in_region| found || in_region,
+    )
+}
+
+/// Memory of the guest, which the addresses of the vrings are checked against.
+pub trait VhostKernMemory {
+    /// Check whether the `size` bytes at the virtual address `addr` of the VMM are contained in
+    /// a single region of the guest memory.
+    fn is_hva_range_valid(&self, addr: u64, size: u64) -> bool;
+
+    /// Check whether the `size` bytes at the guest physical address `addr` are contained in a
+    /// single region of the guest memory.
+    fn is_gpa_range_valid(&self, addr: u64, size: u64) -> bool;
+}
+
+#[cfg(feature = "vm-memory")]
+impl<AS: GuestAddressSpace> VhostKernMemory for AS {
+    fn is_hva_range_valid(&self, addr: u64, size: u64) -> bool {
+        is_range_in_region(&*self.memory(), addr, size, true)
+    }
+
+    fn is_gpa_range_valid(&self, addr: u64, size: u64) -> bool {
+        is_range_in_region(&*self.memory(), addr, size, false)
+    }
+}
+
+/// Guest memory described by the raw host addresses of its regions.
+///
+/// The regions are usually the ones passed to `set_mem_table()`, and must be updated by the
+/// embedder whenever the memory table of the device changes.
+#[derive(Clone, Debug, Default)]
+pub struct RawGuestMemory {
+    regions: Vec<VhostUserMemoryRegionInfo>,
+}
+
+impl RawGuestMemory {
+    /// Create the guest memory from its regions.
+    pub fn new(regions: Vec<VhostUserMemoryRegionInfo>) -> Self {
+        RawGuestMemory { regions }
+    }
+
+    /// Get the regions of the guest memory.
+    pub fn regions(&self) -> &[VhostUserMemoryRegionInfo] {
+        &self.regions
+    }
+
+    /// Replace the regions of the guest memory.
+    pub fn set_regions(&mut self, regions: Vec<VhostUserMemoryRegionInfo>) {
+        self.regions = regions;
+    }
+
+    fn is_range_in_region<F>(&self, addr: u64, size: u64, base: F) -> bool
+    where
+        F: Fn(&VhostUserMemoryRegionInfo) -> u64,
+    {
+        let end = match addr.checked_add(size) {
+            Some(end) => end,
+            None => return false,
+        };
+        self.regions.iter().any(|region| {
+            let start = base(region);
+            addr >= start
+                && start
+                    .checked_add(region.memory_size)
+                    .is_some_and(|v| end <= v)
+        })
+    }
+}
+
+impl VhostKernMemory for RawGuestMemory {
+    fn is_hva_range_valid(&self, addr: u64, size: u64) -> bool {
+        self.is_range_in_region(addr, size, |region| region.userspace_addr)
+    }
+
+    fn is_gpa_range_valid(&self, addr: u64, size: u64) -> bool {
+        self.is_range_in_region(addr, size, |region| region.guest_phys_addr)
+    }
+}
+
 /// Represent an in-kernel vhost device backend.
 pub trait VhostKernBackend: AsRawFd {
     /// Assoicated type to access guest memory.
-    type AS: GuestAddressSpace;
+    type AS: VhostKernMemory;
 
     /// Get the object to access the guest's memory.
     fn mem(&self) -> &Self::AS;
@@ -55,36 +232,280 @@ pub trait VhostKernBackend: AsRawFd {
             return false;
         }
 
-        // TODO: the GuestMemory trait lacks of method to look up GPA by HVA,
-        // so there's no way to validate HVAs. Please extend vm-memory crate
-        // first.
-        /*
-        let desc_table_size = 16 * u64::from(queue_size) as GuestUsize;
-        let avail_ring_size = 6 + 2 * u64::from(queue_size) as GuestUsize;
-        let used_ring_size = 6 + 8 * u64::from(queue_size) as GuestUsize;
-        if GuestAddress(config_data.desc_table_addr)
-            .checked_add(desc_table_size)
-            .map_or(true, |v| !self.mem().address_in_range(v))
-        {
-            false
-        } else if GuestAddress(config_data.avail_ring_addr)
-            .checked_add(avail_ring_size)
-            .map_or(true, |v| !self.mem().address_in_range(v))
+        config_data.is_log_addr_valid()
+    }
+
+    /// Check the addresses of a split ring before setting them.
+    ///
+    /// The descriptor table, available and used rings are virtual addresses of the VMM, which
+    /// must be aligned and each contained in a single region of the guest memory. When logging
+    /// is enabled, the log address is the guest physical address of the used ring.
+    fn check_vring_addr(&self, config_data: &VringConfigData) -> Result<()> {
+        check_vring_alignment(config_data)?;
+        let mem = self.mem();
+        let queue_size = u64::from(config_data.queue_size);
+        if !mem.is_hva_range_valid(config_data.desc_table_addr, 16 * queue_size) {
+            return Err(Error::DescriptorTableAddress);
+        }
+        if !mem.is_hva_range_valid(config_data.avail_ring_addr, 6 + 2 * queue_size) {
+            return Err(Error::AvailAddress);
+        }
+        let used_ring_size = 6 + 8 * queue_size;
+        if !mem.is_hva_range_valid(config_data.used_ring_addr, used_ring_size) {
+            return Err(Error::UsedAddress);
+        }
+        if config_data.flags & 0x1 != 0
+            && !mem.is_gpa_range_valid(config_data.get_log_addr(), used_ring_size)
         {
-            false
-        } else if GuestAddress(config_data.used_ring_addr)
-            .checked_add(used_ring_size)
-            .map_or(true, |v| !self.mem().address_in_range(v))
+            return Err(Error::LogAddress);
+        }
+        Ok(())
+    }
+
+    /// Check whether the packed ring configuration is valid.
+    ///
+    /// Unlike split rings, the size of packed rings doesn't need to be a power of 2.
+    fn is_valid_packed(&self, config_data: &VringConfigData) -> bool {
+        let queue_size = config_data.queue_size;
+        if queue_size > config_data.queue_max_size
+            || queue_size == 0
+            || queue_size > 0x8000
+            || config_data.desc_table_addr & 0xf != 0
+            || config_data.avail_ring_addr & 0x3 != 0
+            || config_data.used_ring_addr & 0x3 != 0
         {
-            false
+            return false;
         }
-        */
 
         config_data.is_log_addr_valid()
     }
+
+    /// Set the addresses of a packed ring, once VIRTIO_F_RING_PACKED has been negotiated.
+    ///
+    /// For packed rings, `desc_table_addr` is the address of the descriptor ring,
+    /// `avail_ring_addr` the address of the driver event suppression area and `used_ring_addr`
+    /// the address of the device event suppression area.
+    fn set_vring_addr_packed(&self, queue_index: usize, config_data: &VringConfigData) -> Result<()>
+    where
+        Self: Sized,
+    {
+        if !self.is_valid_packed(config_data) {
+            return Err(Error::InvalidQueue);
+        }
+
+        let vring_addr = vhost_vring_addr {
+            index: queue_index as u32,
+            flags: config_data.flags,
+            desc_user_addr: config_data.desc_table_addr,
+            used_user_addr: config_data.used_ring_addr,
+            avail_user_addr: config_data.avail_ring_addr,
+            log_guest_addr: config_data.get_log_addr(),
+        };
+
+        // This ioctl is called on a valid vhost fd and has its return value checked.
+        let ret = unsafe { ioctl_with_ref(self, VHOST_SET_VRING_ADDR(), &vring_addr) };
+        ioctl_result(ret, ())
+    }
+
+    /// Set the position to restart a packed ring from.
+    fn set_vring_base_packed(&self, queue_index: usize, base: VringPackedBase) -> Result<()>
+    where
+        Self: Sized,
+    {
+        let vring_state = vhost_vring_state {
+            index: queue_index as u32,
+            num: base.to_vring_base(),
+        };
+
+        // This ioctl is called on a valid vhost fd and has its return value checked.
+        let ret = unsafe { ioctl_with_ref(self, VHOST_SET_VRING_BASE(), &vring_state) };
+        ioctl_result(ret, ())
+    }
+
+    /// Stop a packed ring and get its current position.
+    fn get_vring_base_packed(&self, queue_index: usize) -> Result<VringPackedBase>
+    where
+        Self: Sized,
+    {
+        let mut vring_state = vhost_vring_state {
+            index: queue_index as u32,
+            num: 0,
+        };
+        // This ioctl is called on a valid vhost fd and has its return value checked.
+        let ret = unsafe { ioctl_with_mut_ref(self, VHOST_GET_VRING_BASE(), &mut vring_state) };
+        ioctl_result(ret, VringPackedBase::from_vring_base(vring_state.num))
+    }
+
+    /// Set the endianness of a legacy vring, for drivers whose endianness differs from the host.
+    ///
+    /// Only supported by kernels built with CONFIG_VHOST_CROSS_ENDIAN_LEGACY, and must be called
+    /// while the vring is stopped.
+    fn set_vring_endian(&self, queue_index: usize, big_endian: bool) -> Result<()>
+    where
+        Self: Sized,
+    {
+        let vring_state = vhost_vring_state {
+            index: queue_index as u32,
+            num: if big_endian {
+                VHOST_VRING_BIG_ENDIAN
+            } else {
+                VHOST_VRING_LITTLE_ENDIAN
+            },
+        };
+
+        // This ioctl is called on a valid vhost fd and has its return value checked.
+        let ret = unsafe { ioctl_with_ref(self, VHOST_SET_VRING_ENDIAN(), &vring_state) };
+        ioctl_result(ret, ())
+    }
+
+    /// Check whether a legacy vring is big endian.
+    fn get_vring_endian(&self, queue_index: usize) -> Result<bool>
+    where
+        Self: Sized,
+    {
+        let mut vring_state = vhost_vring_state {
+            index: queue_index as u32,
+            num: 0,
+        };
+
+        // This ioctl is called on a valid vhost fd and has its return value checked.
+        let ret = unsafe { ioctl_with_mut_ref(self, VHOST_GET_VRING_ENDIAN(), &mut vring_state) };
+        ioctl_result(ret, vring_state.num == VHOST_VRING_BIG_ENDIAN)
+    }
+
+    /// Create a new vhost worker thread for the device, and return its id.
+    ///
+    /// The device starts with a single worker, serving all its vrings. Vrings may then be moved
+    /// to other workers with `attach_vring_worker()`, to spread the load of the device over
+    /// several kernel threads.
+    fn new_worker(&self) -> Result<u32>
+    where
+        Self: Sized,
+    {
+        let mut state = vhost_worker_state::default();
+
+        // This ioctl is called on a valid vhost fd and has its return value checked.
+        let ret = unsafe { ioctl_with_mut_ref(self, VHOST_NEW_WORKER(), &mut state) };
+        ioctl_result(ret, state.worker_id)
+    }
+
+    /// Free a worker thread created by `new_worker()`, which must not serve any vring.
+    fn free_worker(&self, worker_id: u32) -> Result<()>
+    where
+        Self: Sized,
+    {
+        let state = vhost_worker_state { worker_id };
+
+        // This ioctl is called on a valid vhost fd and has its return value checked.
+        let ret = unsafe { ioctl_with_ref(self, VHOST_FREE_WORKER(), &state) };
+        ioctl_result(ret, ())
+    }
+
+    /// Move a vring to a worker thread.
+    fn attach_vring_worker(&self, queue_index: usize, worker_id: u32) -> Result<()>
+    where
+        Self: Sized,
+    {
+        let vring_worker = vhost_vring_worker {
+            index: queue_index as u32,
+            worker_id,
+        };
+
+        // This ioctl is called on a valid vhost fd and has its return value checked.
+        let ret = unsafe { ioctl_with_ref(self, VHOST_ATTACH_VRING_WORKER(), &vring_worker) };
+        ioctl_result(ret, ())
+    }
+
+    /// Get the id of the worker thread serving a vring.
+    fn get_vring_worker(&self, queue_index: usize) -> Result<u32>
+    where
+        Self: Sized,
+    {
+        let mut vring_worker = vhost_vring_worker {
+            index: queue_index as u32,
+            worker_id: 0,
+        };
+
+        // This ioctl is called on a valid vhost fd and has its return value checked.
+        let ret = unsafe { ioctl_with_mut_ref(self, VHOST_GET_VRING_WORKER(), &mut vring_worker) };
+        ioctl_result(ret, vring_worker.worker_id)
+    }
+
+    /// Set the time, in microseconds, the vhost worker busy polls the vring for new buffers
+    /// before waiting for a kick. A timeout of 0 disables busy polling.
+    ///
+    /// Only vhost-net makes use of the timeout so far, and it must be set while the vring is
+    /// stopped.
+    fn set_vring_busyloop_timeout(&self, queue_index: usize, timeout: u32) -> Result<()>
+    where
+        Self: Sized,
+    {
+        let vring_state = vhost_vring_state {
+            index: queue_index as u32,
+            num: timeout,
+        };
+
+        // This ioctl is called on a valid vhost fd and has its return value checked.
+        let ret = unsafe { ioctl_with_ref(self, VHOST_SET_VRING_BUSYLOOP_TIMEOUT(), &vring_state) };
+        ioctl_result(ret, ())
+    }
+
+    /// Get the busy polling timeout of the vring, in microseconds.
+    fn get_vring_busyloop_timeout(&self, queue_index: usize) -> Result<u32>
+    where
+        Self: Sized,
+    {
+        let mut vring_state = vhost_vring_state {
+            index: queue_index as u32,
+            num: 0,
+        };
+
+        // This ioctl is called on a valid vhost fd and has its return value checked.
+        let ret = unsafe {
+            ioctl_with_mut_ref(self, VHOST_GET_VRING_BUSYLOOP_TIMEOUT(), &mut vring_state)
+        };
+        ioctl_result(ret, vring_state.num)
+    }
+}
+
+/// Position of a packed ring, as exchanged with the vhost drivers by the VHOST_SET_VRING_BASE
+/// and VHOST_GET_VRING_BASE ioctls.
+///
+/// The drivers encode the next available index and its wrap counter in bits 0-15 of the vring
+/// state, and the next used index and its wrap counter in bits 16-31, with the wrap counters in
+/// the most significant bit of each half.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct VringPackedBase {
+    /// Index of the next descriptor to look for available buffers.
+    pub avail_idx: u16,
+    /// Wrap counter of the available index.
+    pub avail_wrap_counter: bool,
+    /// Index of the next descriptor to put used buffers into.
+    pub used_idx: u16,
+    /// Wrap counter of the used index.
+    pub used_wrap_counter: bool,
+}
+
+impl VringPackedBase {
+    /// Encode the position as a vring state number.
+    pub fn to_vring_base(&self) -> u32 {
+        let avail = u32::from(self.avail_idx & 0x7fff) | (u32::from(self.avail_wrap_counter) << 15);
+        let used = u32::from(self.used_idx & 0x7fff) | (u32::from(self.used_wrap_counter) << 15);
+        avail | (used << 16)
+    }
+
+    /// Decode the position from a vring state number.
+    pub fn from_vring_base(num: u32) -> Self {
+        VringPackedBase {
+            avail_idx: (num & 0x7fff) as u16,
+            avail_wrap_counter: num & 0x8000 != 0,
+            used_idx: ((num >> 16) & 0x7fff) as u16,
+            used_wrap_counter: num & 0x8000_0000 != 0,
+        }
+    }
 }
 
-impl<T: VhostKernBackend> VhostBackend for T {
+impl<T: VhostKernBackend> VhostOwner for T {
     /// Set the current process as the owner of this file descriptor.
     /// This must be run before any other vhost ioctls.
     fn set_owner(&mut self) -> Result<()> {
@@ -98,7 +519,9 @@ impl<T: VhostKernBackend> VhostBackend for T {
         let ret = unsafe { ioctl(self, VHOST_RESET_OWNER()) };
         ioctl_result(ret, ())
     }
+}
 
+impl<T: VhostKernBackend> VhostFeatures for T {
     /// Get a bitmask of supported virtio/vhost features.
     fn get_features(&mut self) -> Result<u64> {
         let mut avail_features: u64 = 0;
@@ -117,7 +540,9 @@ impl<T: VhostKernBackend> VhostBackend for T {
         let ret = unsafe { ioctl_with_ref(self, VHOST_SET_FEATURES(), &features) };
         ioctl_result(ret, ())
     }
+}
 
+impl<T: VhostKernBackend> super::VhostMemory for T {
     /// Set the guest memory mappings for vhost to use.
     fn set_mem_table(&mut self, regions: &[VhostUserMemoryRegionInfo]) -> Result<()> {
         if regions.is_empty() || regions.len() > VHOST_MAX_MEMORY_REGIONS {
@@ -146,10 +571,14 @@ impl<T: VhostKernBackend> VhostBackend for T {
 
     /// Set base address for page modification logging.
     ///
+    /// Once VHOST_F_LOG_ALL has been acked, the driver logs the pages it writes into the log,
+    /// which must be at least `log_size()` bytes large for the current memory table.
+    ///
     /// # Arguments
-    /// * `base` - Base address for page modification logging.
-    fn set_log_base(&mut self, base: u64, fd: Option<RawFd>) -> Result<()> {
-        if fd.is_some() {
+    /// * `base` - Virtual address of the log in the VMM.
+    /// * `region` - Shared memory regions aren't supported by the vhost kernel drivers.
+    fn set_log_base(&mut self, base: u64, region: Option<VhostUserDirtyLogRegion>) -> Result<()> {
+        if region.is_some() {
             return Err(Error::LogAddress);
         }
 
@@ -165,7 +594,9 @@ impl<T: VhostKernBackend> VhostBackend for T {
         let ret = unsafe { ioctl_with_ref(self, VHOST_SET_LOG_FD(), &val) };
         ioctl_result(ret, ())
     }
+}
 
+impl<T: VhostKernBackend> VhostVring for T {
     /// Set the number of descriptors in the vring.
     ///
     /// # Arguments
@@ -191,6 +622,7 @@ impl<T: VhostKernBackend> VhostBackend for T {
         if !self.is_valid(config_data) {
             return Err(Error::InvalidQueue);
         }
+        self.check_vring_addr(config_data)?;
 
         let vring_addr = vhost_vring_addr {
             index: queue_index as u32,
@@ -223,14 +655,17 @@ impl<T: VhostKernBackend> VhostBackend for T {
         ioctl_result(ret, ())
     }
 
-    /// Get a bitmask of supported virtio/vhost features.
+    /// Stop the vring and get the index of the next available descriptor to process.
+    ///
+    /// # Arguments
+    /// * `queue_index` - Index of the queue to query.
     fn get_vring_base(&mut self, queue_index: usize) -> Result<u32> {
-        let vring_state = vhost_vring_state {
+        let mut vring_state = vhost_vring_state {
             index: queue_index as u32,
             num: 0,
         };
         // This ioctl is called on a valid vhost fd and has its return value checked.
-        let ret = unsafe { ioctl_with_ref(self, VHOST_GET_VRING_BASE(), &vring_state) };
+        let ret = unsafe { ioctl_with_mut_ref(self, VHOST_GET_VRING_BASE(), &mut vring_state) };
         ioctl_result(ret, vring_state.num)
     }
 
@@ -283,3 +718,203 @@ impl<T: VhostKernBackend> VhostBackend for T {
         ioctl_result(ret, ())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[cfg(feature = "vm-memory")]
+    use std::sync::Arc;
+    #[cfg(feature = "vm-memory")]
+    use vm_memory::{GuestAddress, GuestMemoryMmap};
+
+    #[cfg(feature = "vm-memory")]
+    struct DummyBackend {
+        mem: Arc<GuestMemoryMmap>,
+    }
+
+    #[cfg(feature = "vm-memory")]
+    impl AsRawFd for DummyBackend {
+        fn as_raw_fd(&self) -> RawFd {
+            -1
+        }
+    }
+
+    #[cfg(feature = "vm-memory")]
+    impl VhostKernBackend for DummyBackend {
+        type AS = Arc<GuestMemoryMmap>;
+
+        fn mem(&self) -> &Self::AS {
+            &self.mem
+        }
+    }
+
+    #[test]
+    fn test_log_size() {
+        let region = |guest_phys_addr, memory_size| VhostUserMemoryRegionInfo {
+            guest_phys_addr,
+            memory_size,
+            userspace_addr: 0,
+            mmap_offset: 0,
+            mmap_handle: -1,
+        };
+        assert_eq!(log_size(&[]), 0);
+        assert_eq!(log_size(&[region(0, 0x1000)]), 8);
+        assert_eq!(log_size(&[region(0, 0x40_0000)]), 128);
+        assert_eq!(log_size(&[region(0, 0x40_0001)]), 136);
+        assert_eq!(
+            log_size(&[region(0x1_0000_0000, 0x1000), region(0, 0x10_0000)]),
+            0x2_0008
+        );
+    }
+
+    struct DummyRawBackend {
+        mem: RawGuestMemory,
+    }
+
+    impl AsRawFd for DummyRawBackend {
+        fn as_raw_fd(&self) -> RawFd {
+            -1
+        }
+    }
+
+    impl VhostKernBackend for DummyRawBackend {
+        type AS = RawGuestMemory;
+
+        fn mem(&self) -> &Self::AS {
+            &self.mem
+        }
+    }
+
+    #[cfg(feature = "vm-memory")]
+    #[test]
+    fn test_check_vring_addr() {
+        let mem = GuestMemoryMmap::from_ranges(&[
+            (GuestAddress(0), 0x10000),
+            (GuestAddress(0x10000), 0x10000),
+        ])
+        .unwrap();
+        let hva = |gpa: u64| mem.get_host_address(GuestAddress(gpa)).unwrap() as u64;
+        let mut config = VringConfigData {
+            queue_max_size: 256,
+            queue_size: 256,
+            flags: 0,
+            desc_table_addr: hva(0x1000),
+            used_ring_addr: hva(0x3000),
+            avail_ring_addr: hva(0x2000),
+            log_addr: None,
+        };
+        let backend = DummyBackend {
+            mem: Arc::new(mem.clone()),
+        };
+        backend.check_vring_addr(&config).unwrap();
+
+        config.desc_table_addr = hva(0x1008);
+        match backend.check_vring_addr(&config) {
+            Err(Error::DescriptorTableAddress) => {}
+            _ => panic!("misaligned descriptor table accepted"),
+        }
+        // The descriptor table would span both regions, which may not be contiguous in the VMM.
+        config.desc_table_addr = hva(0xf800);
+        match backend.check_vring_addr(&config) {
+            Err(Error::DescriptorTableAddress) => {}
+            _ => panic!("descriptor table across regions accepted"),
+        }
+        config.desc_table_addr = hva(0x1000);
+
+        config.avail_ring_addr = 0x1;
+        match backend.check_vring_addr(&config) {
+            Err(Error::AvailAddress) => {}
+            _ => panic!("misaligned available ring accepted"),
+        }
+        config.avail_ring_addr = 0x2;
+        match backend.check_vring_addr(&config) {
+            Err(Error::AvailAddress) => {}
+            _ => panic!("available ring outside of the guest memory accepted"),
+        }
+        config.avail_ring_addr = hva(0x2000);
+
+        config.used_ring_addr = hva(0xfffc);
+        match backend.check_vring_addr(&config) {
+            Err(Error::UsedAddress) => {}
+            _ => panic!("used ring across regions accepted"),
+        }
+        config.used_ring_addr = hva(0x3000);
+
+        config.flags = 0x1;
+        config.log_addr = Some(0x1_f000);
+        backend.check_vring_addr(&config).unwrap();
+        config.log_addr = Some(0x1_ff00);
+        match backend.check_vring_addr(&config) {
+            Err(Error::LogAddress) => {}
+            _ => panic!("log address outside of the guest memory accepted"),
+        }
+    }
+
+    #[test]
+    fn test_raw_guest_memory() {
+        let region = |guest_phys_addr, userspace_addr| VhostUserMemoryRegionInfo {
+            guest_phys_addr,
+            memory_size: 0x10000,
+            userspace_addr,
+            mmap_offset: 0,
+            mmap_handle: -1,
+        };
+        let mut mem = RawGuestMemory::new(vec![region(0, 0x7f00_0000_0000)]);
+        assert!(mem.is_hva_range_valid(0x7f00_0000_fff0, 0x10));
+        assert!(!mem.is_hva_range_valid(0x7f00_0000_fff0, 0x11));
+        assert!(!mem.is_hva_range_valid(u64::MAX, 1));
+        assert!(mem.is_gpa_range_valid(0x1000, 0x100));
+        assert!(!mem.is_gpa_range_valid(0x7f00_0000_1000, 0x100));
+        mem.set_regions(vec![
+            region(0, 0x7f00_0000_0000),
+            region(0x10000, 0x7f00_1000_0000),
+        ]);
+        assert_eq!(mem.regions().len(), 2);
+        // The regions are contiguous in the guest, but not in the VMM.
+        assert!(!mem.is_gpa_range_valid(0xf800, 0x1000));
+        assert!(mem.is_hva_range_valid(0x7f00_1000_0000, 0x1000));
+
+        let mut config = VringConfigData {
+            queue_max_size: 256,
+            queue_size: 256,
+            flags: 0,
+            desc_table_addr: 0x7f00_0000_1000,
+            used_ring_addr: 0x7f00_0000_3000,
+            avail_ring_addr: 0x7f00_0000_2000,
+            log_addr: None,
+        };
+        let backend = DummyRawBackend { mem };
+        backend.check_vring_addr(&config).unwrap();
+        config.used_ring_addr = 0x7f00_0000_fffc;
+        match backend.check_vring_addr(&config) {
+            Err(Error::UsedAddress) => {}
+            _ => panic!("used ring across regions accepted"),
+        }
+        config.used_ring_addr = 0x7f00_1000_0000;
+        config.flags = 0x1;
+        config.log_addr = Some(0x1_ff00);
+        match backend.check_vring_addr(&config) {
+            Err(Error::LogAddress) => {}
+            _ => panic!("log address outside of the guest memory accepted"),
+        }
+    }
+
+    #[test]
+    fn test_vring_packed_base() {
+        let base = VringPackedBase {
+            avail_idx: 0x10,
+            avail_wrap_counter: true,
+            used_idx: 0x7fff,
+            used_wrap_counter: false,
+        };
+        assert_eq!(base.to_vring_base(), 0x7fff_8010);
+        assert_eq!(VringPackedBase::from_vring_base(0x7fff_8010), base);
+
+        let base = VringPackedBase::from_vring_base(0x8001_0002);
+        assert_eq!(base.avail_idx, 2);
+        assert!(!base.avail_wrap_counter);
+        assert_eq!(base.used_idx, 1);
+        assert!(base.used_wrap_counter);
+        assert_eq!(VringPackedBase::default().to_vring_base(), 0);
+    }
+}
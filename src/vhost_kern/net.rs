@@ -0,0 +1,57 @@
+// Copyright (C) 2020 Alibaba Cloud Computing. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0 or BSD-3-Clause
+
+//! Kernel-based net vhost backend.
+
+use std::fs::File;
+use std::os::unix::io::{AsRawFd, RawFd};
+
+use super::vhost_binding::{vhost_vring_file, VHOST_NET_SET_BACKEND};
+use super::{ioctl_result, open_device, Result, VhostKernBackend, VhostKernMemory};
+use crate::net::VhostNet;
+use vmm_sys_util::ioctl::ioctl_with_ref;
+
+const VHOST_NET_PATH: &str = "/dev/vhost-net";
+
+/// Handle for running VHOST_NET ioctls.
+pub struct Net<AS: VhostKernMemory> {
+    fd: File,
+    mem: AS,
+}
+
+impl<AS: VhostKernMemory> Net<AS> {
+    /// Open a handle to a new VHOST-NET instance.
+    pub fn new(mem: AS) -> Result<Self> {
+        Ok(Net {
+            fd: open_device(VHOST_NET_PATH)?,
+            mem,
+        })
+    }
+}
+
+impl<AS: VhostKernMemory> VhostNet for Net<AS> {
+    fn set_backend(&mut self, queue_index: usize, fd: Option<&File>) -> Result<()> {
+        let vring_file = vhost_vring_file {
+            index: queue_index as u32,
+            fd: fd.map_or(-1, |v| v.as_raw_fd()),
+        };
+
+        // This ioctl is called on a valid vhost-net fd and has its return value checked.
+        let ret = unsafe { ioctl_with_ref(&self.fd, VHOST_NET_SET_BACKEND(), &vring_file) };
+        ioctl_result(ret, ())
+    }
+}
+
+impl<AS: VhostKernMemory> VhostKernBackend for Net<AS> {
+    type AS = AS;
+
+    fn mem(&self) -> &Self::AS {
+        &self.mem
+    }
+}
+
+impl<AS: VhostKernMemory> AsRawFd for Net<AS> {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd.as_raw_fd()
+    }
+}
@@ -0,0 +1,125 @@
+// Copyright (C) 2020 Alibaba Cloud Computing. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0 or BSD-3-Clause
+
+//! Kernel-based scsi vhost backend.
+
+use std::fs::File;
+use std::os::raw;
+use std::os::unix::io::{AsRawFd, RawFd};
+
+use super::vhost_binding::{
+    vhost_scsi_target, VHOST_SCSI_ABI_VERSION, VHOST_SCSI_CLEAR_ENDPOINT,
+    VHOST_SCSI_GET_ABI_VERSION, VHOST_SCSI_GET_EVENTS_MISSED, VHOST_SCSI_SET_ENDPOINT,
+    VHOST_SCSI_SET_EVENTS_MISSED,
+};
+use super::{ioctl_result, open_device, Error, Result, VhostKernBackend, VhostKernMemory};
+use crate::scsi::VhostScsi;
+use vmm_sys_util::ioctl::{ioctl_with_mut_ref, ioctl_with_ref};
+
+const VHOST_SCSI_PATH: &str = "/dev/vhost-scsi";
+
+// Build the argument of the VHOST_SCSI_SET_ENDPOINT and VHOST_SCSI_CLEAR_ENDPOINT ioctls.
+fn scsi_target(wwpn: &str, tpgt: u16) -> Result<vhost_scsi_target> {
+    let mut target = vhost_scsi_target {
+        abi_version: VHOST_SCSI_ABI_VERSION as raw::c_int,
+        vhost_tpgt: tpgt,
+        ..Default::default()
+    };
+    // The WWPN must be NUL terminated.
+    let bytes = wwpn.as_bytes();
+    if bytes.is_empty() || bytes.len() >= target.vhost_wwpn.len() || bytes.contains(&0) {
+        return Err(Error::InvalidParam);
+    }
+    for (dst, src) in target.vhost_wwpn.iter_mut().zip(bytes) {
+        *dst = *src as raw::c_char;
+    }
+    Ok(target)
+}
+
+/// Handle for running VHOST_SCSI ioctls.
+pub struct Scsi<AS: VhostKernMemory> {
+    fd: File,
+    mem: AS,
+}
+
+impl<AS: VhostKernMemory> Scsi<AS> {
+    /// Open a handle to a new VHOST-SCSI instance.
+    pub fn new(mem: AS) -> Result<Self> {
+        Ok(Scsi {
+            fd: open_device(VHOST_SCSI_PATH)?,
+            mem,
+        })
+    }
+}
+
+impl<AS: VhostKernMemory> VhostScsi for Scsi<AS> {
+    fn set_endpoint(&mut self, wwpn: &str, tpgt: u16) -> Result<()> {
+        let target = scsi_target(wwpn, tpgt)?;
+        // This ioctl is called on a valid vhost-scsi fd and has its return value checked.
+        let ret = unsafe { ioctl_with_ref(&self.fd, VHOST_SCSI_SET_ENDPOINT(), &target) };
+        ioctl_result(ret, ())
+    }
+
+    fn clear_endpoint(&mut self, wwpn: &str, tpgt: u16) -> Result<()> {
+        let target = scsi_target(wwpn, tpgt)?;
+        // This ioctl is called on a valid vhost-scsi fd and has its return value checked.
+        let ret = unsafe { ioctl_with_ref(&self.fd, VHOST_SCSI_CLEAR_ENDPOINT(), &target) };
+        ioctl_result(ret, ())
+    }
+
+    fn get_abi_version(&mut self) -> Result<i32> {
+        let mut version: raw::c_int = 0;
+        // This ioctl is called on a valid vhost-scsi fd and has its return value checked.
+        let ret =
+            unsafe { ioctl_with_mut_ref(&self.fd, VHOST_SCSI_GET_ABI_VERSION(), &mut version) };
+        ioctl_result(ret, version)
+    }
+
+    fn set_events_missed(&mut self, missed: bool) -> Result<()> {
+        let val: raw::c_uint = if missed { 1 } else { 0 };
+        // This ioctl is called on a valid vhost-scsi fd and has its return value checked.
+        let ret = unsafe { ioctl_with_ref(&self.fd, VHOST_SCSI_SET_EVENTS_MISSED(), &val) };
+        ioctl_result(ret, ())
+    }
+
+    fn get_events_missed(&mut self) -> Result<bool> {
+        let mut val: raw::c_uint = 0;
+        // This ioctl is called on a valid vhost-scsi fd and has its return value checked.
+        let ret = unsafe { ioctl_with_mut_ref(&self.fd, VHOST_SCSI_GET_EVENTS_MISSED(), &mut val) };
+        ioctl_result(ret, val != 0)
+    }
+}
+
+impl<AS: VhostKernMemory> VhostKernBackend for Scsi<AS> {
+    type AS = AS;
+
+    fn mem(&self) -> &Self::AS {
+        &self.mem
+    }
+}
+
+impl<AS: VhostKernMemory> AsRawFd for Scsi<AS> {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd.as_raw_fd()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_scsi_target() {
+        let target = scsi_target("naa.500140562d3c9d78", 1).unwrap();
+        assert_eq!(target.abi_version, VHOST_SCSI_ABI_VERSION as raw::c_int);
+        assert_eq!(target.vhost_tpgt, 1);
+        assert_eq!(target.vhost_wwpn[0], b'n' as raw::c_char);
+        assert_eq!(target.vhost_wwpn[20], 0);
+
+        assert!(scsi_target("", 1).is_err());
+        assert!(scsi_target("naa\0", 1).is_err());
+        let wwpn = "n".repeat(224);
+        assert!(scsi_target(&wwpn, 1).is_err());
+        assert!(scsi_target(&wwpn[1..], 1).is_ok());
+    }
+}
@@ -0,0 +1,268 @@
+// Copyright (C) 2020 Alibaba Cloud Computing. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0 or BSD-3-Clause
+
+//! Kernel-based vdpa vhost backend.
+
+use std::fs::File;
+use std::io::Write;
+use std::mem;
+use std::os::raw;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::slice;
+
+use super::vhost_binding::*;
+use super::{
+    check_vring_alignment, ioctl_result, open_device, Error, Result, VhostKernBackend,
+    VhostKernMemory,
+};
+use crate::backend::VringConfigData;
+use crate::vdpa::{VhostVdpa, VhostVdpaIovaRange};
+use vmm_sys_util::eventfd::EventFd;
+use vmm_sys_util::ioctl::{ioctl_with_mut_ptr, ioctl_with_mut_ref, ioctl_with_ptr, ioctl_with_ref};
+
+/// Handle for running VHOST_VDPA ioctls.
+pub struct VhostKernVdpa<AS: VhostKernMemory> {
+    fd: File,
+    mem: AS,
+    backend_features_acked: u64,
+}
+
+impl<AS: VhostKernMemory> VhostKernVdpa<AS> {
+    /// Open a handle to the vhost-vdpa character device at `path`, usually
+    /// `/dev/vhost-vdpa-<N>`.
+    pub fn new(path: &str, mem: AS) -> Result<Self> {
+        Ok(VhostKernVdpa {
+            fd: open_device(path)?,
+            mem,
+            backend_features_acked: 0,
+        })
+    }
+
+    /// Get a bitmask of supported vhost backend features.
+    pub fn get_backend_features(&mut self) -> Result<u64> {
+        let mut features: u64 = 0;
+        // This ioctl is called on a valid vhost-vdpa fd and has its return value checked.
+        let ret =
+            unsafe { ioctl_with_mut_ref(&self.fd, VHOST_GET_BACKEND_FEATURES(), &mut features) };
+        ioctl_result(ret, features)
+    }
+
+    /// Inform the vhost subsystem which backend features to enable. DMA mappings need the
+    /// VHOST_BACKEND_F_IOTLB_MSG_V2 feature.
+    pub fn set_backend_features(&mut self, features: u64) -> Result<()> {
+        // This ioctl is called on a valid vhost-vdpa fd and has its return value checked.
+        let ret = unsafe { ioctl_with_ref(&self.fd, VHOST_SET_BACKEND_FEATURES(), &features) };
+        ioctl_result(ret, ())?;
+        self.backend_features_acked = features;
+        Ok(())
+    }
+
+    fn send_iotlb_msg(&mut self, iotlb: vhost_iotlb_msg) -> Result<()> {
+        if self.backend_features_acked & VHOST_BACKEND_F_IOTLB_MSG_V2 == 0 {
+            return Err(Error::InvalidOperation);
+        }
+
+        let mut msg = vhost_msg_v2 {
+            type_: VHOST_IOTLB_MSG_V2,
+            ..Default::default()
+        };
+        msg.__bindgen_anon_1.iotlb = iotlb;
+        // Safe because vhost_msg_v2 is a plain old data structure.
+        let buf = unsafe {
+            slice::from_raw_parts(
+                &msg as *const vhost_msg_v2 as *const u8,
+                mem::size_of::<vhost_msg_v2>(),
+            )
+        };
+        (&self.fd).write_all(buf).map_err(Error::IOError)
+    }
+
+    // Build the argument of the VHOST_VDPA_{GET,SET}_CONFIG ioctls, a vhost_vdpa_config header
+    // followed by the configuration space content.
+    fn config_buffer(offset: u32, len: usize) -> Result<Vec<u8>> {
+        if len > u32::MAX as usize {
+            return Err(Error::InvalidParam);
+        }
+        let mut buf = vec![0u8; mem::size_of::<vhost_vdpa_config>() + len];
+        buf[0..4].copy_from_slice(&offset.to_ne_bytes());
+        buf[4..8].copy_from_slice(&(len as u32).to_ne_bytes());
+        Ok(buf)
+    }
+}
+
+impl<AS: VhostKernMemory> VhostVdpa for VhostKernVdpa<AS> {
+    fn get_device_id(&mut self) -> Result<u32> {
+        let mut device_id: raw::c_uint = 0;
+        // This ioctl is called on a valid vhost-vdpa fd and has its return value checked.
+        let ret =
+            unsafe { ioctl_with_mut_ref(&self.fd, VHOST_VDPA_GET_DEVICE_ID(), &mut device_id) };
+        ioctl_result(ret, device_id)
+    }
+
+    fn get_status(&mut self) -> Result<u8> {
+        let mut status: raw::c_uchar = 0;
+        // This ioctl is called on a valid vhost-vdpa fd and has its return value checked.
+        let ret = unsafe { ioctl_with_mut_ref(&self.fd, VHOST_VDPA_GET_STATUS(), &mut status) };
+        ioctl_result(ret, status)
+    }
+
+    fn set_status(&mut self, status: u8) -> Result<()> {
+        // This ioctl is called on a valid vhost-vdpa fd and has its return value checked.
+        let ret = unsafe { ioctl_with_ref(&self.fd, VHOST_VDPA_SET_STATUS(), &status) };
+        ioctl_result(ret, ())
+    }
+
+    fn get_config(&mut self, offset: u32, buffer: &mut [u8]) -> Result<()> {
+        let mut config = Self::config_buffer(offset, buffer.len())?;
+        // This ioctl is called on a valid vhost-vdpa fd with a buffer large enough for the
+        // requested length, and has its return value checked.
+        let ret =
+            unsafe { ioctl_with_mut_ptr(&self.fd, VHOST_VDPA_GET_CONFIG(), config.as_mut_ptr()) };
+        ioctl_result(ret, ())?;
+        buffer.copy_from_slice(&config[mem::size_of::<vhost_vdpa_config>()..]);
+        Ok(())
+    }
+
+    fn set_config(&mut self, offset: u32, buffer: &[u8]) -> Result<()> {
+        let mut config = Self::config_buffer(offset, buffer.len())?;
+        config[mem::size_of::<vhost_vdpa_config>()..].copy_from_slice(buffer);
+        // This ioctl is called on a valid vhost-vdpa fd and has its return value checked.
+        let ret = unsafe { ioctl_with_ptr(&self.fd, VHOST_VDPA_SET_CONFIG(), config.as_ptr()) };
+        ioctl_result(ret, ())
+    }
+
+    fn set_vring_enable(&mut self, queue_index: usize, enabled: bool) -> Result<()> {
+        let vring_state = vhost_vring_state {
+            index: queue_index as u32,
+            num: enabled as u32,
+        };
+        // This ioctl is called on a valid vhost-vdpa fd and has its return value checked.
+        let ret = unsafe { ioctl_with_ref(&self.fd, VHOST_VDPA_SET_VRING_ENABLE(), &vring_state) };
+        ioctl_result(ret, ())
+    }
+
+    fn get_vring_num(&mut self) -> Result<u16> {
+        let mut vring_num: raw::c_ushort = 0;
+        // This ioctl is called on a valid vhost-vdpa fd and has its return value checked.
+        let ret =
+            unsafe { ioctl_with_mut_ref(&self.fd, VHOST_VDPA_GET_VRING_NUM(), &mut vring_num) };
+        ioctl_result(ret, vring_num)
+    }
+
+    fn set_config_call(&mut self, fd: &EventFd) -> Result<()> {
+        let event_fd: raw::c_int = fd.as_raw_fd();
+        // This ioctl is called on a valid vhost-vdpa fd and has its return value checked.
+        let ret = unsafe { ioctl_with_ref(&self.fd, VHOST_VDPA_SET_CONFIG_CALL(), &event_fd) };
+        ioctl_result(ret, ())
+    }
+
+    fn get_iova_range(&mut self) -> Result<VhostVdpaIovaRange> {
+        let mut range = vhost_vdpa_iova_range::default();
+        // This ioctl is called on a valid vhost-vdpa fd and has its return value checked.
+        let ret = unsafe { ioctl_with_mut_ref(&self.fd, VHOST_VDPA_GET_IOVA_RANGE(), &mut range) };
+        ioctl_result(
+            ret,
+            VhostVdpaIovaRange {
+                first: range.first,
+                last: range.last,
+            },
+        )
+    }
+
+    fn dma_map(&mut self, iova: u64, size: u64, vaddr: *const u8, readonly: bool) -> Result<()> {
+        let perm = if readonly {
+            VHOST_ACCESS_RO
+        } else {
+            VHOST_ACCESS_RW
+        };
+        self.send_iotlb_msg(vhost_iotlb_msg {
+            iova,
+            size,
+            uaddr: vaddr as u64,
+            perm: perm as u8,
+            type_: VHOST_IOTLB_UPDATE as u8,
+        })
+    }
+
+    fn dma_unmap(&mut self, iova: u64, size: u64) -> Result<()> {
+        self.send_iotlb_msg(vhost_iotlb_msg {
+            iova,
+            size,
+            type_: VHOST_IOTLB_INVALIDATE as u8,
+            ..Default::default()
+        })
+    }
+}
+
+impl<AS: VhostKernMemory> VhostKernBackend for VhostKernVdpa<AS> {
+    type AS = AS;
+
+    fn mem(&self) -> &Self::AS {
+        &self.mem
+    }
+
+    // The vring addresses are IOVAs mapped by the IOTLB messages, which can't be checked against
+    // the guest memory.
+    fn check_vring_addr(&self, config_data: &VringConfigData) -> Result<()> {
+        check_vring_alignment(config_data)
+    }
+}
+
+impl<AS: VhostKernMemory> AsRawFd for VhostKernVdpa<AS> {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd.as_raw_fd()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vhost_kern::RawGuestMemory;
+    use vmm_sys_util::tempfile::TempFile;
+
+    // Build a handle over a regular file, as no vhost-vdpa device is available for testing.
+    fn dummy_vdpa() -> VhostKernVdpa<RawGuestMemory> {
+        VhostKernVdpa {
+            fd: TempFile::new().unwrap().into_file(),
+            mem: RawGuestMemory::new(Vec::new()),
+            backend_features_acked: 0,
+        }
+    }
+
+    #[test]
+    fn test_config_buffer() {
+        let buf = VhostKernVdpa::<RawGuestMemory>::config_buffer(0x10, 6).unwrap();
+        assert_eq!(buf.len(), mem::size_of::<vhost_vdpa_config>() + 6);
+        assert_eq!(&buf[0..4], &0x10u32.to_ne_bytes());
+        assert_eq!(&buf[4..8], &6u32.to_ne_bytes());
+        assert!(buf[8..].iter().all(|b| *b == 0));
+
+        match VhostKernVdpa::<RawGuestMemory>::config_buffer(0, u32::MAX as usize + 1) {
+            Err(Error::InvalidParam) => {}
+            _ => panic!("oversized configuration space access accepted"),
+        }
+    }
+
+    #[test]
+    fn test_iotlb_msg_v2_not_acked() {
+        let mut vdpa = dummy_vdpa();
+        let buf = [0u8; 0x1000];
+        match vdpa.dma_map(0x1000, 0x1000, buf.as_ptr(), false) {
+            Err(Error::InvalidOperation) => {}
+            _ => panic!("DMA mapping without VHOST_BACKEND_F_IOTLB_MSG_V2"),
+        }
+        match vdpa.dma_unmap(0x1000, 0x1000) {
+            Err(Error::InvalidOperation) => {}
+            _ => panic!("DMA unmapping without VHOST_BACKEND_F_IOTLB_MSG_V2"),
+        }
+        assert_eq!(vdpa.fd.metadata().unwrap().len(), 0);
+
+        // Once acked, the messages are written to the device.
+        vdpa.backend_features_acked = VHOST_BACKEND_F_IOTLB_MSG_V2;
+        vdpa.dma_map(0x1000, 0x1000, buf.as_ptr(), true).unwrap();
+        assert_eq!(
+            vdpa.fd.metadata().unwrap().len(),
+            mem::size_of::<vhost_msg_v2>() as u64
+        );
+    }
+}
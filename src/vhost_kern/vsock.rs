@@ -7,46 +7,75 @@
 
 //! Kernel-based vsock vhost backend.
 
-use std::fs::{File, OpenOptions};
-use std::os::unix::fs::OpenOptionsExt;
+use std::fs::File;
 use std::os::unix::io::{AsRawFd, RawFd};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 
 use super::vhost_binding::{VHOST_VSOCK_SET_GUEST_CID, VHOST_VSOCK_SET_RUNNING};
-use super::{ioctl_result, Error, Result, VhostKernBackend};
-use libc;
-use vm_memory::GuestAddressSpace;
+use super::{ioctl_result, open_device, Error, Result, VhostKernBackend, VhostKernMemory};
 use vmm_sys_util::ioctl::ioctl_with_ref;
 
 const VHOST_PATH: &str = "/dev/vhost-vsock";
 
+// CIDs 0 to 2 are reserved for the hypervisor and the host, and U32_MAX is VMADDR_CID_ANY.
+const VSOCK_MIN_GUEST_CID: u64 = 3;
+const VSOCK_MAX_GUEST_CID: u64 = u32::MAX as u64 - 1;
+
+// Check that `cid` may be assigned to a guest.
+fn check_guest_cid(cid: u64) -> Result<()> {
+    if !(VSOCK_MIN_GUEST_CID..=VSOCK_MAX_GUEST_CID).contains(&cid) {
+        return Err(Error::InvalidParam);
+    }
+    Ok(())
+}
+
 /// Handle for running VHOST_VSOCK ioctls.
-pub struct Vsock<AS: GuestAddressSpace> {
+pub struct Vsock<AS: VhostKernMemory> {
     fd: File,
     mem: AS,
+    // CID assigned to the guest, 0 until assigned
+    cid: AtomicU64,
+    running: AtomicBool,
 }
 
-impl<AS: GuestAddressSpace> Vsock<AS> {
+impl<AS: VhostKernMemory> Vsock<AS> {
     /// Open a handle to a new VHOST-VSOCK instance.
     pub fn new(mem: AS) -> Result<Self> {
         Ok(Vsock {
-            fd: OpenOptions::new()
-                .read(true)
-                .write(true)
-                .custom_flags(libc::O_CLOEXEC | libc::O_NONBLOCK)
-                .open(VHOST_PATH)
-                .map_err(Error::VhostOpen)?,
+            fd: open_device(VHOST_PATH)?,
             mem,
+            cid: AtomicU64::new(0),
+            running: AtomicBool::new(false),
         })
     }
 
     /// Set the CID for the guest.  This number is used for routing all data destined for
     /// running in the guest. Each guest on a hypervisor must have an unique CID
     ///
+    /// Reserved CIDs, below 3 or VMADDR_CID_ANY and above, are rejected with
+    /// `Error::InvalidParam`.
+    ///
     /// # Arguments
     /// * `cid` - CID to assign to the guest
     pub fn set_guest_cid(&self, cid: u64) -> Result<()> {
+        check_guest_cid(cid)?;
         let ret = unsafe { ioctl_with_ref(&self.fd, VHOST_VSOCK_SET_GUEST_CID(), &cid) };
-        ioctl_result(ret, ())
+        ioctl_result(ret, ())?;
+        self.cid.store(cid, Ordering::Release);
+        Ok(())
+    }
+
+    /// Get the CID assigned to the guest by `set_guest_cid()`, if any.
+    pub fn guest_cid(&self) -> Option<u64> {
+        match self.cid.load(Ordering::Acquire) {
+            0 => None,
+            cid => Some(cid),
+        }
+    }
+
+    /// Check whether the VHOST driver has been started by `start()`.
+    pub fn is_running(&self) -> bool {
+        self.running.load(Ordering::Acquire)
     }
 
     /// Tell the VHOST driver to start performing data transfer.
@@ -62,11 +91,13 @@ impl<AS: GuestAddressSpace> Vsock<AS> {
     fn set_running(&self, running: bool) -> Result<()> {
         let on: ::std::os::raw::c_int = if running { 1 } else { 0 };
         let ret = unsafe { ioctl_with_ref(&self.fd, VHOST_VSOCK_SET_RUNNING(), &on) };
-        ioctl_result(ret, ())
+        ioctl_result(ret, ())?;
+        self.running.store(running, Ordering::Release);
+        Ok(())
     }
 }
 
-impl<AS: GuestAddressSpace> VhostKernBackend for Vsock<AS> {
+impl<AS: VhostKernMemory> VhostKernBackend for Vsock<AS> {
     type AS = AS;
 
     fn mem(&self) -> &Self::AS {
@@ -74,8 +105,22 @@ impl<AS: GuestAddressSpace> VhostKernBackend for Vsock<AS> {
     }
 }
 
-impl<AS: GuestAddressSpace> AsRawFd for Vsock<AS> {
+impl<AS: VhostKernMemory> AsRawFd for Vsock<AS> {
     fn as_raw_fd(&self) -> RawFd {
         self.fd.as_raw_fd()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_guest_cid() {
+        for cid in &[0, 1, 2, u64::from(u32::MAX), u64::from(u32::MAX) + 1] {
+            assert!(check_guest_cid(*cid).is_err());
+        }
+        check_guest_cid(3).unwrap();
+        check_guest_cid(u64::from(u32::MAX) - 1).unwrap();
+    }
+}
@@ -0,0 +1,748 @@
+// Copyright (C) 2020 Alibaba Cloud Computing. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Asynchronous vhost-user endpoints, built on `std::future` without depending on any runtime.
+//!
+//! The endpoints switch their connection to non-blocking mode, and return futures which send and
+//! receive the messages, along with their file descriptors, as far as the socket allows whenever
+//! they are polled. No reactor is provided: the caller monitors the file descriptor returned by
+//! `as_raw_fd()` in its own event loop, and calls `Readiness::notify()` once the connection is
+//! readable or writable, to wake up the task waiting on it.
+//!
+//! `AsyncMaster` has helpers for the requests setting up a session and its vrings. The other
+//! requests are sent with `AsyncMaster::send_request()`, and their replies decoded by the caller.
+
+use std::future::Future;
+#[cfg(feature = "vhost-user-master")]
+use std::io::ErrorKind;
+#[cfg(feature = "vhost-user-master")]
+use std::mem;
+use std::os::unix::io::{AsRawFd, RawFd};
+#[cfg(feature = "vhost-user-master")]
+use std::os::unix::net::UnixStream;
+use std::pin::Pin;
+#[cfg(feature = "vhost-user-master")]
+use std::ptr;
+#[cfg(feature = "vhost-user-master")]
+use std::slice;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker};
+
+#[cfg(feature = "vhost-user-master")]
+use vmm_sys_util::eventfd::EventFd;
+
+#[cfg(feature = "vhost-user-master")]
+use super::connection::Endpoint;
+#[cfg(feature = "vhost-user-master")]
+use super::message::*;
+#[cfg(feature = "vhost-user-master")]
+use super::Error;
+use super::Result;
+#[cfg(feature = "vhost-user-slave")]
+use super::{SlaveReqHandler, VhostUserSlaveReqHandler};
+#[cfg(feature = "vhost-user-master")]
+use crate::backend::{VhostUserMemoryRegionInfo, VringConfigData};
+
+/// Reply of the slave to a request: its body followed by the payload, and the attached file
+/// descriptors.
+#[cfg(feature = "vhost-user-master")]
+pub type Reply = (Vec<u8>, Option<Vec<RawFd>>);
+
+/// Handle to wake up the task waiting for a vhost-user connection to become ready.
+#[derive(Clone, Default)]
+pub struct Readiness {
+    waker: Arc<Mutex<Option<Waker>>>,
+}
+
+impl Readiness {
+    /// Wake up the task waiting on the connection, if any, once the event loop of the caller has
+    /// reported the connection as readable or writable.
+    pub fn notify(&self) {
+        // We unwrap() the return value to assert that we are not expecting threads to ever fail
+        // while holding the lock.
+        if let Some(waker) = self.waker.lock().unwrap().take() {
+            waker.wake();
+        }
+    }
+
+    // The waker is registered before each attempt to use the socket, so readiness reported
+    // between the attempt and the return of Poll::Pending isn't lost.
+    fn register(&self, waker: &Waker) {
+        *self.waker.lock().unwrap() = Some(waker.clone());
+    }
+}
+
+// Check whether the socket operation should be attempted again: right away when interrupted
+// by a signal, once the connection is ready when it would block, and on the next poll when
+// short of resources.
+#[cfg(feature = "vhost-user-master")]
+fn retry_kind(err: &Error) -> Option<ErrorKind> {
+    match err {
+        Error::SocketRetry(e) => Some(e.kind()),
+        _ => None,
+    }
+}
+
+// View a message structure as bytes.
+#[cfg(feature = "vhost-user-master")]
+fn as_bytes<T: Sized>(msg: &T) -> &[u8] {
+    // Safe because the message structures are plain data structures.
+    unsafe { slice::from_raw_parts(msg as *const T as *const u8, mem::size_of::<T>()) }
+}
+
+/// Asynchronous vhost-user master, sending one request at a time to the slave.
+#[cfg(feature = "vhost-user-master")]
+pub struct AsyncMaster {
+    sock: Endpoint<MasterReq>,
+    readiness: Readiness,
+    reply_ack: bool,
+    // A request has been started but not completed, so the connection is out of sync.
+    in_flight: bool,
+}
+
+#[cfg(feature = "vhost-user-master")]
+impl AsyncMaster {
+    /// Create a new instance from a Unix stream socket, switched to non-blocking mode.
+    pub fn from_stream(sock: UnixStream) -> Result<Self> {
+        let sock = Endpoint::from_stream(sock);
+        sock.set_nonblocking(true)?;
+        Ok(AsyncMaster {
+            sock,
+            readiness: Readiness::default(),
+            reply_ack: false,
+            in_flight: false,
+        })
+    }
+
+    /// Create a new instance connected to the slave listening at `path`.
+    pub fn connect(path: &str) -> Result<Self> {
+        let sock = UnixStream::connect(path).map_err(Error::SocketConnect)?;
+        Self::from_stream(sock)
+    }
+
+    /// Get the handle to wake up the task waiting on the connection.
+    pub fn readiness(&self) -> Readiness {
+        self.readiness.clone()
+    }
+
+    /// Ask the slave to acknowledge the requests without replies, once the REPLY_ACK protocol
+    /// feature has been negotiated.
+    pub fn set_reply_ack(&mut self, enabled: bool) {
+        self.reply_ack = enabled;
+    }
+
+    /// Send a request made of the header and `body`, with optional attached file descriptors,
+    /// which must stay open until the request has been sent.
+    ///
+    /// The returned future resolves to the reply of the slave, or to None for requests without
+    /// replies nor acknowledgements. Dropping it before completion leaves the connection out of
+    /// sync, and the next requests fail with InvalidOperation.
+    ///
+    /// # Return:
+    /// * - the future of the request on success.
+    /// * - InvalidParam: the body is too large, or too many file descriptors are attached.
+    /// * - InvalidOperation: a previous request hasn't been completed.
+    pub fn send_request(
+        &mut self,
+        code: MasterReq,
+        body: &[u8],
+        fds: Option<&[RawFd]>,
+    ) -> Result<Request<'_>> {
+        if body.len() > self.sock.max_msg_size()
+            || matches!(fds, Some(fds) if fds.len() > MAX_ATTACHED_FD_ENTRIES)
+        {
+            return Err(Error::InvalidParam);
+        }
+        if self.in_flight {
+            return Err(Error::InvalidOperation);
+        }
+
+        let mut hdr = VhostUserMsgHeader::new(code, 0x1, body.len() as u32);
+        hdr.set_need_reply(self.reply_ack && !code.has_reply());
+        let hdr_buf = as_bytes(&hdr);
+        let mut buf = Vec::with_capacity(hdr_buf.len() + body.len());
+        buf.extend_from_slice(hdr_buf);
+        buf.extend_from_slice(body);
+        self.in_flight = true;
+        Ok(Request {
+            master: self,
+            hdr,
+            buf,
+            fds: fds.map(|fds| fds.to_vec()),
+            sent: 0,
+        })
+    }
+
+    /// Send a request with the message structure `msg` as body.
+    pub fn send_request_with_body<T: Sized>(
+        &mut self,
+        code: MasterReq,
+        msg: &T,
+        fds: Option<&[RawFd]>,
+    ) -> Result<Request<'_>> {
+        self.send_request(code, as_bytes(msg), fds)
+    }
+
+    /// Set the current master as an owner of the session.
+    pub fn set_owner(&mut self) -> Result<Response<'_, ()>> {
+        let request = self.send_request(MasterReq::SET_OWNER, &[], None)?;
+        Ok(Response::new(request, parse_ack))
+    }
+
+    /// Release the ownership of the session.
+    pub fn reset_owner(&mut self) -> Result<Response<'_, ()>> {
+        let request = self.send_request(MasterReq::RESET_OWNER, &[], None)?;
+        Ok(Response::new(request, parse_ack))
+    }
+
+    /// Get the virtio features supported by the slave.
+    pub fn get_features(&mut self) -> Result<Response<'_, u64>> {
+        let request = self.send_request(MasterReq::GET_FEATURES, &[], None)?;
+        Ok(Response::new(request, parse_u64))
+    }
+
+    /// Enable virtio features in the slave.
+    pub fn set_features(&mut self, features: u64) -> Result<Response<'_, ()>> {
+        let val = VhostUserU64::new(features);
+        let request = self.send_request_with_body(MasterReq::SET_FEATURES, &val, None)?;
+        Ok(Response::new(request, parse_ack))
+    }
+
+    /// Get the vhost-user protocol features supported by the slave.
+    pub fn get_protocol_features(&mut self) -> Result<Response<'_, VhostUserProtocolFeatures>> {
+        let request = self.send_request(MasterReq::GET_PROTOCOL_FEATURES, &[], None)?;
+        Ok(Response::new(request, parse_protocol_features))
+    }
+
+    /// Enable vhost-user protocol features in the slave.
+    pub fn set_protocol_features(
+        &mut self,
+        features: VhostUserProtocolFeatures,
+    ) -> Result<Response<'_, ()>> {
+        let val = VhostUserU64::new(features.bits());
+        let request = self.send_request_with_body(MasterReq::SET_PROTOCOL_FEATURES, &val, None)?;
+        Ok(Response::new(request, parse_ack))
+    }
+
+    /// Get the number of queues supported by the slave, once the MQ protocol feature has been
+    /// negotiated.
+    pub fn get_queue_num(&mut self) -> Result<Response<'_, u64>> {
+        let request = self.send_request(MasterReq::GET_QUEUE_NUM, &[], None)?;
+        Ok(Response::new(request, parse_queue_num))
+    }
+
+    /// Set the memory regions of the guest, whose file descriptors are attached to the request.
+    ///
+    /// # Return:
+    /// * - the future of the request on success.
+    /// * - InvalidParam: no region, more than MAX_ATTACHED_FD_ENTRIES regions, or an empty one.
+    pub fn set_mem_table(
+        &mut self,
+        regions: &[VhostUserMemoryRegionInfo],
+    ) -> Result<Response<'_, ()>> {
+        if regions.is_empty() || regions.len() > MAX_ATTACHED_FD_ENTRIES {
+            return Err(Error::InvalidParam);
+        }
+        let mut body = as_bytes(&VhostUserMemory::new(regions.len() as u32)).to_vec();
+        let mut fds = Vec::with_capacity(regions.len());
+        for region in regions {
+            if region.memory_size == 0 || region.mmap_handle < 0 {
+                return Err(Error::InvalidParam);
+            }
+            let reg = VhostUserMemoryRegion {
+                guest_phys_addr: region.guest_phys_addr,
+                memory_size: region.memory_size,
+                user_addr: region.userspace_addr,
+                mmap_offset: region.mmap_offset,
+            };
+            body.extend_from_slice(as_bytes(&reg));
+            fds.push(region.mmap_handle);
+        }
+        let request = self.send_request(MasterReq::SET_MEM_TABLE, &body, Some(&fds))?;
+        Ok(Response::new(request, parse_ack))
+    }
+
+    /// Set the size of the vring.
+    pub fn set_vring_num(&mut self, queue_index: usize, num: u16) -> Result<Response<'_, ()>> {
+        self.send_vring_state(MasterReq::SET_VRING_NUM, queue_index, num.into())
+    }
+
+    /// Set the addresses of the descriptor table, the rings and the log of the vring.
+    pub fn set_vring_addr(
+        &mut self,
+        queue_index: usize,
+        config_data: &VringConfigData,
+    ) -> Result<Response<'_, ()>> {
+        if queue_index as u64 >= VHOST_USER_MAX_VRINGS
+            || config_data.flags & !(VhostUserVringAddrFlags::all().bits()) != 0
+        {
+            return Err(Error::InvalidParam);
+        }
+        let val = VhostUserVringAddr::from_config_data(queue_index as u32, config_data);
+        let request = self.send_request_with_body(MasterReq::SET_VRING_ADDR, &val, None)?;
+        Ok(Response::new(request, parse_ack))
+    }
+
+    /// Set the next index of the available ring the slave should process.
+    pub fn set_vring_base(&mut self, queue_index: usize, base: u16) -> Result<Response<'_, ()>> {
+        self.send_vring_state(MasterReq::SET_VRING_BASE, queue_index, base.into())
+    }
+
+    /// Stop the vring, and get the next index of its available ring the slave would process.
+    pub fn get_vring_base(&mut self, queue_index: usize) -> Result<Response<'_, u32>> {
+        if queue_index as u64 >= VHOST_USER_MAX_VRINGS {
+            return Err(Error::InvalidParam);
+        }
+        let val = VhostUserVringState::new(queue_index as u32, 0);
+        let request = self.send_request_with_body(MasterReq::GET_VRING_BASE, &val, None)?;
+        Ok(Response::new(request, parse_vring_base))
+    }
+
+    /// Set the eventfd the slave signals once buffers have been used.
+    pub fn set_vring_call<'a>(
+        &'a mut self,
+        queue_index: usize,
+        fd: &'a EventFd,
+    ) -> Result<Response<'a, ()>> {
+        self.send_vring_fd(MasterReq::SET_VRING_CALL, queue_index, fd)
+    }
+
+    /// Set the eventfd signaled once buffers have been made available.
+    pub fn set_vring_kick<'a>(
+        &'a mut self,
+        queue_index: usize,
+        fd: &'a EventFd,
+    ) -> Result<Response<'a, ()>> {
+        self.send_vring_fd(MasterReq::SET_VRING_KICK, queue_index, fd)
+    }
+
+    /// Set the eventfd the slave signals on errors.
+    pub fn set_vring_err<'a>(
+        &'a mut self,
+        queue_index: usize,
+        fd: &'a EventFd,
+    ) -> Result<Response<'a, ()>> {
+        self.send_vring_fd(MasterReq::SET_VRING_ERR, queue_index, fd)
+    }
+
+    /// Enable or disable the vring, once the PROTOCOL_FEATURES virtio feature has been
+    /// negotiated.
+    pub fn set_vring_enable(
+        &mut self,
+        queue_index: usize,
+        enable: bool,
+    ) -> Result<Response<'_, ()>> {
+        self.send_vring_state(MasterReq::SET_VRING_ENABLE, queue_index, enable.into())
+    }
+
+    fn send_vring_state(
+        &mut self,
+        code: MasterReq,
+        queue_index: usize,
+        num: u32,
+    ) -> Result<Response<'_, ()>> {
+        if queue_index as u64 >= VHOST_USER_MAX_VRINGS {
+            return Err(Error::InvalidParam);
+        }
+        let val = VhostUserVringState::new(queue_index as u32, num);
+        let request = self.send_request_with_body(code, &val, None)?;
+        Ok(Response::new(request, parse_ack))
+    }
+
+    fn send_vring_fd<'a>(
+        &'a mut self,
+        code: MasterReq,
+        queue_index: usize,
+        fd: &'a EventFd,
+    ) -> Result<Response<'a, ()>> {
+        // Bits (0-7) of the payload contain the vring index, and bit 8 is the invalid FD flag.
+        if queue_index > 0xff {
+            return Err(Error::InvalidParam);
+        }
+        let val = VhostUserU64::new(queue_index as u64);
+        let request = self.send_request_with_body(code, &val, Some(&[fd.as_raw_fd()]))?;
+        Ok(Response::new(request, parse_ack))
+    }
+}
+
+#[cfg(feature = "vhost-user-master")]
+impl AsRawFd for AsyncMaster {
+    fn as_raw_fd(&self) -> RawFd {
+        self.sock.as_raw_fd()
+    }
+}
+
+/// Future of a request sent by `AsyncMaster`, resolving to the reply of the slave.
+#[cfg(feature = "vhost-user-master")]
+#[must_use = "futures do nothing unless polled"]
+pub struct Request<'a> {
+    master: &'a mut AsyncMaster,
+    hdr: VhostUserMsgHeader<MasterReq>,
+    buf: Vec<u8>,
+    fds: Option<Vec<RawFd>>,
+    sent: usize,
+}
+
+#[cfg(feature = "vhost-user-master")]
+impl<'a> Request<'a> {
+    fn poll_send(&mut self, cx: &mut Context) -> Poll<Result<()>> {
+        while self.sent < self.buf.len() {
+            // The file descriptors are attached to the first bytes of the message.
+            let fds = if self.sent == 0 {
+                self.fds.as_deref()
+            } else {
+                None
+            };
+            match self.master.sock.send_slice(&self.buf[self.sent..], fds) {
+                Ok(0) => {
+                    let err = std::io::Error::from_raw_os_error(libc::ECONNRESET);
+                    return Poll::Ready(Err(Error::SocketBroken(err)));
+                }
+                Ok(n) => self.sent += n,
+                Err(e) => match retry_kind(&e) {
+                    Some(ErrorKind::Interrupted) => {}
+                    Some(kind) => return Self::pending(cx, kind),
+                    None => return Poll::Ready(Err(e)),
+                },
+            }
+        }
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_reply(&mut self, cx: &mut Context) -> Poll<Result<Option<Reply>>> {
+        if !self.hdr.is_need_reply() && !self.hdr.get_code().has_reply() {
+            return Poll::Ready(Ok(None));
+        }
+        match self.master.sock.handle_readable() {
+            Ok(Some((reply, body, rfds))) => {
+                if !reply.is_reply_for(&self.hdr) {
+                    Endpoint::<MasterReq>::close_rfds(rfds);
+                    return Poll::Ready(Err(Error::InvalidMessage));
+                }
+                Poll::Ready(Ok(Some((body, rfds))))
+            }
+            Ok(None) => Poll::Pending,
+            Err(e) => match retry_kind(&e) {
+                Some(kind) => Self::pending(cx, kind),
+                None => Poll::Ready(Err(e)),
+            },
+        }
+    }
+
+    // Wait for the connection to become ready, or for the next poll when the socket is short of
+    // resources, as its readiness doesn't change then.
+    fn pending<T>(cx: &mut Context, kind: ErrorKind) -> Poll<T> {
+        if kind != ErrorKind::WouldBlock {
+            cx.waker().wake_by_ref();
+        }
+        Poll::Pending
+    }
+}
+
+#[cfg(feature = "vhost-user-master")]
+impl<'a> Future for Request<'a> {
+    type Output = Result<Option<Reply>>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        self.master.readiness.register(cx.waker());
+        let res = match self.poll_send(cx) {
+            Poll::Ready(Ok(())) => self.poll_reply(cx),
+            Poll::Ready(Err(e)) => Poll::Ready(Err(e)),
+            Poll::Pending => Poll::Pending,
+        };
+        // Failed requests are completed too, as callers are expected to give up on the
+        // connection rather than to poll them again.
+        if res.is_ready() {
+            self.master.in_flight = false;
+        }
+        res
+    }
+}
+
+/// Future of a request sent by `AsyncMaster`, resolving to the decoded reply of the slave.
+#[cfg(feature = "vhost-user-master")]
+#[must_use = "futures do nothing unless polled"]
+pub struct Response<'a, T> {
+    request: Request<'a>,
+    parse: fn(MasterReq, Option<Reply>) -> Result<T>,
+}
+
+#[cfg(feature = "vhost-user-master")]
+impl<'a, T> Response<'a, T> {
+    fn new(request: Request<'a>, parse: fn(MasterReq, Option<Reply>) -> Result<T>) -> Self {
+        Response { request, parse }
+    }
+}
+
+#[cfg(feature = "vhost-user-master")]
+impl<'a, T> Future for Response<'a, T> {
+    type Output = Result<T>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let code = self.request.hdr.get_code();
+        match Pin::new(&mut self.request).poll(cx) {
+            Poll::Ready(Ok(reply)) => Poll::Ready((self.parse)(code, reply)),
+            Poll::Ready(Err(e)) => Poll::Ready(Err(e)),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+// Decode the body of a reply made of the message structure T only.
+#[cfg(feature = "vhost-user-master")]
+fn parse_body<T: Sized + VhostUserMsgValidator>(reply: Option<Reply>) -> Result<T> {
+    match reply {
+        Some((body, None)) if body.len() == mem::size_of::<T>() => {
+            // Safe because the body holds a whole T, which is a plain data structure.
+            let msg = unsafe { ptr::read_unaligned(body.as_ptr() as *const T) };
+            if !msg.is_valid() {
+                return Err(Error::InvalidMessage);
+            }
+            Ok(msg)
+        }
+        Some((_, rfds)) => {
+            Endpoint::<MasterReq>::close_rfds(rfds);
+            Err(Error::InvalidMessage)
+        }
+        None => Err(Error::InvalidMessage),
+    }
+}
+
+#[cfg(feature = "vhost-user-master")]
+fn parse_u64(_code: MasterReq, reply: Option<Reply>) -> Result<u64> {
+    parse_body::<VhostUserU64>(reply).map(|val| val.value)
+}
+
+#[cfg(feature = "vhost-user-master")]
+fn parse_protocol_features(
+    code: MasterReq,
+    reply: Option<Reply>,
+) -> Result<VhostUserProtocolFeatures> {
+    VhostUserProtocolFeatures::from_bits(parse_u64(code, reply)?).ok_or(Error::InvalidMessage)
+}
+
+#[cfg(feature = "vhost-user-master")]
+fn parse_queue_num(code: MasterReq, reply: Option<Reply>) -> Result<u64> {
+    match parse_u64(code, reply)? {
+        num if num > VHOST_USER_MAX_VRINGS => Err(Error::InvalidMessage),
+        num => Ok(num),
+    }
+}
+
+#[cfg(feature = "vhost-user-master")]
+fn parse_vring_base(_code: MasterReq, reply: Option<Reply>) -> Result<u32> {
+    parse_body::<VhostUserVringState>(reply).map(|val| val.num)
+}
+
+// Requests without replies are only acknowledged once REPLY_ACK has been negotiated.
+#[cfg(feature = "vhost-user-master")]
+fn parse_ack(code: MasterReq, reply: Option<Reply>) -> Result<()> {
+    if reply.is_none() {
+        return Ok(());
+    }
+    match parse_body::<VhostUserU64>(reply)?.value {
+        0 => Ok(()),
+        val => Err(Error::from_reply_code(val, code)),
+    }
+}
+
+/// Asynchronous vhost-user slave, handling the requests of the master as they arrive.
+#[cfg(feature = "vhost-user-slave")]
+pub struct AsyncSlaveReqHandler<S: VhostUserSlaveReqHandler> {
+    handler: SlaveReqHandler<S>,
+    readiness: Readiness,
+}
+
+#[cfg(feature = "vhost-user-slave")]
+impl<S: VhostUserSlaveReqHandler> AsyncSlaveReqHandler<S> {
+    /// Create a new instance from a request handler, whose connection is switched to
+    /// non-blocking mode.
+    pub fn new(handler: SlaveReqHandler<S>) -> Result<Self> {
+        handler.set_nonblocking(true)?;
+        Ok(AsyncSlaveReqHandler {
+            handler,
+            readiness: Readiness::default(),
+        })
+    }
+
+    /// Get the handle to wake up the task waiting on the connection.
+    pub fn readiness(&self) -> Readiness {
+        self.readiness.clone()
+    }
+
+    /// Get the request handler.
+    pub fn handler(&self) -> &SlaveReqHandler<S> {
+        &self.handler
+    }
+
+    /// Receive and handle the next request of the master.
+    ///
+    /// The returned future resolves once a request has been handled. Partially received requests
+    /// are kept by the handler, so the future may be dropped and created again at will.
+    pub fn handle_request(&mut self) -> HandleRequest<'_, S> {
+        HandleRequest { slave: self }
+    }
+}
+
+#[cfg(feature = "vhost-user-slave")]
+impl<S: VhostUserSlaveReqHandler> AsRawFd for AsyncSlaveReqHandler<S> {
+    fn as_raw_fd(&self) -> RawFd {
+        self.handler.as_raw_fd()
+    }
+}
+
+/// Future of the next request handled by `AsyncSlaveReqHandler`.
+#[cfg(feature = "vhost-user-slave")]
+#[must_use = "futures do nothing unless polled"]
+pub struct HandleRequest<'a, S: VhostUserSlaveReqHandler> {
+    slave: &'a mut AsyncSlaveReqHandler<S>,
+}
+
+#[cfg(feature = "vhost-user-slave")]
+impl<'a, S: VhostUserSlaveReqHandler> Future for HandleRequest<'a, S> {
+    type Output = Result<()>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        self.slave.readiness.register(cx.waker());
+        match self.slave.handler.handle_readable() {
+            Ok(true) => Poll::Ready(Ok(())),
+            Ok(false) => Poll::Pending,
+            Err(e) => Poll::Ready(Err(e)),
+        }
+    }
+}
+
+#[cfg(all(test, feature = "vhost-user-master", feature = "vhost-user-slave"))]
+mod tests {
+    use super::super::dummy_slave::{DummySlaveReqHandler, MAX_QUEUE_NUM, VIRTIO_FEATURES};
+    use super::super::{Listener, SessionState, SlaveListener};
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::task::Wake;
+
+    #[derive(Default)]
+    struct CountingWaker {
+        wakes: AtomicUsize,
+    }
+
+    impl Wake for CountingWaker {
+        fn wake(self: Arc<Self>) {
+            self.wakes.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    fn poll_once<F: Future + Unpin>(fut: &mut F, waker: &Arc<CountingWaker>) -> Poll<F::Output> {
+        let waker = Waker::from(waker.clone());
+        Pin::new(fut).poll(&mut Context::from_waker(&waker))
+    }
+
+    // Poll the future of a request of the master, and let the slave handle the requests sent,
+    // until the future completes.
+    fn complete<F, S>(fut: &mut F, slave: &mut AsyncSlaveReqHandler<S>) -> F::Output
+    where
+        F: Future + Unpin,
+        S: VhostUserSlaveReqHandler,
+    {
+        let waker = Arc::new(CountingWaker::default());
+        let res = loop {
+            if let Poll::Ready(res) = poll_once(fut, &waker) {
+                break res;
+            }
+            assert!(poll_once(&mut slave.handle_request(), &waker).is_ready());
+        };
+        // Requests without replies complete once sent, before the slave has handled them.
+        while let Poll::Ready(res) = poll_once(&mut slave.handle_request(), &waker) {
+            res.unwrap();
+        }
+        res
+    }
+
+    #[test]
+    fn test_async_master_slave() {
+        let path = "/tmp/vhost_user_lib_unit_test_aio";
+        let listener = Listener::new(path, true).unwrap();
+        let backend = Arc::new(Mutex::new(DummySlaveReqHandler::new()));
+        let mut slave_listener = SlaveListener::new(listener, backend.clone()).unwrap();
+        let mut master = AsyncMaster::connect(path).unwrap();
+        let mut slave =
+            AsyncSlaveReqHandler::new(slave_listener.accept().unwrap().unwrap()).unwrap();
+        let waker = Arc::new(CountingWaker::default());
+        let slave_waker = Arc::new(CountingWaker::default());
+        // The event loop keeps the handles to wake up the tasks waiting on the connections.
+        let master_readiness = master.readiness();
+        let slave_readiness = slave.readiness();
+
+        // Nothing has been received yet.
+        assert!(poll_once(&mut slave.handle_request(), &slave_waker).is_pending());
+
+        assert!(poll_once(&mut master.set_owner().unwrap(), &waker).is_ready());
+        assert!(poll_once(&mut slave.handle_request(), &slave_waker).is_ready());
+        assert_eq!(slave.handler().session_state(), SessionState::Owned);
+
+        // The request is sent, but the master waits for the reply until woken up.
+        let mut request = master.get_features().unwrap();
+        assert!(poll_once(&mut request, &waker).is_pending());
+        assert!(poll_once(&mut slave.handle_request(), &slave_waker).is_ready());
+        assert_eq!(waker.wakes.load(Ordering::SeqCst), 0);
+        slave_readiness.notify();
+        assert_eq!(slave_waker.wakes.load(Ordering::SeqCst), 1);
+        assert_eq!(waker.wakes.load(Ordering::SeqCst), 0);
+        master_readiness.notify();
+        assert_eq!(waker.wakes.load(Ordering::SeqCst), 1);
+        match poll_once(&mut request, &waker) {
+            Poll::Ready(Ok(features)) => assert_eq!(features, VIRTIO_FEATURES),
+            _ => panic!("no reply to GET_FEATURES"),
+        }
+
+        let mut request = master.set_features(VIRTIO_FEATURES).unwrap();
+        assert!(poll_once(&mut request, &waker).is_ready());
+        assert!(poll_once(&mut slave.handle_request(), &slave_waker).is_ready());
+        assert_eq!(backend.lock().unwrap().acked_features, VIRTIO_FEATURES);
+
+        let features = complete(&mut master.get_protocol_features().unwrap(), &mut slave).unwrap();
+        assert!(features.contains(VhostUserProtocolFeatures::MQ));
+        let mut request = master
+            .set_protocol_features(VhostUserProtocolFeatures::MQ)
+            .unwrap();
+        complete(&mut request, &mut slave).unwrap();
+        let num = complete(&mut master.get_queue_num().unwrap(), &mut slave).unwrap();
+        assert_eq!(num, MAX_QUEUE_NUM as u64);
+        complete(&mut master.set_vring_num(1, 128).unwrap(), &mut slave).unwrap();
+        complete(&mut master.set_vring_base(1, 2).unwrap(), &mut slave).unwrap();
+        let call = EventFd::new(0).unwrap();
+        complete(&mut master.set_vring_call(1, &call).unwrap(), &mut slave).unwrap();
+        assert_eq!(backend.lock().unwrap().vring_num[1], 128);
+        assert!(backend.lock().unwrap().call_fd[1].is_some());
+        match master.set_vring_kick(0x100, &call) {
+            Err(Error::InvalidParam) => {}
+            _ => panic!("vring index overflowing the payload accepted"),
+        }
+
+        // Requests abandoned before their reply leave the connection out of sync.
+        let mut request = master.get_protocol_features().unwrap();
+        assert!(poll_once(&mut request, &waker).is_pending());
+        match master.get_features() {
+            Err(Error::InvalidOperation) => {}
+            _ => panic!("request sent after an abandoned one"),
+        }
+
+        // The slave fails once the master has gone.
+        drop(master);
+        match poll_once(&mut slave.handle_request(), &slave_waker) {
+            Poll::Ready(Err(_)) => {}
+            _ => panic!("closed connection not reported"),
+        }
+
+        // Failed requests complete, so the next ones may be sent.
+        let mut master = AsyncMaster::connect(path).unwrap();
+        let slave = slave_listener.accept().unwrap().unwrap();
+        let mut request = master.get_features().unwrap();
+        assert!(poll_once(&mut request, &waker).is_pending());
+        drop(slave);
+        match poll_once(&mut request, &waker) {
+            Poll::Ready(Err(_)) => {}
+            _ => panic!("closed connection not reported"),
+        }
+        assert!(master.get_features().is_ok());
+    }
+}
@@ -0,0 +1,1200 @@
+// Copyright (C) 2020 Alibaba Cloud Computing. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! A vhost-user-blk slave backend built on the daemon framework.
+//!
+//! Requests of all virtqueues are served by a `BlockStorage` implementation, so backends may
+//! provide their own image formats. `RawFile` serves raw disk images. The capacity, the write cache
+//! mode, the number of queues and the discard and write zeroes limits are exposed through the
+//! device configuration space, and the driver may switch the write cache mode when
+//! VIRTIO_BLK_F_CONFIG_WCE has been negotiated.
+//!
+//! With the `vhost-user-io-uring` feature, reads and writes of storage backed by a file
+//! descriptor may be submitted through io_uring, see `BlockBackend::register_io_uring()`.
+
+use std::cmp;
+use std::fs::File;
+use std::io::{self, Read, Write};
+#[cfg(feature = "vhost-user-io-uring")]
+use std::mem;
+use std::os::unix::fs::FileExt;
+use std::os::unix::io::{AsRawFd, RawFd};
+#[cfg(feature = "vhost-user-io-uring")]
+use std::sync::{Arc, Mutex, RwLock};
+
+use vm_memory::{Address, Bytes, GuestMemoryMmap};
+#[cfg(feature = "vhost-user-io-uring")]
+use vm_memory::{GuestAddress, GuestMemory, GuestMemoryRegion};
+#[cfg(feature = "vhost-user-io-uring")]
+use vmm_sys_util::epoll::EventSet;
+#[cfg(feature = "vhost-user-io-uring")]
+use vmm_sys_util::eventfd::{EventFd, EFD_NONBLOCK};
+
+#[cfg(feature = "vhost-user-io-uring")]
+use super::daemon::VringEpollHandler;
+use super::daemon::{
+    read_config_space, ChainReader, ChainWriter, DescriptorChain, VhostUserBackend, Vring,
+};
+#[cfg(feature = "vhost-user-io-uring")]
+use super::io_uring::IoUring;
+use super::message::{VhostUserProtocolFeatures, VHOST_USER_CONFIG_OFFSET};
+use crate::features::{DeviceFeatures, VirtioBlockFeatures, VirtioFeatures};
+
+/// Maximum number of segments in a request is in seg_max.
+pub const VIRTIO_BLK_F_SEG_MAX: u64 = 2;
+/// The device is read only.
+pub const VIRTIO_BLK_F_RO: u64 = 5;
+/// Block size of the device is in blk_size.
+pub const VIRTIO_BLK_F_BLK_SIZE: u64 = 6;
+/// The device supports cache flush requests.
+pub const VIRTIO_BLK_F_FLUSH: u64 = 9;
+/// The driver can switch the write cache mode through the writeback field.
+pub const VIRTIO_BLK_F_CONFIG_WCE: u64 = 11;
+/// The device supports multiple virtqueues.
+pub const VIRTIO_BLK_F_MQ: u64 = 12;
+/// The device supports discard requests.
+pub const VIRTIO_BLK_F_DISCARD: u64 = 13;
+/// The device supports write zeroes requests.
+pub const VIRTIO_BLK_F_WRITE_ZEROES: u64 = 14;
+/// The device is compliant with the virtio 1.0 specification.
+pub const VIRTIO_F_VERSION_1: u64 = 32;
+
+/// Size of the sectors addressed by requests.
+pub const SECTOR_SIZE: u64 = 512;
+
+// Request types.
+const VIRTIO_BLK_T_IN: u32 = 0;
+const VIRTIO_BLK_T_OUT: u32 = 1;
+const VIRTIO_BLK_T_FLUSH: u32 = 4;
+const VIRTIO_BLK_T_GET_ID: u32 = 8;
+const VIRTIO_BLK_T_DISCARD: u32 = 11;
+const VIRTIO_BLK_T_WRITE_ZEROES: u32 = 13;
+
+// Request status.
+const VIRTIO_BLK_S_OK: u8 = 0;
+const VIRTIO_BLK_S_IOERR: u8 = 1;
+const VIRTIO_BLK_S_UNSUPP: u8 = 2;
+
+// Unmap flag of write zeroes segments.
+const VIRTIO_BLK_WRITE_ZEROES_FLAG_UNMAP: u32 = 0x1;
+
+// Sizes of the request header, of discard and write zeroes segments, and of the device id.
+const REQUEST_HEADER_SIZE: usize = 16;
+const SEGMENT_SIZE: usize = 16;
+const VIRTIO_BLK_ID_BYTES: usize = 20;
+
+// Size of the virtio-blk configuration space, up to write_zeroes_may_unmap and its padding.
+const VIRTIO_BLK_CONFIG_SIZE: usize = 60;
+// Offset of the writeback field in the configuration space.
+const CONFIG_WRITEBACK_OFFSET: usize = 32;
+
+// Limits reported to the driver.
+const SEG_MAX: u32 = 128;
+const MAX_DISCARD_SECTORS: u32 = u32::MAX;
+const MAX_DISCARD_SEG: u32 = 32;
+// Largest chunk of data copied at once between the guest memory and the storage.
+const MAX_CHUNK_SIZE: usize = 0x2_0000;
+// Size of the virtqueues.
+const QUEUE_SIZE: usize = 256;
+// Largest number of buffers of a request submitted through io_uring.
+#[cfg(feature = "vhost-user-io-uring")]
+const MAX_IOVECS: usize = 1024;
+
+/// Storage serving the requests of a block device.
+///
+/// Offsets and lengths are in bytes. Requests may be served concurrently from the worker threads
+/// of the virtqueues.
+pub trait BlockStorage: Send + Sync + 'static {
+    /// Get the size of the storage.
+    fn size(&self) -> u64;
+
+    /// Read `buf.len()` bytes at `offset`.
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<()>;
+
+    /// Write `buf.len()` bytes at `offset`.
+    fn write_at(&self, buf: &[u8], offset: u64) -> io::Result<()>;
+
+    /// Flush written data to stable storage.
+    fn flush(&self) -> io::Result<()>;
+
+    /// Discard `len` bytes at `offset`.
+    fn discard(&self, _offset: u64, _len: u64) -> io::Result<()> {
+        Err(io::Error::from_raw_os_error(libc::EOPNOTSUPP))
+    }
+
+    /// Write zeroes to `len` bytes at `offset`, optionally deallocating the range.
+    fn write_zeroes(&self, offset: u64, len: u64, _unmap: bool) -> io::Result<()> {
+        fill_zeroes(self, offset, len)
+    }
+
+    /// Get the file descriptor holding the data at the offsets of the storage, for reads and
+    /// writes to be submitted to the kernel without going through `read_at()` and `write_at()`.
+    fn as_raw_fd(&self) -> Option<RawFd> {
+        None
+    }
+}
+
+// Write zeroes through a bounded buffer, for storage lacking a more efficient way.
+fn fill_zeroes<S: BlockStorage + ?Sized>(storage: &S, offset: u64, len: u64) -> io::Result<()> {
+    let zeroes = vec![0u8; cmp::min(len, 0x10_0000) as usize];
+    let mut done = 0;
+    while done < len {
+        let chunk = cmp::min(len - done, zeroes.len() as u64) as usize;
+        storage.write_at(&zeroes[..chunk], offset + done)?;
+        done += chunk as u64;
+    }
+    Ok(())
+}
+
+/// Raw disk image.
+pub struct RawFile {
+    file: File,
+    size: u64,
+}
+
+impl RawFile {
+    /// Serve the raw disk image in `file`.
+    pub fn new(file: File) -> io::Result<Self> {
+        let size = file.metadata()?.len();
+        Ok(RawFile { file, size })
+    }
+
+    fn fallocate(&self, mode: libc::c_int, offset: u64, len: u64) -> io::Result<()> {
+        // Safe because the file descriptor is valid and we check the return value.
+        let ret = unsafe {
+            libc::fallocate(
+                self.file.as_raw_fd(),
+                mode | libc::FALLOC_FL_KEEP_SIZE,
+                offset as libc::off_t,
+                len as libc::off_t,
+            )
+        };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+}
+
+impl BlockStorage for RawFile {
+    fn size(&self) -> u64 {
+        self.size
+    }
+
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<()> {
+        self.file.read_exact_at(buf, offset)
+    }
+
+    fn write_at(&self, buf: &[u8], offset: u64) -> io::Result<()> {
+        self.file.write_all_at(buf, offset)
+    }
+
+    fn flush(&self) -> io::Result<()> {
+        self.file.sync_data()
+    }
+
+    fn discard(&self, offset: u64, len: u64) -> io::Result<()> {
+        self.fallocate(libc::FALLOC_FL_PUNCH_HOLE, offset, len)
+    }
+
+    fn write_zeroes(&self, offset: u64, len: u64, unmap: bool) -> io::Result<()> {
+        let mode = if unmap {
+            libc::FALLOC_FL_PUNCH_HOLE
+        } else {
+            libc::FALLOC_FL_ZERO_RANGE
+        };
+        match self.fallocate(mode, offset, len) {
+            Err(ref e) if e.raw_os_error() == Some(libc::EOPNOTSUPP) => {
+                fill_zeroes(self, offset, len)
+            }
+            res => res,
+        }
+    }
+
+    fn as_raw_fd(&self) -> Option<RawFd> {
+        Some(self.file.as_raw_fd())
+    }
+}
+
+// A read or write request in flight in an io_uring instance.
+#[cfg(feature = "vhost-user-io-uring")]
+struct UringRequest {
+    head_index: u16,
+    status_addr: GuestAddress,
+    read: bool,
+    // number of bytes to transfer
+    len: usize,
+    // buffers of the guest memory the data is transferred to or from
+    iovecs: Vec<libc::iovec>,
+    // number of operations which haven't completed yet
+    pending: u32,
+    failed: bool,
+    // Keeps the buffers mapped until the request completes.
+    mem: GuestMemoryMmap,
+}
+
+// Safe because the iovecs point into the guest memory mapping owned by the request.
+#[cfg(feature = "vhost-user-io-uring")]
+unsafe impl Send for UringRequest {}
+
+// The io_uring instance of a virtqueue, and the requests in flight in it.
+#[cfg(feature = "vhost-user-io-uring")]
+struct UringQueue {
+    ring: IoUring,
+    event: EventFd,
+    requests: Vec<Option<UringRequest>>,
+    free_slots: Vec<usize>,
+}
+
+#[cfg(feature = "vhost-user-io-uring")]
+impl UringQueue {
+    fn new() -> io::Result<Self> {
+        // A request takes up to two entries, its data operation and a flush.
+        let ring = IoUring::new(2 * QUEUE_SIZE as u32)?;
+        let event = EventFd::new(EFD_NONBLOCK)?;
+        ring.register_eventfd(event.as_raw_fd())?;
+        Ok(UringQueue {
+            ring,
+            event,
+            requests: (0..QUEUE_SIZE).map(|_| None).collect(),
+            free_slots: (0..QUEUE_SIZE).rev().collect(),
+        })
+    }
+}
+
+#[cfg(feature = "vhost-user-io-uring")]
+impl Drop for UringQueue {
+    fn drop(&mut self) {
+        // The kernel may still access the buffers of the requests in flight after the ring has
+        // been closed, so wait for their operations to complete. The requests are leaked, along
+        // with the guest memory they keep mapped, if that fails.
+        let mut res = self.ring.submit();
+        while res.is_ok() && self.requests.iter().any(Option::is_some) {
+            res = self.ring.wait(1);
+            while let Some((user_data, _)) = self.ring.pop_completion() {
+                let slot = (user_data / 2) as usize;
+                if let Some(Some(request)) = self.requests.get_mut(slot) {
+                    request.pending -= 1;
+                    if request.pending == 0 {
+                        self.requests[slot] = None;
+                    }
+                }
+            }
+        }
+        if res.is_err() {
+            mem::forget(mem::take(&mut self.requests));
+        }
+    }
+}
+
+// Describe `len` bytes of the guest memory at `addr` as iovecs, split at region boundaries.
+#[cfg(feature = "vhost-user-io-uring")]
+fn push_iovecs(
+    mem: &GuestMemoryMmap,
+    addr: GuestAddress,
+    len: usize,
+    iovecs: &mut Vec<libc::iovec>,
+) -> io::Result<()> {
+    if len == 0 {
+        return Ok(());
+    }
+    let done = mem
+        .try_access(len, addr, |_, count, region_addr, region| {
+            let base = region.get_host_address(region_addr)?;
+            iovecs.push(libc::iovec {
+                iov_base: base as *mut libc::c_void,
+                iov_len: count,
+            });
+            Ok(count)
+        })
+        .map_err(|_| io::Error::from_raw_os_error(libc::EFAULT))?;
+    if done != len {
+        return Err(io::Error::from_raw_os_error(libc::EFAULT));
+    }
+    Ok(())
+}
+
+/// A vhost-user-blk device backend.
+pub struct BlockBackend<S: BlockStorage> {
+    storage: S,
+    num_queues: usize,
+    read_only: bool,
+    writeback: bool,
+    id: [u8; VIRTIO_BLK_ID_BYTES],
+    mem: Option<GuestMemoryMmap>,
+    // io_uring instances of the virtqueues, empty if requests are served synchronously
+    #[cfg(feature = "vhost-user-io-uring")]
+    uring: Vec<Mutex<UringQueue>>,
+}
+
+impl<S: BlockStorage> BlockBackend<S> {
+    /// Create a backend serving the storage through `num_queues` virtqueues.
+    ///
+    /// The write cache is enabled until the driver switches it off.
+    pub fn new(storage: S, num_queues: usize, read_only: bool) -> io::Result<Self> {
+        if num_queues == 0 || num_queues > 64 {
+            return Err(io::Error::from_raw_os_error(libc::EINVAL));
+        }
+        Ok(BlockBackend {
+            storage,
+            num_queues,
+            read_only,
+            writeback: true,
+            id: [0u8; VIRTIO_BLK_ID_BYTES],
+            mem: None,
+            #[cfg(feature = "vhost-user-io-uring")]
+            uring: Vec::new(),
+        })
+    }
+
+    /// Set the serial number returned by GET_ID requests, truncated to 20 bytes.
+    pub fn set_serial(&mut self, serial: &str) {
+        let len = cmp::min(serial.len(), VIRTIO_BLK_ID_BYTES);
+        self.id = [0u8; VIRTIO_BLK_ID_BYTES];
+        self.id[..len].copy_from_slice(&serial.as_bytes()[..len]);
+    }
+
+    /// Check whether the write cache is enabled.
+    pub fn writeback(&self) -> bool {
+        self.writeback
+    }
+
+    /// Submit the reads and writes of each virtqueue through an io_uring instance, completed on
+    /// the worker threads of the daemon, as returned by `Daemon::get_vring_workers()`. Requests
+    /// then don't block the worker threads until the storage has served them.
+    ///
+    /// Return false if the storage has no file descriptor or io_uring is unavailable, in which
+    /// case requests keep being served synchronously. Other requests are always served
+    /// synchronously.
+    #[cfg(feature = "vhost-user-io-uring")]
+    pub fn register_io_uring(
+        &mut self,
+        workers: &[Arc<VringEpollHandler<Self>>],
+    ) -> io::Result<bool> {
+        let queues_per_thread = self.queues_per_thread();
+        if workers.len() != queues_per_thread.len() {
+            return Err(io::Error::from_raw_os_error(libc::EINVAL));
+        }
+        if self.storage.as_raw_fd().is_none() {
+            return Ok(false);
+        }
+        let mut uring = Vec::new();
+        for _ in 0..self.num_queues {
+            match UringQueue::new() {
+                Ok(queue) => uring.push(Mutex::new(queue)),
+                Err(_) => return Ok(false),
+            }
+        }
+        for (index, queue) in uring.iter().enumerate() {
+            let thread_id = queues_per_thread
+                .iter()
+                .position(|mask| mask & (1 << index) != 0)
+                .ok_or_else(|| io::Error::from_raw_os_error(libc::EINVAL))?;
+            workers[thread_id].register_listener(
+                queue.lock().unwrap().event.as_raw_fd(),
+                EventSet::IN,
+                self.uring_event(index),
+            )?;
+        }
+        self.uring = uring;
+        Ok(true)
+    }
+
+    #[cfg(feature = "vhost-user-io-uring")]
+    fn uring_event(&self, queue_index: usize) -> u64 {
+        // Data num_queues is reserved for the exit event of the worker threads.
+        (self.num_queues + 1 + queue_index) as u64
+    }
+
+    fn config_space(&self) -> [u8; VIRTIO_BLK_CONFIG_SIZE] {
+        let mut config = [0u8; VIRTIO_BLK_CONFIG_SIZE];
+        let capacity = self.storage.size() / SECTOR_SIZE;
+        config[0..8].copy_from_slice(&capacity.to_le_bytes());
+        config[12..16].copy_from_slice(&SEG_MAX.to_le_bytes());
+        config[20..24].copy_from_slice(&(SECTOR_SIZE as u32).to_le_bytes());
+        config[CONFIG_WRITEBACK_OFFSET] = self.writeback as u8;
+        config[34..36].copy_from_slice(&(self.num_queues as u16).to_le_bytes());
+        config[36..40].copy_from_slice(&MAX_DISCARD_SECTORS.to_le_bytes());
+        config[40..44].copy_from_slice(&MAX_DISCARD_SEG.to_le_bytes());
+        config[44..48].copy_from_slice(&1u32.to_le_bytes());
+        config[48..52].copy_from_slice(&MAX_DISCARD_SECTORS.to_le_bytes());
+        config[52..56].copy_from_slice(&MAX_DISCARD_SEG.to_le_bytes());
+        config[56] = 1;
+        config
+    }
+
+    // Serve a request, and return the number of bytes written into the guest memory.
+    //
+    // Data is copied between the guest memory and the storage in chunks of MAX_CHUNK_SIZE bytes,
+    // as the size of the buffers of the chains is up to the driver.
+    fn process_request(&self, mem: &GuestMemoryMmap, chain: &DescriptorChain) -> io::Result<u32> {
+        // The status is the last byte of the last device writable buffer. Malformed requests
+        // are completed all the same so the driver gets its buffers back, with an error status
+        // if there is room for one.
+        let status_addr = match chain.writable().last() {
+            Some(desc) if !desc.is_empty() => desc.addr().checked_add(u64::from(desc.len()) - 1),
+            _ => None,
+        };
+        let status_addr = match status_addr {
+            Some(addr) => addr,
+            None => return Ok(0),
+        };
+        let mut reader = chain.reader(mem);
+        let mut hdr = [0u8; REQUEST_HEADER_SIZE];
+        let short = reader.available_bytes() < REQUEST_HEADER_SIZE;
+        if !short {
+            reader.read_exact(&mut hdr)?;
+        }
+
+        let mut req_type = [0u8; 4];
+        req_type.copy_from_slice(&hdr[0..4]);
+        let req_type = u32::from_le_bytes(req_type);
+        let mut sector = [0u8; 8];
+        sector.copy_from_slice(&hdr[8..16]);
+        let offset = u64::from_le_bytes(sector).checked_mul(SECTOR_SIZE);
+
+        let mut written = 0;
+        // Besides the header and the status, requests have at most seg_max data buffers.
+        let status = if short || chain.descriptors().len() > SEG_MAX as usize + 2 {
+            VIRTIO_BLK_S_IOERR
+        } else {
+            match req_type {
+                VIRTIO_BLK_T_IN => {
+                    let mut writer = chain.writer(mem);
+                    let len = writer.available_bytes() - 1;
+                    let status = match self.check_range(offset, len as u64) {
+                        Some(offset) => self.read_to_chain(&mut writer, offset, len)?,
+                        None => VIRTIO_BLK_S_IOERR,
+                    };
+                    written = writer.bytes_written();
+                    status
+                }
+                VIRTIO_BLK_T_OUT => {
+                    let len = reader.available_bytes();
+                    match self.check_range(offset, len as u64) {
+                        Some(offset) if !self.read_only => {
+                            self.write_from_chain(&mut reader, offset, len)?
+                        }
+                        _ => VIRTIO_BLK_S_IOERR,
+                    }
+                }
+                VIRTIO_BLK_T_FLUSH => self.status(self.storage.flush()),
+                VIRTIO_BLK_T_GET_ID => {
+                    written = Self::write_data(mem, chain, &self.id)?;
+                    VIRTIO_BLK_S_OK
+                }
+                VIRTIO_BLK_T_DISCARD | VIRTIO_BLK_T_WRITE_ZEROES if self.read_only => {
+                    VIRTIO_BLK_S_IOERR
+                }
+                VIRTIO_BLK_T_DISCARD | VIRTIO_BLK_T_WRITE_ZEROES => {
+                    if reader.available_bytes() > MAX_DISCARD_SEG as usize * SEGMENT_SIZE {
+                        VIRTIO_BLK_S_UNSUPP
+                    } else {
+                        let mut data = vec![0u8; reader.available_bytes()];
+                        reader.read_exact(&mut data)?;
+                        self.process_segments(req_type, &data)
+                    }
+                }
+                _ => VIRTIO_BLK_S_UNSUPP,
+            }
+        };
+
+        mem.write_obj(status, status_addr)
+            .map_err(|_| io::Error::from_raw_os_error(libc::EFAULT))?;
+        Ok(written as u32 + 1)
+    }
+
+    // Submit a read or write request to the io_uring instance of the virtqueue. Return false if
+    // the request must be served synchronously.
+    #[cfg(feature = "vhost-user-io-uring")]
+    fn submit_request(
+        &self,
+        uring: &mut UringQueue,
+        mem: &GuestMemoryMmap,
+        chain: &DescriptorChain,
+    ) -> io::Result<bool> {
+        let fd = match self.storage.as_raw_fd() {
+            Some(fd) => fd,
+            None => return Ok(false),
+        };
+        let mut hdr = [0u8; REQUEST_HEADER_SIZE];
+        if io::Read::read_exact(&mut chain.reader(mem), &mut hdr).is_err() {
+            return Ok(false);
+        }
+        let status_desc = match chain.writable().last() {
+            Some(desc) if !desc.is_empty() => *desc,
+            _ => return Ok(false),
+        };
+        // Requests with more than seg_max data buffers are failed synchronously.
+        if chain.descriptors().len() > SEG_MAX as usize + 2 {
+            return Ok(false);
+        }
+        let mut req_type = [0u8; 4];
+        req_type.copy_from_slice(&hdr[0..4]);
+        let read = match u32::from_le_bytes(req_type) {
+            VIRTIO_BLK_T_IN => true,
+            VIRTIO_BLK_T_OUT if !self.read_only => false,
+            _ => return Ok(false),
+        };
+
+        // Data is read into the device writable buffers in front of the status byte, and
+        // written from the device readable buffers after the header.
+        let mut iovecs = Vec::new();
+        if read {
+            let count = chain.writable().count();
+            for (i, desc) in chain.writable().enumerate() {
+                let len = desc.len() as usize - if i + 1 == count { 1 } else { 0 };
+                push_iovecs(mem, desc.addr(), len, &mut iovecs)?;
+            }
+        } else {
+            let mut skip = REQUEST_HEADER_SIZE;
+            for desc in chain.readable() {
+                let start = cmp::min(skip, desc.len() as usize);
+                skip -= start;
+                push_iovecs(
+                    mem,
+                    desc.addr().unchecked_add(start as u64),
+                    desc.len() as usize - start,
+                    &mut iovecs,
+                )?;
+            }
+        }
+        let len: usize = iovecs.iter().map(|iovec| iovec.iov_len).sum();
+        let mut sector = [0u8; 8];
+        sector.copy_from_slice(&hdr[8..16]);
+        let offset = match self.check_range(
+            u64::from_le_bytes(sector).checked_mul(SECTOR_SIZE),
+            len as u64,
+        ) {
+            Some(offset) => offset,
+            None => return Ok(false),
+        };
+        let flush = !read && !self.writeback;
+        let entries = if flush { 2 } else { 1 };
+        if len == 0 || iovecs.len() > MAX_IOVECS || uring.ring.space_left() < entries {
+            return Ok(false);
+        }
+        let slot = match uring.free_slots.pop() {
+            Some(slot) => slot,
+            None => return Ok(false),
+        };
+
+        let request = UringRequest {
+            head_index: chain.head_index(),
+            status_addr: status_desc
+                .addr()
+                .unchecked_add(u64::from(status_desc.len()) - 1),
+            read,
+            len,
+            iovecs,
+            pending: entries,
+            failed: false,
+            mem: mem.clone(),
+        };
+        let iovecs = &request.iovecs;
+        let user_data = slot as u64 * 2;
+        // Safe because the request keeps the iovecs and the guest memory they point to until
+        // its operations complete.
+        let res = unsafe {
+            if read {
+                uring.ring.push_readv(fd, iovecs, offset, user_data, false)
+            } else {
+                uring.ring.push_writev(fd, iovecs, offset, user_data, flush)
+            }
+        };
+        res.and_then(|_| {
+            if flush {
+                // Only runs once the write has succeeded.
+                uring.ring.push_fsync(fd, user_data + 1)
+            } else {
+                Ok(())
+            }
+        })?;
+        uring.requests[slot] = Some(request);
+        Ok(true)
+    }
+
+    // Complete the requests of the virtqueue whose operations have completed.
+    #[cfg(feature = "vhost-user-io-uring")]
+    fn complete_requests(&self, queue_index: usize, vring: &mut Vring) -> io::Result<()> {
+        let (mem, uring) = match (self.mem.as_ref(), self.uring.get(queue_index)) {
+            (Some(mem), Some(uring)) => (mem, uring),
+            _ => return Ok(()),
+        };
+        let mut uring = uring.lock().unwrap();
+        let uring = &mut *uring;
+        // Consume the event before popping, so later completions signal it again.
+        let _ = uring.event.read();
+        let mut used = vring.used_writer(mem);
+        while let Some((user_data, res)) = uring.ring.pop_completion() {
+            let slot = (user_data / 2) as usize;
+            let request = match uring.requests.get_mut(slot) {
+                Some(Some(request)) => request,
+                _ => continue,
+            };
+            // The data operation must transfer all the bytes, and the flush must succeed.
+            let ok = match res {
+                Ok(count) => user_data % 2 == 1 || count as usize == request.len,
+                Err(_) => false,
+            };
+            request.failed |= !ok;
+            request.pending -= 1;
+            if request.pending != 0 {
+                continue;
+            }
+
+            let request = uring.requests[slot].take().unwrap();
+            uring.free_slots.push(slot);
+            let status = if request.failed {
+                VIRTIO_BLK_S_IOERR
+            } else {
+                VIRTIO_BLK_S_OK
+            };
+            request
+                .mem
+                .write_obj(status, request.status_addr)
+                .map_err(|_| io::Error::from_raw_os_error(libc::EFAULT))?;
+            let written = if request.read && !request.failed {
+                request.len
+            } else {
+                0
+            };
+            used.add_used(request.head_index, written as u32 + 1)?;
+        }
+        used.finish()
+    }
+
+    fn process_segments(&self, req_type: u32, data: &[u8]) -> u8 {
+        if data.is_empty()
+            || data.len() & (SEGMENT_SIZE - 1) != 0
+            || data.len() / SEGMENT_SIZE > MAX_DISCARD_SEG as usize
+        {
+            return VIRTIO_BLK_S_UNSUPP;
+        }
+        for segment in data.chunks(SEGMENT_SIZE) {
+            let mut sector = [0u8; 8];
+            sector.copy_from_slice(&segment[0..8]);
+            let mut num_sectors = [0u8; 4];
+            num_sectors.copy_from_slice(&segment[8..12]);
+            let mut flags = [0u8; 4];
+            flags.copy_from_slice(&segment[12..16]);
+            let flags = u32::from_le_bytes(flags);
+
+            let len = u64::from(u32::from_le_bytes(num_sectors)) * SECTOR_SIZE;
+            let offset =
+                match self.check_range(u64::from_le_bytes(sector).checked_mul(SECTOR_SIZE), len) {
+                    Some(offset) => offset,
+                    None => return VIRTIO_BLK_S_IOERR,
+                };
+            let res = if req_type == VIRTIO_BLK_T_DISCARD {
+                if flags != 0 {
+                    return VIRTIO_BLK_S_UNSUPP;
+                }
+                self.storage.discard(offset, len)
+            } else {
+                let unmap = flags & VIRTIO_BLK_WRITE_ZEROES_FLAG_UNMAP != 0;
+                self.storage
+                    .write_zeroes(offset, len, unmap)
+                    .and_then(|_| self.write_through())
+            };
+            if res.is_err() {
+                return VIRTIO_BLK_S_IOERR;
+            }
+        }
+        VIRTIO_BLK_S_OK
+    }
+
+    // Flush written data right away when the write cache is disabled.
+    fn write_through(&self) -> io::Result<()> {
+        if self.writeback {
+            Ok(())
+        } else {
+            self.storage.flush()
+        }
+    }
+
+    fn status(&self, res: io::Result<()>) -> u8 {
+        match res {
+            Ok(()) => VIRTIO_BLK_S_OK,
+            Err(_) => VIRTIO_BLK_S_IOERR,
+        }
+    }
+
+    // Read `len` bytes at `offset` of the storage into the chain.
+    fn read_to_chain(&self, writer: &mut ChainWriter, offset: u64, len: usize) -> io::Result<u8> {
+        let mut buf = vec![0u8; cmp::min(len, MAX_CHUNK_SIZE)];
+        let mut done = 0;
+        while done < len {
+            let chunk = &mut buf[..cmp::min(len - done, MAX_CHUNK_SIZE)];
+            if self.storage.read_at(chunk, offset + done as u64).is_err() {
+                return Ok(VIRTIO_BLK_S_IOERR);
+            }
+            writer.write_all(chunk)?;
+            done += chunk.len();
+        }
+        Ok(VIRTIO_BLK_S_OK)
+    }
+
+    // Write the `len` bytes left in the chain at `offset` of the storage.
+    fn write_from_chain(
+        &self,
+        reader: &mut ChainReader,
+        offset: u64,
+        len: usize,
+    ) -> io::Result<u8> {
+        let mut buf = vec![0u8; cmp::min(len, MAX_CHUNK_SIZE)];
+        let mut done = 0;
+        while done < len {
+            let chunk = &mut buf[..cmp::min(len - done, MAX_CHUNK_SIZE)];
+            reader.read_exact(chunk)?;
+            if self.storage.write_at(chunk, offset + done as u64).is_err() {
+                return Ok(VIRTIO_BLK_S_IOERR);
+            }
+            done += chunk.len();
+        }
+        Ok(self.status(self.write_through()))
+    }
+
+    fn check_range(&self, offset: Option<u64>, len: u64) -> Option<u64> {
+        let offset = offset?;
+        match offset.checked_add(len) {
+            Some(end) if end <= self.storage.size() => Some(offset),
+            _ => None,
+        }
+    }
+
+    // Write data into the device writable buffers of the chain, before the status byte.
+    fn write_data(mem: &GuestMemoryMmap, chain: &DescriptorChain, buf: &[u8]) -> io::Result<usize> {
+        let capacity = chain
+            .writable()
+            .map(|desc| desc.len() as usize)
+            .sum::<usize>()
+            - 1;
+        let buf = &buf[..cmp::min(buf.len(), capacity)];
+        let mut written = 0;
+        for desc in chain.writable() {
+            if written == buf.len() {
+                break;
+            }
+            let len = cmp::min(desc.len() as usize, buf.len() - written);
+            mem.write_slice(&buf[written..written + len], desc.addr())
+                .map_err(|_| io::Error::from_raw_os_error(libc::EFAULT))?;
+            written += len;
+        }
+        Ok(written)
+    }
+}
+
+impl<S: BlockStorage> VhostUserBackend for BlockBackend<S> {
+    fn num_queues(&self) -> usize {
+        self.num_queues
+    }
+
+    fn max_queue_size(&self) -> usize {
+        QUEUE_SIZE
+    }
+
+    fn features(&self) -> u64 {
+        let mut features = VirtioBlockFeatures::SEG_MAX
+            | VirtioBlockFeatures::BLK_SIZE
+            | VirtioBlockFeatures::FLUSH
+            | VirtioBlockFeatures::CONFIG_WCE
+            | VirtioBlockFeatures::MQ;
+        if self.read_only {
+            features |= VirtioBlockFeatures::RO;
+        } else {
+            features |= VirtioBlockFeatures::DISCARD | VirtioBlockFeatures::WRITE_ZEROES;
+        }
+        features.with_common(VirtioFeatures::VERSION_1 | VirtioFeatures::PROTOCOL_FEATURES)
+    }
+
+    fn protocol_features(&self) -> VhostUserProtocolFeatures {
+        VhostUserProtocolFeatures::MQ | VhostUserProtocolFeatures::CONFIG
+    }
+
+    fn update_memory(&mut self, mem: GuestMemoryMmap) -> io::Result<()> {
+        self.mem = Some(mem);
+        Ok(())
+    }
+
+    fn get_config(&self, offset: u32, size: u32) -> Vec<u8> {
+        read_config_space(&self.config_space(), offset, size)
+    }
+
+    fn set_config(&mut self, offset: u32, buf: &[u8]) -> io::Result<()> {
+        // Only the writeback field is writable.
+        let offset = offset.saturating_sub(VHOST_USER_CONFIG_OFFSET) as usize;
+        if offset != CONFIG_WRITEBACK_OFFSET || buf.len() != 1 || buf[0] > 1 {
+            return Err(io::Error::from_raw_os_error(libc::EINVAL));
+        }
+        if self.writeback && buf[0] == 0 {
+            self.storage.flush()?;
+        }
+        self.writeback = buf[0] == 1;
+        Ok(())
+    }
+
+    fn process_queue(
+        &self,
+        _queue_index: u16,
+        vring: &mut Vring,
+        _thread_id: usize,
+    ) -> io::Result<()> {
+        let mem = match self.mem {
+            Some(ref mem) => mem,
+            None => return Ok(()),
+        };
+        #[cfg(feature = "vhost-user-io-uring")]
+        let mut uring = self
+            .uring
+            .get(_queue_index as usize)
+            .map(|uring| uring.lock().unwrap());
+        let mut used = vring.used_writer(mem);
+        while let Some(chain) = used.pop_avail()? {
+            #[cfg(feature = "vhost-user-io-uring")]
+            {
+                if let Some(ref mut uring) = uring {
+                    if self.submit_request(uring, mem, &chain)? {
+                        continue;
+                    }
+                }
+            }
+            let len = self.process_request(mem, &chain)?;
+            used.add_used(chain.head_index(), len)?;
+        }
+        #[cfg(feature = "vhost-user-io-uring")]
+        {
+            if let Some(ref mut uring) = uring {
+                uring.ring.submit()?;
+            }
+        }
+        used.finish()
+    }
+
+    #[cfg(feature = "vhost-user-io-uring")]
+    fn handle_event(
+        &self,
+        device_event: u16,
+        _evset: EventSet,
+        vrings: &[Arc<RwLock<Vring>>],
+        thread_id: usize,
+    ) -> io::Result<bool> {
+        // Completions of the io_uring instance of a virtqueue are handled with the virtqueue.
+        if u64::from(device_event) >= self.uring_event(0) {
+            let queue_index = (u64::from(device_event) - self.uring_event(0)) as usize;
+            if let Some(vring) = vrings.get(queue_index) {
+                self.complete_requests(queue_index, &mut vring.write().unwrap())?;
+            }
+        } else if let Some(vring) = vrings.get(device_event as usize) {
+            let mut vring = vring.write().unwrap();
+            if vring.is_enabled() {
+                self.process_queue(device_event, &mut vring, thread_id)?;
+            }
+        }
+        Ok(false)
+    }
+}
+
+#[cfg(all(test, feature = "vhost-user-master"))]
+mod tests {
+    use super::*;
+    use crate::backend::{
+        VhostFeatures, VhostMemory, VhostOwner, VhostUserMemoryRegionInfo, VhostVring,
+        VringConfigData,
+    };
+    use crate::vhost_user::message::VhostUserConfigFlags;
+    use crate::vhost_user::{Daemon, Listener, Master, VhostUserMaster};
+    use std::sync::{Arc, RwLock};
+    use std::thread;
+    use vm_memory::{FileOffset, GuestAddress};
+    use vmm_sys_util::eventfd::EventFd;
+    use vmm_sys_util::tempdir::TempDir;
+    use vmm_sys_util::tempfile::TempFile;
+
+    // Guest physical addresses of the rings and buffers, in a single region mapped at VA_BASE.
+    const VA_BASE: u64 = 0x7f00_0000_0000;
+    const RING_ADDRS: [(u64, u64, u64); 2] = [(0x1000, 0x2000, 0x3000), (0x4000, 0x5000, 0x6000)];
+    const HDR_BUF: u64 = 0x8000;
+    const DATA_BUF: u64 = 0x9000;
+    const STATUS_BUF: u64 = 0xa000;
+
+    struct Queue {
+        index: usize,
+        next_avail: u16,
+        call: EventFd,
+        _kick: EventFd,
+    }
+
+    // Make the chain of the `(addr, len, write)` buffers available, then wait for its completion
+    // and return its used length.
+    fn push_chain(mem: &GuestMemoryMmap, queue: &mut Queue, descs: &[(u64, u32, bool)]) -> u32 {
+        let (desc, avail, used) = RING_ADDRS[queue.index];
+        for (i, &(addr, len, write)) in descs.iter().enumerate() {
+            let base = desc + 16 * i as u64;
+            let mut flags = if write { 2u16 } else { 0 };
+            if i + 1 < descs.len() {
+                flags |= 1;
+            }
+            mem.write_obj(addr, GuestAddress(base)).unwrap();
+            mem.write_obj(len, GuestAddress(base + 8)).unwrap();
+            mem.write_obj(flags, GuestAddress(base + 12)).unwrap();
+            mem.write_obj(i as u16 + 1, GuestAddress(base + 14))
+                .unwrap();
+        }
+        let slot = u64::from(queue.next_avail % 16);
+        mem.write_obj(0u16, GuestAddress(avail + 4 + slot * 2))
+            .unwrap();
+        queue.next_avail += 1;
+        mem.write_obj(queue.next_avail, GuestAddress(avail + 2))
+            .unwrap();
+        queue._kick.write(1).unwrap();
+        assert_eq!(queue.call.read().unwrap(), 1);
+        let used_idx: u16 = mem.read_obj(GuestAddress(used + 2)).unwrap();
+        assert_eq!(used_idx, queue.next_avail);
+        mem.read_obj(GuestAddress(used + 8 + slot * 8)).unwrap()
+    }
+
+    // Submit a request made of the header, data buffers all at DATA_BUF and the status byte,
+    // then wait for its completion and return its status.
+    fn submit(
+        mem: &GuestMemoryMmap,
+        queue: &mut Queue,
+        hdr: (u32, u64),
+        data: &[(usize, bool)],
+    ) -> u8 {
+        let mut hdr_buf = [0u8; REQUEST_HEADER_SIZE];
+        hdr_buf[0..4].copy_from_slice(&hdr.0.to_le_bytes());
+        hdr_buf[8..16].copy_from_slice(&hdr.1.to_le_bytes());
+        mem.write_slice(&hdr_buf, GuestAddress(HDR_BUF)).unwrap();
+        mem.write_obj(0xffu8, GuestAddress(STATUS_BUF)).unwrap();
+
+        let mut descs = vec![(HDR_BUF, REQUEST_HEADER_SIZE as u32, false)];
+        for &(len, write) in data {
+            descs.push((DATA_BUF, len as u32, write));
+        }
+        descs.push((STATUS_BUF, 1, true));
+        push_chain(mem, queue, &descs);
+        mem.read_obj(GuestAddress(STATUS_BUF)).unwrap()
+    }
+
+    // Serve requests through a two queue device, once `setup` has been called on the daemon and
+    // the backend.
+    fn run_block_backend<F>(setup: F)
+    where
+        F: FnOnce(&Daemon<BlockBackend<RawFile>>, &RwLock<BlockBackend<RawFile>>),
+    {
+        let image = TempFile::new().unwrap().into_file();
+        image.set_len(0x10_0000).unwrap();
+        let storage = RawFile::new(image.try_clone().unwrap()).unwrap();
+        let mut backend = BlockBackend::new(storage, 2, false).unwrap();
+        backend.set_serial("vhost-blk-test");
+        let backend = Arc::new(RwLock::new(backend));
+        let mut daemon = Daemon::new("test-blk".to_string(), backend.clone()).unwrap();
+        setup(&daemon, &backend);
+
+        let file = TempFile::new().unwrap().into_file();
+        file.set_len(0x10000).unwrap();
+        let mem = GuestMemoryMmap::from_ranges_with_files(&[(
+            GuestAddress(0),
+            0x10000,
+            Some(FileOffset::new(file.try_clone().unwrap(), 0)),
+        )])
+        .unwrap();
+
+        let dir = TempDir::new().unwrap();
+        let path = dir.as_path().join("sock").to_str().unwrap().to_string();
+        let listener = Listener::new(&path, true).unwrap();
+        let master_thread = thread::spawn(move || {
+            let mut master = Master::connect(&path, 2).unwrap();
+            master.set_owner().unwrap();
+            let features = master.get_features().unwrap();
+            assert_eq!(features & (1 << VIRTIO_BLK_F_RO), 0);
+            master.set_features(features).unwrap();
+            master.get_protocol_features().unwrap();
+            master
+                .set_protocol_features(
+                    VhostUserProtocolFeatures::MQ | VhostUserProtocolFeatures::CONFIG,
+                )
+                .unwrap();
+            assert_eq!(master.get_queue_num().unwrap(), 2);
+            let (_, config) = master
+                .get_config(
+                    VHOST_USER_CONFIG_OFFSET,
+                    VIRTIO_BLK_CONFIG_SIZE as u32,
+                    VhostUserConfigFlags::WRITABLE,
+                    &[0u8; VIRTIO_BLK_CONFIG_SIZE],
+                )
+                .unwrap();
+            assert_eq!(&config[0..8], &0x800u64.to_le_bytes());
+            assert_eq!(config[CONFIG_WRITEBACK_OFFSET], 1);
+            assert_eq!(&config[34..36], &[2, 0]);
+            master
+                .set_config(
+                    VHOST_USER_CONFIG_OFFSET + CONFIG_WRITEBACK_OFFSET as u32,
+                    VhostUserConfigFlags::WRITABLE,
+                    &[0],
+                )
+                .unwrap();
+
+            let region = VhostUserMemoryRegionInfo {
+                guest_phys_addr: 0,
+                memory_size: 0x10000,
+                userspace_addr: VA_BASE,
+                mmap_offset: 0,
+                mmap_handle: file.as_raw_fd(),
+            };
+            master.set_mem_table(&[region]).unwrap();
+
+            let mut queues = Vec::new();
+            for (index, &(desc, avail, used)) in RING_ADDRS.iter().enumerate() {
+                master.set_vring_num(index, 16).unwrap();
+                let config = VringConfigData {
+                    queue_max_size: QUEUE_SIZE as u16,
+                    queue_size: 16,
+                    flags: 0,
+                    desc_table_addr: VA_BASE + desc,
+                    used_ring_addr: VA_BASE + used,
+                    avail_ring_addr: VA_BASE + avail,
+                    log_addr: None,
+                };
+                master.set_vring_addr(index, &config).unwrap();
+                master.set_vring_base(index, 0).unwrap();
+                let call = EventFd::new(0).unwrap();
+                master.set_vring_call(index, &call).unwrap();
+                master.set_vring_enable(index, true).unwrap();
+                let kick = EventFd::new(0).unwrap();
+                master.set_vring_kick(index, &kick).unwrap();
+                queues.push(Queue {
+                    index,
+                    next_avail: 0,
+                    call,
+                    _kick: kick,
+                });
+            }
+
+            // Write a sector through the first queue, and read it back through the second one.
+            mem.write_slice(&[0x5a; 512], GuestAddress(DATA_BUF))
+                .unwrap();
+            let status = submit(&mem, &mut queues[0], (VIRTIO_BLK_T_OUT, 2), &[(512, false)]);
+            assert_eq!(status, VIRTIO_BLK_S_OK);
+            mem.write_slice(&[0u8; 512], GuestAddress(DATA_BUF))
+                .unwrap();
+            let status = submit(&mem, &mut queues[1], (VIRTIO_BLK_T_IN, 2), &[(512, true)]);
+            assert_eq!(status, VIRTIO_BLK_S_OK);
+            let mut buf = [0u8; 512];
+            mem.read_slice(&mut buf, GuestAddress(DATA_BUF)).unwrap();
+            assert!(buf.iter().all(|b| *b == 0x5a));
+
+            let status = submit(
+                &mem,
+                &mut queues[0],
+                (VIRTIO_BLK_T_IN, 0x800),
+                &[(512, true)],
+            );
+            assert_eq!(status, VIRTIO_BLK_S_IOERR);
+            let status = submit(&mem, &mut queues[0], (VIRTIO_BLK_T_FLUSH, 0), &[]);
+            assert_eq!(status, VIRTIO_BLK_S_OK);
+            let status = submit(
+                &mem,
+                &mut queues[0],
+                (VIRTIO_BLK_T_GET_ID, 0),
+                &[(20, true)],
+            );
+            assert_eq!(status, VIRTIO_BLK_S_OK);
+            let mut id = [0u8; 14];
+            mem.read_slice(&mut id, GuestAddress(DATA_BUF)).unwrap();
+            assert_eq!(&id, b"vhost-blk-test");
+            let status = submit(&mem, &mut queues[0], (0xff, 0), &[]);
+            assert_eq!(status, VIRTIO_BLK_S_UNSUPP);
+
+            // Requests may have buffers aliasing each other.
+            mem.write_slice(&[0xa5; 0x1000], GuestAddress(DATA_BUF))
+                .unwrap();
+            let status = submit(
+                &mem,
+                &mut queues[0],
+                (VIRTIO_BLK_T_OUT, 0x100),
+                &[(0x1000, false); 14],
+            );
+            assert_eq!(status, VIRTIO_BLK_S_OK);
+            mem.write_slice(&[0u8; 0x1000], GuestAddress(DATA_BUF))
+                .unwrap();
+            let status = submit(
+                &mem,
+                &mut queues[1],
+                (VIRTIO_BLK_T_IN, 0x100),
+                &[(0x1000, true); 14],
+            );
+            assert_eq!(status, VIRTIO_BLK_S_OK);
+            let mut buf = [0u8; 0x1000];
+            mem.read_slice(&mut buf, GuestAddress(DATA_BUF)).unwrap();
+            assert!(buf.iter().all(|b| *b == 0xa5));
+
+            // Malformed requests are completed, with an error status if there is room for one.
+            mem.write_obj(0xffu8, GuestAddress(STATUS_BUF)).unwrap();
+            let len = push_chain(
+                &mem,
+                &mut queues[0],
+                &[(HDR_BUF, 8, false), (STATUS_BUF, 1, true)],
+            );
+            assert_eq!(len, 1);
+            let status: u8 = mem.read_obj(GuestAddress(STATUS_BUF)).unwrap();
+            assert_eq!(status, VIRTIO_BLK_S_IOERR);
+            let len = push_chain(
+                &mem,
+                &mut queues[0],
+                &[
+                    (HDR_BUF, REQUEST_HEADER_SIZE as u32, false),
+                    (DATA_BUF, 512, false),
+                ],
+            );
+            assert_eq!(len, 0);
+            let len = push_chain(
+                &mem,
+                &mut queues[0],
+                &[
+                    (HDR_BUF, REQUEST_HEADER_SIZE as u32, false),
+                    (STATUS_BUF, 0, true),
+                ],
+            );
+            assert_eq!(len, 0);
+
+            // Zero the written sector.
+            let mut segment = [0u8; SEGMENT_SIZE];
+            segment[0..8].copy_from_slice(&2u64.to_le_bytes());
+            segment[8..12].copy_from_slice(&1u32.to_le_bytes());
+            mem.write_slice(&segment, GuestAddress(DATA_BUF)).unwrap();
+            let status = submit(
+                &mem,
+                &mut queues[1],
+                (VIRTIO_BLK_T_WRITE_ZEROES, 0),
+                &[(SEGMENT_SIZE, false)],
+            );
+            assert_eq!(status, VIRTIO_BLK_S_OK);
+        });
+
+        daemon.start(listener).unwrap();
+        master_thread.join().unwrap();
+        assert!(daemon.wait().is_err());
+        assert!(!backend.read().unwrap().writeback());
+        let mut buf = [0xffu8; 512];
+        image.read_exact_at(&mut buf, 1024).unwrap();
+        assert!(buf.iter().all(|b| *b == 0));
+    }
+
+    #[test]
+    fn test_block_backend() {
+        run_block_backend(|_, _| {});
+    }
+
+    #[cfg(feature = "vhost-user-io-uring")]
+    #[test]
+    fn test_block_backend_io_uring() {
+        // Requests are served synchronously when io_uring is unavailable.
+        run_block_backend(|daemon, backend| {
+            let workers = daemon.get_vring_workers();
+            let enabled = backend.write().unwrap().register_io_uring(&workers);
+            assert_eq!(enabled.unwrap(), IoUring::new(1).is_ok());
+        });
+    }
+}
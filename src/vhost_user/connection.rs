@@ -6,20 +6,28 @@
 #![allow(dead_code)]
 
 use libc::{c_void, iovec};
+use std::fs::{self, File, Permissions};
 use std::io::ErrorKind;
 use std::marker::PhantomData;
-use std::os::unix::io::{AsRawFd, RawFd};
-use std::os::unix::net::{UnixListener, UnixStream};
+use std::os::linux::net::SocketAddrExt;
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::io::{AsRawFd, FromRawFd, IntoRawFd, RawFd};
+use std::os::unix::net::{SocketAddr, UnixListener, UnixStream};
+use std::time::Duration;
 use std::{mem, slice};
 
 use super::message::*;
-use super::sock_ctrl_msg::ScmSocket;
+use super::sock_ctrl_msg::{ScmSocket, SCM_MAX_FD};
+use super::trace::{Direction, Tracer};
+use super::transport::{self, SharedMemoryHook};
 use super::{Error, Result};
 
 /// Unix domain socket listener for accepting incoming connections.
 pub struct Listener {
     fd: UnixListener,
-    path: String,
+    // None for sockets in the abstract namespace and vsock sockets
+    path: Option<String>,
+    vsock: bool,
 }
 
 impl Listener {
@@ -35,10 +43,99 @@ impl Listener {
         let fd = UnixListener::bind(path).map_err(Error::SocketError)?;
         Ok(Listener {
             fd,
-            path: path.to_string(),
+            path: Some(path.to_string()),
+            vsock: false,
         })
     }
 
+    /// Create a unix domain socket listener of type SOCK_SEQPACKET, for peers which require
+    /// message boundaries to be kept by the socket.
+    ///
+    /// # Return:
+    /// * - the new Listener object on success.
+    /// * - InvalidParam: the path is too long.
+    /// * - SocketError: failed to create listener socket.
+    pub fn new_seqpacket(path: &str, unlink: bool) -> Result<Self> {
+        if unlink {
+            let _ = std::fs::remove_file(path);
+        }
+        let sock = seqpacket_socket()?;
+        let addr = socket_addr(path)?;
+        // Safe because the address is a valid sockaddr_un, and we check the return values.
+        unsafe {
+            if libc::bind(
+                sock.as_raw_fd(),
+                &addr as *const libc::sockaddr_un as *const libc::sockaddr,
+                mem::size_of::<libc::sockaddr_un>() as libc::socklen_t,
+            ) < 0
+                || libc::listen(sock.as_raw_fd(), 128) < 0
+            {
+                return Err(Error::SocketError(std::io::Error::last_os_error()));
+            }
+        }
+        Ok(Listener {
+            // Safe because the socket is a freshly created listening socket we own.
+            fd: unsafe { UnixListener::from_raw_fd(sock.into_raw_fd()) },
+            path: Some(path.to_string()),
+            vsock: false,
+        })
+    }
+
+    /// Create a unix domain socket listener in the abstract namespace, which doesn't appear in
+    /// the filesystem and only accepts connections from the same network namespace.
+    ///
+    /// # Return:
+    /// * - the new Listener object on success.
+    /// * - SocketError: failed to create listener socket.
+    pub fn new_abstract(name: &str) -> Result<Self> {
+        let addr = SocketAddr::from_abstract_name(name.as_bytes()).map_err(Error::SocketError)?;
+        let fd = UnixListener::bind_addr(&addr).map_err(Error::SocketError)?;
+        Ok(Listener {
+            fd,
+            path: None,
+            vsock: false,
+        })
+    }
+
+    /// Create a listener on the vsock `port` of the local context, for masters running in
+    /// other virtual machines. File descriptors can't be passed over such connections, see
+    /// `SharedMemoryHook`.
+    ///
+    /// # Return:
+    /// * - the new Listener object on success.
+    /// * - SocketError: failed to create listener socket.
+    pub fn new_vsock(port: u32) -> Result<Self> {
+        let fd = transport::vsock_listen(port).map_err(Error::SocketError)?;
+        Ok(Listener {
+            fd,
+            path: None,
+            vsock: true,
+        })
+    }
+
+    /// Change the permissions of the socket file, such as 0o600, to restrict which users may
+    /// connect.
+    ///
+    /// # Return:
+    /// * - () on success.
+    /// * - InvalidOperation: the socket is in the abstract namespace.
+    /// * - SocketError: failure from chmod().
+    pub fn set_mode(&self, mode: u32) -> Result<()> {
+        let path = self.path.as_ref().ok_or(Error::InvalidOperation)?;
+        fs::set_permissions(path, Permissions::from_mode(mode)).map_err(Error::SocketError)
+    }
+
+    /// Change the owner and the group of the socket file, keeping them unchanged when None.
+    ///
+    /// # Return:
+    /// * - () on success.
+    /// * - InvalidOperation: the socket is in the abstract namespace.
+    /// * - SocketError: failure from chown().
+    pub fn set_owner(&self, uid: Option<u32>, gid: Option<u32>) -> Result<()> {
+        let path = self.path.as_ref().ok_or(Error::InvalidOperation)?;
+        std::os::unix::fs::chown(path, uid, gid).map_err(Error::SocketError)
+    }
+
     /// Accept an incoming connection.
     ///
     /// # Return:
@@ -47,8 +144,13 @@ impl Listener {
     /// * - SocketError: errors from accept().
     pub fn accept(&self) -> Result<Option<UnixStream>> {
         loop {
-            match self.fd.accept() {
-                Ok((socket, _addr)) => return Ok(Some(socket)),
+            let res = if self.vsock {
+                transport::vsock_accept(self.fd.as_raw_fd())
+            } else {
+                self.fd.accept().map(|(socket, _addr)| socket)
+            };
+            match res {
+                Ok(socket) => return Ok(Some(socket)),
                 Err(e) => {
                     match e.kind() {
                         // No incoming connection available.
@@ -82,13 +184,125 @@ impl AsRawFd for Listener {
 
 impl Drop for Listener {
     fn drop(&mut self) {
-        let _ = std::fs::remove_file(self.path.clone());
+        if let Some(ref path) = self.path {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
+
+/// Credentials of the process at the other end of a connection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PeerCredentials {
+    /// Process id of the peer, when it connected.
+    pub pid: i32,
+    /// User id of the peer.
+    pub uid: u32,
+    /// Group id of the peer.
+    pub gid: u32,
+}
+
+impl PeerCredentials {
+    /// Get the credentials of the peer of a connection, with SO_PEERCRED.
+    ///
+    /// # Return:
+    /// * - the credentials on success.
+    /// * - SocketError: failure from getsockopt().
+    pub fn from_stream(sock: &UnixStream) -> Result<Self> {
+        let mut cred = libc::ucred {
+            pid: 0,
+            uid: 0,
+            gid: 0,
+        };
+        let mut len = mem::size_of::<libc::ucred>() as libc::socklen_t;
+        // Safe because the kernel writes at most `len` bytes into `cred`, and we check the return
+        // value.
+        let ret = unsafe {
+            libc::getsockopt(
+                sock.as_raw_fd(),
+                libc::SOL_SOCKET,
+                libc::SO_PEERCRED,
+                &mut cred as *mut libc::ucred as *mut c_void,
+                &mut len,
+            )
+        };
+        if ret < 0 {
+            return Err(Error::SocketError(std::io::Error::last_os_error()));
+        }
+        Ok(PeerCredentials {
+            pid: cred.pid,
+            uid: cred.uid,
+            gid: cred.gid,
+        })
+    }
+}
+
+// Create a unix domain socket of type SOCK_SEQPACKET. UnixStream is only used to own the file
+// descriptor, since the standard library has no type for such sockets.
+fn seqpacket_socket() -> Result<UnixStream> {
+    // Safe because we check the return value, and take the ownership of the new socket.
+    unsafe {
+        let fd = libc::socket(libc::AF_UNIX, libc::SOCK_SEQPACKET | libc::SOCK_CLOEXEC, 0);
+        if fd < 0 {
+            return Err(Error::SocketError(std::io::Error::last_os_error()));
+        }
+        Ok(UnixStream::from_raw_fd(fd))
+    }
+}
+
+// Build the address of the socket file at `path`.
+fn socket_addr(path: &str) -> Result<libc::sockaddr_un> {
+    // Safe because sockaddr_un is a plain data structure.
+    let mut addr: libc::sockaddr_un = unsafe { mem::zeroed() };
+    addr.sun_family = libc::AF_UNIX as libc::sa_family_t;
+    let path = path.as_bytes();
+    // Keep the terminating nul byte.
+    if path.len() >= addr.sun_path.len() || path.contains(&0) {
+        return Err(Error::InvalidParam);
+    }
+    for (dst, src) in addr.sun_path.iter_mut().zip(path) {
+        *dst = *src as libc::c_char;
+    }
+    Ok(addr)
+}
+
+// Get the type of a socket, such as SOCK_STREAM.
+fn socket_type(fd: RawFd) -> Option<libc::c_int> {
+    let mut sock_type: libc::c_int = 0;
+    let mut len = mem::size_of::<libc::c_int>() as libc::socklen_t;
+    // Safe because the kernel writes at most `len` bytes into `sock_type`, and we check the
+    // return value.
+    let ret = unsafe {
+        libc::getsockopt(
+            fd,
+            libc::SOL_SOCKET,
+            libc::SO_TYPE,
+            &mut sock_type as *mut libc::c_int as *mut c_void,
+            &mut len,
+        )
+    };
+    if ret < 0 {
+        None
+    } else {
+        Some(sock_type)
     }
 }
 
 /// Unix domain socket endpoint for vhost-user connection.
 pub(super) struct Endpoint<R: Req> {
     sock: UnixStream,
+    // the socket is of type SOCK_SEQPACKET, with the unread bytes of the last received packet
+    seqpacket: bool,
+    packet: Vec<u8>,
+    // bytes and file descriptors of a partially received message, in non-blocking mode
+    rbuf: Vec<u8>,
+    rfds: Option<Vec<RawFd>>,
+    // timeout of socket operations, in blocking mode
+    timeout: Option<Duration>,
+    // maximum size of the body and payload of the messages
+    max_msg_size: usize,
+    // transfers the file descriptors attached to messages instead of the socket
+    hook: Option<Box<dyn SharedMemoryHook>>,
+    tracer: Tracer,
     _r: PhantomData<R>,
 }
 
@@ -103,14 +317,247 @@ impl<R: Req> Endpoint<R> {
         Ok(Self::from_stream(sock))
     }
 
+    /// Create a new SOCK_SEQPACKET socket by connecting to server at `str`.
+    ///
+    /// # Return:
+    /// * - the new Endpoint object on success.
+    /// * - InvalidParam: the path is too long.
+    /// * - SocketError: failed to create the socket.
+    /// * - SocketConnect: failed to connect to peer.
+    pub fn connect_seqpacket(path: &str) -> Result<Self> {
+        let sock = seqpacket_socket()?;
+        let addr = socket_addr(path)?;
+        // Safe because the address is a valid sockaddr_un, and we check the return value.
+        let ret = unsafe {
+            libc::connect(
+                sock.as_raw_fd(),
+                &addr as *const libc::sockaddr_un as *const libc::sockaddr,
+                mem::size_of::<libc::sockaddr_un>() as libc::socklen_t,
+            )
+        };
+        if ret < 0 {
+            return Err(Error::SocketConnect(std::io::Error::last_os_error()));
+        }
+        Ok(Self::from_stream(sock))
+    }
+
     /// Create an endpoint from a stream object.
+    ///
+    /// The stream may hold a connected socket of type SOCK_SEQPACKET instead, such as one
+    /// accepted from `Listener::new_seqpacket()` or created by socketpair(), whose packets are
+    /// then received whole and handed out as a stream.
     pub fn from_stream(sock: UnixStream) -> Self {
+        let seqpacket = socket_type(sock.as_raw_fd()) == Some(libc::SOCK_SEQPACKET);
         Endpoint {
             sock,
+            seqpacket,
+            packet: Vec::new(),
+            rbuf: Vec::new(),
+            rfds: None,
+            timeout: None,
+            max_msg_size: MAX_MSG_SIZE,
+            hook: None,
+            tracer: Tracer::default(),
             _r: PhantomData,
         }
     }
 
+    /// Create a new stream by connecting to the vsock `port` of the context `cid`.
+    ///
+    /// # Return:
+    /// * - the new Endpoint object on success.
+    /// * - SocketConnect: failed to connect to peer.
+    pub fn connect_vsock(cid: u32, port: u32) -> Result<Self> {
+        let sock = transport::vsock_connect(cid, port).map_err(Error::SocketConnect)?;
+        Ok(Self::from_stream(sock))
+    }
+
+    /// Transfer the file descriptors attached to the messages with `hook` instead of the
+    /// socket, for transports which can't pass file descriptors.
+    pub fn set_shared_memory_hook(&mut self, hook: Box<dyn SharedMemoryHook>) {
+        self.hook = Some(hook);
+    }
+
+    // Hand the file descriptors attached to a message over to the hook, if any, returning
+    // those to attach to the message on the socket.
+    fn send_fds<'a>(&mut self, fds: Option<&'a [RawFd]>) -> Result<Option<&'a [RawFd]>> {
+        match self.hook {
+            Some(ref mut hook) => {
+                hook.send_fds(fds.unwrap_or(&[]))
+                    .map_err(Error::SocketError)?;
+                Ok(None)
+            }
+            None => Ok(fds),
+        }
+    }
+
+    // Get the file descriptors attached to a received message from the hook, if any. Nothing
+    // is expected from the hook if no message has been received, once the peer has gone.
+    fn recv_fds(&mut self, bytes: usize, rfds: Option<Vec<RawFd>>) -> Result<Option<Vec<RawFd>>> {
+        match self.hook {
+            Some(ref mut hook) if bytes > 0 => {
+                Self::close_rfds(rfds);
+                let fds = hook.recv_fds().map_err(Error::SocketError)?;
+                Ok(if fds.is_empty() { None } else { Some(fds) })
+            }
+            _ => Ok(rfds),
+        }
+    }
+
+    /// Change blocking status on the endpoint.
+    ///
+    /// # Return:
+    /// * - () on success.
+    /// * - SocketError: failure from set_nonblocking().
+    pub fn set_nonblocking(&self, nonblocking: bool) -> Result<()> {
+        self.sock
+            .set_nonblocking(nonblocking)
+            .map_err(Error::SocketError)
+    }
+
+    /// Send and receive messages whose body and payload take up to `size` bytes, MAX_MSG_SIZE
+    /// by default.
+    ///
+    /// Bigger messages fail with OversizedMsg when sent, and with InvalidMessage when received,
+    /// before their body is read.
+    ///
+    /// # Return:
+    /// * - () on success.
+    /// * - InvalidParam: size is below MAX_MSG_SIZE or above MAX_JUMBO_MSG_SIZE.
+    pub fn set_max_msg_size(&mut self, size: usize) -> Result<()> {
+        if !(MAX_MSG_SIZE..=MAX_JUMBO_MSG_SIZE).contains(&size) {
+            return Err(Error::InvalidParam);
+        }
+        self.max_msg_size = size;
+        Ok(())
+    }
+
+    /// Get the maximum size of the body and payload of the messages.
+    pub fn max_msg_size(&self) -> usize {
+        self.max_msg_size
+    }
+
+    /// Set the timeout of sending and receiving on the endpoint, or wait forever if `timeout` is
+    /// None.
+    ///
+    /// Socket operations which don't complete in time fail with Timeout, or with PartialMessage
+    /// if part of the message has already been transferred, since the stream can't be resumed.
+    ///
+    /// # Return:
+    /// * - () on success.
+    /// * - InvalidParam: the timeout is zero.
+    /// * - SocketError: failure from setting the socket timeouts.
+    pub fn set_timeout(&mut self, timeout: Option<Duration>) -> Result<()> {
+        if timeout == Some(Duration::from_secs(0)) {
+            return Err(Error::InvalidParam);
+        }
+        self.sock
+            .set_read_timeout(timeout)
+            .map_err(Error::SocketError)?;
+        self.sock
+            .set_write_timeout(timeout)
+            .map_err(Error::SocketError)?;
+        self.timeout = timeout;
+        Ok(())
+    }
+
+    // Socket operations fail with EAGAIN once the timeout has expired.
+    fn check_timeout(&self, err: Error) -> Error {
+        match err {
+            Error::SocketRetry(ref e)
+                if self.timeout.is_some() && e.kind() == ErrorKind::WouldBlock =>
+            {
+                Error::Timeout
+            }
+            e => e,
+        }
+    }
+
+    /// Read the data available on the socket, and return the next message once it has been
+    /// completely received.
+    ///
+    /// This is meant to be called when the socket is reported as readable in non-blocking mode.
+    /// The bytes and file descriptors of a partially received message are kept until the rest of
+    /// the message arrives, so callers may wait for the next readable event and call again.
+    /// Reads never cross the message boundary, to keep the attached file descriptors.
+    ///
+    /// # Return:
+    /// * - Some((message header, message body and payload, [received fds])) on success.
+    /// * - None: the message hasn't been completely received yet.
+    /// * - SocketRetry: temporary error caused by short of resources.
+    /// * - SocketBroken: the underline socket is broken.
+    /// * - SocketError: other socket related errors.
+    /// * - InvalidMessage: received a invalid message.
+    #[allow(clippy::type_complexity)]
+    pub fn handle_readable(
+        &mut self,
+    ) -> Result<Option<(VhostUserMsgHeader<R>, Vec<u8>, Option<Vec<RawFd>>)>> {
+        match self.fill_rbuf() {
+            Ok(Some(hdr)) => {
+                let mut buf = mem::take(&mut self.rbuf);
+                let rfds = self.rfds.take();
+                let rfds = self.recv_fds(buf.len(), rfds)?;
+                self.tracer.message(
+                    Direction::Recv,
+                    &hdr,
+                    rfds.as_ref().map_or(0, |fds| fds.len()),
+                );
+                buf.drain(..mem::size_of::<VhostUserMsgHeader<R>>());
+                Ok(Some((hdr, buf, rfds)))
+            }
+            Ok(None) => Ok(None),
+            Err(e) => {
+                self.rbuf.clear();
+                Self::close_rfds(self.rfds.take());
+                Err(e)
+            }
+        }
+    }
+
+    fn fill_rbuf(&mut self) -> Result<Option<VhostUserMsgHeader<R>>> {
+        let hdr_size = mem::size_of::<VhostUserMsgHeader<R>>();
+        loop {
+            let mut hdr = None;
+            let mut want = hdr_size.saturating_sub(self.rbuf.len());
+            if want == 0 {
+                // Safe because the buffer holds a whole header, and VhostUserMsgHeader is a
+                // plain data structure.
+                let h = unsafe {
+                    std::ptr::read_unaligned(self.rbuf.as_ptr() as *const VhostUserMsgHeader<R>)
+                };
+                if !h.is_valid_with_max_size(self.max_msg_size) {
+                    return Err(Error::InvalidMessage);
+                }
+                want = hdr_size + h.get_size() as usize - self.rbuf.len();
+                hdr = Some(h);
+            }
+            if want == 0 {
+                return Ok(hdr);
+            }
+
+            let (bytes, buf, rfds) = match self.recv_into_buf(want) {
+                Ok(res) => res,
+                Err(Error::SocketRetry(ref e)) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(Error::SocketRetry(ref e)) if e.kind() == ErrorKind::WouldBlock => {
+                    return Ok(None)
+                }
+                Err(Error::Timeout) => return Ok(None),
+                Err(e) => return Err(e),
+            };
+            if bytes == 0 {
+                let err = std::io::Error::from_raw_os_error(libc::ECONNRESET);
+                return Err(Error::SocketBroken(err));
+            }
+            // File descriptors are only expected along with the first bytes of a message.
+            if self.rbuf.is_empty() && self.rfds.is_none() {
+                self.rfds = rfds;
+            } else {
+                Self::close_rfds(rfds);
+            }
+            self.rbuf.extend_from_slice(&buf[..bytes]);
+        }
+    }
+
     /// Sends bytes from scatter-gather vectors over the socket with optional attached file
     /// descriptors.
     ///
@@ -124,7 +571,9 @@ impl<R: Req> Endpoint<R> {
             Some(rfds) => rfds,
             _ => &[],
         };
-        self.sock.send_with_fds(iovs, rfds).map_err(Into::into)
+        self.sock
+            .send_with_fds(iovs, rfds)
+            .map_err(|e| self.check_timeout(e.into()))
     }
 
     /// Sends all bytes from scatter-gather vectors over the socket with optional attached file
@@ -134,27 +583,32 @@ impl<R: Req> Endpoint<R> {
     /// * - number of bytes sent on success
     /// * - SocketBroken: the underline socket is broken.
     /// * - SocketError: other socket related errors.
+    /// * - Timeout: nothing has been sent before the timeout expired.
+    /// * - PartialMessage: the timeout expired after sending part of the data.
     pub fn send_iovec_all(&mut self, iovs: &[&[u8]], fds: Option<&[RawFd]>) -> Result<usize> {
+        let data_total: usize = iovs.iter().map(|iov| iov.len()).sum();
         let mut data_sent = 0;
-        let mut data_total = 0;
-        let iov_lens: Vec<usize> = iovs.iter().map(|iov| iov.len()).collect();
-        for len in &iov_lens {
-            data_total += len;
-        }
+        // The vectors left to send are only built after a short write, so messages sent at once
+        // are passed to sendmsg() as is.
+        let mut remaining = Vec::new();
 
         while (data_total - data_sent) > 0 {
-            let (nr_skip, offset) = get_sub_iovs_offset(&iov_lens, data_sent);
-            let iov = &iovs[nr_skip][offset..];
-
-            let data = &[&[iov], &iovs[(nr_skip + 1)..]].concat();
-            let sfds = if data_sent == 0 { fds } else { None };
-
-            let sent = self.send_iovec(data, sfds);
+            let sent = if data_sent == 0 {
+                self.send_iovec(iovs, fds)
+            } else {
+                self.send_iovec(&remaining, None)
+            };
             match sent {
                 Ok(0) => return Ok(data_sent),
-                Ok(n) => data_sent += n,
+                Ok(n) => {
+                    data_sent += n;
+                    if data_sent < data_total {
+                        remaining = get_sub_iovs(iovs, data_sent);
+                    }
+                }
                 Err(e) => match e {
                     Error::SocketRetry(_) => {}
+                    Error::Timeout if data_sent > 0 => return Err(Error::PartialMessage),
                     _ => return Err(e),
                 },
             }
@@ -193,6 +647,9 @@ impl<R: Req> Endpoint<R> {
                 mem::size_of::<VhostUserMsgHeader<R>>(),
             )]
         };
+        self.tracer
+            .message(Direction::Send, hdr, fds.map_or(0, |fds| fds.len()));
+        let fds = self.send_fds(fds)?;
         let bytes = self.send_iovec_all(&iovs[..], fds)?;
         if bytes != mem::size_of::<VhostUserMsgHeader<R>>() {
             return Err(Error::PartialMessage);
@@ -225,6 +682,9 @@ impl<R: Req> Endpoint<R> {
                 slice::from_raw_parts(body as *const T as *const u8, mem::size_of::<T>()),
             ]
         };
+        self.tracer
+            .message(Direction::Send, hdr, fds.map_or(0, |fds| fds.len()));
+        let fds = self.send_fds(fds)?;
         let bytes = self.send_iovec_all(&iovs[..], fds)?;
         if bytes != mem::size_of::<VhostUserMsgHeader<R>>() + mem::size_of::<T>() {
             return Err(Error::PartialMessage);
@@ -250,8 +710,8 @@ impl<R: Req> Endpoint<R> {
         payload: &[P],
         fds: Option<&[RawFd]>,
     ) -> Result<()> {
-        let len = payload.len() * mem::size_of::<P>();
-        if len > MAX_MSG_SIZE - mem::size_of::<T>() {
+        let len = mem::size_of_val(payload);
+        if len > self.max_msg_size.saturating_sub(mem::size_of::<T>()) {
             return Err(Error::OversizedMsg);
         }
         if let Some(fd_arr) = fds {
@@ -272,6 +732,9 @@ impl<R: Req> Endpoint<R> {
             ]
         };
         let total = mem::size_of::<VhostUserMsgHeader<R>>() + mem::size_of::<T>() + len;
+        self.tracer
+            .message(Direction::Send, hdr, fds.map_or(0, |fds| fds.len()));
+        let fds = self.send_fds(fds)?;
         let len = self.send_iovec_all(&iovs, fds)?;
         if len != total {
             return Err(Error::PartialMessage);
@@ -287,15 +750,52 @@ impl<R: Req> Endpoint<R> {
     /// * - SocketBroken: the underline socket is broken.
     /// * - SocketError: other socket related errors.
     pub fn recv_data(&mut self, len: usize) -> Result<(usize, Vec<u8>)> {
-        let mut rbuf = vec![0u8; len];
-        let mut iovs = [iovec {
-            iov_base: rbuf.as_mut_ptr() as *mut c_void,
-            iov_len: len,
-        }];
-        let (bytes, _) = self.sock.recv_with_fds(&mut iovs, &mut [])?;
+        let (bytes, rbuf, rfds) = self.recv_into_buf(len)?;
+        Self::close_rfds(rfds);
         Ok((bytes, rbuf))
     }
 
+    /// Reads `len` bytes from the socket into `buf`, reusing its allocation, and drops any
+    /// attached file descriptors.
+    ///
+    /// `buf` is truncated to the number of bytes received, so callers receiving messages one
+    /// after another don't allocate a buffer for each of them. Fewer bytes than `len` are only
+    /// received if the peer has closed the connection.
+    ///
+    /// # Return:
+    /// * - number of bytes received on success
+    /// * - SocketBroken: the underline socket is broken.
+    /// * - SocketError: other socket related errors.
+    /// * - Timeout: nothing has been received before the timeout expired.
+    /// * - PartialMessage: the timeout expired after receiving part of the data.
+    pub fn recv_data_into(&mut self, buf: &mut Vec<u8>, len: usize) -> Result<usize> {
+        buf.clear();
+        if len == 0 {
+            return Ok(0);
+        }
+        buf.resize(len, 0);
+        // Big messages may not be received at once from stream sockets, receive them chunk by
+        // chunk once the header has been consumed.
+        let res = {
+            let mut iovs = [iovec {
+                iov_base: buf.as_mut_ptr() as *mut c_void,
+                iov_len: len,
+            }];
+            self.recv_into_iovec_all(&mut iovs)
+        };
+        match res {
+            Ok((bytes, rfds)) => {
+                Self::close_rfds(rfds);
+                buf.truncate(bytes);
+                Ok(bytes)
+            }
+            Err(e) => {
+                buf.clear();
+                Err(e)
+            }
+        }
+    }
+
     /// Reads bytes from the socket into the given scatter/gather vectors with optional attached
     /// file descriptors.
     ///
@@ -305,6 +805,7 @@ impl<R: Req> Endpoint<R> {
     /// attached file descriptors, the receiver must obey following rules:
     ///   1) file descriptors are attached to a message.
     ///   2) message(packet) boundaries must be respected on the receive side.
+    ///
     /// In other words, recvmsg() operations must not cross the packet boundary, otherwise the
     /// attached file descriptors will get lost.
     ///
@@ -314,10 +815,66 @@ impl<R: Req> Endpoint<R> {
     /// * - SocketBroken: the underline socket is broken.
     /// * - SocketError: other socket related errors.
     pub fn recv_into_iovec(&mut self, iovs: &mut [iovec]) -> Result<(usize, Option<Vec<RawFd>>)> {
-        let mut fd_array = vec![0; MAX_ATTACHED_FD_ENTRIES];
-        let (bytes, fds) = self.sock.recv_with_fds(iovs, &mut fd_array)?;
+        if self.seqpacket {
+            return self.recv_packet_into_iovec(iovs);
+        }
+        self.recvmsg(iovs)
+    }
+
+    // Reading part of a packet from a SOCK_SEQPACKET socket discards the rest of it, so receive
+    // whole packets and hand their bytes out as they are asked for.
+    fn recv_packet_into_iovec(
+        &mut self,
+        iovs: &mut [iovec],
+    ) -> Result<(usize, Option<Vec<RawFd>>)> {
+        let mut rfds = None;
+        if self.packet.is_empty() {
+            let mut buf = vec![0u8; mem::size_of::<VhostUserMsgHeader<R>>() + self.max_msg_size];
+            let mut packet_iovs = [iovec {
+                iov_base: buf.as_mut_ptr() as *mut c_void,
+                iov_len: buf.len(),
+            }];
+            let (bytes, fds) = self.recvmsg(&mut packet_iovs)?;
+            buf.truncate(bytes);
+            self.packet = buf;
+            rfds = fds;
+        }
+
+        let mut copied = 0;
+        for iov in iovs.iter() {
+            let len = std::cmp::min(iov.iov_len, self.packet.len() - copied);
+            // Safe because the caller provides iovecs pointing to writable buffers of their
+            // length.
+            unsafe {
+                std::ptr::copy_nonoverlapping(
+                    self.packet[copied..].as_ptr(),
+                    iov.iov_base as *mut u8,
+                    len,
+                );
+            }
+            copied += len;
+        }
+        self.packet.drain(..copied);
+        Ok((copied, rfds))
+    }
+
+    fn recvmsg(&mut self, iovs: &mut [iovec]) -> Result<(usize, Option<Vec<RawFd>>)> {
+        // Room is made for as many file descriptors as the kernel may pass, so messages with too
+        // many of them are rejected, instead of the extra ones being silently discarded.
+        let mut fd_array = [0; SCM_MAX_FD];
+        let (bytes, fds) = match self.sock.recv_with_fds(iovs, &mut fd_array) {
+            Ok(res) => res,
+            Err(e) if e.errno() == libc::EMSGSIZE => {
+                return Err(Error::TooManyFds(MAX_ATTACHED_FD_ENTRIES))
+            }
+            Err(e) => return Err(self.check_timeout(e.into())),
+        };
         let rfds = match fds {
             0 => None,
+            n if n > MAX_ATTACHED_FD_ENTRIES => {
+                Self::close_rfds(Some(fd_array[..n].to_vec()));
+                return Err(Error::TooManyFds(MAX_ATTACHED_FD_ENTRIES));
+            }
             n => {
                 let mut fds = Vec::with_capacity(n);
                 fds.extend_from_slice(&fd_array[0..n]);
@@ -337,6 +894,7 @@ impl<R: Req> Endpoint<R> {
     /// attached file descriptors, the receiver must obey following rules:
     ///   1) file descriptors are attached to a message.
     ///   2) message(packet) boundaries must be respected on the receive side.
+    ///
     /// In other words, recvmsg() operations must not cross the packet boundary, otherwise the
     /// attached file descriptors will get lost.
     ///
@@ -344,6 +902,8 @@ impl<R: Req> Endpoint<R> {
     /// * - (number of bytes received, [received fds]) on success
     /// * - SocketBroken: the underline socket is broken.
     /// * - SocketError: other socket related errors.
+    /// * - Timeout: nothing has been received before the timeout expired.
+    /// * - PartialMessage: the timeout expired after receiving part of the data.
     pub fn recv_into_iovec_all(
         &mut self,
         iovs: &mut [iovec],
@@ -373,13 +933,25 @@ impl<R: Req> Endpoint<R> {
             match res {
                 Ok((0, _)) => return Ok((data_read, rfds)),
                 Ok((n, fds)) => {
-                    if data_read == 0 {
-                        rfds = fds;
+                    // File descriptors may come with any part of the message, when the peer
+                    // has sent it in several pieces.
+                    match (rfds.as_mut(), fds) {
+                        (Some(rfds), Some(fds)) => rfds.extend(fds),
+                        (None, fds) => rfds = fds,
+                        (Some(_), None) => {}
+                    }
+                    if rfds.as_ref().map_or(0, Vec::len) > MAX_ATTACHED_FD_ENTRIES {
+                        Self::close_rfds(rfds);
+                        return Err(Error::TooManyFds(MAX_ATTACHED_FD_ENTRIES));
                     }
                     data_read += n;
                 }
                 Err(e) => match e {
                     Error::SocketRetry(_) => {}
+                    Error::Timeout if data_read > 0 => {
+                        Self::close_rfds(rfds);
+                        return Err(Error::PartialMessage);
+                    }
                     _ => return Err(e),
                 },
             }
@@ -411,8 +983,7 @@ impl<R: Req> Endpoint<R> {
     }
 
     /// Receive a header-only message with optional attached file descriptors.
-    /// Note, only the first MAX_ATTACHED_FD_ENTRIES file descriptors will be
-    /// accepted and all other file descriptor will be discard silently.
+    /// Messages with more than MAX_ATTACHED_FD_ENTRIES file descriptors fail with TooManyFds.
     ///
     /// # Return:
     /// * - (message header, [received fds]) on success.
@@ -428,19 +999,53 @@ impl<R: Req> Endpoint<R> {
             iov_len: mem::size_of::<VhostUserMsgHeader<R>>(),
         }];
         let (bytes, rfds) = self.recv_into_iovec_all(&mut iovs[..])?;
+        let rfds = self.recv_fds(bytes, rfds)?;
 
         if bytes != mem::size_of::<VhostUserMsgHeader<R>>() {
             return Err(Error::PartialMessage);
-        } else if !hdr.is_valid() {
+        } else if !hdr.is_valid_with_max_size(self.max_msg_size) {
             return Err(Error::InvalidMessage);
         }
+        self.tracer.message(
+            Direction::Recv,
+            &hdr,
+            rfds.as_ref().map_or(0, |fds| fds.len()),
+        );
 
         Ok((hdr, rfds))
     }
 
+    /// Receive a whole message and drop it, closing the attached file descriptors.
+    ///
+    /// # Return:
+    /// * - message header on success.
+    /// * - SocketBroken: the underline socket is broken.
+    /// * - SocketError: other socket related errors.
+    /// * - Timeout: nothing has been received before the timeout expired.
+    /// * - PartialMessage: received a partial message.
+    /// * - InvalidMessage: received a invalid message.
+    pub fn discard_message(&mut self) -> Result<VhostUserMsgHeader<R>> {
+        let (hdr, rfds) = self.recv_header()?;
+        Self::close_rfds(rfds);
+        let mut buf = vec![0u8; hdr.get_size() as usize];
+        let mut iovs = [iovec {
+            iov_base: buf.as_mut_ptr() as *mut c_void,
+            iov_len: buf.len(),
+        }];
+        // The header has been consumed, so the stream can't be resumed after a timeout.
+        let (bytes, rfds) = self.recv_into_iovec_all(&mut iovs).map_err(|e| match e {
+            Error::Timeout => Error::PartialMessage,
+            e => e,
+        })?;
+        Self::close_rfds(rfds);
+        if bytes != buf.len() {
+            return Err(Error::PartialMessage);
+        }
+        Ok(hdr)
+    }
+
     /// Receive a message with optional attached file descriptors.
-    /// Note, only the first MAX_ATTACHED_FD_ENTRIES file descriptors will be
-    /// accepted and all other file descriptor will be discard silently.
+    /// Messages with more than MAX_ATTACHED_FD_ENTRIES file descriptors fail with TooManyFds.
     ///
     /// # Return:
     /// * - (message header, message body, [received fds]) on success.
@@ -465,23 +1070,27 @@ impl<R: Req> Endpoint<R> {
             },
         ];
         let (bytes, rfds) = self.recv_into_iovec_all(&mut iovs[..])?;
+        let rfds = self.recv_fds(bytes, rfds)?;
 
         let total = mem::size_of::<VhostUserMsgHeader<R>>() + mem::size_of::<T>();
         if bytes != total {
             return Err(Error::PartialMessage);
-        } else if !hdr.is_valid() || !body.is_valid() {
+        } else if !hdr.is_valid_with_max_size(self.max_msg_size) || !body.is_valid() {
             return Err(Error::InvalidMessage);
         }
+        self.tracer.message(
+            Direction::Recv,
+            &hdr,
+            rfds.as_ref().map_or(0, |fds| fds.len()),
+        );
 
         Ok((hdr, body, rfds))
     }
 
     /// Receive a message with header and optional content. Callers need to
     /// pre-allocate a big enough buffer to receive the message body and
-    /// optional payload. If there are attached file descriptor associated
-    /// with the message, the first MAX_ATTACHED_FD_ENTRIES file descriptors
-    /// will be accepted and all other file descriptor will be discard
-    /// silently.
+    /// optional payload. Messages with more than MAX_ATTACHED_FD_ENTRIES
+    /// file descriptors fail with TooManyFds.
     ///
     /// # Return:
     /// * - (message header, message size, [received fds]) on success.
@@ -506,19 +1115,24 @@ impl<R: Req> Endpoint<R> {
             },
         ];
         let (bytes, rfds) = self.recv_into_iovec_all(&mut iovs[..])?;
+        let rfds = self.recv_fds(bytes, rfds)?;
 
         if bytes < mem::size_of::<VhostUserMsgHeader<R>>() {
             return Err(Error::PartialMessage);
-        } else if !hdr.is_valid() {
+        } else if !hdr.is_valid_with_max_size(self.max_msg_size) {
             return Err(Error::InvalidMessage);
         }
+        self.tracer.message(
+            Direction::Recv,
+            &hdr,
+            rfds.as_ref().map_or(0, |fds| fds.len()),
+        );
 
         Ok((hdr, bytes - mem::size_of::<VhostUserMsgHeader<R>>(), rfds))
     }
 
     /// Receive a message with optional payload and attached file descriptors.
-    /// Note, only the first MAX_ATTACHED_FD_ENTRIES file descriptors will be
-    /// accepted and all other file descriptor will be discard silently.
+    /// Messages with more than MAX_ATTACHED_FD_ENTRIES file descriptors fail with TooManyFds.
     ///
     /// # Return:
     /// * - (message header, message body, size of payload, [received fds]) on success.
@@ -549,13 +1163,19 @@ impl<R: Req> Endpoint<R> {
             },
         ];
         let (bytes, rfds) = self.recv_into_iovec_all(&mut iovs[..])?;
+        let rfds = self.recv_fds(bytes, rfds)?;
 
         let total = mem::size_of::<VhostUserMsgHeader<R>>() + mem::size_of::<T>();
         if bytes < total {
             return Err(Error::PartialMessage);
-        } else if !hdr.is_valid() || !body.is_valid() {
+        } else if !hdr.is_valid_with_max_size(self.max_msg_size) || !body.is_valid() {
             return Err(Error::InvalidMessage);
         }
+        self.tracer.message(
+            Direction::Recv,
+            &hdr,
+            rfds.as_ref().map_or(0, |fds| fds.len()),
+        );
 
         Ok((hdr, body, bytes - total, rfds))
     }
@@ -569,6 +1189,24 @@ impl<R: Req> Endpoint<R> {
             }
         }
     }
+
+    /// Take ownership of the only file descriptor attached to a message.
+    ///
+    /// # Return:
+    /// * - Some(File) if exactly one file descriptor has been received.
+    /// * - None otherwise, and all received file descriptors are closed.
+    pub fn take_single_file(rfds: Option<Vec<RawFd>>) -> Option<File> {
+        match rfds {
+            Some(ref fds) if fds.len() == 1 => {
+                // Safe because we have just received the rawfd from kernel.
+                Some(unsafe { File::from_raw_fd(fds[0]) })
+            }
+            _ => {
+                Self::close_rfds(rfds);
+                None
+            }
+        }
+    }
 }
 
 impl<T: Req> AsRawFd for Endpoint<T> {
@@ -577,6 +1215,22 @@ impl<T: Req> AsRawFd for Endpoint<T> {
     }
 }
 
+impl<T: Req> Drop for Endpoint<T> {
+    fn drop(&mut self) {
+        Self::close_rfds(self.rfds.take());
+    }
+}
+
+// Get the part of the vectors `iovs` left once `skip_size` bytes have been sent.
+fn get_sub_iovs<'a>(iovs: &[&'a [u8]], skip_size: usize) -> Vec<&'a [u8]> {
+    let iov_lens: Vec<usize> = iovs.iter().map(|iov| iov.len()).collect();
+    let (nr_skip, offset) = get_sub_iovs_offset(&iov_lens, skip_size);
+    let mut sub_iovs = Vec::with_capacity(iovs.len() - nr_skip);
+    sub_iovs.push(&iovs[nr_skip][offset..]);
+    sub_iovs.extend_from_slice(&iovs[(nr_skip + 1)..]);
+    sub_iovs
+}
+
 // Given a slice of sizes and the `skip_size`, return the offset of `skip_size` in the slice.
 // For example:
 //     let iov_lens = vec![4, 4, 5];
@@ -604,6 +1258,7 @@ mod tests {
     use std::fs::File;
     use std::io::{Read, Seek, SeekFrom, Write};
     use std::os::unix::io::FromRawFd;
+    use std::thread;
     use vmm_sys_util::tempfile::TempFile;
 
     const UNIX_SOCKET_LISTENER: &'static str = "/tmp/vhost_user_test_rust_listener";
@@ -611,12 +1266,39 @@ mod tests {
     const UNIX_SOCKET_DATA: &'static str = "/tmp/vhost_user_test_rust_data";
     const UNIX_SOCKET_FD: &'static str = "/tmp/vhost_user_test_rust_fd";
     const UNIX_SOCKET_SEND: &'static str = "/tmp/vhost_user_test_rust_send";
+    const UNIX_SOCKET_NONBLOCKING: &str = "/tmp/vhost_user_test_rust_nonblocking";
+    const UNIX_SOCKET_TIMEOUT: &str = "/tmp/vhost_user_test_rust_timeout";
+    const UNIX_SOCKET_PERMISSIONS: &str = "/tmp/vhost_user_test_rust_permissions";
+    const UNIX_SOCKET_ABSTRACT: &str = "vhost_user_test_rust_abstract";
+    const UNIX_SOCKET_SEQPACKET: &str = "/tmp/vhost_user_test_rust_seqpacket";
+    const UNIX_SOCKET_VSOCK_PORT: u32 = 0x5555;
 
     #[test]
     fn create_listener() {
         let _ = Listener::new(UNIX_SOCKET_LISTENER, true).unwrap();
     }
 
+    #[test]
+    fn listener_permissions() {
+        let listener = Listener::new(UNIX_SOCKET_PERMISSIONS, true).unwrap();
+        listener.set_mode(0o600).unwrap();
+        let meta = fs::metadata(UNIX_SOCKET_PERMISSIONS).unwrap();
+        assert_eq!(meta.permissions().mode() & 0o777, 0o600);
+        // Keep the current owner, which is the only one allowed when not running as root.
+        listener.set_owner(None, None).unwrap();
+
+        let listener = Listener::new_abstract(UNIX_SOCKET_ABSTRACT).unwrap();
+        assert!(listener.set_mode(0o600).is_err());
+        let addr = SocketAddr::from_abstract_name(UNIX_SOCKET_ABSTRACT.as_bytes()).unwrap();
+        let sock = UnixStream::connect_addr(&addr).unwrap();
+        let peer = listener.accept().unwrap().unwrap();
+        let cred = PeerCredentials::from_stream(&peer).unwrap();
+        assert_eq!(cred.pid, std::process::id() as i32);
+        // Safe because getuid() can't fail.
+        assert_eq!(cred.uid, unsafe { libc::getuid() });
+        drop(sock);
+    }
+
     #[test]
     fn accept_connection() {
         let listener = Listener::new(UNIX_SOCKET_CONNECTION, true).unwrap();
@@ -840,4 +1522,223 @@ mod tests {
         assert_eq!(hdr1, hdr2);
         assert!(rfds.is_none());
     }
+
+    #[test]
+    fn recv_too_many_fds() {
+        let (master, slave) = UnixStream::pair().unwrap();
+        let mut master = Endpoint::<MasterReq>::from_stream(master);
+        let mut slave = Endpoint::<MasterReq>::from_stream(slave);
+
+        let fd = TempFile::new().unwrap().into_file();
+        let fds = vec![fd.as_raw_fd(); MAX_ATTACHED_FD_ENTRIES + 1];
+        let hdr = VhostUserMsgHeader::new(MasterReq::SET_MEM_TABLE, 0, 0);
+        master.send_header(&hdr, Some(&fds)).unwrap();
+        match slave.recv_header() {
+            Err(Error::TooManyFds(max)) => assert_eq!(max, MAX_ATTACHED_FD_ENTRIES),
+            res => panic!("unexpected result {:?}", res.map(|(hdr, _)| hdr.get_code())),
+        }
+    }
+
+    #[test]
+    fn send_iovecs() {
+        let (master, slave) = UnixStream::pair().unwrap();
+        let mut master = Endpoint::<MasterReq>::from_stream(master);
+        let mut slave = Endpoint::<MasterReq>::from_stream(slave);
+
+        let data = [0x1u8, 0x2, 0x3, 0x4, 0x5, 0x6];
+        let iovs: Vec<&[u8]> = vec![&data[..2], &data[2..3], &data[3..]];
+        assert_eq!(get_sub_iovs(&iovs, 2), vec![&data[2..3], &data[3..]]);
+        assert_eq!(get_sub_iovs(&iovs, 4), vec![&data[4..]]);
+
+        // More vectors than kept on the stack by sendmsg().
+        let iovs: Vec<&[u8]> = data.chunks(1).collect();
+        assert_eq!(master.send_iovec_all(&iovs, None).unwrap(), data.len());
+        let (bytes, buf) = slave.recv_data(data.len()).unwrap();
+        assert_eq!(bytes, data.len());
+        assert_eq!(&buf[..], &data[..]);
+    }
+
+    #[test]
+    fn send_recv_jumbo() {
+        let (master, slave) = UnixStream::pair().unwrap();
+        let mut master = Endpoint::<MasterReq>::from_stream(master);
+        let mut slave = Endpoint::<MasterReq>::from_stream(slave);
+        assert_eq!(master.max_msg_size(), MAX_MSG_SIZE);
+        assert!(master.set_max_msg_size(MAX_MSG_SIZE - 1).is_err());
+        assert!(master.set_max_msg_size(MAX_JUMBO_MSG_SIZE + 1).is_err());
+
+        // Bigger than the socket buffers, so it's received in several chunks.
+        let len = 0x4_0000;
+        let payload: Vec<u8> = (0..len).map(|i| i as u8).collect();
+        let body = VhostUserU64::new(0);
+        let hdr = VhostUserMsgHeader::new(MasterReq::SET_CONFIG, 0x1, (len + 8) as u32);
+        match master.send_message_with_payload(&hdr, &body, &payload, None) {
+            Err(Error::OversizedMsg) => {}
+            _ => panic!("oversized message sent"),
+        }
+
+        master.set_max_msg_size(MAX_JUMBO_MSG_SIZE).unwrap();
+        let sender = thread::spawn(move || {
+            master
+                .send_message_with_payload(&hdr, &body, &payload, None)
+                .unwrap();
+            master
+                .send_message_with_payload(&hdr, &body, &payload, None)
+                .unwrap();
+            payload
+        });
+
+        // The header is rejected with the default limit.
+        match slave.recv_header() {
+            Err(Error::InvalidMessage) => {}
+            _ => panic!("oversized message received"),
+        }
+        let mut buf = Vec::new();
+        assert_eq!(slave.recv_data_into(&mut buf, len + 8).unwrap(), len + 8);
+
+        slave.set_max_msg_size(MAX_JUMBO_MSG_SIZE).unwrap();
+        let (hdr2, rfds) = slave.recv_header().unwrap();
+        assert_eq!(hdr, hdr2);
+        assert!(rfds.is_none());
+        assert_eq!(slave.recv_data_into(&mut buf, len + 8).unwrap(), len + 8);
+        let payload = sender.join().unwrap();
+        assert_eq!(&buf[8..], &payload[..]);
+    }
+
+    #[test]
+    fn send_recv_seqpacket() {
+        let listener = Listener::new_seqpacket(UNIX_SOCKET_SEQPACKET, true).unwrap();
+        let mut master = Endpoint::<MasterReq>::connect_seqpacket(UNIX_SOCKET_SEQPACKET).unwrap();
+        let sock = listener.accept().unwrap().unwrap();
+        let mut slave = Endpoint::<MasterReq>::from_stream(sock);
+        assert!(slave.seqpacket);
+
+        // The header and the body are received separately from the same packet.
+        let fd = TempFile::new().unwrap().into_file();
+        let hdr1 = VhostUserMsgHeader::new(MasterReq::SET_FEATURES, 0x1, 8);
+        let features1 = VhostUserU64::new(0x1234);
+        master
+            .send_message(&hdr1, &features1, Some(&[fd.as_raw_fd()]))
+            .unwrap();
+        master.send_header(&hdr1, None).unwrap();
+        let (hdr2, rfds) = slave.recv_header().unwrap();
+        assert_eq!(hdr1, hdr2);
+        assert_eq!(rfds.as_ref().map(|fds| fds.len()), Some(1));
+        Endpoint::<MasterReq>::close_rfds(rfds);
+        let (bytes, buf, rfds) = slave.recv_into_buf(8).unwrap();
+        assert_eq!(bytes, 8);
+        assert_eq!(buf, 0x1234u64.to_ne_bytes());
+        assert!(rfds.is_none());
+
+        // Reads don't cross packet boundaries.
+        let (bytes, _, _) = slave.recv_into_buf(0x100).unwrap();
+        assert_eq!(bytes, mem::size_of::<VhostUserMsgHeader<MasterReq>>());
+
+        slave.set_nonblocking(true).unwrap();
+        master.send_message(&hdr1, &features1, None).unwrap();
+        let (hdr2, body, rfds) = slave.handle_readable().unwrap().unwrap();
+        assert_eq!(hdr1, hdr2);
+        assert_eq!(body, 0x1234u64.to_ne_bytes());
+        assert!(rfds.is_none());
+        assert!(slave.handle_readable().unwrap().is_none());
+    }
+
+    #[test]
+    #[ignore]
+    fn connect_vsock() {
+        // Needs the vsock loopback transport of the host.
+        let listener = Listener::new_vsock(UNIX_SOCKET_VSOCK_PORT).unwrap();
+        assert!(listener.set_mode(0o600).is_err());
+        let mut master =
+            Endpoint::<MasterReq>::connect_vsock(libc::VMADDR_CID_LOCAL, UNIX_SOCKET_VSOCK_PORT)
+                .unwrap();
+        let sock = listener.accept().unwrap().unwrap();
+        let mut slave = Endpoint::<MasterReq>::from_stream(sock);
+
+        let hdr1 = VhostUserMsgHeader::new(MasterReq::GET_FEATURES, 0, 0);
+        master.send_header(&hdr1, None).unwrap();
+        let (hdr2, rfds) = slave.recv_header().unwrap();
+        assert_eq!(hdr1, hdr2);
+        assert!(rfds.is_none());
+    }
+
+    #[test]
+    fn recv_nonblocking() {
+        let listener = Listener::new(UNIX_SOCKET_NONBLOCKING, true).unwrap();
+        let mut master = Endpoint::<MasterReq>::connect(UNIX_SOCKET_NONBLOCKING).unwrap();
+        let sock = listener.accept().unwrap().unwrap();
+        let mut slave = Endpoint::<MasterReq>::from_stream(sock);
+        slave.set_nonblocking(true).unwrap();
+        assert!(slave.handle_readable().unwrap().is_none());
+
+        // Send a message in three parts, with a file descriptor attached to the first one.
+        let hdr1 = VhostUserMsgHeader::new(MasterReq::SET_FEATURES, 0x1, 8);
+        let hdr_buf = unsafe {
+            slice::from_raw_parts(
+                (&hdr1 as *const VhostUserMsgHeader<MasterReq>) as *const u8,
+                mem::size_of::<VhostUserMsgHeader<MasterReq>>(),
+            )
+        };
+        let features = 0x1234u64.to_le_bytes();
+        let fd = TempFile::new().unwrap().into_file();
+        master
+            .send_slice(&hdr_buf[..6], Some(&[fd.as_raw_fd()]))
+            .unwrap();
+        assert!(slave.handle_readable().unwrap().is_none());
+        master.send_slice(&hdr_buf[6..], None).unwrap();
+        assert!(slave.handle_readable().unwrap().is_none());
+        master.send_slice(&features[..], None).unwrap();
+        let (hdr2, body, rfds) = slave.handle_readable().unwrap().unwrap();
+        assert_eq!(hdr1, hdr2);
+        assert_eq!(body, features.to_vec());
+        assert!(Endpoint::<MasterReq>::take_single_file(rfds).is_some());
+
+        // Messages sent back to back are returned one at a time.
+        let hdr3 = VhostUserMsgHeader::new(MasterReq::SET_OWNER, 0x1, 0);
+        master.send_header(&hdr3, None).unwrap();
+        master.send_header(&hdr1, None).unwrap();
+        let (hdr4, body, rfds) = slave.handle_readable().unwrap().unwrap();
+        assert_eq!(hdr3, hdr4);
+        assert!(body.is_empty());
+        assert!(rfds.is_none());
+        assert!(slave.handle_readable().unwrap().is_none());
+
+        // The connection is closed in the middle of the second message.
+        drop(master);
+        match slave.handle_readable() {
+            Err(Error::SocketBroken(_)) => {}
+            _ => panic!("expected broken socket"),
+        }
+    }
+
+    #[test]
+    fn recv_timeout() {
+        let listener = Listener::new(UNIX_SOCKET_TIMEOUT, true).unwrap();
+        let mut master = Endpoint::<MasterReq>::connect(UNIX_SOCKET_TIMEOUT).unwrap();
+        let sock = listener.accept().unwrap().unwrap();
+        let mut slave = Endpoint::<MasterReq>::from_stream(sock);
+        assert!(slave.set_timeout(Some(Duration::from_secs(0))).is_err());
+        slave.set_timeout(Some(Duration::from_millis(10))).unwrap();
+
+        match slave.recv_header() {
+            Err(Error::Timeout) => {}
+            _ => panic!("expected timeout"),
+        }
+
+        // The timeout expires in the middle of the message.
+        let hdr = VhostUserMsgHeader::new(MasterReq::SET_FEATURES, 0x1, 8);
+        master.send_header(&hdr, None).unwrap();
+        match slave.discard_message() {
+            Err(Error::PartialMessage) => {}
+            _ => panic!("expected partial message"),
+        }
+
+        let hdr = VhostUserMsgHeader::new(MasterReq::SET_OWNER, 0x1, 0);
+        master.send_header(&hdr, None).unwrap();
+        assert_eq!(slave.discard_message().unwrap(), hdr);
+
+        slave.set_timeout(None).unwrap();
+        master.send_header(&hdr, None).unwrap();
+        assert_eq!(slave.recv_header().unwrap().0, hdr);
+    }
 }
@@ -0,0 +1,556 @@
+// Copyright (C) 2020 Alibaba Cloud Computing. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! A vhost-user-console slave backend built on the daemon framework.
+//!
+//! The single port of the device forwards the bytes written by the guest on the transmit
+//! virtqueue to a `ConsolePort`, usually the master side of a PTY, and fills the receive
+//! virtqueue with the bytes read from it. Both virtqueues are served by a single worker thread,
+//! which also waits for incoming bytes: backends must be registered to the worker thread with
+//! `ConsoleBackend::register_port()` before the daemon is started.
+//!
+//! The size of the console is exposed through the device configuration space, and the master is
+//! notified of resizes through the slave communication channel. Multiple ports aren't supported.
+
+use std::cmp;
+use std::ffi::CStr;
+use std::fs::File;
+use std::io;
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::sync::{Arc, RwLock};
+
+use vm_memory::{Address, Bytes, GuestMemoryMmap};
+use vmm_sys_util::epoll::EventSet;
+
+use super::daemon::{
+    read_config_space, DescriptorChain, VhostUserBackend, Vring, VringEpollHandler,
+};
+use super::message::{VhostUserProtocolFeatures, VHOST_USER_CONFIG_OFFSET};
+use super::MasterReqSender;
+use crate::features::{DeviceFeatures, VirtioConsoleFeatures, VirtioFeatures};
+
+// Size of the virtio-console configuration space: cols, rows, max_nr_ports and emerg_wr.
+const VIRTIO_CONSOLE_CONFIG_SIZE: usize = 12;
+const CONFIG_EMERG_WR_OFFSET: usize = 8;
+// Largest chunk of data moved at once between a virtqueue and the port.
+const MAX_CHUNK_SIZE: usize = 4096;
+// Size of the virtqueues.
+const QUEUE_SIZE: usize = 128;
+// Indexes of the receive and transmit virtqueues of the port.
+const RX_QUEUE: u16 = 0;
+const TX_QUEUE: u16 = 1;
+
+/// Character device the port of the console is connected to.
+pub struct ConsolePort {
+    file: File,
+}
+
+impl ConsolePort {
+    /// Open a new PTY and use its master side as port.
+    ///
+    /// Returns the port together with the path of the slave side, for a terminal to be attached.
+    pub fn open_pty() -> io::Result<(Self, String)> {
+        // Safe because we check the return value.
+        let fd = unsafe { libc::posix_openpt(libc::O_RDWR | libc::O_NOCTTY | libc::O_CLOEXEC) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // Safe because we have just opened the file descriptor.
+        let file = unsafe { File::from_raw_fd(fd) };
+        let mut name = [0 as libc::c_char; 64];
+        // Safe because the file descriptor is a PTY master, the buffer is valid for its whole
+        // length and we check the return values.
+        unsafe {
+            if libc::grantpt(fd) < 0 || libc::unlockpt(fd) < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            let ret = libc::ptsname_r(fd, name.as_mut_ptr(), name.len());
+            if ret != 0 {
+                return Err(io::Error::from_raw_os_error(ret));
+            }
+        }
+        // Safe because ptsname_r() has written a null terminated string.
+        let path = unsafe { CStr::from_ptr(name.as_ptr()) }
+            .to_string_lossy()
+            .into_owned();
+        Ok((Self::from_file(file)?, path))
+    }
+
+    /// Use any file descriptor transferring a byte stream, like a PTY or a stream socket, as port.
+    ///
+    /// The file descriptor is switched to non-blocking mode.
+    pub fn from_file(file: File) -> io::Result<Self> {
+        // Safe because the file descriptor is valid and we check the return values.
+        let flags = unsafe { libc::fcntl(file.as_raw_fd(), libc::F_GETFL) };
+        if flags < 0
+            || unsafe { libc::fcntl(file.as_raw_fd(), libc::F_SETFL, flags | libc::O_NONBLOCK) } < 0
+        {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(ConsolePort { file })
+    }
+
+    fn recv(&self, buf: &mut [u8]) -> io::Result<usize> {
+        // Safe because the buffer is valid for its whole length and we check the return value.
+        let ret = unsafe {
+            libc::read(
+                self.file.as_raw_fd(),
+                buf.as_mut_ptr() as *mut libc::c_void,
+                buf.len(),
+            )
+        };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(ret as usize)
+    }
+
+    fn send(&self, buf: &[u8]) -> io::Result<usize> {
+        // Safe because the buffer is valid for its whole length and we check the return value.
+        let ret = unsafe {
+            libc::write(
+                self.file.as_raw_fd(),
+                buf.as_ptr() as *const libc::c_void,
+                buf.len(),
+            )
+        };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(ret as usize)
+    }
+}
+
+impl AsRawFd for ConsolePort {
+    fn as_raw_fd(&self) -> RawFd {
+        self.file.as_raw_fd()
+    }
+}
+
+/// A vhost-user-console device backend.
+///
+/// Virtqueues 0 and 1 are the receive and transmit virtqueues of the single port of the device.
+pub struct ConsoleBackend {
+    port: ConsolePort,
+    cols: u16,
+    rows: u16,
+    vu_req: Option<MasterReqSender>,
+    mem: Option<GuestMemoryMmap>,
+}
+
+impl ConsoleBackend {
+    /// Create a backend forwarding the data of the console to `port`, with a console of `cols`
+    /// columns and `rows` rows.
+    pub fn new(port: ConsolePort, cols: u16, rows: u16) -> Self {
+        ConsoleBackend {
+            port,
+            cols,
+            rows,
+            vu_req: None,
+            mem: None,
+        }
+    }
+
+    /// Register the port to the worker thread of the daemon, as returned by
+    /// `Daemon::get_vring_workers()`, to process incoming data.
+    pub fn register_port(
+        &self,
+        workers: &[Arc<VringEpollHandler<ConsoleBackend>>],
+    ) -> io::Result<()> {
+        if workers.len() != 1 {
+            return Err(io::Error::from_raw_os_error(libc::EINVAL));
+        }
+        // Incoming data is left in the port when the guest runs out of receive buffers, so wait
+        // for new data only and retry when buffers are made available.
+        workers[0].register_listener(
+            self.port.as_raw_fd(),
+            EventSet::IN | EventSet::EDGE_TRIGGERED,
+            self.port_event(),
+        )
+    }
+
+    /// Change the size of the console, and notify the master once the communication channel to
+    /// the master has been set up.
+    pub fn resize(&mut self, cols: u16, rows: u16) -> io::Result<()> {
+        if cols == self.cols && rows == self.rows {
+            return Ok(());
+        }
+        self.cols = cols;
+        self.rows = rows;
+        if let Some(ref mut vu_req) = self.vu_req {
+            vu_req.send_config_change().map_err(io::Error::other)?;
+        }
+        Ok(())
+    }
+
+    fn port_event(&self) -> u64 {
+        // Data num_queues is reserved for the exit event of the worker thread.
+        (self.num_queues() + 1) as u64
+    }
+
+    fn config_space(&self) -> [u8; VIRTIO_CONSOLE_CONFIG_SIZE] {
+        let mut config = [0u8; VIRTIO_CONSOLE_CONFIG_SIZE];
+        config[0..2].copy_from_slice(&self.cols.to_le_bytes());
+        config[2..4].copy_from_slice(&self.rows.to_le_bytes());
+        config[4..8].copy_from_slice(&1u32.to_le_bytes());
+        config
+    }
+
+    fn process_rx(&self, mem: &GuestMemoryMmap, vring: &mut Vring) -> io::Result<()> {
+        let mut buf = vec![0u8; MAX_CHUNK_SIZE];
+        let mut used = false;
+        while let Some(chain) = vring.pop_avail(mem)? {
+            let size = chain.writable().map(|desc| desc.len() as usize).sum();
+            let len = match self.port.recv(&mut buf[..cmp::min(size, MAX_CHUNK_SIZE)]) {
+                Ok(len) if len > 0 => len,
+                result => {
+                    // Give the buffers back for the next data.
+                    vring.return_avail(&chain);
+                    match result {
+                        // The port is hung up, like a PTY without a terminal attached to it.
+                        Ok(_) => break,
+                        Err(ref e) if e.raw_os_error() == Some(libc::EIO) => break,
+                        Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                        Err(e) => return Err(e),
+                    }
+                }
+            };
+            Self::write_chain(mem, &chain, &buf[..len])?;
+            vring.add_used(mem, chain.head_index(), len as u32)?;
+            used = true;
+        }
+        if used {
+            vring.notify_used_queue(mem)?;
+        }
+        Ok(())
+    }
+
+    fn process_tx(&self, mem: &GuestMemoryMmap, vring: &mut Vring) -> io::Result<()> {
+        let mut buf = vec![0u8; MAX_CHUNK_SIZE];
+        let mut used = false;
+        while let Some(chain) = vring.pop_avail(mem)? {
+            for desc in chain.readable() {
+                let mut addr = desc.addr();
+                let mut remaining = desc.len() as usize;
+                while remaining > 0 {
+                    let len = cmp::min(remaining, MAX_CHUNK_SIZE);
+                    mem.read_slice(&mut buf[..len], addr)
+                        .map_err(|_| io::Error::from_raw_os_error(libc::EFAULT))?;
+                    self.send_all(&buf[..len])?;
+                    addr = addr.unchecked_add(len as u64);
+                    remaining -= len;
+                }
+            }
+            vring.add_used(mem, chain.head_index(), 0)?;
+            used = true;
+        }
+        if used {
+            vring.notify_used_queue(mem)?;
+        }
+        Ok(())
+    }
+
+    // Data is dropped when the port can't take it.
+    fn send_all(&self, mut buf: &[u8]) -> io::Result<()> {
+        while !buf.is_empty() {
+            match self.port.send(buf) {
+                Ok(len) => buf = &buf[len..],
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+
+    // Write received data into the writable buffers of the chain, which are large enough.
+    fn write_chain(mem: &GuestMemoryMmap, chain: &DescriptorChain, buf: &[u8]) -> io::Result<()> {
+        let mut written = 0;
+        for desc in chain.writable() {
+            if written == buf.len() {
+                break;
+            }
+            let len = cmp::min(desc.len() as usize, buf.len() - written);
+            mem.write_slice(&buf[written..written + len], desc.addr())
+                .map_err(|_| io::Error::from_raw_os_error(libc::EFAULT))?;
+            written += len;
+        }
+        Ok(())
+    }
+}
+
+impl VhostUserBackend for ConsoleBackend {
+    fn num_queues(&self) -> usize {
+        2
+    }
+
+    fn max_queue_size(&self) -> usize {
+        QUEUE_SIZE
+    }
+
+    fn features(&self) -> u64 {
+        let features = VirtioConsoleFeatures::SIZE | VirtioConsoleFeatures::EMERG_WRITE;
+        features.with_common(VirtioFeatures::VERSION_1 | VirtioFeatures::PROTOCOL_FEATURES)
+    }
+
+    fn protocol_features(&self) -> VhostUserProtocolFeatures {
+        // MQ lets the master enable the transmit virtqueue.
+        VhostUserProtocolFeatures::MQ
+            | VhostUserProtocolFeatures::CONFIG
+            | VhostUserProtocolFeatures::SLAVE_REQ
+    }
+
+    fn update_memory(&mut self, mem: GuestMemoryMmap) -> io::Result<()> {
+        self.mem = Some(mem);
+        Ok(())
+    }
+
+    fn get_config(&self, offset: u32, size: u32) -> Vec<u8> {
+        read_config_space(&self.config_space(), offset, size)
+    }
+
+    fn set_config(&mut self, offset: u32, buf: &[u8]) -> io::Result<()> {
+        // Only the emerg_wr field is writable, to output a character before the virtqueues
+        // are set up.
+        let offset = offset.saturating_sub(VHOST_USER_CONFIG_OFFSET) as usize;
+        if offset != CONFIG_EMERG_WR_OFFSET || buf.len() != 4 {
+            return Err(io::Error::from_raw_os_error(libc::EPERM));
+        }
+        self.send_all(&buf[..1])
+    }
+
+    fn set_slave_req_fd(&mut self, vu_req: MasterReqSender) {
+        self.vu_req = Some(vu_req);
+    }
+
+    fn queues_per_thread(&self) -> Vec<u64> {
+        vec![0x3]
+    }
+
+    fn process_queue(
+        &self,
+        queue_index: u16,
+        vring: &mut Vring,
+        _thread_id: usize,
+    ) -> io::Result<()> {
+        let mem = match self.mem {
+            Some(ref mem) => mem,
+            None => return Ok(()),
+        };
+        match queue_index {
+            RX_QUEUE => self.process_rx(mem, vring),
+            TX_QUEUE => self.process_tx(mem, vring),
+            _ => Ok(()),
+        }
+    }
+
+    fn handle_event(
+        &self,
+        device_event: u16,
+        _evset: EventSet,
+        vrings: &[Arc<RwLock<Vring>>],
+        thread_id: usize,
+    ) -> io::Result<bool> {
+        // Incoming data is processed by the receive virtqueue.
+        let queue_index = if u64::from(device_event) == self.port_event() {
+            RX_QUEUE
+        } else {
+            device_event
+        };
+        if let Some(vring) = vrings.get(queue_index as usize) {
+            let mut vring = vring.write().unwrap();
+            if vring.is_enabled() {
+                self.process_queue(queue_index, &mut vring, thread_id)?;
+            }
+        }
+        Ok(false)
+    }
+}
+
+#[cfg(all(test, feature = "vhost-user-master"))]
+mod tests {
+    use super::*;
+    use crate::backend::{
+        VhostFeatures, VhostMemory, VhostOwner, VhostUserMemoryRegionInfo, VhostVring,
+        VringConfigData,
+    };
+    use crate::vhost_user::message::VhostUserConfigFlags;
+    use crate::vhost_user::{
+        Daemon, HandlerResult, Listener, Master, MasterReqHandler, VhostUserMaster,
+        VhostUserMasterReqHandler,
+    };
+    use std::io::{Read, Write};
+    use std::os::unix::io::IntoRawFd;
+    use std::os::unix::net::UnixStream;
+    use std::sync::Mutex;
+    use std::thread;
+    use vm_memory::{FileOffset, GuestAddress};
+    use vmm_sys_util::eventfd::EventFd;
+    use vmm_sys_util::tempfile::TempFile;
+
+    // Guest physical addresses of the rings and buffers, in a single region mapped at VA_BASE.
+    const VA_BASE: u64 = 0x7f00_0000_0000;
+    const RING_ADDRS: [(u64, u64, u64); 2] = [(0x1000, 0x2000, 0x3000), (0x4000, 0x5000, 0x6000)];
+    const RX_BUF: u64 = 0x8000;
+    const TX_BUF: u64 = 0x9000;
+
+    #[derive(Default)]
+    struct ConfigChangeHandler {
+        config_changed: bool,
+    }
+
+    impl VhostUserMasterReqHandler for ConfigChangeHandler {
+        fn handle_config_change(&mut self) -> HandlerResult<u64> {
+            self.config_changed = true;
+            Ok(0)
+        }
+    }
+
+    // Make the buffer at `addr` available as the only descriptor of the vring.
+    fn add_avail(mem: &GuestMemoryMmap, queue: usize, addr: u64, len: u32, write: bool) {
+        let (desc, avail, _) = RING_ADDRS[queue];
+        mem.write_obj(addr, GuestAddress(desc)).unwrap();
+        mem.write_obj(len, GuestAddress(desc + 8)).unwrap();
+        mem.write_obj(if write { 2u16 } else { 0u16 }, GuestAddress(desc + 12))
+            .unwrap();
+        mem.write_obj(0u16, GuestAddress(avail + 4)).unwrap();
+        mem.write_obj(1u16, GuestAddress(avail + 2)).unwrap();
+    }
+
+    #[test]
+    fn test_console_backend() {
+        let path = "/tmp/vhost_user_lib_unit_test_console_backend";
+        let (sock, mut peer) = UnixStream::pair().unwrap();
+        // Safe because we take the ownership of the socket.
+        let file = unsafe { File::from_raw_fd(sock.into_raw_fd()) };
+        let port = ConsolePort::from_file(file).unwrap();
+        let backend = Arc::new(RwLock::new(ConsoleBackend::new(port, 80, 25)));
+        let mut daemon = Daemon::new("test-console".to_string(), backend.clone()).unwrap();
+        backend
+            .read()
+            .unwrap()
+            .register_port(&daemon.get_vring_workers())
+            .unwrap();
+
+        let file = TempFile::new().unwrap().into_file();
+        file.set_len(0x10000).unwrap();
+        let mem = GuestMemoryMmap::from_ranges_with_files(&[(
+            GuestAddress(0),
+            0x10000,
+            Some(FileOffset::new(file.try_clone().unwrap(), 0)),
+        )])
+        .unwrap();
+
+        let listener = Listener::new(path, true).unwrap();
+        let console = backend.clone();
+        let master_thread = thread::spawn(move || {
+            let mut master = Master::connect(path, 2).unwrap();
+            master.set_owner().unwrap();
+            let features = master.get_features().unwrap();
+            assert_ne!(features & VirtioConsoleFeatures::SIZE.bits(), 0);
+            master.set_features(features).unwrap();
+            master.get_protocol_features().unwrap();
+            master
+                .set_protocol_features(
+                    VhostUserProtocolFeatures::MQ
+                        | VhostUserProtocolFeatures::CONFIG
+                        | VhostUserProtocolFeatures::SLAVE_REQ,
+                )
+                .unwrap();
+            let master_be = Arc::new(Mutex::new(ConfigChangeHandler::default()));
+            let mut master_handler = MasterReqHandler::new(master_be.clone()).unwrap();
+            master
+                .set_slave_request_fd(master_handler.get_tx_raw_fd())
+                .unwrap();
+            let (_, config) = master
+                .get_config(
+                    VHOST_USER_CONFIG_OFFSET,
+                    VIRTIO_CONSOLE_CONFIG_SIZE as u32,
+                    VhostUserConfigFlags::WRITABLE,
+                    &[0u8; VIRTIO_CONSOLE_CONFIG_SIZE],
+                )
+                .unwrap();
+            assert_eq!(&config[0..8], &[80, 0, 25, 0, 1, 0, 0, 0]);
+
+            // Resizing the console notifies the master.
+            console.write().unwrap().resize(132, 43).unwrap();
+            master_handler.handle_request().unwrap();
+            assert!(master_be.lock().unwrap().config_changed);
+            let (_, config) = master
+                .get_config(
+                    VHOST_USER_CONFIG_OFFSET,
+                    4,
+                    VhostUserConfigFlags::WRITABLE,
+                    &[0u8; 4],
+                )
+                .unwrap();
+            assert_eq!(&config[0..4], &[132, 0, 43, 0]);
+
+            // Output a character before the virtqueues are set up.
+            master
+                .set_config(
+                    VHOST_USER_CONFIG_OFFSET + CONFIG_EMERG_WR_OFFSET as u32,
+                    VhostUserConfigFlags::WRITABLE,
+                    &[b'!', 0, 0, 0],
+                )
+                .unwrap();
+            let mut buf = [0u8; 1];
+            peer.read_exact(&mut buf).unwrap();
+            assert_eq!(&buf, b"!");
+
+            let region = VhostUserMemoryRegionInfo {
+                guest_phys_addr: 0,
+                memory_size: 0x10000,
+                userspace_addr: VA_BASE,
+                mmap_offset: 0,
+                mmap_handle: file.as_raw_fd(),
+            };
+            master.set_mem_table(&[region]).unwrap();
+
+            let mut calls = Vec::new();
+            for (queue, &(desc, avail, used)) in RING_ADDRS.iter().enumerate() {
+                master.set_vring_num(queue, 16).unwrap();
+                let config = VringConfigData {
+                    queue_max_size: QUEUE_SIZE as u16,
+                    queue_size: 16,
+                    flags: 0,
+                    desc_table_addr: VA_BASE + desc,
+                    used_ring_addr: VA_BASE + used,
+                    avail_ring_addr: VA_BASE + avail,
+                    log_addr: None,
+                };
+                master.set_vring_addr(queue, &config).unwrap();
+                master.set_vring_base(queue, 0).unwrap();
+                let call = EventFd::new(0).unwrap();
+                master.set_vring_call(queue, &call).unwrap();
+                master.set_vring_enable(queue, true).unwrap();
+                let kick = EventFd::new(0).unwrap();
+                master.set_vring_kick(queue, &kick).unwrap();
+                calls.push((call, kick));
+            }
+
+            // Output data written by the guest.
+            mem.write_slice(b"login: ", GuestAddress(TX_BUF)).unwrap();
+            add_avail(&mem, 1, TX_BUF, 7, false);
+            calls[1].1.write(1).unwrap();
+            assert_eq!(calls[1].0.read().unwrap(), 1);
+            let mut buf = [0u8; 7];
+            peer.read_exact(&mut buf).unwrap();
+            assert_eq!(&buf, b"login: ");
+
+            // Input data into the guest.
+            add_avail(&mem, 0, RX_BUF, 0x100, true);
+            calls[0].1.write(1).unwrap();
+            peer.write_all(b"root\n").unwrap();
+            assert_eq!(calls[0].0.read().unwrap(), 1);
+            let len: u32 = mem.read_obj(GuestAddress(RING_ADDRS[0].2 + 8)).unwrap();
+            assert_eq!(len, 5);
+            let mut buf = [0u8; 5];
+            mem.read_slice(&mut buf, GuestAddress(RX_BUF)).unwrap();
+            assert_eq!(&buf, b"root\n");
+        });
+
+        daemon.start(listener).unwrap();
+        master_thread.join().unwrap();
+        assert!(daemon.wait().is_err());
+    }
+}
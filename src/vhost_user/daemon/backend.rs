@@ -0,0 +1,197 @@
+// Copyright (C) 2020 Alibaba Cloud Computing. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Trait to be implemented by vhost-user device backends served by the daemon.
+
+use std::cmp;
+use std::fs::File;
+use std::io;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use vm_memory::GuestMemoryMmap;
+use vmm_sys_util::epoll::EventSet;
+
+use super::Vring;
+use crate::vhost_user::device_state::DeviceStateSerializer;
+use crate::vhost_user::dirty_log::DirtyLog;
+use crate::vhost_user::message::{VhostUserProtocolFeatures, VHOST_USER_CONFIG_OFFSET};
+use crate::vhost_user::MasterReqSender;
+
+/// Trait for vhost-user device backends.
+///
+/// The daemon takes care of the vhost-user protocol and of the vring bookkeeping, and calls into
+/// the backend to query the device capabilities and to process the virtqueues whenever the guest
+/// kicks them.
+pub trait VhostUserBackend: Send + Sync + 'static {
+    /// Get the number of virtqueues supported by the device.
+    fn num_queues(&self) -> usize;
+
+    /// Get the maximum size of each virtqueue.
+    fn max_queue_size(&self) -> usize;
+
+    /// Get the virtio features supported by the device.
+    fn features(&self) -> u64;
+
+    /// Notify the backend of the virtio features acked by the driver.
+    fn acked_features(&mut self, _features: u64) {}
+
+    /// Get the vhost-user protocol features supported by the device.
+    fn protocol_features(&self) -> VhostUserProtocolFeatures;
+
+    /// Update the guest memory the virtqueues live in.
+    fn update_memory(&mut self, mem: GuestMemoryMmap) -> io::Result<()>;
+
+    /// Read the virtio device configuration space.
+    fn get_config(&self, _offset: u32, _size: u32) -> Vec<u8> {
+        Vec::new()
+    }
+
+    /// Write the virtio device configuration space.
+    fn set_config(&mut self, _offset: u32, _buf: &[u8]) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Reset the device to its initial state, on request of the master.
+    ///
+    /// The vrings have already been stopped and reset by the daemon. Only called if the backend
+    /// supports the VHOST_USER_PROTOCOL_F_RESET_DEVICE protocol feature.
+    fn reset_device(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Notify the backend of the virtio device status set by the driver, for instance to start
+    /// processing the virtqueues once DRIVER_OK is set.
+    ///
+    /// Only called if the backend supports the VHOST_USER_PROTOCOL_F_STATUS protocol feature.
+    fn set_status(&mut self, _status: u8) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Notify the backend that the master has enabled or disabled a virtqueue, for instance to
+    /// start or stop polling the device queue backing it.
+    ///
+    /// When VHOST_USER_F_PROTOCOL_FEATURES hasn't been negotiated, all virtqueues are enabled
+    /// once the features are acked.
+    fn set_queue_enabled(&mut self, _queue_index: u16, _enabled: bool) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Broadcast a RARP packet announcing the MAC address of the guest, for net devices.
+    ///
+    /// Only called if the backend supports the VHOST_USER_PROTOCOL_F_RARP protocol feature.
+    fn send_rarp(&mut self, _mac: [u8; 6]) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Set the MTU exposed to the guest, for net devices.
+    ///
+    /// Only called if the backend supports the VHOST_USER_PROTOCOL_F_MTU protocol feature.
+    fn net_set_mtu(&mut self, _mtu: u16) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Get the file descriptor of a shared object the backend has registered itself as the
+    /// exporter of through `MasterReqSender::shared_object_add()`.
+    ///
+    /// Only called if the backend supports the VHOST_USER_PROTOCOL_F_SHARED_OBJECT protocol
+    /// feature.
+    fn get_shared_object(&mut self, _uuid: [u8; 16]) -> io::Result<File> {
+        Err(io::Error::from_raw_os_error(libc::ENOSYS))
+    }
+
+    /// Get the sizes of the shared memory regions of the device, indexed by shmid, for the master
+    /// to expose them to the guest, as virtio-fs does for its DAX window.
+    ///
+    /// Only used if the backend supports the VHOST_USER_PROTOCOL_F_SHMEM protocol feature.
+    fn shared_memory_regions(&self) -> Vec<u64> {
+        Vec::new()
+    }
+
+    /// Get the serializer of the internal state of the device, to migrate it through
+    /// SET_DEVICE_STATE_FD.
+    ///
+    /// Only used if the backend supports the VHOST_USER_PROTOCOL_F_DEVICE_STATE protocol feature.
+    fn device_state(&mut self) -> Option<&mut dyn DeviceStateSerializer> {
+        None
+    }
+
+    /// Provide the communication channel to send requests to the master.
+    fn set_slave_req_fd(&mut self, _vu_req: MasterReqSender) {}
+
+    /// Provide the log to record guest pages written by the backend during live migration.
+    ///
+    /// Only used if the backend supports the VHOST_USER_PROTOCOL_F_LOG_SHMFD protocol feature.
+    fn set_dirty_log(&mut self, _log: DirtyLog) {}
+
+    /// Get the assignment of virtqueues to worker threads.
+    ///
+    /// Each entry of the returned vector describes one worker thread, as a bitmask of the
+    /// virtqueues it serves. Every virtqueue must be served by exactly one worker thread. The
+    /// default policy spawns one worker thread per virtqueue, which limits the device to 64
+    /// virtqueues.
+    fn queues_per_thread(&self) -> Vec<u64> {
+        (0..self.num_queues())
+            .map(|i| 1u64.checked_shl(i as u32).unwrap_or(0))
+            .collect()
+    }
+
+    /// Get how long the worker threads keep polling a kicked virtqueue for new buffers.
+    ///
+    /// While a virtqueue is polled, the driver is asked not to kick it, and the poll goes on as
+    /// long as new buffers are found within the duration, which spares wakeups under heavy load.
+    /// Kicks are enabled again once the virtqueue has stayed empty for the whole duration. The
+    /// default implementation disables polling. Only queried when the daemon is created.
+    fn busy_poll_duration(&self) -> Option<Duration> {
+        None
+    }
+
+    /// Process the available buffers of a virtqueue after the guest has kicked it.
+    ///
+    /// `thread_id` is the index of the worker thread, as returned by `queues_per_thread()`.
+    fn process_queue(
+        &self,
+        queue_index: u16,
+        vring: &mut Vring,
+        thread_id: usize,
+    ) -> io::Result<()>;
+
+    /// Handle an event on a worker thread.
+    ///
+    /// `device_event` is the index of the kicked virtqueue, or the data registered together with
+    /// a backend specific file descriptor by `VringEpollHandler::register_listener()`. Return
+    /// true to stop the worker thread.
+    ///
+    /// The default implementation calls `process_queue()` for kicks of enabled virtqueues and
+    /// ignores all other events.
+    fn handle_event(
+        &self,
+        device_event: u16,
+        _evset: EventSet,
+        vrings: &[Arc<RwLock<Vring>>],
+        thread_id: usize,
+    ) -> io::Result<bool> {
+        if let Some(vring) = vrings.get(device_event as usize) {
+            let mut vring = vring.write().unwrap();
+            if vring.is_enabled() {
+                self.process_queue(device_event, &mut vring, thread_id)?;
+            }
+        }
+        Ok(false)
+    }
+}
+
+/// Read `size` bytes at `offset` of the configuration space `config` of a device, for the
+/// `get_config()` implementations of the backends.
+///
+/// `offset` is relative to the start of the vhost-user configuration space, and the bytes beyond
+/// the end of `config` read as zero.
+pub fn read_config_space(config: &[u8], offset: u32, size: u32) -> Vec<u8> {
+    let mut buf = vec![0u8; size as usize];
+    let offset = offset.saturating_sub(VHOST_USER_CONFIG_OFFSET) as usize;
+    if offset < config.len() {
+        let len = cmp::min(config.len() - offset, buf.len());
+        buf[..len].copy_from_slice(&config[offset..offset + len]);
+    }
+    buf
+}
@@ -0,0 +1,570 @@
+// Copyright (C) 2020 Alibaba Cloud Computing. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Descriptor chains of split virtqueues, and the buffers they describe.
+//!
+//! `DescriptorIter` walks a chain in the descriptor table of a split vring, following indirect
+//! descriptor tables, and validates every descriptor against the guest memory. The buffers of a
+//! `DescriptorChain` may then be accessed as a stream of bytes with `ChainReader` and
+//! `ChainWriter`, rather than descriptor by descriptor.
+
+use std::cmp;
+use std::io::{self, Read, Write};
+
+use vm_memory::{Address, ByteValued, Bytes, GuestAddress, GuestMemory, GuestMemoryMmap};
+
+use super::TranslationCache;
+
+// Flags of split virtqueue descriptors.
+const VIRTQ_DESC_F_NEXT: u16 = 0x1;
+const VIRTQ_DESC_F_WRITE: u16 = 0x2;
+const VIRTQ_DESC_F_INDIRECT: u16 = 0x4;
+
+// Size of the descriptors.
+const VIRTQ_DESC_SIZE: u64 = 16;
+
+// Read an object from the guest memory, through the translation cache if any.
+fn read_obj<T: ByteValued>(
+    mem: &GuestMemoryMmap,
+    cache: Option<&mut TranslationCache>,
+    base: GuestAddress,
+    offset: u64,
+) -> io::Result<T> {
+    let addr = base
+        .checked_add(offset)
+        .ok_or_else(|| io::Error::from_raw_os_error(libc::EFAULT))?;
+    match cache {
+        Some(cache) => cache.read_obj(mem, addr),
+        None => mem
+            .read_obj(addr)
+            .map_err(|_| io::Error::from_raw_os_error(libc::EFAULT)),
+    }
+}
+
+// Check the size of an indirect descriptor table, returning its number of descriptors.
+fn indirect_table_size(len: u32, flags: u16) -> io::Result<u16> {
+    let size = u64::from(len) / VIRTQ_DESC_SIZE;
+    if flags & VIRTQ_DESC_F_NEXT != 0
+        || size == 0
+        || !u64::from(len).is_multiple_of(VIRTQ_DESC_SIZE)
+        || size > u64::from(u16::MAX)
+    {
+        return Err(io::Error::from_raw_os_error(libc::EINVAL));
+    }
+    Ok(size as u16)
+}
+
+/// Buffer in guest memory described by a virtqueue descriptor.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Descriptor {
+    addr: GuestAddress,
+    len: u32,
+    write_only: bool,
+}
+
+impl Descriptor {
+    // Create a descriptor, checking that its whole buffer is in the guest memory. Buffers found
+    // in a single cached region don't need to be looked up.
+    fn checked(
+        mem: &GuestMemoryMmap,
+        cache: Option<&mut TranslationCache>,
+        addr: u64,
+        len: u32,
+        flags: u16,
+    ) -> io::Result<Self> {
+        let desc = Descriptor {
+            addr: GuestAddress(addr),
+            len,
+            write_only: flags & VIRTQ_DESC_F_WRITE != 0,
+        };
+        if !desc.is_empty()
+            && cache.is_none_or(|cache| cache.translate(mem, desc.addr, len as usize).is_err())
+        {
+            let last = desc
+                .addr
+                .checked_add(u64::from(len) - 1)
+                .ok_or_else(|| io::Error::from_raw_os_error(libc::EFAULT))?;
+            if !mem.address_in_range(desc.addr) || !mem.address_in_range(last) {
+                return Err(io::Error::from_raw_os_error(libc::EFAULT));
+            }
+        }
+        Ok(desc)
+    }
+
+    /// Get the guest physical address of the buffer.
+    pub fn addr(&self) -> GuestAddress {
+        self.addr
+    }
+
+    /// Get the size of the buffer.
+    pub fn len(&self) -> u32 {
+        self.len
+    }
+
+    /// Check whether the buffer is empty.
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Check whether the device writes the buffer, rather than reading it.
+    pub fn is_write_only(&self) -> bool {
+        self.write_only
+    }
+}
+
+/// Iterator over the descriptors of a chain in the descriptor table of a split virtqueue.
+///
+/// A descriptor pointing to an indirect table is replaced by the descriptors of the table.
+/// Malformed chains, like chains longer than their table, nested indirect tables or descriptors
+/// outside of the guest memory, end the iteration with an error.
+pub struct DescriptorIter<'a> {
+    mem: &'a GuestMemoryMmap,
+    cache: Option<&'a mut TranslationCache>,
+    table: GuestAddress,
+    table_size: u16,
+    next: Option<u16>,
+    // whether the descriptors are read from an indirect table
+    indirect: bool,
+    // number of descriptors read from the current table
+    count: u16,
+}
+
+impl<'a> DescriptorIter<'a> {
+    /// Create an iterator over the chain headed by `head_index`, in the descriptor table at
+    /// `table` of a vring of `size` descriptors.
+    pub fn new(mem: &'a GuestMemoryMmap, table: GuestAddress, size: u16, head_index: u16) -> Self {
+        DescriptorIter {
+            mem,
+            cache: None,
+            table,
+            table_size: size,
+            next: Some(head_index),
+            indirect: false,
+            count: 0,
+        }
+    }
+
+    /// Translate the addresses of the descriptors and of their buffers through `cache`.
+    pub fn with_cache(mut self, cache: &'a mut TranslationCache) -> Self {
+        self.cache = Some(cache);
+        self
+    }
+
+    fn read_obj<T: ByteValued>(&mut self, offset: u64) -> io::Result<T> {
+        read_obj(self.mem, self.cache.as_deref_mut(), self.table, offset)
+    }
+
+    fn read_descriptor(&mut self, mut index: u16) -> io::Result<Descriptor> {
+        loop {
+            // A chain can't be longer than its table, which also breaks loops in the chain.
+            if index >= self.table_size || self.count >= self.table_size {
+                return Err(io::Error::from_raw_os_error(libc::EINVAL));
+            }
+            self.count += 1;
+            let offset = u64::from(index) * VIRTQ_DESC_SIZE;
+            let addr: u64 = self.read_obj(offset)?;
+            let len: u32 = self.read_obj(offset + 8)?;
+            let flags: u16 = self.read_obj(offset + 12)?;
+            let next: u16 = self.read_obj(offset + 14)?;
+
+            if flags & VIRTQ_DESC_F_INDIRECT != 0 {
+                if self.indirect {
+                    return Err(io::Error::from_raw_os_error(libc::EINVAL));
+                }
+                self.table = GuestAddress(addr);
+                self.table_size = indirect_table_size(len, flags)?;
+                self.indirect = true;
+                self.count = 0;
+                index = 0;
+                continue;
+            }
+
+            let desc = Descriptor::checked(self.mem, self.cache.as_deref_mut(), addr, len, flags)?;
+            if flags & VIRTQ_DESC_F_NEXT != 0 {
+                self.next = Some(next);
+            }
+            return Ok(desc);
+        }
+    }
+}
+
+impl<'a> Iterator for DescriptorIter<'a> {
+    type Item = io::Result<Descriptor>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let index = self.next.take()?;
+        Some(self.read_descriptor(index))
+    }
+}
+
+/// Chain of descriptors made available by the driver.
+///
+/// Per the virtio specification, the device readable descriptors come first in the chain,
+/// followed by the device writable ones.
+#[derive(Clone, Debug)]
+pub struct DescriptorChain {
+    head_index: u16,
+    descriptors: Vec<Descriptor>,
+}
+
+impl DescriptorChain {
+    // Read the chain headed by `head_index` in the descriptor table at `table`.
+    pub(super) fn read(
+        mem: &GuestMemoryMmap,
+        cache: &mut TranslationCache,
+        table: GuestAddress,
+        size: u16,
+        head_index: u16,
+    ) -> io::Result<Self> {
+        let descriptors = DescriptorIter::new(mem, table, size, head_index)
+            .with_cache(cache)
+            .collect::<io::Result<Vec<Descriptor>>>()?;
+        Ok(DescriptorChain {
+            head_index,
+            descriptors,
+        })
+    }
+
+    // Read the chain starting at position `index` of the descriptor table of a packed vring of
+    // `size` descriptors, returning it with the number of descriptors it takes in the vring.
+    //
+    // The head index of the chain is the buffer id set by the driver in its last descriptor.
+    pub(super) fn read_packed(
+        mem: &GuestMemoryMmap,
+        cache: &mut TranslationCache,
+        table: GuestAddress,
+        size: u16,
+        mut index: u16,
+    ) -> io::Result<(Self, u16)> {
+        let mut descriptors = Vec::new();
+        let mut count = 0;
+        loop {
+            if count >= size {
+                return Err(io::Error::from_raw_os_error(libc::EINVAL));
+            }
+            count += 1;
+            let offset = u64::from(index) * VIRTQ_DESC_SIZE;
+            let addr: u64 = read_obj(mem, Some(&mut *cache), table, offset)?;
+            let len: u32 = read_obj(mem, Some(&mut *cache), table, offset + 8)?;
+            let id: u16 = read_obj(mem, Some(&mut *cache), table, offset + 12)?;
+            let flags: u16 = read_obj(mem, Some(&mut *cache), table, offset + 14)?;
+            index = if index + 1 == size { 0 } else { index + 1 };
+
+            if flags & VIRTQ_DESC_F_INDIRECT != 0 {
+                // All the descriptors of an indirect table are part of the chain.
+                let indirect = GuestAddress(addr);
+                for i in 0..indirect_table_size(len, flags)? {
+                    let offset = u64::from(i) * VIRTQ_DESC_SIZE;
+                    let addr: u64 = read_obj(mem, Some(&mut *cache), indirect, offset)?;
+                    let len: u32 = read_obj(mem, Some(&mut *cache), indirect, offset + 8)?;
+                    let flags: u16 = read_obj(mem, Some(&mut *cache), indirect, offset + 14)?;
+                    if flags & VIRTQ_DESC_F_INDIRECT != 0 {
+                        return Err(io::Error::from_raw_os_error(libc::EINVAL));
+                    }
+                    descriptors.push(Descriptor::checked(
+                        mem,
+                        Some(&mut *cache),
+                        addr,
+                        len,
+                        flags,
+                    )?);
+                }
+            } else {
+                descriptors.push(Descriptor::checked(
+                    mem,
+                    Some(&mut *cache),
+                    addr,
+                    len,
+                    flags,
+                )?);
+            }
+            if flags & VIRTQ_DESC_F_NEXT == 0 {
+                let chain = DescriptorChain {
+                    head_index: id,
+                    descriptors,
+                };
+                return Ok((chain, count));
+            }
+        }
+    }
+
+    /// Get the index of the first descriptor of the chain, or the buffer id of the chain for
+    /// packed vrings, to return the chain to the driver.
+    pub fn head_index(&self) -> u16 {
+        self.head_index
+    }
+
+    /// Get all descriptors of the chain.
+    pub fn descriptors(&self) -> &[Descriptor] {
+        &self.descriptors
+    }
+
+    /// Get the descriptors of the buffers the device reads from.
+    pub fn readable(&self) -> impl Iterator<Item = &Descriptor> {
+        self.descriptors.iter().filter(|desc| !desc.write_only)
+    }
+
+    /// Get the descriptors of the buffers the device writes to.
+    pub fn writable(&self) -> impl Iterator<Item = &Descriptor> {
+        self.descriptors.iter().filter(|desc| desc.write_only)
+    }
+
+    /// Get a reader of the bytes of the buffers the device reads from.
+    pub fn reader<'a>(&'a self, mem: &'a GuestMemoryMmap) -> ChainReader<'a> {
+        ChainReader {
+            mem,
+            segments: Segments::new(&self.descriptors, false),
+        }
+    }
+
+    /// Get a writer of bytes into the buffers the device writes to.
+    pub fn writer<'a>(&'a self, mem: &'a GuestMemoryMmap) -> ChainWriter<'a> {
+        ChainWriter {
+            mem,
+            segments: Segments::new(&self.descriptors, true),
+        }
+    }
+}
+
+// Position in the readable or writable buffers of a chain.
+struct Segments<'a> {
+    descriptors: &'a [Descriptor],
+    write_only: bool,
+    index: usize,
+    offset: u32,
+    done: usize,
+}
+
+impl<'a> Segments<'a> {
+    fn new(descriptors: &'a [Descriptor], write_only: bool) -> Self {
+        Segments {
+            descriptors,
+            write_only,
+            index: 0,
+            offset: 0,
+            done: 0,
+        }
+    }
+
+    // Get the address and size of the rest of the current buffer, if any.
+    fn current(&mut self) -> Option<(GuestAddress, usize)> {
+        while let Some(desc) = self.descriptors.get(self.index) {
+            if desc.write_only == self.write_only && self.offset < desc.len {
+                // Can't overflow, the buffers have been checked to be in the guest memory.
+                let addr = desc.addr.unchecked_add(u64::from(self.offset));
+                return Some((addr, (desc.len - self.offset) as usize));
+            }
+            self.index += 1;
+            self.offset = 0;
+        }
+        None
+    }
+
+    fn advance(&mut self, len: usize) {
+        self.offset += len as u32;
+        self.done += len;
+    }
+
+    fn remaining(&self) -> usize {
+        let total: usize = self
+            .descriptors
+            .iter()
+            .filter(|desc| desc.write_only == self.write_only)
+            .map(|desc| desc.len as usize)
+            .sum();
+        total - self.done
+    }
+}
+
+/// Reader of the bytes of the buffers the device reads from, in the order of the chain.
+pub struct ChainReader<'a> {
+    mem: &'a GuestMemoryMmap,
+    segments: Segments<'a>,
+}
+
+impl<'a> ChainReader<'a> {
+    /// Get the number of bytes read so far.
+    pub fn bytes_read(&self) -> usize {
+        self.segments.done
+    }
+
+    /// Get the number of bytes left to read.
+    pub fn available_bytes(&self) -> usize {
+        self.segments.remaining()
+    }
+}
+
+impl<'a> Read for ChainReader<'a> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut count = 0;
+        while count < buf.len() {
+            let (addr, len) = match self.segments.current() {
+                Some(segment) => segment,
+                None => break,
+            };
+            let len = cmp::min(len, buf.len() - count);
+            self.mem
+                .read_slice(&mut buf[count..count + len], addr)
+                .map_err(|_| io::Error::from_raw_os_error(libc::EFAULT))?;
+            self.segments.advance(len);
+            count += len;
+        }
+        Ok(count)
+    }
+}
+
+/// Writer of bytes into the buffers the device writes to, in the order of the chain.
+pub struct ChainWriter<'a> {
+    mem: &'a GuestMemoryMmap,
+    segments: Segments<'a>,
+}
+
+impl<'a> ChainWriter<'a> {
+    /// Get the number of bytes written so far, to return the chain to the driver.
+    pub fn bytes_written(&self) -> usize {
+        self.segments.done
+    }
+
+    /// Get the number of bytes which may still be written.
+    pub fn available_bytes(&self) -> usize {
+        self.segments.remaining()
+    }
+}
+
+impl<'a> Write for ChainWriter<'a> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut count = 0;
+        while count < buf.len() {
+            let (addr, len) = match self.segments.current() {
+                Some(segment) => segment,
+                None => break,
+            };
+            let len = cmp::min(len, buf.len() - count);
+            self.mem
+                .write_slice(&buf[count..count + len], addr)
+                .map_err(|_| io::Error::from_raw_os_error(libc::EFAULT))?;
+            self.segments.advance(len);
+            count += len;
+        }
+        Ok(count)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write_desc(mem: &GuestMemoryMmap, table: u64, index: u16, desc: (u64, u32, u16, u16)) {
+        let addr = table + u64::from(index) * VIRTQ_DESC_SIZE;
+        mem.write_obj(desc.0, GuestAddress(addr)).unwrap();
+        mem.write_obj(desc.1, GuestAddress(addr + 8)).unwrap();
+        mem.write_obj(desc.2, GuestAddress(addr + 12)).unwrap();
+        mem.write_obj(desc.3, GuestAddress(addr + 14)).unwrap();
+    }
+
+    #[test]
+    fn test_descriptor_chain() {
+        let mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        // A readable buffer followed by an indirect table of a readable and two writable ones.
+        write_desc(&mem, 0x1000, 0, (0x4000, 4, VIRTQ_DESC_F_NEXT, 3));
+        write_desc(&mem, 0x1000, 3, (0x2000, 48, VIRTQ_DESC_F_INDIRECT, 0));
+        write_desc(&mem, 0x2000, 0, (0x5000, 2, VIRTQ_DESC_F_NEXT, 2));
+        write_desc(&mem, 0x2000, 2, (0x6000, 3, 0x3, 1));
+        write_desc(&mem, 0x2000, 1, (0x7000, 0x10, VIRTQ_DESC_F_WRITE, 0));
+        mem.write_slice(b"abcd", GuestAddress(0x4000)).unwrap();
+        mem.write_slice(b"ef", GuestAddress(0x5000)).unwrap();
+
+        let mut cache = TranslationCache::new();
+        let chain = DescriptorChain::read(&mem, &mut cache, GuestAddress(0x1000), 4, 0).unwrap();
+        assert_eq!(chain.head_index(), 0);
+        assert_eq!(chain.descriptors().len(), 4);
+        assert_eq!(chain.readable().count(), 2);
+        assert_eq!(chain.writable().count(), 2);
+
+        let mut reader = chain.reader(&mem);
+        assert_eq!(reader.available_bytes(), 6);
+        let mut buf = Vec::new();
+        reader.read_to_end(&mut buf).unwrap();
+        assert_eq!(&buf, b"abcdef");
+        assert_eq!(reader.bytes_read(), 6);
+
+        // The writes span the writable buffers, and stop once they are full.
+        let mut writer = chain.writer(&mem);
+        assert_eq!(writer.available_bytes(), 0x13);
+        writer.write_all(b"01234").unwrap();
+        assert_eq!(writer.write(&[b'x'; 0x20]).unwrap(), 0xe);
+        assert!(writer.write_all(b"y").is_err());
+        assert_eq!(writer.bytes_written(), 0x13);
+        let mut buf = [0u8; 5];
+        mem.read_slice(&mut buf[..3], GuestAddress(0x6000)).unwrap();
+        mem.read_slice(&mut buf[3..], GuestAddress(0x7000)).unwrap();
+        assert_eq!(&buf, b"01234");
+    }
+
+    #[test]
+    fn test_descriptor_chain_malformed() {
+        let mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let mut cache = TranslationCache::new();
+        let mut read =
+            |head| DescriptorChain::read(&mem, &mut cache, GuestAddress(0x1000), 4, head);
+
+        // Loop in the chain.
+        write_desc(&mem, 0x1000, 0, (0x4000, 4, VIRTQ_DESC_F_NEXT, 1));
+        write_desc(&mem, 0x1000, 1, (0x4000, 4, VIRTQ_DESC_F_NEXT, 0));
+        assert!(read(0).is_err());
+        // Next descriptor out of the table.
+        write_desc(&mem, 0x1000, 1, (0x4000, 4, VIRTQ_DESC_F_NEXT, 4));
+        assert!(read(0).is_err());
+        // Buffer out of the guest memory.
+        write_desc(&mem, 0x1000, 1, (0xfff0, 0x20, 0, 0));
+        assert!(read(0).is_err());
+        // Indirect table of a size which isn't a multiple of the descriptor size.
+        write_desc(&mem, 0x1000, 2, (0x2000, 20, VIRTQ_DESC_F_INDIRECT, 0));
+        assert!(read(2).is_err());
+        // Nested indirect tables.
+        write_desc(&mem, 0x1000, 2, (0x2000, 16, VIRTQ_DESC_F_INDIRECT, 0));
+        write_desc(&mem, 0x2000, 0, (0x3000, 16, VIRTQ_DESC_F_INDIRECT, 0));
+        assert!(read(2).is_err());
+        write_desc(&mem, 0x2000, 0, (0x3000, 16, 0, 0));
+        assert_eq!(read(2).unwrap().descriptors().len(), 1);
+        // Indirect descriptor followed by another one.
+        write_desc(&mem, 0x1000, 2, (0x2000, 16, 0x5, 3));
+        assert!(read(2).is_err());
+    }
+
+    #[test]
+    fn test_translation_cache() {
+        let mem = GuestMemoryMmap::from_ranges(&[
+            (GuestAddress(0), 0x10000),
+            (GuestAddress(0x10000), 0x10000),
+        ])
+        .unwrap();
+        // A chain in the first region, with a buffer in each region and one spanning both.
+        write_desc(&mem, 0x1000, 0, (0x4000, 4, VIRTQ_DESC_F_NEXT, 1));
+        write_desc(&mem, 0x1000, 1, (0x14000, 4, VIRTQ_DESC_F_NEXT, 2));
+        write_desc(&mem, 0x1000, 2, (0xfff0, 0x20, 0, 0));
+
+        // All lookups but the first one of each region hit the cache.
+        let mut cache = TranslationCache::new();
+        let chain = DescriptorChain::read(&mem, &mut cache, GuestAddress(0x1000), 4, 0).unwrap();
+        assert_eq!(chain.descriptors().len(), 3);
+        let stats = cache.stats();
+        assert_eq!(stats.misses, 3);
+        assert_eq!(stats.hits, 12);
+
+        // Translations are made against the guest memory the cache has been filled from.
+        let host_addr = cache.translate(&mem, GuestAddress(0x14000), 4).unwrap();
+        assert_eq!(
+            host_addr,
+            mem.get_host_address(GuestAddress(0x14000)).unwrap()
+        );
+        let mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        assert!(cache.translate(&mem, GuestAddress(0x14000), 4).is_ok());
+        cache.invalidate();
+        assert!(cache.translate(&mem, GuestAddress(0x14000), 4).is_err());
+    }
+}
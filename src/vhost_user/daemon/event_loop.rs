@@ -0,0 +1,281 @@
+// Copyright (C) 2020 Alibaba Cloud Computing. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Epoll based worker threads to wait for vring kicks and backend specific events.
+
+use std::io;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+
+use vmm_sys_util::epoll::{ControlOperation, Epoll, EpollEvent, EventSet};
+use vmm_sys_util::eventfd::{EventFd, EFD_NONBLOCK};
+
+use super::{GuestMemoryAtomic, Metrics, VhostUserBackend, Vring};
+
+// Maximum number of events to fetch by each epoll_wait() call.
+const EPOLL_EVENTS_LEN: usize = 100;
+
+/// Event loop of a worker thread serving some of the vrings of a vhost-user device.
+///
+/// Events with data in range `0..num_queues` are kicks of the corresponding vring, and data
+/// `num_queues` is reserved for the internal exit event. Backends may register their own file
+/// descriptors with any data value greater than `num_queues`.
+///
+/// The worker thread exits when the backend fails to handle an event, unless the event is a kick
+/// of a vring with an error eventfd set by SET_VRING_ERR, which is signaled instead.
+///
+/// When the backend sets a `VhostUserBackend::busy_poll_duration()`, kicked vrings are polled for
+/// new buffers, which are handled as kicks, until they stay empty for the whole duration.
+pub struct VringEpollHandler<B: VhostUserBackend> {
+    epoll: Epoll,
+    backend: Arc<RwLock<B>>,
+    vrings: Vec<Arc<RwLock<Vring>>>,
+    exit_event: EventFd,
+    thread_id: usize,
+    metrics: Arc<dyn Metrics>,
+    memory: GuestMemoryAtomic,
+    poll_duration: Option<Duration>,
+}
+
+impl<B: VhostUserBackend> VringEpollHandler<B> {
+    pub(super) fn new(
+        backend: Arc<RwLock<B>>,
+        vrings: Vec<Arc<RwLock<Vring>>>,
+        thread_id: usize,
+        metrics: Arc<dyn Metrics>,
+        memory: GuestMemoryAtomic,
+    ) -> io::Result<Self> {
+        let epoll = Epoll::new()?;
+        let exit_event = EventFd::new(EFD_NONBLOCK)?;
+        let poll_duration = backend.read().unwrap().busy_poll_duration();
+        let handler = VringEpollHandler {
+            epoll,
+            backend,
+            vrings,
+            exit_event,
+            thread_id,
+            metrics,
+            memory,
+            poll_duration,
+        };
+        handler.ctl(
+            ControlOperation::Add,
+            handler.exit_event.as_raw_fd(),
+            EventSet::IN,
+            handler.exit_event_id(),
+        )?;
+        Ok(handler)
+    }
+
+    /// Register a backend specific file descriptor to the event loop.
+    ///
+    /// `data` is passed to `VhostUserBackend::handle_event()` when the file descriptor gets
+    /// ready, and must be greater than the number of queues of the device.
+    pub fn register_listener(&self, fd: RawFd, ev_type: EventSet, data: u64) -> io::Result<()> {
+        if data <= self.exit_event_id() {
+            return Err(io::Error::from_raw_os_error(libc::EINVAL));
+        }
+        self.ctl(ControlOperation::Add, fd, ev_type, data)
+    }
+
+    /// Unregister a backend specific file descriptor from the event loop.
+    pub fn unregister_listener(&self, fd: RawFd, ev_type: EventSet, data: u64) -> io::Result<()> {
+        if data <= self.exit_event_id() {
+            return Err(io::Error::from_raw_os_error(libc::EINVAL));
+        }
+        self.ctl(ControlOperation::Delete, fd, ev_type, data)
+    }
+
+    // Also exit the event loop once `exit_event`, shared with the other threads of the daemon,
+    // is signaled. The event isn't consumed, so all the threads see it.
+    pub(super) fn register_exit_event(&self, exit_event: &EventFd) -> io::Result<()> {
+        self.ctl(
+            ControlOperation::Add,
+            exit_event.as_raw_fd(),
+            EventSet::IN,
+            self.exit_event_id(),
+        )
+    }
+
+    pub(super) fn register_kick(&self, index: usize, fd: RawFd) -> io::Result<()> {
+        self.ctl(ControlOperation::Add, fd, EventSet::IN, index as u64)
+    }
+
+    pub(super) fn unregister_kick(&self, index: usize, fd: RawFd) -> io::Result<()> {
+        self.ctl(ControlOperation::Delete, fd, EventSet::IN, index as u64)
+    }
+
+    /// Get the index of the worker thread.
+    pub fn thread_id(&self) -> usize {
+        self.thread_id
+    }
+
+    /// Ask the event loop to exit.
+    pub(super) fn exit(&self) -> io::Result<()> {
+        self.exit_event.write(1)
+    }
+
+    /// Run the event loop until the exit event is signaled or the backend asks to stop.
+    pub(super) fn run(&self) -> io::Result<()> {
+        let mut events = vec![EpollEvent::new(EventSet::empty(), 0); EPOLL_EVENTS_LEN];
+        // vrings polled for new buffers, until `deadline` if they stay empty
+        let mut polled = Vec::new();
+        let mut deadline = Instant::now();
+        loop {
+            // Don't wait for events while polling.
+            let timeout = if polled.is_empty() { -1 } else { 0 };
+            let num_events = match self.epoll.wait(timeout, &mut events[..]) {
+                Ok(num) => num,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            };
+
+            for event in events.iter().take(num_events) {
+                let data = event.data();
+                if data == self.exit_event_id() {
+                    return Ok(());
+                }
+                if data < self.vrings.len() as u64 {
+                    self.metrics.queue_kicked(data as u16);
+                    // Consume the kick so the level triggered event doesn't fire again.
+                    let mut vring = self.vrings[data as usize].write().unwrap();
+                    vring.kicked();
+                    if let Some(kick) = vring.kick() {
+                        let _ = kick.read();
+                    }
+                    if let Some(duration) = self.poll_duration {
+                        if self.start_polling(data as usize, &mut vring, &mut polled) {
+                            deadline = Instant::now() + duration;
+                        }
+                    }
+                }
+                if self.dispatch(data, event.event_set())? {
+                    return Ok(());
+                }
+            }
+
+            if let (Some(duration), false) = (self.poll_duration, polled.is_empty()) {
+                let busy = match self.poll_vrings(&mut polled)? {
+                    Some(busy) => busy,
+                    None => return Ok(()),
+                };
+                if busy {
+                    deadline = Instant::now() + duration;
+                } else if Instant::now() >= deadline && self.stop_polling(&mut polled)? {
+                    return Ok(());
+                }
+            }
+        }
+    }
+
+    // Pass an event to the backend, returning whether the worker thread should stop.
+    fn dispatch(&self, data: u64, evset: EventSet) -> io::Result<bool> {
+        let res = self.backend.read().unwrap().handle_event(
+            data as u16,
+            evset,
+            &self.vrings,
+            self.thread_id,
+        );
+        match res {
+            Ok(stop) => Ok(stop),
+            // Report failures to process a vring through its error eventfd, so the master may
+            // reset the vring, and keep serving the other vrings.
+            Err(e) => {
+                self.metrics.error();
+                match self.vrings.get(data as usize) {
+                    Some(vring) if vring.read().unwrap().err().is_some() => {
+                        vring.read().unwrap().signal_error()?;
+                        Ok(false)
+                    }
+                    _ => Err(e),
+                }
+            }
+        }
+    }
+
+    // Start polling a kicked vring, asking the driver to stop kicking it. Return false if the
+    // vring can't be polled.
+    fn start_polling(&self, index: usize, vring: &mut Vring, polled: &mut Vec<usize>) -> bool {
+        if polled.contains(&index) {
+            return true;
+        }
+        match self.memory.memory() {
+            Some(ref mem) if vring.is_started() && vring.set_kicks_enabled(mem, false).is_ok() => {
+                polled.push(index);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    // Handle the polled vrings having new buffers, and stop polling the vrings which have been
+    // stopped. Return whether the backend has taken new buffers, or None to stop the worker
+    // thread. Buffers left in the vring, such as receive buffers waiting for packets, don't keep
+    // the vring polled.
+    fn poll_vrings(&self, polled: &mut Vec<usize>) -> io::Result<Option<bool>> {
+        let mem = match self.memory.memory() {
+            Some(mem) => mem,
+            None => {
+                polled.clear();
+                return Ok(Some(false));
+            }
+        };
+        let mut busy = false;
+        let mut i = 0;
+        while i < polled.len() {
+            let index = polled[i];
+            let next_avail = {
+                let vring = self.vrings[index].read().unwrap();
+                if !vring.is_started() {
+                    polled.swap_remove(i);
+                    continue;
+                }
+                if vring.is_enabled() && vring.has_avail(&mem).unwrap_or(false) {
+                    Some(vring.next_avail())
+                } else {
+                    None
+                }
+            };
+            if let Some(next_avail) = next_avail {
+                if self.dispatch(index as u64, EventSet::IN)? {
+                    return Ok(None);
+                }
+                busy |= self.vrings[index].read().unwrap().next_avail() != next_avail;
+            }
+            i += 1;
+        }
+        Ok(Some(busy))
+    }
+
+    // Enable the kicks of the polled vrings again, and handle the buffers made available before
+    // the driver saw it. Return whether the worker thread should stop.
+    fn stop_polling(&self, polled: &mut Vec<usize>) -> io::Result<bool> {
+        let mem = self.memory.memory();
+        for index in polled.drain(..) {
+            let ready = {
+                let mut vring = self.vrings[index].write().unwrap();
+                match mem {
+                    Some(ref mem) if vring.is_started() => {
+                        vring.set_kicks_enabled(mem, true).is_ok()
+                            && vring.is_enabled()
+                            && vring.has_avail(mem).unwrap_or(false)
+                    }
+                    _ => false,
+                }
+            };
+            if ready && self.dispatch(index as u64, EventSet::IN)? {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    fn exit_event_id(&self) -> u64 {
+        self.vrings.len() as u64
+    }
+
+    fn ctl(&self, op: ControlOperation, fd: RawFd, ev_type: EventSet, data: u64) -> io::Result<()> {
+        self.epoll.ctl(op, fd, EpollEvent::new(ev_type, data))
+    }
+}
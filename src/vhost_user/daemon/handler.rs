@@ -0,0 +1,803 @@
+// Copyright (C) 2020 Alibaba Cloud Computing. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Vhost-user protocol state machine relaying master requests to the device backend.
+
+use std::fs::File;
+use std::io;
+use std::os::unix::io::{AsRawFd, FromRawFd, IntoRawFd, RawFd};
+use std::os::unix::thread::JoinHandleExt;
+use std::sync::{Arc, RwLock};
+use std::thread;
+
+use vm_memory::{GuestAddress, GuestMemoryMmap};
+use vmm_sys_util::eventfd::EventFd;
+
+use super::{
+    GuestMemoryAtomic, GuestMemoryManager, MemoryMapper, MemoryPolicy, Metrics, VhostUserBackend,
+    Vring, VringEpollHandler, WorkerConfig,
+};
+use crate::backend::VringConfigData;
+use crate::features::VirtioFeatures;
+use crate::vhost_user::device_state::DeviceStateTransfer;
+use crate::vhost_user::dirty_log::DirtyLog;
+use crate::vhost_user::message::*;
+use crate::vhost_user::{
+    Error, MasterReqSender, MemoryRegionSnapshot, Result, SessionSnapshot,
+    VhostUserSlaveReqHandler, VringSnapshot,
+};
+
+// Maximum number of memory regions the daemon accepts through ADD_MEM_REG.
+const MAX_MEM_SLOTS: u64 = 32;
+
+pub(super) struct VhostUserHandler<B: VhostUserBackend> {
+    backend: Arc<RwLock<B>>,
+    workers: Vec<Arc<VringEpollHandler<B>>>,
+    worker_threads: Vec<thread::JoinHandle<io::Result<()>>>,
+    // Index of the worker thread serving each vring.
+    queue_workers: Vec<usize>,
+    vrings: Vec<Arc<RwLock<Vring>>>,
+    // Addresses of each vring as sent by the master, for snapshots.
+    vring_configs: Vec<Option<VringConfigData>>,
+    owned: bool,
+    features_acked: bool,
+    acked_features: u64,
+    acked_protocol_features: u64,
+    num_queues: usize,
+    max_queue_size: usize,
+    memory: GuestMemoryManager,
+    status: u8,
+    state_transfer: Option<DeviceStateTransfer>,
+    metrics: Arc<dyn Metrics>,
+}
+
+impl<B: VhostUserBackend> VhostUserHandler<B> {
+    pub(super) fn new(
+        name: &str,
+        backend: Arc<RwLock<B>>,
+        metrics: Arc<dyn Metrics>,
+        exit_event: &EventFd,
+    ) -> io::Result<Self> {
+        let (num_queues, max_queue_size, queues_per_thread) = {
+            let b = backend.read().unwrap();
+            (b.num_queues(), b.max_queue_size(), b.queues_per_thread())
+        };
+        if num_queues == 0
+            || num_queues as u64 > VHOST_USER_MAX_VRINGS
+            || max_queue_size == 0
+            || max_queue_size > u16::MAX as usize
+        {
+            return Err(io::Error::from_raw_os_error(libc::EINVAL));
+        }
+        let queue_workers = Self::assign_queue_workers(num_queues, &queues_per_thread)?;
+        let memory = GuestMemoryManager::new();
+
+        let vrings: Vec<_> = (0..num_queues)
+            .map(|index| {
+                let vring =
+                    Vring::with_metrics(max_queue_size as u16, index as u16, metrics.clone());
+                Arc::new(RwLock::new(vring))
+            })
+            .collect();
+        let mut workers = Vec::new();
+        let mut worker_threads = Vec::new();
+        for thread_id in 0..queues_per_thread.len() {
+            let worker = Arc::new(VringEpollHandler::new(
+                backend.clone(),
+                vrings.clone(),
+                thread_id,
+                metrics.clone(),
+                memory.atomic(),
+            )?);
+            worker.register_exit_event(exit_event)?;
+            let handler = worker.clone();
+            let worker_thread = thread::Builder::new()
+                .name(format!("{}-worker-{}", name, thread_id))
+                .spawn(move || handler.run())?;
+            workers.push(worker);
+            worker_threads.push(worker_thread);
+        }
+
+        Ok(VhostUserHandler {
+            backend,
+            workers,
+            worker_threads,
+            queue_workers,
+            vrings,
+            vring_configs: vec![None; num_queues],
+            owned: false,
+            features_acked: false,
+            acked_features: 0,
+            acked_protocol_features: 0,
+            num_queues,
+            max_queue_size,
+            status: 0,
+            state_transfer: None,
+            memory,
+            metrics,
+        })
+    }
+
+    pub(super) fn vring_workers(&self) -> Vec<Arc<VringEpollHandler<B>>> {
+        self.workers.clone()
+    }
+
+    pub(super) fn vrings(&self) -> Vec<Arc<RwLock<Vring>>> {
+        self.vrings.clone()
+    }
+
+    // Ask the worker threads to exit, returning the threads to wait for.
+    pub(super) fn exit_workers(&mut self) -> io::Result<Vec<thread::JoinHandle<io::Result<()>>>> {
+        for worker in self.workers.iter() {
+            worker.exit()?;
+        }
+        Ok(self.worker_threads.drain(..).collect())
+    }
+
+    // Apply `config` to the worker thread `thread_id`, unless it has exited.
+    pub(super) fn configure_worker(
+        &self,
+        thread_id: usize,
+        config: &WorkerConfig,
+    ) -> io::Result<()> {
+        match self.worker_threads.get(thread_id) {
+            Some(thread) => config.apply(thread.as_pthread_t()),
+            None => Err(io::Error::from_raw_os_error(libc::ESRCH)),
+        }
+    }
+
+    // Map each vring to the worker thread serving it, checking every vring is served by exactly
+    // one worker.
+    fn assign_queue_workers(
+        num_queues: usize,
+        queues_per_thread: &[u64],
+    ) -> io::Result<Vec<usize>> {
+        let einval = || io::Error::from_raw_os_error(libc::EINVAL);
+        if num_queues > 64 {
+            return Err(einval());
+        }
+        let mut queue_workers = vec![None; num_queues];
+        for (thread_id, mask) in queues_per_thread.iter().enumerate() {
+            if *mask == 0 || (num_queues < 64 && mask >> num_queues != 0) {
+                return Err(einval());
+            }
+            for (index, worker) in queue_workers.iter_mut().enumerate() {
+                if mask & (1u64 << index) != 0 {
+                    if worker.is_some() {
+                        return Err(einval());
+                    }
+                    *worker = Some(thread_id);
+                }
+            }
+        }
+        queue_workers
+            .into_iter()
+            .map(|worker| worker.ok_or_else(einval))
+            .collect()
+    }
+
+    pub(super) fn memory(&self) -> GuestMemoryAtomic {
+        self.memory.atomic()
+    }
+
+    pub(super) fn set_memory_policy<F>(&mut self, policy: F)
+    where
+        F: Fn(GuestAddress, u64) -> MemoryPolicy + Send + Sync + 'static,
+    {
+        self.memory.set_policy(policy);
+    }
+
+    pub(super) fn set_memory_mapper<M: MemoryMapper + 'static>(&mut self, mapper: M) {
+        self.memory.set_mapper(mapper);
+    }
+
+    fn vmm_va_to_gpa(&self, vmm_va: u64) -> Result<GuestAddress> {
+        self.memory.vmm_va_to_gpa(vmm_va)
+    }
+
+    fn check_vring_index(&self, index: usize) -> Result<()> {
+        if index >= self.num_queues {
+            return Err(Error::InvalidParam);
+        }
+        Ok(())
+    }
+
+    // Stop monitoring the kick eventfd of the vring, if any.
+    fn stop_vring(&self, index: usize) -> Result<()> {
+        let mut vring = self.vrings[index].write().unwrap();
+        if let Some(kick) = vring.set_kick(None) {
+            self.workers[self.queue_workers[index]]
+                .unregister_kick(index, kick.as_raw_fd())
+                .map_err(Error::ReqHandlerError)?;
+        }
+        vring.set_started(false);
+        Ok(())
+    }
+
+    // Pass the new guest memory to the backend, dropping the translations the vrings have cached
+    // from the previous one while the worker threads are kept out by the backend lock.
+    fn update_memory(&self, mem: GuestMemoryMmap) -> Result<()> {
+        let mut backend = self.backend.write().unwrap();
+        for vring in self.vrings.iter() {
+            vring.write().unwrap().invalidate_translations();
+        }
+        backend.update_memory(mem).map_err(Error::ReqHandlerError)
+    }
+
+    // Take a snapshot of the session configured by the master.
+    pub(super) fn snapshot(&self) -> SessionSnapshot {
+        let protocol_features =
+            self.acked_features & VhostUserVirtioFeatures::PROTOCOL_FEATURES.bits() != 0;
+        let regions = self
+            .memory
+            .regions()
+            .iter()
+            .map(|region| MemoryRegionSnapshot {
+                guest_phys_addr: region.guest_phys_addr,
+                memory_size: region.memory_size,
+                userspace_addr: region.user_addr,
+                mmap_offset: region.mmap_offset,
+            })
+            .collect();
+        let vrings = self
+            .vrings
+            .iter()
+            .zip(self.vring_configs.iter())
+            .map(|(vring, config)| {
+                let vring = vring.read().unwrap();
+                VringSnapshot {
+                    size: Some(vring.size()),
+                    config: *config,
+                    base: Some(Self::vring_base(&vring)),
+                    enabled: vring.is_enabled(),
+                }
+            })
+            .collect();
+        SessionSnapshot {
+            features: Some(self.acked_features).filter(|_| self.features_acked),
+            protocol_features: Some(self.acked_protocol_features).filter(|_| protocol_features),
+            regions,
+            vrings,
+            status: Some(self.status).filter(|&status| status != 0),
+        }
+    }
+
+    // Replay the requests which configured the session of `snapshot`, with the files backing its
+    // memory regions. The ownership is released afterwards for the next master to take it.
+    pub(super) fn restore(&mut self, snapshot: &SessionSnapshot, files: Vec<File>) -> Result<()> {
+        if self.owned || snapshot.vrings.len() > self.num_queues {
+            return Err(Error::InvalidOperation);
+        } else if files.len() != snapshot.regions.len() {
+            return Err(Error::InvalidParam);
+        }
+        self.set_owner()?;
+        let res = self.replay(snapshot, files);
+        self.owned = false;
+        res
+    }
+
+    fn replay(&mut self, snapshot: &SessionSnapshot, files: Vec<File>) -> Result<()> {
+        if let Some(features) = snapshot.features {
+            self.set_features(features)?;
+        }
+        if let Some(features) = snapshot.protocol_features {
+            self.set_protocol_features(features)?;
+        }
+        if !snapshot.regions.is_empty() {
+            let regions: Vec<_> = snapshot
+                .regions
+                .iter()
+                .map(|region| {
+                    VhostUserMemoryRegion::new(
+                        region.guest_phys_addr,
+                        region.memory_size,
+                        region.userspace_addr,
+                        region.mmap_offset,
+                    )
+                })
+                .collect();
+            let fds: Vec<_> = files.into_iter().map(File::into_raw_fd).collect();
+            self.set_mem_table(&regions, &fds)?;
+        }
+        let protocol_features =
+            self.acked_features & VhostUserVirtioFeatures::PROTOCOL_FEATURES.bits() != 0;
+        for (index, vring) in snapshot.vrings.iter().enumerate() {
+            let index = index as u32;
+            if let Some(size) = vring.size {
+                self.set_vring_num(index, u32::from(size))?;
+            }
+            if let Some(ref config) = vring.config {
+                let flags =
+                    VhostUserVringAddrFlags::from_bits(config.flags).ok_or(Error::InvalidParam)?;
+                self.set_vring_addr(
+                    index,
+                    flags,
+                    config.desc_table_addr,
+                    config.used_ring_addr,
+                    config.avail_ring_addr,
+                    config.log_addr.unwrap_or(0),
+                )?;
+            }
+            if let Some(base) = vring.base {
+                self.set_vring_base(index, base)?;
+            }
+            if vring.enabled && protocol_features {
+                self.set_vring_enable(index, true)?;
+            }
+        }
+        if let Some(status) = snapshot.status {
+            self.set_status(status)?;
+        }
+        Ok(())
+    }
+
+    // Get the base of a vring, as returned by GET_VRING_BASE.
+    fn vring_base(vring: &Vring) -> u32 {
+        let mut base = u32::from(vring.next_avail());
+        // The base of packed vrings holds the position of the next available descriptor in bits
+        // 0-15 and of the next used one in bits 16-31, each with its wrap counter in the most
+        // significant bit.
+        if vring.is_packed() {
+            base |= u32::from(vring.next_used()) << 16;
+        }
+        base
+    }
+
+    fn reset_vrings(&mut self) -> Result<()> {
+        for index in 0..self.num_queues {
+            self.stop_vring(index)?;
+            let mut vring = self.vrings[index].write().unwrap();
+            *vring = Vring::with_metrics(
+                self.max_queue_size as u16,
+                index as u16,
+                self.metrics.clone(),
+            );
+            self.vring_configs[index] = None;
+        }
+        Ok(())
+    }
+
+    fn eventfd_from_raw_fd(fd: Option<RawFd>) -> Option<EventFd> {
+        // Safe because the file descriptor has just been received from the master and we take
+        // the ownership of it.
+        fd.map(|fd| unsafe { EventFd::from_raw_fd(fd) })
+    }
+}
+
+impl<B: VhostUserBackend> VhostUserSlaveReqHandler for VhostUserHandler<B> {
+    fn set_owner(&mut self) -> Result<()> {
+        if self.owned {
+            return Err(Error::InvalidOperation);
+        }
+        self.owned = true;
+        Ok(())
+    }
+
+    fn reset_owner(&mut self) -> Result<()> {
+        self.owned = false;
+        self.features_acked = false;
+        self.acked_features = 0;
+        self.acked_protocol_features = 0;
+        Ok(())
+    }
+
+    fn get_features(&mut self) -> Result<u64> {
+        // The kicks are received through eventfds, which can't carry the notification data.
+        let features = self.backend.read().unwrap().features();
+        Ok(features & !VirtioFeatures::NOTIFICATION_DATA.bits())
+    }
+
+    fn set_features(&mut self, features: u64) -> Result<()> {
+        if !self.owned {
+            return Err(Error::InvalidOperation);
+        } else if (features & !self.backend.read().unwrap().features()) != 0 {
+            return Err(Error::InvalidParam);
+        }
+
+        let initialized = self.features_acked;
+        self.acked_features = features;
+        self.features_acked = true;
+        let event_idx = features & VirtioFeatures::RING_EVENT_IDX.bits() != 0;
+        let packed = features & VirtioFeatures::RING_PACKED.bits() != 0;
+        for vring in self.vrings.iter() {
+            let mut vring = vring.write().unwrap();
+            vring.set_event_idx(event_idx);
+            vring.set_packed(packed);
+        }
+        if initialized {
+            // The features are sent again to switch the logging of dirty pages, which doesn't
+            // change the state of the rings.
+            self.backend.write().unwrap().acked_features(features);
+            return Ok(());
+        }
+
+        // If VHOST_USER_F_PROTOCOL_FEATURES has not been negotiated, the rings are initialized
+        // in an enabled state, otherwise they are initialized in a disabled state.
+        let vring_enabled =
+            self.acked_features & VhostUserVirtioFeatures::PROTOCOL_FEATURES.bits() == 0;
+        for vring in self.vrings.iter() {
+            vring.write().unwrap().set_enabled(vring_enabled);
+        }
+
+        let mut backend = self.backend.write().unwrap();
+        backend.acked_features(features);
+        if vring_enabled {
+            for index in 0..self.num_queues {
+                backend
+                    .set_queue_enabled(index as u16, true)
+                    .map_err(Error::ReqHandlerError)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn get_protocol_features(&mut self) -> Result<VhostUserProtocolFeatures> {
+        // The memory regions are mapped as regular shared memory, unless the memory mapper can
+        // map Xen grants and foreign memory.
+        let features = self.backend.read().unwrap().protocol_features();
+        if self.memory.supports_xen_mmap() {
+            Ok(features)
+        } else {
+            Ok(features - VhostUserProtocolFeatures::XEN_MMAP)
+        }
+    }
+
+    fn set_protocol_features(&mut self, features: u64) -> Result<()> {
+        self.acked_protocol_features = features;
+        Ok(())
+    }
+
+    fn set_mem_table(&mut self, ctx: &[VhostUserMemoryRegion], fds: &[RawFd]) -> Result<()> {
+        // Safe because the file descriptors have just been received from the master and we take
+        // the ownership of them.
+        let files = fds
+            .iter()
+            .map(|fd| unsafe { File::from_raw_fd(*fd) })
+            .collect();
+        let mem = self.memory.set_mem_table(ctx, files)?;
+        self.update_memory(mem)
+    }
+
+    fn get_queue_num(&mut self) -> Result<u64> {
+        Ok(self.num_queues as u64)
+    }
+
+    fn set_vring_num(&mut self, index: u32, num: u32) -> Result<()> {
+        self.check_vring_index(index as usize)?;
+        if num == 0 || num as usize > self.max_queue_size {
+            return Err(Error::InvalidParam);
+        }
+        self.vrings[index as usize]
+            .write()
+            .unwrap()
+            .set_size(num as u16);
+        Ok(())
+    }
+
+    fn set_vring_addr(
+        &mut self,
+        index: u32,
+        flags: VhostUserVringAddrFlags,
+        descriptor: u64,
+        used: u64,
+        available: u64,
+        log: u64,
+    ) -> Result<()> {
+        self.check_vring_index(index as usize)?;
+        if self.memory.memory().is_none() {
+            return Err(Error::InvalidOperation);
+        }
+        let desc_table = self.vmm_va_to_gpa(descriptor)?;
+        let avail_ring = self.vmm_va_to_gpa(available)?;
+        let used_ring = self.vmm_va_to_gpa(used)?;
+        let mut vring = self.vrings[index as usize].write().unwrap();
+        vring.set_addresses(desc_table, avail_ring, used_ring);
+        let log_addr = if flags.contains(VhostUserVringAddrFlags::VHOST_VRING_F_LOG) {
+            Some(log)
+        } else {
+            None
+        };
+        self.vring_configs[index as usize] = Some(VringConfigData {
+            queue_max_size: self.max_queue_size as u16,
+            queue_size: vring.size(),
+            flags: flags.bits(),
+            desc_table_addr: descriptor,
+            used_ring_addr: used,
+            avail_ring_addr: available,
+            log_addr,
+        });
+        Ok(())
+    }
+
+    fn set_vring_base(&mut self, index: u32, base: u32) -> Result<()> {
+        self.check_vring_index(index as usize)?;
+        let mut vring = self.vrings[index as usize].write().unwrap();
+        // The base of packed vrings holds the position of the next available descriptor in bits
+        // 0-15 and of the next used one in bits 16-31, each with its wrap counter in the most
+        // significant bit.
+        if vring.is_packed() {
+            vring.set_next_avail(base as u16);
+            vring.set_next_used((base >> 16) as u16);
+        } else if base > u32::from(u16::MAX) {
+            return Err(Error::InvalidParam);
+        } else {
+            vring.set_next_avail(base as u16);
+            vring.set_next_used(base as u16);
+        }
+        Ok(())
+    }
+
+    fn get_vring_base(&mut self, index: u32) -> Result<VhostUserVringState> {
+        self.check_vring_index(index as usize)?;
+        // The slave must stop the ring upon receiving VHOST_USER_GET_VRING_BASE.
+        self.stop_vring(index as usize)?;
+        let vring = self.vrings[index as usize].read().unwrap();
+        Ok(VhostUserVringState::new(index, Self::vring_base(&vring)))
+    }
+
+    fn set_vring_kick(&mut self, index: u8, fd: Option<RawFd>) -> Result<()> {
+        let kick = Self::eventfd_from_raw_fd(fd);
+        self.check_vring_index(index as usize)?;
+        self.stop_vring(index as usize)?;
+
+        // The slave must start the ring upon receiving a kick on the descriptor specified by
+        // VHOST_USER_SET_VRING_KICK.
+        let mut vring = self.vrings[index as usize].write().unwrap();
+        if let Some(ref kick) = kick {
+            self.workers[self.queue_workers[index as usize]]
+                .register_kick(index as usize, kick.as_raw_fd())
+                .map_err(Error::ReqHandlerError)?;
+        }
+        vring.set_kick(kick);
+        vring.set_started(true);
+        Ok(())
+    }
+
+    fn set_vring_call(&mut self, index: u8, fd: Option<RawFd>) -> Result<()> {
+        let call = Self::eventfd_from_raw_fd(fd);
+        self.check_vring_index(index as usize)?;
+        self.vrings[index as usize].write().unwrap().set_call(call);
+        Ok(())
+    }
+
+    fn set_vring_err(&mut self, index: u8, fd: Option<RawFd>) -> Result<()> {
+        let err = Self::eventfd_from_raw_fd(fd);
+        self.check_vring_index(index as usize)?;
+        self.vrings[index as usize].write().unwrap().set_err(err);
+        Ok(())
+    }
+
+    fn set_vring_enable(&mut self, index: u32, enable: bool) -> Result<()> {
+        // This request should be handled only when VHOST_USER_F_PROTOCOL_FEATURES has been
+        // negotiated.
+        if self.acked_features & VhostUserVirtioFeatures::PROTOCOL_FEATURES.bits() == 0 {
+            return Err(Error::InvalidOperation);
+        }
+        self.check_vring_index(index as usize)?;
+        self.backend
+            .write()
+            .unwrap()
+            .set_queue_enabled(index as u16, enable)
+            .map_err(Error::ReqHandlerError)?;
+        self.vrings[index as usize]
+            .write()
+            .unwrap()
+            .set_enabled(enable);
+        Ok(())
+    }
+
+    fn set_vring_endian(&mut self, index: u32, big_endian: bool) -> Result<()> {
+        self.check_vring_index(index as usize)?;
+        // Vrings are processed in little endian only.
+        if big_endian {
+            return Err(Error::InvalidParam);
+        }
+        Ok(())
+    }
+
+    fn send_rarp(&mut self, mac: [u8; 6]) -> Result<()> {
+        self.backend
+            .write()
+            .unwrap()
+            .send_rarp(mac)
+            .map_err(Error::ReqHandlerError)
+    }
+
+    fn net_set_mtu(&mut self, mtu: u16) -> Result<()> {
+        self.backend
+            .write()
+            .unwrap()
+            .net_set_mtu(mtu)
+            .map_err(Error::ReqHandlerError)
+    }
+
+    fn get_config(
+        &mut self,
+        offset: u32,
+        size: u32,
+        _flags: VhostUserConfigFlags,
+    ) -> Result<Vec<u8>> {
+        Ok(self.backend.read().unwrap().get_config(offset, size))
+    }
+
+    fn set_config(&mut self, offset: u32, buf: &[u8], _flags: VhostUserConfigFlags) -> Result<()> {
+        self.backend
+            .write()
+            .unwrap()
+            .set_config(offset, buf)
+            .map_err(Error::ReqHandlerError)
+    }
+
+    fn set_slave_req_fd(&mut self, vu_req: MasterReqSender) {
+        // The vrings send their calls and errors through the slave channel, unless the master
+        // sends call and err eventfds.
+        let inband = self.acked_protocol_features
+            & VhostUserProtocolFeatures::INBAND_NOTIFICATIONS.bits()
+            != 0;
+        for (index, vring) in self.vrings.iter().enumerate() {
+            let sender = if inband { Some(vu_req.clone()) } else { None };
+            vring.write().unwrap().set_inband(index as u32, sender);
+        }
+        self.backend.write().unwrap().set_slave_req_fd(vu_req);
+    }
+
+    fn get_inflight_fd(
+        &mut self,
+        _inflight: &VhostUserInflight,
+    ) -> Result<(VhostUserInflight, File)> {
+        Err(Error::InvalidOperation)
+    }
+
+    fn set_inflight_fd(&mut self, _inflight: &VhostUserInflight, _file: File) -> Result<()> {
+        Err(Error::InvalidOperation)
+    }
+
+    fn postcopy_advise(&mut self) -> Result<File> {
+        Err(Error::InvalidOperation)
+    }
+
+    fn postcopy_listen(&mut self) -> Result<()> {
+        Err(Error::InvalidOperation)
+    }
+
+    fn postcopy_end(&mut self) -> Result<()> {
+        Err(Error::InvalidOperation)
+    }
+
+    fn set_log_base(&mut self, log: &VhostUserLog, file: File) -> Result<()> {
+        let dirty_log = DirtyLog::from_file(file, log).map_err(Error::ReqHandlerError)?;
+        self.backend.write().unwrap().set_dirty_log(dirty_log);
+        Ok(())
+    }
+
+    fn iotlb_msg(&mut self, _iotlb: &VhostUserIotlb) -> Result<()> {
+        Err(Error::InvalidOperation)
+    }
+
+    fn set_log_fd(&mut self, _file: File) -> Result<()> {
+        // Dirty pages are logged through the shared memory region only, the master doesn't
+        // need to be notified of log writes.
+        Ok(())
+    }
+
+    fn reset_session(&mut self, _preserve_inflight: bool) -> Result<()> {
+        // The daemon doesn't support inflight I/O tracking, so there's nothing to preserve.
+        self.reset_vrings()?;
+        self.owned = false;
+        self.features_acked = false;
+        self.acked_features = 0;
+        self.acked_protocol_features = 0;
+        self.memory.clear();
+        self.status = 0;
+        Ok(())
+    }
+
+    fn reset_device(&mut self) -> Result<()> {
+        self.reset_vrings()?;
+        self.features_acked = false;
+        self.acked_features = 0;
+        self.status = 0;
+        self.backend
+            .write()
+            .unwrap()
+            .reset_device()
+            .map_err(Error::ReqHandlerError)
+    }
+
+    fn set_status(&mut self, status: u8) -> Result<()> {
+        self.backend
+            .write()
+            .unwrap()
+            .set_status(status)
+            .map_err(Error::ReqHandlerError)?;
+        self.status = status;
+        Ok(())
+    }
+
+    fn get_status(&mut self) -> Result<u8> {
+        Ok(self.status)
+    }
+
+    fn get_shared_object(&mut self, shared: &VhostUserShared) -> Result<File> {
+        self.backend
+            .write()
+            .unwrap()
+            .get_shared_object(shared.uuid)
+            .map_err(Error::ReqHandlerError)
+    }
+
+    fn set_device_state_fd(
+        &mut self,
+        direction: VhostTransferStateDirection,
+        _phase: VhostTransferStatePhase,
+        file: File,
+    ) -> Result<Option<File>> {
+        let mut backend = self.backend.write().unwrap();
+        let serializer = backend.device_state().ok_or(Error::InvalidOperation)?;
+        let transfer = DeviceStateTransfer::start(direction, file, serializer)
+            .map_err(Error::ReqHandlerError)?;
+        self.state_transfer = Some(transfer);
+        Ok(None)
+    }
+
+    fn check_device_state(&mut self) -> Result<()> {
+        let transfer = self.state_transfer.take().ok_or(Error::InvalidOperation)?;
+        let mut backend = self.backend.write().unwrap();
+        let serializer = backend.device_state().ok_or(Error::InvalidOperation)?;
+        transfer.finish(serializer).map_err(Error::ReqHandlerError)
+    }
+
+    fn get_shared_memory_regions(&mut self) -> Result<Vec<u64>> {
+        Ok(self.backend.read().unwrap().shared_memory_regions())
+    }
+
+    fn get_max_mem_slots(&mut self) -> Result<u64> {
+        Ok(MAX_MEM_SLOTS)
+    }
+
+    fn add_mem_region(&mut self, region: &VhostUserSingleMemoryRegion, file: File) -> Result<()> {
+        if self.memory.num_regions() as u64 >= MAX_MEM_SLOTS {
+            return Err(Error::InvalidOperation);
+        }
+        let mem = self.memory.add_region(&region.region, file)?;
+        self.update_memory(mem)
+    }
+
+    fn remove_mem_region(&mut self, region: &VhostUserSingleMemoryRegion) -> Result<()> {
+        let mem = self.memory.remove_region(&region.region)?;
+        self.update_memory(mem)
+    }
+
+    fn set_mem_table_xen(&mut self, ctx: &[VhostUserXenMemoryRegion], fds: &[RawFd]) -> Result<()> {
+        // Safe because the file descriptors have just been received from the master and we take
+        // the ownership of them.
+        let files = fds
+            .iter()
+            .map(|fd| unsafe { File::from_raw_fd(*fd) })
+            .collect();
+        let mem = self.memory.set_mem_table_xen(ctx, files)?;
+        self.update_memory(mem)
+    }
+
+    fn add_mem_region_xen(
+        &mut self,
+        region: &VhostUserXenSingleMemoryRegion,
+        file: File,
+    ) -> Result<()> {
+        if self.memory.num_regions() as u64 >= MAX_MEM_SLOTS {
+            return Err(Error::InvalidOperation);
+        }
+        let mem = self.memory.add_xen_region(&{ region.region }, file)?;
+        self.update_memory(mem)
+    }
+}
+
+impl<B: VhostUserBackend> Drop for VhostUserHandler<B> {
+    fn drop(&mut self) {
+        for (worker, thread) in self.workers.iter().zip(self.worker_threads.drain(..)) {
+            if worker.exit().is_ok() {
+                let _ = thread.join();
+            }
+        }
+    }
+}
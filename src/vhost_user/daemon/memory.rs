@@ -0,0 +1,558 @@
+// Copyright (C) 2020 Alibaba Cloud Computing. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Guest memory mapped from the regions sent by the master.
+
+use std::fs::File;
+use std::io;
+use std::mem;
+use std::os::unix::io::AsRawFd;
+use std::ptr;
+use std::sync::{Arc, RwLock};
+
+use vm_memory::mmap::MmapRegionError;
+use vm_memory::{
+    Address, ByteValued, FileOffset, GuestAddress, GuestMemory, GuestMemoryMmap, GuestMemoryRegion,
+    GuestRegionMmap, MmapRegion,
+};
+
+use crate::vhost_user::message::{
+    VhostUserMemoryRegion, VhostUserXenMemoryRegion, VhostUserXenMmap,
+};
+use crate::vhost_user::{Error, Result};
+
+// Modes of mbind().
+const MPOL_PREFERRED: libc::c_int = 1;
+const MPOL_BIND: libc::c_int = 2;
+const MPOL_INTERLEAVE: libc::c_int = 3;
+
+// Magic number of hugetlbfs, from linux/magic.h.
+const HUGETLBFS_MAGIC: u32 = 0x9584_58f6;
+
+// Number of guest memory regions remembered by a `TranslationCache`.
+const TRANSLATION_CACHE_SIZE: usize = 4;
+
+/// NUMA placement of the pages of a guest memory region.
+#[derive(Clone, Debug, PartialEq)]
+pub enum NumaPolicy {
+    /// Only allocate the pages from the given nodes.
+    Bind(Vec<u32>),
+    /// Allocate the pages from the given node when possible.
+    Preferred(u32),
+    /// Spread the pages over the given nodes.
+    Interleave(Vec<u32>),
+}
+
+/// Policy applied to a guest memory region once it has been mapped into the slave.
+///
+/// Policies only affect the pages allocated afterwards, and pages already allocated by the
+/// master stay where they are. Failing to apply a policy fails the request which sent the
+/// region.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct MemoryPolicy {
+    /// NUMA placement of the pages, set with mbind(2).
+    pub numa: Option<NumaPolicy>,
+    /// Whether the region should be backed by transparent huge pages, advised with madvise(2).
+    pub huge_pages: Option<bool>,
+}
+
+impl MemoryPolicy {
+    // Apply the policy to the mapping of `len` bytes at `addr`.
+    fn apply(&self, addr: *mut u8, len: usize) -> io::Result<()> {
+        if let Some(ref numa) = self.numa {
+            let (mode, nodes) = match numa {
+                NumaPolicy::Bind(nodes) => (MPOL_BIND, nodes.clone()),
+                NumaPolicy::Preferred(node) => (MPOL_PREFERRED, vec![*node]),
+                NumaPolicy::Interleave(nodes) => (MPOL_INTERLEAVE, nodes.clone()),
+            };
+            let bits = 8 * mem::size_of::<libc::c_ulong>();
+            let max_node = nodes.iter().max().map_or(0, |&node| node as usize);
+            let mut mask = vec![0 as libc::c_ulong; max_node / bits + 1];
+            for node in nodes {
+                mask[node as usize / bits] |= 1 << (node as usize % bits);
+            }
+            // Safe because the mapping and the node mask are valid for the whole call and we
+            // check the result. The kernel only reads `maxnode - 1` bits of the mask.
+            let ret = unsafe {
+                libc::syscall(
+                    libc::SYS_mbind,
+                    addr,
+                    len,
+                    mode,
+                    mask.as_ptr(),
+                    mask.len() * bits + 1,
+                    0,
+                )
+            };
+            if ret < 0 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+        if let Some(huge_pages) = self.huge_pages {
+            let advice = if huge_pages {
+                libc::MADV_HUGEPAGE
+            } else {
+                libc::MADV_NOHUGEPAGE
+            };
+            // Safe because the mapping is valid and we check the result.
+            let ret = unsafe { libc::madvise(addr as *mut libc::c_void, len, advice) };
+            if ret < 0 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+        Ok(())
+    }
+}
+
+// Choice of the policy of each region, from its guest physical address and size.
+type PolicyFn = dyn Fn(GuestAddress, u64) -> MemoryPolicy + Send + Sync;
+
+/// Mapper of the guest memory regions sent by the master into the slave.
+///
+/// The `MmapMapper` maps the file sent with each region as regular shared memory. Slaves may
+/// supply their own mapper to map encrypted memory, to register the regions with a userfaultfd or
+/// to map Xen foreign memory and grants, whose file is a privcmd or gntdev device to be told which
+/// pages of the domain to map. The mappings returned cover the whole regions, and are unmapped
+/// once the regions are dropped.
+pub trait MemoryMapper: Send + Sync {
+    /// Map the `region`, backed by `file`, as regular shared memory.
+    fn map_region(&self, region: &VhostUserMemoryRegion, file: File) -> io::Result<MmapRegion>;
+
+    /// Map the `region`, backed by `file`, as the Xen foreign memory or grants described by
+    /// `xen_mmap`.
+    fn map_xen_region(
+        &self,
+        _region: &VhostUserMemoryRegion,
+        _xen_mmap: &VhostUserXenMmap,
+        _file: File,
+    ) -> io::Result<MmapRegion> {
+        Err(io::Error::from_raw_os_error(libc::EOPNOTSUPP))
+    }
+
+    /// Check whether Xen foreign memory and grants can be mapped, so the XEN_MMAP protocol
+    /// feature may be negotiated.
+    fn supports_xen_mmap(&self) -> bool {
+        false
+    }
+}
+
+/// Mapper of the guest memory regions as regular shared memory, with mmap(2).
+#[derive(Clone, Copy, Debug, Default)]
+pub struct MmapMapper;
+
+impl MemoryMapper for MmapMapper {
+    fn map_region(&self, region: &VhostUserMemoryRegion, file: File) -> io::Result<MmapRegion> {
+        MmapRegion::from_file(
+            FileOffset::new(file, region.mmap_offset),
+            region.memory_size as usize,
+        )
+        .map_err(|e| match e {
+            MmapRegionError::Mmap(e) => e,
+            e => io::Error::other(e.to_string()),
+        })
+    }
+}
+
+// Mapping from the master's virtual addresses to guest physical addresses.
+struct AddrMapping {
+    vmm_addr: u64,
+    size: u64,
+    gpa_base: u64,
+    mmap_offset: u64,
+}
+
+impl AddrMapping {
+    fn new(region: &VhostUserMemoryRegion) -> Self {
+        AddrMapping {
+            vmm_addr: region.user_addr,
+            size: region.memory_size,
+            gpa_base: region.guest_phys_addr,
+            mmap_offset: region.mmap_offset,
+        }
+    }
+}
+
+#[derive(Default)]
+struct MemoryState {
+    generation: u64,
+    memory: Option<GuestMemoryMmap>,
+}
+
+/// Handle to the current guest memory, shared with the threads accessing the guest memory.
+///
+/// The generation is incremented every time the guest memory is updated by the master, so
+/// threads may cache data derived from the guest memory, such as host addresses, until it
+/// changes.
+#[derive(Clone, Default)]
+pub struct GuestMemoryAtomic {
+    state: Arc<RwLock<MemoryState>>,
+}
+
+impl GuestMemoryAtomic {
+    /// Get the current guest memory and its generation.
+    pub fn load(&self) -> (u64, Option<GuestMemoryMmap>) {
+        let state = self.state.read().unwrap();
+        (state.generation, state.memory.clone())
+    }
+
+    /// Get the current guest memory, if the master has sent it.
+    pub fn memory(&self) -> Option<GuestMemoryMmap> {
+        self.state.read().unwrap().memory.clone()
+    }
+
+    /// Get the generation of the current guest memory.
+    pub fn generation(&self) -> u64 {
+        self.state.read().unwrap().generation
+    }
+
+    fn store(&self, memory: Option<GuestMemoryMmap>) {
+        let mut state = self.state.write().unwrap();
+        state.generation += 1;
+        state.memory = memory;
+    }
+}
+
+/// Hit and miss counters of a `TranslationCache`, to tune how the guest memory is laid out.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct TranslationStats {
+    /// Number of translations made from a cached region.
+    pub hits: u64,
+    /// Number of translations which had to look the region up in the guest memory.
+    pub misses: u64,
+}
+
+// Guest memory region cached by a `TranslationCache`.
+#[derive(Clone, Copy)]
+struct CachedRegion {
+    start: u64,
+    len: u64,
+    host_addr: usize,
+}
+
+/// Cache of the guest memory regions last used to translate guest physical addresses into host
+/// addresses.
+///
+/// Looking an address up in a `GuestMemoryMmap` is a binary search over its regions, while the
+/// descriptors of a virtqueue and their buffers usually lie in a few regions only. The cache keeps
+/// the guest memory it has been filled from alive, so its host addresses stay valid until
+/// `invalidate()` is called, which must be done whenever the guest memory changes.
+#[derive(Default)]
+pub struct TranslationCache {
+    memory: Option<GuestMemoryMmap>,
+    regions: [Option<CachedRegion>; TRANSLATION_CACHE_SIZE],
+    // slot of the region replaced by the next miss
+    next: usize,
+    stats: TranslationStats,
+}
+
+impl TranslationCache {
+    /// Create an empty cache.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Translate the `len` bytes at `addr`, which must lie in a single region of `mem`, into the
+    /// host address of their mapping.
+    pub fn translate(
+        &mut self,
+        mem: &GuestMemoryMmap,
+        addr: GuestAddress,
+        len: usize,
+    ) -> io::Result<*mut u8> {
+        if let Some(host_addr) = self.lookup(addr, len) {
+            self.stats.hits += 1;
+            return Ok(host_addr);
+        }
+        self.stats.misses += 1;
+        let region = self
+            .memory
+            .get_or_insert_with(|| mem.clone())
+            .find_region(addr)
+            .ok_or_else(|| io::Error::from_raw_os_error(libc::EFAULT))?;
+        let start = region.start_addr().raw_value();
+        // The region is already cached if the bytes run past its end.
+        if !self
+            .regions
+            .iter()
+            .flatten()
+            .any(|cached| cached.start == start)
+        {
+            self.regions[self.next] = Some(CachedRegion {
+                start,
+                len: region.len(),
+                host_addr: region.as_ptr() as usize,
+            });
+            self.next = (self.next + 1) % TRANSLATION_CACHE_SIZE;
+        }
+        self.lookup(addr, len)
+            .ok_or_else(|| io::Error::from_raw_os_error(libc::EFAULT))
+    }
+
+    /// Read an object from the guest memory at `addr`.
+    pub fn read_obj<T: ByteValued>(
+        &mut self,
+        mem: &GuestMemoryMmap,
+        addr: GuestAddress,
+    ) -> io::Result<T> {
+        let host_addr = self.translate(mem, addr, mem::size_of::<T>())?;
+        // Safe because the object is in a mapping kept alive by the cache, and any value is a
+        // valid `ByteValued` object.
+        Ok(unsafe { ptr::read_unaligned(host_addr as *const T) })
+    }
+
+    /// Forget the cached regions, once the guest memory has changed.
+    pub fn invalidate(&mut self) {
+        self.memory = None;
+        self.regions = [None; TRANSLATION_CACHE_SIZE];
+        self.next = 0;
+    }
+
+    /// Get the hit and miss counters of the cache.
+    pub fn stats(&self) -> TranslationStats {
+        self.stats
+    }
+
+    fn lookup(&self, addr: GuestAddress, len: usize) -> Option<*mut u8> {
+        let addr = addr.raw_value();
+        self.regions.iter().flatten().find_map(|region| {
+            let offset = addr.checked_sub(region.start)?;
+            if offset.checked_add(len as u64)? > region.len {
+                return None;
+            }
+            Some((region.host_addr + offset as usize) as *mut u8)
+        })
+    }
+}
+
+/// Manager of the guest memory of a slave.
+///
+/// The regions sent with SET_MEM_TABLE and ADD_MEM_REG are mapped into the slave, given the
+/// `MemoryPolicy` chosen for them, and published to the `GuestMemoryAtomic` handles. The mapping
+/// of a removed region is released once the last user of the guest memory it was part of drops
+/// it. The regions are mapped by the `MemoryMapper` of the manager, the `MmapMapper` by default,
+/// and the policy is only applied to regions mapped as regular shared memory. Each region is
+/// mapped from its own offset in its file, which may be backed by huge pages of any size, and
+/// regions which aren't made of whole pages of their file are rejected with
+/// `Error::UnalignedMemRegion`.
+#[derive(Default)]
+pub struct GuestMemoryManager {
+    mappings: Vec<AddrMapping>,
+    atomic: GuestMemoryAtomic,
+    policy: Option<Box<PolicyFn>>,
+    mapper: Option<Box<dyn MemoryMapper>>,
+}
+
+impl GuestMemoryManager {
+    /// Create a manager without guest memory.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Get a handle to the guest memory.
+    pub fn atomic(&self) -> GuestMemoryAtomic {
+        self.atomic.clone()
+    }
+
+    /// Get the current guest memory, if the master has sent it.
+    pub fn memory(&self) -> Option<GuestMemoryMmap> {
+        self.atomic.memory()
+    }
+
+    /// Choose the policy of each region mapped from now on with `policy`, called with the guest
+    /// physical address and the size of the region.
+    pub fn set_policy<F>(&mut self, policy: F)
+    where
+        F: Fn(GuestAddress, u64) -> MemoryPolicy + Send + Sync + 'static,
+    {
+        self.policy = Some(Box::new(policy));
+    }
+
+    /// Map the regions with `mapper` from now on.
+    pub fn set_mapper<M: MemoryMapper + 'static>(&mut self, mapper: M) {
+        self.mapper = Some(Box::new(mapper));
+    }
+
+    /// Check whether Xen foreign memory and grants can be mapped.
+    pub fn supports_xen_mmap(&self) -> bool {
+        self.mapper().supports_xen_mmap()
+    }
+
+    /// Get the number of memory regions.
+    pub fn num_regions(&self) -> usize {
+        self.mappings.len()
+    }
+
+    /// Get the memory regions, as sent by the master.
+    pub fn regions(&self) -> Vec<VhostUserMemoryRegion> {
+        self.mappings
+            .iter()
+            .map(|mapping| VhostUserMemoryRegion {
+                guest_phys_addr: mapping.gpa_base,
+                memory_size: mapping.size,
+                user_addr: mapping.vmm_addr,
+                mmap_offset: mapping.mmap_offset,
+            })
+            .collect()
+    }
+
+    /// Replace the guest memory with the regions of a SET_MEM_TABLE request, each backed by the
+    /// file at the same index in `files`.
+    pub fn set_mem_table(
+        &mut self,
+        regions: &[VhostUserMemoryRegion],
+        files: Vec<File>,
+    ) -> Result<GuestMemoryMmap> {
+        let regions: Vec<VhostUserXenMemoryRegion> = regions
+            .iter()
+            .map(|region| VhostUserXenMemoryRegion::new(*region, VhostUserXenMmap::default()))
+            .collect();
+        self.set_mem_table_xen(&regions, files)
+    }
+
+    /// Replace the guest memory with the regions of a SET_MEM_TABLE request sent with their Xen
+    /// mapping, each backed by the file at the same index in `files`.
+    pub fn set_mem_table_xen(
+        &mut self,
+        regions: &[VhostUserXenMemoryRegion],
+        files: Vec<File>,
+    ) -> Result<GuestMemoryMmap> {
+        if regions.len() != files.len() {
+            return Err(Error::InvalidParam);
+        }
+        let mut guest_regions = Vec::new();
+        for (region, file) in regions.iter().zip(files) {
+            guest_regions.push(self.map_region(region, file)?);
+        }
+
+        let mem = GuestMemoryMmap::from_regions(guest_regions).map_err(map_err)?;
+        self.mappings = regions
+            .iter()
+            .map(|region| AddrMapping::new(&{ region.region }))
+            .collect();
+        self.atomic.store(Some(mem.clone()));
+        Ok(mem)
+    }
+
+    /// Add the region of an ADD_MEM_REG request, backed by `file`, to the guest memory.
+    pub fn add_region(
+        &mut self,
+        region: &VhostUserMemoryRegion,
+        file: File,
+    ) -> Result<GuestMemoryMmap> {
+        let region = VhostUserXenMemoryRegion::new(*region, VhostUserXenMmap::default());
+        self.add_xen_region(&region, file)
+    }
+
+    /// Add the region of an ADD_MEM_REG request sent with its Xen mapping, backed by `file`, to
+    /// the guest memory.
+    pub fn add_xen_region(
+        &mut self,
+        region: &VhostUserXenMemoryRegion,
+        file: File,
+    ) -> Result<GuestMemoryMmap> {
+        let guest_region = self.map_region(region, file)?;
+        let mem = self
+            .memory()
+            .unwrap_or_default()
+            .insert_region(Arc::new(guest_region))
+            .map_err(map_err)?;
+        self.mappings.push(AddrMapping::new(&{ region.region }));
+        self.atomic.store(Some(mem.clone()));
+        Ok(mem)
+    }
+
+    /// Remove the region of a REM_MEM_REG request from the guest memory.
+    pub fn remove_region(&mut self, region: &VhostUserMemoryRegion) -> Result<GuestMemoryMmap> {
+        let mem = self.memory().ok_or(Error::InvalidParam)?;
+        let (mem, _) = mem
+            .remove_region(GuestAddress(region.guest_phys_addr), region.memory_size)
+            .map_err(|_| Error::InvalidParam)?;
+        let gpa_base = region.guest_phys_addr;
+        self.mappings.retain(|mapping| mapping.gpa_base != gpa_base);
+        self.atomic.store(Some(mem.clone()));
+        Ok(mem)
+    }
+
+    /// Drop the guest memory.
+    pub fn clear(&mut self) {
+        self.mappings.clear();
+        self.atomic.store(None);
+    }
+
+    fn mapper(&self) -> &dyn MemoryMapper {
+        self.mapper.as_deref().unwrap_or(&MmapMapper)
+    }
+
+    // Map a region, as described by its Xen mapping.
+    fn map_region(&self, region: &VhostUserXenMemoryRegion, file: File) -> Result<GuestRegionMmap> {
+        let (region, xen_mmap) = (region.region, region.xen_mmap);
+        let gpa = GuestAddress(region.guest_phys_addr);
+        let mmap = if xen_mmap.is_shared_memory() {
+            check_alignment(&region, &file)?;
+            self.mapper().map_region(&region, file)
+        } else {
+            self.mapper().map_xen_region(&region, &xen_mmap, file)
+        }
+        .map_err(Error::ReqHandlerError)?;
+        if (mmap.size() as u64) < region.memory_size {
+            return Err(Error::InvalidParam);
+        }
+        if xen_mmap.is_shared_memory() {
+            self.apply_policy(gpa, region.memory_size, mmap.as_ptr())?;
+        }
+        GuestRegionMmap::new(mmap, gpa).map_err(map_err)
+    }
+
+    fn apply_policy(&self, gpa: GuestAddress, size: u64, addr: *mut u8) -> Result<()> {
+        match self.policy {
+            Some(ref policy) => policy(gpa, size)
+                .apply(addr, size as usize)
+                .map_err(Error::ReqHandlerError),
+            None => Ok(()),
+        }
+    }
+
+    /// Translate a virtual address of the master into a guest physical address.
+    pub fn vmm_va_to_gpa(&self, vmm_va: u64) -> Result<GuestAddress> {
+        for mapping in self.mappings.iter() {
+            if vmm_va >= mapping.vmm_addr && vmm_va < mapping.vmm_addr + mapping.size {
+                return Ok(GuestAddress(vmm_va - mapping.vmm_addr + mapping.gpa_base));
+            }
+        }
+        Err(Error::InvalidParam)
+    }
+}
+
+// Check that the region is made of whole pages of its file, which may be backed by huge pages of
+// any size, so the kernel doesn't fail to map it with a bare EINVAL.
+fn check_alignment(region: &VhostUserMemoryRegion, file: &File) -> Result<()> {
+    let alignment = page_size(file).map_err(Error::ReqHandlerError)?;
+    if (region.mmap_offset | region.memory_size) & (alignment - 1) != 0 {
+        return Err(Error::UnalignedMemRegion {
+            guest_phys_addr: region.guest_phys_addr,
+            alignment,
+        });
+    }
+    Ok(())
+}
+
+// Get the size of the pages of the file.
+fn page_size(file: &File) -> io::Result<u64> {
+    // Safe because the structure is plain data filled by the kernel, and we check the return
+    // value.
+    unsafe {
+        let mut statfs: libc::statfs = mem::zeroed();
+        if libc::fstatfs(file.as_raw_fd(), &mut statfs) < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        if statfs.f_type as u32 == HUGETLBFS_MAGIC {
+            Ok(statfs.f_bsize as u64)
+        } else {
+            Ok(libc::sysconf(libc::_SC_PAGESIZE) as u64)
+        }
+    }
+}
+
+fn map_err<E: ToString>(e: E) -> Error {
+    Error::ReqHandlerError(io::Error::other(e.to_string()))
+}
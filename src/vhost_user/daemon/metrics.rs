@@ -0,0 +1,79 @@
+// Copyright (C) 2020 Alibaba Cloud Computing. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Metrics of the activity of the daemon, for VMM operators to monitor the backends.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Sink of the events counted by the daemon and its worker threads.
+///
+/// The methods are called from the thread handling the master requests and from the worker
+/// threads, so implementations must be cheap and thread safe. All methods default to doing
+/// nothing, for implementations interested in some events only.
+pub trait Metrics: Send + Sync {
+    /// A request from the master has been handled.
+    fn message_processed(&self) {}
+
+    /// The vring `queue_index` has been kicked by the driver.
+    fn queue_kicked(&self, _queue_index: u16) {}
+
+    /// A descriptor chain of the vring `queue_index` has been returned to the driver.
+    fn chain_handled(&self, _queue_index: u16) {}
+
+    /// The daemon failed to handle a request, or the backend failed to handle an event.
+    fn error(&self) {}
+}
+
+// Metrics of daemons created without any.
+pub(super) struct NoMetrics;
+
+impl Metrics for NoMetrics {}
+
+/// Metrics made of atomic counters, to be read while the daemon is running.
+#[derive(Debug, Default)]
+pub struct AtomicMetrics {
+    messages: AtomicU64,
+    kicks: AtomicU64,
+    chains: AtomicU64,
+    errors: AtomicU64,
+}
+
+impl AtomicMetrics {
+    /// Get the number of requests from the master handled.
+    pub fn messages(&self) -> u64 {
+        self.messages.load(Ordering::Relaxed)
+    }
+
+    /// Get the number of vring kicks.
+    pub fn kicks(&self) -> u64 {
+        self.kicks.load(Ordering::Relaxed)
+    }
+
+    /// Get the number of descriptor chains returned to the driver.
+    pub fn chains(&self) -> u64 {
+        self.chains.load(Ordering::Relaxed)
+    }
+
+    /// Get the number of failures.
+    pub fn errors(&self) -> u64 {
+        self.errors.load(Ordering::Relaxed)
+    }
+}
+
+impl Metrics for AtomicMetrics {
+    fn message_processed(&self) {
+        self.messages.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn queue_kicked(&self, _queue_index: u16) {
+        self.kicks.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn chain_handled(&self, _queue_index: u16) {
+        self.chains.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn error(&self) {
+        self.errors.fetch_add(1, Ordering::Relaxed);
+    }
+}
@@ -0,0 +1,1566 @@
+// Copyright (C) 2020 Alibaba Cloud Computing. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! A framework to implement vhost-user slaves.
+//!
+//! The `Daemon` accepts a connection from the master, handles the vhost-user protocol on a
+//! dedicated thread, maps the guest memory with a `GuestMemoryManager` and tracks the vring
+//! configuration. The NUMA placement and huge page advice of the guest memory regions may be
+//! chosen with `Daemon::set_memory_policy()`, and the regions may be mapped by a custom
+//! `MemoryMapper` set with `Daemon::set_memory_mapper()`, for encrypted memory or Xen grants for
+//! instance. The vring kick eventfds are monitored by a pool of
+//! epoll based worker threads, which call into the user supplied `VhostUserBackend`
+//! implementation to process the virtqueues. By default each virtqueue gets its own worker
+//! thread, and backends may share worker threads among virtqueues by overriding
+//! `VhostUserBackend::queues_per_thread()`. The activity of the daemon may be monitored through
+//! the `Metrics` passed to `Daemon::with_metrics()`.
+//!
+//! The daemon either listens for the master with `Daemon::start()`, or connects to the socket the
+//! master listens on with `Daemon::connect()`, as DPDK does in client mode. `Daemon::shutdown()`
+//! stops the daemon once the backend has returned the descriptor chains it is processing.
+//! Signaling `Daemon::exit_event()` wakes up all the threads of the daemon to make them exit, and
+//! may be done from another thread or a signal handler. The worker threads may be pinned to CPUs,
+//! renamed and given a real-time priority through `Daemon::set_worker_config()`. The requests
+//! of untrusted masters may be screened with `Daemon::set_request_filter()`, and their rate
+//! limited with `Daemon::set_rate_limit()`.
+//!
+//! The configuration of the session may be saved with `Daemon::snapshot()`, and set up again
+//! with `Daemon::restore()` in a new daemon, for instance when a VMM restores a snapshot.
+
+use std::any::Any;
+use std::fs::File;
+use std::io;
+use std::net::Shutdown;
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::os::unix::net::UnixStream;
+use std::sync::{Arc, Mutex, RwLock};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use vm_memory::GuestAddress;
+use vmm_sys_util::eventfd::{EventFd, EFD_NONBLOCK};
+
+use super::message::MasterReq;
+use super::{
+    Error as VhostUserError, Listener, RateLimitPolicy, RateLimiter, SessionSnapshot,
+    SlaveListener, SlaveReqHandler,
+};
+
+mod backend;
+pub use self::backend::{read_config_space, VhostUserBackend};
+mod chain;
+pub use self::chain::{ChainReader, ChainWriter, Descriptor, DescriptorChain, DescriptorIter};
+mod event_loop;
+pub use self::event_loop::VringEpollHandler;
+mod handler;
+use self::handler::VhostUserHandler;
+mod memory;
+pub use self::memory::{
+    GuestMemoryAtomic, GuestMemoryManager, MemoryMapper, MemoryPolicy, MmapMapper, NumaPolicy,
+    TranslationCache, TranslationStats,
+};
+mod metrics;
+use self::metrics::NoMetrics;
+pub use self::metrics::{AtomicMetrics, Metrics};
+mod vring;
+pub use self::vring::{QueueStats, UsedRingWriter, Vring};
+mod worker;
+pub use self::worker::{WorkerConfig, WorkerPriority};
+
+/// Errors for the vhost-user daemon.
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum Error {
+    /// Failed to create the vhost-user protocol handler.
+    NewVhostUserHandler(io::Error),
+    /// Failed to create the slave listener.
+    CreateSlaveListener(VhostUserError),
+    /// Failed to accept the connection from the master.
+    CreateSlaveReqHandler(VhostUserError),
+    /// Failed to start the daemon thread.
+    StartDaemon(io::Error),
+    /// The daemon thread panicked.
+    WaitDaemon(Box<dyn Any + Send>),
+    /// Failed to handle a request from the master.
+    HandleRequest(VhostUserError),
+    /// Failed to stop the daemon.
+    Shutdown(io::Error),
+    /// Failed to configure a worker thread.
+    ConfigureWorker(io::Error),
+    /// Failed to restore a session snapshot.
+    RestoreSnapshot(VhostUserError),
+    /// Waiting has been cancelled through the exit eventfd of the daemon.
+    Exited,
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Error::NewVhostUserHandler(e) => write!(f, "failed to create handler: {}", e),
+            Error::CreateSlaveListener(e) => write!(f, "failed to create slave listener: {}", e),
+            Error::CreateSlaveReqHandler(e) => write!(f, "failed to accept connection: {}", e),
+            Error::StartDaemon(e) => write!(f, "failed to start daemon: {}", e),
+            Error::WaitDaemon(_) => write!(f, "daemon thread panicked"),
+            Error::HandleRequest(e) => write!(f, "failed to handle request: {}", e),
+            Error::Shutdown(e) => write!(f, "failed to stop daemon: {}", e),
+            Error::ConfigureWorker(e) => write!(f, "failed to configure worker thread: {}", e),
+            Error::RestoreSnapshot(e) => write!(f, "failed to restore session snapshot: {}", e),
+            Error::Exited => write!(f, "daemon asked to exit"),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::NewVhostUserHandler(e)
+            | Error::StartDaemon(e)
+            | Error::Shutdown(e)
+            | Error::ConfigureWorker(e) => Some(e),
+            Error::CreateSlaveListener(e)
+            | Error::CreateSlaveReqHandler(e)
+            | Error::HandleRequest(e)
+            | Error::RestoreSnapshot(e) => Some(e),
+            Error::WaitDaemon(_) | Error::Exited => None,
+        }
+    }
+}
+
+/// Result of vhost-user daemon operations.
+pub type Result<T> = std::result::Result<T, Error>;
+
+type RequestFilter = dyn FnMut(MasterReq, u32, usize) -> bool + Send;
+
+/// A vhost-user slave daemon serving a `VhostUserBackend`.
+pub struct Daemon<B: VhostUserBackend> {
+    name: String,
+    handler: Arc<Mutex<VhostUserHandler<B>>>,
+    main_thread: Option<thread::JoinHandle<Result<()>>>,
+    // duplicate of the socket connected to the master, to stop the main thread
+    main_sock: Option<UnixStream>,
+    exit_event: Arc<EventFd>,
+    metrics: Arc<dyn Metrics>,
+    // shared by the connections to the master
+    filter: Option<Arc<Mutex<RequestFilter>>>,
+    // copied into each connection to the master
+    rate_limit: Option<(Vec<MasterReq>, RateLimiter, RateLimitPolicy)>,
+}
+
+impl<B: VhostUserBackend> Daemon<B> {
+    /// Create a new daemon for the backend.
+    ///
+    /// This spawns the worker threads, named after `name`, serving the vrings of the device.
+    pub fn new(name: String, backend: Arc<RwLock<B>>) -> Result<Self> {
+        Self::with_metrics(name, backend, Arc::new(NoMetrics))
+    }
+
+    /// Create a new daemon for the backend, counting its activity into `metrics`.
+    ///
+    /// `AtomicMetrics` may be passed to read the counters while the daemon is running.
+    pub fn with_metrics(
+        name: String,
+        backend: Arc<RwLock<B>>,
+        metrics: Arc<dyn Metrics>,
+    ) -> Result<Self> {
+        let exit_event = EventFd::new(EFD_NONBLOCK).map_err(Error::NewVhostUserHandler)?;
+        let handler = VhostUserHandler::new(&name, backend, metrics.clone(), &exit_event)
+            .map_err(Error::NewVhostUserHandler)?;
+
+        Ok(Daemon {
+            name,
+            handler: Arc::new(Mutex::new(handler)),
+            main_thread: None,
+            main_sock: None,
+            exit_event: Arc::new(exit_event),
+            metrics,
+            filter: None,
+            rate_limit: None,
+        })
+    }
+
+    /// Wait for a connection from the master on the listener, and start handling its requests
+    /// on a dedicated thread.
+    pub fn start(&mut self, listener: Listener) -> Result<()> {
+        let fd = listener.as_raw_fd();
+        if wait_for(Some(fd), &self.exit_event, None).map_err(Error::StartDaemon)? == Wakeup::Exit {
+            return Err(Error::Exited);
+        }
+        let mut slave_listener = SlaveListener::new(listener, self.handler.clone())
+            .map_err(Error::CreateSlaveListener)?;
+        let slave_handler = match slave_listener
+            .accept()
+            .map_err(Error::CreateSlaveReqHandler)?
+        {
+            Some(handler) => handler,
+            None => {
+                let err = io::Error::from_raw_os_error(libc::EAGAIN);
+                return Err(Error::CreateSlaveReqHandler(VhostUserError::SocketRetry(
+                    err,
+                )));
+            }
+        };
+        self.spawn(slave_handler)
+    }
+
+    /// Connect to the master listening on the socket at `path`, and start handling its requests
+    /// on a dedicated thread.
+    ///
+    /// The master may not be listening yet, so connecting is attempted `retry_count` more times
+    /// when the socket is missing or refuses the connection, doubling the wait between attempts
+    /// from `retry_interval`.
+    pub fn connect(
+        &mut self,
+        path: &str,
+        retry_count: u32,
+        retry_interval: Duration,
+    ) -> Result<()> {
+        let mut retry_count = retry_count;
+        let mut retry_interval = retry_interval;
+        let slave_handler = loop {
+            match SlaveReqHandler::connect(path, self.handler.clone()) {
+                Ok(handler) => break handler,
+                Err(VhostUserError::SocketConnect(ref e))
+                    if retry_count > 0 && is_master_not_ready(e) =>
+                {
+                    retry_count -= 1;
+                    let wakeup = wait_for(None, &self.exit_event, Some(retry_interval))
+                        .map_err(Error::StartDaemon)?;
+                    if wakeup == Wakeup::Exit {
+                        return Err(Error::Exited);
+                    }
+                    retry_interval *= 2;
+                }
+                Err(e) => return Err(Error::CreateSlaveReqHandler(e)),
+            }
+        };
+        self.spawn(slave_handler)
+    }
+
+    fn spawn(&mut self, mut slave_handler: SlaveReqHandler<VhostUserHandler<B>>) -> Result<()> {
+        // Safe because we check the return value, and the stream takes the ownership of the new
+        // file descriptor.
+        let sock = unsafe {
+            let fd = libc::fcntl(slave_handler.as_raw_fd(), libc::F_DUPFD_CLOEXEC, 0);
+            if fd < 0 {
+                return Err(Error::StartDaemon(io::Error::last_os_error()));
+            }
+            UnixStream::from_raw_fd(fd)
+        };
+
+        if let Some(ref filter) = self.filter {
+            let filter = filter.clone();
+            slave_handler.set_request_filter(move |code, size, num_fds| {
+                (filter.lock().unwrap())(code, size, num_fds)
+            });
+        }
+
+        let mut disconnect = false;
+        if let Some((ref requests, ref limiter, policy)) = self.rate_limit {
+            slave_handler.set_rate_limit(requests, limiter.clone(), policy);
+            disconnect = policy == RateLimitPolicy::Disconnect;
+        }
+
+        let metrics = self.metrics.clone();
+        let exit_event = self.exit_event.clone();
+        let handle = thread::Builder::new()
+            .name(self.name.clone())
+            .spawn(move || -> Result<()> {
+                let fd = slave_handler.as_raw_fd();
+                loop {
+                    match wait_for(Some(fd), &exit_event, None) {
+                        Ok(Wakeup::Exit) => return Ok(()),
+                        Ok(_) => {}
+                        Err(e) => return Err(Error::HandleRequest(VhostUserError::SocketError(e))),
+                    }
+                    match slave_handler.handle_request() {
+                        Ok(()) => metrics.message_processed(),
+                        // The master may go on once its request has been denied.
+                        Err(VhostUserError::RejectedRequest(_)) => metrics.error(),
+                        Err(VhostUserError::RateLimited(_)) if !disconnect => metrics.error(),
+                        Err(e) => {
+                            metrics.error();
+                            return Err(Error::HandleRequest(e));
+                        }
+                    }
+                }
+            })
+            .map_err(Error::StartDaemon)?;
+        self.main_thread = Some(handle);
+        self.main_sock = Some(sock);
+
+        Ok(())
+    }
+
+    /// Stop the daemon and its worker threads, returning the statistics of each vring.
+    ///
+    /// The connection to the master is closed first, so no more requests are handled. The worker
+    /// threads keep serving the vrings until the backend has returned all the descriptor chains
+    /// it has taken, or `timeout` has elapsed, and are then asked to exit through their exit
+    /// eventfd. The chains still in flight are reported in the statistics.
+    pub fn shutdown(&mut self, timeout: Duration) -> Result<Vec<QueueStats>> {
+        let deadline = Instant::now() + timeout;
+        if let Some(sock) = self.main_sock.take() {
+            sock.shutdown(Shutdown::Both).map_err(Error::Shutdown)?;
+        }
+        // The main thread fails once the connection is closed.
+        if let Some(handle) = self.main_thread.take() {
+            let _ = handle.join().map_err(Error::WaitDaemon)?;
+        }
+
+        let vrings = self.handler.lock().unwrap().vrings();
+        while Instant::now() < deadline
+            && vrings
+                .iter()
+                .any(|vring| vring.read().unwrap().inflight() != 0)
+        {
+            let wakeup = wait_for(None, &self.exit_event, Some(Duration::from_millis(1)))
+                .map_err(Error::Shutdown)?;
+            if wakeup == Wakeup::Exit {
+                break;
+            }
+        }
+
+        let threads = self
+            .handler
+            .lock()
+            .unwrap()
+            .exit_workers()
+            .map_err(Error::Shutdown)?;
+        for thread in threads {
+            // Failures of the worker threads have already been counted in the metrics.
+            let _ = thread.join().map_err(Error::WaitDaemon)?;
+        }
+        Ok(vrings
+            .iter()
+            .map(|vring| vring.read().unwrap().stats())
+            .collect())
+    }
+
+    /// Wait for the thread handling the master requests to exit.
+    ///
+    /// Unless the exit eventfd has been signaled, the thread only exits on failure, including the
+    /// master closing the connection, so the returned error tells why the daemon stopped.
+    pub fn wait(&mut self) -> Result<()> {
+        let res = match self.main_thread.take() {
+            Some(handle) => handle.join().map_err(Error::WaitDaemon)?,
+            None => Ok(()),
+        };
+        // Close the connection to the master.
+        self.main_sock = None;
+        res
+    }
+
+    /// Get the eventfd making the daemon exit once signaled.
+    ///
+    /// Writing to it cancels all the blocking waits of the daemon: `start()` and `connect()` fail
+    /// with `Exited`, `shutdown()` stops waiting for the descriptor chains in flight, and the
+    /// thread handling the master requests and the worker threads exit. The eventfd may be
+    /// written from other threads, or from signal handlers through its raw file descriptor. It's
+    /// never consumed, so the daemon can't be started again once it has been signaled.
+    pub fn exit_event(&self) -> &EventFd {
+        &self.exit_event
+    }
+
+    /// Pin the worker thread `thread_id`, as indexed by `VhostUserBackend::queues_per_thread()`,
+    /// to CPUs, rename it or change its scheduling policy.
+    pub fn set_worker_config(&self, thread_id: usize, config: &WorkerConfig) -> Result<()> {
+        self.handler
+            .lock()
+            .unwrap()
+            .configure_worker(thread_id, config)
+            .map_err(Error::ConfigureWorker)
+    }
+
+    /// Only handle the requests of the master passing `filter`, as
+    /// `SlaveReqHandler::set_request_filter()` does, from the next connection on.
+    ///
+    /// The requests denied by the filter are failed, and counted as errors in the metrics, but
+    /// the daemon keeps serving the master.
+    pub fn set_request_filter<F>(&mut self, filter: F)
+    where
+        F: FnMut(MasterReq, u32, usize) -> bool + Send + 'static,
+    {
+        self.filter = Some(Arc::new(Mutex::new(filter)));
+    }
+
+    /// Limit the rate of the `requests` of the master, or of all its requests if empty, as
+    /// `SlaveReqHandler::set_rate_limit()` does, from the next connection on. Each connection
+    /// starts with a full copy of `limiter`.
+    ///
+    /// The requests beyond the limit are failed, and counted as errors in the metrics. The
+    /// daemon then keeps serving the master with `RateLimitPolicy::Reject`, and drops the
+    /// connection with `RateLimitPolicy::Disconnect`.
+    pub fn set_rate_limit(
+        &mut self,
+        requests: &[MasterReq],
+        limiter: RateLimiter,
+        policy: RateLimitPolicy,
+    ) {
+        self.rate_limit = Some((requests.to_vec(), limiter, policy));
+    }
+
+    /// Choose the NUMA placement and huge page advice of each guest memory region with `policy`,
+    /// called with the guest physical address and the size of the region once it has been
+    /// mapped.
+    pub fn set_memory_policy<F>(&self, policy: F)
+    where
+        F: Fn(GuestAddress, u64) -> MemoryPolicy + Send + Sync + 'static,
+    {
+        self.handler.lock().unwrap().set_memory_policy(policy);
+    }
+
+    /// Map the guest memory regions sent by the master with `mapper`, instead of the
+    /// `MmapMapper`. The master may negotiate the XEN_MMAP protocol feature, if the backend
+    /// supports it, once the mapper supports Xen foreign memory and grants.
+    pub fn set_memory_mapper<M: MemoryMapper + 'static>(&self, mapper: M) {
+        self.handler.lock().unwrap().set_memory_mapper(mapper);
+    }
+
+    /// Take a snapshot of the session configured by the master, preferably while the vrings are
+    /// stopped.
+    pub fn snapshot(&self) -> SessionSnapshot {
+        self.handler.lock().unwrap().snapshot()
+    }
+
+    /// Set up the session from `snapshot` before the master connects, as if the master had sent
+    /// the requests which configured it, with the memory regions of the snapshot backed by
+    /// `files`, in the same order.
+    ///
+    /// The ownership of the session is released afterwards: the master still takes it and sends
+    /// the vring eventfds, and may resend any of the configuration.
+    pub fn restore(&self, snapshot: &SessionSnapshot, files: Vec<File>) -> Result<()> {
+        self.handler
+            .lock()
+            .unwrap()
+            .restore(snapshot, files)
+            .map_err(Error::RestoreSnapshot)
+    }
+
+    /// Get a handle to the guest memory, for the threads of the backend accessing it outside of
+    /// `VhostUserBackend::update_memory()`.
+    pub fn memory(&self) -> GuestMemoryAtomic {
+        self.handler.lock().unwrap().memory()
+    }
+
+    /// Get the event loops of the worker threads, indexed by thread id, for backends to register
+    /// their own file descriptors.
+    pub fn get_vring_workers(&self) -> Vec<Arc<VringEpollHandler<B>>> {
+        self.handler.lock().unwrap().vring_workers()
+    }
+}
+
+// What interrupted `wait_for()`.
+#[derive(Debug, PartialEq)]
+enum Wakeup {
+    Ready,
+    Exit,
+    Timeout,
+}
+
+// Wait for `fd` to be readable, if any, until `exit_event` is signaled or `timeout` has elapsed.
+fn wait_for(
+    fd: Option<RawFd>,
+    exit_event: &EventFd,
+    timeout: Option<Duration>,
+) -> io::Result<Wakeup> {
+    let mut fds = [
+        libc::pollfd {
+            fd: exit_event.as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        },
+        // Negative file descriptors are ignored by poll().
+        libc::pollfd {
+            fd: fd.unwrap_or(-1),
+            events: libc::POLLIN,
+            revents: 0,
+        },
+    ];
+    let timeout = timeout.map_or(-1, |t| t.as_millis().min(i32::MAX as u128) as i32);
+    loop {
+        // Safe because the array outlives the call, and we check the return value.
+        let ret = unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, timeout) };
+        if ret >= 0 {
+            break;
+        }
+        let err = io::Error::last_os_error();
+        if err.kind() != io::ErrorKind::Interrupted {
+            return Err(err);
+        }
+    }
+    if fds[0].revents != 0 {
+        Ok(Wakeup::Exit)
+    } else if fds[1].revents != 0 {
+        Ok(Wakeup::Ready)
+    } else {
+        Ok(Wakeup::Timeout)
+    }
+}
+
+// The socket of the master doesn't exist yet, or nothing listens on it.
+fn is_master_not_ready(err: &io::Error) -> bool {
+    matches!(
+        err.kind(),
+        io::ErrorKind::NotFound | io::ErrorKind::ConnectionRefused
+    )
+}
+
+#[cfg(all(test, feature = "vhost-user-master"))]
+mod tests {
+    use super::*;
+    use crate::backend::{
+        VhostFeatures, VhostMemory, VhostOwner, VhostUserMemoryRegionInfo, VhostVring,
+        VringConfigData,
+    };
+    use crate::features::VirtioFeatures;
+    use crate::vhost_user::message::{
+        VhostUserMemoryRegion, VhostUserProtocolFeatures, VhostUserVirtioFeatures,
+        VhostUserXenMemoryRegion, VhostUserXenMmap, VhostUserXenMmapFlags,
+    };
+    use crate::vhost_user::{
+        Master, MasterListener, MemoryRegionSnapshot, VhostUserMaster, VringSnapshot,
+    };
+    use std::os::unix::io::AsRawFd;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::mpsc::{channel, Sender};
+    use vm_memory::{Bytes, FileOffset, GuestAddress, GuestMemory, GuestMemoryMmap};
+    use vmm_sys_util::epoll::EventSet;
+    use vmm_sys_util::eventfd::EventFd;
+    use vmm_sys_util::tempfile::TempFile;
+
+    struct DummyBackend {
+        mem: Option<GuestMemoryMmap>,
+        events: Mutex<Sender<(u16, usize)>>,
+        queues_per_thread: Option<Vec<u64>>,
+        enabled: Vec<(u16, bool)>,
+    }
+
+    impl VhostUserBackend for DummyBackend {
+        fn num_queues(&self) -> usize {
+            2
+        }
+
+        fn max_queue_size(&self) -> usize {
+            256
+        }
+
+        fn features(&self) -> u64 {
+            0xffff_ffff_ffff_ffff
+        }
+
+        fn protocol_features(&self) -> VhostUserProtocolFeatures {
+            VhostUserProtocolFeatures::all()
+        }
+
+        fn update_memory(&mut self, mem: GuestMemoryMmap) -> io::Result<()> {
+            self.mem = Some(mem);
+            Ok(())
+        }
+
+        fn set_queue_enabled(&mut self, queue_index: u16, enabled: bool) -> io::Result<()> {
+            self.enabled.push((queue_index, enabled));
+            Ok(())
+        }
+
+        fn queues_per_thread(&self) -> Vec<u64> {
+            match self.queues_per_thread {
+                Some(ref masks) => masks.clone(),
+                None => vec![0x1, 0x2],
+            }
+        }
+
+        fn process_queue(
+            &self,
+            queue_index: u16,
+            vring: &mut Vring,
+            thread_id: usize,
+        ) -> io::Result<()> {
+            assert_eq!(vring.desc_table().0, 0x1000);
+            // Only queues of 128 descriptors are supported.
+            if vring.size() != 128 {
+                return Err(io::Error::from_raw_os_error(libc::EINVAL));
+            }
+            vring.add_used(self.mem.as_ref().unwrap(), 0, 0)?;
+            vring.signal_used_queue()?;
+            self.events
+                .lock()
+                .unwrap()
+                .send((queue_index, thread_id))
+                .unwrap();
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_daemon() {
+        let path = "/tmp/vhost_user_lib_unit_test_daemon";
+        let (tx, rx) = channel();
+        let backend = Arc::new(RwLock::new(DummyBackend {
+            mem: None,
+            events: Mutex::new(tx),
+            queues_per_thread: None,
+            enabled: Vec::new(),
+        }));
+        let metrics = Arc::new(AtomicMetrics::default());
+        let mut daemon =
+            Daemon::with_metrics("test-daemon".to_string(), backend.clone(), metrics.clone())
+                .unwrap();
+        let memory = daemon.memory();
+        assert!(memory.memory().is_none());
+        let workers = daemon.get_vring_workers();
+        assert_eq!(workers.len(), 2);
+        assert_eq!(workers[1].thread_id(), 1);
+        let fd = EventFd::new(0).unwrap();
+        assert!(workers[0]
+            .register_listener(fd.as_raw_fd(), EventSet::IN, 2)
+            .is_err());
+
+        let listener = Listener::new(path, true).unwrap();
+        let master_thread = thread::spawn(move || {
+            let mut master = Master::connect(path, 2).unwrap();
+            master.set_owner().unwrap();
+            // The vring is a split one.
+            let features = master.get_features().unwrap() & !VirtioFeatures::RING_PACKED.bits();
+            master.set_features(features).unwrap();
+            master.get_protocol_features().unwrap();
+            master
+                .set_protocol_features(
+                    VhostUserProtocolFeatures::MQ | VhostUserProtocolFeatures::CONFIGURE_MEM_SLOTS,
+                )
+                .unwrap();
+
+            let file = TempFile::new().unwrap().into_file();
+            file.set_len(0x10000).unwrap();
+            let region = VhostUserMemoryRegionInfo {
+                guest_phys_addr: 0,
+                memory_size: 0x10000,
+                userspace_addr: 0x7f00_0000_0000,
+                mmap_offset: 0,
+                mmap_handle: file.as_raw_fd(),
+            };
+            master.set_mem_table(&[region]).unwrap();
+            let hotplug = VhostUserMemoryRegionInfo {
+                guest_phys_addr: 0x10_0000,
+                memory_size: 0x1000,
+                userspace_addr: 0x7f00_1000_0000,
+                mmap_offset: 0,
+                mmap_handle: file.as_raw_fd(),
+            };
+            master.add_mem_region(&hotplug).unwrap();
+            master.add_mem_region(&region).unwrap();
+
+            master.set_vring_num(1, 128).unwrap();
+            let config = VringConfigData {
+                queue_max_size: 256,
+                queue_size: 128,
+                flags: 0,
+                desc_table_addr: 0x7f00_0000_1000,
+                used_ring_addr: 0x7f00_0000_3000,
+                avail_ring_addr: 0x7f00_0000_2000,
+                log_addr: None,
+            };
+            master.set_vring_addr(1, &config).unwrap();
+            master.set_vring_base(1, 0).unwrap();
+            let call = EventFd::new(0).unwrap();
+            master.set_vring_call(1, &call).unwrap();
+            // Kicks of disabled vrings are dropped, so enable the vring before starting it.
+            master.set_vring_enable(1, true).unwrap();
+            let kick = EventFd::new(0).unwrap();
+            master.set_vring_kick(1, &kick).unwrap();
+
+            kick.write(1).unwrap();
+            assert_eq!(call.read().unwrap(), 1);
+
+            // Failures to process the vring are reported through the error eventfd.
+            let err = EventFd::new(0).unwrap();
+            master.set_vring_err(1, &err).unwrap();
+            master.set_vring_num(1, 64).unwrap();
+            // Wait for the requests without replies to be handled.
+            master.get_features().unwrap();
+            kick.write(1).unwrap();
+            assert_eq!(err.read().unwrap(), 1);
+            assert_eq!(master.get_vring_base(1).unwrap(), 0);
+        });
+
+        daemon.start(listener).unwrap();
+        assert_eq!(rx.recv().unwrap(), (1, 1));
+        master_thread.join().unwrap();
+
+        // The master has closed the connection.
+        assert!(daemon.wait().is_err());
+        assert_eq!(metrics.messages(), 18);
+        assert_eq!(metrics.kicks(), 2);
+        assert_eq!(metrics.chains(), 1);
+        // The failure to process the vring, and the master closing the connection.
+        assert_eq!(metrics.errors(), 2);
+        let backend = backend.read().unwrap();
+        assert_eq!(backend.mem.as_ref().unwrap().num_regions(), 2);
+        // The failed ADD_MEM_REG of an overlapping region hasn't updated the guest memory.
+        let (generation, mem) = memory.load();
+        assert_eq!(generation, 2);
+        assert_eq!(mem.unwrap().num_regions(), 2);
+        assert_eq!(backend.enabled, vec![(1, true)]);
+    }
+
+    #[test]
+    fn test_daemon_queues_per_thread() {
+        let create = |masks: Vec<u64>| {
+            let (tx, _rx) = channel();
+            let backend = Arc::new(RwLock::new(DummyBackend {
+                mem: None,
+                events: Mutex::new(tx),
+                queues_per_thread: Some(masks),
+                enabled: Vec::new(),
+            }));
+            Daemon::new("test-daemon".to_string(), backend)
+        };
+
+        let daemon = create(vec![0x3]).unwrap();
+        assert_eq!(daemon.get_vring_workers().len(), 1);
+        assert!(create(vec![0x1]).is_err());
+        assert!(create(vec![0x1, 0x3]).is_err());
+        assert!(create(vec![0x3, 0x4]).is_err());
+        assert!(create(vec![0x1, 0x0, 0x2]).is_err());
+    }
+
+    #[test]
+    fn test_daemon_worker_config() {
+        let (tx, _rx) = channel();
+        let backend = Arc::new(RwLock::new(DummyBackend {
+            mem: None,
+            events: Mutex::new(tx),
+            queues_per_thread: None,
+            enabled: Vec::new(),
+        }));
+        let daemon = Daemon::new("test-daemon".to_string(), backend).unwrap();
+
+        // Pin the worker thread to one of the CPUs the process may run on.
+        // Safe because the set is valid for the whole call and we check the result.
+        let mut set: libc::cpu_set_t = unsafe { std::mem::zeroed() };
+        let ret =
+            unsafe { libc::sched_getaffinity(0, std::mem::size_of::<libc::cpu_set_t>(), &mut set) };
+        assert_eq!(ret, 0);
+        let cpu = (0..std::mem::size_of::<libc::cpu_set_t>() * 8)
+            .find(|&cpu| unsafe { libc::CPU_ISSET(cpu, &set) })
+            .unwrap();
+        let config = WorkerConfig {
+            cpus: vec![cpu],
+            name: Some("test-pinned-worker".to_string()),
+            priority: None,
+        };
+        daemon.set_worker_config(1, &config).unwrap();
+
+        let task = std::fs::read_dir("/proc/self/task")
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .find(|task| {
+                let comm = std::fs::read_to_string(task.join("comm")).unwrap();
+                comm.trim_end() == "test-pinned-wor"
+            })
+            .unwrap();
+        let status = std::fs::read_to_string(task.join("status")).unwrap();
+        assert!(status
+            .lines()
+            .any(|line| line.split_whitespace().collect::<Vec<_>>()
+                == vec!["Cpus_allowed_list:", &cpu.to_string()]));
+
+        let config = WorkerConfig {
+            priority: Some(WorkerPriority::Fifo(0)),
+            ..Default::default()
+        };
+        match daemon.set_worker_config(0, &config) {
+            Err(Error::ConfigureWorker(ref e)) if e.raw_os_error() == Some(libc::EINVAL) => {}
+            _ => panic!("invalid priority accepted"),
+        }
+        assert!(daemon
+            .set_worker_config(2, &WorkerConfig::default())
+            .is_err());
+    }
+
+    #[test]
+    fn test_memory_policy() {
+        // The kernel lacks NUMA or transparent huge pages support.
+        if !std::path::Path::new("/proc/self/numa_maps").exists()
+            || !std::path::Path::new("/sys/kernel/mm/transparent_hugepage").exists()
+        {
+            return;
+        }
+        let mut manager = GuestMemoryManager::new();
+        manager.set_policy(|gpa, size| {
+            assert_eq!(size, 0x20_0000);
+            if gpa.0 == 0 {
+                MemoryPolicy {
+                    numa: Some(NumaPolicy::Bind(vec![0])),
+                    huge_pages: Some(true),
+                }
+            } else {
+                MemoryPolicy {
+                    numa: Some(NumaPolicy::Bind(vec![1023])),
+                    huge_pages: None,
+                }
+            }
+        });
+        let file = TempFile::new().unwrap().into_file();
+        file.set_len(0x20_0000).unwrap();
+        let region = VhostUserMemoryRegion::new(0, 0x20_0000, 0x7f00_0000_0000, 0);
+        let mem = manager
+            .set_mem_table(&[region], vec![file.try_clone().unwrap()])
+            .unwrap();
+
+        // Find the mapping in the policies and the attributes of the mappings of the process.
+        let addr = format!(
+            "{:x}",
+            mem.get_host_address(GuestAddress(0)).unwrap() as usize
+        );
+        let numa_maps = std::fs::read_to_string("/proc/self/numa_maps").unwrap();
+        let policy = numa_maps
+            .lines()
+            .find(|line| line.starts_with(&addr))
+            .unwrap();
+        assert!(policy.contains(" bind:0"));
+        let smaps = std::fs::read_to_string("/proc/self/smaps").unwrap();
+        let flags = smaps
+            .split_terminator('\n')
+            .skip_while(|line| !line.starts_with(&addr))
+            .find(|line| line.starts_with("VmFlags:"))
+            .unwrap();
+        assert!(flags.split_whitespace().any(|flag| flag == "hg"));
+
+        // Regions whose policy can't be applied aren't mapped.
+        let region = VhostUserMemoryRegion::new(0x100_0000, 0x20_0000, 0x7f00_1000_0000, 0);
+        assert!(manager.add_region(&region, file).is_err());
+        assert_eq!(manager.num_regions(), 1);
+    }
+
+    // Maps the file sent with the grants, as gntdev does once told which grants to map, and
+    // counts the regions mapped as regular shared memory.
+    struct DummyXenMapper {
+        domid: u32,
+        shared: Arc<AtomicUsize>,
+    }
+
+    impl MemoryMapper for DummyXenMapper {
+        fn map_region(
+            &self,
+            region: &VhostUserMemoryRegion,
+            file: File,
+        ) -> io::Result<vm_memory::MmapRegion> {
+            self.shared.fetch_add(1, Ordering::SeqCst);
+            MmapMapper.map_region(region, file)
+        }
+
+        fn map_xen_region(
+            &self,
+            region: &VhostUserMemoryRegion,
+            xen_mmap: &VhostUserXenMmap,
+            file: File,
+        ) -> io::Result<vm_memory::MmapRegion> {
+            if xen_mmap.domid != self.domid || xen_mmap.flags & 0x2 == 0 {
+                return Err(io::Error::from_raw_os_error(libc::EINVAL));
+            }
+            MmapMapper.map_region(region, file)
+        }
+
+        fn supports_xen_mmap(&self) -> bool {
+            true
+        }
+    }
+
+    #[test]
+    fn test_unaligned_regions() {
+        let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as u64;
+        let file = TempFile::new().unwrap().into_file();
+        file.set_len(4 * page_size).unwrap();
+        let mut manager = GuestMemoryManager::new();
+
+        // Regions are mapped from their own offset in the file.
+        let regions = [
+            VhostUserMemoryRegion::new(0, page_size, 0x7f00_0000_0000, 2 * page_size),
+            VhostUserMemoryRegion::new(0x10_0000, page_size, 0x7f00_1000_0000, page_size),
+        ];
+        let files = vec![file.try_clone().unwrap(), file.try_clone().unwrap()];
+        let mem = manager.set_mem_table(&regions, files).unwrap();
+        mem.write_obj(0x55aa_u16, GuestAddress(0)).unwrap();
+        let mut buf = [0u8; 2];
+        let mut reader = file.try_clone().unwrap();
+        std::io::Seek::seek(&mut reader, std::io::SeekFrom::Start(2 * page_size)).unwrap();
+        std::io::Read::read_exact(&mut reader, &mut buf).unwrap();
+        assert_eq!(u16::from_ne_bytes(buf), 0x55aa);
+        assert_eq!({ manager.regions()[0].mmap_offset }, 2 * page_size);
+
+        for (offset, size) in [(page_size / 2, page_size), (0, page_size + 0x100)].iter() {
+            let region = VhostUserMemoryRegion::new(0x20_0000, *size, 0x7f00_2000_0000, *offset);
+            match manager.add_region(&region, file.try_clone().unwrap()) {
+                Err(VhostUserError::UnalignedMemRegion {
+                    guest_phys_addr: 0x20_0000,
+                    alignment,
+                }) => assert_eq!(alignment, page_size),
+                _ => panic!("expected an unaligned region"),
+            }
+        }
+        assert_eq!(manager.num_regions(), 2);
+    }
+
+    #[test]
+    fn test_memory_mapper() {
+        let file = TempFile::new().unwrap().into_file();
+        file.set_len(0x2000).unwrap();
+        let region = VhostUserMemoryRegion::new(0x10_0000, 0x1000, 0x7f00_0000_0000, 0x1000);
+        let grant = VhostUserXenMemoryRegion::new(
+            region,
+            VhostUserXenMmap::new(VhostUserXenMmapFlags::GRANT, 1),
+        );
+
+        // The default mapper can't map Xen regions.
+        let mut manager = GuestMemoryManager::new();
+        assert!(!manager.supports_xen_mmap());
+        assert!(manager
+            .set_mem_table_xen(&[grant], vec![file.try_clone().unwrap()])
+            .is_err());
+
+        let shared = Arc::new(AtomicUsize::new(0));
+        manager.set_mapper(DummyXenMapper {
+            domid: 1,
+            shared: shared.clone(),
+        });
+        assert!(manager.supports_xen_mmap());
+        let mem = manager
+            .set_mem_table_xen(&[grant], vec![file.try_clone().unwrap()])
+            .unwrap();
+        mem.write_obj(0x55aa_u16, GuestAddress(0x10_0000)).unwrap();
+        assert!(manager.vmm_va_to_gpa(0x7f00_0000_0010).is_ok());
+        assert_eq!(shared.load(Ordering::SeqCst), 0);
+
+        // Errors of the mapper fail the request.
+        let region = VhostUserMemoryRegion::new(0x20_0000, 0x1000, 0x7f00_1000_0000, 0);
+        let foreign = VhostUserXenMemoryRegion::new(
+            region,
+            VhostUserXenMmap::new(VhostUserXenMmapFlags::FOREIGN, 1),
+        );
+        assert!(manager
+            .add_xen_region(&foreign, file.try_clone().unwrap())
+            .is_err());
+        assert_eq!(manager.num_regions(), 1);
+        // Regular shared memory goes through the mapper as well.
+        manager.add_region(&region, file).unwrap();
+        assert_eq!(manager.num_regions(), 2);
+        assert_eq!(shared.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn test_daemon_snapshot() {
+        let create = || {
+            let (tx, _rx) = channel();
+            let backend = Arc::new(RwLock::new(DummyBackend {
+                mem: None,
+                events: Mutex::new(tx),
+                queues_per_thread: None,
+                enabled: Vec::new(),
+            }));
+            let daemon = Daemon::new("test-daemon".to_string(), backend.clone()).unwrap();
+            (daemon, backend)
+        };
+        let file = TempFile::new().unwrap().into_file();
+        file.set_len(0x10000).unwrap();
+        let snapshot = SessionSnapshot {
+            features: Some(VhostUserVirtioFeatures::PROTOCOL_FEATURES.bits()),
+            protocol_features: Some(VhostUserProtocolFeatures::MQ.bits()),
+            regions: vec![MemoryRegionSnapshot {
+                guest_phys_addr: 0,
+                memory_size: 0x10000,
+                userspace_addr: 0x7f00_0000_0000,
+                mmap_offset: 0,
+            }],
+            vrings: vec![
+                VringSnapshot {
+                    size: Some(256),
+                    config: None,
+                    base: Some(0),
+                    enabled: false,
+                },
+                VringSnapshot {
+                    size: Some(128),
+                    config: Some(VringConfigData {
+                        queue_max_size: 256,
+                        queue_size: 128,
+                        flags: 0,
+                        desc_table_addr: 0x7f00_0000_1000,
+                        used_ring_addr: 0x7f00_0000_3000,
+                        avail_ring_addr: 0x7f00_0000_2000,
+                        log_addr: None,
+                    }),
+                    base: Some(42),
+                    enabled: true,
+                },
+            ],
+            status: Some(0xf),
+        };
+
+        let (daemon, backend) = create();
+        assert!(daemon.restore(&snapshot, Vec::new()).is_err());
+        daemon
+            .restore(&snapshot, vec![file.try_clone().unwrap()])
+            .unwrap();
+        assert_eq!(daemon.snapshot(), snapshot);
+        let backend = backend.read().unwrap();
+        assert_eq!(backend.mem.as_ref().unwrap().num_regions(), 1);
+        assert_eq!(backend.enabled, vec![(1, true)]);
+
+        // A new daemon starts with an empty session.
+        let (daemon, _) = create();
+        let empty = daemon.snapshot();
+        assert!(empty.features.is_none());
+        assert!(empty.regions.is_empty());
+        assert!(empty.vrings.iter().all(|vring| vring.config.is_none()));
+    }
+
+    #[test]
+    fn test_daemon_connect() {
+        let path = "/tmp/vhost_user_lib_unit_test_daemon_connect";
+        let _ = std::fs::remove_file(path);
+        let (tx, _rx) = channel();
+        let backend = Arc::new(RwLock::new(DummyBackend {
+            mem: None,
+            events: Mutex::new(tx),
+            queues_per_thread: None,
+            enabled: Vec::new(),
+        }));
+        let mut daemon = Daemon::new("test-daemon".to_string(), backend).unwrap();
+        match daemon.connect(path, 0, Duration::from_millis(1)) {
+            Err(Error::CreateSlaveReqHandler(VhostUserError::SocketConnect(_))) => {}
+            _ => panic!("connected to a missing socket"),
+        }
+
+        // The master starts listening after the daemon has started connecting.
+        let master_thread = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(20));
+            let listener = Listener::new(path, true).unwrap();
+            let mut master = MasterListener::new(listener, 2).accept().unwrap().unwrap();
+            master.set_owner().unwrap();
+            let features = master.get_features().unwrap();
+            assert_ne!(
+                features & VhostUserVirtioFeatures::PROTOCOL_FEATURES.bits(),
+                0
+            );
+        });
+        daemon.connect(path, 10, Duration::from_millis(5)).unwrap();
+        master_thread.join().unwrap();
+        assert!(daemon.wait().is_err());
+    }
+
+    #[test]
+    fn test_daemon_shutdown() {
+        let path = "/tmp/vhost_user_lib_unit_test_daemon_shutdown";
+        let (tx, _rx) = channel();
+        let backend = Arc::new(RwLock::new(DummyBackend {
+            mem: None,
+            events: Mutex::new(tx),
+            queues_per_thread: None,
+            enabled: Vec::new(),
+        }));
+        let mut daemon = Daemon::new("test-daemon".to_string(), backend).unwrap();
+        let listener = Listener::new(path, true).unwrap();
+        let mut master = Master::connect(path, 2).unwrap();
+        daemon.start(listener).unwrap();
+        master.set_owner().unwrap();
+        master.get_features().unwrap();
+
+        let stats = daemon.shutdown(Duration::from_millis(100)).unwrap();
+        assert_eq!(stats, vec![QueueStats::default(); 2]);
+        // The connection to the master has been closed.
+        assert!(master.get_features().is_err());
+        assert!(daemon.wait().is_ok());
+    }
+
+    #[test]
+    fn test_daemon_request_filter() {
+        let path = "/tmp/vhost_user_lib_unit_test_daemon_request_filter";
+        let (tx, _rx) = channel();
+        let backend = Arc::new(RwLock::new(DummyBackend {
+            mem: None,
+            events: Mutex::new(tx),
+            queues_per_thread: None,
+            enabled: Vec::new(),
+        }));
+        let metrics = Arc::new(AtomicMetrics::default());
+        let mut daemon =
+            Daemon::with_metrics("test-daemon".to_string(), backend, metrics.clone()).unwrap();
+        daemon.set_request_filter(|code, _, _| code != MasterReq::SET_FEATURES);
+        let listener = Listener::new(path, true).unwrap();
+        let mut master = Master::connect(path, 2).unwrap();
+        daemon.start(listener).unwrap();
+        master.set_owner().unwrap();
+        let features = master.get_features().unwrap();
+        master.set_features(features).unwrap();
+        // The daemon keeps serving the master after the rejection.
+        master.get_features().unwrap();
+        assert_eq!(metrics.errors(), 1);
+        drop(master);
+        assert!(daemon.wait().is_err());
+        assert_eq!(metrics.messages(), 3);
+    }
+
+    #[test]
+    fn test_daemon_rate_limit() {
+        let path = "/tmp/vhost_user_lib_unit_test_daemon_rate_limit";
+        for &policy in [RateLimitPolicy::Reject, RateLimitPolicy::Disconnect].iter() {
+            let (tx, _rx) = channel();
+            let backend = Arc::new(RwLock::new(DummyBackend {
+                mem: None,
+                events: Mutex::new(tx),
+                queues_per_thread: None,
+                enabled: Vec::new(),
+            }));
+            let mut daemon = Daemon::new("test-daemon".to_string(), backend).unwrap();
+            let limiter = RateLimiter::new(1, Duration::from_secs(3600));
+            daemon.set_rate_limit(&[MasterReq::SET_FEATURES], limiter, policy);
+            let listener = Listener::new(path, true).unwrap();
+            let mut master = Master::connect(path, 2).unwrap();
+            daemon.start(listener).unwrap();
+            master.set_owner().unwrap();
+            let features = master.get_features().unwrap();
+            master.set_features(features).unwrap();
+            master.set_features(features).unwrap();
+            match policy {
+                // The daemon keeps serving the master after the limited request.
+                RateLimitPolicy::Reject => {
+                    master.get_features().unwrap();
+                    drop(master);
+                    assert!(daemon.wait().is_err());
+                }
+                RateLimitPolicy::Disconnect => match daemon.wait() {
+                    Err(Error::HandleRequest(VhostUserError::RateLimited(
+                        MasterReq::SET_FEATURES,
+                    ))) => {}
+                    res => panic!("connection kept after the rate limit: {:?}", res),
+                },
+            }
+        }
+    }
+
+    #[test]
+    fn test_daemon_exit_event() {
+        let path = "/tmp/vhost_user_lib_unit_test_daemon_exit_event";
+        let create = || {
+            let (tx, _rx) = channel();
+            let backend = Arc::new(RwLock::new(DummyBackend {
+                mem: None,
+                events: Mutex::new(tx),
+                queues_per_thread: None,
+                enabled: Vec::new(),
+            }));
+            Daemon::new("test-daemon".to_string(), backend).unwrap()
+        };
+
+        // Signaled from another thread while handling the requests of the master.
+        let mut daemon = create();
+        let listener = Listener::new(path, true).unwrap();
+        let mut master = Master::connect(path, 2).unwrap();
+        daemon.start(listener).unwrap();
+        master.set_owner().unwrap();
+        master.get_features().unwrap();
+        let exit_event = daemon.exit_event().try_clone().unwrap();
+        thread::spawn(move || exit_event.write(1).unwrap())
+            .join()
+            .unwrap();
+        assert!(daemon.wait().is_ok());
+        assert!(master.get_features().is_err());
+
+        // Signaled while waiting for the master.
+        let mut daemon = create();
+        let exit_event = daemon.exit_event().try_clone().unwrap();
+        let signal = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(20));
+            exit_event.write(1).unwrap();
+        });
+        let _ = std::fs::remove_file(path);
+        match daemon.connect(path, 100, Duration::from_millis(5)) {
+            Err(Error::Exited) => {}
+            _ => panic!("waiting for the master not cancelled"),
+        }
+        signal.join().unwrap();
+        let listener = Listener::new(path, true).unwrap();
+        match daemon.start(listener) {
+            Err(Error::Exited) => {}
+            _ => panic!("daemon started after exiting"),
+        }
+    }
+
+    #[test]
+    fn test_vring_stats() {
+        let mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let mut vring = Vring::new(4);
+        vring.set_addresses(
+            GuestAddress(0x1000),
+            GuestAddress(0x2000),
+            GuestAddress(0x3000),
+        );
+        mem.write_obj(0x4000u64, GuestAddress(0x1000)).unwrap();
+        mem.write_obj(0x10u32, GuestAddress(0x1008)).unwrap();
+        mem.write_obj(2u16, GuestAddress(0x2002)).unwrap();
+
+        let chain = vring.pop_avail(&mem).unwrap().unwrap();
+        vring.pop_avail(&mem).unwrap().unwrap();
+        assert_eq!(vring.inflight(), 2);
+        vring.add_used(&mem, chain.head_index(), 0).unwrap();
+        assert_eq!(
+            vring.stats(),
+            QueueStats {
+                kicks: 0,
+                chains: 1,
+                inflight: 1,
+            }
+        );
+        // Both chains are in the region looked up for the first descriptor.
+        assert_eq!(
+            vring.translation_stats(),
+            TranslationStats { hits: 9, misses: 1 }
+        );
+    }
+
+    #[test]
+    fn test_vring_event_idx() {
+        let mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let mut vring = Vring::new(4);
+        vring.set_addresses(
+            GuestAddress(0x1000),
+            GuestAddress(0x2000),
+            GuestAddress(0x3000),
+        );
+        vring.set_event_idx(true);
+        assert!(vring.has_event_idx());
+        mem.write_obj(0x4000u64, GuestAddress(0x1000)).unwrap();
+        mem.write_obj(0x10u32, GuestAddress(0x1008)).unwrap();
+
+        // The driver is asked to kick once it makes the next buffer available.
+        mem.write_obj(0xffffu16, GuestAddress(0x3024)).unwrap();
+        assert!(vring.pop_avail(&mem).unwrap().is_none());
+        assert_eq!(mem.read_obj::<u16>(GuestAddress(0x3024)).unwrap(), 0);
+
+        // The driver wants to be notified once the second buffer has been used.
+        mem.write_obj(1u16, GuestAddress(0x200c)).unwrap();
+        let mut used = Vec::new();
+        for idx in 1..4u16 {
+            mem.write_obj(idx, GuestAddress(0x2002)).unwrap();
+            let chain = vring.pop_avail(&mem).unwrap().unwrap();
+            assert!(vring.pop_avail(&mem).unwrap().is_none());
+            assert_eq!(mem.read_obj::<u16>(GuestAddress(0x3024)).unwrap(), idx);
+            vring.add_used(&mem, chain.head_index(), 0).unwrap();
+            used.push(vring.needs_notification(&mem).unwrap());
+        }
+        // The guest is always notified the first time.
+        assert_eq!(used, vec![true, true, false]);
+
+        vring.set_event_idx(false);
+        assert!(vring.needs_notification(&mem).unwrap());
+    }
+
+    // Write the descriptor at `position` of the packed vring at 0x1000.
+    fn write_packed_desc(mem: &GuestMemoryMmap, position: u64, desc: (u64, u32, u16, u16)) {
+        let addr = 0x1000 + position * 16;
+        mem.write_obj(desc.0, GuestAddress(addr)).unwrap();
+        mem.write_obj(desc.1, GuestAddress(addr + 8)).unwrap();
+        mem.write_obj(desc.2, GuestAddress(addr + 12)).unwrap();
+        mem.write_obj(desc.3, GuestAddress(addr + 14)).unwrap();
+    }
+
+    #[test]
+    fn test_vring_packed() {
+        // Flags of the descriptors made available by the driver with its wrap counter set.
+        const AVAIL: u16 = 0x80;
+        const NEXT_AVAIL: u16 = 0x81;
+        let mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let mut vring = Vring::new(4);
+        vring.set_addresses(
+            GuestAddress(0x1000),
+            GuestAddress(0x2000),
+            GuestAddress(0x3000),
+        );
+        vring.set_packed(true);
+        assert!(vring.is_packed());
+        assert_eq!(vring.next_avail(), 0x8000);
+
+        // A chain of two descriptors, identified by the buffer id of the last one.
+        write_packed_desc(&mem, 0, (0x4000, 0x10, 0, NEXT_AVAIL));
+        write_packed_desc(&mem, 1, (0x5000, 0x20, 2, AVAIL | 0x2));
+        let chain = vring.pop_avail(&mem).unwrap().unwrap();
+        assert_eq!(chain.head_index(), 2);
+        assert_eq!(chain.readable().count(), 1);
+        assert_eq!(chain.writable().count(), 1);
+        assert!(vring.pop_avail(&mem).unwrap().is_none());
+        vring.return_avail(&chain);
+        assert_eq!((vring.next_avail(), vring.inflight()), (0x8000, 0));
+        let chain = vring.pop_avail(&mem).unwrap().unwrap();
+        assert_eq!(vring.next_avail(), 0x8002);
+
+        // An indirect table, then a single descriptor ending the first lap of the vring.
+        mem.write_obj(0x6000u64, GuestAddress(0x7000)).unwrap();
+        mem.write_obj(0x10u32, GuestAddress(0x7008)).unwrap();
+        mem.write_obj(0x6100u64, GuestAddress(0x7010)).unwrap();
+        mem.write_obj(0x10u32, GuestAddress(0x7018)).unwrap();
+        mem.write_obj(0x2u16, GuestAddress(0x701e)).unwrap();
+        write_packed_desc(&mem, 2, (0x7000, 0x20, 0, AVAIL | 0x4));
+        write_packed_desc(&mem, 3, (0x8000, 0x10, 1, AVAIL));
+        assert_eq!(
+            vring.pop_avail(&mem).unwrap().unwrap().descriptors().len(),
+            2
+        );
+        assert_eq!(vring.pop_avail(&mem).unwrap().unwrap().head_index(), 1);
+        assert_eq!((vring.next_avail(), vring.inflight()), (0, 3));
+
+        // The used descriptors are written in order, each taking the place of its chain.
+        vring.add_used(&mem, chain.head_index(), 0x20).unwrap();
+        assert_eq!(mem.read_obj::<u32>(GuestAddress(0x1008)).unwrap(), 0x20);
+        assert_eq!(mem.read_obj::<u16>(GuestAddress(0x100c)).unwrap(), 2);
+        assert_eq!(mem.read_obj::<u16>(GuestAddress(0x100e)).unwrap(), 0x8080);
+        vring.add_used(&mem, 1, 0).unwrap();
+        assert_eq!(mem.read_obj::<u16>(GuestAddress(0x102c)).unwrap(), 1);
+        vring.add_used(&mem, 0, 0).unwrap();
+        assert!(vring.add_used(&mem, 0, 0).is_err());
+        assert_eq!((vring.next_used(), vring.inflight()), (0, 0));
+
+        // The driver flips the meaning of the flags on the second lap.
+        write_packed_desc(&mem, 0, (0x4000, 0x10, 3, 0x8000));
+        assert_eq!(vring.pop_avail(&mem).unwrap().unwrap().head_index(), 3);
+    }
+
+    #[test]
+    fn test_vring_packed_event_idx() {
+        let mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let mut vring = Vring::new(4);
+        vring.set_addresses(
+            GuestAddress(0x1000),
+            GuestAddress(0x2000),
+            GuestAddress(0x3000),
+        );
+        vring.set_packed(true);
+        vring.set_event_idx(true);
+        for id in 0..4u16 {
+            write_packed_desc(&mem, u64::from(id), (0x4000, 0x10, id, 0x80));
+            vring.pop_avail(&mem).unwrap().unwrap();
+        }
+        // The driver is asked to kick once it makes the next descriptor available.
+        assert!(vring.pop_avail(&mem).unwrap().is_none());
+        assert_eq!(mem.read_obj::<u16>(GuestAddress(0x3000)).unwrap(), 0);
+        assert_eq!(mem.read_obj::<u16>(GuestAddress(0x3002)).unwrap(), 2);
+
+        // The driver disables the notifications.
+        mem.write_obj(1u16, GuestAddress(0x2002)).unwrap();
+        vring.add_used(&mem, 0, 0).unwrap();
+        assert!(!vring.needs_notification(&mem).unwrap());
+
+        // The driver wants to be notified once the descriptor at position 1 has been used.
+        mem.write_obj(0x8001u16, GuestAddress(0x2000)).unwrap();
+        mem.write_obj(2u16, GuestAddress(0x2002)).unwrap();
+        let mut used = Vec::new();
+        for id in 1..3u16 {
+            vring.add_used(&mem, id, 0).unwrap();
+            used.push(vring.needs_notification(&mem).unwrap());
+        }
+        assert_eq!(used, vec![true, false]);
+
+        // The event is checked across the wrapping of the vring.
+        mem.write_obj(0x8003u16, GuestAddress(0x2000)).unwrap();
+        vring.add_used(&mem, 3, 0).unwrap();
+        assert_eq!(vring.next_used(), 0);
+        assert!(vring.needs_notification(&mem).unwrap());
+    }
+
+    #[test]
+    fn test_used_ring_writer() {
+        let mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let mut vring = Vring::new(4);
+        vring.set_addresses(
+            GuestAddress(0x1000),
+            GuestAddress(0x2000),
+            GuestAddress(0x3000),
+        );
+        let call = EventFd::new(0).unwrap();
+        vring.set_call(Some(call.try_clone().unwrap()));
+        for index in 0..3u16 {
+            mem.write_obj(index, GuestAddress(0x2004 + u64::from(index) * 2))
+                .unwrap();
+        }
+        mem.write_obj(3u16, GuestAddress(0x2002)).unwrap();
+
+        // The used index is updated once per batch, and when the writer finishes.
+        let mut used = vring.used_writer(&mem);
+        used.set_batch_size(2);
+        let mut indexes = Vec::new();
+        while let Some(chain) = used.pop_avail().unwrap() {
+            used.add_used(chain.head_index(), 0).unwrap();
+            indexes.push(mem.read_obj::<u16>(GuestAddress(0x3002)).unwrap());
+        }
+        assert_eq!(indexes, vec![0, 2, 2]);
+        used.finish().unwrap();
+        assert_eq!(mem.read_obj::<u16>(GuestAddress(0x3002)).unwrap(), 3);
+        assert_eq!(mem.read_obj::<u32>(GuestAddress(0x3014)).unwrap(), 2);
+        assert_eq!(call.read().unwrap(), 1);
+
+        // The used descriptors of packed vrings are published by their flags.
+        vring.set_packed(true);
+        write_packed_desc(&mem, 0, (0x4000, 0x10, 0, 0x80));
+        let mut used = vring.used_writer(&mem);
+        let chain = used.pop_avail().unwrap().unwrap();
+        used.add_used(chain.head_index(), 0x10).unwrap();
+        assert_eq!(mem.read_obj::<u16>(GuestAddress(0x100e)).unwrap(), 0x80);
+        drop(used);
+        assert_eq!(mem.read_obj::<u16>(GuestAddress(0x100e)).unwrap(), 0x8080);
+        assert_eq!(vring.stats().chains, 4);
+    }
+
+    #[test]
+    fn test_vring_kicks_enabled() {
+        let mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let mut vring = Vring::new(4);
+        vring.set_addresses(
+            GuestAddress(0x1000),
+            GuestAddress(0x2000),
+            GuestAddress(0x3000),
+        );
+        assert!(!vring.has_avail(&mem).unwrap());
+        mem.write_obj(1u16, GuestAddress(0x2002)).unwrap();
+        assert!(vring.has_avail(&mem).unwrap());
+        vring.set_kicks_enabled(&mem, false).unwrap();
+        assert_eq!(mem.read_obj::<u16>(GuestAddress(0x3000)).unwrap(), 1);
+        vring.set_kicks_enabled(&mem, true).unwrap();
+        assert_eq!(mem.read_obj::<u16>(GuestAddress(0x3000)).unwrap(), 0);
+
+        // With VIRTIO_RING_F_EVENT_IDX, the avail event is left behind the taken buffers.
+        vring.set_event_idx(true);
+        vring.set_kicks_enabled(&mem, false).unwrap();
+        assert_eq!(mem.read_obj::<u16>(GuestAddress(0x3024)).unwrap(), 0xffff);
+        vring.pop_avail(&mem).unwrap().unwrap();
+        assert!(vring.pop_avail(&mem).unwrap().is_none());
+        assert_eq!(mem.read_obj::<u16>(GuestAddress(0x3024)).unwrap(), 0xffff);
+        vring.set_kicks_enabled(&mem, true).unwrap();
+        assert_eq!(mem.read_obj::<u16>(GuestAddress(0x3024)).unwrap(), 1);
+
+        // Packed vrings disable the events of their device event suppression structure.
+        vring.set_packed(true);
+        vring.set_kicks_enabled(&mem, false).unwrap();
+        assert_eq!(mem.read_obj::<u16>(GuestAddress(0x3002)).unwrap(), 1);
+        vring.set_kicks_enabled(&mem, true).unwrap();
+        assert_eq!(mem.read_obj::<u16>(GuestAddress(0x3000)).unwrap(), 0x8000);
+        assert_eq!(mem.read_obj::<u16>(GuestAddress(0x3002)).unwrap(), 2);
+    }
+
+    struct PollingBackend {
+        mem: Option<GuestMemoryMmap>,
+    }
+
+    impl VhostUserBackend for PollingBackend {
+        fn num_queues(&self) -> usize {
+            1
+        }
+
+        fn max_queue_size(&self) -> usize {
+            16
+        }
+
+        fn features(&self) -> u64 {
+            VirtioFeatures::VERSION_1.bits()
+        }
+
+        fn protocol_features(&self) -> VhostUserProtocolFeatures {
+            VhostUserProtocolFeatures::empty()
+        }
+
+        fn update_memory(&mut self, mem: GuestMemoryMmap) -> io::Result<()> {
+            self.mem = Some(mem);
+            Ok(())
+        }
+
+        fn busy_poll_duration(&self) -> Option<Duration> {
+            Some(Duration::from_millis(500))
+        }
+
+        fn process_queue(
+            &self,
+            _queue_index: u16,
+            vring: &mut Vring,
+            _thread_id: usize,
+        ) -> io::Result<()> {
+            let mem = self.mem.as_ref().unwrap();
+            let mut used = vring.used_writer(mem);
+            while let Some(chain) = used.pop_avail()? {
+                used.add_used(chain.head_index(), 0)?;
+            }
+            used.finish()
+        }
+    }
+
+    #[test]
+    fn test_daemon_busy_poll() {
+        let path = "/tmp/vhost_user_lib_unit_test_daemon_busy_poll";
+        let backend = Arc::new(RwLock::new(PollingBackend { mem: None }));
+        let mut daemon = Daemon::new("test-daemon".to_string(), backend).unwrap();
+
+        let file = TempFile::new().unwrap().into_file();
+        file.set_len(0x10000).unwrap();
+        let mem = GuestMemoryMmap::from_ranges_with_files(&[(
+            GuestAddress(0),
+            0x10000,
+            Some(FileOffset::new(file.try_clone().unwrap(), 0)),
+        )])
+        .unwrap();
+
+        let listener = Listener::new(path, true).unwrap();
+        let master_thread = thread::spawn(move || {
+            let mut master = Master::connect(path, 1).unwrap();
+            master.set_owner().unwrap();
+            let features = master.get_features().unwrap();
+            master.set_features(features).unwrap();
+            let region = VhostUserMemoryRegionInfo {
+                guest_phys_addr: 0,
+                memory_size: 0x10000,
+                userspace_addr: 0x7f00_0000_0000,
+                mmap_offset: 0,
+                mmap_handle: file.as_raw_fd(),
+            };
+            master.set_mem_table(&[region]).unwrap();
+            master.set_vring_num(0, 16).unwrap();
+            let config = VringConfigData {
+                queue_max_size: 16,
+                queue_size: 16,
+                flags: 0,
+                desc_table_addr: 0x7f00_0000_1000,
+                used_ring_addr: 0x7f00_0000_3000,
+                avail_ring_addr: 0x7f00_0000_2000,
+                log_addr: None,
+            };
+            master.set_vring_addr(0, &config).unwrap();
+            master.set_vring_base(0, 0).unwrap();
+            let call = EventFd::new(0).unwrap();
+            master.set_vring_call(0, &call).unwrap();
+            let kick = EventFd::new(0).unwrap();
+            master.set_vring_kick(0, &kick).unwrap();
+
+            // Make the buffers available one by one, only kicking for the first one.
+            mem.write_obj(0x8000u64, GuestAddress(0x1000)).unwrap();
+            mem.write_obj(0x10u32, GuestAddress(0x1008)).unwrap();
+            for idx in 1..4u16 {
+                mem.write_obj(idx, GuestAddress(0x2002)).unwrap();
+                if idx == 1 {
+                    kick.write(1).unwrap();
+                }
+                call.read().unwrap();
+                assert_eq!(mem.read_obj::<u16>(GuestAddress(0x3002)).unwrap(), idx);
+                // The driver is asked not to kick the vring while it's polled.
+                assert_eq!(mem.read_obj::<u16>(GuestAddress(0x3000)).unwrap(), 1);
+            }
+
+            // Kicks are enabled again once the vring has stayed empty.
+            thread::sleep(Duration::from_millis(1000));
+            assert_eq!(mem.read_obj::<u16>(GuestAddress(0x3000)).unwrap(), 0);
+            mem.write_obj(4u16, GuestAddress(0x2002)).unwrap();
+            kick.write(1).unwrap();
+            call.read().unwrap();
+            assert_eq!(mem.read_obj::<u16>(GuestAddress(0x3002)).unwrap(), 4);
+        });
+
+        daemon.start(listener).unwrap();
+        master_thread.join().unwrap();
+        assert!(daemon.wait().is_err());
+    }
+}
@@ -0,0 +1,769 @@
+// Copyright (C) 2020 Alibaba Cloud Computing. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! State of virtqueues managed by the daemon.
+
+use std::io;
+use std::sync::atomic::{fence, Ordering};
+use std::sync::Arc;
+
+use vm_memory::{Address, ByteValued, Bytes, GuestAddress, GuestMemoryMmap};
+use vmm_sys_util::eventfd::EventFd;
+
+use super::chain::DescriptorChain;
+use super::{Metrics, TranslationCache, TranslationStats};
+use crate::vhost_user::MasterReqSender;
+
+// Sizes of the elements of the avail and used rings.
+const VIRTQ_AVAIL_ELEMENT_SIZE: u64 = 2;
+const VIRTQ_USED_ELEMENT_SIZE: u64 = 8;
+// Offset of the ring elements, after the flags and idx fields.
+const VIRTQ_RING_OFFSET: u64 = 4;
+// Flag of the used ring asking the driver not to kick the vring.
+const VIRTQ_USED_F_NO_NOTIFY: u16 = 0x1;
+
+// Flags of packed virtqueue descriptors, telling whether the descriptors are available or used.
+const VIRTQ_DESC_F_AVAIL: u16 = 1 << 7;
+const VIRTQ_DESC_F_USED: u16 = 1 << 15;
+// Size of the descriptors of packed virtqueues, and offset of their flags.
+const VIRTQ_PACKED_DESC_SIZE: u64 = 16;
+const VIRTQ_PACKED_DESC_FLAGS_OFFSET: u64 = 14;
+// Flags of the event suppression structures of packed virtqueues.
+const RING_EVENT_FLAGS_DISABLE: u16 = 0x1;
+const RING_EVENT_FLAGS_DESC: u16 = 0x2;
+// Wrap counter bit of the ring positions of packed virtqueues.
+const PACKED_WRAP_COUNTER: u16 = 1 << 15;
+// Largest size of packed virtqueues, for the positions to fit in 15 bits.
+const PACKED_MAX_SIZE: u16 = 1 << 15;
+
+// Number of descriptor chains returned by a `UsedRingWriter` before publishing them.
+const DEFAULT_USED_BATCH_SIZE: usize = 32;
+
+/// Statistics of a vring, reported when the daemon shuts down.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct QueueStats {
+    /// Number of kicks of the vring handled by its worker thread.
+    pub kicks: u64,
+    /// Number of descriptor chains returned to the driver.
+    pub chains: u64,
+    /// Number of descriptor chains taken by the backend and not returned to the driver yet.
+    pub inflight: u16,
+}
+
+/// State of a virtqueue configured by the master.
+///
+/// All ring addresses have been translated into guest physical addresses. Vrings use the split
+/// virtqueue layout, or the packed one once VIRTIO_F_RING_PACKED has been negotiated: the driver
+/// and device areas then hold the event suppression structures of the driver and the device.
+///
+/// A vring is updated by the protocol handler, behind the `RwLock` shared with the worker
+/// threads, and owns the eventfds sent by the master: the kick eventfd is released when the vring
+/// is stopped by GET_VRING_BASE or a new SET_VRING_KICK, while the call and err eventfds are kept
+/// until they are replaced or the session is reset.
+pub struct Vring {
+    max_size: u16,
+    size: u16,
+    desc_table: GuestAddress,
+    avail_ring: GuestAddress,
+    used_ring: GuestAddress,
+    next_avail: u16,
+    next_used: u16,
+    kick: Option<EventFd>,
+    call: Option<EventFd>,
+    err: Option<EventFd>,
+    enabled: bool,
+    started: bool,
+    // whether VIRTIO_RING_F_EVENT_IDX has been negotiated
+    event_idx: bool,
+    // whether VIRTIO_F_RING_PACKED has been negotiated
+    packed: bool,
+    // whether the driver has been asked not to kick the vring
+    kicks_suppressed: bool,
+    // number of descriptors taken by the chains of a packed ring not returned yet, by buffer id
+    packed_chains: Vec<u16>,
+    // offsets and values of the flags of the used descriptors of a packed ring to publish
+    staged_flags: Vec<(u64, u16)>,
+    // next_used when the guest was last notified, if it has been notified since the vring started
+    signalled_used: Option<u16>,
+    // index of the vring and where to send its calls and errors without eventfds, when
+    // INBAND_NOTIFICATIONS has been negotiated
+    inband: Option<(u32, MasterReqSender)>,
+    // index of the vring and where to count the descriptor chains returned to the driver
+    metrics: Option<(u16, Arc<dyn Metrics>)>,
+    // regions holding the descriptors and buffers, only used by the worker thread of the vring
+    translations: TranslationCache,
+    kicks: u64,
+    chains: u64,
+}
+
+impl Vring {
+    /// Create a new vring with the maximum size supported by the device.
+    pub fn new(max_size: u16) -> Self {
+        Vring {
+            max_size,
+            size: max_size,
+            desc_table: GuestAddress(0),
+            avail_ring: GuestAddress(0),
+            used_ring: GuestAddress(0),
+            next_avail: 0,
+            next_used: 0,
+            kick: None,
+            call: None,
+            err: None,
+            enabled: false,
+            started: false,
+            event_idx: false,
+            packed: false,
+            kicks_suppressed: false,
+            packed_chains: Vec::new(),
+            staged_flags: Vec::new(),
+            signalled_used: None,
+            inband: None,
+            metrics: None,
+            translations: TranslationCache::new(),
+            kicks: 0,
+            chains: 0,
+        }
+    }
+
+    // Create a new vring, counting the descriptor chains returned to the driver into `metrics`.
+    pub(super) fn with_metrics(max_size: u16, index: u16, metrics: Arc<dyn Metrics>) -> Self {
+        let mut vring = Self::new(max_size);
+        vring.metrics = Some((index, metrics));
+        vring
+    }
+
+    /// Get the maximum size supported by the device.
+    pub fn max_size(&self) -> u16 {
+        self.max_size
+    }
+
+    /// Get the size of the vring set by the master.
+    pub fn size(&self) -> u16 {
+        self.size
+    }
+
+    /// Get the guest physical address of the descriptor table.
+    pub fn desc_table(&self) -> GuestAddress {
+        self.desc_table
+    }
+
+    /// Get the guest physical address of the available ring.
+    pub fn avail_ring(&self) -> GuestAddress {
+        self.avail_ring
+    }
+
+    /// Get the guest physical address of the used ring.
+    pub fn used_ring(&self) -> GuestAddress {
+        self.used_ring
+    }
+
+    /// Get the index of the next available descriptor to process.
+    ///
+    /// For packed vrings, the index is the position of the descriptor in the descriptor table,
+    /// with the wrap counter of the driver in bit 15, as in the vring base of the vhost-user
+    /// protocol.
+    pub fn next_avail(&self) -> u16 {
+        self.next_avail
+    }
+
+    /// Set the index of the next available descriptor to process.
+    pub fn set_next_avail(&mut self, next_avail: u16) {
+        self.next_avail = next_avail;
+    }
+
+    /// Get the index of the next element of the used ring to fill.
+    ///
+    /// For packed vrings, the index is the position of the descriptor in the descriptor table,
+    /// with the wrap counter of the device in bit 15.
+    pub fn next_used(&self) -> u16 {
+        self.next_used
+    }
+
+    /// Set the index of the next element of the used ring to fill.
+    pub fn set_next_used(&mut self, next_used: u16) {
+        self.next_used = next_used;
+        self.signalled_used = None;
+    }
+
+    /// Check whether VIRTIO_RING_F_EVENT_IDX has been negotiated, so the driver and the device
+    /// may suppress redundant notifications.
+    pub fn has_event_idx(&self) -> bool {
+        self.event_idx
+    }
+
+    /// Check whether VIRTIO_F_RING_PACKED has been negotiated, so the vring uses the packed
+    /// virtqueue layout.
+    pub fn is_packed(&self) -> bool {
+        self.packed
+    }
+
+    /// Take the next descriptor chain made available by the driver, if any.
+    ///
+    /// With VIRTIO_RING_F_EVENT_IDX, the driver is asked to only kick the vring once it makes new
+    /// buffers available after the vring has been found empty.
+    ///
+    /// Indirect descriptor tables are followed, so backends may offer the
+    /// VIRTIO_RING_F_INDIRECT_DESC feature.
+    pub fn pop_avail(&mut self, mem: &GuestMemoryMmap) -> io::Result<Option<DescriptorChain>> {
+        if self.size == 0 {
+            return Ok(None);
+        } else if self.packed {
+            return self.pop_avail_packed(mem);
+        }
+        let mut avail_idx: u16 = Self::read_obj(mem, self.avail_ring, 2)?;
+        if avail_idx == self.next_avail && self.event_idx && !self.kicks_suppressed {
+            let offset = VIRTQ_RING_OFFSET + u64::from(self.size) * VIRTQ_USED_ELEMENT_SIZE;
+            Self::write_obj(mem, self.used_ring, offset, self.next_avail)?;
+            // Check again for buffers made available before the driver saw the avail event.
+            fence(Ordering::SeqCst);
+            avail_idx = Self::read_obj(mem, self.avail_ring, 2)?;
+        }
+        // Read the ring elements only after the driver has published them.
+        fence(Ordering::Acquire);
+        if avail_idx == self.next_avail {
+            return Ok(None);
+        } else if avail_idx.wrapping_sub(self.next_avail) > self.size {
+            return Err(io::Error::from_raw_os_error(libc::EINVAL));
+        }
+
+        let slot = u64::from(self.next_avail % self.size);
+        let offset = VIRTQ_RING_OFFSET + slot * VIRTQ_AVAIL_ELEMENT_SIZE;
+        let head_index: u16 = Self::read_obj(mem, self.avail_ring, offset)?;
+        let chain = DescriptorChain::read(
+            mem,
+            &mut self.translations,
+            self.desc_table,
+            self.size,
+            head_index,
+        )?;
+        self.next_avail = self.next_avail.wrapping_add(1);
+        Ok(Some(chain))
+    }
+
+    /// Check whether the driver has made buffers available which haven't been taken yet.
+    pub fn has_avail(&self, mem: &GuestMemoryMmap) -> io::Result<bool> {
+        if self.size == 0 {
+            return Ok(false);
+        } else if self.packed {
+            return self.packed_avail(mem);
+        }
+        let avail_idx: u16 = Self::read_obj(mem, self.avail_ring, 2)?;
+        Ok(avail_idx != self.next_avail)
+    }
+
+    /// Ask the driver to stop kicking the vring, or to kick it again, for instance while the
+    /// vring is polled for new buffers.
+    ///
+    /// The driver doesn't kick the vring for buffers made available before it sees kicks are
+    /// enabled again, so `has_avail()` should be checked afterwards.
+    pub fn set_kicks_enabled(&mut self, mem: &GuestMemoryMmap, enabled: bool) -> io::Result<()> {
+        if self.size == 0 {
+            return Ok(());
+        }
+        self.kicks_suppressed = !enabled;
+        if self.packed {
+            let flags = if !enabled {
+                RING_EVENT_FLAGS_DISABLE
+            } else if self.event_idx {
+                Self::write_obj(mem, self.used_ring, 0, self.next_avail)?;
+                RING_EVENT_FLAGS_DESC
+            } else {
+                0
+            };
+            Self::write_obj(mem, self.used_ring, 2, flags)?;
+        } else if self.event_idx {
+            // The driver ignores the used ring flags, but only kicks the vring when the avail
+            // index goes past the avail event, which is left behind while kicks are disabled.
+            let avail_event = if enabled {
+                self.next_avail
+            } else {
+                self.next_avail.wrapping_sub(1)
+            };
+            let offset = VIRTQ_RING_OFFSET + u64::from(self.size) * VIRTQ_USED_ELEMENT_SIZE;
+            Self::write_obj(mem, self.used_ring, offset, avail_event)?;
+        } else {
+            let flags = if enabled { 0 } else { VIRTQ_USED_F_NO_NOTIFY };
+            Self::write_obj(mem, self.used_ring, 0, flags)?;
+        }
+        // Make the flags visible before checking for available buffers.
+        fence(Ordering::SeqCst);
+        Ok(())
+    }
+
+    /// Give back the last descriptor chain taken by `pop_avail()`, to take it again later.
+    pub fn return_avail(&mut self, chain: &DescriptorChain) {
+        if !self.packed {
+            self.next_avail = self.next_avail.wrapping_sub(1);
+            return;
+        }
+        if let Some(count) = self.packed_chains.get_mut(chain.head_index() as usize) {
+            let position = self.next_avail & !PACKED_WRAP_COUNTER;
+            let mut wrap = self.next_avail & PACKED_WRAP_COUNTER;
+            let position = if position >= *count {
+                position - *count
+            } else {
+                wrap ^= PACKED_WRAP_COUNTER;
+                position + self.size - *count
+            };
+            self.next_avail = position | wrap;
+            *count = 0;
+        }
+    }
+
+    /// Return a descriptor chain to the driver, with `len` bytes written by the device.
+    ///
+    /// Backends should call `signal_used_queue()` once done with the available buffers, and may
+    /// return many chains at once with a `UsedRingWriter`.
+    pub fn add_used(&mut self, mem: &GuestMemoryMmap, head_index: u16, len: u32) -> io::Result<()> {
+        self.stage_used(mem, head_index, len)?;
+        self.publish_used(mem)
+    }
+
+    /// Get a writer returning descriptor chains to the driver in batches.
+    pub fn used_writer<'a>(&'a mut self, mem: &'a GuestMemoryMmap) -> UsedRingWriter<'a> {
+        UsedRingWriter {
+            vring: self,
+            mem,
+            batch_size: DEFAULT_USED_BATCH_SIZE,
+            pending: 0,
+            added: false,
+        }
+    }
+
+    /// Get the number of descriptor chains taken by `pop_avail()` and not returned by
+    /// `add_used()` yet.
+    pub fn inflight(&self) -> u16 {
+        if self.packed {
+            self.packed_chains
+                .iter()
+                .filter(|&&count| count != 0)
+                .count() as u16
+        } else {
+            self.next_avail.wrapping_sub(self.next_used)
+        }
+    }
+
+    /// Get the statistics of the vring.
+    pub fn stats(&self) -> QueueStats {
+        QueueStats {
+            kicks: self.kicks,
+            chains: self.chains,
+            inflight: self.inflight(),
+        }
+    }
+
+    /// Get the statistics of the cache translating the addresses of the descriptor chains taken
+    /// by `pop_avail()`.
+    pub fn translation_stats(&self) -> TranslationStats {
+        self.translations.stats()
+    }
+
+    /// Drop the cached translations of guest addresses, which must be done whenever the guest
+    /// memory changes. The daemon does it for the vrings it manages.
+    pub fn invalidate_translations(&mut self) {
+        self.translations.invalidate();
+    }
+
+    /// Check whether the vring has been enabled by the master.
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    /// Check whether the vring has been started by receiving a kick file descriptor.
+    pub fn is_started(&self) -> bool {
+        self.started
+    }
+
+    /// Get the eventfd the guest kicks the vring with.
+    pub fn kick(&self) -> Option<&EventFd> {
+        self.kick.as_ref()
+    }
+
+    /// Get the eventfd to interrupt the guest with.
+    pub fn call(&self) -> Option<&EventFd> {
+        self.call.as_ref()
+    }
+
+    /// Get the eventfd to notify the master of errors with.
+    pub fn err(&self) -> Option<&EventFd> {
+        self.err.as_ref()
+    }
+
+    /// Check whether the guest needs to be notified of the buffers put into the used ring since
+    /// the last notification.
+    ///
+    /// With VIRTIO_RING_F_EVENT_IDX, the guest is only notified once the used ring goes past the
+    /// used event index the driver has set in the available ring. Otherwise the guest is always
+    /// notified, unless the driver of a packed vring has disabled the notifications.
+    pub fn needs_notification(&mut self, mem: &GuestMemoryMmap) -> io::Result<bool> {
+        if self.packed {
+            return self.needs_notification_packed(mem);
+        } else if !self.event_idx {
+            return Ok(true);
+        }
+        // Read the used event only after the used index has been updated.
+        fence(Ordering::SeqCst);
+        let offset = VIRTQ_RING_OFFSET + u64::from(self.size) * VIRTQ_AVAIL_ELEMENT_SIZE;
+        let used_event: u16 = Self::read_obj(mem, self.avail_ring, offset)?;
+        let old = self.signalled_used.replace(self.next_used);
+        Ok(match old {
+            // Notify if used_event is in [old, next_used), accounting for wrapping.
+            Some(old) => {
+                self.next_used.wrapping_sub(used_event).wrapping_sub(1)
+                    < self.next_used.wrapping_sub(old)
+            }
+            None => true,
+        })
+    }
+
+    /// Notify the guest that buffers have been put into the used ring, unless the driver has
+    /// suppressed the notification with VIRTIO_RING_F_EVENT_IDX.
+    pub fn notify_used_queue(&mut self, mem: &GuestMemoryMmap) -> io::Result<()> {
+        if self.needs_notification(mem)? {
+            self.signal_used_queue()
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Notify the guest that buffers have been put into the used ring.
+    ///
+    /// Without call eventfd, the notification is sent in-band to the master if the
+    /// INBAND_NOTIFICATIONS protocol feature has been negotiated.
+    pub fn signal_used_queue(&self) -> io::Result<()> {
+        match (&self.call, &self.inband) {
+            (Some(call), _) => call.write(1),
+            (None, Some((index, sender))) => sender
+                .clone()
+                .send_vring_call(*index)
+                .map(|_| ())
+                .map_err(io::Error::other),
+            (None, None) => Ok(()),
+        }
+    }
+
+    /// Notify the master that an error happened on the vring.
+    ///
+    /// Without err eventfd, the notification is sent in-band to the master if the
+    /// INBAND_NOTIFICATIONS protocol feature has been negotiated.
+    pub fn signal_error(&self) -> io::Result<()> {
+        match (&self.err, &self.inband) {
+            (Some(err), _) => err.write(1),
+            (None, Some((index, sender))) => sender
+                .clone()
+                .send_vring_err(*index)
+                .map(|_| ())
+                .map_err(io::Error::other),
+            (None, None) => Ok(()),
+        }
+    }
+
+    fn pop_avail_packed(&mut self, mem: &GuestMemoryMmap) -> io::Result<Option<DescriptorChain>> {
+        if self.size > PACKED_MAX_SIZE {
+            return Err(io::Error::from_raw_os_error(libc::EINVAL));
+        }
+        let mut available = self.packed_avail(mem)?;
+        if !available && self.event_idx && !self.kicks_suppressed {
+            // Ask for a kick once the driver makes the next descriptor available.
+            Self::write_obj(mem, self.used_ring, 0, self.next_avail)?;
+            Self::write_obj(mem, self.used_ring, 2, RING_EVENT_FLAGS_DESC)?;
+            // Check again for descriptors made available before the driver saw the event.
+            fence(Ordering::SeqCst);
+            available = self.packed_avail(mem)?;
+        }
+        if !available {
+            return Ok(None);
+        }
+        // Read the chain only after the driver has published it.
+        fence(Ordering::Acquire);
+
+        let position = self.next_avail & !PACKED_WRAP_COUNTER;
+        let (chain, count) = DescriptorChain::read_packed(
+            mem,
+            &mut self.translations,
+            self.desc_table,
+            self.size,
+            position,
+        )?;
+        let id = chain.head_index() as usize;
+        if id >= self.size as usize {
+            return Err(io::Error::from_raw_os_error(libc::EINVAL));
+        }
+        self.packed_chains.resize(self.size as usize, 0);
+        if self.packed_chains[id] != 0 {
+            // The buffer id is already used by a chain in flight.
+            return Err(io::Error::from_raw_os_error(libc::EINVAL));
+        }
+        self.packed_chains[id] = count;
+        self.next_avail = self.advance_packed(self.next_avail, count);
+        Ok(Some(chain))
+    }
+
+    // Check whether the descriptor at next_avail has been made available by the driver.
+    fn packed_avail(&self, mem: &GuestMemoryMmap) -> io::Result<bool> {
+        let position = u64::from(self.next_avail & !PACKED_WRAP_COUNTER);
+        let offset = position * VIRTQ_PACKED_DESC_SIZE + VIRTQ_PACKED_DESC_FLAGS_OFFSET;
+        let flags: u16 = Self::read_obj(mem, self.desc_table, offset)?;
+        let wrap = self.next_avail & PACKED_WRAP_COUNTER != 0;
+        Ok((flags & VIRTQ_DESC_F_AVAIL != 0) == wrap && (flags & VIRTQ_DESC_F_USED != 0) != wrap)
+    }
+
+    // Write a used element, without making it visible to the driver until `publish_used()`.
+    fn stage_used(&mut self, mem: &GuestMemoryMmap, head_index: u16, len: u32) -> io::Result<()> {
+        if head_index >= self.size {
+            return Err(io::Error::from_raw_os_error(libc::EINVAL));
+        }
+        if self.packed {
+            self.stage_used_packed(mem, head_index, len)?;
+        } else {
+            let slot = u64::from(self.next_used % self.size);
+            let offset = VIRTQ_RING_OFFSET + slot * VIRTQ_USED_ELEMENT_SIZE;
+            Self::write_obj(mem, self.used_ring, offset, u32::from(head_index))?;
+            Self::write_obj(mem, self.used_ring, offset + 4, len)?;
+            self.next_used = self.next_used.wrapping_add(1);
+        }
+        self.chains += 1;
+        if let Some((index, ref metrics)) = self.metrics {
+            metrics.chain_handled(index);
+        }
+        Ok(())
+    }
+
+    fn stage_used_packed(&mut self, mem: &GuestMemoryMmap, id: u16, len: u32) -> io::Result<()> {
+        let count = match self.packed_chains.get_mut(id as usize) {
+            Some(count) if *count != 0 => std::mem::replace(count, 0),
+            _ => return Err(io::Error::from_raw_os_error(libc::EINVAL)),
+        };
+        let offset = u64::from(self.next_used & !PACKED_WRAP_COUNTER) * VIRTQ_PACKED_DESC_SIZE;
+        Self::write_obj(mem, self.desc_table, offset + 8, len)?;
+        Self::write_obj(mem, self.desc_table, offset + 12, id)?;
+        let flags = if self.next_used & PACKED_WRAP_COUNTER != 0 {
+            VIRTQ_DESC_F_AVAIL | VIRTQ_DESC_F_USED
+        } else {
+            0
+        };
+        self.staged_flags
+            .push((offset + VIRTQ_PACKED_DESC_FLAGS_OFFSET, flags));
+        self.next_used = self.advance_packed(self.next_used, count);
+        Ok(())
+    }
+
+    // Make the used elements written by `stage_used()` visible to the driver.
+    fn publish_used(&mut self, mem: &GuestMemoryMmap) -> io::Result<()> {
+        // Publish the used elements before updating the index, or the flags of the descriptors
+        // of packed vrings.
+        fence(Ordering::Release);
+        if !self.packed {
+            return Self::write_obj(mem, self.used_ring, 2, self.next_used);
+        }
+        let desc_table = self.desc_table;
+        for (offset, flags) in self.staged_flags.drain(..) {
+            Self::write_obj(mem, desc_table, offset, flags)?;
+        }
+        Ok(())
+    }
+
+    fn needs_notification_packed(&mut self, mem: &GuestMemoryMmap) -> io::Result<bool> {
+        // Read the driver event suppression structure only after the used descriptors have been
+        // published.
+        fence(Ordering::SeqCst);
+        let off_wrap: u16 = Self::read_obj(mem, self.avail_ring, 0)?;
+        let flags: u16 = Self::read_obj(mem, self.avail_ring, 2)?;
+        let new = self.next_used;
+        let old = self.signalled_used.replace(new);
+        if flags == RING_EVENT_FLAGS_DISABLE {
+            return Ok(false);
+        } else if flags != RING_EVENT_FLAGS_DESC || !self.event_idx {
+            return Ok(true);
+        }
+        let old = match old {
+            Some(old) if old == new => return Ok(false),
+            Some(old) => old,
+            None => return Ok(true),
+        };
+        // Notify if the event descriptor is in [old, new), accounting for wrapping.
+        let new_position = new & !PACKED_WRAP_COUNTER;
+        let mut old_position = old & !PACKED_WRAP_COUNTER;
+        if new_position <= old_position {
+            old_position = old_position.wrapping_sub(self.size);
+        }
+        let mut event = off_wrap & !PACKED_WRAP_COUNTER;
+        if off_wrap & PACKED_WRAP_COUNTER != new & PACKED_WRAP_COUNTER {
+            event = event.wrapping_sub(self.size);
+        }
+        Ok(new_position.wrapping_sub(event).wrapping_sub(1)
+            < new_position.wrapping_sub(old_position))
+    }
+
+    // Move a position of a packed vring by `count` descriptors, toggling its wrap counter when
+    // going past the end of the vring.
+    fn advance_packed(&self, position: u16, count: u16) -> u16 {
+        let wrap = position & PACKED_WRAP_COUNTER;
+        let position = (position & !PACKED_WRAP_COUNTER) + count;
+        if position >= self.size {
+            (position - self.size) | (wrap ^ PACKED_WRAP_COUNTER)
+        } else {
+            position | wrap
+        }
+    }
+
+    fn read_obj<T: ByteValued>(
+        mem: &GuestMemoryMmap,
+        base: GuestAddress,
+        offset: u64,
+    ) -> io::Result<T> {
+        let addr = base
+            .checked_add(offset)
+            .ok_or_else(|| io::Error::from_raw_os_error(libc::EFAULT))?;
+        mem.read_obj(addr)
+            .map_err(|_| io::Error::from_raw_os_error(libc::EFAULT))
+    }
+
+    fn write_obj<T: ByteValued>(
+        mem: &GuestMemoryMmap,
+        base: GuestAddress,
+        offset: u64,
+        val: T,
+    ) -> io::Result<()> {
+        let addr = base
+            .checked_add(offset)
+            .ok_or_else(|| io::Error::from_raw_os_error(libc::EFAULT))?;
+        mem.write_obj(val, addr)
+            .map_err(|_| io::Error::from_raw_os_error(libc::EFAULT))
+    }
+
+    pub(super) fn kicked(&mut self) {
+        self.kicks += 1;
+    }
+
+    pub(super) fn set_size(&mut self, size: u16) {
+        self.size = size;
+    }
+
+    pub(super) fn set_addresses(
+        &mut self,
+        desc_table: GuestAddress,
+        avail_ring: GuestAddress,
+        used_ring: GuestAddress,
+    ) {
+        self.desc_table = desc_table;
+        self.avail_ring = avail_ring;
+        self.used_ring = used_ring;
+        self.signalled_used = None;
+        self.kicks_suppressed = false;
+        self.packed_chains.clear();
+        self.staged_flags.clear();
+    }
+
+    pub(super) fn set_inband(&mut self, index: u32, sender: Option<MasterReqSender>) {
+        self.inband = sender.map(|sender| (index, sender));
+    }
+
+    pub(super) fn set_event_idx(&mut self, event_idx: bool) {
+        self.event_idx = event_idx;
+    }
+
+    pub(super) fn set_packed(&mut self, packed: bool) {
+        if packed != self.packed {
+            self.packed = packed;
+            self.packed_chains.clear();
+            self.staged_flags.clear();
+            // The wrap counters of packed vrings start at 1.
+            let base = if packed { PACKED_WRAP_COUNTER } else { 0 };
+            self.next_avail = base;
+            self.next_used = base;
+            self.signalled_used = None;
+        }
+    }
+
+    pub(super) fn set_enabled(&mut self, enabled: bool) {
+        self.enabled = enabled;
+    }
+
+    pub(super) fn set_started(&mut self, started: bool) {
+        self.started = started;
+        if !started {
+            self.kicks_suppressed = false;
+        }
+    }
+
+    pub(super) fn set_kick(&mut self, kick: Option<EventFd>) -> Option<EventFd> {
+        std::mem::replace(&mut self.kick, kick)
+    }
+
+    pub(super) fn set_call(&mut self, call: Option<EventFd>) {
+        self.call = call;
+    }
+
+    pub(super) fn set_err(&mut self, err: Option<EventFd>) {
+        self.err = err;
+    }
+}
+
+/// Writer returning descriptor chains to the driver in batches.
+///
+/// The used elements are made visible to the driver every `batch_size` chains rather than one by
+/// one, which spares memory barriers and cache line bounces with the driver. `finish()` publishes
+/// the pending elements and notifies the guest, unless the driver has suppressed the
+/// notification with VIRTIO_RING_F_EVENT_IDX. Pending elements are also published when the
+/// writer is dropped, without notifying the guest.
+pub struct UsedRingWriter<'a> {
+    vring: &'a mut Vring,
+    mem: &'a GuestMemoryMmap,
+    batch_size: usize,
+    pending: usize,
+    added: bool,
+}
+
+impl<'a> UsedRingWriter<'a> {
+    /// Set the number of descriptor chains to return before publishing them, 32 by default.
+    pub fn set_batch_size(&mut self, batch_size: usize) {
+        self.batch_size = std::cmp::max(batch_size, 1);
+    }
+
+    /// Take the next descriptor chain made available by the driver, if any.
+    pub fn pop_avail(&mut self) -> io::Result<Option<DescriptorChain>> {
+        self.vring.pop_avail(self.mem)
+    }
+
+    /// Get the vring the chains are returned to.
+    pub fn vring(&mut self) -> &mut Vring {
+        self.vring
+    }
+
+    /// Return a descriptor chain to the driver, with `len` bytes written by the device.
+    pub fn add_used(&mut self, head_index: u16, len: u32) -> io::Result<()> {
+        self.vring.stage_used(self.mem, head_index, len)?;
+        self.pending += 1;
+        self.added = true;
+        if self.pending >= self.batch_size {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    /// Publish the descriptor chains returned since the last batch.
+    pub fn flush(&mut self) -> io::Result<()> {
+        if self.pending != 0 {
+            self.pending = 0;
+            self.vring.publish_used(self.mem)?;
+        }
+        Ok(())
+    }
+
+    /// Publish the pending descriptor chains, and notify the guest if chains have been returned.
+    pub fn finish(mut self) -> io::Result<()> {
+        self.flush()?;
+        if self.added {
+            self.vring.notify_used_queue(self.mem)?;
+        }
+        Ok(())
+    }
+}
+
+impl<'a> Drop for UsedRingWriter<'a> {
+    fn drop(&mut self) {
+        let _ = self.flush();
+    }
+}
@@ -0,0 +1,85 @@
+// Copyright (C) 2020 Alibaba Cloud Computing. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Placement and scheduling of the worker threads.
+
+use std::ffi::CString;
+use std::io;
+use std::mem;
+
+// Longest thread name supported by Linux, without the terminating null byte.
+const MAX_THREAD_NAME_LEN: usize = 15;
+
+/// Real-time scheduling policy of a worker thread, with its priority from 1 to 99.
+///
+/// Setting a real-time policy usually requires the CAP_SYS_NICE capability.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum WorkerPriority {
+    /// SCHED_FIFO, running the thread until it blocks or a higher priority thread is ready.
+    Fifo(i32),
+    /// SCHED_RR, sharing the CPU with the threads of the same priority in time slices.
+    RoundRobin(i32),
+}
+
+/// Configuration of a worker thread, applied by `Daemon::set_worker_config()`.
+///
+/// Settings left empty keep the values the thread has inherited from the thread which created
+/// the daemon.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct WorkerConfig {
+    /// CPUs the thread is allowed to run on.
+    pub cpus: Vec<usize>,
+    /// Name of the thread, truncated to 15 bytes, instead of `<daemon name>-worker-<thread id>`.
+    pub name: Option<String>,
+    /// Real-time scheduling policy of the thread.
+    pub priority: Option<WorkerPriority>,
+}
+
+impl WorkerConfig {
+    // Apply the configuration to a running thread.
+    pub(super) fn apply(&self, thread: libc::pthread_t) -> io::Result<()> {
+        if !self.cpus.is_empty() {
+            // Safe because cpu_set_t is a plain bitmap.
+            let mut set: libc::cpu_set_t = unsafe { mem::zeroed() };
+            for &cpu in self.cpus.iter() {
+                if cpu >= mem::size_of::<libc::cpu_set_t>() * 8 {
+                    return Err(io::Error::from_raw_os_error(libc::EINVAL));
+                }
+                // Safe because the CPU is in the set.
+                unsafe { libc::CPU_SET(cpu, &mut set) };
+            }
+            // Safe because the set is valid for the whole call and we check the result.
+            check(unsafe {
+                libc::pthread_setaffinity_np(thread, mem::size_of::<libc::cpu_set_t>(), &set)
+            })?;
+        }
+        if let Some(ref name) = self.name {
+            let mut len = std::cmp::min(name.len(), MAX_THREAD_NAME_LEN);
+            while !name.is_char_boundary(len) {
+                len -= 1;
+            }
+            let name = CString::new(&name[..len])
+                .map_err(|_| io::Error::from_raw_os_error(libc::EINVAL))?;
+            // Safe because the name is a valid C string and we check the result.
+            check(unsafe { libc::pthread_setname_np(thread, name.as_ptr()) })?;
+        }
+        if let Some(priority) = self.priority {
+            let (policy, sched_priority) = match priority {
+                WorkerPriority::Fifo(priority) => (libc::SCHED_FIFO, priority),
+                WorkerPriority::RoundRobin(priority) => (libc::SCHED_RR, priority),
+            };
+            let param = libc::sched_param { sched_priority };
+            // Safe because the parameters are valid for the whole call and we check the result.
+            check(unsafe { libc::pthread_setschedparam(thread, policy, &param) })?;
+        }
+        Ok(())
+    }
+}
+
+// The pthread functions return the error number rather than setting errno.
+fn check(ret: libc::c_int) -> io::Result<()> {
+    if ret != 0 {
+        return Err(io::Error::from_raw_os_error(ret));
+    }
+    Ok(())
+}
@@ -0,0 +1,82 @@
+// Copyright (C) 2020 Alibaba Cloud Computing. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Transfer of the internal state of the device during migration.
+//!
+//! When the VHOST_USER_PROTOCOL_F_DEVICE_STATE protocol feature has been negotiated, the master
+//! migrates the internal state of the slave, which isn't covered by the vrings, through a pipe
+//! sent with SET_DEVICE_STATE_FD. When saving, the slave writes its state into the pipe and
+//! closes it, and the master reads it until EOF. When loading, the master writes the state into
+//! the pipe and closes it, and the slave reads it until EOF. The master then sends
+//! CHECK_DEVICE_STATE to know whether the slave has successfully completed the transfer.
+
+use std::fs::File;
+use std::io::{Error as IOError, Read, Result as IOResult, Write};
+use std::thread::{self, JoinHandle};
+
+use super::message::VhostTransferStateDirection;
+
+/// Trait for slaves to serialize their internal state.
+pub trait DeviceStateSerializer {
+    /// Serialize the internal state of the device, which is stopped.
+    fn save_state(&mut self) -> IOResult<Vec<u8>>;
+
+    /// Restore the internal state of the device, which is stopped, from data produced by
+    /// `save_state()`.
+    fn load_state(&mut self, state: &[u8]) -> IOResult<()>;
+}
+
+/// Ongoing transfer of the internal state of the device.
+///
+/// The pipe is written or read by a thread, so the slave keeps handling requests while the
+/// master reads or writes the other end.
+pub struct DeviceStateTransfer {
+    direction: VhostTransferStateDirection,
+    thread: JoinHandle<IOResult<Vec<u8>>>,
+}
+
+impl DeviceStateTransfer {
+    /// Start a transfer through the file received with SET_DEVICE_STATE_FD, in the stopped
+    /// migration phase, the only one defined so far. The state is serialized at once when saving
+    /// it.
+    pub fn start(
+        direction: VhostTransferStateDirection,
+        mut file: File,
+        serializer: &mut dyn DeviceStateSerializer,
+    ) -> IOResult<Self> {
+        let thread = match direction {
+            VhostTransferStateDirection::Save => {
+                let state = serializer.save_state()?;
+                thread::Builder::new()
+                    .name("vhost_user_save_state".to_string())
+                    .spawn(move || file.write_all(&state).map(|_| Vec::new()))?
+            }
+            VhostTransferStateDirection::Load => thread::Builder::new()
+                .name("vhost_user_load_state".to_string())
+                .spawn(move || {
+                    let mut state = Vec::new();
+                    file.read_to_end(&mut state)?;
+                    Ok(state)
+                })?,
+        };
+        Ok(DeviceStateTransfer { direction, thread })
+    }
+
+    /// Get the direction of the transfer.
+    pub fn direction(&self) -> VhostTransferStateDirection {
+        self.direction
+    }
+
+    /// Wait for the transfer to complete, on CHECK_DEVICE_STATE, and restore the state when
+    /// loading it.
+    pub fn finish(self, serializer: &mut dyn DeviceStateSerializer) -> IOResult<()> {
+        let state = self
+            .thread
+            .join()
+            .map_err(|_| IOError::from_raw_os_error(libc::EIO))??;
+        match self.direction {
+            VhostTransferStateDirection::Save => Ok(()),
+            VhostTransferStateDirection::Load => serializer.load_state(&state),
+        }
+    }
+}
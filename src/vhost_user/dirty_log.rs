@@ -0,0 +1,176 @@
+// Copyright (C) 2020 Alibaba Cloud Computing. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Shared memory bitmap to log pages of guest memory written by the slave.
+//!
+//! During live migration the master asks the slave to log all guest pages it writes, so they can
+//! be sent again to the destination. When the VHOST_USER_PROTOCOL_F_LOG_SHMFD protocol feature
+//! has been negotiated, the master allocates the log and shares it with the slave through the
+//! SET_LOG_BASE request.
+//!
+//! The log layout is the same as the one used by the vhost kernel drivers: each bit of the log
+//! covers a VHOST_LOG_PAGE sized page of guest physical memory, with bit `n % 8` of byte `n / 8`
+//! tracking page `n`.
+
+use std::fs::File;
+use std::io::{Error as IOError, Result as IOResult};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::ptr::null_mut;
+use std::sync::atomic::{AtomicU8, Ordering};
+
+use libc;
+
+use super::message::{VhostUserLog, VhostUserMsgValidator};
+
+/// Size of the guest pages tracked by each bit of the dirty log.
+pub const VHOST_LOG_PAGE: u64 = 0x1000;
+
+/// Dirty page log shared with the master.
+pub struct DirtyLog {
+    file: File,
+    addr: *mut u8,
+    mmap_size: usize,
+}
+
+// It's safe because the mapping is owned by the DirtyLog object and the log is only accessed
+// through atomic operations.
+unsafe impl Send for DirtyLog {}
+unsafe impl Sync for DirtyLog {}
+
+impl DirtyLog {
+    /// Map a dirty log received from the master by the SET_LOG_BASE request.
+    pub fn from_file(file: File, log: &VhostUserLog) -> IOResult<Self> {
+        if !log.is_valid() || log.mmap_size > usize::MAX as u64 {
+            return Err(IOError::from_raw_os_error(libc::EINVAL));
+        }
+        let file_size = file.metadata()?.len();
+        if log.mmap_offset + log.mmap_size > file_size {
+            return Err(IOError::from_raw_os_error(libc::EINVAL));
+        }
+
+        let mmap_size = log.mmap_size as usize;
+        // Safe because we check the return value and the mapping is released on drop.
+        let addr = unsafe {
+            libc::mmap(
+                null_mut(),
+                mmap_size,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_SHARED,
+                file.as_raw_fd(),
+                log.mmap_offset as libc::off_t,
+            )
+        };
+        if addr == libc::MAP_FAILED {
+            return Err(IOError::last_os_error());
+        }
+
+        Ok(DirtyLog {
+            file,
+            addr: addr as *mut u8,
+            mmap_size,
+        })
+    }
+
+    /// Get the file backing the dirty log.
+    pub fn file(&self) -> &File {
+        &self.file
+    }
+
+    /// Get size of the dirty log in bytes.
+    pub fn size(&self) -> usize {
+        self.mmap_size
+    }
+
+    /// Get size of the guest memory covered by the dirty log.
+    pub fn memory_size(&self) -> u64 {
+        self.mmap_size as u64 * 8 * VHOST_LOG_PAGE
+    }
+
+    /// Mark the pages covering `len` bytes of guest memory starting at `gpa` as dirty.
+    ///
+    /// Return EINVAL if the range isn't covered by the dirty log, without touching the log.
+    pub fn mark_dirty(&self, gpa: u64, len: u64) -> IOResult<()> {
+        if len == 0 {
+            return Ok(());
+        }
+        match gpa.checked_add(len) {
+            Some(end) if end <= self.memory_size() => {}
+            _ => return Err(IOError::from_raw_os_error(libc::EINVAL)),
+        }
+
+        let first = gpa / VHOST_LOG_PAGE;
+        let last = (gpa + len - 1) / VHOST_LOG_PAGE;
+        for page in first..=last {
+            self.log_byte(page)
+                .fetch_or(1 << (page % 8), Ordering::SeqCst);
+        }
+        Ok(())
+    }
+
+    /// Check whether the page containing `gpa` has been marked as dirty.
+    pub fn is_dirty(&self, gpa: u64) -> bool {
+        if gpa >= self.memory_size() {
+            return false;
+        }
+        let page = gpa / VHOST_LOG_PAGE;
+        self.log_byte(page).load(Ordering::SeqCst) & (1 << (page % 8)) != 0
+    }
+
+    fn log_byte(&self, page: u64) -> &AtomicU8 {
+        // Safe because callers make sure the page is covered by the log, and AtomicU8 has the
+        // same in-memory representation as u8.
+        unsafe { &*(self.addr.add((page / 8) as usize) as *const AtomicU8) }
+    }
+}
+
+impl AsRawFd for DirtyLog {
+    fn as_raw_fd(&self) -> RawFd {
+        self.file.as_raw_fd()
+    }
+}
+
+impl Drop for DirtyLog {
+    fn drop(&mut self) {
+        // Safe because the area has been mapped by ourself.
+        unsafe { libc::munmap(self.addr as *mut libc::c_void, self.mmap_size) };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::FileExt;
+    use vmm_sys_util::tempfile::TempFile;
+
+    #[test]
+    fn test_dirty_log() {
+        let file = TempFile::new().unwrap().into_file();
+        file.set_len(0x1000).unwrap();
+        assert!(
+            DirtyLog::from_file(file.try_clone().unwrap(), &VhostUserLog::new(0x2000, 0)).is_err()
+        );
+        assert!(DirtyLog::from_file(file.try_clone().unwrap(), &VhostUserLog::new(0, 0)).is_err());
+
+        let log = DirtyLog::from_file(file, &VhostUserLog::new(0x1000, 0)).unwrap();
+        assert_eq!(log.size(), 0x1000);
+        assert_eq!(log.memory_size(), 0x1000 * 8 * VHOST_LOG_PAGE);
+        assert!(!log.is_dirty(0));
+
+        log.mark_dirty(0x1fff, 2).unwrap();
+        assert!(!log.is_dirty(0));
+        assert!(log.is_dirty(0x1000));
+        assert!(log.is_dirty(0x2000));
+        assert!(!log.is_dirty(0x3000));
+        let mut byte = [0u8; 1];
+        log.file().read_exact_at(&mut byte, 0).unwrap();
+        assert_eq!(byte[0], 0x6);
+        log.mark_dirty(0x9000, 0).unwrap();
+        assert!(!log.is_dirty(0x9000));
+
+        assert!(log.mark_dirty(log.memory_size() - 1, 2).is_err());
+        assert!(log.mark_dirty(u64::MAX, 2).is_err());
+        log.mark_dirty(log.memory_size() - 1, 1).unwrap();
+        assert!(log.is_dirty(log.memory_size() - 1));
+        assert!(!log.is_dirty(log.memory_size()));
+    }
+}
@@ -1,13 +1,22 @@
 // Copyright (C) 2019 Alibaba Cloud Computing. All rights reserved.
 // SPDX-License-Identifier: Apache-2.0
 
+use super::device_state::{DeviceStateSerializer, DeviceStateTransfer};
+use super::dirty_log::DirtyLog;
+use super::gpu::GpuReqSender;
+use super::inflight::InflightRegion;
+use super::iotlb::IotlbCache;
 use super::message::*;
+use super::userfaultfd::Userfaultfd;
 use super::*;
+use crate::features::VirtioFeatures;
+use std::fs::File;
 use std::os::unix::io::RawFd;
 
 pub const MAX_QUEUE_NUM: usize = 2;
 pub const MAX_VRING_NUM: usize = 256;
-pub const VIRTIO_FEATURES: u64 = 0x40000003;
+pub const VIRTIO_FEATURES: u64 = 0x2_4400_0003;
+pub const MAX_MEM_SLOTS: usize = 40;
 
 #[derive(Default)]
 pub struct DummySlaveReqHandler {
@@ -23,6 +32,24 @@ pub struct DummySlaveReqHandler {
     pub err_fd: [Option<RawFd>; MAX_QUEUE_NUM],
     pub vring_started: [bool; MAX_QUEUE_NUM],
     pub vring_enabled: [bool; MAX_QUEUE_NUM],
+    pub vring_big_endian: [bool; MAX_QUEUE_NUM],
+    pub rarp_mac: Option<[u8; 6]>,
+    pub mtu: Option<u16>,
+    pub inflight: Option<InflightRegion>,
+    pub uffd: Option<Userfaultfd>,
+    pub postcopy_listening: bool,
+    pub slave_req: Option<MasterReqSender>,
+    pub gpu: Option<GpuReqSender>,
+    pub dirty_log: Option<DirtyLog>,
+    pub log_fd: Option<File>,
+    pub iotlb: IotlbCache,
+    pub mem_regions: Vec<(VhostUserMemoryRegion, File)>,
+    pub status: u8,
+    pub shared_objects: Vec<([u8; 16], File)>,
+    pub device_state: Vec<u8>,
+    pub state_transfer: Option<DeviceStateTransfer>,
+    pub shmem_sizes: Vec<u64>,
+    pub xen_mmaps: Vec<VhostUserXenMmap>,
 }
 
 impl DummySlaveReqHandler {
@@ -32,6 +59,19 @@ impl DummySlaveReqHandler {
             ..Default::default()
         }
     }
+
+    fn close_vring_fds(&mut self) {
+        for fd in self
+            .call_fd
+            .iter_mut()
+            .chain(self.kick_fd.iter_mut())
+            .chain(self.err_fd.iter_mut())
+        {
+            if let Some(fd) = fd.take() {
+                let _ = unsafe { libc::close(fd) };
+            }
+        }
+    }
 }
 
 impl VhostUserSlaveReqHandler for DummySlaveReqHandler {
@@ -58,13 +98,19 @@ impl VhostUserSlaveReqHandler for DummySlaveReqHandler {
     fn set_features(&mut self, features: u64) -> Result<()> {
         if !self.owned {
             return Err(Error::InvalidOperation);
-        } else if self.features_acked {
+        } else if self.features_acked
+            && (features ^ self.acked_features) & !VirtioFeatures::LOG_ALL.bits() != 0
+        {
+            // Only the logging of dirty pages may be switched once the features are acked.
             return Err(Error::InvalidOperation);
         } else if (features & !VIRTIO_FEATURES) != 0 {
             return Err(Error::InvalidParam);
         }
 
         self.acked_features = features;
+        if self.features_acked {
+            return Ok(());
+        }
         self.features_acked = true;
 
         // If VHOST_USER_F_PROTOCOL_FEATURES has not been negotiated,
@@ -216,13 +262,39 @@ impl VhostUserSlaveReqHandler for DummySlaveReqHandler {
         Ok(())
     }
 
+    fn set_vring_endian(&mut self, index: u32, big_endian: bool) -> Result<()> {
+        if index as usize >= self.queue_num {
+            return Err(Error::InvalidParam);
+        }
+        // The endianness can't change while the vring is running.
+        if self.vring_started[index as usize] {
+            return Err(Error::InvalidOperation);
+        }
+        self.vring_big_endian[index as usize] = big_endian;
+        Ok(())
+    }
+
+    fn send_rarp(&mut self, mac: [u8; 6]) -> Result<()> {
+        self.rarp_mac = Some(mac);
+        Ok(())
+    }
+
+    fn net_set_mtu(&mut self, mtu: u16) -> Result<()> {
+        // The minimal MTU of Ethernet devices.
+        if mtu < 68 {
+            return Err(Error::InvalidParam);
+        }
+        self.mtu = Some(mtu);
+        Ok(())
+    }
+
     fn get_config(
         &mut self,
         offset: u32,
         size: u32,
         _flags: VhostUserConfigFlags,
     ) -> Result<Vec<u8>> {
-        if self.acked_features & VhostUserProtocolFeatures::CONFIG.bits() == 0 {
+        if self.acked_protocol_features & VhostUserProtocolFeatures::CONFIG.bits() == 0 {
             return Err(Error::InvalidOperation);
         } else if offset < VHOST_USER_CONFIG_OFFSET
             || offset >= VHOST_USER_CONFIG_SIZE
@@ -236,7 +308,7 @@ impl VhostUserSlaveReqHandler for DummySlaveReqHandler {
 
     fn set_config(&mut self, offset: u32, buf: &[u8], _flags: VhostUserConfigFlags) -> Result<()> {
         let size = buf.len() as u32;
-        if self.acked_features & VhostUserProtocolFeatures::CONFIG.bits() == 0 {
+        if self.acked_protocol_features & VhostUserProtocolFeatures::CONFIG.bits() == 0 {
             return Err(Error::InvalidOperation);
         } else if offset < VHOST_USER_CONFIG_OFFSET
             || offset >= VHOST_USER_CONFIG_SIZE
@@ -247,4 +319,211 @@ impl VhostUserSlaveReqHandler for DummySlaveReqHandler {
         }
         Ok(())
     }
+
+    fn set_slave_req_fd(&mut self, vu_req: MasterReqSender) {
+        self.slave_req = Some(vu_req);
+    }
+
+    fn set_gpu_socket(&mut self, gpu: GpuReqSender) {
+        self.gpu = Some(gpu);
+    }
+
+    fn get_inflight_fd(
+        &mut self,
+        inflight: &VhostUserInflight,
+    ) -> Result<(VhostUserInflight, File)> {
+        let region = InflightRegion::new(inflight.num_queues, inflight.queue_size)
+            .map_err(Error::ReqHandlerError)?;
+        let file = region.file().try_clone().map_err(Error::ReqHandlerError)?;
+        let reply = region.inflight();
+        self.inflight = Some(region);
+        Ok((reply, file))
+    }
+
+    fn set_inflight_fd(&mut self, inflight: &VhostUserInflight, file: File) -> Result<()> {
+        let region = InflightRegion::from_file(file, inflight).map_err(Error::ReqHandlerError)?;
+        self.inflight = Some(region);
+        Ok(())
+    }
+
+    fn postcopy_advise(&mut self) -> Result<File> {
+        let uffd = Userfaultfd::new().map_err(Error::ReqHandlerError)?;
+        let file = uffd.try_clone_file().map_err(Error::ReqHandlerError)?;
+        self.uffd = Some(uffd);
+        Ok(file)
+    }
+
+    fn postcopy_listen(&mut self) -> Result<()> {
+        if self.uffd.is_none() || self.postcopy_listening {
+            return Err(Error::InvalidOperation);
+        }
+        self.postcopy_listening = true;
+        Ok(())
+    }
+
+    fn postcopy_end(&mut self) -> Result<()> {
+        self.uffd = None;
+        self.postcopy_listening = false;
+        Ok(())
+    }
+
+    fn set_log_base(&mut self, log: &VhostUserLog, file: File) -> Result<()> {
+        let dirty_log = DirtyLog::from_file(file, log).map_err(Error::ReqHandlerError)?;
+        self.dirty_log = Some(dirty_log);
+        Ok(())
+    }
+
+    fn set_log_fd(&mut self, file: File) -> Result<()> {
+        self.log_fd = Some(file);
+        Ok(())
+    }
+
+    fn iotlb_msg(&mut self, iotlb: &VhostUserIotlb) -> Result<()> {
+        if self.acked_features & VhostUserVirtioFeatures::IOMMU_PLATFORM.bits() == 0 {
+            return Err(Error::InvalidOperation);
+        }
+        self.iotlb.handle_msg(iotlb)
+    }
+
+    fn reset_session(&mut self, preserve_inflight: bool) -> Result<()> {
+        self.close_vring_fds();
+        let inflight = if preserve_inflight {
+            self.inflight.take()
+        } else {
+            None
+        };
+        *self = DummySlaveReqHandler::new();
+        self.inflight = inflight;
+        Ok(())
+    }
+
+    fn reset_device(&mut self) -> Result<()> {
+        // Unlike RESET_OWNER, the master keeps the ownership and the negotiated protocol
+        // features.
+        self.close_vring_fds();
+        self.features_acked = false;
+        self.acked_features = 0;
+        self.vring_num = [0; MAX_QUEUE_NUM];
+        self.vring_base = [0; MAX_QUEUE_NUM];
+        self.vring_started = [false; MAX_QUEUE_NUM];
+        self.vring_enabled = [false; MAX_QUEUE_NUM];
+        self.vring_big_endian = [false; MAX_QUEUE_NUM];
+        self.inflight = None;
+        self.iotlb.clear();
+        self.status = 0;
+        Ok(())
+    }
+
+    fn set_status(&mut self, status: u8) -> Result<()> {
+        if VhostUserDeviceStatus::from_bits(status).is_none() {
+            return Err(Error::InvalidParam);
+        }
+        self.status = status;
+        Ok(())
+    }
+
+    fn get_status(&mut self) -> Result<u8> {
+        Ok(self.status)
+    }
+
+    fn get_shared_object(&mut self, shared: &VhostUserShared) -> Result<File> {
+        let uuid = shared.uuid;
+        match self.shared_objects.iter().find(|(u, _)| *u == uuid) {
+            Some((_, file)) => file.try_clone().map_err(Error::ReqHandlerError),
+            None => Err(Error::InvalidParam),
+        }
+    }
+
+    fn set_device_state_fd(
+        &mut self,
+        direction: VhostTransferStateDirection,
+        _phase: VhostTransferStatePhase,
+        file: File,
+    ) -> Result<Option<File>> {
+        if self.vring_started.iter().any(|started| *started) {
+            return Err(Error::InvalidOperation);
+        }
+        let transfer =
+            DeviceStateTransfer::start(direction, file, self).map_err(Error::ReqHandlerError)?;
+        self.state_transfer = Some(transfer);
+        Ok(None)
+    }
+
+    fn check_device_state(&mut self) -> Result<()> {
+        match self.state_transfer.take() {
+            Some(transfer) => transfer.finish(self).map_err(Error::ReqHandlerError),
+            None => Err(Error::InvalidOperation),
+        }
+    }
+
+    fn get_shared_memory_regions(&mut self) -> Result<Vec<u64>> {
+        Ok(self.shmem_sizes.clone())
+    }
+
+    fn get_max_mem_slots(&mut self) -> Result<u64> {
+        Ok(MAX_MEM_SLOTS as u64)
+    }
+
+    fn add_mem_region(&mut self, region: &VhostUserSingleMemoryRegion, file: File) -> Result<()> {
+        let region = region.region;
+        if self.mem_regions.len() >= MAX_MEM_SLOTS {
+            return Err(Error::InvalidOperation);
+        }
+        let end = region.guest_phys_addr + region.memory_size;
+        if self.mem_regions.iter().any(|(r, _)| {
+            region.guest_phys_addr < r.guest_phys_addr + r.memory_size && r.guest_phys_addr < end
+        }) {
+            return Err(Error::InvalidParam);
+        }
+        self.mem_regions.push((region, file));
+        Ok(())
+    }
+
+    fn remove_mem_region(&mut self, region: &VhostUserSingleMemoryRegion) -> Result<()> {
+        let region = region.region;
+        match self.mem_regions.iter().position(|(r, _)| {
+            r.guest_phys_addr == region.guest_phys_addr && r.memory_size == region.memory_size
+        }) {
+            Some(index) => {
+                self.mem_regions.remove(index);
+                Ok(())
+            }
+            None => Err(Error::InvalidParam),
+        }
+    }
+
+    fn set_mem_table_xen(
+        &mut self,
+        ctx: &[VhostUserXenMemoryRegion],
+        _fds: &[RawFd],
+    ) -> Result<()> {
+        self.xen_mmaps = ctx.iter().map(|region| region.xen_mmap).collect();
+        Ok(())
+    }
+
+    fn add_mem_region_xen(
+        &mut self,
+        region: &VhostUserXenSingleMemoryRegion,
+        file: File,
+    ) -> Result<()> {
+        let region = region.region;
+        let single = VhostUserSingleMemoryRegion {
+            padding: 0,
+            region: region.region,
+        };
+        self.add_mem_region(&single, file)?;
+        self.xen_mmaps.push(region.xen_mmap);
+        Ok(())
+    }
+}
+
+impl DeviceStateSerializer for DummySlaveReqHandler {
+    fn save_state(&mut self) -> std::io::Result<Vec<u8>> {
+        Ok(self.device_state.clone())
+    }
+
+    fn load_state(&mut self, state: &[u8]) -> std::io::Result<()> {
+        self.device_state = state.to_vec();
+        Ok(())
+    }
 }
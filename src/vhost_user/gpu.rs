@@ -0,0 +1,1045 @@
+// Copyright (C) 2020 Alibaba Cloud Computing. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! The vhost-user-gpu protocol, spoken on the socket the master shares with GPU backends by
+//! GPU_SET_SOCKET.
+//!
+//! GPU backends are managed through the control socket like any other vhost-user slave, and
+//! display their scanouts through this second socket: the backend sends requests to the frontend
+//! to query the display configuration and to push the scanouts, the framebuffer updates and the
+//! cursor. `GpuReqSender` sends these requests from the backend, and `GpuReqHandler` relays them
+//! to the `VhostUserGpuReqHandler` of the frontend.
+//!
+//! Messages have the same header as the vhost-user ones, without a version in their flags.
+//! Framebuffer updates carry the pixels of the updated rectangle, so messages may be much bigger
+//! than MAX_MSG_SIZE, up to VHOST_USER_GPU_MAX_MSG_SIZE.
+
+#![allow(non_camel_case_types)]
+
+use std::fs::File;
+use std::io::{self, ErrorKind, Read, Write};
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::os::unix::net::UnixStream;
+use std::sync::{Arc, Mutex};
+use std::{mem, ptr, slice};
+
+use libc::{c_void, iovec};
+
+use super::message::{VhostUserMsgPlain, VhostUserMsgView, VhostUserU64, MAX_ATTACHED_FD_ENTRIES};
+use super::sock_ctrl_msg::ScmSocket;
+use super::{Error, HandlerResult, Result};
+
+/// Maximum size of the payload of vhost-user-gpu messages, enough for updating a whole 4K
+/// scanout at 32 bits per pixel.
+pub const VHOST_USER_GPU_MAX_MSG_SIZE: usize = 0x400_0000;
+
+/// Maximum number of scanouts of a GPU.
+pub const VHOST_USER_GPU_MAX_SCANOUTS: usize = 16;
+
+/// Width and height of the cursor images, in pixels.
+pub const VHOST_USER_GPU_CURSOR_SIZE: usize = 64;
+
+/// Maximum size of the EDID of a scanout.
+pub const VHOST_USER_GPU_EDID_SIZE: usize = 1024;
+
+// Flag marking the replies.
+const VHOST_USER_GPU_MSG_FLAG_REPLY: u32 = 0x4;
+
+// Size of the message header on the wire.
+const HEADER_SIZE: usize = 12;
+
+/// Type of requests sent from GPU backends to the frontend.
+#[repr(u32)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum GpuReq {
+    /// Null operation.
+    NOOP = 0,
+    /// Get the protocol features supported by the frontend.
+    GET_PROTOCOL_FEATURES = 1,
+    /// Enable the protocol features supported by both sides.
+    SET_PROTOCOL_FEATURES = 2,
+    /// Get the preferred display configuration.
+    GET_DISPLAY_INFO = 3,
+    /// Move the cursor.
+    CURSOR_POS = 4,
+    /// Hide the cursor.
+    CURSOR_POS_HIDE = 5,
+    /// Set the image and the hotspot of the cursor, and move it.
+    CURSOR_UPDATE = 6,
+    /// Set the size of a scanout, or disable it with a zero size.
+    SCANOUT = 7,
+    /// Update a rectangle of a scanout with the pixels following the request.
+    UPDATE = 8,
+    /// Display a dmabuf on a scanout, or disable it without dmabuf.
+    DMABUF_SCANOUT = 9,
+    /// Notify that a rectangle of the dmabuf displayed by a scanout has been updated.
+    DMABUF_UPDATE = 10,
+    /// Get the EDID of a scanout.
+    GET_EDID = 11,
+    /// Display a dmabuf on a scanout, with the format modifier of the dmabuf.
+    DMABUF_SCANOUT2 = 12,
+    /// Upper bound of valid commands.
+    MAX_CMD = 13,
+}
+
+impl GpuReq {
+    fn from_code(code: u32) -> Option<GpuReq> {
+        if code == GpuReq::NOOP as u32 || code >= GpuReq::MAX_CMD as u32 {
+            return None;
+        }
+        // Safe because GpuReq is repr(u32), and the value is within its range.
+        Some(unsafe { mem::transmute::<u32, GpuReq>(code) })
+    }
+}
+
+bitflags! {
+    /// Protocol features of the vhost-user-gpu protocol.
+    pub struct VhostUserGpuProtocolFeatures: u64 {
+        /// GET_EDID is supported.
+        const EDID = 0x1;
+        /// DMABUF_SCANOUT2 is supported.
+        const DMABUF2 = 0x2;
+    }
+}
+
+/// Rectangle of a scanout, with the layout of struct virtio_gpu_rect.
+#[repr(C, packed)]
+#[derive(Default, Clone, Copy, Debug, PartialEq)]
+pub struct VhostUserGpuRect {
+    /// Horizontal position of the rectangle.
+    pub x: u32,
+    /// Vertical position of the rectangle.
+    pub y: u32,
+    /// Width of the rectangle.
+    pub width: u32,
+    /// Height of the rectangle.
+    pub height: u32,
+}
+
+/// Configuration of a scanout, with the layout of struct virtio_gpu_display_one.
+#[repr(C, packed)]
+#[derive(Default, Clone, Copy, Debug, PartialEq)]
+pub struct VhostUserGpuDisplayOne {
+    /// Preferred position and size of the scanout.
+    pub r: VhostUserGpuRect,
+    /// Whether the scanout is enabled.
+    pub enabled: u32,
+    /// Flags of the scanout.
+    pub flags: u32,
+}
+
+/// Header of the virtio-gpu responses, with the layout of struct virtio_gpu_ctrl_hdr.
+#[repr(C, packed)]
+#[derive(Default, Clone, Copy, Debug, PartialEq)]
+pub struct VhostUserGpuCtrlHdr {
+    /// Type of the response.
+    pub type_: u32,
+    /// Flags of the response.
+    pub flags: u32,
+    /// Fence of the response.
+    pub fence_id: u64,
+    /// Rendering context of the response.
+    pub ctx_id: u32,
+    /// Padding for alignment.
+    pub padding: u32,
+}
+
+/// Reply to GET_DISPLAY_INFO, with the layout of struct virtio_gpu_resp_display_info.
+#[repr(C, packed)]
+#[derive(Default, Clone, Copy)]
+pub struct VhostUserGpuDisplayInfo {
+    /// Response header.
+    pub hdr: VhostUserGpuCtrlHdr,
+    /// Configuration of each scanout.
+    pub pmodes: [VhostUserGpuDisplayOne; VHOST_USER_GPU_MAX_SCANOUTS],
+}
+
+/// Payload of the GET_EDID requests.
+#[repr(C, packed)]
+#[derive(Default, Clone, Copy)]
+pub struct VhostUserGpuEdidRequest {
+    /// Scanout whose EDID is requested.
+    pub scanout_id: u32,
+}
+
+/// Reply to GET_EDID, with the layout of struct virtio_gpu_resp_edid.
+#[repr(C, packed)]
+#[derive(Clone, Copy)]
+pub struct VhostUserGpuEdid {
+    /// Response header.
+    pub hdr: VhostUserGpuCtrlHdr,
+    /// Size of the EDID.
+    pub size: u32,
+    /// Padding for alignment.
+    pub padding: u32,
+    /// EDID of the scanout.
+    pub edid: [u8; VHOST_USER_GPU_EDID_SIZE],
+}
+
+impl Default for VhostUserGpuEdid {
+    fn default() -> Self {
+        VhostUserGpuEdid {
+            hdr: VhostUserGpuCtrlHdr::default(),
+            size: 0,
+            padding: 0,
+            edid: [0; VHOST_USER_GPU_EDID_SIZE],
+        }
+    }
+}
+
+/// Position of the cursor, payload of the CURSOR_POS and CURSOR_POS_HIDE requests.
+#[repr(C, packed)]
+#[derive(Default, Clone, Copy, Debug, PartialEq)]
+pub struct VhostUserGpuCursorPos {
+    /// Scanout showing the cursor.
+    pub scanout_id: u32,
+    /// Horizontal position of the cursor.
+    pub x: u32,
+    /// Vertical position of the cursor.
+    pub y: u32,
+}
+
+/// Payload of the CURSOR_UPDATE requests.
+#[repr(C, packed)]
+#[derive(Clone, Copy)]
+pub struct VhostUserGpuCursorUpdate {
+    /// Position of the cursor.
+    pub pos: VhostUserGpuCursorPos,
+    /// Horizontal position of the hotspot in the image.
+    pub hot_x: u32,
+    /// Vertical position of the hotspot in the image.
+    pub hot_y: u32,
+    /// Image of the cursor, in 32 bits pixels.
+    pub data: [u32; VHOST_USER_GPU_CURSOR_SIZE * VHOST_USER_GPU_CURSOR_SIZE],
+}
+
+impl Default for VhostUserGpuCursorUpdate {
+    fn default() -> Self {
+        VhostUserGpuCursorUpdate {
+            pos: VhostUserGpuCursorPos::default(),
+            hot_x: 0,
+            hot_y: 0,
+            data: [0; VHOST_USER_GPU_CURSOR_SIZE * VHOST_USER_GPU_CURSOR_SIZE],
+        }
+    }
+}
+
+/// Payload of the SCANOUT requests.
+#[repr(C, packed)]
+#[derive(Default, Clone, Copy, Debug, PartialEq)]
+pub struct VhostUserGpuScanout {
+    /// Scanout to configure.
+    pub scanout_id: u32,
+    /// Width of the scanout.
+    pub width: u32,
+    /// Height of the scanout.
+    pub height: u32,
+}
+
+/// Payload of the UPDATE requests, followed by the pixels of the rectangle, and of the
+/// DMABUF_UPDATE requests.
+#[repr(C, packed)]
+#[derive(Default, Clone, Copy, Debug, PartialEq)]
+pub struct VhostUserGpuUpdate {
+    /// Scanout to update.
+    pub scanout_id: u32,
+    /// Horizontal position of the updated rectangle.
+    pub x: u32,
+    /// Vertical position of the updated rectangle.
+    pub y: u32,
+    /// Width of the updated rectangle.
+    pub width: u32,
+    /// Height of the updated rectangle.
+    pub height: u32,
+}
+
+/// Payload of the DMABUF_SCANOUT requests.
+#[repr(C, packed)]
+#[derive(Default, Clone, Copy, Debug, PartialEq)]
+pub struct VhostUserGpuDmabufScanout {
+    /// Scanout to configure.
+    pub scanout_id: u32,
+    /// Horizontal position of the displayed rectangle of the dmabuf.
+    pub x: u32,
+    /// Vertical position of the displayed rectangle of the dmabuf.
+    pub y: u32,
+    /// Width of the displayed rectangle of the dmabuf.
+    pub width: u32,
+    /// Height of the displayed rectangle of the dmabuf.
+    pub height: u32,
+    /// Width of the dmabuf.
+    pub fd_width: u32,
+    /// Height of the dmabuf.
+    pub fd_height: u32,
+    /// Stride of the dmabuf.
+    pub fd_stride: u32,
+    /// Flags of the dmabuf.
+    pub fd_flags: u32,
+    /// DRM format of the dmabuf.
+    pub fd_drm_fourcc: u32,
+}
+
+/// Payload of the DMABUF_SCANOUT2 requests.
+#[repr(C, packed)]
+#[derive(Default, Clone, Copy, Debug, PartialEq)]
+pub struct VhostUserGpuDmabufScanout2 {
+    /// Scanout and dmabuf configuration.
+    pub dmabuf_scanout: VhostUserGpuDmabufScanout,
+    /// DRM format modifier of the dmabuf.
+    pub modifier: u64,
+}
+
+unsafe impl VhostUserMsgPlain for VhostUserGpuDisplayInfo {}
+unsafe impl VhostUserMsgPlain for VhostUserGpuEdidRequest {}
+unsafe impl VhostUserMsgPlain for VhostUserGpuEdid {}
+unsafe impl VhostUserMsgPlain for VhostUserGpuCursorPos {}
+unsafe impl VhostUserMsgPlain for VhostUserGpuCursorUpdate {}
+unsafe impl VhostUserMsgPlain for VhostUserGpuScanout {}
+unsafe impl VhostUserMsgPlain for VhostUserGpuUpdate {}
+unsafe impl VhostUserMsgPlain for VhostUserGpuDmabufScanout {}
+unsafe impl VhostUserMsgPlain for VhostUserGpuDmabufScanout2 {}
+
+/// Trait implemented by GPU frontends to handle the requests of the backend.
+///
+/// Requests with a reply which fail aren't replied, the error is returned by
+/// `GpuReqHandler::handle_request()` and the caller is expected to drop the connection.
+pub trait VhostUserGpuReqHandler {
+    /// Get the protocol features supported by the frontend.
+    fn get_protocol_features(&mut self) -> HandlerResult<VhostUserGpuProtocolFeatures> {
+        Ok(VhostUserGpuProtocolFeatures::empty())
+    }
+
+    /// Enable the protocol features negotiated with the backend.
+    fn set_protocol_features(
+        &mut self,
+        _features: VhostUserGpuProtocolFeatures,
+    ) -> HandlerResult<()> {
+        Ok(())
+    }
+
+    /// Get the preferred display configuration.
+    fn get_display_info(&mut self) -> HandlerResult<VhostUserGpuDisplayInfo> {
+        Err(io::Error::from_raw_os_error(libc::ENOSYS))
+    }
+
+    /// Get the EDID of a scanout, once the EDID protocol feature has been negotiated.
+    fn get_edid(&mut self, _req: &VhostUserGpuEdidRequest) -> HandlerResult<VhostUserGpuEdid> {
+        Err(io::Error::from_raw_os_error(libc::ENOSYS))
+    }
+
+    /// Move the cursor.
+    fn cursor_pos(&mut self, _pos: &VhostUserGpuCursorPos) -> HandlerResult<()> {
+        Err(io::Error::from_raw_os_error(libc::ENOSYS))
+    }
+
+    /// Hide the cursor.
+    fn cursor_pos_hide(&mut self, _pos: &VhostUserGpuCursorPos) -> HandlerResult<()> {
+        Err(io::Error::from_raw_os_error(libc::ENOSYS))
+    }
+
+    /// Set the image and the hotspot of the cursor, and move it.
+    fn cursor_update(&mut self, _update: &VhostUserGpuCursorUpdate) -> HandlerResult<()> {
+        Err(io::Error::from_raw_os_error(libc::ENOSYS))
+    }
+
+    /// Set the size of a scanout, or disable it with a zero size.
+    fn set_scanout(&mut self, _scanout: &VhostUserGpuScanout) -> HandlerResult<()> {
+        Err(io::Error::from_raw_os_error(libc::ENOSYS))
+    }
+
+    /// Update a rectangle of a scanout with its pixels in `data`.
+    fn update_scanout(&mut self, _update: &VhostUserGpuUpdate, _data: &[u8]) -> HandlerResult<()> {
+        Err(io::Error::from_raw_os_error(libc::ENOSYS))
+    }
+
+    /// Display the dmabuf `fd` on a scanout, or disable it if `fd` is None.
+    fn set_dmabuf_scanout(
+        &mut self,
+        _scanout: &VhostUserGpuDmabufScanout,
+        _fd: Option<File>,
+    ) -> HandlerResult<()> {
+        Err(io::Error::from_raw_os_error(libc::ENOSYS))
+    }
+
+    /// Display the dmabuf `fd` with a format modifier on a scanout, once the DMABUF2 protocol
+    /// feature has been negotiated.
+    fn set_dmabuf_scanout2(
+        &mut self,
+        _scanout: &VhostUserGpuDmabufScanout2,
+        _fd: Option<File>,
+    ) -> HandlerResult<()> {
+        Err(io::Error::from_raw_os_error(libc::ENOSYS))
+    }
+
+    /// Render the updated rectangle of the dmabuf displayed by a scanout. The backend waits for
+    /// the request to be replied before reusing the dmabuf.
+    fn update_dmabuf(&mut self, _update: &VhostUserGpuUpdate) -> HandlerResult<()> {
+        Err(io::Error::from_raw_os_error(libc::ENOSYS))
+    }
+}
+
+// Message received on the GPU socket.
+struct GpuMsg {
+    request: u32,
+    flags: u32,
+    payload: Vec<u8>,
+    files: Vec<File>,
+}
+
+fn io_error(e: io::Error) -> Error {
+    match e.kind() {
+        ErrorKind::UnexpectedEof | ErrorKind::BrokenPipe | ErrorKind::ConnectionReset => {
+            Error::SocketBroken(e)
+        }
+        _ => Error::SocketError(e),
+    }
+}
+
+fn as_bytes<T: VhostUserMsgPlain>(msg: &T) -> &[u8] {
+    // Safe because the message structures are plain data.
+    unsafe { slice::from_raw_parts(msg as *const T as *const u8, mem::size_of::<T>()) }
+}
+
+fn parse_body<T: VhostUserMsgPlain>(payload: &[u8]) -> Result<T> {
+    if payload.len() != mem::size_of::<T>() {
+        return Err(Error::InvalidMessage);
+    }
+    // Safe because the size of the payload matches the plain data message structure.
+    Ok(unsafe { ptr::read_unaligned(payload.as_ptr() as *const T) })
+}
+
+fn send_msg(
+    sock: &UnixStream,
+    request: GpuReq,
+    flags: u32,
+    body: &[&[u8]],
+    fds: &[RawFd],
+) -> Result<()> {
+    let size: usize = body.iter().map(|buf| buf.len()).sum();
+    if size > VHOST_USER_GPU_MAX_MSG_SIZE {
+        return Err(Error::OversizedMsg);
+    }
+    let mut hdr = [0u8; HEADER_SIZE];
+    hdr[..4].copy_from_slice(&(request as u32).to_ne_bytes());
+    hdr[4..8].copy_from_slice(&flags.to_ne_bytes());
+    hdr[8..].copy_from_slice(&(size as u32).to_ne_bytes());
+    let mut iovs: Vec<&[u8]> = Vec::with_capacity(body.len() + 1);
+    iovs.push(&hdr);
+    iovs.extend_from_slice(body);
+
+    // Framebuffer updates may not be sent at once, the rest of the message is sent without the
+    // file descriptors.
+    let mut sent = sock.send_with_fds(&iovs, fds)?;
+    let mut writer = sock;
+    for buf in iovs {
+        if sent >= buf.len() {
+            sent -= buf.len();
+            continue;
+        }
+        writer.write_all(&buf[sent..]).map_err(io_error)?;
+        sent = 0;
+    }
+    Ok(())
+}
+
+fn recv_msg(sock: &UnixStream) -> Result<GpuMsg> {
+    let mut hdr = [0u8; HEADER_SIZE];
+    let mut fds = [-1; MAX_ATTACHED_FD_ENTRIES];
+    let (bytes, num_fds) = {
+        let mut iovs = [iovec {
+            iov_base: hdr.as_mut_ptr() as *mut c_void,
+            iov_len: HEADER_SIZE,
+        }];
+        sock.recv_with_fds(&mut iovs, &mut fds)?
+    };
+    // Safe because the file descriptors have just been received, and are owned by us.
+    let files = fds[..num_fds]
+        .iter()
+        .map(|fd| unsafe { File::from_raw_fd(*fd) })
+        .collect();
+    if bytes == 0 {
+        let err = io::Error::from_raw_os_error(libc::ECONNRESET);
+        return Err(Error::SocketBroken(err));
+    }
+    let mut reader = sock;
+    reader.read_exact(&mut hdr[bytes..]).map_err(io_error)?;
+
+    let field = |i: usize| {
+        let mut buf = [0u8; 4];
+        buf.copy_from_slice(&hdr[i * 4..(i + 1) * 4]);
+        u32::from_ne_bytes(buf)
+    };
+    let size = field(2) as usize;
+    if size > VHOST_USER_GPU_MAX_MSG_SIZE {
+        return Err(Error::InvalidMessage);
+    }
+    let mut payload = vec![0u8; size];
+    reader.read_exact(&mut payload).map_err(io_error)?;
+    Ok(GpuMsg {
+        request: field(0),
+        flags: field(1),
+        payload,
+        files,
+    })
+}
+
+struct GpuReqSenderInternal {
+    sock: UnixStream,
+    // Protocol features enabled by SET_PROTOCOL_FEATURES.
+    acked_protocol_features: VhostUserGpuProtocolFeatures,
+    // whether the endpoint has encountered any failure
+    error: Option<i32>,
+}
+
+impl GpuReqSenderInternal {
+    fn check_state(&self) -> Result<()> {
+        match self.error {
+            Some(e) => Err(Error::SocketBroken(io::Error::from_raw_os_error(e))),
+            None => Ok(()),
+        }
+    }
+
+    fn send_request(&mut self, request: GpuReq, body: &[&[u8]], fds: &[RawFd]) -> Result<()> {
+        self.check_state()?;
+        send_msg(&self.sock, request, 0, body, fds)
+    }
+
+    fn wait_for_reply(&mut self, request: GpuReq) -> Result<Vec<u8>> {
+        let msg = recv_msg(&self.sock)?;
+        if msg.request != request as u32
+            || msg.flags != VHOST_USER_GPU_MSG_FLAG_REPLY
+            || !msg.files.is_empty()
+        {
+            return Err(Error::InvalidMessage);
+        }
+        Ok(msg.payload)
+    }
+}
+
+/// Sender of vhost-user-gpu requests from the backend to the frontend.
+///
+/// The sender may be cloned to share the socket between the threads of the backend, requests
+/// are serialized.
+#[derive(Clone)]
+pub struct GpuReqSender {
+    node: Arc<Mutex<GpuReqSenderInternal>>,
+}
+
+impl GpuReqSender {
+    /// Create a sender of requests over `sock`, the socket received with GPU_SET_SOCKET.
+    pub fn from_stream(sock: UnixStream) -> Self {
+        GpuReqSender {
+            node: Arc::new(Mutex::new(GpuReqSenderInternal {
+                sock,
+                acked_protocol_features: VhostUserGpuProtocolFeatures::empty(),
+                error: None,
+            })),
+        }
+    }
+
+    /// Mark endpoint as failed with specified error code.
+    pub fn set_failed(&self, error: i32) {
+        self.node.lock().unwrap().error = Some(error);
+    }
+
+    /// Get the protocol features supported by the frontend.
+    pub fn get_protocol_features(&self) -> Result<VhostUserGpuProtocolFeatures> {
+        let mut node = self.node.lock().unwrap();
+        node.send_request(GpuReq::GET_PROTOCOL_FEATURES, &[], &[])?;
+        let reply = node.wait_for_reply(GpuReq::GET_PROTOCOL_FEATURES)?;
+        let features = parse_body::<VhostUserU64>(&reply)?;
+        Ok(VhostUserGpuProtocolFeatures::from_bits_truncate(
+            features.value,
+        ))
+    }
+
+    /// Enable the protocol `features`, which must be supported by the frontend.
+    pub fn set_protocol_features(&self, features: VhostUserGpuProtocolFeatures) -> Result<()> {
+        let mut node = self.node.lock().unwrap();
+        let bits = features.bits();
+        node.send_request(GpuReq::SET_PROTOCOL_FEATURES, &[&bits.to_ne_bytes()], &[])?;
+        node.acked_protocol_features = features;
+        Ok(())
+    }
+
+    /// Get the preferred display configuration.
+    pub fn get_display_info(&self) -> Result<VhostUserGpuDisplayInfo> {
+        let mut node = self.node.lock().unwrap();
+        node.send_request(GpuReq::GET_DISPLAY_INFO, &[], &[])?;
+        parse_body(&node.wait_for_reply(GpuReq::GET_DISPLAY_INFO)?)
+    }
+
+    /// Get the EDID of the scanout `scanout_id`.
+    ///
+    /// Fails with InvalidOperation unless the EDID protocol feature has been negotiated.
+    pub fn get_edid(&self, scanout_id: u32) -> Result<VhostUserGpuEdid> {
+        let mut node = self.node.lock().unwrap();
+        if !node
+            .acked_protocol_features
+            .contains(VhostUserGpuProtocolFeatures::EDID)
+        {
+            return Err(Error::InvalidOperation);
+        }
+        let req = VhostUserGpuEdidRequest { scanout_id };
+        node.send_request(GpuReq::GET_EDID, &[as_bytes(&req)], &[])?;
+        parse_body(&node.wait_for_reply(GpuReq::GET_EDID)?)
+    }
+
+    /// Move the cursor.
+    pub fn cursor_pos(&self, pos: &VhostUserGpuCursorPos) -> Result<()> {
+        let mut node = self.node.lock().unwrap();
+        node.send_request(GpuReq::CURSOR_POS, &[as_bytes(pos)], &[])
+    }
+
+    /// Hide the cursor.
+    pub fn cursor_pos_hide(&self, pos: &VhostUserGpuCursorPos) -> Result<()> {
+        let mut node = self.node.lock().unwrap();
+        node.send_request(GpuReq::CURSOR_POS_HIDE, &[as_bytes(pos)], &[])
+    }
+
+    /// Set the image and the hotspot of the cursor, and move it.
+    pub fn cursor_update(&self, update: &VhostUserGpuCursorUpdate) -> Result<()> {
+        let mut node = self.node.lock().unwrap();
+        node.send_request(GpuReq::CURSOR_UPDATE, &[as_bytes(update)], &[])
+    }
+
+    /// Set the size of a scanout, or disable it with a zero size.
+    pub fn set_scanout(&self, scanout: &VhostUserGpuScanout) -> Result<()> {
+        let mut node = self.node.lock().unwrap();
+        node.send_request(GpuReq::SCANOUT, &[as_bytes(scanout)], &[])
+    }
+
+    /// Update a rectangle of a scanout with its pixels in `data`.
+    pub fn update_scanout(&self, update: &VhostUserGpuUpdate, data: &[u8]) -> Result<()> {
+        let mut node = self.node.lock().unwrap();
+        node.send_request(GpuReq::UPDATE, &[as_bytes(update), data], &[])
+    }
+
+    /// Display the dmabuf `fd` on a scanout, or disable it if `fd` is None.
+    pub fn set_dmabuf_scanout(
+        &self,
+        scanout: &VhostUserGpuDmabufScanout,
+        fd: Option<RawFd>,
+    ) -> Result<()> {
+        let mut node = self.node.lock().unwrap();
+        let fds: &[RawFd] = match fd {
+            Some(ref fd) => slice::from_ref(fd),
+            None => &[],
+        };
+        node.send_request(GpuReq::DMABUF_SCANOUT, &[as_bytes(scanout)], fds)
+    }
+
+    /// Display the dmabuf `fd` with a format modifier on a scanout, or disable it if `fd` is
+    /// None.
+    ///
+    /// Fails with InvalidOperation unless the DMABUF2 protocol feature has been negotiated.
+    pub fn set_dmabuf_scanout2(
+        &self,
+        scanout: &VhostUserGpuDmabufScanout2,
+        fd: Option<RawFd>,
+    ) -> Result<()> {
+        let mut node = self.node.lock().unwrap();
+        if !node
+            .acked_protocol_features
+            .contains(VhostUserGpuProtocolFeatures::DMABUF2)
+        {
+            return Err(Error::InvalidOperation);
+        }
+        let fds: &[RawFd] = match fd {
+            Some(ref fd) => slice::from_ref(fd),
+            None => &[],
+        };
+        node.send_request(GpuReq::DMABUF_SCANOUT2, &[as_bytes(scanout)], fds)
+    }
+
+    /// Notify that a rectangle of the dmabuf displayed by a scanout has been updated, and wait
+    /// for the frontend to render it before returning.
+    pub fn update_dmabuf(&self, update: &VhostUserGpuUpdate) -> Result<()> {
+        let mut node = self.node.lock().unwrap();
+        node.send_request(GpuReq::DMABUF_UPDATE, &[as_bytes(update)], &[])?;
+        if !node.wait_for_reply(GpuReq::DMABUF_UPDATE)?.is_empty() {
+            return Err(Error::InvalidMessage);
+        }
+        Ok(())
+    }
+}
+
+/// A vhost-user-gpu request endpoint which relays all requests received from the backend to the
+/// provided request handler.
+pub struct GpuReqHandler<S: VhostUserGpuReqHandler> {
+    // underlying Unix domain socket for communication
+    sock: UnixStream,
+    tx_sock: UnixStream,
+    // the GPU frontend
+    backend: Arc<Mutex<S>>,
+    // Protocol features enabled by SET_PROTOCOL_FEATURES.
+    acked_protocol_features: VhostUserGpuProtocolFeatures,
+    // whether the endpoint has encountered any failure
+    error: Option<i32>,
+}
+
+impl<S: VhostUserGpuReqHandler> GpuReqHandler<S> {
+    /// Create a vhost-user-gpu request handler.
+    /// This opens a pair of connected anonymous sockets.
+    /// Returns Self and the socket that must be sent to the backend via GPU_SET_SOCKET.
+    pub fn new(backend: Arc<Mutex<S>>) -> Result<Self> {
+        let (tx, rx) = UnixStream::pair().map_err(Error::SocketError)?;
+
+        Ok(GpuReqHandler {
+            sock: rx,
+            tx_sock: tx,
+            backend,
+            acked_protocol_features: VhostUserGpuProtocolFeatures::empty(),
+            error: None,
+        })
+    }
+
+    /// Get the raw fd to send to the backend as GPU communication channel.
+    pub fn get_tx_raw_fd(&self) -> RawFd {
+        self.tx_sock.as_raw_fd()
+    }
+
+    /// Get the protocol features negotiated with the backend.
+    pub fn acked_protocol_features(&self) -> VhostUserGpuProtocolFeatures {
+        self.acked_protocol_features
+    }
+
+    /// Mark endpoint as failed with specified error code.
+    pub fn set_failed(&mut self, error: i32) {
+        self.error = Some(error);
+    }
+
+    /// Receive and handle one incoming request message from the backend.
+    /// The caller needs to:
+    /// . serialize calls to this function
+    /// . decide what to do when error happens
+    /// . optional recover from failure
+    pub fn handle_request(&mut self) -> Result<()> {
+        // Return error if the endpoint is already in failed state.
+        if let Some(e) = self.error {
+            return Err(Error::SocketBroken(io::Error::from_raw_os_error(e)));
+        }
+
+        let msg = recv_msg(&self.sock)?;
+        let code = match GpuReq::from_code(msg.request) {
+            Some(code) if msg.flags == 0 => code,
+            _ => return Err(Error::InvalidMessage),
+        };
+        let mut files = msg.files;
+        let payload = msg.payload;
+        let fd = match code {
+            GpuReq::DMABUF_SCANOUT | GpuReq::DMABUF_SCANOUT2 if files.len() <= 1 => files.pop(),
+            _ if files.is_empty() => None,
+            _ => return Err(Error::IncorrectFds),
+        };
+
+        let mut backend = self.backend.lock().unwrap();
+        match code {
+            GpuReq::GET_PROTOCOL_FEATURES => {
+                Self::check_empty(&payload)?;
+                let features = backend
+                    .get_protocol_features()
+                    .map_err(Error::ReqHandlerError)?;
+                Self::send_reply(&self.sock, code, &features.bits().to_ne_bytes())
+            }
+            GpuReq::SET_PROTOCOL_FEATURES => {
+                let bits = parse_body::<VhostUserU64>(&payload)?;
+                let features = match VhostUserGpuProtocolFeatures::from_bits(bits.value) {
+                    Some(features) => features,
+                    None => return Err(Error::InvalidMessage),
+                };
+                backend
+                    .set_protocol_features(features)
+                    .map_err(Error::ReqHandlerError)?;
+                self.acked_protocol_features = features;
+                Ok(())
+            }
+            GpuReq::GET_DISPLAY_INFO => {
+                Self::check_empty(&payload)?;
+                let info = backend.get_display_info().map_err(Error::ReqHandlerError)?;
+                Self::send_reply(&self.sock, code, as_bytes(&info))
+            }
+            GpuReq::GET_EDID => {
+                self.check_feature(VhostUserGpuProtocolFeatures::EDID)?;
+                let req = parse_body::<VhostUserGpuEdidRequest>(&payload)?;
+                let edid = backend.get_edid(&req).map_err(Error::ReqHandlerError)?;
+                Self::send_reply(&self.sock, code, as_bytes(&edid))
+            }
+            GpuReq::CURSOR_POS => backend
+                .cursor_pos(&parse_body(&payload)?)
+                .map_err(Error::ReqHandlerError),
+            GpuReq::CURSOR_POS_HIDE => backend
+                .cursor_pos_hide(&parse_body(&payload)?)
+                .map_err(Error::ReqHandlerError),
+            GpuReq::CURSOR_UPDATE => backend
+                .cursor_update(&parse_body(&payload)?)
+                .map_err(Error::ReqHandlerError),
+            GpuReq::SCANOUT => backend
+                .set_scanout(&parse_body(&payload)?)
+                .map_err(Error::ReqHandlerError),
+            GpuReq::UPDATE => {
+                let view = match VhostUserMsgView::<VhostUserGpuUpdate, u8>::from_bytes(&payload) {
+                    Some(view) => view,
+                    None => return Err(Error::InvalidMessage),
+                };
+                backend
+                    .update_scanout(view.body(), view.payload())
+                    .map_err(Error::ReqHandlerError)
+            }
+            GpuReq::DMABUF_SCANOUT => backend
+                .set_dmabuf_scanout(&parse_body(&payload)?, fd)
+                .map_err(Error::ReqHandlerError),
+            GpuReq::DMABUF_SCANOUT2 => {
+                self.check_feature(VhostUserGpuProtocolFeatures::DMABUF2)?;
+                backend
+                    .set_dmabuf_scanout2(&parse_body(&payload)?, fd)
+                    .map_err(Error::ReqHandlerError)
+            }
+            GpuReq::DMABUF_UPDATE => {
+                let update = parse_body::<VhostUserGpuUpdate>(&payload)?;
+                let res = backend
+                    .update_dmabuf(&update)
+                    .map_err(Error::ReqHandlerError);
+                // The backend waits for the reply before reusing the dmabuf, whatever the
+                // outcome of the rendering.
+                Self::send_reply(&self.sock, code, &[])?;
+                res
+            }
+            _ => Err(Error::InvalidMessage),
+        }
+    }
+
+    fn check_empty(payload: &[u8]) -> Result<()> {
+        if !payload.is_empty() {
+            return Err(Error::InvalidMessage);
+        }
+        Ok(())
+    }
+
+    fn check_feature(&self, feature: VhostUserGpuProtocolFeatures) -> Result<()> {
+        if !self.acked_protocol_features.contains(feature) {
+            return Err(Error::InvalidOperation);
+        }
+        Ok(())
+    }
+
+    fn send_reply(sock: &UnixStream, code: GpuReq, payload: &[u8]) -> Result<()> {
+        send_msg(sock, code, VHOST_USER_GPU_MSG_FLAG_REPLY, &[payload], &[])
+    }
+}
+
+impl<S: VhostUserGpuReqHandler> AsRawFd for GpuReqHandler<S> {
+    fn as_raw_fd(&self) -> RawFd {
+        self.sock.as_raw_fd()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    #[derive(Default)]
+    struct Display {
+        features: u64,
+        cursor: Option<VhostUserGpuCursorPos>,
+        scanout: Option<VhostUserGpuScanout>,
+        pixels: Vec<u8>,
+        dmabuf: Option<(VhostUserGpuDmabufScanout, bool)>,
+        dmabuf_updates: usize,
+    }
+
+    impl VhostUserGpuReqHandler for Display {
+        fn get_protocol_features(&mut self) -> HandlerResult<VhostUserGpuProtocolFeatures> {
+            Ok(VhostUserGpuProtocolFeatures::EDID)
+        }
+
+        fn set_protocol_features(
+            &mut self,
+            features: VhostUserGpuProtocolFeatures,
+        ) -> HandlerResult<()> {
+            self.features = features.bits();
+            Ok(())
+        }
+
+        fn get_display_info(&mut self) -> HandlerResult<VhostUserGpuDisplayInfo> {
+            let mut info = VhostUserGpuDisplayInfo::default();
+            info.pmodes[0].r.width = 1024;
+            info.pmodes[0].r.height = 768;
+            info.pmodes[0].enabled = 1;
+            Ok(info)
+        }
+
+        fn get_edid(&mut self, req: &VhostUserGpuEdidRequest) -> HandlerResult<VhostUserGpuEdid> {
+            let mut edid = VhostUserGpuEdid {
+                size: 128,
+                ..Default::default()
+            };
+            edid.edid[0] = req.scanout_id as u8 + 1;
+            Ok(edid)
+        }
+
+        fn cursor_pos(&mut self, pos: &VhostUserGpuCursorPos) -> HandlerResult<()> {
+            self.cursor = Some(*pos);
+            Ok(())
+        }
+
+        fn set_scanout(&mut self, scanout: &VhostUserGpuScanout) -> HandlerResult<()> {
+            self.scanout = Some(*scanout);
+            Ok(())
+        }
+
+        fn update_scanout(
+            &mut self,
+            _update: &VhostUserGpuUpdate,
+            data: &[u8],
+        ) -> HandlerResult<()> {
+            self.pixels = data.to_vec();
+            Ok(())
+        }
+
+        fn set_dmabuf_scanout(
+            &mut self,
+            scanout: &VhostUserGpuDmabufScanout,
+            fd: Option<File>,
+        ) -> HandlerResult<()> {
+            self.dmabuf = Some((*scanout, fd.is_some()));
+            Ok(())
+        }
+
+        fn update_dmabuf(&mut self, _update: &VhostUserGpuUpdate) -> HandlerResult<()> {
+            self.dmabuf_updates += 1;
+            Ok(())
+        }
+    }
+
+    fn handler() -> (GpuReqSender, GpuReqHandler<Display>, Arc<Mutex<Display>>) {
+        let display = Arc::new(Mutex::new(Display::default()));
+        let handler = GpuReqHandler::new(display.clone()).unwrap();
+        let sock = handler.tx_sock.try_clone().unwrap();
+        (GpuReqSender::from_stream(sock), handler, display)
+    }
+
+    #[test]
+    fn test_gpu_requests() {
+        let (sender, mut handler, display) = handler();
+        // The requests which aren't sent, as the features haven't been negotiated, aren't counted.
+        let frontend = thread::spawn(move || {
+            for _ in 0..9 {
+                handler.handle_request().unwrap();
+            }
+            handler.acked_protocol_features()
+        });
+
+        let features = sender.get_protocol_features().unwrap();
+        assert_eq!(features, VhostUserGpuProtocolFeatures::EDID);
+        match sender.get_edid(0) {
+            Err(Error::InvalidOperation) => {}
+            _ => panic!("EDID requested without negotiating it"),
+        }
+        sender.set_protocol_features(features).unwrap();
+        assert_eq!(sender.get_edid(1).unwrap().edid[0], 2);
+
+        let info = sender.get_display_info().unwrap();
+        assert_eq!({ info.pmodes[0].r.width }, 1024);
+        assert_eq!({ info.pmodes[1].enabled }, 0);
+
+        let pos = VhostUserGpuCursorPos {
+            scanout_id: 0,
+            x: 10,
+            y: 20,
+        };
+        sender.cursor_pos(&pos).unwrap();
+        let scanout = VhostUserGpuScanout {
+            scanout_id: 0,
+            width: 64,
+            height: 64,
+        };
+        sender.set_scanout(&scanout).unwrap();
+        // Bigger than the socket buffers, so the pixels are sent in several chunks.
+        let pixels: Vec<u8> = (0..0x40_0000).map(|i| i as u8).collect();
+        let update = VhostUserGpuUpdate {
+            scanout_id: 0,
+            x: 0,
+            y: 0,
+            width: 1024,
+            height: 1024,
+        };
+        sender.update_scanout(&update, &pixels).unwrap();
+
+        let dmabuf = File::open("/dev/null").unwrap();
+        let dmabuf_scanout = VhostUserGpuDmabufScanout {
+            scanout_id: 0,
+            width: 64,
+            height: 64,
+            ..Default::default()
+        };
+        sender
+            .set_dmabuf_scanout(&dmabuf_scanout, Some(dmabuf.as_raw_fd()))
+            .unwrap();
+        sender.update_dmabuf(&update).unwrap();
+        match sender.set_dmabuf_scanout2(&VhostUserGpuDmabufScanout2::default(), None) {
+            Err(Error::InvalidOperation) => {}
+            _ => panic!("DMABUF2 used without negotiating it"),
+        }
+
+        assert_eq!(frontend.join().unwrap(), VhostUserGpuProtocolFeatures::EDID);
+        let display = display.lock().unwrap();
+        assert_eq!(display.features, VhostUserGpuProtocolFeatures::EDID.bits());
+        assert_eq!(display.cursor, Some(pos));
+        assert_eq!(display.scanout, Some(scanout));
+        assert_eq!(display.pixels, pixels);
+        assert_eq!(display.dmabuf, Some((dmabuf_scanout, true)));
+        assert_eq!(display.dmabuf_updates, 1);
+    }
+
+    #[test]
+    fn test_gpu_invalid_requests() {
+        let (sender, mut handler, _) = handler();
+        let sock = sender.node.lock().unwrap().sock.try_clone().unwrap();
+
+        // Requests must not be flagged as replies.
+        send_msg(
+            &sock,
+            GpuReq::CURSOR_POS,
+            VHOST_USER_GPU_MSG_FLAG_REPLY,
+            &[],
+            &[],
+        )
+        .unwrap();
+        match handler.handle_request() {
+            Err(Error::InvalidMessage) => {}
+            _ => panic!("reply handled as a request"),
+        }
+
+        // The payload must match the request.
+        send_msg(&sock, GpuReq::SCANOUT, 0, &[&[0u8; 4]], &[]).unwrap();
+        match handler.handle_request() {
+            Err(Error::InvalidMessage) => {}
+            _ => panic!("truncated request handled"),
+        }
+
+        // Only dmabufs are attached to requests.
+        let file = File::open("/dev/null").unwrap();
+        let pos = VhostUserGpuCursorPos::default();
+        send_msg(
+            &sock,
+            GpuReq::CURSOR_POS,
+            0,
+            &[as_bytes(&pos)],
+            &[file.as_raw_fd()],
+        )
+        .unwrap();
+        match handler.handle_request() {
+            Err(Error::IncorrectFds) => {}
+            _ => panic!("file descriptor accepted"),
+        }
+
+        // Requests not implemented by the frontend fail.
+        sender.cursor_pos_hide(&pos).unwrap();
+        match handler.handle_request() {
+            Err(Error::ReqHandlerError(e)) => assert_eq!(e.raw_os_error(), Some(libc::ENOSYS)),
+            _ => panic!("unimplemented request handled"),
+        }
+    }
+}
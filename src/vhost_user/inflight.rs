@@ -0,0 +1,440 @@
+// Copyright (C) 2020 Alibaba Cloud Computing. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Shared memory buffer to track inflight I/O requests of split virtqueues.
+//!
+//! When the VHOST_USER_PROTOCOL_F_INFLIGHT_SHMFD protocol feature has been negotiated, the slave
+//! allocates a shared memory buffer on GET_INFLIGHT_FD and records which descriptors have been
+//! fetched from the available ring but not yet put into the used ring. The master keeps the file
+//! descriptor and sends it back with SET_INFLIGHT_FD after the slave restarts, so the slave can
+//! resubmit those descriptors instead of losing them.
+//!
+//! The buffer layout follows the one used by QEMU and libvhost-user: each queue owns a region
+//! starting with a QueueRegionSplit header followed by an array of DescStateSplit entries, and
+//! every queue region is padded to INFLIGHT_ALIGNMENT bytes.
+
+use std::ffi::CString;
+use std::fs::File;
+use std::io::{Error as IOError, Result as IOResult};
+use std::mem;
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::ptr::{self, null_mut};
+use std::sync::atomic::{fence, Ordering};
+
+use libc;
+
+use super::message::{DescStateSplit, QueueRegionSplit, VhostUserInflight};
+
+/// Alignment of the per queue regions in the inflight buffer.
+pub const INFLIGHT_ALIGNMENT: usize = 64;
+
+/// Version of the inflight buffer format.
+pub const INFLIGHT_VERSION: u16 = 1;
+
+// Offsets of fields in QueueRegionSplit.
+const QUEUE_FEATURES_OFFSET: usize = 0;
+const QUEUE_VERSION_OFFSET: usize = 8;
+const QUEUE_DESC_NUM_OFFSET: usize = 10;
+const QUEUE_LAST_BATCH_HEAD_OFFSET: usize = 12;
+const QUEUE_USED_IDX_OFFSET: usize = 14;
+// Offsets of fields in DescStateSplit.
+const DESC_INFLIGHT_OFFSET: usize = 0;
+const DESC_COUNTER_OFFSET: usize = 8;
+
+/// Shared memory buffer for inflight I/O tracking.
+pub struct InflightRegion {
+    file: File,
+    addr: *mut u8,
+    mmap_size: usize,
+    mmap_offset: u64,
+    num_queues: u16,
+    queue_size: u16,
+    counters: Vec<u64>,
+}
+
+// It's safe because the mapping is owned by the InflightRegion object and all accesses to it go
+// through `&self` or `&mut self`.
+unsafe impl Send for InflightRegion {}
+
+impl InflightRegion {
+    /// Get size of the inflight region for a queue with `queue_size` descriptors.
+    pub fn queue_region_size(queue_size: u16) -> usize {
+        let size = mem::size_of::<DescStateSplit>() * queue_size as usize + mem::size_of::<u16>();
+        (size + INFLIGHT_ALIGNMENT - 1) & !(INFLIGHT_ALIGNMENT - 1)
+    }
+
+    /// Allocate a zeroed inflight buffer for `num_queues` queues with `queue_size` descriptors
+    /// each, backed by an anonymous memfd which may be shared with the master.
+    pub fn new(num_queues: u16, queue_size: u16) -> IOResult<Self> {
+        if num_queues == 0 || queue_size == 0 {
+            return Err(IOError::from_raw_os_error(libc::EINVAL));
+        }
+        let mmap_size = Self::queue_region_size(queue_size) * num_queues as usize;
+
+        let name = CString::new("vhost-user-inflight").unwrap();
+        // Safe because we check the return value.
+        let fd = unsafe { libc::memfd_create(name.as_ptr(), libc::MFD_CLOEXEC) };
+        if fd < 0 {
+            return Err(IOError::last_os_error());
+        }
+        // Safe because we have just created the fd and nobody else owns it.
+        let file = unsafe { File::from_raw_fd(fd) };
+        file.set_len(mmap_size as u64)?;
+
+        let mut region = Self::map(file, mmap_size, 0, num_queues, queue_size)?;
+        for index in 0..num_queues {
+            region.queue_mut(index).unwrap().init(0);
+        }
+        Ok(region)
+    }
+
+    /// Map an inflight buffer received from the master by the SET_INFLIGHT_FD request.
+    ///
+    /// Note: the queue regions are not initialized, so inflight descriptors recorded by the
+    /// previous slave instance are preserved.
+    pub fn from_file(file: File, inflight: &VhostUserInflight) -> IOResult<Self> {
+        let mmap_size = inflight.mmap_size as usize;
+        let num_queues = inflight.num_queues;
+        let queue_size = inflight.queue_size;
+        if num_queues == 0
+            || queue_size == 0
+            || mmap_size < Self::queue_region_size(queue_size) * num_queues as usize
+        {
+            return Err(IOError::from_raw_os_error(libc::EINVAL));
+        }
+        let file_size = file.metadata()?.len();
+        match inflight.mmap_offset.checked_add(inflight.mmap_size) {
+            Some(end) if end <= file_size => {}
+            _ => return Err(IOError::from_raw_os_error(libc::EINVAL)),
+        }
+
+        let mut region = Self::map(
+            file,
+            mmap_size,
+            inflight.mmap_offset,
+            num_queues,
+            queue_size,
+        )?;
+        for index in 0..num_queues as usize {
+            region.counters[index] = region.max_counter(index) + 1;
+        }
+        Ok(region)
+    }
+
+    fn map(
+        file: File,
+        mmap_size: usize,
+        mmap_offset: u64,
+        num_queues: u16,
+        queue_size: u16,
+    ) -> IOResult<Self> {
+        // Safe because we check the return value and the mapping is released on drop.
+        let addr = unsafe {
+            libc::mmap(
+                null_mut(),
+                mmap_size,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_SHARED,
+                file.as_raw_fd(),
+                mmap_offset as libc::off_t,
+            )
+        };
+        if addr == libc::MAP_FAILED {
+            return Err(IOError::last_os_error());
+        }
+
+        Ok(InflightRegion {
+            file,
+            addr: addr as *mut u8,
+            mmap_size,
+            mmap_offset,
+            num_queues,
+            queue_size,
+            counters: vec![1; num_queues as usize],
+        })
+    }
+
+    /// Get the file backing the inflight buffer.
+    pub fn file(&self) -> &File {
+        &self.file
+    }
+
+    /// Get the message describing the inflight buffer, to be sent with GET_INFLIGHT_FD replies.
+    pub fn inflight(&self) -> VhostUserInflight {
+        VhostUserInflight::new(
+            self.mmap_size as u64,
+            self.mmap_offset,
+            self.num_queues,
+            self.queue_size,
+        )
+    }
+
+    /// Get number of queues covered by the inflight buffer.
+    pub fn num_queues(&self) -> u16 {
+        self.num_queues
+    }
+
+    /// Get number of descriptors per queue covered by the inflight buffer.
+    pub fn queue_size(&self) -> u16 {
+        self.queue_size
+    }
+
+    /// Get an accessor for the inflight region of queue `index`.
+    pub fn queue_mut(&mut self, index: u16) -> Option<InflightQueue<'_>> {
+        if index >= self.num_queues {
+            return None;
+        }
+        let offset = Self::queue_region_size(self.queue_size) * index as usize;
+        Some(InflightQueue {
+            // Safe because the offset is within the mapped area.
+            base: unsafe { self.addr.add(offset) },
+            queue_size: self.queue_size,
+            counter: &mut self.counters[index as usize],
+        })
+    }
+
+    fn max_counter(&self, index: usize) -> u64 {
+        let base = Self::queue_region_size(self.queue_size) * index;
+        (0..self.queue_size as usize)
+            .map(|head| {
+                let desc = base
+                    + mem::size_of::<QueueRegionSplit>()
+                    + head * mem::size_of::<DescStateSplit>();
+                // Safe because the offset is within the mapped area and naturally aligned.
+                unsafe {
+                    ptr::read_volatile(self.addr.add(desc + DESC_COUNTER_OFFSET) as *const u64)
+                }
+            })
+            .max()
+            .unwrap_or(0)
+    }
+}
+
+impl AsRawFd for InflightRegion {
+    fn as_raw_fd(&self) -> RawFd {
+        self.file.as_raw_fd()
+    }
+}
+
+impl Drop for InflightRegion {
+    fn drop(&mut self) {
+        // Safe because the area has been mapped by ourself.
+        unsafe { libc::munmap(self.addr as *mut libc::c_void, self.mmap_size) };
+    }
+}
+
+/// Accessor for the inflight region of a split virtqueue.
+///
+/// All updates are done with volatile accesses and ordered by memory fences, so the region stays
+/// consistent for the next slave instance even if the current one crashes at any point.
+pub struct InflightQueue<'a> {
+    base: *mut u8,
+    queue_size: u16,
+    counter: &'a mut u64,
+}
+
+impl<'a> InflightQueue<'a> {
+    /// Initialize the queue region if it hasn't been initialized yet.
+    pub fn init(&mut self, features: u64) {
+        if self.version() == 0 {
+            self.write(QUEUE_FEATURES_OFFSET, features);
+            self.write(QUEUE_DESC_NUM_OFFSET, self.queue_size);
+            fence(Ordering::Release);
+            self.write(QUEUE_VERSION_OFFSET, INFLIGHT_VERSION);
+        }
+    }
+
+    /// Get features recorded for the queue.
+    pub fn features(&self) -> u64 {
+        self.read(QUEUE_FEATURES_OFFSET)
+    }
+
+    /// Get version of the queue region, zero means the region hasn't been initialized.
+    pub fn version(&self) -> u16 {
+        self.read(QUEUE_VERSION_OFFSET)
+    }
+
+    /// Get number of descriptors tracked by the queue region.
+    pub fn desc_num(&self) -> u16 {
+        self.read(QUEUE_DESC_NUM_OFFSET)
+    }
+
+    /// Get the shadow used ring index.
+    pub fn used_idx(&self) -> u16 {
+        self.read(QUEUE_USED_IDX_OFFSET)
+    }
+
+    /// Check whether descriptor `head` is inflight.
+    pub fn is_inflight(&self, head: u16) -> bool {
+        head < self.queue_size
+            && self.read::<u8>(self.desc_offset(head) + DESC_INFLIGHT_OFFSET) != 0
+    }
+
+    /// Mark descriptor `head` as inflight after fetching it from the available ring.
+    pub fn get(&mut self, head: u16) -> bool {
+        if head >= self.queue_size {
+            return false;
+        }
+        let desc = self.desc_offset(head);
+        self.write(desc + DESC_COUNTER_OFFSET, *self.counter);
+        *self.counter += 1;
+        self.write(desc + DESC_INFLIGHT_OFFSET, 1u8);
+        true
+    }
+
+    /// Record descriptor `head` as the head of the batch about to be put into the used ring.
+    pub fn pre_put(&mut self, head: u16) -> bool {
+        if head >= self.queue_size {
+            return false;
+        }
+        self.write(QUEUE_LAST_BATCH_HEAD_OFFSET, head);
+        true
+    }
+
+    /// Clear the inflight state of descriptor `head` after the used ring index has been updated
+    /// to `used_idx`.
+    pub fn post_put(&mut self, head: u16, used_idx: u16) -> bool {
+        if head >= self.queue_size {
+            return false;
+        }
+        self.write(self.desc_offset(head) + DESC_INFLIGHT_OFFSET, 0u8);
+        fence(Ordering::Release);
+        self.write(QUEUE_USED_IDX_OFFSET, used_idx);
+        true
+    }
+
+    /// Recover the queue region after reconnecting and get inflight descriptors to resubmit,
+    /// sorted by their original submission order.
+    ///
+    /// # Arguments
+    /// * `used_idx` - current index of the used ring in guest memory.
+    pub fn recover(&mut self, used_idx: u16) -> Vec<u16> {
+        if self.version() == 0 {
+            self.init(0);
+            return Vec::new();
+        }
+
+        // The previous instance may have crashed after updating the used ring but before
+        // clearing the inflight state of the last batch.
+        if self.used_idx() != used_idx {
+            let head = self.read::<u16>(QUEUE_LAST_BATCH_HEAD_OFFSET);
+            if head < self.queue_size {
+                self.write(self.desc_offset(head) + DESC_INFLIGHT_OFFSET, 0u8);
+            }
+            fence(Ordering::Release);
+            self.write(QUEUE_USED_IDX_OFFSET, used_idx);
+        }
+
+        let mut pending: Vec<(u64, u16)> = (0..self.queue_size)
+            .filter(|head| self.is_inflight(*head))
+            .map(|head| {
+                (
+                    self.read(self.desc_offset(head) + DESC_COUNTER_OFFSET),
+                    head,
+                )
+            })
+            .collect();
+        pending.sort();
+        pending.into_iter().map(|(_, head)| head).collect()
+    }
+
+    fn desc_offset(&self, head: u16) -> usize {
+        mem::size_of::<QueueRegionSplit>() + head as usize * mem::size_of::<DescStateSplit>()
+    }
+
+    fn read<T: Copy>(&self, offset: usize) -> T {
+        // Safe because all offsets are within the queue region and naturally aligned.
+        unsafe { ptr::read_volatile(self.base.add(offset) as *const T) }
+    }
+
+    fn write<T: Copy>(&mut self, offset: usize, val: T) {
+        // Safe because all offsets are within the queue region and naturally aligned.
+        unsafe { ptr::write_volatile(self.base.add(offset) as *mut T, val) }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_queue_region_size() {
+        assert_eq!(InflightRegion::queue_region_size(1), 64);
+        assert_eq!(InflightRegion::queue_region_size(3), 64);
+        assert_eq!(InflightRegion::queue_region_size(4), 128);
+        assert_eq!(InflightRegion::queue_region_size(256), 4160);
+    }
+
+    #[test]
+    fn test_create_inflight_region() {
+        assert!(InflightRegion::new(0, 256).is_err());
+        assert!(InflightRegion::new(1, 0).is_err());
+
+        let mut region = InflightRegion::new(2, 256).unwrap();
+        let inflight = region.inflight();
+        assert_eq!({ inflight.mmap_size }, 2 * 4160);
+        assert_eq!({ inflight.mmap_offset }, 0);
+        assert_eq!({ inflight.num_queues }, 2);
+        assert_eq!({ inflight.queue_size }, 256);
+        assert_eq!(region.file().metadata().unwrap().len(), 2 * 4160);
+
+        let queue = region.queue_mut(1).unwrap();
+        assert_eq!(queue.version(), INFLIGHT_VERSION);
+        assert_eq!(queue.desc_num(), 256);
+        assert_eq!(queue.used_idx(), 0);
+        assert!(region.queue_mut(2).is_none());
+    }
+
+    #[test]
+    fn test_inflight_tracking() {
+        let mut region = InflightRegion::new(1, 16).unwrap();
+        {
+            let mut queue = region.queue_mut(0).unwrap();
+            assert!(queue.get(3));
+            assert!(queue.get(1));
+            assert!(queue.get(7));
+            assert!(!queue.get(16));
+            assert!(queue.is_inflight(3));
+            assert!(!queue.is_inflight(2));
+
+            assert!(queue.pre_put(1));
+            assert!(queue.post_put(1, 1));
+            assert!(!queue.is_inflight(1));
+            assert_eq!(queue.used_idx(), 1);
+        }
+
+        // Simulate a crash after the used ring has been updated for descriptor 7.
+        {
+            let mut queue = region.queue_mut(0).unwrap();
+            assert!(queue.pre_put(7));
+        }
+
+        let file = region.file().try_clone().unwrap();
+        let inflight = region.inflight();
+        drop(region);
+
+        let mut region = InflightRegion::from_file(file, &inflight).unwrap();
+        let mut queue = region.queue_mut(0).unwrap();
+        assert_eq!(queue.recover(2), vec![3]);
+        assert_eq!(queue.used_idx(), 2);
+
+        // The submission counter continues from the previous instance.
+        assert!(queue.get(5));
+        assert_eq!(queue.recover(2), vec![3, 5]);
+    }
+
+    #[test]
+    fn test_invalid_inflight_file() {
+        let region = InflightRegion::new(1, 16).unwrap();
+        let file = region.file().try_clone().unwrap();
+
+        let mut inflight = region.inflight();
+        inflight.mmap_size = 32;
+        assert!(InflightRegion::from_file(file.try_clone().unwrap(), &inflight).is_err());
+        let mut inflight = region.inflight();
+        inflight.mmap_offset = 0x1000;
+        assert!(InflightRegion::from_file(file.try_clone().unwrap(), &inflight).is_err());
+        let inflight = region.inflight();
+        assert!(InflightRegion::from_file(file, &inflight).is_ok());
+    }
+}
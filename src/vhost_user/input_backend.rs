@@ -0,0 +1,863 @@
+// Copyright (C) 2020 Alibaba Cloud Computing. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! A vhost-user-input slave backend built on the daemon framework.
+//!
+//! The device passes the events of an evdev input device, such as `/dev/input/event0`, through
+//! to the guest on the event virtqueue, and forwards the events sent by the guest on the status
+//! virtqueue, like LED updates, back to the evdev device. Both virtqueues are served by a single
+//! worker thread, which also waits for incoming events: backends must be registered to the
+//! worker thread with `InputBackend::register_device()` before the daemon is started.
+//!
+//! The name, the serial number, the IDs and the supported events of the evdev device are exposed
+//! through the select and subsel fields of the device configuration space.
+
+use std::cmp;
+use std::collections::BTreeMap;
+use std::ffi::CString;
+use std::fs::File;
+use std::io;
+use std::mem;
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::sync::{Arc, RwLock};
+
+use vm_memory::{Bytes, GuestMemoryMmap};
+use vmm_sys_util::epoll::EventSet;
+use vmm_sys_util::ioctl::{ioctl_with_mut_ref, ioctl_with_ptr, ioctl_with_val};
+
+use super::daemon::{
+    read_config_space, DescriptorChain, VhostUserBackend, Vring, VringEpollHandler,
+};
+use super::message::{VhostUserProtocolFeatures, VHOST_USER_CONFIG_OFFSET};
+use crate::features::VirtioFeatures;
+
+/// No configuration is selected.
+pub const VIRTIO_INPUT_CFG_UNSET: u8 = 0x00;
+/// Name of the device, as a string.
+pub const VIRTIO_INPUT_CFG_ID_NAME: u8 = 0x01;
+/// Serial number of the device, as a string.
+pub const VIRTIO_INPUT_CFG_ID_SERIAL: u8 = 0x02;
+/// Bus type, vendor, product and version IDs of the device.
+pub const VIRTIO_INPUT_CFG_ID_DEVIDS: u8 = 0x03;
+/// Bitmap of the input properties of the device.
+pub const VIRTIO_INPUT_CFG_PROP_BITS: u8 = 0x10;
+/// Bitmap of the event codes supported for the event type in subsel.
+pub const VIRTIO_INPUT_CFG_EV_BITS: u8 = 0x11;
+/// Range of the absolute axis in subsel.
+pub const VIRTIO_INPUT_CFG_ABS_INFO: u8 = 0x12;
+
+/// Size of the events exchanged on the virtqueues, struct virtio_input_event.
+pub const VIRTIO_INPUT_EVENT_SIZE: usize = 8;
+
+// Size of the virtio-input configuration space: select, subsel, size, padding and the 128 byte
+// union of the selected configuration.
+const VIRTIO_INPUT_CONFIG_SIZE: usize = 136;
+const CONFIG_DATA_OFFSET: usize = 8;
+const CONFIG_DATA_SIZE: usize = 128;
+// Size of the virtqueues.
+const QUEUE_SIZE: usize = 64;
+// Indexes of the event and status virtqueues.
+const EVENT_QUEUE: u16 = 0;
+const STATUS_QUEUE: u16 = 1;
+
+// Event types, properties and axes of the Linux input subsystem.
+const EV_SYN: u8 = 0x00;
+const EV_ABS: u8 = 0x03;
+const EV_MAX: u8 = 0x1f;
+const ABS_MAX: u8 = 0x3f;
+
+// evdev ioctls.
+mod evdev_ioctls {
+    use std::os::raw::{c_int, c_uint};
+
+    use vmm_sys_util::ioctl::_IOC_READ;
+
+    use super::{InputAbsInfo, InputIds};
+
+    const EVDEV: c_uint = 0x45;
+    ioctl_ior_nr!(EVIOCGID, EVDEV, 0x02, InputIds);
+    ioctl_ioc_nr!(EVIOCGNAME, _IOC_READ, EVDEV, 0x06, len, len);
+    ioctl_ioc_nr!(EVIOCGUNIQ, _IOC_READ, EVDEV, 0x08, len, len);
+    ioctl_ioc_nr!(EVIOCGPROP, _IOC_READ, EVDEV, 0x09, len, len);
+    ioctl_ioc_nr!(EVIOCGBIT, _IOC_READ, EVDEV, 0x20 + ev, len, ev, len);
+    ioctl_ior_nr!(EVIOCGABS, EVDEV, 0x40 + abs, InputAbsInfo, abs);
+    ioctl_iow_nr!(EVIOCGRAB, EVDEV, 0x90, c_int);
+}
+use self::evdev_ioctls::{
+    EVIOCGABS, EVIOCGBIT, EVIOCGID, EVIOCGNAME, EVIOCGPROP, EVIOCGRAB, EVIOCGUNIQ,
+};
+
+/// IDs of an input device, struct input_id.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct InputIds {
+    /// Bus type.
+    pub bustype: u16,
+    /// Vendor ID.
+    pub vendor: u16,
+    /// Product ID.
+    pub product: u16,
+    /// Version.
+    pub version: u16,
+}
+
+/// Range of an absolute axis, struct input_absinfo.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct InputAbsInfo {
+    /// Current value of the axis, not exposed to the guest.
+    pub value: i32,
+    /// Minimum value.
+    pub min: i32,
+    /// Maximum value.
+    pub max: i32,
+    /// Fuzz value filtered out of the event stream.
+    pub fuzz: i32,
+    /// Size of the dead zone.
+    pub flat: i32,
+    /// Resolution of the axis.
+    pub res: i32,
+}
+
+/// Description of an input device, exposed through the configuration space.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct InputConfig {
+    /// Name of the device.
+    pub name: String,
+    /// Serial number of the device.
+    pub serial: String,
+    /// IDs of the device.
+    pub ids: InputIds,
+    /// Bitmap of the input properties.
+    pub properties: Vec<u8>,
+    /// Bitmaps of the supported event codes, indexed by event type.
+    pub events: BTreeMap<u8, Vec<u8>>,
+    /// Ranges of the absolute axes, indexed by axis.
+    pub abs_info: BTreeMap<u8, InputAbsInfo>,
+}
+
+impl InputConfig {
+    // Get the selected configuration.
+    fn select(&self, select: u8, subsel: u8) -> Vec<u8> {
+        let mut data = match select {
+            VIRTIO_INPUT_CFG_ID_NAME if subsel == 0 => self.name.as_bytes().to_vec(),
+            VIRTIO_INPUT_CFG_ID_SERIAL if subsel == 0 => self.serial.as_bytes().to_vec(),
+            VIRTIO_INPUT_CFG_ID_DEVIDS if subsel == 0 => {
+                let ids = [
+                    self.ids.bustype,
+                    self.ids.vendor,
+                    self.ids.product,
+                    self.ids.version,
+                ];
+                ids.iter()
+                    .flat_map(|id| id.to_le_bytes().to_vec())
+                    .collect()
+            }
+            VIRTIO_INPUT_CFG_PROP_BITS if subsel == 0 => self.properties.clone(),
+            VIRTIO_INPUT_CFG_EV_BITS => self.events.get(&subsel).cloned().unwrap_or_default(),
+            VIRTIO_INPUT_CFG_ABS_INFO => match self.abs_info.get(&subsel) {
+                Some(abs) => [abs.min, abs.max, abs.fuzz, abs.flat, abs.res]
+                    .iter()
+                    .flat_map(|v| v.to_le_bytes().to_vec())
+                    .collect(),
+                None => Vec::new(),
+            },
+            _ => Vec::new(),
+        };
+        // Empty bitmaps are reported with a null size.
+        if select == VIRTIO_INPUT_CFG_PROP_BITS || select == VIRTIO_INPUT_CFG_EV_BITS {
+            while data.last() == Some(&0) {
+                data.pop();
+            }
+        }
+        data.truncate(CONFIG_DATA_SIZE);
+        data
+    }
+}
+
+// Same layout as struct input_event.
+#[repr(C)]
+struct InputEvent {
+    time: libc::timeval,
+    type_: u16,
+    code: u16,
+    value: i32,
+}
+
+/// Event device the events of the device are passed through from and to.
+pub struct Evdev {
+    file: File,
+    config: InputConfig,
+}
+
+impl Evdev {
+    /// Open the evdev device at `path` and query its description.
+    ///
+    /// With `grab`, the events of the device are only delivered to the guest, and not to the
+    /// other clients of the device on the host.
+    pub fn open(path: &str, grab: bool) -> io::Result<Self> {
+        let path = CString::new(path).map_err(|_| io::Error::from_raw_os_error(libc::EINVAL))?;
+        // Safe because the path is a valid C string and we check the return value.
+        let fd = unsafe {
+            libc::open(
+                path.as_ptr(),
+                libc::O_RDWR | libc::O_NONBLOCK | libc::O_CLOEXEC,
+            )
+        };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // Safe because we have just opened the file descriptor.
+        let file = unsafe { File::from_raw_fd(fd) };
+        let config = Self::query_config(&file)?;
+        // This ioctl is called on a valid evdev fd and has its return value checked.
+        if grab && unsafe { ioctl_with_val(&file, EVIOCGRAB(), 1) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Evdev { file, config })
+    }
+
+    /// Use any file descriptor transferring one struct input_event per read and write as event
+    /// device, described by `config`.
+    ///
+    /// The file descriptor is switched to non-blocking mode.
+    pub fn from_file(file: File, config: InputConfig) -> io::Result<Self> {
+        // Safe because the file descriptor is valid and we check the return values.
+        let flags = unsafe { libc::fcntl(file.as_raw_fd(), libc::F_GETFL) };
+        if flags < 0
+            || unsafe { libc::fcntl(file.as_raw_fd(), libc::F_SETFL, flags | libc::O_NONBLOCK) } < 0
+        {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Evdev { file, config })
+    }
+
+    /// Get the description of the device.
+    pub fn config(&self) -> &InputConfig {
+        &self.config
+    }
+
+    fn query_config(file: &File) -> io::Result<InputConfig> {
+        let mut config = InputConfig {
+            name: Self::query_string(file, EVIOCGNAME(CONFIG_DATA_SIZE as u32))?,
+            // Devices without serial number fail the request.
+            serial: Self::query_string(file, EVIOCGUNIQ(CONFIG_DATA_SIZE as u32))
+                .unwrap_or_default(),
+            properties: Self::query_bitmap(file, EVIOCGPROP(CONFIG_DATA_SIZE as u32))?,
+            ..Default::default()
+        };
+        // These ioctls are called on a valid evdev fd and have their return value checked.
+        if unsafe { ioctl_with_mut_ref(file, EVIOCGID(), &mut config.ids) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let types = Self::query_bitmap(file, EVIOCGBIT(0, CONFIG_DATA_SIZE as u32))?;
+        for ev in EV_SYN + 1..=EV_MAX {
+            if !Self::test_bit(&types, ev) {
+                continue;
+            }
+            let bits = Self::query_bitmap(file, EVIOCGBIT(u32::from(ev), CONFIG_DATA_SIZE as u32))?;
+            if ev == EV_ABS {
+                for abs in 0..=ABS_MAX {
+                    if !Self::test_bit(&bits, abs) {
+                        continue;
+                    }
+                    let mut info = InputAbsInfo::default();
+                    if unsafe { ioctl_with_mut_ref(file, EVIOCGABS(u32::from(abs)), &mut info) } < 0
+                    {
+                        return Err(io::Error::last_os_error());
+                    }
+                    config.abs_info.insert(abs, info);
+                }
+            }
+            config.events.insert(ev, bits);
+        }
+        Ok(config)
+    }
+
+    fn query_bitmap(file: &File, request: libc::c_ulong) -> io::Result<Vec<u8>> {
+        let mut buf = vec![0u8; CONFIG_DATA_SIZE];
+        // Safe because the buffer is as large as encoded in the request and we check the return
+        // value.
+        let ret = unsafe { ioctl_with_ptr(file, request, buf.as_mut_ptr()) };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        buf.truncate(ret as usize);
+        Ok(buf)
+    }
+
+    fn query_string(file: &File, request: libc::c_ulong) -> io::Result<String> {
+        let mut buf = Self::query_bitmap(file, request)?;
+        if let Some(end) = buf.iter().position(|&b| b == 0) {
+            buf.truncate(end);
+        }
+        Ok(String::from_utf8_lossy(&buf).into_owned())
+    }
+
+    fn test_bit(bitmap: &[u8], bit: u8) -> bool {
+        bitmap
+            .get(bit as usize / 8)
+            .is_some_and(|byte| byte & (1 << (bit % 8)) != 0)
+    }
+
+    // Read an event, as struct virtio_input_event.
+    fn recv(&self) -> io::Result<[u8; VIRTIO_INPUT_EVENT_SIZE]> {
+        // Safe because InputEvent is a plain C structure.
+        let mut event: InputEvent = unsafe { mem::zeroed() };
+        // Safe because the event is valid for its whole size and we check the return value.
+        let ret = unsafe {
+            libc::read(
+                self.file.as_raw_fd(),
+                &mut event as *mut InputEvent as *mut libc::c_void,
+                mem::size_of::<InputEvent>(),
+            )
+        };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        if ret as usize != mem::size_of::<InputEvent>() {
+            return Err(io::Error::from_raw_os_error(libc::EIO));
+        }
+        let mut buf = [0u8; VIRTIO_INPUT_EVENT_SIZE];
+        buf[0..2].copy_from_slice(&event.type_.to_le_bytes());
+        buf[2..4].copy_from_slice(&event.code.to_le_bytes());
+        buf[4..8].copy_from_slice(&event.value.to_le_bytes());
+        Ok(buf)
+    }
+
+    // Write an event given as struct virtio_input_event.
+    fn send(&self, buf: &[u8; VIRTIO_INPUT_EVENT_SIZE]) -> io::Result<()> {
+        // The kernel timestamps the events written to evdev devices.
+        let event = InputEvent {
+            time: libc::timeval {
+                tv_sec: 0,
+                tv_usec: 0,
+            },
+            type_: u16::from_le_bytes([buf[0], buf[1]]),
+            code: u16::from_le_bytes([buf[2], buf[3]]),
+            value: i32::from_le_bytes([buf[4], buf[5], buf[6], buf[7]]),
+        };
+        // Safe because the event is valid for its whole size and we check the return value.
+        let ret = unsafe {
+            libc::write(
+                self.file.as_raw_fd(),
+                &event as *const InputEvent as *const libc::c_void,
+                mem::size_of::<InputEvent>(),
+            )
+        };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+}
+
+impl AsRawFd for Evdev {
+    fn as_raw_fd(&self) -> RawFd {
+        self.file.as_raw_fd()
+    }
+}
+
+/// A vhost-user-input device backend.
+///
+/// Virtqueue 0 is the event virtqueue, filled with the events of the evdev device, and virtqueue
+/// 1 is the status virtqueue, carrying the events sent to the evdev device.
+pub struct InputBackend {
+    device: Evdev,
+    select: u8,
+    subsel: u8,
+    mem: Option<GuestMemoryMmap>,
+}
+
+impl InputBackend {
+    /// Create a backend passing the events of `device` through.
+    pub fn new(device: Evdev) -> Self {
+        InputBackend {
+            device,
+            select: VIRTIO_INPUT_CFG_UNSET,
+            subsel: 0,
+            mem: None,
+        }
+    }
+
+    /// Register the evdev device to the worker thread of the daemon, as returned by
+    /// `Daemon::get_vring_workers()`, to process incoming events.
+    pub fn register_device(
+        &self,
+        workers: &[Arc<VringEpollHandler<InputBackend>>],
+    ) -> io::Result<()> {
+        if workers.len() != 1 {
+            return Err(io::Error::from_raw_os_error(libc::EINVAL));
+        }
+        // Incoming events are left in the device when the guest runs out of event buffers, so
+        // wait for new events only and retry when buffers are made available.
+        workers[0].register_listener(
+            self.device.as_raw_fd(),
+            EventSet::IN | EventSet::EDGE_TRIGGERED,
+            self.device_event(),
+        )
+    }
+
+    fn device_event(&self) -> u64 {
+        // Data num_queues is reserved for the exit event of the worker thread.
+        (self.num_queues() + 1) as u64
+    }
+
+    fn config_space(&self) -> [u8; VIRTIO_INPUT_CONFIG_SIZE] {
+        let mut config = [0u8; VIRTIO_INPUT_CONFIG_SIZE];
+        let data = self.device.config.select(self.select, self.subsel);
+        config[0] = self.select;
+        config[1] = self.subsel;
+        config[2] = data.len() as u8;
+        config[CONFIG_DATA_OFFSET..CONFIG_DATA_OFFSET + data.len()].copy_from_slice(&data);
+        config
+    }
+
+    fn process_event(&self, mem: &GuestMemoryMmap, vring: &mut Vring) -> io::Result<()> {
+        let mut used = false;
+        while let Some(chain) = vring.pop_avail(mem)? {
+            // Buffers too short for an event are given back empty, the event waiting for the
+            // next one.
+            let room: usize = chain.writable().map(|desc| desc.len() as usize).sum();
+            if room < VIRTIO_INPUT_EVENT_SIZE {
+                vring.add_used(mem, chain.head_index(), 0)?;
+                used = true;
+                continue;
+            }
+            let event = match self.device.recv() {
+                Ok(event) => event,
+                Err(e) => {
+                    // Give the buffer back for the next event.
+                    vring.return_avail(&chain);
+                    if e.kind() == io::ErrorKind::WouldBlock {
+                        break;
+                    }
+                    return Err(e);
+                }
+            };
+            Self::write_chain(mem, &chain, &event)?;
+            vring.add_used(mem, chain.head_index(), VIRTIO_INPUT_EVENT_SIZE as u32)?;
+            used = true;
+        }
+        if used {
+            vring.notify_used_queue(mem)?;
+        }
+        Ok(())
+    }
+
+    fn process_status(&self, mem: &GuestMemoryMmap, vring: &mut Vring) -> io::Result<()> {
+        let mut used = false;
+        while let Some(chain) = vring.pop_avail(mem)? {
+            // Truncated events are dropped, and so are events the device can't take.
+            if let Some(event) = Self::read_chain(mem, &chain)? {
+                match self.device.send(&event) {
+                    Ok(()) => {}
+                    Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                    Err(e) => return Err(e),
+                }
+            }
+            vring.add_used(mem, chain.head_index(), 0)?;
+            used = true;
+        }
+        if used {
+            vring.notify_used_queue(mem)?;
+        }
+        Ok(())
+    }
+
+    // Write an event into the writable buffers of the chain, which are large enough.
+    fn write_chain(
+        mem: &GuestMemoryMmap,
+        chain: &DescriptorChain,
+        event: &[u8; VIRTIO_INPUT_EVENT_SIZE],
+    ) -> io::Result<()> {
+        let mut written = 0;
+        for desc in chain.writable() {
+            if written == event.len() {
+                break;
+            }
+            let len = cmp::min(desc.len() as usize, event.len() - written);
+            mem.write_slice(&event[written..written + len], desc.addr())
+                .map_err(|_| io::Error::from_raw_os_error(libc::EFAULT))?;
+            written += len;
+        }
+        Ok(())
+    }
+
+    // Read an event from the readable buffers of the chain, unless they are too short.
+    fn read_chain(
+        mem: &GuestMemoryMmap,
+        chain: &DescriptorChain,
+    ) -> io::Result<Option<[u8; VIRTIO_INPUT_EVENT_SIZE]>> {
+        let mut event = [0u8; VIRTIO_INPUT_EVENT_SIZE];
+        let mut read = 0;
+        for desc in chain.readable() {
+            if read == event.len() {
+                break;
+            }
+            let len = cmp::min(desc.len() as usize, event.len() - read);
+            mem.read_slice(&mut event[read..read + len], desc.addr())
+                .map_err(|_| io::Error::from_raw_os_error(libc::EFAULT))?;
+            read += len;
+        }
+        if read != event.len() {
+            return Ok(None);
+        }
+        Ok(Some(event))
+    }
+}
+
+impl VhostUserBackend for InputBackend {
+    fn num_queues(&self) -> usize {
+        2
+    }
+
+    fn max_queue_size(&self) -> usize {
+        QUEUE_SIZE
+    }
+
+    fn features(&self) -> u64 {
+        (VirtioFeatures::VERSION_1 | VirtioFeatures::PROTOCOL_FEATURES).bits()
+    }
+
+    fn protocol_features(&self) -> VhostUserProtocolFeatures {
+        // MQ lets the master enable the status virtqueue.
+        VhostUserProtocolFeatures::MQ | VhostUserProtocolFeatures::CONFIG
+    }
+
+    fn update_memory(&mut self, mem: GuestMemoryMmap) -> io::Result<()> {
+        self.mem = Some(mem);
+        Ok(())
+    }
+
+    fn get_config(&self, offset: u32, size: u32) -> Vec<u8> {
+        read_config_space(&self.config_space(), offset, size)
+    }
+
+    fn set_config(&mut self, offset: u32, buf: &[u8]) -> io::Result<()> {
+        // Only the select and subsel fields are writable.
+        let offset = offset.saturating_sub(VHOST_USER_CONFIG_OFFSET) as usize;
+        if offset + buf.len() > 2 {
+            return Err(io::Error::from_raw_os_error(libc::EPERM));
+        }
+        for (field, &value) in buf.iter().enumerate() {
+            if offset + field == 0 {
+                self.select = value;
+            } else {
+                self.subsel = value;
+            }
+        }
+        Ok(())
+    }
+
+    fn queues_per_thread(&self) -> Vec<u64> {
+        vec![0x3]
+    }
+
+    fn process_queue(
+        &self,
+        queue_index: u16,
+        vring: &mut Vring,
+        _thread_id: usize,
+    ) -> io::Result<()> {
+        let mem = match self.mem {
+            Some(ref mem) => mem,
+            None => return Ok(()),
+        };
+        match queue_index {
+            EVENT_QUEUE => self.process_event(mem, vring),
+            STATUS_QUEUE => self.process_status(mem, vring),
+            _ => Ok(()),
+        }
+    }
+
+    fn handle_event(
+        &self,
+        device_event: u16,
+        _evset: EventSet,
+        vrings: &[Arc<RwLock<Vring>>],
+        thread_id: usize,
+    ) -> io::Result<bool> {
+        // Incoming events are processed by the event virtqueue.
+        let queue_index = if u64::from(device_event) == self.device_event() {
+            EVENT_QUEUE
+        } else {
+            device_event
+        };
+        if let Some(vring) = vrings.get(queue_index as usize) {
+            let mut vring = vring.write().unwrap();
+            if vring.is_enabled() {
+                self.process_queue(queue_index, &mut vring, thread_id)?;
+            }
+        }
+        Ok(false)
+    }
+}
+
+#[cfg(all(test, feature = "vhost-user-master"))]
+mod tests {
+    use super::*;
+    use crate::backend::{
+        VhostFeatures, VhostMemory, VhostOwner, VhostUserMemoryRegionInfo, VhostVring,
+        VringConfigData,
+    };
+    use crate::vhost_user::message::VhostUserConfigFlags;
+    use crate::vhost_user::{Daemon, Listener, Master, VhostUserMaster};
+    use std::os::unix::io::IntoRawFd;
+    use std::os::unix::net::UnixDatagram;
+    use std::thread;
+    use vm_memory::{FileOffset, GuestAddress};
+    use vmm_sys_util::eventfd::EventFd;
+    use vmm_sys_util::tempfile::TempFile;
+
+    const EV_KEY: u8 = 0x01;
+    const EV_LED: u8 = 0x11;
+
+    // Guest physical addresses of the rings and buffers, in a single region mapped at VA_BASE.
+    const VA_BASE: u64 = 0x7f00_0000_0000;
+    const RING_ADDRS: [(u64, u64, u64); 2] = [(0x1000, 0x2000, 0x3000), (0x4000, 0x5000, 0x6000)];
+    const EVENT_BUF: u64 = 0x8000;
+    const STATUS_BUF: u64 = 0x9000;
+
+    fn create_device() -> (Evdev, UnixDatagram) {
+        let (sock, peer) = UnixDatagram::pair().unwrap();
+        // Safe because we take the ownership of the socket.
+        let file = unsafe { File::from_raw_fd(sock.into_raw_fd()) };
+        let mut config = InputConfig {
+            name: "test keyboard".to_string(),
+            serial: "0123".to_string(),
+            ids: InputIds {
+                bustype: 0x3,
+                vendor: 0x1234,
+                product: 0x5678,
+                version: 1,
+            },
+            ..Default::default()
+        };
+        config.events.insert(EV_KEY, vec![0xfe, 0xff, 0, 0]);
+        config.events.insert(EV_LED, vec![0x7]);
+        config.abs_info.insert(
+            0,
+            InputAbsInfo {
+                min: -10,
+                max: 10,
+                ..Default::default()
+            },
+        );
+        (Evdev::from_file(file, config).unwrap(), peer)
+    }
+
+    // Build a struct input_event.
+    fn input_event(type_: u16, code: u16, value: i32) -> Vec<u8> {
+        let mut buf = vec![0u8; mem::size_of::<libc::timeval>()];
+        buf.extend_from_slice(&type_.to_le_bytes());
+        buf.extend_from_slice(&code.to_le_bytes());
+        buf.extend_from_slice(&value.to_le_bytes());
+        buf
+    }
+
+    // Make the buffer at `addr` available as the only descriptor of the vring.
+    fn add_avail(mem: &GuestMemoryMmap, queue: usize, addr: u64, len: u32, write: bool, idx: u16) {
+        let (desc, avail, _) = RING_ADDRS[queue];
+        mem.write_obj(addr, GuestAddress(desc)).unwrap();
+        mem.write_obj(len, GuestAddress(desc + 8)).unwrap();
+        mem.write_obj(if write { 2u16 } else { 0u16 }, GuestAddress(desc + 12))
+            .unwrap();
+        mem.write_obj(0u16, GuestAddress(avail + 4 + 2 * u64::from(idx - 1) % 16))
+            .unwrap();
+        mem.write_obj(idx, GuestAddress(avail + 2)).unwrap();
+    }
+
+    #[test]
+    fn test_input_config() {
+        let (device, _peer) = create_device();
+        let mut backend = InputBackend::new(device);
+        let config = backend.get_config(VHOST_USER_CONFIG_OFFSET, 8);
+        assert_eq!(config, vec![0u8; 8]);
+
+        backend
+            .set_config(VHOST_USER_CONFIG_OFFSET, &[VIRTIO_INPUT_CFG_ID_NAME, 0])
+            .unwrap();
+        let config = backend.get_config(VHOST_USER_CONFIG_OFFSET, 136);
+        assert_eq!(&config[0..3], &[VIRTIO_INPUT_CFG_ID_NAME, 0, 13]);
+        assert_eq!(&config[8..21], b"test keyboard");
+        assert_eq!(config[21], 0);
+
+        backend
+            .set_config(VHOST_USER_CONFIG_OFFSET, &[VIRTIO_INPUT_CFG_ID_DEVIDS])
+            .unwrap();
+        let config = backend.get_config(VHOST_USER_CONFIG_OFFSET, 16);
+        assert_eq!(config[2], 8);
+        assert_eq!(&config[8..16], &[3, 0, 0x34, 0x12, 0x78, 0x56, 1, 0]);
+
+        // The trailing null bytes of bitmaps are dropped.
+        backend
+            .set_config(
+                VHOST_USER_CONFIG_OFFSET,
+                &[VIRTIO_INPUT_CFG_EV_BITS, EV_KEY],
+            )
+            .unwrap();
+        let config = backend.get_config(VHOST_USER_CONFIG_OFFSET, 12);
+        assert_eq!(&config[0..3], &[VIRTIO_INPUT_CFG_EV_BITS, EV_KEY, 2]);
+        assert_eq!(&config[8..12], &[0xfe, 0xff, 0, 0]);
+        backend
+            .set_config(VHOST_USER_CONFIG_OFFSET + 1, &[EV_ABS])
+            .unwrap();
+        assert_eq!(backend.get_config(VHOST_USER_CONFIG_OFFSET + 2, 1), vec![0]);
+
+        backend
+            .set_config(VHOST_USER_CONFIG_OFFSET, &[VIRTIO_INPUT_CFG_ABS_INFO, 0])
+            .unwrap();
+        let config = backend.get_config(VHOST_USER_CONFIG_OFFSET, 16);
+        assert_eq!(config[2], 20);
+        assert_eq!(&config[8..16], &[0xf6, 0xff, 0xff, 0xff, 10, 0, 0, 0]);
+
+        // Only select and subsel are writable.
+        assert!(backend
+            .set_config(VHOST_USER_CONFIG_OFFSET + 2, &[1])
+            .is_err());
+        assert!(backend
+            .set_config(VHOST_USER_CONFIG_OFFSET, &[1, 0, 0])
+            .is_err());
+    }
+
+    #[test]
+    fn test_input_backend() {
+        let path = "/tmp/vhost_user_lib_unit_test_input_backend";
+        let (device, peer) = create_device();
+        let backend = Arc::new(RwLock::new(InputBackend::new(device)));
+        let mut daemon = Daemon::new("test-input".to_string(), backend.clone()).unwrap();
+        backend
+            .read()
+            .unwrap()
+            .register_device(&daemon.get_vring_workers())
+            .unwrap();
+
+        let file = TempFile::new().unwrap().into_file();
+        file.set_len(0x10000).unwrap();
+        let mem = GuestMemoryMmap::from_ranges_with_files(&[(
+            GuestAddress(0),
+            0x10000,
+            Some(FileOffset::new(file.try_clone().unwrap(), 0)),
+        )])
+        .unwrap();
+
+        let listener = Listener::new(path, true).unwrap();
+        let master_thread = thread::spawn(move || {
+            let mut master = Master::connect(path, 2).unwrap();
+            master.set_owner().unwrap();
+            let features = master.get_features().unwrap();
+            master.set_features(features).unwrap();
+            master.get_protocol_features().unwrap();
+            master
+                .set_protocol_features(
+                    VhostUserProtocolFeatures::MQ | VhostUserProtocolFeatures::CONFIG,
+                )
+                .unwrap();
+            assert_eq!(master.get_queue_num().unwrap(), 2);
+            master
+                .set_config(
+                    VHOST_USER_CONFIG_OFFSET,
+                    VhostUserConfigFlags::WRITABLE,
+                    &[VIRTIO_INPUT_CFG_ID_SERIAL, 0],
+                )
+                .unwrap();
+            let (_, config) = master
+                .get_config(
+                    VHOST_USER_CONFIG_OFFSET,
+                    VIRTIO_INPUT_CONFIG_SIZE as u32,
+                    VhostUserConfigFlags::WRITABLE,
+                    &[0u8; VIRTIO_INPUT_CONFIG_SIZE],
+                )
+                .unwrap();
+            assert_eq!(config[2], 4);
+            assert_eq!(&config[8..12], b"0123");
+
+            let region = VhostUserMemoryRegionInfo {
+                guest_phys_addr: 0,
+                memory_size: 0x10000,
+                userspace_addr: VA_BASE,
+                mmap_offset: 0,
+                mmap_handle: file.as_raw_fd(),
+            };
+            master.set_mem_table(&[region]).unwrap();
+
+            let mut calls = Vec::new();
+            for (queue, &(desc, avail, used)) in RING_ADDRS.iter().enumerate() {
+                master.set_vring_num(queue, 16).unwrap();
+                let config = VringConfigData {
+                    queue_max_size: QUEUE_SIZE as u16,
+                    queue_size: 16,
+                    flags: 0,
+                    desc_table_addr: VA_BASE + desc,
+                    used_ring_addr: VA_BASE + used,
+                    avail_ring_addr: VA_BASE + avail,
+                    log_addr: None,
+                };
+                master.set_vring_addr(queue, &config).unwrap();
+                master.set_vring_base(queue, 0).unwrap();
+                let call = EventFd::new(0).unwrap();
+                master.set_vring_call(queue, &call).unwrap();
+                master.set_vring_enable(queue, true).unwrap();
+                let kick = EventFd::new(0).unwrap();
+                master.set_vring_kick(queue, &kick).unwrap();
+                calls.push((call, kick));
+            }
+
+            // Pass an event through to the guest.
+            add_avail(&mem, 0, EVENT_BUF, 16, true, 1);
+            calls[0].1.write(1).unwrap();
+            peer.send(&input_event(u16::from(EV_KEY), 30, 1)).unwrap();
+            assert_eq!(calls[0].0.read().unwrap(), 1);
+            let len: u32 = mem.read_obj(GuestAddress(RING_ADDRS[0].2 + 8)).unwrap();
+            assert_eq!(len as usize, VIRTIO_INPUT_EVENT_SIZE);
+            let mut buf = [0u8; VIRTIO_INPUT_EVENT_SIZE];
+            mem.read_slice(&mut buf, GuestAddress(EVENT_BUF)).unwrap();
+            assert_eq!(&buf, &[1, 0, 30, 0, 1, 0, 0, 0]);
+
+            // Events pending in the device are delivered once a buffer is made available.
+            peer.send(&input_event(u16::from(EV_KEY), 30, 0)).unwrap();
+            add_avail(&mem, 0, EVENT_BUF, 16, true, 2);
+            calls[0].1.write(1).unwrap();
+            assert_eq!(calls[0].0.read().unwrap(), 1);
+            mem.read_slice(&mut buf, GuestAddress(EVENT_BUF)).unwrap();
+            assert_eq!(&buf, &[1, 0, 30, 0, 0, 0, 0, 0]);
+
+            // Buffers too short for an event are completed empty.
+            add_avail(&mem, 0, EVENT_BUF, 4, true, 3);
+            calls[0].1.write(1).unwrap();
+            assert_eq!(calls[0].0.read().unwrap(), 1);
+            let used_idx: u16 = mem.read_obj(GuestAddress(RING_ADDRS[0].2 + 2)).unwrap();
+            assert_eq!(used_idx, 3);
+            let len: u32 = mem.read_obj(GuestAddress(RING_ADDRS[0].2 + 24)).unwrap();
+            assert_eq!(len, 0);
+
+            // Forward a LED update from the guest.
+            mem.write_slice(&[0x11, 0, 1, 0, 1, 0, 0, 0], GuestAddress(STATUS_BUF))
+                .unwrap();
+            add_avail(&mem, 1, STATUS_BUF, 8, false, 1);
+            calls[1].1.write(1).unwrap();
+            assert_eq!(calls[1].0.read().unwrap(), 1);
+            let mut buf = [0u8; 32];
+            let len = peer.recv(&mut buf).unwrap();
+            assert_eq!(&buf[..len], input_event(u16::from(EV_LED), 1, 1).as_slice());
+
+            // Truncated status updates are dropped.
+            add_avail(&mem, 1, STATUS_BUF, 4, false, 2);
+            calls[1].1.write(1).unwrap();
+            assert_eq!(calls[1].0.read().unwrap(), 1);
+            let used_idx: u16 = mem.read_obj(GuestAddress(RING_ADDRS[1].2 + 2)).unwrap();
+            assert_eq!(used_idx, 2);
+            peer.set_nonblocking(true).unwrap();
+            assert_eq!(
+                peer.recv(&mut buf).unwrap_err().kind(),
+                io::ErrorKind::WouldBlock
+            );
+        });
+
+        daemon.start(listener).unwrap();
+        master_thread.join().unwrap();
+        assert!(daemon.wait().is_err());
+    }
+}
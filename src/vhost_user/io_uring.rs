@@ -0,0 +1,478 @@
+// Copyright (C) 2020 Alibaba Cloud Computing. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! A minimal io_uring instance, for backends to submit file I/O without blocking their worker
+//! threads.
+//!
+//! Only the vectored read and write operations and fsync are supported. Completions are reaped
+//! with `IoUring::pop_completion()`, usually once the eventfd registered with
+//! `IoUring::register_eventfd()` has been signaled and has woken up the worker thread. Creating
+//! the instance fails on kernels without io_uring, or when its use is forbidden, in which case
+//! backends are expected to fall back to synchronous I/O.
+
+use std::fs::File;
+use std::io;
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::ptr;
+use std::sync::atomic::{AtomicU32, Ordering};
+
+// Operations.
+const IORING_OP_READV: u8 = 1;
+const IORING_OP_WRITEV: u8 = 2;
+const IORING_OP_FSYNC: u8 = 3;
+
+// Flags of the submission queue entries.
+const IOSQE_IO_LINK: u8 = 1 << 2;
+// Flags of fsync operations.
+const IORING_FSYNC_DATASYNC: u32 = 1;
+
+// Offsets of the ring mappings.
+const IORING_OFF_SQ_RING: libc::off_t = 0;
+const IORING_OFF_CQ_RING: libc::off_t = 0x800_0000;
+const IORING_OFF_SQES: libc::off_t = 0x1000_0000;
+
+// Flags of io_uring_enter().
+const IORING_ENTER_GETEVENTS: libc::c_uint = 1;
+
+// Registration opcodes.
+const IORING_REGISTER_EVENTFD: libc::c_uint = 4;
+
+#[repr(C)]
+#[derive(Default)]
+struct SqringOffsets {
+    head: u32,
+    tail: u32,
+    ring_mask: u32,
+    ring_entries: u32,
+    flags: u32,
+    dropped: u32,
+    array: u32,
+    resv1: u32,
+    user_addr: u64,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct CqringOffsets {
+    head: u32,
+    tail: u32,
+    ring_mask: u32,
+    ring_entries: u32,
+    overflow: u32,
+    cqes: u32,
+    flags: u32,
+    resv1: u32,
+    user_addr: u64,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct Params {
+    sq_entries: u32,
+    cq_entries: u32,
+    flags: u32,
+    sq_thread_cpu: u32,
+    sq_thread_idle: u32,
+    features: u32,
+    wq_fd: u32,
+    resv: [u32; 3],
+    sq_off: SqringOffsets,
+    cq_off: CqringOffsets,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct Sqe {
+    opcode: u8,
+    flags: u8,
+    ioprio: u16,
+    fd: i32,
+    off: u64,
+    addr: u64,
+    len: u32,
+    op_flags: u32,
+    user_data: u64,
+    buf_index: u16,
+    personality: u16,
+    splice_fd_in: i32,
+    addr3: u64,
+    pad: u64,
+}
+
+#[repr(C)]
+struct Cqe {
+    user_data: u64,
+    res: i32,
+    flags: u32,
+}
+
+// Memory shared with the kernel.
+struct Mapping {
+    addr: *mut u8,
+    len: usize,
+}
+
+impl Mapping {
+    fn new(fd: RawFd, len: usize, offset: libc::off_t) -> io::Result<Self> {
+        // Safe because we map a new area and check the return value.
+        let addr = unsafe {
+            libc::mmap(
+                ptr::null_mut(),
+                len,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_SHARED | libc::MAP_POPULATE,
+                fd,
+                offset,
+            )
+        };
+        if addr == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Mapping {
+            addr: addr as *mut u8,
+            len,
+        })
+    }
+
+    // Get a pointer to the object at `offset`, which the kernel has placed in the mapping.
+    fn at<T>(&self, offset: u32) -> *mut T {
+        // Safe because the offsets given by the kernel are in the mapping.
+        unsafe { self.addr.add(offset as usize) as *mut T }
+    }
+}
+
+impl Drop for Mapping {
+    fn drop(&mut self) {
+        // Safe because we own the mapping.
+        unsafe { libc::munmap(self.addr as *mut libc::c_void, self.len) };
+    }
+}
+
+/// An io_uring instance, with its submission and completion queues.
+pub struct IoUring {
+    sq_head: *const AtomicU32,
+    sq_tail: *const AtomicU32,
+    sq_mask: u32,
+    sq_entries: u32,
+    sq_array: *mut u32,
+    sqes: *mut Sqe,
+    cq_head: *const AtomicU32,
+    cq_tail: *const AtomicU32,
+    cq_mask: u32,
+    cqes: *const Cqe,
+    // number of entries queued since the last submission
+    queued: u32,
+    // The mappings are kept until the instance is dropped, as the pointers above point into them.
+    _sq_ring: Mapping,
+    _cq_ring: Mapping,
+    _sqes_mapping: Mapping,
+    file: File,
+}
+
+// Safe because the instance owns the mappings the pointers point into, and only accesses them
+// through `&mut self`.
+unsafe impl Send for IoUring {}
+
+impl IoUring {
+    /// Create an instance with room for `entries` submissions.
+    pub fn new(entries: u32) -> io::Result<Self> {
+        let mut params = Params::default();
+        // Safe because the parameters are valid for the whole call and we check the result.
+        let fd = unsafe {
+            libc::syscall(
+                libc::SYS_io_uring_setup,
+                entries,
+                &mut params as *mut Params,
+            )
+        };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // Safe because we have just created the file descriptor.
+        let file = unsafe { File::from_raw_fd(fd as RawFd) };
+
+        let sq_len = params.sq_off.array as usize + params.sq_entries as usize * 4;
+        let sq_ring = Mapping::new(file.as_raw_fd(), sq_len, IORING_OFF_SQ_RING)?;
+        let cq_len =
+            params.cq_off.cqes as usize + params.cq_entries as usize * std::mem::size_of::<Cqe>();
+        let cq_ring = Mapping::new(file.as_raw_fd(), cq_len, IORING_OFF_CQ_RING)?;
+        let sqes_len = params.sq_entries as usize * std::mem::size_of::<Sqe>();
+        let sqes_mapping = Mapping::new(file.as_raw_fd(), sqes_len, IORING_OFF_SQES)?;
+
+        // Safe because the rings have just been mapped, and the masks don't change.
+        let (sq_mask, cq_mask) = unsafe {
+            (
+                *sq_ring.at::<u32>(params.sq_off.ring_mask),
+                *cq_ring.at::<u32>(params.cq_off.ring_mask),
+            )
+        };
+        Ok(IoUring {
+            sq_head: sq_ring.at(params.sq_off.head),
+            sq_tail: sq_ring.at(params.sq_off.tail),
+            sq_mask,
+            sq_entries: params.sq_entries,
+            sq_array: sq_ring.at(params.sq_off.array),
+            sqes: sqes_mapping.at(0),
+            cq_head: cq_ring.at(params.cq_off.head),
+            cq_tail: cq_ring.at(params.cq_off.tail),
+            cq_mask,
+            cqes: cq_ring.at(params.cq_off.cqes),
+            queued: 0,
+            _sq_ring: sq_ring,
+            _cq_ring: cq_ring,
+            _sqes_mapping: sqes_mapping,
+            file,
+        })
+    }
+
+    /// Signal `fd` whenever an operation completes.
+    pub fn register_eventfd(&self, fd: RawFd) -> io::Result<()> {
+        // Safe because the argument is valid for the whole call and we check the result.
+        let ret = unsafe {
+            libc::syscall(
+                libc::SYS_io_uring_register,
+                self.file.as_raw_fd(),
+                IORING_REGISTER_EVENTFD,
+                &fd as *const RawFd,
+                1,
+            )
+        };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    /// Get the number of entries which may still be queued.
+    pub fn space_left(&self) -> u32 {
+        // Safe because the head and tail are in the submission queue mapping.
+        let (head, tail) = unsafe {
+            (
+                (*self.sq_head).load(Ordering::Acquire),
+                (*self.sq_tail).load(Ordering::Relaxed),
+            )
+        };
+        self.sq_entries - tail.wrapping_sub(head)
+    }
+
+    /// Queue a read from `fd` at `offset` into the buffers of `iovecs`.
+    ///
+    /// When `link` is set, the next queued operation only starts once this one has succeeded.
+    ///
+    /// # Safety
+    ///
+    /// The iovecs and the buffers they point to must stay valid until the completion with
+    /// `user_data` has been popped.
+    pub unsafe fn push_readv(
+        &mut self,
+        fd: RawFd,
+        iovecs: &[libc::iovec],
+        offset: u64,
+        user_data: u64,
+        link: bool,
+    ) -> io::Result<()> {
+        self.push(Sqe {
+            opcode: IORING_OP_READV,
+            flags: if link { IOSQE_IO_LINK } else { 0 },
+            fd,
+            off: offset,
+            addr: iovecs.as_ptr() as u64,
+            len: iovecs.len() as u32,
+            user_data,
+            ..Default::default()
+        })
+    }
+
+    /// Queue a write to `fd` at `offset` from the buffers of `iovecs`.
+    ///
+    /// When `link` is set, the next queued operation only starts once this one has succeeded.
+    ///
+    /// # Safety
+    ///
+    /// The iovecs and the buffers they point to must stay valid until the completion with
+    /// `user_data` has been popped.
+    pub unsafe fn push_writev(
+        &mut self,
+        fd: RawFd,
+        iovecs: &[libc::iovec],
+        offset: u64,
+        user_data: u64,
+        link: bool,
+    ) -> io::Result<()> {
+        self.push(Sqe {
+            opcode: IORING_OP_WRITEV,
+            flags: if link { IOSQE_IO_LINK } else { 0 },
+            fd,
+            off: offset,
+            addr: iovecs.as_ptr() as u64,
+            len: iovecs.len() as u32,
+            user_data,
+            ..Default::default()
+        })
+    }
+
+    /// Queue a flush of the data written to `fd`.
+    pub fn push_fsync(&mut self, fd: RawFd, user_data: u64) -> io::Result<()> {
+        self.push(Sqe {
+            opcode: IORING_OP_FSYNC,
+            fd,
+            op_flags: IORING_FSYNC_DATASYNC,
+            user_data,
+            ..Default::default()
+        })
+    }
+
+    fn push(&mut self, sqe: Sqe) -> io::Result<()> {
+        if self.space_left() == 0 {
+            return Err(io::Error::from_raw_os_error(libc::EBUSY));
+        }
+        // Safe because the entry and the array slot are in their mappings, and the kernel only
+        // reads them once the tail has been updated.
+        unsafe {
+            let tail = (*self.sq_tail).load(Ordering::Relaxed);
+            let index = tail & self.sq_mask;
+            ptr::write_volatile(self.sqes.add(index as usize), sqe);
+            ptr::write_volatile(self.sq_array.add(index as usize), index);
+            (*self.sq_tail).store(tail.wrapping_add(1), Ordering::Release);
+        }
+        self.queued += 1;
+        Ok(())
+    }
+
+    /// Submit the queued operations to the kernel.
+    ///
+    /// Fails with `EAGAIN` if the kernel doesn't take any of them, the operations left staying
+    /// queued for the next submission.
+    pub fn submit(&mut self) -> io::Result<()> {
+        while self.queued != 0 {
+            let ret = self.enter(self.queued, 0, 0)?;
+            if ret == 0 {
+                return Err(io::Error::from_raw_os_error(libc::EAGAIN));
+            }
+            self.queued -= ret;
+        }
+        Ok(())
+    }
+
+    /// Wait for at least `count` completions to be available.
+    pub fn wait(&mut self, count: u32) -> io::Result<()> {
+        self.enter(0, count, IORING_ENTER_GETEVENTS).map(|_| ())
+    }
+
+    // Call io_uring_enter(), restarting it when interrupted, and return the number of entries
+    // submitted.
+    fn enter(&self, to_submit: u32, min_complete: u32, flags: libc::c_uint) -> io::Result<u32> {
+        loop {
+            // Safe because no pointer is passed and we check the result.
+            let ret = unsafe {
+                libc::syscall(
+                    libc::SYS_io_uring_enter,
+                    self.file.as_raw_fd(),
+                    to_submit,
+                    min_complete,
+                    flags,
+                    ptr::null::<libc::sigset_t>(),
+                    0,
+                )
+            };
+            if ret >= 0 {
+                return Ok(ret as u32);
+            }
+            let e = io::Error::last_os_error();
+            if e.kind() != io::ErrorKind::Interrupted {
+                return Err(e);
+            }
+        }
+    }
+
+    /// Pop the next completion, as the user data and the result of the operation.
+    pub fn pop_completion(&mut self) -> Option<(u64, io::Result<u32>)> {
+        // Safe because the head, tail and entries are in the completion queue mapping, and the
+        // kernel only reuses an entry once the head has been updated.
+        unsafe {
+            let head = (*self.cq_head).load(Ordering::Relaxed);
+            if head == (*self.cq_tail).load(Ordering::Acquire) {
+                return None;
+            }
+            let cqe = &*self.cqes.add((head & self.cq_mask) as usize);
+            let (user_data, res) = (cqe.user_data, cqe.res);
+            (*self.cq_head).store(head.wrapping_add(1), Ordering::Release);
+            let res = if res < 0 {
+                Err(io::Error::from_raw_os_error(-res))
+            } else {
+                Ok(res as u32)
+            };
+            Some((user_data, res))
+        }
+    }
+}
+
+impl AsRawFd for IoUring {
+    fn as_raw_fd(&self) -> RawFd {
+        self.file.as_raw_fd()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Read, Seek, SeekFrom, Write};
+    use vmm_sys_util::eventfd::EventFd;
+    use vmm_sys_util::tempfile::TempFile;
+
+    #[test]
+    fn test_io_uring() {
+        let mut ring = match IoUring::new(8) {
+            Ok(ring) => ring,
+            // io_uring isn't available.
+            Err(_) => return,
+        };
+        let event = EventFd::new(0).unwrap();
+        ring.register_eventfd(event.as_raw_fd()).unwrap();
+        assert_eq!(ring.space_left(), 8);
+
+        let mut file = TempFile::new().unwrap().into_file();
+        let mut data = *b"hello world";
+        let iovecs = [
+            libc::iovec {
+                iov_base: data.as_mut_ptr() as *mut libc::c_void,
+                iov_len: 6,
+            },
+            libc::iovec {
+                iov_base: data[6..].as_mut_ptr() as *mut libc::c_void,
+                iov_len: 5,
+            },
+        ];
+        // Safe because the buffers outlive the operations.
+        unsafe { ring.push_writev(file.as_raw_fd(), &iovecs, 2, 1, true) }.unwrap();
+        ring.push_fsync(file.as_raw_fd(), 2).unwrap();
+        ring.submit().unwrap();
+        let mut completions = Vec::new();
+        while completions.len() < 2 {
+            event.read().unwrap();
+            while let Some((user_data, res)) = ring.pop_completion() {
+                completions.push((user_data, res.unwrap()));
+            }
+        }
+        assert_eq!(completions, vec![(1, 11), (2, 0)]);
+        let mut buf = Vec::new();
+        file.seek(SeekFrom::Start(0)).unwrap();
+        file.read_to_end(&mut buf).unwrap();
+        assert_eq!(&buf, b"\0\0hello world");
+
+        file.write_all(b"!").unwrap();
+        let mut buf = [0u8; 4];
+        let iovecs = [libc::iovec {
+            iov_base: buf.as_mut_ptr() as *mut libc::c_void,
+            iov_len: 4,
+        }];
+        // Safe because the buffer outlives the operation.
+        unsafe { ring.push_readv(file.as_raw_fd(), &iovecs, 11, 3, false) }.unwrap();
+        ring.submit().unwrap();
+        ring.wait(1).unwrap();
+        let (user_data, res) = ring.pop_completion().unwrap();
+        assert_eq!((user_data, res.unwrap()), (3, 3));
+        assert_eq!(&buf, b"ld!\0");
+        assert!(ring.pop_completion().is_none());
+    }
+}
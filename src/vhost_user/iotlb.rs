@@ -0,0 +1,211 @@
+// Copyright (C) 2020 Alibaba Cloud Computing. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Cache of IOVA translations sent by the master.
+//!
+//! When the VIRTIO_F_IOMMU_PLATFORM feature has been negotiated, the addresses found in the
+//! virtqueues are I/O virtual addresses, which the slave translates into virtual addresses of the
+//! master process through a device IOTLB. The master populates the IOTLB with IOTLB_MSG update
+//! requests and invalidates stale entries with IOTLB_MSG invalidate requests. On a miss the slave
+//! sends an IOTLB miss message to the master through the slave request channel, and retries once
+//! the master has sent the missing translation.
+
+use std::collections::BTreeMap;
+
+use super::message::{VhostUserIotlb, VhostUserIotlbAccess, VhostUserIotlbMsgType};
+use super::{Error, Result};
+
+struct IotlbEntry {
+    size: u64,
+    user_addr: u64,
+    perm: VhostUserIotlbAccess,
+}
+
+/// Device IOTLB translating IOVAs into virtual addresses of the master process.
+#[derive(Default)]
+pub struct IotlbCache {
+    // Entries indexed by their first IOVA. Entries never overlap.
+    entries: BTreeMap<u64, IotlbEntry>,
+}
+
+impl IotlbCache {
+    /// Create an empty IOTLB.
+    pub fn new() -> Self {
+        IotlbCache {
+            entries: BTreeMap::new(),
+        }
+    }
+
+    /// Get the number of cached translations.
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Check whether the IOTLB is empty.
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Apply an update or invalidate message received from the master.
+    pub fn handle_msg(&mut self, msg: &VhostUserIotlb) -> Result<()> {
+        let perm = match VhostUserIotlbAccess::from_bits(msg.perm) {
+            Some(perm) => perm,
+            None => return Err(Error::InvalidMessage),
+        };
+        if msg.optype == VhostUserIotlbMsgType::Update as u8 {
+            self.update(msg.iova, msg.size, msg.user_addr, perm)
+        } else if msg.optype == VhostUserIotlbMsgType::Invalidate as u8 {
+            self.invalidate(msg.iova, msg.size);
+            Ok(())
+        } else {
+            Err(Error::InvalidMessage)
+        }
+    }
+
+    /// Add the translation of `size` bytes starting at `iova` to `user_addr`, replacing any
+    /// overlapping translation.
+    pub fn update(
+        &mut self,
+        iova: u64,
+        size: u64,
+        user_addr: u64,
+        perm: VhostUserIotlbAccess,
+    ) -> Result<()> {
+        if size == 0
+            || iova.checked_add(size - 1).is_none()
+            || user_addr.checked_add(size - 1).is_none()
+        {
+            return Err(Error::InvalidParam);
+        }
+        self.invalidate(iova, size);
+        self.entries.insert(
+            iova,
+            IotlbEntry {
+                size,
+                user_addr,
+                perm,
+            },
+        );
+        Ok(())
+    }
+
+    /// Drop all translations overlapping the `size` bytes starting at `iova`.
+    pub fn invalidate(&mut self, iova: u64, size: u64) {
+        if size == 0 {
+            return;
+        }
+        let last = iova.saturating_add(size - 1);
+        let overlapping: Vec<u64> = self
+            .entries
+            .range(..=last)
+            .rev()
+            .take_while(|(start, entry)| *start + (entry.size - 1) >= iova)
+            .map(|(start, _)| *start)
+            .collect();
+        for start in overlapping {
+            self.entries.remove(&start);
+        }
+    }
+
+    /// Drop all translations.
+    pub fn clear(&mut self) {
+        self.entries.clear();
+    }
+
+    /// Translate `len` bytes starting at `iova` into a virtual address of the master process.
+    ///
+    /// Return None on a miss, if the range isn't covered by a single translation or if the
+    /// translation doesn't allow the requested access.
+    pub fn translate(&self, iova: u64, len: u64, access: VhostUserIotlbAccess) -> Option<u64> {
+        let last = iova.checked_add(len.saturating_sub(1))?;
+        let (start, entry) = self.entries.range(..=iova).next_back()?;
+        if last > *start + (entry.size - 1) || !entry.perm.contains(access) {
+            return None;
+        }
+        Some(entry.user_addr + (iova - *start))
+    }
+
+    /// Build the miss message to send to the master when `translate()` fails.
+    pub fn miss_msg(iova: u64, access: VhostUserIotlbAccess) -> VhostUserIotlb {
+        VhostUserIotlb::new(iova, 0, 0, access, VhostUserIotlbMsgType::Miss)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vhost_user::message::VhostUserMsgValidator;
+
+    #[test]
+    fn test_iotlb_cache() {
+        let mut iotlb = IotlbCache::new();
+        assert!(iotlb.is_empty());
+        assert_eq!(iotlb.translate(0x1000, 1, VhostUserIotlbAccess::RO), None);
+        assert!(iotlb
+            .update(0x1000, 0, 0x7f00_0000_0000, VhostUserIotlbAccess::RW)
+            .is_err());
+
+        iotlb
+            .update(0x1000, 0x2000, 0x7f00_0000_0000, VhostUserIotlbAccess::RW)
+            .unwrap();
+        iotlb
+            .update(0x4000, 0x1000, 0x7f00_1000_0000, VhostUserIotlbAccess::RO)
+            .unwrap();
+        assert_eq!(iotlb.len(), 2);
+        assert_eq!(
+            iotlb.translate(0x1800, 0x100, VhostUserIotlbAccess::WO),
+            Some(0x7f00_0000_0800)
+        );
+        assert_eq!(
+            iotlb.translate(0x2800, 0x1000, VhostUserIotlbAccess::RO),
+            None
+        );
+        assert_eq!(iotlb.translate(0x3000, 1, VhostUserIotlbAccess::RO), None);
+        assert_eq!(
+            iotlb.translate(0x4fff, 1, VhostUserIotlbAccess::RO),
+            Some(0x7f00_1000_0fff)
+        );
+        assert_eq!(iotlb.translate(0x4000, 1, VhostUserIotlbAccess::WO), None);
+
+        // Replace the overlapping translation.
+        iotlb
+            .update(0x2000, 0x1000, 0x7f00_2000_0000, VhostUserIotlbAccess::RW)
+            .unwrap();
+        assert_eq!(iotlb.len(), 2);
+        assert_eq!(iotlb.translate(0x1000, 1, VhostUserIotlbAccess::RO), None);
+        assert_eq!(
+            iotlb.translate(0x2000, 1, VhostUserIotlbAccess::RO),
+            Some(0x7f00_2000_0000)
+        );
+
+        let msg = VhostUserIotlb::new(
+            0x4800,
+            0x10,
+            0,
+            VhostUserIotlbAccess::NO_ACCESS,
+            VhostUserIotlbMsgType::Invalidate,
+        );
+        iotlb.handle_msg(&msg).unwrap();
+        assert_eq!(iotlb.len(), 1);
+        assert_eq!(iotlb.translate(0x4000, 1, VhostUserIotlbAccess::RO), None);
+
+        let msg = IotlbCache::miss_msg(0x4000, VhostUserIotlbAccess::RO);
+        assert!(msg.is_valid());
+        assert!(iotlb.handle_msg(&msg).is_err());
+        let msg = VhostUserIotlb::new(
+            0x4000,
+            0x1000,
+            0x7f00_1000_0000,
+            VhostUserIotlbAccess::RO,
+            VhostUserIotlbMsgType::Update,
+        );
+        iotlb.handle_msg(&msg).unwrap();
+        assert_eq!(
+            iotlb.translate(0x4000, 1, VhostUserIotlbAccess::RO),
+            Some(0x7f00_1000_0000)
+        );
+
+        iotlb.invalidate(0, u64::MAX);
+        assert!(iotlb.is_empty());
+    }
+}
@@ -0,0 +1,101 @@
+// Copyright (C) 2020 Alibaba Cloud Computing. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Master and slave connected inside the same process, for end-to-end protocol tests.
+//!
+//! The master and the slave communicate through a socketpair, so tests neither need a socket
+//! path nor a separate process, and the slave may run its handler on a thread of the test.
+
+use std::os::unix::net::UnixStream;
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+
+use super::{Error, Master, Result, SlaveReqHandler, VhostUserSlaveReqHandler};
+
+/// Create a master connected to a slave serving requests with `backend`.
+///
+/// The master supports up to `max_queue_num` vrings.
+pub fn loopback<S: VhostUserSlaveReqHandler>(
+    backend: Arc<Mutex<S>>,
+    max_queue_num: u64,
+) -> Result<(Master, SlaveReqHandler<S>)> {
+    let (master_sock, slave_sock) = UnixStream::pair().map_err(Error::SocketError)?;
+    Ok((
+        Master::from_stream(master_sock, max_queue_num),
+        SlaveReqHandler::from_stream(slave_sock, backend),
+    ))
+}
+
+/// Create a master connected to a slave serving requests with `backend` on a new thread.
+pub fn spawn_loopback<S: VhostUserSlaveReqHandler + Send + 'static>(
+    backend: Arc<Mutex<S>>,
+    max_queue_num: u64,
+) -> Result<(Master, SlaveThread)> {
+    let (master, slave) = loopback(backend, max_queue_num)?;
+    Ok((master, SlaveThread::spawn(slave)))
+}
+
+/// Thread handling the requests of a slave until the master disconnects or an error happens.
+pub struct SlaveThread {
+    handle: JoinHandle<Error>,
+}
+
+impl SlaveThread {
+    /// Start handling the requests of `slave` on a new thread.
+    pub fn spawn<S: VhostUserSlaveReqHandler + Send + 'static>(
+        mut slave: SlaveReqHandler<S>,
+    ) -> Self {
+        let handle = thread::spawn(move || loop {
+            if let Err(e) = slave.handle_request() {
+                return e;
+            }
+        });
+        SlaveThread { handle }
+    }
+
+    /// Wait for the thread to end, once the master has been dropped.
+    ///
+    /// Return the error which stopped the slave, unless it's the disconnection of the master.
+    pub fn join(self) -> Result<()> {
+        match self.handle.join() {
+            Ok(Error::SocketBroken(_)) => Ok(()),
+            Ok(e) => Err(e),
+            Err(_) => Err(Error::SlaveInternalError),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::dummy_slave::{DummySlaveReqHandler, VIRTIO_FEATURES};
+    use super::super::message::MasterReq;
+    use super::*;
+    use crate::backend::{VhostFeatures, VhostOwner};
+
+    #[test]
+    fn test_loopback() {
+        let backend = Arc::new(Mutex::new(DummySlaveReqHandler::new()));
+        let (mut master, slave) = spawn_loopback(backend.clone(), 1).unwrap();
+
+        master.set_owner().unwrap();
+        assert_eq!(master.get_features().unwrap(), VIRTIO_FEATURES);
+        assert!(backend.lock().unwrap().owned);
+
+        drop(master);
+        slave.join().unwrap();
+    }
+
+    #[test]
+    fn test_loopback_error() {
+        let backend = Arc::new(Mutex::new(DummySlaveReqHandler::new()));
+        let (mut master, slave) = spawn_loopback(backend, 1).unwrap();
+
+        // The slave rejects a second SET_OWNER, and stops handling requests.
+        master.set_owner().unwrap();
+        master.set_owner().unwrap();
+        match slave.join() {
+            Err(Error::OutOfOrderRequest(MasterReq::SET_OWNER)) => {}
+            _ => panic!("slave error not returned"),
+        }
+    }
+}
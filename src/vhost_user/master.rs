@@ -3,17 +3,25 @@
 
 //! Traits and Struct for vhost-user master.
 
+use std::collections::VecDeque;
+use std::fs::File;
 use std::mem;
 use std::os::unix::io::{AsRawFd, RawFd};
 use std::os::unix::net::UnixStream;
 use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
 use vmm_sys_util::eventfd::EventFd;
 
-use super::connection::Endpoint;
+use super::connection::{Endpoint, Listener};
 use super::message::*;
-use super::{Error as VhostUserError, Result as VhostUserResult};
-use crate::backend::{VhostBackend, VhostUserMemoryRegionInfo, VringConfigData};
+use super::{Error as VhostUserError, Result as VhostUserResult, SharedMemoryHook};
+use crate::backend::{
+    VhostBackend, VhostFeatures, VhostMemory, VhostOwner, VhostUserDirtyLogRegion,
+    VhostUserMemoryRegionInfo, VhostVring, VringConfigData,
+};
+#[cfg(feature = "versionize")]
+use crate::versionize::{Snapshot, Snapshottable, Versionize};
 use crate::{Error, Result};
 
 /// Trait for vhost-user master to provide extra methods not covered by the VhostBackend yet.
@@ -34,6 +42,19 @@ pub trait VhostUserMaster: VhostBackend {
     /// disabled by VHOST_USER_SET_VRING_ENABLE with parameter 0.
     fn set_vring_enable(&mut self, queue_index: usize, enable: bool) -> Result<()>;
 
+    /// Set the endianness of a legacy vring, once the CROSS_ENDIAN protocol feature has been
+    /// negotiated. This must be sent before the vring is started.
+    fn set_vring_endian(&mut self, queue_index: usize, big_endian: bool) -> Result<()>;
+
+    /// Ask a vhost-user-net slave to broadcast a RARP packet announcing the guest MAC address,
+    /// once the RARP protocol feature has been negotiated. Usually sent on the destination host
+    /// after live migration, so switches learn the new location of the guest.
+    fn send_rarp(&mut self, mac: &[u8; 6]) -> Result<()>;
+
+    /// Tell a vhost-user-net slave the MTU exposed to the guest, once the MTU protocol feature
+    /// has been negotiated.
+    fn net_set_mtu(&mut self, mtu: u16) -> Result<()>;
+
     /// Fetch the contents of the virtio device configuration space.
     fn get_config(
         &mut self,
@@ -49,13 +70,131 @@ pub trait VhostUserMaster: VhostBackend {
 
     /// Setup slave communication channel.
     fn set_slave_request_fd(&mut self, fd: RawFd) -> Result<()>;
+
+    /// Share the socket of the vhost-user-gpu protocol with a GPU slave.
+    fn set_gpu_socket(&mut self, fd: RawFd) -> Result<()>;
+
+    /// Retrieve shared buffer for inflight I/O tracking.
+    fn get_inflight_fd(
+        &mut self,
+        inflight: &VhostUserInflight,
+    ) -> Result<(VhostUserInflight, File)>;
+
+    /// Set shared buffer for inflight I/O tracking.
+    fn set_inflight_fd(&mut self, inflight: &VhostUserInflight, fd: RawFd) -> Result<()>;
+
+    /// Advise the slave that postcopy migration is about to start, and retrieve the userfaultfd
+    /// created by the slave.
+    fn postcopy_advise(&mut self) -> Result<File>;
+
+    /// Inform the slave that the master has switched to postcopy mode, so page faults on the
+    /// registered guest memory are expected to be served by the master.
+    fn postcopy_listen(&mut self) -> Result<()>;
+
+    /// Inform the slave that postcopy migration has completed, so it should release the
+    /// userfaultfd.
+    fn postcopy_end(&mut self) -> Result<()>;
+
+    /// Update or invalidate an entry of the slave's device IOTLB, once VIRTIO_F_IOMMU_PLATFORM
+    /// has been negotiated.
+    fn send_iotlb_msg(&mut self, iotlb: &VhostUserIotlb) -> Result<()>;
+
+    /// Query the maximum number of memory slots supported by the slave.
+    fn get_max_mem_slots(&mut self) -> Result<u64>;
+
+    /// Add a new guest memory region to the slave's memory table, without resending the
+    /// whole memory table.
+    fn add_mem_region(&mut self, region: &VhostUserMemoryRegionInfo) -> Result<()>;
+
+    /// Remove a guest memory region from the slave's memory table.
+    fn remove_mem_region(&mut self, region: &VhostUserMemoryRegionInfo) -> Result<()>;
+
+    /// Ask the slave to disable all vrings and reset the device to its initial state, while
+    /// keeping the ownership of the session.
+    fn reset_device(&mut self) -> Result<()>;
+
+    /// Propagate the virtio device status set by the driver to the slave.
+    fn set_status(&mut self, status: u8) -> Result<()>;
+
+    /// Get the virtio device status from the slave.
+    fn get_status(&mut self) -> Result<u8>;
+
+    /// Retrieve the file descriptor of a shared object the slave has registered itself as the
+    /// exporter of, for another device looking it up.
+    fn get_shared_object(&mut self, shared: &VhostUserShared) -> Result<File>;
+
+    /// Start transferring the internal state of the device through the file descriptor, a pipe
+    /// end the slave writes its state into when saving it, and reads its state from when loading
+    /// it. The slave may return another file descriptor to be used instead of `fd`.
+    fn set_device_state_fd(
+        &mut self,
+        direction: VhostTransferStateDirection,
+        phase: VhostTransferStatePhase,
+        fd: RawFd,
+    ) -> Result<Option<File>>;
+
+    /// Check whether the slave has successfully completed the transfer of its internal state,
+    /// once the master has read the state until EOF or closed its end after writing the state.
+    fn check_device_state(&mut self) -> Result<()>;
+
+    /// Get the sizes of the shared memory regions the slave requires, indexed by shmid, to size
+    /// the memory BARs exposing them to the guest, once the SHMEM protocol feature has been
+    /// negotiated.
+    fn get_shared_memory_regions(&mut self) -> Result<Vec<u64>>;
+
+    /// Set the memory map regions on the slave, each mapped as described by the `VhostUserXenMmap`
+    /// at the same index in `xen_mmaps`, once the XEN_MMAP protocol feature has been negotiated.
+    fn set_mem_table_xen(
+        &mut self,
+        regions: &[VhostUserMemoryRegionInfo],
+        xen_mmaps: &[VhostUserXenMmap],
+    ) -> Result<()>;
+
+    /// Add a new guest memory region to the slave's memory table, mapped as described by
+    /// `xen_mmap`, once the XEN_MMAP protocol feature has been negotiated.
+    fn add_mem_region_xen(
+        &mut self,
+        region: &VhostUserMemoryRegionInfo,
+        xen_mmap: VhostUserXenMmap,
+    ) -> Result<()>;
+
+    /// Set up the vring to be kicked with VRING_KICK requests, and to notify the master of used
+    /// buffers and errors with slave requests, instead of eventfds, once the
+    /// INBAND_NOTIFICATIONS protocol feature has been negotiated.
+    ///
+    /// The vring is started, as by `set_vring_kick()`.
+    fn set_vring_inband(&mut self, queue_index: usize) -> Result<()>;
+
+    /// Kick a vring set up in-band by `set_vring_inband()`.
+    fn vring_kick(&mut self, queue_index: usize) -> Result<()>;
 }
 
+/// Default maximum number of replies the master keeps waiting for after requests timed out.
+pub const DEFAULT_MAX_PENDING_REPLIES: usize = 4;
+
 fn error_code<T>(err: VhostUserError) -> Result<T> {
     Err(Error::VhostUserProtocol(err))
 }
 
+/// Features and limits negotiated with the slave by `Master::negotiate()`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ProtocolCapabilities {
+    /// Virtio features acked.
+    pub virtio_features: u64,
+    /// Protocol features acked, empty if VHOST_USER_F_PROTOCOL_FEATURES isn't supported.
+    pub protocol_features: VhostUserProtocolFeatures,
+    /// Number of queues supported by the slave.
+    pub queue_num: u64,
+    /// Maximum number of memory regions supported by the slave.
+    pub max_mem_slots: u64,
+}
+
 /// Struct for the vhost-user master endpoint.
+///
+/// Clones share the connection to the slave, and may be used from several threads, for instance
+/// to configure different queues. The requests are serialized on the connection though: replies
+/// carry no identifier of the request they answer, so a request holds the connection until its
+/// reply or acknowledgement has been received.
 #[derive(Clone)]
 pub struct Master {
     node: Arc<Mutex<MasterInternal>>,
@@ -73,12 +212,48 @@ impl Master {
                 acked_protocol_features: 0,
                 protocol_features_ready: false,
                 max_queue_num,
+                postcopy_listening: false,
+                seq: 0,
+                pending_replies: VecDeque::new(),
+                max_pending_replies: DEFAULT_MAX_PENDING_REPLIES,
                 error: None,
             })),
         }
     }
 
-    /// Create a new instance from a Unix stream socket.
+    /// Set how long to wait for the slave when sending requests and receiving replies, or wait
+    /// forever if `timeout` is None, the default.
+    ///
+    /// Requests the slave doesn't reply to in time fail with `Timeout`. Their replies are still
+    /// expected, and get discarded once they arrive. Replies which don't match the requests
+    /// they're expected for, in the order of the requests, fail with `UnexpectedReply` and
+    /// leave the connection broken.
+    pub fn set_timeout(&self, timeout: Option<Duration>) -> Result<()> {
+        let mut node = self.node.lock().unwrap();
+        node.main_sock.set_timeout(timeout).map_err(|e| e.into())
+    }
+
+    /// Exchange messages whose body and payload take up to `size` bytes with the slave, instead
+    /// of MAX_MSG_SIZE, when both ends agree on a bigger limit.
+    ///
+    /// Requests beyond this size fail with `InvalidParam` without being sent, and bigger replies
+    /// are rejected with `InvalidMessage`. `size` may not exceed MAX_JUMBO_MSG_SIZE.
+    pub fn set_max_msg_size(&self, size: usize) -> Result<()> {
+        let mut node = self.node.lock().unwrap();
+        node.main_sock.set_max_msg_size(size).map_err(|e| e.into())
+    }
+
+    /// Set the maximum number of replies to requests which timed out the master keeps waiting
+    /// for. Once more replies are pending, the slave is considered hung and all requests fail
+    /// with `Timeout` without being sent.
+    pub fn set_max_pending_replies(&self, max_pending_replies: usize) {
+        self.node.lock().unwrap().max_pending_replies = max_pending_replies;
+    }
+
+    /// Create a new instance from a connected Unix domain socket, such as one end of a
+    /// socketpair() or a socket inherited from the parent process.
+    ///
+    /// Sockets of type SOCK_SEQPACKET may be passed as well, wrapped into a `UnixStream`.
     pub fn from_stream(sock: UnixStream, max_queue_num: u64) -> Self {
         Self::new(Endpoint::<MasterReq>::from_stream(sock), max_queue_num)
     }
@@ -90,9 +265,89 @@ impl Master {
     /// # Arguments
     /// * `path` - path of Unix domain socket listener to connect to
     pub fn connect(path: &str, max_queue_num: u64) -> Result<Self> {
+        Self::connect_with(path, max_queue_num, Endpoint::<MasterReq>::connect)
+    }
+
+    /// Create a new vhost-user master endpoint connected to a SOCK_SEQPACKET listener.
+    ///
+    /// Will retry as the backend may not be ready to accept the connection.
+    ///
+    /// # Arguments
+    /// * `path` - path of Unix domain socket listener to connect to
+    pub fn connect_seqpacket(path: &str, max_queue_num: u64) -> Result<Self> {
+        Self::connect_with(
+            path,
+            max_queue_num,
+            Endpoint::<MasterReq>::connect_seqpacket,
+        )
+    }
+
+    /// Create a new vhost-user master endpoint connected to the vsock `port` of the context
+    /// `cid`.
+    ///
+    /// File descriptors can't be passed over vsock, so a hook must be set with
+    /// `set_shared_memory_hook()` before sending requests carrying them.
+    pub fn connect_vsock(cid: u32, port: u32, max_queue_num: u64) -> Result<Self> {
+        let endpoint = Endpoint::<MasterReq>::connect_vsock(cid, port)?;
+        Ok(Self::new(endpoint, max_queue_num))
+    }
+
+    /// Transfer the file descriptors attached to the requests and replies with `hook` instead
+    /// of the socket.
+    pub fn set_shared_memory_hook(&self, hook: Box<dyn SharedMemoryHook>) {
+        let mut node = self.node.lock().unwrap();
+        node.main_sock.set_shared_memory_hook(hook);
+    }
+
+    /// Take the ownership of the session, and negotiate the `virtio_features` and
+    /// `protocol_features` also supported by the slave.
+    ///
+    /// The number of queues and memory regions supported by the slave are queried when the MQ and
+    /// CONFIGURE_MEM_SLOTS protocol features have been negotiated. Otherwise the `max_queue_num`
+    /// the master has been created with, and the number of regions a SET_MEM_TABLE request may
+    /// carry, are reported.
+    pub fn negotiate(
+        &mut self,
+        virtio_features: u64,
+        protocol_features: VhostUserProtocolFeatures,
+    ) -> Result<ProtocolCapabilities> {
+        self.set_owner()?;
+        let virtio_features = self.get_features()? & virtio_features;
+        self.set_features(virtio_features)?;
+
+        let mut caps = ProtocolCapabilities {
+            virtio_features,
+            protocol_features: VhostUserProtocolFeatures::empty(),
+            queue_num: self.node.lock().unwrap().max_queue_num,
+            max_mem_slots: MAX_ATTACHED_FD_ENTRIES as u64,
+        };
+        if virtio_features & VhostUserVirtioFeatures::PROTOCOL_FEATURES.bits() == 0 {
+            return Ok(caps);
+        }
+        caps.protocol_features = self.get_protocol_features()? & protocol_features;
+        self.set_protocol_features(caps.protocol_features)?;
+        if caps
+            .protocol_features
+            .contains(VhostUserProtocolFeatures::MQ)
+        {
+            caps.queue_num = self.get_queue_num()?;
+        }
+        if caps
+            .protocol_features
+            .contains(VhostUserProtocolFeatures::CONFIGURE_MEM_SLOTS)
+        {
+            caps.max_mem_slots = self.get_max_mem_slots()?;
+        }
+        Ok(caps)
+    }
+
+    fn connect_with<F>(path: &str, max_queue_num: u64, connect: F) -> Result<Self>
+    where
+        F: Fn(&str) -> VhostUserResult<Endpoint<MasterReq>>,
+    {
         let mut retry_count = 5;
         let endpoint = loop {
-            match Endpoint::<MasterReq>::connect(path) {
+            match connect(path) {
                 Ok(endpoint) => break Ok(endpoint),
                 Err(e) => match &e {
                     VhostUserError::SocketConnect(why) => {
@@ -111,93 +366,224 @@ impl Master {
 
         Ok(Self::new(endpoint, max_queue_num))
     }
-}
 
-impl VhostBackend for Master {
-    /// Get from the underlying vhost implementation the feature bitmask.
-    fn get_features(&mut self) -> Result<u64> {
+    // Send the memory table, each region being mapped as described by the `VhostUserXenMmap` at
+    // the same index, which must be regular shared memory unless XEN_MMAP has been negotiated.
+    fn send_mem_table(
+        &mut self,
+        regions: &[VhostUserMemoryRegionInfo],
+        xen_mmaps: &[VhostUserXenMmap],
+    ) -> Result<()> {
+        if regions.is_empty() || regions.len() != xen_mmaps.len() {
+            return error_code(VhostUserError::InvalidParam);
+        }
+        let count = regions.len().min(MAX_ATTACHED_FD_ENTRIES);
+        if regions.len() > count {
+            let mem_slots = self.node.lock().unwrap().is_feature_mem_slots_available();
+            let max = if mem_slots {
+                self.get_max_mem_slots()? as usize
+            } else {
+                MAX_ATTACHED_FD_ENTRIES
+            };
+            if regions.len() > max {
+                return Err(VhostUserError::TooManyMemRegions {
+                    max,
+                    overflowed: regions[max..].iter().map(|r| r.guest_phys_addr).collect(),
+                }
+                .into());
+            }
+        }
+
+        let mut ctx = VhostUserMemoryContext::new();
+        for region in regions[..count].iter() {
+            if region.memory_size == 0 || region.mmap_handle < 0 {
+                return error_code(VhostUserError::InvalidParam);
+            }
+            let reg = VhostUserMemoryRegion {
+                guest_phys_addr: region.guest_phys_addr,
+                memory_size: region.memory_size,
+                user_addr: region.userspace_addr,
+                mmap_offset: region.mmap_offset,
+            };
+            ctx.append(&reg, region.mmap_handle);
+        }
+
         let mut node = self.node.lock().unwrap();
-        let hdr = node.send_request_header(MasterReq::GET_FEATURES, None)?;
-        let val = node.recv_reply::<VhostUserU64>(&hdr)?;
-        node.virtio_features = val.value;
-        Ok(node.virtio_features)
+        let body = VhostUserMemory::new(ctx.regions.len() as u32);
+        let hdr = if node.is_feature_xen_mmap_available() {
+            let payload: Vec<VhostUserXenMemoryRegion> = ctx
+                .regions
+                .iter()
+                .zip(xen_mmaps)
+                .map(|(region, xen_mmap)| VhostUserXenMemoryRegion::new(*region, *xen_mmap))
+                .collect();
+            node.send_request_with_payload(
+                MasterReq::SET_MEM_TABLE,
+                &body,
+                payload.as_slice(),
+                Some(ctx.fds.as_slice()),
+            )?
+        } else {
+            if xen_mmaps
+                .iter()
+                .any(|xen_mmap| !xen_mmap.is_shared_memory())
+            {
+                return error_code(VhostUserError::InvalidOperation);
+            }
+            node.send_request_with_payload(
+                MasterReq::SET_MEM_TABLE,
+                &body,
+                ctx.regions.as_slice(),
+                Some(ctx.fds.as_slice()),
+            )?
+        };
+        node.wait_for_ack(&hdr)?;
+        drop(node);
+
+        for (region, xen_mmap) in regions[count..].iter().zip(&xen_mmaps[count..]) {
+            self.send_mem_region(region, *xen_mmap)?;
+        }
+        Ok(())
     }
 
-    /// Enable features in the underlying vhost implementation using a bitmask.
-    fn set_features(&mut self, features: u64) -> Result<()> {
+    // Add a region with ADD_MEM_REG, mapped as described by `xen_mmap`, which must be regular
+    // shared memory unless XEN_MMAP has been negotiated.
+    fn send_mem_region(
+        &mut self,
+        region: &VhostUserMemoryRegionInfo,
+        xen_mmap: VhostUserXenMmap,
+    ) -> Result<()> {
         let mut node = self.node.lock().unwrap();
-        let val = VhostUserU64::new(features);
-        let _ = node.send_request_with_body(MasterReq::SET_FEATURES, &val, None)?;
-        // Don't wait for ACK here because the protocol feature negotiation process hasn't been
-        // completed yet.
-        node.acked_virtio_features = features & node.virtio_features;
-        Ok(())
+        if !node.is_feature_mem_slots_available() {
+            return error_code(VhostUserError::InvalidOperation);
+        }
+        if region.memory_size == 0 || region.mmap_handle < 0 {
+            return error_code(VhostUserError::InvalidParam);
+        }
+
+        let body = VhostUserSingleMemoryRegion::new(
+            region.guest_phys_addr,
+            region.memory_size,
+            region.userspace_addr,
+            region.mmap_offset,
+        );
+        let fds = [region.mmap_handle];
+        let hdr = if node.is_feature_xen_mmap_available() {
+            let body = VhostUserXenSingleMemoryRegion::new(body.region, xen_mmap);
+            node.send_request_with_body(MasterReq::ADD_MEM_REG, &body, Some(&fds))?
+        } else if xen_mmap.is_shared_memory() {
+            node.send_request_with_body(MasterReq::ADD_MEM_REG, &body, Some(&fds))?
+        } else {
+            return error_code(VhostUserError::InvalidOperation);
+        };
+        node.wait_for_mem_slot_ack(&hdr).map_err(|e| e.into())
+    }
+}
+
+/// Vhost-user master side connection listener, for masters acting as the socket server the
+/// slave connects to, like QEMU with `server=on`.
+pub struct MasterListener {
+    listener: Listener,
+    max_queue_num: u64,
+}
+
+impl MasterListener {
+    /// Wait for slaves connecting to `listener`, with masters supporting up to `max_queue_num`
+    /// vrings.
+    pub fn new(listener: Listener, max_queue_num: u64) -> Self {
+        MasterListener {
+            listener,
+            max_queue_num,
+        }
+    }
+
+    /// Accept an incoming connection from the slave, returning Some(Master) on success, or None
+    /// if the socket is nonblocking and no incoming connection was detected.
+    ///
+    /// A slave restarted after a crash connects again to the listener, and the master returned
+    /// for the new connection must be set up from scratch, see `ReconnectingMaster`.
+    pub fn accept(&self) -> Result<Option<Master>> {
+        let sock = self.listener.accept()?;
+        Ok(sock.map(|sock| Master::from_stream(sock, self.max_queue_num)))
+    }
+
+    /// Get the listener slaves connect to.
+    pub fn listener(&self) -> &Listener {
+        &self.listener
     }
+}
 
+impl VhostOwner for Master {
     /// Set the current Master as an owner of the session.
     fn set_owner(&mut self) -> Result<()> {
         // We unwrap() the return value to assert that we are not expecting threads to ever fail
         // while holding the lock.
         let mut node = self.node.lock().unwrap();
-        let _ = node.send_request_header(MasterReq::SET_OWNER, None)?;
-        // Don't wait for ACK here because the protocol feature negotiation process hasn't been
-        // completed yet.
-        Ok(())
+        let hdr = node.send_request_header(MasterReq::SET_OWNER, None)?;
+        node.wait_for_ack(&hdr).map_err(|e| e.into())
     }
 
     fn reset_owner(&mut self) -> Result<()> {
         let mut node = self.node.lock().unwrap();
-        let _ = node.send_request_header(MasterReq::RESET_OWNER, None)?;
-        // Don't wait for ACK here because the protocol feature negotiation process hasn't been
-        // completed yet.
+        let hdr = node.send_request_header(MasterReq::RESET_OWNER, None)?;
+        node.wait_for_ack(&hdr).map_err(|e| e.into())
+    }
+}
+
+impl VhostFeatures for Master {
+    /// Get from the underlying vhost implementation the feature bitmask.
+    fn get_features(&mut self) -> Result<u64> {
+        let mut node = self.node.lock().unwrap();
+        let hdr = node.send_request_header(MasterReq::GET_FEATURES, None)?;
+        let val = node.recv_reply::<VhostUserU64>(&hdr)?;
+        node.virtio_features = val.value;
+        Ok(node.virtio_features)
+    }
+
+    /// Enable features in the underlying vhost implementation using a bitmask.
+    fn set_features(&mut self, features: u64) -> Result<()> {
+        let mut node = self.node.lock().unwrap();
+        let val = VhostUserU64::new(features);
+        let hdr = node.send_request_with_body(MasterReq::SET_FEATURES, &val, None)?;
+        node.wait_for_ack(&hdr)?;
+        node.acked_virtio_features = features & node.virtio_features;
         Ok(())
     }
+}
 
+impl VhostMemory for Master {
     /// Set the memory map regions on the slave so it can translate the vring
     /// addresses. In the ancillary data there is an array of file descriptors
+    ///
+    /// SET_MEM_TABLE carries at most MAX_ATTACHED_FD_ENTRIES regions. Once the
+    /// CONFIGURE_MEM_SLOTS protocol feature has been negotiated, the other regions are added with
+    /// ADD_MEM_REG, up to the number of memory slots of the slave.
     fn set_mem_table(&mut self, regions: &[VhostUserMemoryRegionInfo]) -> Result<()> {
-        if regions.is_empty() || regions.len() > MAX_ATTACHED_FD_ENTRIES {
-            return error_code(VhostUserError::InvalidParam);
-        }
-
-        let mut ctx = VhostUserMemoryContext::new();
-        for region in regions.iter() {
-            if region.memory_size == 0 || region.mmap_handle < 0 {
-                return error_code(VhostUserError::InvalidParam);
-            }
-            let reg = VhostUserMemoryRegion {
-                guest_phys_addr: region.guest_phys_addr,
-                memory_size: region.memory_size,
-                user_addr: region.userspace_addr,
-                mmap_offset: region.mmap_offset,
-            };
-            ctx.append(&reg, region.mmap_handle);
-        }
-
-        let mut node = self.node.lock().unwrap();
-        let body = VhostUserMemory::new(ctx.regions.len() as u32);
-        let hdr = node.send_request_with_payload(
-            MasterReq::SET_MEM_TABLE,
-            &body,
-            ctx.regions.as_slice(),
-            Some(ctx.fds.as_slice()),
-        )?;
-        node.wait_for_ack(&hdr).map_err(|e| e.into())
+        self.send_mem_table(regions, &vec![VhostUserXenMmap::default(); regions.len()])
     }
 
-    // Clippy doesn't seem to know that if let with && is still experimental
-    #[allow(clippy::unnecessary_unwrap)]
-    fn set_log_base(&mut self, base: u64, fd: Option<RawFd>) -> Result<()> {
+    fn set_log_base(&mut self, base: u64, region: Option<VhostUserDirtyLogRegion>) -> Result<()> {
         let mut node = self.node.lock().unwrap();
-        let val = VhostUserU64::new(base);
 
-        if node.acked_protocol_features & VhostUserProtocolFeatures::LOG_SHMFD.bits() != 0
-            && fd.is_some()
-        {
-            let fds = [fd.unwrap()];
-            let _ = node.send_request_with_body(MasterReq::SET_LOG_BASE, &val, Some(&fds))?;
-        } else {
-            let _ = node.send_request_with_body(MasterReq::SET_LOG_BASE, &val, None)?;
+        match region {
+            Some(region)
+                if node.acked_protocol_features & VhostUserProtocolFeatures::LOG_SHMFD.bits()
+                    != 0 =>
+            {
+                let log = VhostUserLog::new(region.mmap_size, region.mmap_offset);
+                let fds = [region.mmap_handle];
+                let hdr = node.send_request_with_body(MasterReq::SET_LOG_BASE, &log, Some(&fds))?;
+                // The slave always replies to SET_LOG_BASE requests carrying a shared memory
+                // region, no matter whether REPLY_ACK has been negotiated.
+                let val = node.recv_reply::<VhostUserU64>(&hdr)?;
+                if val.value != 0 {
+                    return error_code(VhostUserError::from_reply_code(val.value, hdr.get_code()));
+                }
+            }
+            _ => {
+                let val = VhostUserU64::new(base);
+                let _ = node.send_request_with_body(MasterReq::SET_LOG_BASE, &val, None)?;
+            }
         }
         Ok(())
     }
@@ -205,10 +591,12 @@ impl VhostBackend for Master {
     fn set_log_fd(&mut self, fd: RawFd) -> Result<()> {
         let mut node = self.node.lock().unwrap();
         let fds = [fd];
-        node.send_request_header(MasterReq::SET_LOG_FD, Some(&fds))?;
-        Ok(())
+        let hdr = node.send_request_header(MasterReq::SET_LOG_FD, Some(&fds))?;
+        node.wait_for_ack(&hdr).map_err(|e| e.into())
     }
+}
 
+impl VhostVring for Master {
     /// Set the size of the queue.
     fn set_vring_num(&mut self, queue_index: usize, num: u16) -> Result<()> {
         let mut node = self.node.lock().unwrap();
@@ -256,6 +644,9 @@ impl VhostBackend for Master {
         let req = VhostUserVringState::new(queue_index as u32, 0);
         let hdr = node.send_request_with_body(MasterReq::GET_VRING_BASE, &req, None)?;
         let reply = node.recv_reply::<VhostUserVringState>(&hdr)?;
+        if reply.index != queue_index as u32 {
+            return error_code(VhostUserError::InvalidMessage);
+        }
         Ok(reply.num)
     }
 
@@ -268,8 +659,8 @@ impl VhostBackend for Master {
         if queue_index as u64 >= node.max_queue_num {
             return error_code(VhostUserError::InvalidParam);
         }
-        node.send_fd_for_vring(MasterReq::SET_VRING_CALL, queue_index, fd.as_raw_fd())?;
-        Ok(())
+        let hdr = node.send_fd_for_vring(MasterReq::SET_VRING_CALL, queue_index, fd.as_raw_fd())?;
+        node.wait_for_ack(&hdr).map_err(|e| e.into())
     }
 
     /// Set the event file descriptor for adding buffers to the vring.
@@ -281,8 +672,8 @@ impl VhostBackend for Master {
         if queue_index as u64 >= node.max_queue_num {
             return error_code(VhostUserError::InvalidParam);
         }
-        node.send_fd_for_vring(MasterReq::SET_VRING_KICK, queue_index, fd.as_raw_fd())?;
-        Ok(())
+        let hdr = node.send_fd_for_vring(MasterReq::SET_VRING_KICK, queue_index, fd.as_raw_fd())?;
+        node.wait_for_ack(&hdr).map_err(|e| e.into())
     }
 
     /// Set the event file descriptor to signal when error occurs.
@@ -293,8 +684,8 @@ impl VhostBackend for Master {
         if queue_index as u64 >= node.max_queue_num {
             return error_code(VhostUserError::InvalidParam);
         }
-        node.send_fd_for_vring(MasterReq::SET_VRING_ERR, queue_index, fd.as_raw_fd())?;
-        Ok(())
+        let hdr = node.send_fd_for_vring(MasterReq::SET_VRING_ERR, queue_index, fd.as_raw_fd())?;
+        node.wait_for_ack(&hdr).map_err(|e| e.into())
     }
 }
 
@@ -323,9 +714,10 @@ impl VhostUserMaster for Master {
             return error_code(VhostUserError::InvalidOperation);
         }
         let val = VhostUserU64::new(features.bits());
-        let _ = node.send_request_with_body(MasterReq::SET_PROTOCOL_FEATURES, &val, None)?;
-        // Don't wait for ACK here because the protocol feature negotiation process hasn't been
-        // completed yet.
+        let hdr = node.send_request_with_body(MasterReq::SET_PROTOCOL_FEATURES, &val, None)?;
+        // Acks are only requested once REPLY_ACK has been negotiated, so the protocol features
+        // previously acked decide whether to wait for one.
+        node.wait_for_ack(&hdr)?;
         node.acked_protocol_features = features.bits();
         node.protocol_features_ready = true;
         Ok(())
@@ -351,6 +743,9 @@ impl VhostUserMaster for Master {
         // set_vring_enable() is supported only when PROTOCOL_FEATURES has been enabled.
         if node.acked_virtio_features & VhostUserVirtioFeatures::PROTOCOL_FEATURES.bits() == 0 {
             return error_code(VhostUserError::InvalidOperation);
+        } else if queue_index > 0 && !node.is_feature_mq_available() {
+            // Only the first vring may be enabled without the MQ protocol feature.
+            return error_code(VhostUserError::InvalidOperation);
         } else if queue_index as u64 >= node.max_queue_num {
             return error_code(VhostUserError::InvalidParam);
         }
@@ -361,6 +756,49 @@ impl VhostUserMaster for Master {
         node.wait_for_ack(&hdr).map_err(|e| e.into())
     }
 
+    fn set_vring_endian(&mut self, queue_index: usize, big_endian: bool) -> Result<()> {
+        let mut node = self.node.lock().unwrap();
+        if node.acked_protocol_features & VhostUserProtocolFeatures::CROSS_ENDIAN.bits() == 0 {
+            return error_code(VhostUserError::InvalidOperation);
+        } else if queue_index as u64 >= node.max_queue_num {
+            return error_code(VhostUserError::InvalidParam);
+        }
+
+        let endian = if big_endian {
+            VHOST_USER_VRING_BIG_ENDIAN
+        } else {
+            VHOST_USER_VRING_LITTLE_ENDIAN
+        };
+        let val = VhostUserVringState::new(queue_index as u32, endian);
+        let hdr = node.send_request_with_body(MasterReq::SET_VRING_ENDIAN, &val, None)?;
+        node.wait_for_ack(&hdr).map_err(|e| e.into())
+    }
+
+    fn send_rarp(&mut self, mac: &[u8; 6]) -> Result<()> {
+        let mut node = self.node.lock().unwrap();
+        if node.acked_protocol_features & VhostUserProtocolFeatures::RARP.bits() == 0 {
+            return error_code(VhostUserError::InvalidOperation);
+        }
+
+        // The MAC address lives in the first bytes of the payload.
+        let mut payload = [0u8; 8];
+        payload[..6].copy_from_slice(mac);
+        let val = VhostUserU64::new(u64::from_le_bytes(payload));
+        let hdr = node.send_request_with_body(MasterReq::SEND_RARP, &val, None)?;
+        node.wait_for_ack(&hdr).map_err(|e| e.into())
+    }
+
+    fn net_set_mtu(&mut self, mtu: u16) -> Result<()> {
+        let mut node = self.node.lock().unwrap();
+        if node.acked_protocol_features & VhostUserProtocolFeatures::MTU.bits() == 0 {
+            return error_code(VhostUserError::InvalidOperation);
+        }
+
+        let val = VhostUserU64::new(u64::from(mtu));
+        let hdr = node.send_request_with_body(MasterReq::NET_SET_MTU, &val, None)?;
+        node.wait_for_ack(&hdr).map_err(|e| e.into())
+    }
+
     fn get_config(
         &mut self,
         offset: u32,
@@ -422,7 +860,406 @@ impl VhostUserMaster for Master {
         }
 
         let fds = [fd];
-        node.send_request_header(MasterReq::SET_SLAVE_REQ_FD, Some(&fds))?;
+        let hdr = node.send_request_header(MasterReq::SET_SLAVE_REQ_FD, Some(&fds))?;
+        node.wait_for_ack(&hdr).map_err(|e| e.into())
+    }
+
+    fn set_gpu_socket(&mut self, fd: RawFd) -> Result<()> {
+        let mut node = self.node.lock().unwrap();
+        let fds = [fd];
+        let hdr = node.send_request_header(MasterReq::GPU_SET_SOCKET, Some(&fds))?;
+        node.wait_for_ack(&hdr).map_err(|e| e.into())
+    }
+
+    fn get_inflight_fd(
+        &mut self,
+        inflight: &VhostUserInflight,
+    ) -> Result<(VhostUserInflight, File)> {
+        let mut node = self.node.lock().unwrap();
+        if node.acked_protocol_features & VhostUserProtocolFeatures::INFLIGHT_SHMFD.bits() == 0 {
+            return error_code(VhostUserError::InvalidOperation);
+        }
+
+        let hdr = node.send_request_with_body(MasterReq::GET_INFLIGHT_FD, inflight, None)?;
+        let (inflight, rfds) = node.recv_reply_with_fds::<VhostUserInflight>(&hdr)?;
+        match Endpoint::<MasterReq>::take_single_file(rfds) {
+            Some(file) if inflight.mmap_size != 0 => Ok((inflight, file)),
+            _ => error_code(VhostUserError::IncorrectFds),
+        }
+    }
+
+    fn set_inflight_fd(&mut self, inflight: &VhostUserInflight, fd: RawFd) -> Result<()> {
+        let mut node = self.node.lock().unwrap();
+        if node.acked_protocol_features & VhostUserProtocolFeatures::INFLIGHT_SHMFD.bits() == 0 {
+            return error_code(VhostUserError::InvalidOperation);
+        }
+        if inflight.mmap_size == 0 || !inflight.is_valid() || fd < 0 {
+            return error_code(VhostUserError::InvalidParam);
+        }
+
+        let hdr = node.send_request_with_body(MasterReq::SET_INFLIGHT_FD, inflight, Some(&[fd]))?;
+        node.wait_for_ack(&hdr).map_err(|e| e.into())
+    }
+
+    fn postcopy_advise(&mut self) -> Result<File> {
+        let mut node = self.node.lock().unwrap();
+        if node.acked_protocol_features & VhostUserProtocolFeatures::PAGEFAULT.bits() == 0 {
+            return error_code(VhostUserError::InvalidOperation);
+        }
+
+        let hdr = node.send_request_header(MasterReq::POSTCOPY_ADVISE, None)?;
+        let (val, rfds) = node.recv_reply_with_fds::<VhostUserU64>(&hdr)?;
+        if val.value != 0 {
+            Endpoint::<MasterReq>::close_rfds(rfds);
+            return error_code(VhostUserError::from_reply_code(val.value, hdr.get_code()));
+        }
+        match Endpoint::<MasterReq>::take_single_file(rfds) {
+            Some(file) => Ok(file),
+            None => error_code(VhostUserError::IncorrectFds),
+        }
+    }
+
+    fn postcopy_listen(&mut self) -> Result<()> {
+        let mut node = self.node.lock().unwrap();
+        if node.acked_protocol_features & VhostUserProtocolFeatures::PAGEFAULT.bits() == 0 {
+            return error_code(VhostUserError::InvalidOperation);
+        }
+
+        // The slave always replies to postcopy requests, no matter whether REPLY_ACK has been
+        // negotiated.
+        let hdr = node.send_request_header(MasterReq::POSTCOPY_LISTEN, None)?;
+        let val = node.recv_reply::<VhostUserU64>(&hdr)?;
+        if val.value != 0 {
+            return error_code(VhostUserError::from_reply_code(val.value, hdr.get_code()));
+        }
+        node.postcopy_listening = true;
+        Ok(())
+    }
+
+    fn postcopy_end(&mut self) -> Result<()> {
+        let mut node = self.node.lock().unwrap();
+        if node.acked_protocol_features & VhostUserProtocolFeatures::PAGEFAULT.bits() == 0 {
+            return error_code(VhostUserError::InvalidOperation);
+        }
+
+        let hdr = node.send_request_header(MasterReq::POSTCOPY_END, None)?;
+        node.postcopy_listening = false;
+        let val = node.recv_reply::<VhostUserU64>(&hdr)?;
+        if val.value != 0 {
+            return error_code(VhostUserError::from_reply_code(val.value, hdr.get_code()));
+        }
+        Ok(())
+    }
+
+    fn send_iotlb_msg(&mut self, iotlb: &VhostUserIotlb) -> Result<()> {
+        let mut node = self.node.lock().unwrap();
+        if node.acked_virtio_features & VhostUserVirtioFeatures::IOMMU_PLATFORM.bits() == 0 {
+            return error_code(VhostUserError::InvalidOperation);
+        }
+        if !iotlb.is_valid()
+            || (iotlb.optype != VhostUserIotlbMsgType::Update as u8
+                && iotlb.optype != VhostUserIotlbMsgType::Invalidate as u8)
+        {
+            return error_code(VhostUserError::InvalidParam);
+        }
+
+        // The slave always replies to IOTLB messages, no matter whether REPLY_ACK has been
+        // negotiated.
+        let hdr = node.send_request_with_body(MasterReq::IOTLB_MSG, iotlb, None)?;
+        let val = node.recv_reply::<VhostUserU64>(&hdr)?;
+        if val.value != 0 {
+            return error_code(VhostUserError::from_reply_code(val.value, hdr.get_code()));
+        }
+        Ok(())
+    }
+
+    fn get_max_mem_slots(&mut self) -> Result<u64> {
+        let mut node = self.node.lock().unwrap();
+        if !node.is_feature_mem_slots_available() {
+            return error_code(VhostUserError::InvalidOperation);
+        }
+
+        let hdr = node.send_request_header(MasterReq::GET_MAX_MEM_SLOTS, None)?;
+        let val = node.recv_reply::<VhostUserU64>(&hdr)?;
+        Ok(val.value)
+    }
+
+    fn add_mem_region(&mut self, region: &VhostUserMemoryRegionInfo) -> Result<()> {
+        self.send_mem_region(region, VhostUserXenMmap::default())
+    }
+
+    fn remove_mem_region(&mut self, region: &VhostUserMemoryRegionInfo) -> Result<()> {
+        let mut node = self.node.lock().unwrap();
+        if !node.is_feature_mem_slots_available() {
+            return error_code(VhostUserError::InvalidOperation);
+        }
+        if region.memory_size == 0 {
+            return error_code(VhostUserError::InvalidParam);
+        }
+
+        let body = VhostUserSingleMemoryRegion::new(
+            region.guest_phys_addr,
+            region.memory_size,
+            region.userspace_addr,
+            region.mmap_offset,
+        );
+        // The mapping of the region doesn't matter to remove it.
+        let hdr = if node.is_feature_xen_mmap_available() {
+            let body =
+                VhostUserXenSingleMemoryRegion::new(body.region, VhostUserXenMmap::default());
+            node.send_request_with_body(MasterReq::REM_MEM_REG, &body, None)?
+        } else {
+            node.send_request_with_body(MasterReq::REM_MEM_REG, &body, None)?
+        };
+        node.wait_for_mem_slot_ack(&hdr).map_err(|e| e.into())
+    }
+
+    fn reset_device(&mut self) -> Result<()> {
+        let mut node = self.node.lock().unwrap();
+        if node.acked_protocol_features & VhostUserProtocolFeatures::RESET_DEVICE.bits() == 0 {
+            return error_code(VhostUserError::InvalidOperation);
+        }
+
+        let hdr = node.send_request_header(MasterReq::RESET_DEVICE, None)?;
+        node.wait_for_ack(&hdr).map_err(|e| e.into())
+    }
+
+    fn set_status(&mut self, status: u8) -> Result<()> {
+        let mut node = self.node.lock().unwrap();
+        if node.acked_protocol_features & VhostUserProtocolFeatures::STATUS.bits() == 0 {
+            return error_code(VhostUserError::InvalidOperation);
+        }
+
+        let val = VhostUserU64::new(u64::from(status));
+        let hdr = node.send_request_with_body(MasterReq::SET_STATUS, &val, None)?;
+        node.wait_for_ack(&hdr).map_err(|e| e.into())
+    }
+
+    fn get_status(&mut self) -> Result<u8> {
+        let mut node = self.node.lock().unwrap();
+        if node.acked_protocol_features & VhostUserProtocolFeatures::STATUS.bits() == 0 {
+            return error_code(VhostUserError::InvalidOperation);
+        }
+
+        let hdr = node.send_request_header(MasterReq::GET_STATUS, None)?;
+        let val = node.recv_reply::<VhostUserU64>(&hdr)?;
+        if val.value > u64::from(u8::MAX) {
+            return error_code(VhostUserError::InvalidMessage);
+        }
+        Ok(val.value as u8)
+    }
+
+    fn get_shared_object(&mut self, shared: &VhostUserShared) -> Result<File> {
+        let mut node = self.node.lock().unwrap();
+        if node.acked_protocol_features & VhostUserProtocolFeatures::SHARED_OBJECT.bits() == 0 {
+            return error_code(VhostUserError::InvalidOperation);
+        }
+
+        let hdr = node.send_request_with_body(MasterReq::GET_SHARED_OBJECT, shared, None)?;
+        let (val, rfds) = node.recv_reply_with_fds::<VhostUserU64>(&hdr)?;
+        if val.value != 0 {
+            Endpoint::<MasterReq>::close_rfds(rfds);
+            return error_code(VhostUserError::from_reply_code(val.value, hdr.get_code()));
+        }
+        match Endpoint::<MasterReq>::take_single_file(rfds) {
+            Some(file) => Ok(file),
+            None => error_code(VhostUserError::IncorrectFds),
+        }
+    }
+
+    fn set_device_state_fd(
+        &mut self,
+        direction: VhostTransferStateDirection,
+        phase: VhostTransferStatePhase,
+        fd: RawFd,
+    ) -> Result<Option<File>> {
+        let mut node = self.node.lock().unwrap();
+        if node.acked_protocol_features & VhostUserProtocolFeatures::DEVICE_STATE.bits() == 0 {
+            return error_code(VhostUserError::InvalidOperation);
+        }
+        if fd < 0 {
+            return error_code(VhostUserError::InvalidParam);
+        }
+
+        let body = VhostUserTransferDeviceState::new(direction, phase);
+        let hdr =
+            node.send_request_with_body(MasterReq::SET_DEVICE_STATE_FD, &body, Some(&[fd]))?;
+        let (val, rfds) = node.recv_reply_with_fds::<VhostUserU64>(&hdr)?;
+        if val.value & 0xff != 0 {
+            Endpoint::<MasterReq>::close_rfds(rfds);
+            let err = VhostUserError::from_reply_code(val.value & 0xff, hdr.get_code());
+            return error_code(err);
+        }
+        if val.value & VHOST_USER_DEVICE_STATE_NOFD_MASK != 0 {
+            if rfds.is_some() {
+                Endpoint::<MasterReq>::close_rfds(rfds);
+                return error_code(VhostUserError::IncorrectFds);
+            }
+            return Ok(None);
+        }
+        match Endpoint::<MasterReq>::take_single_file(rfds) {
+            Some(file) => Ok(Some(file)),
+            None => error_code(VhostUserError::IncorrectFds),
+        }
+    }
+
+    fn check_device_state(&mut self) -> Result<()> {
+        let mut node = self.node.lock().unwrap();
+        if node.acked_protocol_features & VhostUserProtocolFeatures::DEVICE_STATE.bits() == 0 {
+            return error_code(VhostUserError::InvalidOperation);
+        }
+
+        let hdr = node.send_request_header(MasterReq::CHECK_DEVICE_STATE, None)?;
+        let val = node.recv_reply::<VhostUserU64>(&hdr)?;
+        if val.value != 0 {
+            return error_code(VhostUserError::from_reply_code(val.value, hdr.get_code()));
+        }
+        Ok(())
+    }
+
+    fn get_shared_memory_regions(&mut self) -> Result<Vec<u64>> {
+        let mut node = self.node.lock().unwrap();
+        if node.acked_protocol_features & VhostUserProtocolFeatures::SHMEM.bits() == 0 {
+            return error_code(VhostUserError::InvalidOperation);
+        }
+
+        let hdr = node.send_request_header(MasterReq::GET_SHMEM_CONFIG, None)?;
+        let config = node.recv_reply::<VhostUserShMemConfig>(&hdr)?;
+        Ok(config.sizes())
+    }
+
+    fn set_mem_table_xen(
+        &mut self,
+        regions: &[VhostUserMemoryRegionInfo],
+        xen_mmaps: &[VhostUserXenMmap],
+    ) -> Result<()> {
+        if !self.node.lock().unwrap().is_feature_xen_mmap_available() {
+            return error_code(VhostUserError::InvalidOperation);
+        }
+        if xen_mmaps.iter().any(|xen_mmap| !xen_mmap.is_valid()) {
+            return error_code(VhostUserError::InvalidParam);
+        }
+        self.send_mem_table(regions, xen_mmaps)
+    }
+
+    fn add_mem_region_xen(
+        &mut self,
+        region: &VhostUserMemoryRegionInfo,
+        xen_mmap: VhostUserXenMmap,
+    ) -> Result<()> {
+        if !self.node.lock().unwrap().is_feature_xen_mmap_available() {
+            return error_code(VhostUserError::InvalidOperation);
+        }
+        if !xen_mmap.is_valid() {
+            return error_code(VhostUserError::InvalidParam);
+        }
+        self.send_mem_region(region, xen_mmap)
+    }
+
+    fn set_vring_inband(&mut self, queue_index: usize) -> Result<()> {
+        let mut node = self.node.lock().unwrap();
+        if !node.is_feature_inband_notifications_available() {
+            return error_code(VhostUserError::InvalidOperation);
+        }
+        // The kick comes last, as it starts the vring.
+        for code in &[
+            MasterReq::SET_VRING_CALL,
+            MasterReq::SET_VRING_ERR,
+            MasterReq::SET_VRING_KICK,
+        ] {
+            let hdr = node.send_nofd_for_vring(*code, queue_index)?;
+            node.wait_for_ack(&hdr)?;
+        }
+        Ok(())
+    }
+
+    fn vring_kick(&mut self, queue_index: usize) -> Result<()> {
+        let mut node = self.node.lock().unwrap();
+        if !node.is_feature_inband_notifications_available() {
+            return error_code(VhostUserError::InvalidOperation);
+        } else if queue_index as u64 >= node.max_queue_num {
+            return error_code(VhostUserError::InvalidParam);
+        }
+        let val = VhostUserVringState::new(queue_index as u32, 0);
+        let hdr = node.send_request_with_body(MasterReq::VRING_KICK, &val, None)?;
+        node.wait_for_ack(&hdr).map_err(|e| e.into())
+    }
+}
+
+/// Features negotiated by the master, saved in its snapshots.
+#[cfg(feature = "versionize")]
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct MasterState {
+    /// Virtio features returned by GET_FEATURES.
+    pub virtio_features: u64,
+    /// Virtio features acked by SET_FEATURES.
+    pub acked_virtio_features: u64,
+    /// Protocol features returned by GET_PROTOCOL_FEATURES.
+    pub protocol_features: u64,
+    /// Protocol features acked by SET_PROTOCOL_FEATURES.
+    pub acked_protocol_features: u64,
+    /// Whether the protocol features have been acked.
+    pub protocol_features_ready: bool,
+    /// Maximum number of queues supported by the slave.
+    pub max_queue_num: u64,
+}
+
+#[cfg(feature = "versionize")]
+impl Versionize for MasterState {
+    fn serialize(&self, buf: &mut Vec<u8>, version: u16) {
+        self.virtio_features.serialize(buf, version);
+        self.acked_virtio_features.serialize(buf, version);
+        self.protocol_features.serialize(buf, version);
+        self.acked_protocol_features.serialize(buf, version);
+        self.protocol_features_ready.serialize(buf, version);
+        self.max_queue_num.serialize(buf, version);
+    }
+
+    fn deserialize(input: &mut &[u8], version: u16) -> Result<Self> {
+        Ok(MasterState {
+            virtio_features: u64::deserialize(input, version)?,
+            acked_virtio_features: u64::deserialize(input, version)?,
+            protocol_features: u64::deserialize(input, version)?,
+            acked_protocol_features: u64::deserialize(input, version)?,
+            protocol_features_ready: bool::deserialize(input, version)?,
+            max_queue_num: u64::deserialize(input, version)?,
+        })
+    }
+}
+
+#[cfg(feature = "versionize")]
+const MASTER_ID: &str = "vhost-user-master";
+
+// Only the negotiated features are saved and restored, without sending any request: the slave
+// restores the rest of the session, with `Daemon::restore()` for instance.
+#[cfg(feature = "versionize")]
+impl Snapshottable for Master {
+    fn id(&self) -> String {
+        MASTER_ID.to_string()
+    }
+
+    fn snapshot(&mut self) -> Result<Snapshot> {
+        let node = self.node.lock().unwrap();
+        let state = MasterState {
+            virtio_features: node.virtio_features,
+            acked_virtio_features: node.acked_virtio_features,
+            protocol_features: node.protocol_features,
+            acked_protocol_features: node.acked_protocol_features,
+            protocol_features_ready: node.protocol_features_ready,
+            max_queue_num: node.max_queue_num,
+        };
+        Ok(Snapshot::new(MASTER_ID, &state, MasterState::version()))
+    }
+
+    fn restore(&mut self, snapshot: &Snapshot) -> Result<()> {
+        let state: MasterState = snapshot.to_state(MASTER_ID)?;
+        let mut node = self.node.lock().unwrap();
+        node.virtio_features = state.virtio_features;
+        node.acked_virtio_features = state.acked_virtio_features;
+        node.protocol_features = state.protocol_features;
+        node.acked_protocol_features = state.acked_protocol_features;
+        node.protocol_features_ready = state.protocol_features_ready;
+        node.max_queue_num = state.max_queue_num;
         Ok(())
     }
 }
@@ -471,6 +1308,15 @@ struct MasterInternal {
     protocol_features_ready: bool,
     // Cached maxinum number of queues supported from the slave.
     max_queue_num: u64,
+    // Whether the slave has been switched to postcopy mode.
+    postcopy_listening: bool,
+    // Sequence number of the last request sent to the slave.
+    seq: u64,
+    // Sequence numbers and types of the requests which timed out, whose replies are still to be
+    // received and discarded.
+    pending_replies: VecDeque<(u64, MasterReq)>,
+    // Maximum number of pending replies before giving up on the slave.
+    max_pending_replies: usize,
     // Internal flag to mark failure state.
     error: Option<i32>,
 }
@@ -482,7 +1328,7 @@ impl MasterInternal {
         fds: Option<&[RawFd]>,
     ) -> VhostUserResult<VhostUserMsgHeader<MasterReq>> {
         self.check_state()?;
-        let hdr = Self::new_request_header(code, 0);
+        let hdr = self.new_request_header(code, 0);
         self.main_sock.send_header(&hdr, fds)?;
         Ok(hdr)
     }
@@ -498,7 +1344,7 @@ impl MasterInternal {
         }
         self.check_state()?;
 
-        let hdr = Self::new_request_header(code, mem::size_of::<T>() as u32);
+        let hdr = self.new_request_header(code, mem::size_of::<T>() as u32);
         self.main_sock.send_message(&hdr, msg, fds)?;
         Ok(hdr)
     }
@@ -510,18 +1356,18 @@ impl MasterInternal {
         payload: &[P],
         fds: Option<&[RawFd]>,
     ) -> VhostUserResult<VhostUserMsgHeader<MasterReq>> {
-        let len = mem::size_of::<T>() + payload.len() * mem::size_of::<P>();
-        if len > MAX_MSG_SIZE {
+        let len = mem::size_of::<T>() + mem::size_of_val(payload);
+        if len > self.main_sock.max_msg_size() {
             return Err(VhostUserError::InvalidParam);
         }
-        if let Some(ref fd_arr) = fds {
+        if let Some(fd_arr) = fds {
             if fd_arr.len() > MAX_ATTACHED_FD_ENTRIES {
                 return Err(VhostUserError::InvalidParam);
             }
         }
         self.check_state()?;
 
-        let hdr = Self::new_request_header(code, len as u32);
+        let hdr = self.new_request_header(code, len as u32);
         self.main_sock
             .send_message_with_payload(&hdr, msg, payload, fds)?;
         Ok(hdr)
@@ -542,11 +1388,23 @@ impl MasterInternal {
         // This flag is set when there is no file descriptor in the ancillary data. This signals
         // that polling will be used instead of waiting for the call.
         let msg = VhostUserU64::new(queue_index as u64);
-        let hdr = Self::new_request_header(code, mem::size_of::<VhostUserU64>() as u32);
+        let hdr = self.new_request_header(code, mem::size_of::<VhostUserU64>() as u32);
         self.main_sock.send_message(&hdr, &msg, Some(&[fd]))?;
         Ok(hdr)
     }
 
+    fn send_nofd_for_vring(
+        &mut self,
+        code: MasterReq,
+        queue_index: usize,
+    ) -> VhostUserResult<VhostUserMsgHeader<MasterReq>> {
+        if queue_index as u64 >= self.max_queue_num {
+            return Err(VhostUserError::InvalidParam);
+        }
+        let msg = VhostUserU64::new(queue_index as u64 | VHOST_USER_VRING_NOFD_MASK);
+        self.send_request_with_body(code, &msg, None)
+    }
+
     fn recv_reply<T: Sized + Default + VhostUserMsgValidator>(
         &mut self,
         hdr: &VhostUserMsgHeader<MasterReq>,
@@ -556,14 +1414,39 @@ impl MasterInternal {
         }
         self.check_state()?;
 
-        let (reply, body, rfds) = self.main_sock.recv_body::<T>()?;
-        if !reply.is_reply_for(&hdr) || rfds.is_some() || !body.is_valid() {
+        let (reply, body, rfds) = self.recv_within_timeout(hdr, |sock| sock.recv_body::<T>())?;
+        if let Err(e) = self.check_reply(hdr, &reply) {
+            Endpoint::<MasterReq>::close_rfds(rfds);
+            return Err(e);
+        }
+        if rfds.is_some() || !body.is_valid() {
             Endpoint::<MasterReq>::close_rfds(rfds);
             return Err(VhostUserError::InvalidMessage);
         }
         Ok(body)
     }
 
+    fn recv_reply_with_fds<T: Sized + Default + VhostUserMsgValidator>(
+        &mut self,
+        hdr: &VhostUserMsgHeader<MasterReq>,
+    ) -> VhostUserResult<(T, Option<Vec<RawFd>>)> {
+        if mem::size_of::<T>() > MAX_MSG_SIZE || hdr.is_reply() {
+            return Err(VhostUserError::InvalidParam);
+        }
+        self.check_state()?;
+
+        let (reply, body, rfds) = self.recv_within_timeout(hdr, |sock| sock.recv_body::<T>())?;
+        if let Err(e) = self.check_reply(hdr, &reply) {
+            Endpoint::<MasterReq>::close_rfds(rfds);
+            return Err(e);
+        }
+        if !body.is_valid() {
+            Endpoint::<MasterReq>::close_rfds(rfds);
+            return Err(VhostUserError::InvalidMessage);
+        }
+        Ok((body, rfds))
+    }
+
     fn recv_reply_with_payload<T: Sized + Default + VhostUserMsgValidator>(
         &mut self,
         hdr: &VhostUserMsgHeader<MasterReq>,
@@ -574,15 +1457,19 @@ impl MasterInternal {
         self.check_state()?;
 
         let mut buf: Vec<u8> = vec![0; hdr.get_size() as usize - mem::size_of::<T>()];
-        let (reply, body, bytes, rfds) = self.main_sock.recv_payload_into_buf::<T>(&mut buf)?;
-        if !reply.is_reply_for(hdr)
-            || reply.get_size() as usize != mem::size_of::<T>() + bytes
+        let (reply, body, bytes, rfds) =
+            self.recv_within_timeout(hdr, |sock| sock.recv_payload_into_buf::<T>(&mut buf))?;
+        if let Err(e) = self.check_reply(hdr, &reply) {
+            Endpoint::<MasterReq>::close_rfds(rfds);
+            return Err(e);
+        }
+        if reply.get_size() as usize != mem::size_of::<T>() + bytes
             || rfds.is_some()
             || !body.is_valid()
         {
             Endpoint::<MasterReq>::close_rfds(rfds);
             return Err(VhostUserError::InvalidMessage);
-        } else if bytes > MAX_MSG_SIZE - mem::size_of::<T>() {
+        } else if bytes > self.main_sock.max_msg_size() - mem::size_of::<T>() {
             return Err(VhostUserError::InvalidMessage);
         } else if bytes < buf.len() {
             // It's safe because we have checked the buffer size
@@ -599,22 +1486,113 @@ impl MasterInternal {
         }
         self.check_state()?;
 
-        let (reply, body, rfds) = self.main_sock.recv_body::<VhostUserU64>()?;
-        if !reply.is_reply_for(&hdr) || rfds.is_some() || !body.is_valid() {
+        let (reply, body, rfds) =
+            self.recv_within_timeout(hdr, |sock| sock.recv_body::<VhostUserU64>())?;
+        if let Err(e) = self.check_reply(hdr, &reply) {
+            Endpoint::<MasterReq>::close_rfds(rfds);
+            return Err(e);
+        }
+        if rfds.is_some() || !body.is_valid() {
             Endpoint::<MasterReq>::close_rfds(rfds);
             return Err(VhostUserError::InvalidMessage);
         }
         if body.value != 0 {
-            return Err(VhostUserError::SlaveInternalError);
+            return Err(VhostUserError::from_reply_code(body.value, hdr.get_code()));
+        }
+        Ok(())
+    }
+
+    // Once postcopy mode has been entered, the slave always acknowledges memory slot updates, no
+    // matter whether REPLY_ACK has been negotiated.
+    fn wait_for_mem_slot_ack(
+        &mut self,
+        hdr: &VhostUserMsgHeader<MasterReq>,
+    ) -> VhostUserResult<()> {
+        if !self.postcopy_listening {
+            return self.wait_for_ack(hdr);
+        }
+
+        let val = self.recv_reply::<VhostUserU64>(hdr)?;
+        if val.value != 0 {
+            return Err(VhostUserError::from_reply_code(val.value, hdr.get_code()));
         }
         Ok(())
     }
 
+    // Receive the reply to the last request `hdr`, once the replies to the requests which timed
+    // out have been discarded. Replies come in the order of the requests.
+    fn recv_within_timeout<T, F>(
+        &mut self,
+        hdr: &VhostUserMsgHeader<MasterReq>,
+        recv: F,
+    ) -> VhostUserResult<T>
+    where
+        F: FnOnce(&mut Endpoint<MasterReq>) -> VhostUserResult<T>,
+    {
+        while let Some(&(seq, request)) = self.pending_replies.front() {
+            match self.main_sock.discard_message() {
+                Ok(reply)
+                    if reply.is_reply()
+                        && !reply.is_need_reply()
+                        && reply.get_code() == request =>
+                {
+                    self.pending_replies.pop_front();
+                }
+                Ok(_) => {
+                    self.error = Some(libc::EPROTO);
+                    return Err(VhostUserError::UnexpectedReply { seq, request });
+                }
+                Err(VhostUserError::Timeout) => {
+                    self.pending_replies.push_back((self.seq, hdr.get_code()));
+                    return Err(VhostUserError::Timeout);
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        let res = recv(&mut self.main_sock);
+        if let Err(VhostUserError::Timeout) = res {
+            self.pending_replies.push_back((self.seq, hdr.get_code()));
+        }
+        res
+    }
+
+    // Check the reply matches the last request `hdr`. The replies to the next requests couldn't
+    // be matched anymore otherwise, so the connection is marked as failed.
+    fn check_reply(
+        &mut self,
+        hdr: &VhostUserMsgHeader<MasterReq>,
+        reply: &VhostUserMsgHeader<MasterReq>,
+    ) -> VhostUserResult<()> {
+        if !reply.is_reply_for(hdr) {
+            self.error = Some(libc::EPROTO);
+            return Err(VhostUserError::UnexpectedReply {
+                seq: self.seq,
+                request: hdr.get_code(),
+            });
+        }
+        Ok(())
+    }
+
+    fn is_feature_xen_mmap_available(&self) -> bool {
+        self.acked_protocol_features & VhostUserProtocolFeatures::XEN_MMAP.bits() != 0
+    }
+
+    fn is_feature_mem_slots_available(&self) -> bool {
+        self.acked_protocol_features & VhostUserProtocolFeatures::CONFIGURE_MEM_SLOTS.bits() != 0
+    }
+
+    fn is_feature_inband_notifications_available(&self) -> bool {
+        self.acked_protocol_features & VhostUserProtocolFeatures::INBAND_NOTIFICATIONS.bits() != 0
+    }
+
     fn is_feature_mq_available(&self) -> bool {
         self.acked_protocol_features & VhostUserProtocolFeatures::MQ.bits() != 0
     }
 
     fn check_state(&self) -> VhostUserResult<()> {
+        if self.pending_replies.len() > self.max_pending_replies {
+            return Err(VhostUserError::Timeout);
+        }
         match self.error {
             Some(e) => Err(VhostUserError::SocketBroken(
                 std::io::Error::from_raw_os_error(e),
@@ -623,10 +1601,21 @@ impl MasterInternal {
         }
     }
 
+    // Once REPLY_ACK has been negotiated, ask the slave to acknowledge the requests without
+    // replies, so failures of the slave are reported to the caller.
     #[inline]
-    fn new_request_header(request: MasterReq, size: u32) -> VhostUserMsgHeader<MasterReq> {
-        // TODO: handle NEED_REPLY flag
-        VhostUserMsgHeader::new(request, 0x1, size)
+    fn new_request_header(
+        &mut self,
+        request: MasterReq,
+        size: u32,
+    ) -> VhostUserMsgHeader<MasterReq> {
+        self.seq += 1;
+        let mut hdr = VhostUserMsgHeader::new(request, 0x1, size);
+        hdr.set_need_reply(
+            self.acked_protocol_features & VhostUserProtocolFeatures::REPLY_ACK.bits() != 0
+                && !request.has_reply(),
+        );
+        hdr
     }
 }
 
@@ -639,6 +1628,12 @@ mod tests {
     const UNIX_SOCKET_MASTER2: &'static str = "/tmp/vhost_user_test_rust_master2";
     const UNIX_SOCKET_MASTER3: &'static str = "/tmp/vhost_user_test_rust_master3";
     const UNIX_SOCKET_MASTER4: &'static str = "/tmp/vhost_user_test_rust_master4";
+    const UNIX_SOCKET_MASTER_TIMEOUT: &str = "/tmp/vhost_user_test_rust_master_timeout";
+    const UNIX_SOCKET_MASTER_VRING_BASE: &str = "/tmp/vhost_user_test_rust_master_vring_base";
+    const UNIX_SOCKET_MASTER_UNEXPECTED_REPLY: &str =
+        "/tmp/vhost_user_test_rust_master_unexpected_reply";
+    #[cfg(feature = "versionize")]
+    const UNIX_SOCKET_MASTER_SNAPSHOT: &str = "/tmp/vhost_user_test_rust_master_snapshot";
 
     fn create_pair(path: &str) -> (Master, Endpoint<MasterReq>) {
         let listener = Listener::new(path, true).unwrap();
@@ -721,6 +1716,28 @@ mod tests {
         assert!(master.get_features().is_err());
     }
 
+    #[cfg(feature = "versionize")]
+    #[test]
+    fn test_master_snapshot() {
+        let (mut master, mut peer) = create_pair(UNIX_SOCKET_MASTER_SNAPSHOT);
+
+        let hdr = VhostUserMsgHeader::new(MasterReq::GET_FEATURES, 0x4, 8);
+        let msg = VhostUserU64::new(0x15);
+        peer.send_message(&hdr, &msg, None).unwrap();
+        master.get_features().unwrap();
+        master.set_features(0x15).unwrap();
+        let snapshot = master.snapshot().unwrap();
+        assert_eq!(snapshot.id, master.id());
+
+        // The negotiated features are restored without any request.
+        let (mut restored, _peer) = create_pair(UNIX_SOCKET_MASTER_SNAPSHOT);
+        restored.restore(&snapshot).unwrap();
+        assert_eq!(restored.snapshot().unwrap(), snapshot);
+        let state: MasterState = snapshot.to_state(&master.id()).unwrap();
+        assert_eq!(state.acked_virtio_features, 0x15);
+        assert_eq!(state.max_queue_num, 2);
+    }
+
     #[test]
     #[ignore]
     fn test_protocol_features() {
@@ -781,4 +1798,114 @@ mod tests {
     fn test_get_ring_num() {
         // TODO
     }
+
+    #[test]
+    fn test_get_vring_base() {
+        let (mut master, mut peer) = create_pair(UNIX_SOCKET_MASTER_VRING_BASE);
+        let flags = VhostUserHeaderFlag::REPLY.bits();
+        let reply = VhostUserMsgHeader::new(MasterReq::GET_VRING_BASE, flags, 8);
+
+        let msg = VhostUserVringState::new(1, 0x1234);
+        peer.send_message(&reply, &msg, None).unwrap();
+        assert_eq!(master.get_vring_base(1).unwrap(), 0x1234);
+        let (hdr, msg, rfds) = peer.recv_body::<VhostUserVringState>().unwrap();
+        assert_eq!(hdr.get_code(), MasterReq::GET_VRING_BASE);
+        assert_eq!({ msg.index }, 1);
+        assert!(rfds.is_none());
+
+        // The reply is for another vring.
+        let msg = VhostUserVringState::new(0, 0x1234);
+        peer.send_message(&reply, &msg, None).unwrap();
+        assert!(master.get_vring_base(1).is_err());
+        peer.recv_body::<VhostUserVringState>().unwrap();
+
+        assert!(master.get_vring_base(2).is_err());
+    }
+
+    #[test]
+    fn test_reply_timeout() {
+        let (mut master, mut peer) = create_pair(UNIX_SOCKET_MASTER_TIMEOUT);
+        peer.set_timeout(Some(Duration::from_millis(10))).unwrap();
+        master.set_timeout(Some(Duration::from_millis(10))).unwrap();
+
+        match master.get_features() {
+            Err(Error::VhostUserProtocol(VhostUserError::Timeout)) => {}
+            _ => panic!("expected timeout"),
+        }
+
+        // The late reply is discarded.
+        let (hdr, _) = peer.recv_header().unwrap();
+        assert_eq!(hdr.get_code(), MasterReq::GET_FEATURES);
+        let flags = VhostUserHeaderFlag::REPLY.bits();
+        let reply = VhostUserMsgHeader::new(MasterReq::GET_FEATURES, flags, 8);
+        let msg = VhostUserU64::new(1);
+        peer.send_message(&reply, &msg, None).unwrap();
+        let msg = VhostUserU64::new(2);
+        peer.send_message(&reply, &msg, None).unwrap();
+        assert_eq!(master.get_features().unwrap(), 2);
+        peer.recv_header().unwrap();
+
+        // The slave is given up on once too many replies are pending.
+        master.set_max_pending_replies(0);
+        assert!(master.get_features().is_err());
+        peer.recv_header().unwrap();
+        match master.get_features() {
+            Err(Error::VhostUserProtocol(VhostUserError::Timeout)) => {}
+            _ => panic!("expected timeout"),
+        }
+        match peer.recv_header() {
+            Err(VhostUserError::Timeout) => {}
+            _ => panic!("the request shouldn't have been sent"),
+        }
+    }
+
+    #[test]
+    fn test_unexpected_reply() {
+        let (mut master, mut peer) = create_pair(UNIX_SOCKET_MASTER_UNEXPECTED_REPLY);
+        let flags = VhostUserHeaderFlag::REPLY.bits();
+        let reply = VhostUserMsgHeader::new(MasterReq::GET_FEATURES, flags, 8);
+        let msg = VhostUserU64::new(1);
+
+        // Replies asking for a reply are rejected.
+        let mut bad_reply = reply;
+        bad_reply.set_need_reply(true);
+        peer.send_message(&bad_reply, &msg, None).unwrap();
+        match master.get_features() {
+            Err(Error::VhostUserProtocol(VhostUserError::UnexpectedReply {
+                seq: 1,
+                request: MasterReq::GET_FEATURES,
+            })) => {}
+            r => panic!("unexpected result {:?}", r),
+        }
+        peer.recv_header().unwrap();
+        // The connection is broken from then on.
+        match master.get_features() {
+            Err(Error::VhostUserProtocol(VhostUserError::SocketBroken(_))) => {}
+            r => panic!("unexpected result {:?}", r),
+        }
+
+        let (mut master, mut peer) = create_pair(UNIX_SOCKET_MASTER_UNEXPECTED_REPLY);
+        peer.set_timeout(Some(Duration::from_millis(10))).unwrap();
+        master.set_timeout(Some(Duration::from_millis(10))).unwrap();
+        master.set_owner().unwrap();
+        peer.recv_header().unwrap();
+        match master.get_features() {
+            Err(Error::VhostUserProtocol(VhostUserError::Timeout)) => {}
+            _ => panic!("expected timeout"),
+        }
+        peer.recv_header().unwrap();
+
+        // The late reply is for another request than the one which timed out.
+        let stale = VhostUserMsgHeader::new(MasterReq::GET_PROTOCOL_FEATURES, flags, 8);
+        peer.send_message(&stale, &msg, None).unwrap();
+        peer.send_message(&reply, &msg, None).unwrap();
+        match master.get_features() {
+            Err(Error::VhostUserProtocol(VhostUserError::UnexpectedReply {
+                seq: 2,
+                request: MasterReq::GET_FEATURES,
+            })) => {}
+            r => panic!("unexpected result {:?}", r),
+        }
+        assert!(master.get_features().is_err());
+    }
 }
@@ -0,0 +1,280 @@
+// Copyright (C) 2020 Alibaba Cloud Computing. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Master side of the virtio-fs DAX window.
+//!
+//! The master reserves the DAX window, the shared memory region exposed to the guest as the
+//! virtio-fs cache, and maps it into the guest. The slave then asks the master to map ranges of
+//! its files into the window, to unmap or to sync them, and to do file IO on behalf of the guest
+//! when the data lives in the window, by sending FS_MAP, FS_UNMAP, FS_SYNC and FS_IO requests
+//! through the slave request channel.
+
+use std::fs::File;
+use std::io;
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::ptr;
+
+use super::message::{VhostUserFSSlaveMsg, VhostUserFSSlaveMsgFlags};
+use super::{HandlerResult, VhostUserMasterReqHandler};
+
+/// Length of FS_UNMAP entries asking to unmap everything from the cache offset to the end of the
+/// window.
+pub const VHOST_USER_FS_UNMAP_ALL: u64 = u64::MAX;
+
+/// The virtio-fs DAX window, serving the FS_MAP, FS_UNMAP, FS_SYNC and FS_IO slave requests.
+///
+/// The cache offsets of the requests are offsets in the window, and unmapped ranges of the window
+/// are inaccessible.
+pub struct FsCacheWindow {
+    addr: *mut u8,
+    size: u64,
+}
+
+// Safe because the window is only accessed through the kernel, by the methods of the handler.
+unsafe impl Send for FsCacheWindow {}
+
+impl FsCacheWindow {
+    /// Reserve a window of `size` bytes.
+    pub fn new(size: u64) -> io::Result<Self> {
+        if size == 0 {
+            return Err(io::Error::from_raw_os_error(libc::EINVAL));
+        }
+        // Safe because we check the return value, and the mapping is owned by the window.
+        let addr = unsafe {
+            libc::mmap(
+                ptr::null_mut(),
+                size as usize,
+                libc::PROT_NONE,
+                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS | libc::MAP_NORESERVE,
+                -1,
+                0,
+            )
+        };
+        if addr == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(FsCacheWindow {
+            addr: addr as *mut u8,
+            size,
+        })
+    }
+
+    /// Get the host virtual address of the window, for the master to map it into the guest.
+    pub fn as_ptr(&self) -> *mut u8 {
+        self.addr
+    }
+
+    /// Get the size of the window.
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    // Get the address of a range of the window, failing if the range overflows the window.
+    fn range(&self, offset: u64, len: u64) -> io::Result<*mut libc::c_void> {
+        match offset.checked_add(len) {
+            Some(end) if end <= self.size => {
+                // Safe because the offset is within the window.
+                Ok(unsafe { self.addr.add(offset as usize) } as *mut libc::c_void)
+            }
+            _ => Err(io::Error::from_raw_os_error(libc::EINVAL)),
+        }
+    }
+
+    // Map anonymous inaccessible memory over a range of the window.
+    fn reset(&self, offset: u64, len: u64) -> io::Result<()> {
+        let addr = self.range(offset, len)?;
+        // Safe because the range is within the window, and we check the return value.
+        let ret = unsafe {
+            libc::mmap(
+                addr,
+                len as usize,
+                libc::PROT_NONE,
+                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS | libc::MAP_NORESERVE | libc::MAP_FIXED,
+                -1,
+                0,
+            )
+        };
+        if ret == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+}
+
+impl Drop for FsCacheWindow {
+    fn drop(&mut self) {
+        // Safe because the window is owned by us.
+        unsafe { libc::munmap(self.addr as *mut libc::c_void, self.size as usize) };
+    }
+}
+
+impl VhostUserMasterReqHandler for FsCacheWindow {
+    fn fs_slave_map(&mut self, fs: &VhostUserFSSlaveMsg, fd: RawFd) -> HandlerResult<u64> {
+        // Safe because we have just received the rawfd from kernel, the file closes it.
+        let file = unsafe { File::from_raw_fd(fd) };
+        for entry in fs.entries() {
+            let addr = self.range(entry.cache_offset, entry.len)?;
+            let mut prot = 0;
+            if entry.flags.contains(VhostUserFSSlaveMsgFlags::MAP_R) {
+                prot |= libc::PROT_READ;
+            }
+            if entry.flags.contains(VhostUserFSSlaveMsgFlags::MAP_W) {
+                prot |= libc::PROT_WRITE;
+            }
+            // Safe because the range is within the window, and we check the return value.
+            let ret = unsafe {
+                libc::mmap(
+                    addr,
+                    entry.len as usize,
+                    prot,
+                    libc::MAP_SHARED | libc::MAP_FIXED,
+                    file.as_raw_fd(),
+                    entry.fd_offset as libc::off_t,
+                )
+            };
+            if ret == libc::MAP_FAILED {
+                return Err(io::Error::last_os_error());
+            }
+        }
+        Ok(0)
+    }
+
+    fn fs_slave_unmap(&mut self, fs: &VhostUserFSSlaveMsg) -> HandlerResult<u64> {
+        for entry in fs.entries() {
+            let len = match entry.len {
+                VHOST_USER_FS_UNMAP_ALL if entry.cache_offset < self.size => {
+                    self.size - entry.cache_offset
+                }
+                len => len,
+            };
+            self.reset(entry.cache_offset, len)?;
+        }
+        Ok(0)
+    }
+
+    fn fs_slave_sync(&mut self, fs: &VhostUserFSSlaveMsg) -> HandlerResult<u64> {
+        for entry in fs.entries() {
+            let addr = self.range(entry.cache_offset, entry.len)?;
+            // Safe because the range is within the window, and we check the return value.
+            if unsafe { libc::msync(addr, entry.len as usize, libc::MS_SYNC) } < 0 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+        Ok(0)
+    }
+
+    /// Read from or write to the file through the window, and return the number of bytes
+    /// transferred. Entries with the MAP_W flag write the window to the file, the others read the
+    /// file into the window.
+    fn fs_slave_io(&mut self, fs: &VhostUserFSSlaveMsg, fd: RawFd) -> HandlerResult<u64> {
+        // Safe because we have just received the rawfd from kernel, the file closes it.
+        let file = unsafe { File::from_raw_fd(fd) };
+        let fd = file.as_raw_fd();
+        let mut done = 0;
+        for entry in fs.entries() {
+            let len = entry.len;
+            let addr = self.range(entry.cache_offset, len)?;
+            let offset = entry.fd_offset as libc::off_t;
+            // Safe because the range is within the window, and we check the return value.
+            let ret = unsafe {
+                if entry.flags.contains(VhostUserFSSlaveMsgFlags::MAP_W) {
+                    libc::pwrite(fd, addr, len as usize, offset)
+                } else {
+                    libc::pread(fd, addr, len as usize, offset)
+                }
+            };
+            if ret < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            done += ret as u64;
+            if (ret as u64) < len {
+                break;
+            }
+        }
+        Ok(done)
+    }
+}
+
+#[cfg(all(test, feature = "vhost-user-slave"))]
+mod tests {
+    use super::*;
+    use crate::vhost_user::message::VhostUserFSSlaveEntry;
+    use crate::vhost_user::{MasterReqHandler, SlaveFsCacheReq};
+    use std::io::{Read, Seek, SeekFrom, Write};
+    use std::os::unix::net::UnixStream;
+    use std::slice;
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+    use vmm_sys_util::tempfile::TempFile;
+
+    fn fs_msg(
+        fd_offset: u64,
+        cache_offset: u64,
+        len: u64,
+        flags: VhostUserFSSlaveMsgFlags,
+    ) -> VhostUserFSSlaveMsg {
+        let entry = VhostUserFSSlaveEntry::new(fd_offset, cache_offset, len, flags);
+        VhostUserFSSlaveMsg::from_entries(&[entry]).unwrap()
+    }
+
+    #[test]
+    fn test_fs_cache_window() {
+        assert!(FsCacheWindow::new(0).is_err());
+        let window = Arc::new(Mutex::new(FsCacheWindow::new(0x10_0000).unwrap()));
+        assert_eq!(window.lock().unwrap().size(), 0x10_0000);
+        let addr = window.lock().unwrap().as_ptr();
+        let mut handler = MasterReqHandler::new(window).unwrap();
+        // Safe because we own the duplicated file descriptor.
+        let stream = unsafe { UnixStream::from_raw_fd(libc::dup(handler.get_tx_raw_fd())) };
+        let mut file = TempFile::new().unwrap().into_file();
+        file.write_all(&[0x5a; 0x2000]).unwrap();
+        let mut out = TempFile::new().unwrap().into_file();
+
+        let (slave_file, slave_out) = (file.try_clone().unwrap(), out.try_clone().unwrap());
+        let slave = thread::spawn(move || {
+            let mut fs_cache = SlaveFsCacheReq::from_stream(stream);
+            let rw = VhostUserFSSlaveMsgFlags::MAP_R | VhostUserFSSlaveMsgFlags::MAP_W;
+            let msg = fs_msg(0x1000, 0x2000, 0x1000, rw);
+            fs_cache.fs_slave_map(&msg, slave_file.as_raw_fd()).unwrap();
+            // The range overflows the window.
+            let msg = fs_msg(0, 0x10_0000, 0x1000, rw);
+            assert!(fs_cache.fs_slave_map(&msg, slave_file.as_raw_fd()).is_err());
+            let msg = fs_msg(0, 0x2000, 0x1000, VhostUserFSSlaveMsgFlags::EMPTY);
+            fs_cache.fs_slave_sync(&msg).unwrap();
+            let msg = fs_msg(0, 0x2000, 0x1000, VhostUserFSSlaveMsgFlags::MAP_W);
+            assert_eq!(
+                fs_cache.fs_slave_io(&msg, slave_out.as_raw_fd()).unwrap(),
+                0x1000
+            );
+            let msg = fs_msg(
+                0,
+                0,
+                VHOST_USER_FS_UNMAP_ALL,
+                VhostUserFSSlaveMsgFlags::EMPTY,
+            );
+            fs_cache.fs_slave_unmap(&msg).unwrap();
+        });
+
+        assert_eq!(handler.handle_request().unwrap(), 0);
+        // Safe because the range has been mapped by the slave request.
+        let cache = unsafe { slice::from_raw_parts_mut(addr.add(0x2000), 0x1000) };
+        assert!(cache.iter().all(|b| *b == 0x5a));
+        cache[..0x10].copy_from_slice(&[0xa5; 0x10]);
+        assert!(handler.handle_request().is_err());
+        assert_eq!(handler.handle_request().unwrap(), 0);
+        assert_eq!(handler.handle_request().unwrap(), 0x1000);
+        assert_eq!(handler.handle_request().unwrap(), 0);
+        slave.join().unwrap();
+
+        let mut buf = vec![0u8; 0x20];
+        file.seek(SeekFrom::Start(0x1000 - 0x10)).unwrap();
+        file.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf[..0x10], &[0x5a; 0x10]);
+        assert_eq!(&buf[0x10..], &[0xa5; 0x10]);
+        let mut buf = vec![0u8; 0x1000];
+        out.seek(SeekFrom::Start(0)).unwrap();
+        out.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf[..0x10], &[0xa5; 0x10]);
+        assert!(buf[0x10..].iter().all(|b| *b == 0x5a));
+    }
+}
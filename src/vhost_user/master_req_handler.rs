@@ -4,25 +4,73 @@
 //! Traits and Structs to handle vhost-user requests from the slave to the master.
 
 use libc;
+use std::fs::File;
+use std::io;
 use std::mem;
 use std::os::unix::io::{AsRawFd, RawFd};
 use std::os::unix::net::UnixStream;
 use std::sync::{Arc, Mutex};
+use std::thread;
+
+use vmm_sys_util::eventfd::{EventFd, EFD_NONBLOCK};
 
 use super::connection::Endpoint;
 use super::message::*;
+use super::parser;
 use super::{Error, HandlerResult, Result};
 
 /// Trait to handle vhost-user requests from the slave to the master.
 pub trait VhostUserMasterReqHandler {
-    // fn handle_iotlb_msg(&mut self, iotlb: VhostUserIotlb);
-    // fn handle_vring_host_notifier(&mut self, area: VhostUserVringArea, fd: RawFd);
+    /// Handle IOTLB miss and access failure messages from the slave.
+    fn handle_iotlb_msg(&mut self, _iotlb: &VhostUserIotlb) -> HandlerResult<u64> {
+        Err(std::io::Error::from_raw_os_error(libc::ENOSYS))
+    }
 
     /// Handle device configuration change notifications from the slave.
     fn handle_config_change(&mut self) -> HandlerResult<u64> {
         Err(std::io::Error::from_raw_os_error(libc::ENOSYS))
     }
 
+    /// Handle vring call notifications from the slave, sent instead of signaling the call eventfd
+    /// when the INBAND_NOTIFICATIONS protocol feature has been negotiated.
+    fn handle_vring_call(&mut self, _vring: &VhostUserVringState) -> HandlerResult<u64> {
+        Err(std::io::Error::from_raw_os_error(libc::ENOSYS))
+    }
+
+    /// Handle vring error notifications from the slave.
+    fn handle_vring_err(&mut self, _vring: &VhostUserVringState) -> HandlerResult<u64> {
+        Err(std::io::Error::from_raw_os_error(libc::ENOSYS))
+    }
+
+    /// Handle host notifier requests from the slave.
+    ///
+    /// When `fd` is some, the master should map `area.size` bytes at `area.offset` of the file
+    /// into the guest MMIO space, as the notification area of the vring. Otherwise the host
+    /// notifier of the vring is removed.
+    fn handle_vring_host_notifier(
+        &mut self,
+        _area: &VhostUserVringArea,
+        _fd: Option<File>,
+    ) -> HandlerResult<u64> {
+        Err(std::io::Error::from_raw_os_error(libc::ENOSYS))
+    }
+
+    /// Handle requests from the slave to register itself as the exporter of a shared object.
+    fn shared_object_add(&mut self, _shared: &VhostUserShared) -> HandlerResult<u64> {
+        Err(std::io::Error::from_raw_os_error(libc::ENOSYS))
+    }
+
+    /// Handle requests from the slave to unregister itself as the exporter of a shared object.
+    fn shared_object_remove(&mut self, _shared: &VhostUserShared) -> HandlerResult<u64> {
+        Err(std::io::Error::from_raw_os_error(libc::ENOSYS))
+    }
+
+    /// Handle shared object lookups from the slave, returning the file descriptor of the object,
+    /// usually retrieved from its exporter through GET_SHARED_OBJECT.
+    fn shared_object_lookup(&mut self, _shared: &VhostUserShared) -> HandlerResult<File> {
+        Err(std::io::Error::from_raw_os_error(libc::ENOSYS))
+    }
+
     /// Handle virtio-fs map file requests from the slave.
     fn fs_slave_map(&mut self, _fs: &VhostUserFSSlaveMsg, fd: RawFd) -> HandlerResult<u64> {
         // Safe because we have just received the rawfd from kernel.
@@ -48,12 +96,148 @@ pub trait VhostUserMasterReqHandler {
     }
 }
 
+/// Handler of slave requests signaling an eventfd when the slave notifies a configuration change.
+///
+/// VMMs usually register the eventfd as an irqfd for the configuration change interrupt of the
+/// device, so the guest driver gets notified without going through the VMM.
+pub struct ConfigChangeNotifier {
+    event: EventFd,
+}
+
+impl ConfigChangeNotifier {
+    /// Create a new notifier signaling `event` on configuration changes.
+    pub fn new(event: EventFd) -> Self {
+        ConfigChangeNotifier { event }
+    }
+
+    /// Get the eventfd signaled on configuration changes.
+    pub fn event(&self) -> &EventFd {
+        &self.event
+    }
+}
+
+impl VhostUserMasterReqHandler for ConfigChangeNotifier {
+    fn handle_config_change(&mut self) -> HandlerResult<u64> {
+        self.event.write(1)?;
+        Ok(0)
+    }
+}
+
+type IotlbHandler = Box<dyn FnMut(&VhostUserIotlb) -> HandlerResult<u64> + Send>;
+type ConfigChangeHandler = Box<dyn FnMut() -> HandlerResult<u64> + Send>;
+type VringHandler = Box<dyn FnMut(&VhostUserVringState) -> HandlerResult<u64> + Send>;
+type HostNotifierHandler =
+    Box<dyn FnMut(&VhostUserVringArea, Option<File>) -> HandlerResult<u64> + Send>;
+
+/// Handler of slave requests dispatching each type of request to the closure registered for it.
+///
+/// Requests without a registered closure are rejected with ENOSYS, as by the default methods of
+/// `VhostUserMasterReqHandler`. Together with `MasterReqHandler::spawn()`, this saves VMMs from
+/// demultiplexing the requests of the slave themselves.
+#[derive(Default)]
+pub struct MasterReqDispatcher {
+    iotlb: Option<IotlbHandler>,
+    config_change: Option<ConfigChangeHandler>,
+    vring_call: Option<VringHandler>,
+    vring_err: Option<VringHandler>,
+    host_notifier: Option<HostNotifierHandler>,
+}
+
+impl MasterReqDispatcher {
+    /// Create a new dispatcher, without any registered closure.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Handle IOTLB miss and access failure messages with `handler`, which usually translates the
+    /// IOVA and sends the matching update back to the slave.
+    pub fn set_iotlb_handler<F>(&mut self, handler: F)
+    where
+        F: FnMut(&VhostUserIotlb) -> HandlerResult<u64> + Send + 'static,
+    {
+        self.iotlb = Some(Box::new(handler));
+    }
+
+    /// Handle device configuration change notifications with `handler`.
+    pub fn set_config_change_handler<F>(&mut self, handler: F)
+    where
+        F: FnMut() -> HandlerResult<u64> + Send + 'static,
+    {
+        self.config_change = Some(Box::new(handler));
+    }
+
+    /// Handle in-band vring call notifications with `handler`.
+    pub fn set_vring_call_handler<F>(&mut self, handler: F)
+    where
+        F: FnMut(&VhostUserVringState) -> HandlerResult<u64> + Send + 'static,
+    {
+        self.vring_call = Some(Box::new(handler));
+    }
+
+    /// Handle vring error notifications with `handler`.
+    pub fn set_vring_err_handler<F>(&mut self, handler: F)
+    where
+        F: FnMut(&VhostUserVringState) -> HandlerResult<u64> + Send + 'static,
+    {
+        self.vring_err = Some(Box::new(handler));
+    }
+
+    /// Handle host notifier requests with `handler`.
+    pub fn set_vring_host_notifier_handler<F>(&mut self, handler: F)
+    where
+        F: FnMut(&VhostUserVringArea, Option<File>) -> HandlerResult<u64> + Send + 'static,
+    {
+        self.host_notifier = Some(Box::new(handler));
+    }
+}
+
+fn not_supported() -> io::Error {
+    io::Error::from_raw_os_error(libc::ENOSYS)
+}
+
+impl VhostUserMasterReqHandler for MasterReqDispatcher {
+    fn handle_iotlb_msg(&mut self, iotlb: &VhostUserIotlb) -> HandlerResult<u64> {
+        self.iotlb
+            .as_mut()
+            .map_or(Err(not_supported()), |f| f(iotlb))
+    }
+
+    fn handle_config_change(&mut self) -> HandlerResult<u64> {
+        self.config_change
+            .as_mut()
+            .map_or(Err(not_supported()), |f| f())
+    }
+
+    fn handle_vring_call(&mut self, vring: &VhostUserVringState) -> HandlerResult<u64> {
+        self.vring_call
+            .as_mut()
+            .map_or(Err(not_supported()), |f| f(vring))
+    }
+
+    fn handle_vring_err(&mut self, vring: &VhostUserVringState) -> HandlerResult<u64> {
+        self.vring_err
+            .as_mut()
+            .map_or(Err(not_supported()), |f| f(vring))
+    }
+
+    fn handle_vring_host_notifier(
+        &mut self,
+        area: &VhostUserVringArea,
+        fd: Option<File>,
+    ) -> HandlerResult<u64> {
+        self.host_notifier
+            .as_mut()
+            .map_or(Err(not_supported()), |f| f(area, fd))
+    }
+}
+
 /// A vhost-user master request endpoint which relays all received requests from the slave to the
 /// provided request handler.
 pub struct MasterReqHandler<S: VhostUserMasterReqHandler> {
     // underlying Unix domain socket for communication
     sub_sock: Endpoint<SlaveReq>,
-    tx_sock: UnixStream,
+    // slave end of the socket pair, until handed to the request thread
+    tx_sock: Option<UnixStream>,
     // the VirtIO backend device object
     backend: Arc<Mutex<S>>,
     // whether the endpoint has encountered any failure
@@ -69,7 +253,7 @@ impl<S: VhostUserMasterReqHandler> MasterReqHandler<S> {
 
         Ok(MasterReqHandler {
             sub_sock: Endpoint::<SlaveReq>::from_stream(rx),
-            tx_sock: tx,
+            tx_sock: Some(tx),
             backend,
             error: None,
         })
@@ -77,7 +261,7 @@ impl<S: VhostUserMasterReqHandler> MasterReqHandler<S> {
 
     /// Get the raw fd to send to the slave as slave communication channel.
     pub fn get_tx_raw_fd(&self) -> RawFd {
-        self.tx_sock.as_raw_fd()
+        self.tx_sock.as_ref().map_or(-1, |sock| sock.as_raw_fd())
     }
 
     /// Mark endpoint as failed or normal state.
@@ -104,19 +288,31 @@ impl<S: VhostUserMasterReqHandler> MasterReqHandler<S> {
         //   message header
         // . validate message body and optional payload
         let (hdr, rfds) = self.sub_sock.recv_header()?;
-        let rfds = self.check_attached_rfds(&hdr, rfds)?;
         let (size, buf) = match hdr.get_size() {
             0 => (0, vec![0u8; 0]),
-            len => {
-                let (size2, rbuf) = self.sub_sock.recv_data(len as usize)?;
-                if size2 != len as usize {
-                    return Err(Error::InvalidMessage);
+            len => match self.sub_sock.recv_data(len as usize) {
+                Ok((size2, rbuf)) if size2 == len as usize => (size2, rbuf),
+                res => {
+                    Endpoint::<SlaveReq>::close_rfds(rfds);
+                    return Err(res.err().unwrap_or(Error::InvalidMessage));
                 }
-                (size2, rbuf)
-            }
+            },
         };
+        let num_fds = rfds.as_ref().map_or(0, |fds| fds.len());
+        if let Err(e) = parser::check_slave_msg(&hdr, &buf, num_fds) {
+            Endpoint::<SlaveReq>::close_rfds(rfds);
+            return Err(e);
+        }
 
         let res = match hdr.get_code() {
+            SlaveReq::IOTLB_MSG => {
+                let msg = self.extract_msg_body::<VhostUserIotlb>(&hdr, size, &buf)?;
+                self.backend
+                    .lock()
+                    .unwrap()
+                    .handle_iotlb_msg(msg)
+                    .map_err(Error::ReqHandlerError)
+            }
             SlaveReq::CONFIG_CHANGE_MSG => {
                 self.check_msg_size(&hdr, size, 0)?;
                 self.backend
@@ -125,6 +321,87 @@ impl<S: VhostUserMasterReqHandler> MasterReqHandler<S> {
                     .handle_config_change()
                     .map_err(Error::ReqHandlerError)
             }
+            SlaveReq::VRING_HOST_NOTIFIER_MSG => {
+                let msg = match self.extract_msg_body::<VhostUserVringArea>(&hdr, size, &buf) {
+                    Ok(msg) => msg,
+                    Err(e) => {
+                        Endpoint::<SlaveReq>::close_rfds(rfds);
+                        return Err(e);
+                    }
+                };
+                let fd = if msg.has_fd() {
+                    match Endpoint::<SlaveReq>::take_single_file(rfds) {
+                        Some(file) => Some(file),
+                        None => return Err(Error::IncorrectFds),
+                    }
+                } else if rfds.is_some() {
+                    Endpoint::<SlaveReq>::close_rfds(rfds);
+                    return Err(Error::IncorrectFds);
+                } else {
+                    None
+                };
+                self.backend
+                    .lock()
+                    .unwrap()
+                    .handle_vring_host_notifier(msg, fd)
+                    .map_err(Error::ReqHandlerError)
+            }
+            SlaveReq::VRING_CALL => {
+                let msg = self.extract_msg_body::<VhostUserVringState>(&hdr, size, &buf)?;
+                self.backend
+                    .lock()
+                    .unwrap()
+                    .handle_vring_call(msg)
+                    .map_err(Error::ReqHandlerError)
+            }
+            SlaveReq::VRING_ERR => {
+                let msg = self.extract_msg_body::<VhostUserVringState>(&hdr, size, &buf)?;
+                self.backend
+                    .lock()
+                    .unwrap()
+                    .handle_vring_err(msg)
+                    .map_err(Error::ReqHandlerError)
+            }
+            SlaveReq::SHARED_OBJECT_ADD => {
+                let msg = self.extract_msg_body::<VhostUserShared>(&hdr, size, &buf)?;
+                self.backend
+                    .lock()
+                    .unwrap()
+                    .shared_object_add(msg)
+                    .map_err(Error::ReqHandlerError)
+            }
+            SlaveReq::SHARED_OBJECT_REMOVE => {
+                let msg = self.extract_msg_body::<VhostUserShared>(&hdr, size, &buf)?;
+                self.backend
+                    .lock()
+                    .unwrap()
+                    .shared_object_remove(msg)
+                    .map_err(Error::ReqHandlerError)
+            }
+            SlaveReq::SHARED_OBJECT_LOOKUP => {
+                let msg = self.extract_msg_body::<VhostUserShared>(&hdr, size, &buf)?;
+                let res = self
+                    .backend
+                    .lock()
+                    .unwrap()
+                    .shared_object_lookup(msg)
+                    .map_err(Error::ReqHandlerError);
+                // Lookups are always replied to, with the file descriptor of the object if found.
+                let reply_hdr = self.new_reply_header::<VhostUserU64>(&hdr)?;
+                return match res {
+                    Ok(file) => {
+                        let msg = VhostUserU64::new(0);
+                        self.sub_sock
+                            .send_message(&reply_hdr, &msg, Some(&[file.as_raw_fd()]))?;
+                        Ok(0)
+                    }
+                    Err(e) => {
+                        let msg = VhostUserU64::new(Self::error_value(&e));
+                        self.sub_sock.send_message(&reply_hdr, &msg, None)?;
+                        Err(e)
+                    }
+                };
+            }
             SlaveReq::FS_MAP => {
                 let msg = self.extract_msg_body::<VhostUserFSSlaveMsg>(&hdr, size, &buf)?;
                 self.backend
@@ -188,37 +465,6 @@ impl<S: VhostUserMasterReqHandler> MasterReqHandler<S> {
         Ok(())
     }
 
-    fn check_attached_rfds(
-        &self,
-        hdr: &VhostUserMsgHeader<SlaveReq>,
-        rfds: Option<Vec<RawFd>>,
-    ) -> Result<Option<Vec<RawFd>>> {
-        match hdr.get_code() {
-            SlaveReq::FS_MAP | SlaveReq::FS_IO => {
-                // Expect an fd set with a single fd.
-                match rfds {
-                    None => Err(Error::InvalidMessage),
-                    Some(fds) => {
-                        if fds.len() != 1 {
-                            Endpoint::<SlaveReq>::close_rfds(Some(fds));
-                            Err(Error::InvalidMessage)
-                        } else {
-                            Ok(Some(fds))
-                        }
-                    }
-                }
-            }
-            _ => {
-                if rfds.is_some() {
-                    Endpoint::<SlaveReq>::close_rfds(rfds);
-                    return Err(Error::InvalidMessage);
-                } else {
-                    Ok(rfds)
-                }
-            }
-        }
-    }
-
     fn extract_msg_body<'a, T: Sized + VhostUserMsgValidator>(
         &self,
         hdr: &VhostUserMsgHeader<SlaveReq>,
@@ -255,22 +501,115 @@ impl<S: VhostUserMasterReqHandler> MasterReqHandler<S> {
     ) -> Result<()> {
         if req.is_need_reply() {
             let hdr = self.new_reply_header::<VhostUserU64>(req)?;
-            let def_err = libc::EINVAL;
             let val = match res {
                 Ok(n) => *n,
-                Err(e) => match &*e {
-                    Error::ReqHandlerError(ioerr) => match ioerr.raw_os_error() {
-                        Some(rawerr) => -rawerr as u64,
-                        None => -def_err as u64,
-                    },
-                    _ => -def_err as u64,
-                },
+                Err(e) => Self::error_value(e),
             };
             let msg = VhostUserU64::new(val);
             self.sub_sock.send_message(&hdr, &msg, None)?;
         }
         Ok(())
     }
+
+    // Replies carry the negative errno of failed requests.
+    fn error_value(err: &Error) -> u64 {
+        let def_err = libc::EINVAL;
+        match err {
+            Error::ReqHandlerError(ioerr) => match ioerr.raw_os_error() {
+                Some(rawerr) => -rawerr as u64,
+                None => -def_err as u64,
+            },
+            _ => -def_err as u64,
+        }
+    }
+}
+
+impl<S: VhostUserMasterReqHandler + Send + 'static> MasterReqHandler<S> {
+    /// Handle the requests from the slave on a dedicated thread named `name`, until the thread
+    /// is stopped or the slave closes the communication channel.
+    ///
+    /// The file descriptor returned by `get_tx_raw_fd()` must have been sent to the slave
+    /// already, as it is closed for the thread to notice when the slave closes its end of the
+    /// channel. Requests failed by the handler are replied to as usual, and don't stop the
+    /// thread.
+    pub fn spawn(mut self, name: String) -> Result<MasterReqThread> {
+        let exit_event = EventFd::new(EFD_NONBLOCK).map_err(Error::SocketError)?;
+        let thread_exit_event = exit_event.try_clone().map_err(Error::SocketError)?;
+        self.tx_sock = None;
+        let handle = thread::Builder::new()
+            .name(name)
+            .spawn(move || -> Result<()> {
+                loop {
+                    if !wait_for_request(self.as_raw_fd(), &thread_exit_event)
+                        .map_err(Error::SocketError)?
+                    {
+                        return Ok(());
+                    }
+                    match self.handle_request() {
+                        Ok(_)
+                        | Err(Error::ReqHandlerError(_))
+                        | Err(Error::InvalidMessage)
+                        | Err(Error::IncorrectFds) => {}
+                        Err(e) => return Err(e),
+                    }
+                }
+            })
+            .map_err(Error::SocketError)?;
+
+        Ok(MasterReqThread { handle, exit_event })
+    }
+}
+
+// Wait for `fd` to be readable, returning false if `exit_event` has been signaled instead.
+fn wait_for_request(fd: RawFd, exit_event: &EventFd) -> io::Result<bool> {
+    let mut fds = [
+        libc::pollfd {
+            fd: exit_event.as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        },
+        libc::pollfd {
+            fd,
+            events: libc::POLLIN,
+            revents: 0,
+        },
+    ];
+    loop {
+        // Safe because the array outlives the call, and we check the return value.
+        let ret = unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, -1) };
+        if ret >= 0 {
+            return Ok(fds[0].revents == 0);
+        }
+        let err = io::Error::last_os_error();
+        if err.kind() != io::ErrorKind::Interrupted {
+            return Err(err);
+        }
+    }
+}
+
+/// Thread handling the requests from the slave, started by `MasterReqHandler::spawn()`.
+pub struct MasterReqThread {
+    handle: thread::JoinHandle<Result<()>>,
+    exit_event: EventFd,
+}
+
+impl MasterReqThread {
+    /// Check whether the thread has exited, after being stopped or on failure.
+    pub fn is_finished(&self) -> bool {
+        self.handle.is_finished()
+    }
+
+    /// Stop handling requests and wait for the thread to exit.
+    ///
+    /// Returns the error which made the thread exit before being stopped, if any, such as
+    /// `Error::SocketBroken` once the slave has closed the communication channel.
+    pub fn stop(self) -> Result<()> {
+        self.exit_event.write(1).map_err(Error::SocketError)?;
+        match self.handle.join() {
+            Ok(res) => res,
+            Err(_) => Err(Error::MasterInternalError),
+        }
+    }
 }
 
 impl<S: VhostUserMasterReqHandler> AsRawFd for MasterReqHandler<S> {
@@ -0,0 +1,306 @@
+// Copyright (C) 2020 Alibaba Cloud Computing. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Struct to send vhost-user requests from the slave to the master.
+
+use std::fs::File;
+use std::mem;
+use std::os::unix::io::RawFd;
+use std::os::unix::net::UnixStream;
+use std::sync::{Arc, Mutex};
+
+use super::connection::Endpoint;
+use super::message::*;
+use super::{Error, Result};
+
+struct MasterReqSenderInternal {
+    sock: Endpoint<SlaveReq>,
+    // Protocol features acked by the master.
+    acked_protocol_features: u64,
+    // Whether the master has acked the REPLY_ACK protocol feature.
+    reply_ack_negotiated: bool,
+    // whether the endpoint has encountered any failure
+    error: Option<i32>,
+}
+
+impl MasterReqSenderInternal {
+    fn check_state(&self) -> Result<()> {
+        match self.error {
+            Some(e) => Err(Error::SocketBroken(std::io::Error::from_raw_os_error(e))),
+            None => Ok(()),
+        }
+    }
+
+    fn send_request<T: Sized>(
+        &mut self,
+        code: SlaveReq,
+        msg: Option<&T>,
+        fds: Option<&[RawFd]>,
+        need_reply: bool,
+    ) -> Result<u64> {
+        let hdr = self.send_header_and_body(code, msg, fds, need_reply)?;
+        if need_reply {
+            self.wait_for_ack(&hdr)
+        } else {
+            Ok(0)
+        }
+    }
+
+    fn send_header_and_body<T: Sized>(
+        &mut self,
+        code: SlaveReq,
+        msg: Option<&T>,
+        fds: Option<&[RawFd]>,
+        need_reply: bool,
+    ) -> Result<VhostUserMsgHeader<SlaveReq>> {
+        self.check_state()?;
+
+        let len = match msg {
+            Some(_) => mem::size_of::<T>(),
+            None => 0,
+        };
+        let mut hdr = VhostUserMsgHeader::new(code, 0, len as u32);
+        hdr.set_need_reply(need_reply);
+        match msg {
+            Some(body) => self.sock.send_message(&hdr, body, fds)?,
+            None => self.sock.send_header(&hdr, fds)?,
+        }
+        Ok(hdr)
+    }
+
+    fn wait_for_ack(&mut self, hdr: &VhostUserMsgHeader<SlaveReq>) -> Result<u64> {
+        let (reply, body, rfds) = self.sock.recv_body::<VhostUserU64>()?;
+        if !reply.is_reply_for(hdr) || rfds.is_some() || !body.is_valid() {
+            Endpoint::<SlaveReq>::close_rfds(rfds);
+            return Err(Error::InvalidMessage);
+        }
+        // The master replies with a negative errno on failure.
+        if (body.value as i64) < 0 {
+            return Err(Error::MasterInternalError);
+        }
+        Ok(body.value)
+    }
+
+    // Wait for a mandatory reply carrying a file descriptor on success.
+    fn wait_for_file(&mut self, hdr: &VhostUserMsgHeader<SlaveReq>) -> Result<File> {
+        let (reply, body, rfds) = self.sock.recv_body::<VhostUserU64>()?;
+        if !reply.is_reply_for(hdr) || !body.is_valid() {
+            Endpoint::<SlaveReq>::close_rfds(rfds);
+            return Err(Error::InvalidMessage);
+        }
+        if body.value != 0 {
+            Endpoint::<SlaveReq>::close_rfds(rfds);
+            return Err(Error::MasterInternalError);
+        }
+        Endpoint::<SlaveReq>::take_single_file(rfds).ok_or(Error::IncorrectFds)
+    }
+
+    fn check_feature(&self, feature: VhostUserProtocolFeatures) -> Result<()> {
+        if self.acked_protocol_features & feature.bits() == 0 {
+            return Err(Error::InvalidOperation);
+        }
+        Ok(())
+    }
+}
+
+/// A vhost-user slave endpoint to send requests to the master through the communication channel
+/// set up by the VHOST_USER_SET_SLAVE_REQ_FD request.
+///
+/// Replies are only requested from the master when the REPLY_ACK protocol feature has been
+/// negotiated, otherwise requests are sent without waiting for acknowledgement.
+#[derive(Clone)]
+pub struct MasterReqSender {
+    // underlying Unix domain socket for communication
+    node: Arc<Mutex<MasterReqSenderInternal>>,
+}
+
+impl MasterReqSender {
+    fn new(ep: Endpoint<SlaveReq>) -> Self {
+        MasterReqSender {
+            node: Arc::new(Mutex::new(MasterReqSenderInternal {
+                sock: ep,
+                acked_protocol_features: 0,
+                reply_ack_negotiated: false,
+                error: None,
+            })),
+        }
+    }
+
+    /// Create a new instance from a Unix stream socket.
+    pub fn from_stream(sock: UnixStream) -> Self {
+        Self::new(Endpoint::<SlaveReq>::from_stream(sock))
+    }
+
+    /// Set the protocol features acked by the master, which decide the requests allowed on the
+    /// communication channel.
+    pub fn set_protocol_features(&mut self, features: u64) {
+        let mut node = self.node.lock().unwrap();
+        node.acked_protocol_features = features;
+        node.reply_ack_negotiated = features & VhostUserProtocolFeatures::REPLY_ACK.bits() != 0;
+    }
+
+    /// Mark endpoint as failed with specified error code.
+    pub fn set_failed(&mut self, error: i32) {
+        self.node.lock().unwrap().error = Some(error);
+    }
+
+    /// Send a request to the master, optionally waiting for the reply.
+    pub(super) fn send_message<T: Sized>(
+        &mut self,
+        code: SlaveReq,
+        msg: &T,
+        fds: Option<&[RawFd]>,
+        need_reply: bool,
+    ) -> Result<u64> {
+        self.node
+            .lock()
+            .unwrap()
+            .send_request(code, Some(msg), fds, need_reply)
+    }
+
+    /// Send an IOTLB miss or access failure message to the master.
+    pub fn send_iotlb_msg(&mut self, iotlb: &VhostUserIotlb) -> Result<u64> {
+        if !iotlb.is_valid() {
+            return Err(Error::InvalidParam);
+        }
+        let mut node = self.node.lock().unwrap();
+        let need_reply = node.reply_ack_negotiated;
+        node.send_request(SlaveReq::IOTLB_MSG, Some(iotlb), None, need_reply)
+    }
+
+    /// Notify the master that the virtio device configuration space has changed.
+    pub fn send_config_change(&mut self) -> Result<u64> {
+        let mut node = self.node.lock().unwrap();
+        node.check_feature(VhostUserProtocolFeatures::CONFIG)?;
+        let need_reply = node.reply_ack_negotiated;
+        node.send_request::<VhostUserU64>(SlaveReq::CONFIG_CHANGE_MSG, None, None, need_reply)
+    }
+
+    /// Hand the master the host notifier area of a vring, `size` bytes at `offset` of the file
+    /// `fd`, for the master to map it into the guest.
+    pub fn set_vring_host_notifier(
+        &mut self,
+        index: u8,
+        fd: RawFd,
+        offset: u64,
+        size: u64,
+    ) -> Result<u64> {
+        let msg = VhostUserVringArea::new(index, size, offset);
+        if !msg.is_valid() {
+            return Err(Error::InvalidParam);
+        }
+        let mut node = self.node.lock().unwrap();
+        node.check_feature(VhostUserProtocolFeatures::HOST_NOTIFIER)?;
+        node.check_feature(VhostUserProtocolFeatures::SLAVE_SEND_FD)?;
+        let need_reply = node.reply_ack_negotiated;
+        node.send_request(
+            SlaveReq::VRING_HOST_NOTIFIER_MSG,
+            Some(&msg),
+            Some(&[fd]),
+            need_reply,
+        )
+    }
+
+    /// Ask the master to stop using the host notifier area of a vring.
+    pub fn remove_vring_host_notifier(&mut self, index: u8) -> Result<u64> {
+        let mut node = self.node.lock().unwrap();
+        node.check_feature(VhostUserProtocolFeatures::HOST_NOTIFIER)?;
+        let msg = VhostUserVringArea::new_nofd(index);
+        let need_reply = node.reply_ack_negotiated;
+        node.send_request(
+            SlaveReq::VRING_HOST_NOTIFIER_MSG,
+            Some(&msg),
+            None,
+            need_reply,
+        )
+    }
+
+    /// Notify the master that a buffer was used from the vring, when the INBAND_NOTIFICATIONS
+    /// protocol feature has been negotiated.
+    pub fn send_vring_call(&mut self, index: u32) -> Result<u64> {
+        let mut node = self.node.lock().unwrap();
+        node.check_feature(VhostUserProtocolFeatures::INBAND_NOTIFICATIONS)?;
+        let msg = VhostUserVringState::new(index, 0);
+        let need_reply = node.reply_ack_negotiated;
+        node.send_request(SlaveReq::VRING_CALL, Some(&msg), None, need_reply)
+    }
+
+    /// Notify the master that an error occurred on the vring, when the INBAND_NOTIFICATIONS
+    /// protocol feature has been negotiated.
+    pub fn send_vring_err(&mut self, index: u32) -> Result<u64> {
+        let mut node = self.node.lock().unwrap();
+        node.check_feature(VhostUserProtocolFeatures::INBAND_NOTIFICATIONS)?;
+        let msg = VhostUserVringState::new(index, 0);
+        let need_reply = node.reply_ack_negotiated;
+        node.send_request(SlaveReq::VRING_ERR, Some(&msg), None, need_reply)
+    }
+
+    /// Tell the master the slave exports the shared object, so other devices looking it up get
+    /// it from the slave through GET_SHARED_OBJECT.
+    pub fn shared_object_add(&mut self, shared: &VhostUserShared) -> Result<u64> {
+        let mut node = self.node.lock().unwrap();
+        node.check_feature(VhostUserProtocolFeatures::SHARED_OBJECT)?;
+        let need_reply = node.reply_ack_negotiated;
+        node.send_request(SlaveReq::SHARED_OBJECT_ADD, Some(shared), None, need_reply)
+    }
+
+    /// Tell the master the slave doesn't export the shared object anymore.
+    pub fn shared_object_remove(&mut self, shared: &VhostUserShared) -> Result<u64> {
+        let mut node = self.node.lock().unwrap();
+        node.check_feature(VhostUserProtocolFeatures::SHARED_OBJECT)?;
+        let need_reply = node.reply_ack_negotiated;
+        node.send_request(
+            SlaveReq::SHARED_OBJECT_REMOVE,
+            Some(shared),
+            None,
+            need_reply,
+        )
+    }
+
+    /// Retrieve the file descriptor of a shared object exported by another device. The master
+    /// always replies to lookups, no matter whether REPLY_ACK has been negotiated.
+    pub fn shared_object_lookup(&mut self, shared: &VhostUserShared) -> Result<File> {
+        let mut node = self.node.lock().unwrap();
+        node.check_feature(VhostUserProtocolFeatures::SHARED_OBJECT)?;
+        let hdr =
+            node.send_header_and_body(SlaveReq::SHARED_OBJECT_LOOKUP, Some(shared), None, false)?;
+        node.wait_for_file(&hdr)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_master_req_sender_feature_checks() {
+        let (p1, p2) = UnixStream::pair().unwrap();
+        let mut sender = MasterReqSender::from_stream(p1);
+        let mut peer = Endpoint::<SlaveReq>::from_stream(p2);
+
+        assert!(sender.send_config_change().is_err());
+        assert!(sender.send_vring_call(0).is_err());
+        assert!(sender.send_vring_err(0).is_err());
+        let shared = VhostUserShared::new([0; 16]);
+        assert!(sender.shared_object_add(&shared).is_err());
+        assert!(sender.shared_object_lookup(&shared).is_err());
+
+        sender.set_protocol_features(
+            (VhostUserProtocolFeatures::CONFIG | VhostUserProtocolFeatures::INBAND_NOTIFICATIONS)
+                .bits(),
+        );
+        assert_eq!(sender.send_config_change().unwrap(), 0);
+        let (hdr, rfds) = peer.recv_header().unwrap();
+        assert_eq!(hdr.get_code(), SlaveReq::CONFIG_CHANGE_MSG);
+        assert!(!hdr.is_need_reply());
+        assert!(rfds.is_none());
+
+        assert_eq!(sender.send_vring_call(1).unwrap(), 0);
+        let (hdr, msg, _) = peer.recv_body::<VhostUserVringState>().unwrap();
+        assert_eq!(hdr.get_code(), SlaveReq::VRING_CALL);
+        assert_eq!({ msg.index }, 1);
+
+        sender.set_failed(libc::EBADF);
+        assert!(sender.send_vring_err(1).is_err());
+    }
+}
@@ -0,0 +1,242 @@
+// Copyright (C) 2020 Alibaba Cloud Computing. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Build the vhost-user memory table of the guest memory.
+//!
+//! The slave maps the guest memory from the file descriptors sent with SET_MEM_TABLE, so every
+//! region of the guest memory must be a shared mapping of a file, such as a memfd or a file on
+//! hugetlbfs, with offsets and sizes aligned on the page size of the file.
+
+use std::fmt;
+use std::io;
+use std::mem;
+use std::os::unix::io::{AsRawFd, RawFd};
+
+use vm_memory::{GuestMemory, GuestMemoryMmap, GuestMemoryRegion, GuestRegionMmap};
+
+use crate::backend::VhostUserMemoryRegionInfo;
+
+// Magic number of hugetlbfs, from linux/magic.h.
+const HUGETLBFS_MAGIC: u32 = 0x9584_58f6;
+
+/// Errors while building the memory table.
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum MemTableError {
+    /// The region at this guest address isn't backed by a file.
+    NoFileBacking(u64),
+    /// The region at this guest address is a private mapping, invisible to the slave.
+    PrivateMapping(u64),
+    /// The region at this guest address isn't aligned on the page size of its file.
+    Unaligned(u64, u64),
+    /// Failed to query the file backing the region at this guest address.
+    QueryFile(u64, io::Error),
+    /// The guest memory needs more regions than supported by the slave.
+    TooManyRegions(usize),
+}
+
+impl fmt::Display for MemTableError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MemTableError::NoFileBacking(addr) => {
+                write!(f, "region at {:#x} isn't backed by a file", addr)
+            }
+            MemTableError::PrivateMapping(addr) => {
+                write!(f, "region at {:#x} isn't a shared mapping", addr)
+            }
+            MemTableError::Unaligned(addr, align) => {
+                write!(
+                    f,
+                    "region at {:#x} isn't aligned on {:#x} bytes",
+                    addr, align
+                )
+            }
+            MemTableError::QueryFile(addr, e) => {
+                write!(
+                    f,
+                    "failed to query the file of region at {:#x}: {}",
+                    addr, e
+                )
+            }
+            MemTableError::TooManyRegions(num) => write!(f, "too many memory regions: {}", num),
+        }
+    }
+}
+
+impl std::error::Error for MemTableError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            MemTableError::QueryFile(_, e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+// A region of the table, with the identity of its file to coalesce it with its neighbours.
+struct TableEntry {
+    info: VhostUserMemoryRegionInfo,
+    file_id: (u64, u64),
+}
+
+/// Build the memory table of `mem`, to be passed to `set_mem_table()`.
+///
+/// Adjacent regions mapping contiguous ranges of the same file at contiguous host addresses are
+/// coalesced, and the table must then fit into `max_regions` entries. The file descriptors of
+/// the table are borrowed from `mem`, which must outlive the SET_MEM_TABLE request.
+pub fn guest_memory_table(
+    mem: &GuestMemoryMmap,
+    max_regions: usize,
+) -> Result<Vec<VhostUserMemoryRegionInfo>, MemTableError> {
+    let mut entries = Vec::with_capacity(mem.num_regions());
+    mem.with_regions_mut(|_, region| {
+        entries.push(table_entry(region)?);
+        Ok(())
+    })?;
+    entries.sort_by_key(|entry| entry.info.guest_phys_addr);
+
+    let table: Vec<VhostUserMemoryRegionInfo> = coalesce(entries)
+        .into_iter()
+        .map(|entry| entry.info)
+        .collect();
+    if table.len() > max_regions {
+        return Err(MemTableError::TooManyRegions(table.len()));
+    }
+    Ok(table)
+}
+
+fn table_entry(region: &GuestRegionMmap) -> Result<TableEntry, MemTableError> {
+    let addr = region.start_addr().0;
+    let file_offset = region
+        .file_offset()
+        .ok_or(MemTableError::NoFileBacking(addr))?;
+    if region.flags() & libc::MAP_SHARED == 0 {
+        return Err(MemTableError::PrivateMapping(addr));
+    }
+    let fd = file_offset.file().as_raw_fd();
+    let (file_id, align) = file_info(fd).map_err(|e| MemTableError::QueryFile(addr, e))?;
+    let info = VhostUserMemoryRegionInfo {
+        guest_phys_addr: addr,
+        memory_size: region.len(),
+        userspace_addr: region.as_ptr() as u64,
+        mmap_offset: file_offset.start(),
+        mmap_handle: fd,
+    };
+    if (info.mmap_offset | info.memory_size | info.userspace_addr) & (align - 1) != 0 {
+        return Err(MemTableError::Unaligned(addr, align));
+    }
+    Ok(TableEntry { info, file_id })
+}
+
+// Get the device and inode numbers of the file, and the size of its pages.
+fn file_info(fd: RawFd) -> io::Result<((u64, u64), u64)> {
+    // Safe because the structures are plain data filled by the kernel, and we check the return
+    // values.
+    unsafe {
+        let mut stat: libc::stat = mem::zeroed();
+        if libc::fstat(fd, &mut stat) < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let mut statfs: libc::statfs = mem::zeroed();
+        if libc::fstatfs(fd, &mut statfs) < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let align = if statfs.f_type as u32 == HUGETLBFS_MAGIC {
+            statfs.f_bsize as u64
+        } else {
+            libc::sysconf(libc::_SC_PAGESIZE) as u64
+        };
+        Ok(((stat.st_dev as u64, stat.st_ino as u64), align))
+    }
+}
+
+// Merge the entries, sorted by guest address, which extend the previous one.
+fn coalesce(entries: Vec<TableEntry>) -> Vec<TableEntry> {
+    let mut merged: Vec<TableEntry> = Vec::with_capacity(entries.len());
+    for entry in entries {
+        if let Some(last) = merged.last_mut() {
+            let size = last.info.memory_size;
+            if last.file_id == entry.file_id
+                && last.info.guest_phys_addr + size == entry.info.guest_phys_addr
+                && last.info.userspace_addr + size == entry.info.userspace_addr
+                && last.info.mmap_offset + size == entry.info.mmap_offset
+            {
+                last.info.memory_size += entry.info.memory_size;
+                continue;
+            }
+        }
+        merged.push(entry);
+    }
+    merged
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use vm_memory::{FileOffset, GuestAddress, MmapRegion};
+    use vmm_sys_util::tempfile::TempFile;
+
+    fn entry(guest_phys_addr: u64, userspace_addr: u64, mmap_offset: u64, ino: u64) -> TableEntry {
+        TableEntry {
+            info: VhostUserMemoryRegionInfo {
+                guest_phys_addr,
+                memory_size: 0x1000,
+                userspace_addr,
+                mmap_offset,
+                mmap_handle: 3,
+            },
+            file_id: (0, ino),
+        }
+    }
+
+    #[test]
+    fn test_coalesce() {
+        let table = coalesce(vec![
+            entry(0, 0x10_0000, 0, 1),
+            entry(0x1000, 0x10_1000, 0x1000, 1),
+            // Another file.
+            entry(0x2000, 0x10_2000, 0x2000, 2),
+            // Not contiguous in the host address space.
+            entry(0x3000, 0x20_0000, 0x3000, 2),
+            // Not contiguous in the file.
+            entry(0x4000, 0x20_1000, 0x5000, 2),
+        ]);
+        assert_eq!(table.len(), 4);
+        assert_eq!(table[0].info.memory_size, 0x2000);
+        assert_eq!(table[1].info.memory_size, 0x1000);
+    }
+
+    #[test]
+    fn test_guest_memory_table() {
+        let mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        match guest_memory_table(&mem, 8) {
+            Err(MemTableError::NoFileBacking(0)) => {}
+            _ => panic!("anonymous memory can't be shared"),
+        }
+
+        let file = TempFile::new().unwrap().into_file();
+        file.set_len(0x20000).unwrap();
+        let regions = vec![
+            (0, 0x10000, 0x1000),
+            (0x10_0000, 0x1000, 0x10000),
+            (0x20_0000, 0x1000, 0x11000),
+        ];
+        let regions = regions
+            .into_iter()
+            .map(|(addr, size, offset)| {
+                let file_offset = FileOffset::new(file.try_clone().unwrap(), offset);
+                let mapping = MmapRegion::from_file(file_offset, size).unwrap();
+                GuestRegionMmap::new(mapping, GuestAddress(addr)).unwrap()
+            })
+            .collect();
+        let mem = GuestMemoryMmap::from_regions(regions).unwrap();
+        let table = guest_memory_table(&mem, 3).unwrap();
+        assert_eq!(table.len(), 3);
+        assert_eq!(table[1].guest_phys_addr, 0x10_0000);
+        assert_eq!(table[1].memory_size, 0x1000);
+        assert_eq!(table[1].mmap_offset, 0x10000);
+        match guest_memory_table(&mem, 2) {
+            Err(MemTableError::TooManyRegions(3)) => {}
+            _ => panic!("the table should be too large"),
+        }
+    }
+}
@@ -10,6 +10,7 @@
 
 use std::fmt::Debug;
 use std::marker::PhantomData;
+use std::{mem, slice};
 
 use crate::VringConfigData;
 
@@ -22,6 +23,11 @@ use crate::VringConfigData;
 /// 4K should be enough too because it can support 255 memory regions at most.
 pub const MAX_MSG_SIZE: usize = 0x1000;
 
+/// Upper bound of the maximum message size endpoints may be configured with, for devices
+/// exchanging messages bigger than MAX_MSG_SIZE. Such messages are received in several chunks
+/// from stream sockets.
+pub const MAX_JUMBO_MSG_SIZE: usize = 0x10_0000;
+
 /// The VhostUserMemory message has variable message size and variable number of attached file
 /// descriptors. Each user memory region entry in the message payload occupies 32 bytes,
 /// so setting maximum number of attached file descriptors based on the maximum message size.
@@ -39,6 +45,12 @@ pub const VHOST_USER_CONFIG_SIZE: u32 = 0x1000;
 /// Maximum number of vrings supported.
 pub const VHOST_USER_MAX_VRINGS: u64 = 0x8000u64;
 
+/// Value of SET_VRING_ENDIAN requests for little endian vrings.
+pub const VHOST_USER_VRING_LITTLE_ENDIAN: u32 = 0;
+
+/// Value of SET_VRING_ENDIAN requests for big endian vrings.
+pub const VHOST_USER_VRING_BIG_ENDIAN: u32 = 1;
+
 pub(super) trait Req:
     Clone + Copy + Debug + PartialEq + Eq + PartialOrd + Ord + Into<u32>
 {
@@ -116,8 +128,33 @@ pub enum MasterReq {
     GET_INFLIGHT_FD = 31,
     /// Send the shared inflight buffer back to slave
     SET_INFLIGHT_FD = 32,
+    /// Set the socket used by a virtio-gpu device.
+    GPU_SET_SOCKET = 33,
+    /// Ask the vhost user backend to disable all rings and reset all internal device state.
+    RESET_DEVICE = 34,
+    /// Indicate that a buffer was added to the vring instead of signalling it using the vring's
+    /// kick eventfd.
+    VRING_KICK = 35,
+    /// Return a u64 payload containing the maximum number of memory slots.
+    GET_MAX_MEM_SLOTS = 36,
+    /// Update the memory tables by adding the region described.
+    ADD_MEM_REG = 37,
+    /// Update the memory tables by removing the region described.
+    REM_MEM_REG = 38,
+    /// Notify the backend with updated device status as defined in the virtio specification.
+    SET_STATUS = 39,
+    /// Query the backend for its device status as defined in the virtio specification.
+    GET_STATUS = 40,
+    /// Retrieve a shared object from the device.
+    GET_SHARED_OBJECT = 41,
+    /// Start transferring the internal state of the device through a file descriptor.
+    SET_DEVICE_STATE_FD = 42,
+    /// Check whether the internal state of the device has been transferred successfully.
+    CHECK_DEVICE_STATE = 43,
+    /// Query the sizes of the shared memory regions the device requires.
+    GET_SHMEM_CONFIG = 44,
     /// Upper bound of valid commands.
-    MAX_CMD = 33,
+    MAX_CMD = 45,
 }
 
 impl Into<u32> for MasterReq {
@@ -132,6 +169,34 @@ impl Req for MasterReq {
     }
 }
 
+impl MasterReq {
+    /// Check whether the slave always replies to the request, in which case the reply carries
+    /// the status of the request and no additional acknowledgement is sent for REPLY_ACK.
+    pub fn has_reply(self) -> bool {
+        matches!(
+            self,
+            MasterReq::GET_FEATURES
+                | MasterReq::SET_LOG_BASE
+                | MasterReq::GET_VRING_BASE
+                | MasterReq::GET_PROTOCOL_FEATURES
+                | MasterReq::GET_QUEUE_NUM
+                | MasterReq::IOTLB_MSG
+                | MasterReq::GET_CONFIG
+                | MasterReq::CREATE_CRYPTO_SESSION
+                | MasterReq::POSTCOPY_ADVISE
+                | MasterReq::POSTCOPY_LISTEN
+                | MasterReq::POSTCOPY_END
+                | MasterReq::GET_INFLIGHT_FD
+                | MasterReq::GET_MAX_MEM_SLOTS
+                | MasterReq::GET_STATUS
+                | MasterReq::GET_SHARED_OBJECT
+                | MasterReq::SET_DEVICE_STATE_FD
+                | MasterReq::CHECK_DEVICE_STATE
+                | MasterReq::GET_SHMEM_CONFIG
+        )
+    }
+}
+
 /// Type of requests sending from slaves to masters.
 #[repr(u32)]
 #[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
@@ -148,16 +213,23 @@ pub enum SlaveReq {
     VRING_CALL = 4,
     /// Indicate that an error occurred on the specific vring.
     VRING_ERR = 5,
+    /// Register the slave as the exporter of a shared object.
+    SHARED_OBJECT_ADD = 6,
+    /// Unregister the slave as the exporter of a shared object.
+    SHARED_OBJECT_REMOVE = 7,
+    /// Retrieve the file descriptor of a shared object exported by another device.
+    SHARED_OBJECT_LOOKUP = 8,
+    // The virtio-fs draft codes follow the standard ones, which took over their original range.
     /// Virtio-fs draft: map file content into the window.
-    FS_MAP = 6,
+    FS_MAP = 9,
     /// Virtio-fs draft: unmap file content from the window.
-    FS_UNMAP = 7,
+    FS_UNMAP = 10,
     /// Virtio-fs draft: sync file content.
-    FS_SYNC = 8,
+    FS_SYNC = 11,
     /// Virtio-fs draft: perform a read/write from an fd directly to GPA.
-    FS_IO = 9,
+    FS_IO = 12,
     /// Upper bound of valid commands.
-    MAX_CMD = 10,
+    MAX_CMD = 13,
 }
 
 impl Into<u32> for SlaveReq {
@@ -182,6 +254,57 @@ pub trait VhostUserMsgValidator {
     }
 }
 
+/// Plain data message structure, which may be viewed in place from any offset of a receive
+/// buffer.
+///
+/// # Safety
+/// Implementors must be `repr(packed)`, so they're byte aligned, and valid for any bit pattern.
+pub unsafe trait VhostUserMsgPlain: Sized {}
+
+unsafe impl VhostUserMsgPlain for u8 {}
+
+/// Typed view of a message body and of the payload following it, borrowed from the receive
+/// buffer instead of being copied out of it.
+pub struct VhostUserMsgView<'a, T: 'a, P: 'a> {
+    body: &'a T,
+    payload: &'a [P],
+}
+
+impl<'a, T: VhostUserMsgPlain, P: VhostUserMsgPlain> VhostUserMsgView<'a, T, P> {
+    /// Create a view of the message in `buf`.
+    ///
+    /// Return None if `buf` is too short for the body, or doesn't hold a whole number of payload
+    /// entries.
+    pub fn from_bytes(buf: &'a [u8]) -> Option<Self> {
+        let body_size = mem::size_of::<T>();
+        let entry_size = mem::size_of::<P>();
+        if buf.len() < body_size || !(buf.len() - body_size).is_multiple_of(entry_size) {
+            return None;
+        }
+        // Safe because the message structures are byte aligned and valid for any bit pattern,
+        // and the size of buf has been checked.
+        unsafe {
+            Some(VhostUserMsgView {
+                body: &*(buf.as_ptr() as *const T),
+                payload: slice::from_raw_parts(
+                    buf.as_ptr().add(body_size) as *const P,
+                    (buf.len() - body_size) / entry_size,
+                ),
+            })
+        }
+    }
+
+    /// Get the message body.
+    pub fn body(&self) -> &'a T {
+        self.body
+    }
+
+    /// Get the payload entries following the message body.
+    pub fn payload(&self) -> &'a [P] {
+        self.payload
+    }
+}
+
 // Bit mask for common message flags.
 bitflags! {
     /// Common message flags for vhost-user requests and replies.
@@ -228,7 +351,8 @@ impl<R: Req> VhostUserMsgHeader<R> {
     /// Get message type.
     pub fn get_code(&self) -> R {
         // It's safe because R is marked as repr(u32).
-        unsafe { std::mem::transmute_copy::<u32, R>(&self.request) }
+        let request = self.request;
+        unsafe { std::mem::transmute_copy::<u32, R>(&request) }
     }
 
     /// Set message type.
@@ -276,8 +400,13 @@ impl<R: Req> VhostUserMsgHeader<R> {
     }
 
     /// Check whether it's the reply message for the request `req`.
+    ///
+    /// Replies never ask for a reply in turn.
     pub fn is_reply_for(&self, req: &VhostUserMsgHeader<R>) -> bool {
-        self.is_reply() && !req.is_reply() && self.get_code() == req.get_code()
+        self.is_reply()
+            && !self.is_need_reply()
+            && !req.is_reply()
+            && self.get_code() == req.get_code()
     }
 
     /// Get message size.
@@ -303,11 +432,18 @@ impl<R: Req> Default for VhostUserMsgHeader<R> {
 }
 
 impl<T: Req> VhostUserMsgValidator for VhostUserMsgHeader<T> {
-    #[allow(clippy::if_same_then_else)]
     fn is_valid(&self) -> bool {
+        self.is_valid_with_max_size(MAX_MSG_SIZE)
+    }
+}
+
+impl<T: Req> VhostUserMsgHeader<T> {
+    /// Validate the header of a message whose body and payload may take up to `max_size` bytes.
+    #[allow(clippy::if_same_then_else)]
+    pub fn is_valid_with_max_size(&self, max_size: usize) -> bool {
         if !self.get_code().is_valid() {
             return false;
-        } else if self.size as usize > MAX_MSG_SIZE {
+        } else if self.size as usize > max_size {
             return false;
         } else if self.get_version() != 0x1 {
             return false;
@@ -324,6 +460,29 @@ bitflags! {
     pub struct VhostUserVirtioFeatures: u64 {
         /// Feature flag for the protocol feature.
         const PROTOCOL_FEATURES = 0x4000_0000;
+        /// The device accesses the virtqueues through the platform IOMMU, so the vring
+        /// addresses are IOVAs translated by IOTLB_MSG requests.
+        const IOMMU_PLATFORM = 0x2_0000_0000;
+    }
+}
+
+// Bit mask for the virtio device status.
+bitflags! {
+    /// Virtio device status bits, as defined by the virtio specification, carried by the
+    /// SET_STATUS and GET_STATUS requests.
+    pub struct VhostUserDeviceStatus: u8 {
+        /// The guest OS has found the device and recognized it as a valid virtio device.
+        const ACKNOWLEDGE = 0x01;
+        /// The guest OS knows how to drive the device.
+        const DRIVER = 0x02;
+        /// The driver is set up and ready to drive the device.
+        const DRIVER_OK = 0x04;
+        /// The driver has acknowledged all the features it understands.
+        const FEATURES_OK = 0x08;
+        /// The device has experienced an error from which it can't recover.
+        const DEVICE_NEEDS_RESET = 0x40;
+        /// Something went wrong in the guest, and it has given up on the device.
+        const FAILED = 0x80;
     }
 }
 
@@ -365,6 +524,14 @@ bitflags! {
         const CONFIGURE_MEM_SLOTS = 0x0000_8000;
         /// Support reporting status.
         const STATUS = 0x0001_0000;
+        /// Support mapping the memory regions as Xen grants or foreign memory.
+        const XEN_MMAP = 0x0002_0000;
+        /// Support sharing objects, such as dma-bufs, between devices.
+        const SHARED_OBJECT = 0x0004_0000;
+        /// Support transferring the internal state of the device.
+        const DEVICE_STATE = 0x0008_0000;
+        /// Support querying the shared memory regions of the device by GET_SHMEM_CONFIG.
+        const SHMEM = 0x0010_0000;
     }
 }
 
@@ -383,6 +550,8 @@ impl VhostUserU64 {
     }
 }
 
+unsafe impl VhostUserMsgPlain for VhostUserU64 {}
+
 impl VhostUserMsgValidator for VhostUserU64 {}
 
 /// Memory region descriptor for the SET_MEM_TABLE request.
@@ -405,6 +574,8 @@ impl VhostUserMemory {
     }
 }
 
+unsafe impl VhostUserMsgPlain for VhostUserMemory {}
+
 impl VhostUserMsgValidator for VhostUserMemory {
     #[allow(clippy::if_same_then_else)]
     fn is_valid(&self) -> bool {
@@ -443,6 +614,8 @@ impl VhostUserMemoryRegion {
     }
 }
 
+unsafe impl VhostUserMsgPlain for VhostUserMemoryRegion {}
+
 impl VhostUserMsgValidator for VhostUserMemoryRegion {
     fn is_valid(&self) -> bool {
         if self.memory_size == 0
@@ -459,6 +632,144 @@ impl VhostUserMsgValidator for VhostUserMemoryRegion {
 /// Payload of the VhostUserMemory message.
 pub type VhostUserMemoryPayload = Vec<VhostUserMemoryRegion>;
 
+/// Single memory region descriptor as payload for the ADD_MEM_REG and REM_MEM_REG requests.
+#[repr(packed)]
+#[derive(Default, Clone, Copy)]
+pub struct VhostUserSingleMemoryRegion {
+    /// Padding for alignment.
+    pub padding: u64,
+    /// The memory region to add or remove.
+    pub region: VhostUserMemoryRegion,
+}
+
+impl VhostUserSingleMemoryRegion {
+    /// Create a new instance.
+    pub fn new(guest_phys_addr: u64, memory_size: u64, user_addr: u64, mmap_offset: u64) -> Self {
+        VhostUserSingleMemoryRegion {
+            padding: 0,
+            region: VhostUserMemoryRegion::new(
+                guest_phys_addr,
+                memory_size,
+                user_addr,
+                mmap_offset,
+            ),
+        }
+    }
+}
+
+impl VhostUserMsgValidator for VhostUserSingleMemoryRegion {
+    fn is_valid(&self) -> bool {
+        self.padding == 0 && self.region.is_valid()
+    }
+}
+
+bitflags! {
+    /// Flags of the Xen mapping of a memory region, once VHOST_USER_PROTOCOL_F_XEN_MMAP has been
+    /// negotiated. Regions without flags are mapped as regular shared memory.
+    pub struct VhostUserXenMmapFlags: u32 {
+        /// Map the region as foreign memory of the domain.
+        const FOREIGN = 0x1;
+        /// Map the region from grant references of the domain.
+        const GRANT = 0x2;
+        /// Only map the grants when they are accessed, instead of mapping the whole region.
+        const NO_ADVANCE_MAP = 0x8;
+    }
+}
+
+/// Xen mapping of a memory region, following its descriptor once VHOST_USER_PROTOCOL_F_XEN_MMAP
+/// has been negotiated.
+#[repr(packed)]
+#[derive(Default, Clone, Copy)]
+pub struct VhostUserXenMmap {
+    /// Bitmask of VhostUserXenMmapFlags.
+    pub flags: u32,
+    /// Id of the domain which owns the memory.
+    pub domid: u32,
+}
+
+impl VhostUserXenMmap {
+    /// Create a new instance.
+    pub fn new(flags: VhostUserXenMmapFlags, domid: u32) -> Self {
+        VhostUserXenMmap {
+            flags: flags.bits(),
+            domid,
+        }
+    }
+
+    /// Check whether the region is mapped as regular shared memory.
+    pub fn is_shared_memory(&self) -> bool {
+        self.flags == 0
+    }
+}
+
+impl VhostUserMsgValidator for VhostUserXenMmap {
+    fn is_valid(&self) -> bool {
+        let flags = match VhostUserXenMmapFlags::from_bits(self.flags) {
+            Some(flags) => flags,
+            None => return false,
+        };
+        // Regions are either foreign memory or grants, and only grants may be mapped lazily.
+        !flags.contains(VhostUserXenMmapFlags::FOREIGN | VhostUserXenMmapFlags::GRANT)
+            && (!flags.contains(VhostUserXenMmapFlags::NO_ADVANCE_MAP)
+                || flags.contains(VhostUserXenMmapFlags::GRANT))
+    }
+}
+
+/// Memory region descriptor followed by its Xen mapping, sent in place of
+/// `VhostUserMemoryRegion` once VHOST_USER_PROTOCOL_F_XEN_MMAP has been negotiated.
+#[repr(packed)]
+#[derive(Default, Clone, Copy)]
+pub struct VhostUserXenMemoryRegion {
+    /// The memory region.
+    pub region: VhostUserMemoryRegion,
+    /// How the memory region is mapped.
+    pub xen_mmap: VhostUserXenMmap,
+}
+
+impl VhostUserXenMemoryRegion {
+    /// Create a new instance.
+    pub fn new(region: VhostUserMemoryRegion, xen_mmap: VhostUserXenMmap) -> Self {
+        VhostUserXenMemoryRegion { region, xen_mmap }
+    }
+}
+
+unsafe impl VhostUserMsgPlain for VhostUserXenMemoryRegion {}
+
+impl VhostUserMsgValidator for VhostUserXenMemoryRegion {
+    fn is_valid(&self) -> bool {
+        let (region, xen_mmap) = (self.region, self.xen_mmap);
+        region.is_valid() && xen_mmap.is_valid()
+    }
+}
+
+/// Body of ADD_MEM_REG and REM_MEM_REG requests once VHOST_USER_PROTOCOL_F_XEN_MMAP has been
+/// negotiated.
+#[repr(packed)]
+#[derive(Default, Clone, Copy)]
+pub struct VhostUserXenSingleMemoryRegion {
+    /// Padding for alignment.
+    pub padding: u64,
+    /// The memory region to add or remove, with its Xen mapping.
+    pub region: VhostUserXenMemoryRegion,
+}
+
+impl VhostUserXenSingleMemoryRegion {
+    /// Create a new instance.
+    pub fn new(region: VhostUserMemoryRegion, xen_mmap: VhostUserXenMmap) -> Self {
+        VhostUserXenSingleMemoryRegion {
+            padding: 0,
+            region: VhostUserXenMemoryRegion::new(region, xen_mmap),
+        }
+    }
+}
+
+impl VhostUserMsgValidator for VhostUserXenSingleMemoryRegion {
+    fn is_valid(&self) -> bool {
+        let region = self.region;
+        self.padding == 0 && region.is_valid()
+    }
+}
+
 /// Vring state descriptor.
 #[repr(packed)]
 #[derive(Default)]
@@ -591,14 +902,17 @@ impl VhostUserConfig {
     }
 }
 
+unsafe impl VhostUserMsgPlain for VhostUserConfig {}
+
 impl VhostUserMsgValidator for VhostUserConfig {
     #[allow(clippy::if_same_then_else)]
     fn is_valid(&self) -> bool {
         if (self.flags & !VhostUserConfigFlags::all().bits()) != 0 {
             return false;
-        } else if self.size == 0
-            || self.size > VHOST_USER_CONFIG_SIZE
-            || self.size + self.offset >= VHOST_USER_CONFIG_SIZE
+        } else if self.offset < VHOST_USER_CONFIG_OFFSET
+            || self.offset >= VHOST_USER_CONFIG_SIZE
+            || self.size == 0
+            || self.size > VHOST_USER_CONFIG_SIZE - self.offset
         {
             return false;
         }
@@ -609,31 +923,395 @@ impl VhostUserMsgValidator for VhostUserConfig {
 /// Payload for the VhostUserConfig message.
 pub type VhostUserConfigPayload = Vec<u8>;
 
-/*
- * TODO: support dirty log, live migration and IOTLB operations.
+/// Message to share the inflight I/O tracking buffer for the GET_INFLIGHT_FD and
+/// SET_INFLIGHT_FD requests.
 #[repr(packed)]
-pub struct VhostUserVringArea {
-    pub index: u32,
-    pub flags: u32,
-    pub size: u64,
-    pub offset: u64,
+#[derive(Default, Clone, Copy)]
+pub struct VhostUserInflight {
+    /// Size of the shared inflight buffer.
+    pub mmap_size: u64,
+    /// Offset where the inflight buffer starts in the shared memory file.
+    pub mmap_offset: u64,
+    /// Number of virtqueues covered by the inflight buffer.
+    pub num_queues: u16,
+    /// Size of each virtqueue covered by the inflight buffer.
+    pub queue_size: u16,
 }
 
+impl VhostUserInflight {
+    /// Create a new instance.
+    pub fn new(mmap_size: u64, mmap_offset: u64, num_queues: u16, queue_size: u16) -> Self {
+        VhostUserInflight {
+            mmap_size,
+            mmap_offset,
+            num_queues,
+            queue_size,
+        }
+    }
+}
+
+impl VhostUserMsgValidator for VhostUserInflight {
+    fn is_valid(&self) -> bool {
+        if self.num_queues == 0
+            || self.queue_size == 0
+            || self.mmap_offset.checked_add(self.mmap_size).is_none()
+        {
+            return false;
+        }
+        true
+    }
+}
+
+/// Inflight state of a descriptor in a split virtqueue, stored in the shared inflight buffer.
 #[repr(packed)]
+#[derive(Default, Clone, Copy)]
+pub struct DescStateSplit {
+    /// Whether the descriptor is inflight (1) or not (0).
+    pub inflight: u8,
+    /// Padding for alignment.
+    pub padding: [u8; 5],
+    /// Link to the next free entry, only used by the master for batching.
+    pub next: u16,
+    /// Submission order, used to resubmit inflight descriptors in the original order.
+    pub counter: u64,
+}
+
+/// Header of the per queue inflight region for split virtqueues. It's followed by an array of
+/// `desc_num` DescStateSplit entries in the shared inflight buffer.
+#[repr(packed)]
+#[derive(Default, Clone, Copy)]
+pub struct QueueRegionSplit {
+    /// Features negotiated for the virtqueue, used to validate the buffer on reconnect.
+    pub features: u64,
+    /// Version of the inflight buffer format, zero means an uninitialized buffer.
+    pub version: u16,
+    /// Number of DescStateSplit entries following the header.
+    pub desc_num: u16,
+    /// Head of the last batch of used descriptors.
+    pub last_batch_head: u16,
+    /// Shadow of the used ring index, in case the backend crashes while updating the used ring.
+    pub used_idx: u16,
+}
+
+/// Shared memory region for dirty page logging, passed by the SET_LOG_BASE request.
+#[repr(packed)]
+#[derive(Default)]
 pub struct VhostUserLog {
+    /// Size of the shared memory region for logging dirty pages.
+    pub mmap_size: u64,
+    /// Offset where the dirty log starts in the shared memory file.
+    pub mmap_offset: u64,
+}
+
+impl VhostUserLog {
+    /// Create a new instance.
+    pub fn new(mmap_size: u64, mmap_offset: u64) -> Self {
+        VhostUserLog {
+            mmap_size,
+            mmap_offset,
+        }
+    }
+}
+
+impl VhostUserMsgValidator for VhostUserLog {
+    fn is_valid(&self) -> bool {
+        if self.mmap_size == 0 || self.mmap_offset.checked_add(self.mmap_size).is_none() {
+            return false;
+        }
+        true
+    }
+}
+
+/// Bits of VhostUserVringArea::index holding the vring index.
+pub const VHOST_USER_VRING_IDX_MASK: u64 = 0xff;
+/// Flag of VhostUserVringArea::index telling no file descriptor is attached, which removes the
+/// host notifier of the vring.
+pub const VHOST_USER_VRING_NOFD_MASK: u64 = 0x100;
+
+/// Host notifier area of a vring, sent by the slave with VRING_HOST_NOTIFIER_MSG requests.
+///
+/// The master maps `size` bytes at `offset` of the attached file into the guest, so the driver
+/// notifies the vring by writing directly to the doorbell of the device.
+#[repr(packed)]
+#[derive(Default, Clone, Copy)]
+pub struct VhostUserVringArea {
+    /// Vring index in bits 0-7, and VHOST_USER_VRING_NOFD_MASK flag.
+    pub index: u64,
+    /// Size of the notifier area.
     pub size: u64,
+    /// Offset of the notifier area in the attached file.
     pub offset: u64,
 }
 
+impl VhostUserVringArea {
+    /// Create a new instance describing the notifier area of a vring.
+    pub fn new(index: u8, size: u64, offset: u64) -> Self {
+        VhostUserVringArea {
+            index: u64::from(index),
+            size,
+            offset,
+        }
+    }
+
+    /// Create a new instance removing the host notifier of a vring.
+    pub fn new_nofd(index: u8) -> Self {
+        VhostUserVringArea {
+            index: u64::from(index) | VHOST_USER_VRING_NOFD_MASK,
+            size: 0,
+            offset: 0,
+        }
+    }
+
+    /// Get the index of the vring.
+    pub fn vring_index(&self) -> u8 {
+        (self.index & VHOST_USER_VRING_IDX_MASK) as u8
+    }
+
+    /// Check whether a file descriptor holding the notifier area comes with the message.
+    pub fn has_fd(&self) -> bool {
+        self.index & VHOST_USER_VRING_NOFD_MASK == 0
+    }
+}
+
+impl VhostUserMsgValidator for VhostUserVringArea {
+    fn is_valid(&self) -> bool {
+        if self.index & !(VHOST_USER_VRING_IDX_MASK | VHOST_USER_VRING_NOFD_MASK) != 0 {
+            return false;
+        }
+        !self.has_fd() || (self.size != 0 && self.offset.checked_add(self.size).is_some())
+    }
+}
+
+// Bit mask for access permissions of IOTLB entries.
+bitflags! {
+    #[derive(Default)]
+    /// Access permissions of IOTLB entries, as defined by the vhost kernel driver.
+    pub struct VhostUserIotlbAccess: u8 {
+        /// No access.
+        const NO_ACCESS = 0x0;
+        /// Read only access.
+        const RO = 0x1;
+        /// Write only access.
+        const WO = 0x2;
+        /// Read and write access.
+        const RW = 0x3;
+    }
+}
+
+/// Type of IOTLB messages, as defined by the vhost kernel driver.
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VhostUserIotlbMsgType {
+    /// Slave asks the master to translate an IOVA.
+    Miss = 1,
+    /// Master sends a new translation to the slave.
+    Update = 2,
+    /// Master invalidates a translation cached by the slave.
+    Invalidate = 3,
+    /// Slave reports an access failure.
+    AccessFail = 4,
+}
+
+/// IOTLB message, with the same layout as struct vhost_iotlb_msg.
 #[repr(packed)]
+#[derive(Default, Clone, Copy)]
 pub struct VhostUserIotlb {
+    /// I/O virtual address.
     pub iova: u64,
+    /// Size of the memory range.
     pub size: u64,
+    /// Virtual address of the memory range in the master process.
     pub user_addr: u64,
-    pub permission: u8,
+    /// Access permission, see VhostUserIotlbAccess.
+    pub perm: u8,
+    /// Message type, see VhostUserIotlbMsgType.
     pub optype: u8,
+    padding: [u8; 6],
+}
+
+impl VhostUserIotlb {
+    /// Create a new instance.
+    pub fn new(
+        iova: u64,
+        size: u64,
+        user_addr: u64,
+        perm: VhostUserIotlbAccess,
+        optype: VhostUserIotlbMsgType,
+    ) -> Self {
+        VhostUserIotlb {
+            iova,
+            size,
+            user_addr,
+            perm: perm.bits(),
+            optype: optype as u8,
+            padding: [0; 6],
+        }
+    }
+}
+
+impl VhostUserMsgValidator for VhostUserIotlb {
+    fn is_valid(&self) -> bool {
+        if VhostUserIotlbAccess::from_bits(self.perm).is_none()
+            || self.optype < VhostUserIotlbMsgType::Miss as u8
+            || self.optype > VhostUserIotlbMsgType::AccessFail as u8
+        {
+            return false;
+        }
+        // Miss and invalidate messages may carry an empty range.
+        if self.optype == VhostUserIotlbMsgType::Update as u8
+            && (self.size == 0
+                || self.iova.checked_add(self.size).is_none()
+                || self.user_addr.checked_add(self.size).is_none())
+        {
+            return false;
+        }
+        true
+    }
+}
+
+/// Body of shared object messages, identifying the object.
+#[repr(packed)]
+#[derive(Default, Clone, Copy)]
+pub struct VhostUserShared {
+    /// UUID of the shared object.
+    pub uuid: [u8; 16],
+}
+
+impl VhostUserShared {
+    /// Create a new instance.
+    pub fn new(uuid: [u8; 16]) -> Self {
+        VhostUserShared { uuid }
+    }
+}
+
+impl VhostUserMsgValidator for VhostUserShared {}
+
+/// Maximum number of shared memory regions of a device, as defined by the virtio specification.
+pub const VHOST_USER_MAX_SHMEM_REGIONS: usize = 256;
+
+/// Body of GET_SHMEM_CONFIG replies, with the sizes of the shared memory regions of the device.
+///
+/// The regions are identified by their index, as the shmid of the virtio shared memory capability
+/// the master exposes for them, and regions of size 0 aren't used.
+#[repr(packed)]
+#[derive(Clone, Copy)]
+pub struct VhostUserShMemConfig {
+    /// Number of shared memory regions.
+    pub nregions: u32,
+    /// Padding for alignment.
+    pub padding: u32,
+    /// Size of each shared memory region.
+    pub memory_sizes: [u64; VHOST_USER_MAX_SHMEM_REGIONS],
+}
+
+impl VhostUserShMemConfig {
+    /// Create a new instance, for the regions of `sizes`.
+    ///
+    /// Returns None if there are more than `VHOST_USER_MAX_SHMEM_REGIONS` sizes.
+    pub fn new(sizes: &[u64]) -> Option<Self> {
+        if sizes.len() > VHOST_USER_MAX_SHMEM_REGIONS {
+            return None;
+        }
+        let mut memory_sizes = [0; VHOST_USER_MAX_SHMEM_REGIONS];
+        memory_sizes[..sizes.len()].copy_from_slice(sizes);
+        Some(VhostUserShMemConfig {
+            nregions: sizes.len() as u32,
+            padding: 0,
+            memory_sizes,
+        })
+    }
+
+    /// Get the sizes of the shared memory regions.
+    pub fn sizes(&self) -> Vec<u64> {
+        let sizes = self.memory_sizes;
+        sizes[..self.nregions as usize].to_vec()
+    }
+}
+
+// Arrays of more than 32 elements don't implement Default.
+impl Default for VhostUserShMemConfig {
+    fn default() -> Self {
+        VhostUserShMemConfig {
+            nregions: 0,
+            padding: 0,
+            memory_sizes: [0; VHOST_USER_MAX_SHMEM_REGIONS],
+        }
+    }
+}
+
+impl VhostUserMsgValidator for VhostUserShMemConfig {
+    fn is_valid(&self) -> bool {
+        let sizes = self.memory_sizes;
+        self.nregions as usize <= VHOST_USER_MAX_SHMEM_REGIONS
+            && sizes[self.nregions as usize..]
+                .iter()
+                .all(|&size| size == 0)
+    }
+}
+
+/// Flag of SET_DEVICE_STATE_FD replies telling that no file descriptor comes with the reply.
+pub const VHOST_USER_DEVICE_STATE_NOFD_MASK: u64 = 0x100;
+
+/// Direction of device state transfers.
+#[repr(u32)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VhostTransferStateDirection {
+    /// The slave saves its state into the file descriptor.
+    Save = 0,
+    /// The slave loads its state from the file descriptor.
+    Load = 1,
+}
+
+/// Migration phase of device state transfers.
+#[repr(u32)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VhostTransferStatePhase {
+    /// The device, including all its vrings, is stopped.
+    Stopped = 0,
+}
+
+/// Body of SET_DEVICE_STATE_FD requests.
+#[repr(packed)]
+#[derive(Default, Clone, Copy)]
+pub struct VhostUserTransferDeviceState {
+    /// Direction of the transfer, as a `VhostTransferStateDirection`.
+    pub transfer_direction: u32,
+    /// Migration phase of the transfer, as a `VhostTransferStatePhase`.
+    pub migration_phase: u32,
+}
+
+impl VhostUserTransferDeviceState {
+    /// Create a new instance.
+    pub fn new(direction: VhostTransferStateDirection, phase: VhostTransferStatePhase) -> Self {
+        VhostUserTransferDeviceState {
+            transfer_direction: direction as u32,
+            migration_phase: phase as u32,
+        }
+    }
+
+    /// Get the direction of the transfer.
+    pub fn direction(&self) -> Option<VhostTransferStateDirection> {
+        match self.transfer_direction {
+            0 => Some(VhostTransferStateDirection::Save),
+            1 => Some(VhostTransferStateDirection::Load),
+            _ => None,
+        }
+    }
+
+    /// Get the migration phase of the transfer.
+    pub fn phase(&self) -> Option<VhostTransferStatePhase> {
+        match self.migration_phase {
+            0 => Some(VhostTransferStatePhase::Stopped),
+            _ => None,
+        }
+    }
+}
+
+impl VhostUserMsgValidator for VhostUserTransferDeviceState {
+    fn is_valid(&self) -> bool {
+        self.direction().is_some() && self.phase().is_some()
+    }
 }
-*/
 
 // Bit mask for flags in virtio-fs slave messages
 bitflags! {
@@ -656,9 +1334,9 @@ pub const VHOST_USER_FS_SLAVE_ENTRIES: usize = 8;
 #[repr(packed)]
 #[derive(Default)]
 pub struct VhostUserFSSlaveMsg {
-    /// TODO:
+    /// Offset of the ranges in the file.
     pub fd_offset: [u64; VHOST_USER_FS_SLAVE_ENTRIES],
-    /// TODO:
+    /// Offset of the ranges in the DAX window.
     pub cache_offset: [u64; VHOST_USER_FS_SLAVE_ENTRIES],
     /// Size of region to map.
     pub len: [u64; VHOST_USER_FS_SLAVE_ENTRIES],
@@ -666,6 +1344,74 @@ pub struct VhostUserFSSlaveMsg {
     pub flags: [VhostUserFSSlaveMsgFlags; VHOST_USER_FS_SLAVE_ENTRIES],
 }
 
+/// A range of a virtio-fs slave request.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct VhostUserFSSlaveEntry {
+    /// Offset of the range in the file.
+    pub fd_offset: u64,
+    /// Offset of the range in the DAX window.
+    pub cache_offset: u64,
+    /// Size of the range.
+    pub len: u64,
+    /// Flags for the mmap operation.
+    pub flags: VhostUserFSSlaveMsgFlags,
+}
+
+impl VhostUserFSSlaveEntry {
+    /// Create a new instance.
+    pub fn new(
+        fd_offset: u64,
+        cache_offset: u64,
+        len: u64,
+        flags: VhostUserFSSlaveMsgFlags,
+    ) -> Self {
+        VhostUserFSSlaveEntry {
+            fd_offset,
+            cache_offset,
+            len,
+            flags,
+        }
+    }
+}
+
+impl VhostUserFSSlaveMsg {
+    /// Create a request for the ranges, or return None if there are more than
+    /// VHOST_USER_FS_SLAVE_ENTRIES ranges, or if a range is empty or invalid.
+    pub fn from_entries(entries: &[VhostUserFSSlaveEntry]) -> Option<Self> {
+        if entries.len() > VHOST_USER_FS_SLAVE_ENTRIES {
+            return None;
+        }
+        let mut msg = VhostUserFSSlaveMsg::default();
+        for (i, entry) in entries.iter().enumerate() {
+            if entry.len == 0 {
+                return None;
+            }
+            msg.fd_offset[i] = entry.fd_offset;
+            msg.cache_offset[i] = entry.cache_offset;
+            msg.len[i] = entry.len;
+            msg.flags[i] = entry.flags;
+        }
+        if !msg.is_valid() {
+            return None;
+        }
+        Some(msg)
+    }
+
+    /// Get the ranges of the request, skipping the unused slots, whose size is 0.
+    pub fn entries(&self) -> impl Iterator<Item = VhostUserFSSlaveEntry> + '_ {
+        (0..VHOST_USER_FS_SLAVE_ENTRIES)
+            .map(move |i| {
+                VhostUserFSSlaveEntry::new(
+                    self.fd_offset[i],
+                    self.cache_offset[i],
+                    self.len[i],
+                    self.flags[i],
+                )
+            })
+            .filter(|entry| entry.len != 0)
+    }
+}
+
 impl VhostUserMsgValidator for VhostUserFSSlaveMsg {
     fn is_valid(&self) -> bool {
         for i in 0..VHOST_USER_FS_SLAVE_ENTRIES {
@@ -693,6 +1439,9 @@ mod tests {
         assert!(!code.is_valid());
         let code = MasterReq::GET_FEATURES;
         assert!(code.is_valid());
+        assert!(code.has_reply());
+        assert!(!MasterReq::SET_FEATURES.has_reply());
+        assert!(MasterReq::POSTCOPY_LISTEN.has_reply());
     }
 
     #[test]
@@ -777,6 +1526,39 @@ mod tests {
         assert!(!msg.is_valid());
     }
 
+    #[test]
+    fn check_user_single_memory_region() {
+        let mut msg = VhostUserSingleMemoryRegion::new(0, 0x1000, 0, 0);
+        assert_eq!(mem::size_of::<VhostUserSingleMemoryRegion>(), 40);
+        assert!(msg.is_valid());
+        msg.padding = 1;
+        assert!(!msg.is_valid());
+        msg.padding = 0;
+        msg.region.memory_size = 0;
+        assert!(!msg.is_valid());
+    }
+
+    #[test]
+    fn check_user_vring_area() {
+        let mut msg = VhostUserVringArea::new(3, 0x1000, 0x2000);
+        assert_eq!(mem::size_of::<VhostUserVringArea>(), 24);
+        assert!(msg.is_valid());
+        assert_eq!(msg.vring_index(), 3);
+        assert!(msg.has_fd());
+        msg.size = 0;
+        assert!(!msg.is_valid());
+        msg.size = u64::MAX;
+        assert!(!msg.is_valid());
+        msg.index |= 0x200;
+        msg.size = 0x1000;
+        assert!(!msg.is_valid());
+
+        let msg = VhostUserVringArea::new_nofd(1);
+        assert!(msg.is_valid());
+        assert_eq!(msg.vring_index(), 1);
+        assert!(!msg.has_fd());
+    }
+
     #[test]
     fn check_user_vring_addr() {
         let mut msg =
@@ -801,7 +1583,126 @@ mod tests {
     }
 
     #[test]
-    #[ignore]
+    fn check_user_inflight() {
+        let mut msg = VhostUserInflight::new(0, 0, 1, 256);
+        assert!(msg.is_valid());
+        msg.mmap_size = 0x1000;
+        assert!(msg.is_valid());
+
+        msg.num_queues = 0;
+        assert!(!msg.is_valid());
+        msg.num_queues = 1;
+        msg.queue_size = 0;
+        assert!(!msg.is_valid());
+        msg.queue_size = 256;
+        msg.mmap_offset = 0xFFFFFFFFFFFFF000;
+        assert!(!msg.is_valid());
+
+        assert_eq!(mem::size_of::<VhostUserInflight>(), 20);
+        assert_eq!(mem::size_of::<DescStateSplit>(), 16);
+        assert_eq!(mem::size_of::<QueueRegionSplit>(), 16);
+    }
+
+    #[test]
+    fn check_user_iotlb() {
+        let mut msg = VhostUserIotlb::new(
+            0x1000,
+            0x1000,
+            0x7f00_0000_0000,
+            VhostUserIotlbAccess::RW,
+            VhostUserIotlbMsgType::Update,
+        );
+        assert!(msg.is_valid());
+        msg.size = 0;
+        assert!(!msg.is_valid());
+        msg.optype = VhostUserIotlbMsgType::Miss as u8;
+        assert!(msg.is_valid());
+        msg.optype = 0;
+        assert!(!msg.is_valid());
+        msg.optype = VhostUserIotlbMsgType::AccessFail as u8 + 1;
+        assert!(!msg.is_valid());
+        msg.optype = VhostUserIotlbMsgType::Invalidate as u8;
+        msg.perm = 0x4;
+        assert!(!msg.is_valid());
+
+        assert_eq!(mem::size_of::<VhostUserIotlb>(), 32);
+    }
+
+    #[test]
+    fn check_user_shmem_config() {
+        let mut config = VhostUserShMemConfig::new(&[0x10_0000, 0, 0x4000]).unwrap();
+        assert!(config.is_valid());
+        assert_eq!(config.sizes(), vec![0x10_0000, 0, 0x4000]);
+        config.nregions = 2;
+        assert!(!config.is_valid());
+        config.nregions = VHOST_USER_MAX_SHMEM_REGIONS as u32 + 1;
+        assert!(!config.is_valid());
+        assert!(VhostUserShMemConfig::new(&[0; VHOST_USER_MAX_SHMEM_REGIONS + 1]).is_none());
+    }
+
+    #[test]
+    fn check_user_xen_memory_region() {
+        let region = VhostUserMemoryRegion::new(0, 0x10_0000, 0x4000_0000, 0);
+        let mut msg = VhostUserXenSingleMemoryRegion::new(
+            region,
+            VhostUserXenMmap::new(VhostUserXenMmapFlags::GRANT, 1),
+        );
+        assert!(msg.is_valid());
+        assert!(!msg.region.xen_mmap.is_shared_memory());
+        msg.region.xen_mmap.flags |= VhostUserXenMmapFlags::NO_ADVANCE_MAP.bits();
+        assert!(msg.is_valid());
+        msg.region.xen_mmap.flags = VhostUserXenMmapFlags::FOREIGN.bits();
+        assert!(msg.is_valid());
+        msg.region.xen_mmap.flags |= VhostUserXenMmapFlags::NO_ADVANCE_MAP.bits();
+        assert!(!msg.is_valid());
+        msg.region.xen_mmap.flags |= VhostUserXenMmapFlags::GRANT.bits();
+        assert!(!msg.is_valid());
+        msg.region.xen_mmap.flags = 0x4;
+        assert!(!msg.is_valid());
+        msg.region.xen_mmap.flags = 0;
+        assert!(msg.is_valid());
+        assert!(msg.region.xen_mmap.is_shared_memory());
+        msg.padding = 1;
+        assert!(!msg.is_valid());
+
+        assert_eq!(mem::size_of::<VhostUserXenMemoryRegion>(), 40);
+        assert_eq!(mem::size_of::<VhostUserXenSingleMemoryRegion>(), 48);
+    }
+
+    #[test]
+    fn check_user_log() {
+        let mut msg = VhostUserLog::new(0x1000, 0);
+        assert!(msg.is_valid());
+        msg.mmap_size = 0;
+        assert!(!msg.is_valid());
+        msg.mmap_size = 0x1000;
+        msg.mmap_offset = u64::MAX;
+        assert!(!msg.is_valid());
+
+        assert_eq!(mem::size_of::<VhostUserLog>(), 16);
+    }
+
+    #[test]
+    fn check_fs_slave_msg() {
+        let rw = VhostUserFSSlaveMsgFlags::MAP_R | VhostUserFSSlaveMsgFlags::MAP_W;
+        let entries = [
+            VhostUserFSSlaveEntry::new(0, 0x1000, 0x1000, VhostUserFSSlaveMsgFlags::MAP_R),
+            VhostUserFSSlaveEntry::new(0x1000, 0x4000, 0x2000, rw),
+        ];
+        let msg = VhostUserFSSlaveMsg::from_entries(&entries).unwrap();
+        assert!(msg.is_valid());
+        assert_eq!({ msg.len[1] }, 0x2000);
+        assert_eq!(msg.entries().collect::<Vec<_>>(), entries.to_vec());
+
+        let entry = VhostUserFSSlaveEntry::new(0, 0, 0x1000, rw);
+        assert!(VhostUserFSSlaveMsg::from_entries(&[entry; 9]).is_none());
+        let entry = VhostUserFSSlaveEntry::new(0, 0, 0, rw);
+        assert!(VhostUserFSSlaveMsg::from_entries(&[entry]).is_none());
+        let entry = VhostUserFSSlaveEntry::new(u64::MAX, 0, 0x1000, rw);
+        assert!(VhostUserFSSlaveMsg::from_entries(&[entry]).is_none());
+    }
+
+    #[test]
     fn check_user_config_msg() {
         let mut msg = VhostUserConfig::new(
             VHOST_USER_CONFIG_OFFSET,
@@ -828,4 +1729,48 @@ mod tests {
         msg.flags |= 0x4;
         assert!(!msg.is_valid());
     }
+
+    #[test]
+    fn check_msg_view() {
+        let mut buf = vec![0u8; mem::size_of::<VhostUserMemory>()];
+        buf[0] = 2;
+        for i in 0..2 {
+            let region = VhostUserMemoryRegion::new(0x1000 * i, 0x1000, 0x10_0000, 0);
+            let bytes = unsafe {
+                slice::from_raw_parts(
+                    &region as *const VhostUserMemoryRegion as *const u8,
+                    mem::size_of::<VhostUserMemoryRegion>(),
+                )
+            };
+            buf.extend_from_slice(bytes);
+        }
+        // Start the message at an odd offset, views don't depend on the buffer alignment.
+        buf.insert(0, 0);
+
+        let view =
+            VhostUserMsgView::<VhostUserMemory, VhostUserMemoryRegion>::from_bytes(&buf[1..])
+                .unwrap();
+        assert_eq!({ view.body().num_regions }, 2);
+        assert_eq!(view.payload().len(), 2);
+        assert_eq!({ view.payload()[1].guest_phys_addr }, 0x1000);
+        assert_eq!(
+            view.payload().as_ptr() as usize,
+            buf.as_ptr() as usize + 1 + mem::size_of::<VhostUserMemory>()
+        );
+
+        // A partial region or body isn't viewed.
+        let len = buf.len();
+        assert!(
+            VhostUserMsgView::<VhostUserMemory, VhostUserMemoryRegion>::from_bytes(
+                &buf[1..len - 1]
+            )
+            .is_none()
+        );
+        assert!(VhostUserMsgView::<VhostUserConfig, u8>::from_bytes(&buf[1..4]).is_none());
+        let view = VhostUserMsgView::<VhostUserConfig, u8>::from_bytes(&buf[1..]).unwrap();
+        assert_eq!(
+            view.payload().len(),
+            len - 1 - mem::size_of::<VhostUserConfig>()
+        );
+    }
 }
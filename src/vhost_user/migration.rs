@@ -0,0 +1,211 @@
+// Copyright (C) 2020 Alibaba Cloud Computing. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Sequence the vhost-user requests migrating a device.
+//!
+//! On the source, the migration of a vhost-user device goes through these steps:
+//! . share a dirty log with the slave and ack VHOST_F_LOG_ALL, while the guest memory is copied
+//! . disable the vrings, so the slave stops processing them
+//! . stop the vrings with GET_VRING_BASE, which returns the state to restore on the destination
+//! . save the internal state of the device, if DEVICE_STATE has been negotiated
+//!
+//! On the destination, the vrings are configured with the bases saved on the source, and the
+//! internal state of the device is loaded before starting the vrings.
+//!
+//! `MigrationController` sends these requests in order, and restarts the device when stopping it
+//! fails, so the VMM may resume the guest on the source.
+
+use std::fs::File;
+use std::io::{Read, Write};
+use std::os::unix::io::{AsRawFd, FromRawFd};
+
+use vmm_sys_util::eventfd::EventFd;
+
+use super::message::*;
+use super::VhostUserMaster;
+use crate::backend::VhostUserDirtyLogRegion;
+use crate::features::VirtioFeatures;
+use crate::{Error, Result};
+
+/// State of the stopped device to restore on the destination.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct MigrationState {
+    /// Base of each vring, returned by GET_VRING_BASE.
+    pub vring_bases: Vec<u16>,
+    /// Internal state of the device, if saved.
+    pub device_state: Option<Vec<u8>>,
+}
+
+/// Controller of the migration of a vhost-user device.
+pub struct MigrationController {
+    features: u64,
+    num_queues: usize,
+    logging: bool,
+    vring_bases: Vec<u16>,
+}
+
+impl MigrationController {
+    /// Create a controller for a device with `num_queues` vrings, and the virtio `features`
+    /// acked by the driver.
+    pub fn new(features: u64, num_queues: usize) -> Self {
+        MigrationController {
+            features,
+            num_queues,
+            logging: false,
+            vring_bases: Vec::new(),
+        }
+    }
+
+    /// Check whether the slave is logging the pages it writes.
+    pub fn is_logging(&self) -> bool {
+        self.logging
+    }
+
+    /// Share the dirty log `region` with the slave, and ask it to log the pages it writes.
+    pub fn start_logging<M: VhostUserMaster>(
+        &mut self,
+        master: &mut M,
+        region: VhostUserDirtyLogRegion,
+    ) -> Result<()> {
+        master.set_log_base(0, Some(region))?;
+        master.set_features(self.features | VirtioFeatures::LOG_ALL.bits())?;
+        self.logging = true;
+        Ok(())
+    }
+
+    /// Ask the slave to stop logging the pages it writes, when the migration is cancelled.
+    pub fn stop_logging<M: VhostUserMaster>(&mut self, master: &mut M) -> Result<()> {
+        if self.logging {
+            master.set_features(self.features)?;
+            self.logging = false;
+        }
+        Ok(())
+    }
+
+    /// Stop the device, and save its internal state if `save_state` is set.
+    ///
+    /// The device is restarted with the `kicks` eventfds, one per vring, if any step fails.
+    /// Errors while restarting the device are ignored, in favour of the one which made stopping
+    /// the device fail.
+    pub fn stop<M: VhostUserMaster>(
+        &mut self,
+        master: &mut M,
+        kicks: &[&EventFd],
+        save_state: bool,
+    ) -> Result<MigrationState> {
+        if kicks.len() != self.num_queues {
+            return Err(Error::InvalidParam);
+        }
+        self.vring_bases.clear();
+
+        let enabled = self.can_enable_vrings();
+        let mut disabled = 0;
+        let res = if enabled {
+            (0..self.num_queues).try_for_each(|index| {
+                master.set_vring_enable(index, false)?;
+                disabled += 1;
+                Ok(())
+            })
+        } else {
+            Ok(())
+        };
+        if let Err(e) = res {
+            for index in 0..disabled {
+                let _ = master.set_vring_enable(index, true);
+            }
+            return Err(e);
+        }
+
+        let res = self.stop_vrings(master).and_then(|_| {
+            if save_state {
+                Self::save_device_state(master).map(Some)
+            } else {
+                Ok(None)
+            }
+        });
+        match res {
+            Ok(device_state) => Ok(MigrationState {
+                vring_bases: self.vring_bases.clone(),
+                device_state,
+            }),
+            Err(e) => {
+                let _ = self.resume(master, kicks);
+                Err(e)
+            }
+        }
+    }
+
+    /// Restart the vrings stopped by `stop()` with the `kicks` eventfds, one per vring, when the
+    /// migration is cancelled.
+    pub fn resume<M: VhostUserMaster>(&mut self, master: &mut M, kicks: &[&EventFd]) -> Result<()> {
+        if kicks.len() != self.num_queues {
+            return Err(Error::InvalidParam);
+        }
+        for (index, base) in self.vring_bases.iter().enumerate() {
+            master.set_vring_base(index, *base)?;
+            master.set_vring_kick(index, kicks[index])?;
+        }
+        self.vring_bases.clear();
+        if self.can_enable_vrings() {
+            for index in 0..self.num_queues {
+                master.set_vring_enable(index, true)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Load the internal state of the device saved on the source, before starting the vrings.
+    pub fn load_device_state<M: VhostUserMaster>(master: &mut M, state: &[u8]) -> Result<()> {
+        let (rx, tx) = create_pipe()?;
+        let file = master.set_device_state_fd(
+            VhostTransferStateDirection::Load,
+            VhostTransferStatePhase::Stopped,
+            rx.as_raw_fd(),
+        )?;
+        drop(rx);
+        let mut tx = file.unwrap_or(tx);
+        tx.write_all(state).map_err(Error::IOError)?;
+        // The slave reads the state until EOF.
+        drop(tx);
+        master.check_device_state()
+    }
+
+    fn can_enable_vrings(&self) -> bool {
+        self.features & VhostUserVirtioFeatures::PROTOCOL_FEATURES.bits() != 0
+    }
+
+    fn stop_vrings<M: VhostUserMaster>(&mut self, master: &mut M) -> Result<()> {
+        for index in 0..self.num_queues {
+            let base = master.get_vring_base(index)?;
+            self.vring_bases.push(base as u16);
+        }
+        Ok(())
+    }
+
+    fn save_device_state<M: VhostUserMaster>(master: &mut M) -> Result<Vec<u8>> {
+        let (rx, tx) = create_pipe()?;
+        let file = master.set_device_state_fd(
+            VhostTransferStateDirection::Save,
+            VhostTransferStatePhase::Stopped,
+            tx.as_raw_fd(),
+        )?;
+        drop(tx);
+        let mut rx = file.unwrap_or(rx);
+        let mut state = Vec::new();
+        rx.read_to_end(&mut state).map_err(Error::IOError)?;
+        master.check_device_state()?;
+        Ok(state)
+    }
+}
+
+fn create_pipe() -> Result<(File, File)> {
+    let mut fds = [0; 2];
+    // Safe because we check the return value, and the files take the ownership of the new file
+    // descriptors.
+    unsafe {
+        if libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC) != 0 {
+            return Err(Error::IOError(std::io::Error::last_os_error()));
+        }
+        Ok((File::from_raw_fd(fds[0]), File::from_raw_fd(fds[1])))
+    }
+}
@@ -13,7 +13,8 @@
 //! VHOST_USER_SET_SLAVE_REQ_FD request to the slave with an auxiliary file descriptor.
 //!
 //! Unix domain socket is used as the underlying communication channel because the master needs to
-//! send file descriptors to the slave.
+//! send file descriptors to the slave. The channel may also run over vsock, with the file
+//! descriptors transferred by a `SharedMemoryHook`.
 //!
 //! Most messages that can be sent via the Unix domain socket implementing vhost-user have an
 //! equivalent ioctl to the kernel implementation.
@@ -23,33 +24,111 @@ use std::io::Error as IOError;
 
 mod connection;
 pub mod message;
-pub use self::connection::Listener;
+pub mod parser;
+pub use self::connection::{Listener, PeerCredentials};
+mod trace;
+mod transport;
+pub use self::transport::SharedMemoryHook;
 #[cfg(feature = "vhost-user-master")]
 mod master;
+#[cfg(all(feature = "vhost-user-master", feature = "versionize"))]
+pub use self::master::MasterState;
 #[cfg(feature = "vhost-user-master")]
-pub use self::master::{Master, VhostUserMaster};
+pub use self::master::{
+    Master, MasterListener, ProtocolCapabilities, VhostUserMaster, DEFAULT_MAX_PENDING_REPLIES,
+};
+#[cfg(feature = "vhost-user-master")]
+mod master_fs_cache;
+#[cfg(feature = "vhost-user-master")]
+pub use self::master_fs_cache::{FsCacheWindow, VHOST_USER_FS_UNMAP_ALL};
+#[cfg(all(feature = "vhost-user-master", feature = "vhost-user-slave"))]
+pub mod loopback;
+#[cfg(feature = "vhost-user-master")]
+mod migration;
+#[cfg(feature = "vhost-user-master")]
+pub use self::migration::{MigrationController, MigrationState};
+#[cfg(feature = "vhost-user-mem-table")]
+mod mem_table;
+#[cfg(feature = "vhost-user-mem-table")]
+pub use self::mem_table::{guest_memory_table, MemTableError};
+#[cfg(feature = "vhost-user-master")]
+mod reconnect;
+#[cfg(feature = "vhost-user-master")]
+pub use self::reconnect::{MasterConnector, ReconnectingMaster, VhostUserReconnectHandler};
 #[cfg(any(feature = "vhost-user-master", feature = "vhost-user-slave"))]
 mod master_req_handler;
 #[cfg(any(feature = "vhost-user-master", feature = "vhost-user-slave"))]
-pub use self::master_req_handler::{MasterReqHandler, VhostUserMasterReqHandler};
+pub use self::master_req_handler::{
+    ConfigChangeNotifier, MasterReqDispatcher, MasterReqHandler, MasterReqThread,
+    VhostUserMasterReqHandler,
+};
+
+#[cfg(any(feature = "vhost-user-master", feature = "vhost-user-slave"))]
+pub mod aio;
+#[cfg(any(feature = "vhost-user-master", feature = "vhost-user-slave"))]
+pub mod gpu;
+#[cfg(any(feature = "vhost-user-master", feature = "vhost-user-slave"))]
+mod snapshot;
+#[cfg(any(feature = "vhost-user-master", feature = "vhost-user-slave"))]
+pub use self::snapshot::{MemoryRegionSnapshot, SessionSnapshot, VringSnapshot};
 
+#[cfg(feature = "vhost-user-slave")]
+pub mod device_state;
+#[cfg(feature = "vhost-user-slave")]
+pub mod dirty_log;
+#[cfg(feature = "vhost-user-slave")]
+pub mod inflight;
+#[cfg(feature = "vhost-user-slave")]
+pub mod iotlb;
+#[cfg(feature = "vhost-user-slave")]
+mod master_req_sender;
+#[cfg(feature = "vhost-user-slave")]
+pub use self::master_req_sender::MasterReqSender;
 #[cfg(feature = "vhost-user-slave")]
 mod slave;
 #[cfg(feature = "vhost-user-slave")]
 pub use self::slave::SlaveListener;
 #[cfg(feature = "vhost-user-slave")]
+mod rate_limit;
+#[cfg(feature = "vhost-user-slave")]
+pub use self::rate_limit::{RateLimitPolicy, RateLimiter};
+#[cfg(feature = "vhost-user-slave")]
 mod slave_req_handler;
 #[cfg(feature = "vhost-user-slave")]
-pub use self::slave_req_handler::{SlaveReqHandler, VhostUserSlaveReqHandler};
+pub use self::slave_req_handler::{SessionState, SlaveReqHandler, VhostUserSlaveReqHandler};
 #[cfg(feature = "vhost-user-slave")]
 mod slave_fs_cache;
 #[cfg(feature = "vhost-user-slave")]
 pub use self::slave_fs_cache::SlaveFsCacheReq;
+#[cfg(feature = "vhost-user-slave")]
+pub mod userfaultfd;
+
+#[cfg(feature = "vhost-user-daemon")]
+pub mod daemon;
+#[cfg(feature = "vhost-user-daemon")]
+pub use self::daemon::{Daemon, VhostUserBackend};
+#[cfg(feature = "vhost-user-block-backend")]
+pub mod block_backend;
+#[cfg(feature = "vhost-user-console-backend")]
+pub mod console_backend;
+#[cfg(feature = "vhost-user-input-backend")]
+pub mod input_backend;
+#[cfg(feature = "vhost-user-io-uring")]
+pub mod io_uring;
+#[cfg(feature = "vhost-user-net-backend")]
+pub mod net_backend;
+#[cfg(feature = "vhost-user-request-backend")]
+pub mod request_backend;
+#[cfg(feature = "vhost-user-rng-backend")]
+pub mod rng_backend;
+#[cfg(feature = "vhost-user-vsock-backend")]
+pub mod vsock_backend;
 
 pub mod sock_ctrl_msg;
 
 /// Errors for vhost-user operations
 #[derive(Debug)]
+#[non_exhaustive]
 pub enum Error {
     /// Invalid parameters.
     InvalidParam,
@@ -63,6 +142,9 @@ pub enum Error {
     OversizedMsg,
     /// Fd array in question is too big or too small
     IncorrectFds,
+    /// The peer attached more file descriptors to a message than the maximum, which have been
+    /// closed.
+    TooManyFds(usize),
     /// Can't connect to peer.
     SocketConnect(std::io::Error),
     /// Generic socket errors.
@@ -77,8 +159,40 @@ pub enum Error {
     MasterInternalError,
     /// Virtio/protocol features mismatch.
     FeatureMismatch,
+    /// The request isn't allowed in the current state of the session.
+    OutOfOrderRequest(message::MasterReq),
+    /// The request has been rejected by the filter of the slave.
+    RejectedRequest(message::MasterReq),
+    /// The master has sent more requests than allowed by the rate limit of the slave.
+    RateLimited(message::MasterReq),
+    /// The slave is short of resources to handle the request.
+    ResourceExhausted,
     /// Error from request handler
     ReqHandlerError(IOError),
+    /// The peer hasn't answered in time.
+    Timeout,
+    /// The memory table has more regions than supported by the slave.
+    TooManyMemRegions {
+        /// Maximum number of memory regions supported by the slave.
+        max: usize,
+        /// Guest physical addresses of the regions beyond the maximum.
+        overflowed: Vec<u64>,
+    },
+    /// The offset or the size of a memory region isn't aligned on the page size of its file.
+    UnalignedMemRegion {
+        /// Guest physical address of the region.
+        guest_phys_addr: u64,
+        /// Page size of the file backing the region, such as 2M or 1G for hugetlbfs.
+        alignment: u64,
+    },
+    /// A reply of the slave doesn't match the request it's expected for, in the order of the
+    /// requests, such as a late reply to a request which timed out.
+    UnexpectedReply {
+        /// Sequence number of the request, counting the requests sent on the connection.
+        seq: u64,
+        /// Request the reply was expected for.
+        request: message::MasterReq,
+    },
 }
 
 impl std::fmt::Display for Error {
@@ -90,6 +204,7 @@ impl std::fmt::Display for Error {
             Error::PartialMessage => write!(f, "partial message"),
             Error::OversizedMsg => write!(f, "oversized message"),
             Error::IncorrectFds => write!(f, "wrong number of attached fds"),
+            Error::TooManyFds(max) => write!(f, "more than {} attached fds", max),
             Error::SocketError(e) => write!(f, "socket error: {}", e),
             Error::SocketConnect(e) => write!(f, "can't connect to peer: {}", e),
             Error::SocketBroken(e) => write!(f, "socket is broken: {}", e),
@@ -97,7 +212,42 @@ impl std::fmt::Display for Error {
             Error::SlaveInternalError => write!(f, "slave internal error"),
             Error::MasterInternalError => write!(f, "Master internal error"),
             Error::FeatureMismatch => write!(f, "virtio/protocol features mismatch"),
+            Error::OutOfOrderRequest(code) => write!(f, "out of order request {:?}", code),
+            Error::RejectedRequest(code) => write!(f, "rejected request {:?}", code),
+            Error::RateLimited(code) => write!(f, "rate limit exceeded by request {:?}", code),
+            Error::ResourceExhausted => write!(f, "slave resources exhausted"),
             Error::ReqHandlerError(e) => write!(f, "handler failed to handle request: {}", e),
+            Error::Timeout => write!(f, "timed out waiting for the peer"),
+            Error::TooManyMemRegions { max, overflowed } => write!(
+                f,
+                "{} memory regions beyond the maximum of {}",
+                overflowed.len(),
+                max
+            ),
+            Error::UnalignedMemRegion {
+                guest_phys_addr,
+                alignment,
+            } => write!(
+                f,
+                "memory region at {:#x} isn't aligned on the {:#x} bytes pages of its file",
+                guest_phys_addr, alignment
+            ),
+            Error::UnexpectedReply { seq, request } => {
+                write!(f, "unexpected reply to request #{} {:?}", seq, request)
+            }
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::SocketConnect(e)
+            | Error::SocketError(e)
+            | Error::SocketBroken(e)
+            | Error::SocketRetry(e)
+            | Error::ReqHandlerError(e) => Some(e),
+            _ => None,
         }
     }
 }
@@ -114,13 +264,59 @@ impl Error {
             Error::SlaveInternalError => true,
             // Master internal error, hope it recovers on reconnect.
             Error::MasterInternalError => true,
+            // Should reconnect because the rest of the message is left on the connection.
+            Error::TooManyFds(_) => true,
+            // Should reconnect because the replies can't be matched with the requests anymore.
+            Error::UnexpectedReply { .. } => true,
             // Should just retry the IO operation instead of rebuilding the underline connection.
             Error::SocketRetry(_) => false,
             Error::InvalidParam | Error::InvalidOperation => false,
             Error::InvalidMessage | Error::IncorrectFds | Error::OversizedMsg => false,
             Error::SocketError(_) | Error::SocketConnect(_) => false,
-            Error::FeatureMismatch => false,
+            Error::FeatureMismatch | Error::OutOfOrderRequest(_) => false,
+            Error::RejectedRequest(_) | Error::RateLimited(_) => false,
+            Error::ResourceExhausted => false,
             Error::ReqHandlerError(_) => false,
+            // The connection still works, the caller decides whether to give up on the peer.
+            Error::Timeout => false,
+            Error::TooManyMemRegions { .. } | Error::UnalignedMemRegion { .. } => false,
+        }
+    }
+
+    /// Get the errno value reported by the slave in the REPLY_ACK payload of a request failing
+    /// with this error.
+    pub fn reply_code(&self) -> u64 {
+        let errno = match self {
+            Error::InvalidParam
+            | Error::InvalidMessage
+            | Error::IncorrectFds
+            | Error::OversizedMsg
+            | Error::TooManyFds(_)
+            | Error::UnalignedMemRegion { .. } => libc::EINVAL,
+            Error::InvalidOperation | Error::FeatureMismatch => libc::EOPNOTSUPP,
+            Error::OutOfOrderRequest(_) => libc::EPROTO,
+            Error::TooManyMemRegions { .. } | Error::ResourceExhausted => libc::ENOSPC,
+            Error::RejectedRequest(_) => libc::EACCES,
+            Error::RateLimited(_) => libc::EBUSY,
+            Error::ReqHandlerError(e) => e.raw_os_error().unwrap_or(libc::EIO),
+            _ => libc::EIO,
+        };
+        errno as u64
+    }
+
+    /// Decode the errno value reported by the slave in the reply to the failed `request`.
+    ///
+    /// Unknown values, such as the 1 sent by slaves which don't report errno values, are
+    /// decoded as `SlaveInternalError`.
+    pub fn from_reply_code(code: u64, request: message::MasterReq) -> Self {
+        match code as i32 {
+            libc::EINVAL => Error::InvalidParam,
+            libc::EOPNOTSUPP => Error::InvalidOperation,
+            libc::EPROTO => Error::OutOfOrderRequest(request),
+            libc::ENOSPC | libc::ENOMEM => Error::ResourceExhausted,
+            libc::EACCES => Error::RejectedRequest(request),
+            libc::EBUSY => Error::RateLimited(request),
+            _ => Error::SlaveInternalError,
         }
     }
 }
@@ -175,10 +371,17 @@ mod dummy_slave;
 
 #[cfg(all(test, feature = "vhost-user-master", feature = "vhost-user-slave"))]
 mod tests {
-    use super::dummy_slave::{DummySlaveReqHandler, VIRTIO_FEATURES};
+    use super::connection::Endpoint;
+    use super::dummy_slave::{DummySlaveReqHandler, MAX_MEM_SLOTS, MAX_QUEUE_NUM, VIRTIO_FEATURES};
     use super::message::*;
     use super::*;
-    use crate::backend::VhostBackend;
+    use crate::backend::{
+        VhostFeatures, VhostMemory, VhostOwner, VhostUserDirtyLogRegion, VhostUserMemoryRegionInfo,
+        VhostVring,
+    };
+    use std::fs::File;
+    use std::io::{Read, Write};
+    use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
     use std::sync::{Arc, Barrier, Mutex};
     use std::thread;
 
@@ -192,6 +395,66 @@ mod tests {
         (master, slave_listener.accept().unwrap().unwrap())
     }
 
+    #[test]
+    fn test_error_reply_code() {
+        let errors = [
+            (Error::InvalidMessage, Error::InvalidParam),
+            (Error::FeatureMismatch, Error::InvalidOperation),
+            (
+                Error::OutOfOrderRequest(MasterReq::SET_OWNER),
+                Error::OutOfOrderRequest(MasterReq::SET_MEM_TABLE),
+            ),
+            (
+                Error::TooManyMemRegions {
+                    max: 8,
+                    overflowed: vec![0x1000],
+                },
+                Error::ResourceExhausted,
+            ),
+            (
+                Error::RateLimited(MasterReq::SET_OWNER),
+                Error::RateLimited(MasterReq::SET_MEM_TABLE),
+            ),
+            (
+                Error::ReqHandlerError(IOError::from_raw_os_error(libc::ENOMEM)),
+                Error::ResourceExhausted,
+            ),
+            (
+                Error::UnalignedMemRegion {
+                    guest_phys_addr: 0x1000,
+                    alignment: 0x20_0000,
+                },
+                Error::InvalidParam,
+            ),
+            (Error::SlaveInternalError, Error::SlaveInternalError),
+        ];
+        for (err, decoded) in errors.iter() {
+            let code = err.reply_code();
+            assert_ne!(code, 0);
+            let res = Error::from_reply_code(code, MasterReq::SET_MEM_TABLE);
+            assert_eq!(format!("{:?}", res), format!("{:?}", decoded));
+        }
+        // Slaves which don't report errno values.
+        match Error::from_reply_code(1, MasterReq::SET_OWNER) {
+            Error::SlaveInternalError => {}
+            e => panic!("unexpected error {:?}", e),
+        }
+    }
+
+    #[test]
+    fn test_error_source() {
+        use std::error::Error as StdError;
+
+        let err = Error::SocketBroken(IOError::from_raw_os_error(libc::EPIPE));
+        let source = err.source().unwrap().downcast_ref::<IOError>().unwrap();
+        assert_eq!(source.raw_os_error(), Some(libc::EPIPE));
+        assert!(Error::InvalidMessage.source().is_none());
+
+        let err = crate::Error::from(err);
+        let source = err.source().unwrap().downcast_ref::<Error>().unwrap();
+        assert!(source.source().is_some());
+    }
+
     #[test]
     fn create_dummy_slave() {
         let mut slave = DummySlaveReqHandler::new();
@@ -215,6 +478,361 @@ mod tests {
         assert_eq!(slave_be.lock().unwrap().owned, true);
     }
 
+    #[test]
+    fn test_negotiate() {
+        let slave_be = Arc::new(Mutex::new(DummySlaveReqHandler::new()));
+        let (mut master, slave) = loopback::spawn_loopback(slave_be.clone(), 1).unwrap();
+        let protocol_features = VhostUserProtocolFeatures::MQ
+            | VhostUserProtocolFeatures::CONFIGURE_MEM_SLOTS
+            | VhostUserProtocolFeatures::REPLY_ACK;
+        let caps = master
+            .negotiate(VIRTIO_FEATURES | 0x8, protocol_features)
+            .unwrap();
+        assert_eq!(caps.virtio_features, VIRTIO_FEATURES);
+        assert_eq!(caps.protocol_features, protocol_features);
+        assert_eq!(caps.queue_num, MAX_QUEUE_NUM as u64);
+        assert_eq!(caps.max_mem_slots, MAX_MEM_SLOTS as u64);
+        assert_eq!(slave_be.lock().unwrap().acked_features, VIRTIO_FEATURES);
+        drop(master);
+        slave.join().unwrap();
+
+        // Without VHOST_USER_F_PROTOCOL_FEATURES, the defaults of the master are reported.
+        let slave_be = Arc::new(Mutex::new(DummySlaveReqHandler::new()));
+        let (mut master, slave) = loopback::spawn_loopback(slave_be, 1).unwrap();
+        let virtio_features = VIRTIO_FEATURES & !VhostUserVirtioFeatures::PROTOCOL_FEATURES.bits();
+        let caps = master
+            .negotiate(virtio_features, protocol_features)
+            .unwrap();
+        assert_eq!(caps.virtio_features, virtio_features);
+        assert!(caps.protocol_features.is_empty());
+        assert_eq!(caps.queue_num, 1);
+        assert_eq!(caps.max_mem_slots, MAX_ATTACHED_FD_ENTRIES as u64);
+        drop(master);
+        slave.join().unwrap();
+    }
+
+    #[test]
+    fn test_inband_notifications() {
+        let slave_be = Arc::new(Mutex::new(DummySlaveReqHandler::new()));
+        let (mut master, slave) = loopback::spawn_loopback(slave_be.clone(), 1).unwrap();
+        master
+            .negotiate(VIRTIO_FEATURES, VhostUserProtocolFeatures::REPLY_ACK)
+            .unwrap();
+        assert!(master.set_vring_inband(0).is_err());
+        assert!(master.vring_kick(0).is_err());
+        drop(master);
+        slave.join().unwrap();
+
+        let slave_be = Arc::new(Mutex::new(DummySlaveReqHandler::new()));
+        let (mut master, slave) = loopback::spawn_loopback(slave_be.clone(), 1).unwrap();
+        master
+            .negotiate(
+                VIRTIO_FEATURES,
+                VhostUserProtocolFeatures::REPLY_ACK
+                    | VhostUserProtocolFeatures::INBAND_NOTIFICATIONS,
+            )
+            .unwrap();
+        let file = vmm_sys_util::tempfile::TempFile::new().unwrap().into_file();
+        file.set_len(0x1000).unwrap();
+        let region = VhostUserMemoryRegionInfo {
+            guest_phys_addr: 0,
+            memory_size: 0x1000,
+            userspace_addr: 0x7f00_0000_0000,
+            mmap_offset: 0,
+            mmap_handle: file.as_raw_fd(),
+        };
+        master.set_mem_table(&[region]).unwrap();
+        master.set_vring_inband(0).unwrap();
+
+        // The backend gets a kick eventfd signaled by the VRING_KICK requests.
+        let kick = {
+            let backend = slave_be.lock().unwrap();
+            assert!(backend.call_fd[0].is_none());
+            assert!(backend.err_fd[0].is_none());
+            let fd = unsafe { libc::dup(backend.kick_fd[0].unwrap()) };
+            unsafe { vmm_sys_util::eventfd::EventFd::from_raw_fd(fd) }
+        };
+        master.vring_kick(0).unwrap();
+        master.vring_kick(0).unwrap();
+        assert_eq!(kick.read().unwrap(), 2);
+        assert!(master.vring_kick(1).is_err());
+        drop(master);
+        slave.join().unwrap();
+    }
+
+    #[test]
+    fn test_fd_leak_check() {
+        let (master_sock, slave_sock) = std::os::unix::net::UnixStream::pair().unwrap();
+        let slave_be = Arc::new(Mutex::new(DummySlaveReqHandler::new()));
+        let mut master = Endpoint::<MasterReq>::from_stream(master_sock);
+        let mut slave = SlaveReqHandler::from_stream(slave_sock, slave_be);
+        slave.set_fd_leak_check(true);
+
+        // SET_OWNER carries no fd, the one attached is closed and reported.
+        let evt = vmm_sys_util::eventfd::EventFd::new(0).unwrap();
+        let hdr = VhostUserMsgHeader::new(MasterReq::SET_OWNER, 0x1, 0);
+        master.send_header(&hdr, Some(&[evt.as_raw_fd()])).unwrap();
+        assert!(slave.handle_request().is_err());
+        assert_eq!(slave.fd_leaks(), &[(MasterReq::SET_OWNER, 1)]);
+
+        master.send_header(&hdr, None).unwrap();
+        slave.handle_request().unwrap();
+        assert_eq!(slave.fd_leaks().len(), 1);
+
+        slave.set_fd_leak_check(false);
+        assert!(slave.fd_leaks().is_empty());
+    }
+
+    #[test]
+    fn test_feature_mismatch() {
+        let (master_sock, slave_sock) = std::os::unix::net::UnixStream::pair().unwrap();
+        let slave_be = Arc::new(Mutex::new(DummySlaveReqHandler::new()));
+        let mut master = Endpoint::<MasterReq>::from_stream(master_sock);
+        let mut slave = SlaveReqHandler::from_stream(slave_sock, slave_be.clone());
+
+        let hdr = VhostUserMsgHeader::new(MasterReq::SET_OWNER, 0x1, 0);
+        master.send_header(&hdr, None).unwrap();
+        slave.handle_request().unwrap();
+
+        // Bit 3 isn't offered by the dummy slave.
+        let hdr = VhostUserMsgHeader::new(MasterReq::SET_FEATURES, 0x1, 8);
+        let msg = VhostUserU64::new(VIRTIO_FEATURES | 0x8);
+        master.send_message(&hdr, &msg, None).unwrap();
+        match slave.handle_request() {
+            Err(Error::FeatureMismatch) => {}
+            _ => panic!("features not offered acked"),
+        }
+        assert!(!slave_be.lock().unwrap().features_acked);
+        let msg = VhostUserU64::new(VIRTIO_FEATURES);
+        master.send_message(&hdr, &msg, None).unwrap();
+        slave.handle_request().unwrap();
+        assert_eq!(slave_be.lock().unwrap().acked_features, VIRTIO_FEATURES);
+
+        let hdr = VhostUserMsgHeader::new(MasterReq::SET_PROTOCOL_FEATURES, 0x1, 8);
+        let msg = VhostUserU64::new(VhostUserProtocolFeatures::all().bits() | 1 << 63);
+        master.send_message(&hdr, &msg, None).unwrap();
+        match slave.handle_request() {
+            Err(Error::FeatureMismatch) => {}
+            _ => panic!("protocol features not offered acked"),
+        }
+        assert_eq!(slave_be.lock().unwrap().acked_protocol_features, 0);
+    }
+
+    #[test]
+    fn test_request_filter() {
+        let (master_sock, slave_sock) = std::os::unix::net::UnixStream::pair().unwrap();
+        let slave_be = Arc::new(Mutex::new(DummySlaveReqHandler::new()));
+        let mut master = Endpoint::<MasterReq>::from_stream(master_sock);
+        let mut slave = SlaveReqHandler::from_stream(slave_sock, slave_be.clone());
+        // Deny resetting the session, and file descriptors attached to SET_OWNER.
+        slave.set_request_filter(|code, _size, num_fds| match code {
+            MasterReq::RESET_OWNER => false,
+            MasterReq::SET_OWNER => num_fds == 0,
+            _ => true,
+        });
+
+        let hdr = VhostUserMsgHeader::new(MasterReq::SET_OWNER, 0x1, 0);
+        let eventfd = vmm_sys_util::eventfd::EventFd::new(0).unwrap();
+        master
+            .send_header(&hdr, Some(&[eventfd.as_raw_fd()]))
+            .unwrap();
+        match slave.handle_request() {
+            Err(Error::RejectedRequest(MasterReq::SET_OWNER)) => {}
+            _ => panic!("request with fds not rejected"),
+        }
+        master.send_header(&hdr, None).unwrap();
+        slave.handle_request().unwrap();
+        assert!(slave_be.lock().unwrap().owned);
+
+        let hdr = VhostUserMsgHeader::new(MasterReq::RESET_OWNER, 0x1, 0);
+        master.send_header(&hdr, None).unwrap();
+        match slave.handle_request() {
+            Err(Error::RejectedRequest(MasterReq::RESET_OWNER)) => {}
+            _ => panic!("denied request handled"),
+        }
+        assert!(slave_be.lock().unwrap().owned);
+    }
+
+    #[test]
+    fn test_rate_limit() {
+        let (master_sock, slave_sock) = std::os::unix::net::UnixStream::pair().unwrap();
+        let slave_be = Arc::new(Mutex::new(DummySlaveReqHandler::new()));
+        let mut master = Endpoint::<MasterReq>::from_stream(master_sock);
+        let mut slave = SlaveReqHandler::from_stream(slave_sock, slave_be);
+        let limiter = RateLimiter::new(1, std::time::Duration::from_secs(3600));
+        slave.set_rate_limit(
+            &[MasterReq::GET_FEATURES],
+            limiter.clone(),
+            RateLimitPolicy::Reject,
+        );
+
+        let hdr = VhostUserMsgHeader::new(MasterReq::SET_OWNER, 0x1, 0);
+        master.send_header(&hdr, None).unwrap();
+        slave.handle_request().unwrap();
+        let hdr = VhostUserMsgHeader::new(MasterReq::GET_FEATURES, 0x1, 0);
+        master.send_header(&hdr, None).unwrap();
+        slave.handle_request().unwrap();
+        master.recv_body::<VhostUserU64>().unwrap();
+        master.send_header(&hdr, None).unwrap();
+        match slave.handle_request() {
+            Err(Error::RateLimited(MasterReq::GET_FEATURES)) => {}
+            _ => panic!("rate limit not enforced"),
+        }
+        // Other requests aren't limited.
+        let hdr = VhostUserMsgHeader::new(MasterReq::GET_PROTOCOL_FEATURES, 0x1, 0);
+        master.send_header(&hdr, None).unwrap();
+        slave.handle_request().unwrap();
+        master.recv_body::<VhostUserU64>().unwrap();
+
+        // The connection is considered broken once all requests exceed the limit.
+        slave.set_rate_limit(&[], limiter, RateLimitPolicy::Disconnect);
+        master.send_header(&hdr, None).unwrap();
+        slave.handle_request().unwrap();
+        master.recv_body::<VhostUserU64>().unwrap();
+        master.send_header(&hdr, None).unwrap();
+        match slave.handle_request() {
+            Err(Error::RateLimited(MasterReq::GET_PROTOCOL_FEATURES)) => {}
+            _ => panic!("rate limit not enforced"),
+        }
+        match slave.handle_request() {
+            Err(Error::SocketBroken(_)) => {}
+            _ => panic!("rate limited connection not broken"),
+        }
+    }
+
+    #[test]
+    fn test_session_state() {
+        let slave_be = Arc::new(Mutex::new(DummySlaveReqHandler::new()));
+        let (mut master, mut slave) =
+            create_slave("/tmp/vhost_user_lib_unit_test_session", slave_be.clone());
+        let eventfd = vmm_sys_util::eventfd::EventFd::new(0).unwrap();
+
+        assert_eq!(slave.session_state(), SessionState::Init);
+        master.set_vring_num(0, 64).unwrap();
+        match slave.handle_request() {
+            Err(Error::OutOfOrderRequest(MasterReq::SET_VRING_NUM)) => {}
+            _ => panic!("SET_VRING_NUM accepted before SET_OWNER"),
+        }
+        master.set_owner().unwrap();
+        slave.handle_request().unwrap();
+        assert_eq!(slave.session_state(), SessionState::Owned);
+        master.set_features(VIRTIO_FEATURES).unwrap();
+        slave.handle_request().unwrap();
+        assert_eq!(slave.session_state(), SessionState::FeaturesNegotiated);
+
+        master.set_vring_kick(0, &eventfd).unwrap();
+        match slave.handle_request() {
+            Err(Error::OutOfOrderRequest(MasterReq::SET_VRING_KICK)) => {}
+            _ => panic!("SET_VRING_KICK accepted before SET_MEM_TABLE"),
+        }
+        let file = vmm_sys_util::tempfile::TempFile::new().unwrap().into_file();
+        file.set_len(0x1000).unwrap();
+        let region = VhostUserMemoryRegionInfo {
+            guest_phys_addr: 0,
+            memory_size: 0x1000,
+            userspace_addr: 0x7f00_0000_0000,
+            mmap_offset: 0,
+            mmap_handle: file.as_raw_fd(),
+        };
+        master.set_mem_table(&[region]).unwrap();
+        slave.handle_request().unwrap();
+        assert_eq!(slave.session_state(), SessionState::MemTableSet);
+        master.set_vring_kick(0, &eventfd).unwrap();
+        slave.handle_request().unwrap();
+        assert_eq!(slave.session_state(), SessionState::Running);
+        assert!(slave_be.lock().unwrap().vring_started[0]);
+
+        master.set_owner().unwrap();
+        assert!(slave.handle_request().is_err());
+        master.reset_owner().unwrap();
+        slave.handle_request().unwrap();
+        assert_eq!(slave.session_state(), SessionState::Init);
+    }
+
+    #[test]
+    fn test_qemu_init_order() {
+        let slave_be = Arc::new(Mutex::new(DummySlaveReqHandler::new()));
+        let (mut master, mut slave) =
+            create_slave("/tmp/vhost_user_lib_unit_test_qemu_init", slave_be.clone());
+        let handle = thread::spawn(move || loop {
+            if let Err(e) = slave.handle_request() {
+                return (slave, e);
+            }
+        });
+
+        // vhost_dev_init() takes the ownership of the session, and sets the call notifiers of
+        // the vrings before the features are negotiated.
+        let call = vmm_sys_util::eventfd::EventFd::new(0).unwrap();
+        master.get_features().unwrap();
+        master.set_owner().unwrap();
+        let features = master.get_features().unwrap();
+        master.set_vring_call(0, &call).unwrap();
+        master.set_vring_err(0, &call).unwrap();
+
+        // vhost_dev_start() then negotiates the features and starts the vrings.
+        let file = vmm_sys_util::tempfile::TempFile::new().unwrap().into_file();
+        file.set_len(0x1000).unwrap();
+        let region = VhostUserMemoryRegionInfo {
+            guest_phys_addr: 0,
+            memory_size: 0x1000,
+            userspace_addr: 0x7f00_0000_0000,
+            mmap_offset: 0,
+            mmap_handle: file.as_raw_fd(),
+        };
+        let kick = vmm_sys_util::eventfd::EventFd::new(0).unwrap();
+        master.set_features(features).unwrap();
+        master.set_mem_table(&[region]).unwrap();
+        master.set_vring_num(0, 64).unwrap();
+        master.set_vring_base(0, 0).unwrap();
+        master.set_vring_kick(0, &kick).unwrap();
+        master.set_vring_call(0, &call).unwrap();
+
+        // Every request has been accepted until the master went away.
+        drop(master);
+        let (slave, e) = handle.join().unwrap();
+        match e {
+            Error::SocketBroken(_) | Error::PartialMessage => {}
+            e => panic!("request of QEMU rejected: {}", e),
+        }
+        assert_eq!(slave.session_state(), SessionState::Running);
+        assert!(slave_be.lock().unwrap().vring_started[0]);
+    }
+
+    #[test]
+    fn test_nonblocking_slave() {
+        let slave_be = Arc::new(Mutex::new(DummySlaveReqHandler::new()));
+        let (mut master, mut slave) = create_slave(
+            "/tmp/vhost_user_lib_unit_test_nonblocking",
+            slave_be.clone(),
+        );
+        slave.set_nonblocking(true).unwrap();
+        assert!(!slave.handle_readable().unwrap());
+
+        master.set_owner().unwrap();
+        master.set_features(VIRTIO_FEATURES).unwrap();
+        let file = vmm_sys_util::tempfile::TempFile::new().unwrap().into_file();
+        file.set_len(0x1000).unwrap();
+        let region = VhostUserMemoryRegionInfo {
+            guest_phys_addr: 0,
+            memory_size: 0x1000,
+            userspace_addr: 0x7f00_0000_0000,
+            mmap_offset: 0,
+            mmap_handle: file.as_raw_fd(),
+        };
+        master.set_mem_table(&[region]).unwrap();
+        let mut handled = 0;
+        while slave.handle_readable().unwrap() {
+            handled += 1;
+        }
+        assert_eq!(handled, 3);
+        assert_eq!(slave.session_state(), SessionState::MemTableSet);
+        assert_eq!(slave_be.lock().unwrap().acked_features, VIRTIO_FEATURES);
+
+        // The socket is broken once the master has gone.
+        drop(master);
+        assert!(slave.handle_readable().is_err());
+    }
+
     #[test]
     fn test_set_features() {
         let mbar = Arc::new(Barrier::new(2));
@@ -258,4 +876,1574 @@ mod tests {
 
         mbar.wait();
     }
+
+    #[test]
+    fn test_ack_features() {
+        use crate::features::{VirtioFeatures, VirtioNetFeatures};
+
+        let mbar = Arc::new(Barrier::new(2));
+        let sbar = mbar.clone();
+        let slave_be = Arc::new(Mutex::new(DummySlaveReqHandler::new()));
+        let (mut master, mut slave) =
+            create_slave("/tmp/vhost_user_lib_unit_test_ack", slave_be.clone());
+
+        thread::spawn(move || {
+            for _ in 0..4 {
+                slave.handle_request().unwrap();
+            }
+            assert_eq!(slave_be.lock().unwrap().acked_features, 0x4000_0002);
+            sbar.wait();
+        });
+
+        master.set_owner().unwrap();
+        // Features not offered by the slave are rejected before being sent.
+        assert!(master
+            .ack_features(VirtioNetFeatures::MAC, VirtioFeatures::PROTOCOL_FEATURES)
+            .is_err());
+        master
+            .ack_features(
+                VirtioNetFeatures::GUEST_CSUM,
+                VirtioFeatures::PROTOCOL_FEATURES,
+            )
+            .unwrap();
+        mbar.wait();
+    }
+
+    #[test]
+    fn test_inflight_fd() {
+        let mbar = Arc::new(Barrier::new(2));
+        let sbar = mbar.clone();
+        let slave_be = Arc::new(Mutex::new(DummySlaveReqHandler::new()));
+        let (mut master, mut slave) =
+            create_slave("/tmp/vhost_user_lib_unit_test_inflight", slave_be.clone());
+
+        thread::spawn(move || {
+            for _ in 0..7 {
+                slave.handle_request().unwrap();
+            }
+            {
+                let mut backend = slave_be.lock().unwrap();
+                let region = backend.inflight.as_mut().unwrap();
+                assert_eq!(region.num_queues(), 2);
+                assert_eq!(region.queue_size(), 256);
+                assert_eq!(region.queue_mut(1).unwrap().desc_num(), 256);
+            }
+            sbar.wait();
+        });
+
+        let inflight = VhostUserInflight::new(0, 0, 2, 256);
+        assert!(master.get_inflight_fd(&inflight).is_err());
+
+        master.set_owner().unwrap();
+        master.get_features().unwrap();
+        master.set_features(VIRTIO_FEATURES).unwrap();
+        master.get_protocol_features().unwrap();
+        master
+            .set_protocol_features(VhostUserProtocolFeatures::INFLIGHT_SHMFD)
+            .unwrap();
+
+        let (reply, file) = master.get_inflight_fd(&inflight).unwrap();
+        assert_eq!({ reply.num_queues }, 2);
+        assert_eq!({ reply.queue_size }, 256);
+        assert_eq!({ reply.mmap_size }, file.metadata().unwrap().len());
+        master.set_inflight_fd(&reply, file.as_raw_fd()).unwrap();
+
+        mbar.wait();
+    }
+
+    #[test]
+    fn test_slave_reconnect() {
+        let path = "/tmp/vhost_user_lib_unit_test_slave_reconnect";
+        let mbar = Arc::new(Barrier::new(2));
+        let sbar = mbar.clone();
+        let slave_be = Arc::new(Mutex::new(DummySlaveReqHandler::new()));
+        let listener = Listener::new(path, true).unwrap();
+        let mut slave_listener = SlaveListener::new(listener, slave_be.clone()).unwrap();
+        slave_listener.set_preserve_inflight(true);
+
+        thread::spawn(move || {
+            let mut slave = slave_listener.accept().unwrap().unwrap();
+            for _ in 0..6 {
+                slave.handle_request().unwrap();
+            }
+            drop(slave);
+            sbar.wait();
+
+            let mut slave = slave_listener.accept().unwrap().unwrap();
+            slave.handle_request().unwrap();
+            {
+                let backend = slave_be.lock().unwrap();
+                assert!(backend.owned);
+                assert_eq!(backend.acked_protocol_features, 0);
+                assert!(backend.inflight.is_some());
+            }
+            sbar.wait();
+        });
+
+        let mut master = Master::connect(path, 1).unwrap();
+        master.set_owner().unwrap();
+        master.get_features().unwrap();
+        master.set_features(VIRTIO_FEATURES).unwrap();
+        master.get_protocol_features().unwrap();
+        master
+            .set_protocol_features(VhostUserProtocolFeatures::INFLIGHT_SHMFD)
+            .unwrap();
+        let inflight = VhostUserInflight::new(0, 0, 2, 256);
+        master.get_inflight_fd(&inflight).unwrap();
+        mbar.wait();
+        drop(master);
+
+        // The session has been reset, so the new master may take the ownership again.
+        let mut master = Master::connect(path, 1).unwrap();
+        master.set_owner().unwrap();
+        mbar.wait();
+    }
+
+    #[test]
+    fn test_seqpacket_socketpair() {
+        let mut fds = [0; 2];
+        // Safe because we check the return value, and take the ownership of the new sockets.
+        let (master_sock, slave_sock) = unsafe {
+            let ret = libc::socketpair(
+                libc::AF_UNIX,
+                libc::SOCK_SEQPACKET | libc::SOCK_CLOEXEC,
+                0,
+                fds.as_mut_ptr(),
+            );
+            assert_eq!(ret, 0);
+            (
+                std::os::unix::net::UnixStream::from_raw_fd(fds[0]),
+                std::os::unix::net::UnixStream::from_raw_fd(fds[1]),
+            )
+        };
+        let slave_be = Arc::new(Mutex::new(DummySlaveReqHandler::new()));
+        let mut master = Master::from_stream(master_sock, 1);
+        let mut slave = SlaveReqHandler::from_stream(slave_sock, slave_be.clone());
+        let handle = thread::spawn(move || {
+            for _ in 0..4 {
+                slave.handle_request().unwrap();
+            }
+        });
+
+        master.set_owner().unwrap();
+        assert_eq!(master.get_features().unwrap(), VIRTIO_FEATURES);
+        master.set_features(VIRTIO_FEATURES).unwrap();
+        let file = vmm_sys_util::tempfile::TempFile::new().unwrap().into_file();
+        file.set_len(0x1000).unwrap();
+        let region = VhostUserMemoryRegionInfo {
+            guest_phys_addr: 0,
+            memory_size: 0x1000,
+            userspace_addr: 0x7f00_0000_0000,
+            mmap_offset: 0,
+            mmap_handle: file.as_raw_fd(),
+        };
+        master.set_mem_table(&[region]).unwrap();
+        handle.join().unwrap();
+        assert_eq!(slave_be.lock().unwrap().acked_features, VIRTIO_FEATURES);
+    }
+
+    // Pass file descriptors over a channel, as a hook of a transport without SCM_RIGHTS would.
+    struct ChannelHook {
+        tx: std::sync::mpsc::Sender<Vec<RawFd>>,
+        rx: std::sync::mpsc::Receiver<Vec<RawFd>>,
+    }
+
+    impl SharedMemoryHook for ChannelHook {
+        fn send_fds(&mut self, fds: &[RawFd]) -> std::io::Result<()> {
+            // Safe because dup() doesn't access memory.
+            let fds = fds.iter().map(|fd| unsafe { libc::dup(*fd) }).collect();
+            self.tx
+                .send(fds)
+                .map_err(|_| IOError::from_raw_os_error(libc::EPIPE))
+        }
+
+        fn recv_fds(&mut self) -> std::io::Result<Vec<RawFd>> {
+            self.rx
+                .recv()
+                .map_err(|_| IOError::from_raw_os_error(libc::EPIPE))
+        }
+    }
+
+    #[test]
+    fn test_shared_memory_hook() {
+        let (master_tx, slave_rx) = std::sync::mpsc::channel();
+        let (slave_tx, master_rx) = std::sync::mpsc::channel();
+        let (master_sock, slave_sock) = std::os::unix::net::UnixStream::pair().unwrap();
+        let slave_be = Arc::new(Mutex::new(DummySlaveReqHandler::new()));
+        let mut master = Master::from_stream(master_sock, 1);
+        master.set_shared_memory_hook(Box::new(ChannelHook {
+            tx: master_tx,
+            rx: master_rx,
+        }));
+        let mut slave = SlaveReqHandler::from_stream(slave_sock, slave_be.clone());
+        slave.set_shared_memory_hook(Box::new(ChannelHook {
+            tx: slave_tx,
+            rx: slave_rx,
+        }));
+        let handle = thread::spawn(move || {
+            for _ in 0..6 {
+                slave.handle_request().unwrap();
+            }
+        });
+
+        master.set_owner().unwrap();
+        master.get_features().unwrap();
+        master.set_features(VIRTIO_FEATURES).unwrap();
+        let file = vmm_sys_util::tempfile::TempFile::new().unwrap().into_file();
+        file.set_len(0x1000).unwrap();
+        let region = VhostUserMemoryRegionInfo {
+            guest_phys_addr: 0,
+            memory_size: 0x1000,
+            userspace_addr: 0x7f00_0000_0000,
+            mmap_offset: 0,
+            mmap_handle: file.as_raw_fd(),
+        };
+        master.set_mem_table(&[region]).unwrap();
+        let kick = vmm_sys_util::eventfd::EventFd::new(0).unwrap();
+        master.set_vring_kick(0, &kick).unwrap();
+        master.get_features().unwrap();
+        handle.join().unwrap();
+        assert!(slave_be.lock().unwrap().kick_fd[0].is_some());
+    }
+
+    #[test]
+    fn test_slave_peer_check() {
+        let path = "/tmp/vhost_user_lib_unit_test_slave_peer_check";
+        let slave_be = Arc::new(Mutex::new(DummySlaveReqHandler::new()));
+        let listener = Listener::new(path, true).unwrap();
+        listener.set_mode(0o600).unwrap();
+        let mut slave_listener = SlaveListener::new(listener, slave_be).unwrap();
+        // Reject the first connection only.
+        let checked = std::sync::atomic::AtomicUsize::new(0);
+        slave_listener.set_peer_check(move |cred| {
+            assert_eq!(cred.pid, std::process::id() as i32);
+            checked.fetch_add(1, std::sync::atomic::Ordering::SeqCst) > 0
+        });
+
+        let mut rejected = Master::connect(path, 1).unwrap();
+        let mut master = Master::connect(path, 1).unwrap();
+        let handle = thread::spawn(move || {
+            let mut slave = slave_listener.accept().unwrap().unwrap();
+            slave.handle_request().unwrap();
+        });
+        assert_eq!(master.get_features().unwrap(), VIRTIO_FEATURES);
+        handle.join().unwrap();
+        assert!(rejected.get_features().is_err());
+    }
+
+    #[test]
+    fn test_reset_device() {
+        let mbar = Arc::new(Barrier::new(2));
+        let sbar = mbar.clone();
+        let slave_be = Arc::new(Mutex::new(DummySlaveReqHandler::new()));
+        let (mut master, mut slave) = create_slave(
+            "/tmp/vhost_user_lib_unit_test_reset_device",
+            slave_be.clone(),
+        );
+
+        thread::spawn(move || {
+            for _ in 0..6 {
+                slave.handle_request().unwrap();
+            }
+            assert_eq!(slave_be.lock().unwrap().vring_num[0], 64);
+            slave.handle_request().unwrap();
+            {
+                let backend = slave_be.lock().unwrap();
+                assert!(backend.owned);
+                assert!(!backend.features_acked);
+                assert_eq!(backend.vring_num[0], 0);
+                assert_eq!(
+                    backend.acked_protocol_features,
+                    VhostUserProtocolFeatures::RESET_DEVICE.bits()
+                );
+            }
+            slave.handle_request().unwrap();
+            assert!(slave_be.lock().unwrap().features_acked);
+            sbar.wait();
+        });
+
+        master.set_owner().unwrap();
+        master.get_features().unwrap();
+        master.set_features(VIRTIO_FEATURES).unwrap();
+        master.get_protocol_features().unwrap();
+        assert!(master.reset_device().is_err());
+        master
+            .set_protocol_features(VhostUserProtocolFeatures::RESET_DEVICE)
+            .unwrap();
+        master.set_vring_num(0, 64).unwrap();
+        master.reset_device().unwrap();
+        // The features have to be negotiated again after the device has been reset.
+        master.set_features(VIRTIO_FEATURES).unwrap();
+        mbar.wait();
+    }
+
+    #[test]
+    fn test_device_status() {
+        let mbar = Arc::new(Barrier::new(2));
+        let sbar = mbar.clone();
+        let slave_be = Arc::new(Mutex::new(DummySlaveReqHandler::new()));
+        let (mut master, mut slave) =
+            create_slave("/tmp/vhost_user_lib_unit_test_status", slave_be.clone());
+
+        thread::spawn(move || {
+            for _ in 0..7 {
+                slave.handle_request().unwrap();
+            }
+            sbar.wait();
+        });
+
+        master.set_owner().unwrap();
+        master.get_features().unwrap();
+        master.set_features(VIRTIO_FEATURES).unwrap();
+        master.get_protocol_features().unwrap();
+        assert!(master.get_status().is_err());
+        master
+            .set_protocol_features(VhostUserProtocolFeatures::STATUS)
+            .unwrap();
+
+        let status = VhostUserDeviceStatus::ACKNOWLEDGE
+            | VhostUserDeviceStatus::DRIVER
+            | VhostUserDeviceStatus::FEATURES_OK
+            | VhostUserDeviceStatus::DRIVER_OK;
+        master.set_status(status.bits()).unwrap();
+        assert_eq!(master.get_status().unwrap(), status.bits());
+        mbar.wait();
+    }
+
+    #[test]
+    fn test_shared_memory_regions() {
+        let mbar = Arc::new(Barrier::new(2));
+        let sbar = mbar.clone();
+        let slave_be = Arc::new(Mutex::new(DummySlaveReqHandler::new()));
+        slave_be.lock().unwrap().shmem_sizes = vec![0x4000_0000, 0, 0x20_0000];
+        let (mut master, mut slave) = create_slave("/tmp/vhost_user_lib_unit_test_shmem", slave_be);
+
+        thread::spawn(move || {
+            for _ in 0..6 {
+                slave.handle_request().unwrap();
+            }
+            sbar.wait();
+        });
+
+        master.set_owner().unwrap();
+        master.get_features().unwrap();
+        master.set_features(VIRTIO_FEATURES).unwrap();
+        master.get_protocol_features().unwrap();
+        assert!(master.get_shared_memory_regions().is_err());
+        master
+            .set_protocol_features(VhostUserProtocolFeatures::SHMEM)
+            .unwrap();
+        assert_eq!(
+            master.get_shared_memory_regions().unwrap(),
+            vec![0x4000_0000, 0, 0x20_0000]
+        );
+        mbar.wait();
+    }
+
+    #[test]
+    fn test_reply_ack() {
+        let mbar = Arc::new(Barrier::new(2));
+        let sbar = mbar.clone();
+        let slave_be = Arc::new(Mutex::new(DummySlaveReqHandler::new()));
+        let (mut master, mut slave) =
+            create_slave("/tmp/vhost_user_lib_unit_test_reply_ack", slave_be.clone());
+
+        thread::spawn(move || {
+            for _ in 0..6 {
+                slave.handle_request().unwrap();
+            }
+            // The master is told about the requests failing with an error as well.
+            assert!(slave.handle_request().is_err());
+            assert!(slave.handle_request().is_err());
+            for _ in 0..2 {
+                slave.handle_request().unwrap();
+            }
+            assert_eq!(slave_be.lock().unwrap().vring_num[0], 64);
+            sbar.wait();
+        });
+
+        master.set_owner().unwrap();
+        master.get_features().unwrap();
+        master.set_features(VIRTIO_FEATURES).unwrap();
+        master.get_protocol_features().unwrap();
+        master
+            .set_protocol_features(VhostUserProtocolFeatures::REPLY_ACK)
+            .unwrap();
+
+        // The nacks tell why the requests failed.
+        match master.set_vring_num(0, 0) {
+            Err(crate::Error::VhostUserProtocol(Error::InvalidParam)) => {}
+            _ => panic!("invalid vring size not nacked"),
+        }
+        match master.set_owner() {
+            Err(crate::Error::VhostUserProtocol(Error::OutOfOrderRequest(
+                MasterReq::SET_OWNER,
+            ))) => {}
+            _ => panic!("second SET_OWNER not nacked"),
+        }
+        match master.set_features(VIRTIO_FEATURES & !0x1) {
+            Err(crate::Error::VhostUserProtocol(Error::InvalidOperation)) => {}
+            _ => panic!("features change not nacked"),
+        }
+        master.set_vring_num(0, 64).unwrap();
+        assert_eq!(master.get_features().unwrap(), VIRTIO_FEATURES);
+        mbar.wait();
+    }
+
+    #[test]
+    fn test_multi_queue() {
+        let mbar = Arc::new(Barrier::new(2));
+        let sbar = mbar.clone();
+        let slave_be = Arc::new(Mutex::new(DummySlaveReqHandler::new()));
+        let (mut master, mut slave) =
+            create_slave("/tmp/vhost_user_lib_unit_test_mq", slave_be.clone());
+
+        thread::spawn(move || {
+            for _ in 0..9 {
+                slave.handle_request().unwrap();
+            }
+            assert_eq!(slave_be.lock().unwrap().vring_enabled, [true, true]);
+            sbar.wait();
+        });
+
+        master.set_owner().unwrap();
+        master.get_features().unwrap();
+        master.set_features(VIRTIO_FEATURES).unwrap();
+        master.get_protocol_features().unwrap();
+        master
+            .set_protocol_features(VhostUserProtocolFeatures::empty())
+            .unwrap();
+        assert!(master.get_queue_num().is_err());
+        assert!(master.set_vring_enable(1, true).is_err());
+
+        master
+            .set_protocol_features(VhostUserProtocolFeatures::MQ)
+            .unwrap();
+        assert!(master.set_vring_enable(1, true).is_err());
+        assert_eq!(master.get_queue_num().unwrap(), MAX_QUEUE_NUM as u64);
+        master.set_vring_enable(1, true).unwrap();
+        master.set_vring_enable(0, true).unwrap();
+        mbar.wait();
+    }
+
+    #[test]
+    fn test_net_messages() {
+        let mbar = Arc::new(Barrier::new(2));
+        let sbar = mbar.clone();
+        let slave_be = Arc::new(Mutex::new(DummySlaveReqHandler::new()));
+        let (mut master, mut slave) =
+            create_slave("/tmp/vhost_user_lib_unit_test_net", slave_be.clone());
+
+        thread::spawn(move || {
+            for _ in 0..7 {
+                slave.handle_request().unwrap();
+            }
+            let backend = slave_be.lock().unwrap();
+            assert_eq!(backend.mtu, Some(1500));
+            assert_eq!(backend.rarp_mac, Some([0x52, 0x54, 0, 0x12, 0x34, 0x56]));
+            sbar.wait();
+        });
+
+        master.set_owner().unwrap();
+        master.get_features().unwrap();
+        master.set_features(VIRTIO_FEATURES).unwrap();
+        master.get_protocol_features().unwrap();
+        assert!(master.send_rarp(&[0u8; 6]).is_err());
+        master
+            .set_protocol_features(VhostUserProtocolFeatures::RARP | VhostUserProtocolFeatures::MTU)
+            .unwrap();
+
+        master.net_set_mtu(1500).unwrap();
+        master
+            .send_rarp(&[0x52, 0x54, 0, 0x12, 0x34, 0x56])
+            .unwrap();
+        mbar.wait();
+    }
+
+    fn create_pipe() -> (File, File) {
+        let mut fds = [0; 2];
+        // Safe because we check the return value, and the files own the new descriptors.
+        unsafe {
+            assert_eq!(libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC), 0);
+            (File::from_raw_fd(fds[0]), File::from_raw_fd(fds[1]))
+        }
+    }
+
+    #[test]
+    fn test_device_state() {
+        let mbar = Arc::new(Barrier::new(2));
+        let sbar = mbar.clone();
+        let slave_be = Arc::new(Mutex::new(DummySlaveReqHandler::new()));
+        slave_be.lock().unwrap().device_state = b"saved state".to_vec();
+        let (mut master, mut slave) = create_slave(
+            "/tmp/vhost_user_lib_unit_test_device_state",
+            slave_be.clone(),
+        );
+
+        thread::spawn(move || {
+            for _ in 0..10 {
+                slave.handle_request().unwrap();
+            }
+            assert_eq!(slave_be.lock().unwrap().device_state, b"loaded state");
+            sbar.wait();
+        });
+
+        master.set_owner().unwrap();
+        master.get_features().unwrap();
+        master.set_features(VIRTIO_FEATURES).unwrap();
+        master.get_protocol_features().unwrap();
+        assert!(master.check_device_state().is_err());
+        master
+            .set_protocol_features(VhostUserProtocolFeatures::DEVICE_STATE)
+            .unwrap();
+
+        // No transfer has been started yet.
+        assert!(master.check_device_state().is_err());
+
+        let (mut rx, tx) = create_pipe();
+        let fd = master
+            .set_device_state_fd(
+                VhostTransferStateDirection::Save,
+                VhostTransferStatePhase::Stopped,
+                tx.as_raw_fd(),
+            )
+            .unwrap();
+        assert!(fd.is_none());
+        drop(tx);
+        let mut state = Vec::new();
+        rx.read_to_end(&mut state).unwrap();
+        assert_eq!(state, b"saved state");
+        master.check_device_state().unwrap();
+
+        let (rx, mut tx) = create_pipe();
+        let fd = master
+            .set_device_state_fd(
+                VhostTransferStateDirection::Load,
+                VhostTransferStatePhase::Stopped,
+                rx.as_raw_fd(),
+            )
+            .unwrap();
+        assert!(fd.is_none());
+        drop(rx);
+        tx.write_all(b"loaded state").unwrap();
+        drop(tx);
+        master.check_device_state().unwrap();
+        mbar.wait();
+    }
+
+    #[test]
+    fn test_migration() {
+        use crate::features::VirtioFeatures;
+
+        let slave_be = Arc::new(Mutex::new(DummySlaveReqHandler::new()));
+        slave_be.lock().unwrap().device_state = b"saved state".to_vec();
+        let (mut master, mut slave) =
+            create_slave("/tmp/vhost_user_lib_unit_test_migration", slave_be.clone());
+        let handle = thread::spawn(move || while slave.handle_request().is_ok() {});
+
+        let features = VIRTIO_FEATURES & !VirtioFeatures::LOG_ALL.bits();
+        master.set_owner().unwrap();
+        master.get_features().unwrap();
+        master.set_features(features).unwrap();
+        master.get_protocol_features().unwrap();
+        master
+            .set_protocol_features(VhostUserProtocolFeatures::LOG_SHMFD)
+            .unwrap();
+        let file = vmm_sys_util::tempfile::TempFile::new().unwrap().into_file();
+        file.set_len(0x2000).unwrap();
+        let region = VhostUserMemoryRegionInfo {
+            guest_phys_addr: 0,
+            memory_size: 0x1000,
+            userspace_addr: 0x7f00_0000_0000,
+            mmap_offset: 0,
+            mmap_handle: file.as_raw_fd(),
+        };
+        master.set_mem_table(&[region]).unwrap();
+        master.set_vring_base(0, 5).unwrap();
+        let kick = vmm_sys_util::eventfd::EventFd::new(0).unwrap();
+        master.set_vring_kick(0, &kick).unwrap();
+        master.set_vring_enable(0, true).unwrap();
+
+        let mut controller = MigrationController::new(features, 1);
+        let log = VhostUserDirtyLogRegion {
+            mmap_size: 0x1000,
+            mmap_offset: 0x1000,
+            mmap_handle: file.as_raw_fd(),
+        };
+        controller.start_logging(&mut master, log).unwrap();
+        assert!(controller.is_logging());
+
+        // DEVICE_STATE hasn't been negotiated, so the vring is restarted.
+        assert!(controller.stop(&mut master, &[&kick], true).is_err());
+        master.get_features().unwrap();
+        {
+            let backend = slave_be.lock().unwrap();
+            assert!(backend.vring_started[0] && backend.vring_enabled[0]);
+            assert_ne!(backend.acked_features & VirtioFeatures::LOG_ALL.bits(), 0);
+        }
+
+        master
+            .set_protocol_features(
+                VhostUserProtocolFeatures::LOG_SHMFD | VhostUserProtocolFeatures::DEVICE_STATE,
+            )
+            .unwrap();
+        let state = controller.stop(&mut master, &[&kick], true).unwrap();
+        assert_eq!(
+            state,
+            MigrationState {
+                vring_bases: vec![5],
+                device_state: Some(b"saved state".to_vec()),
+            }
+        );
+        {
+            let backend = slave_be.lock().unwrap();
+            assert!(!backend.vring_started[0] && !backend.vring_enabled[0]);
+        }
+
+        // Cancel the migration.
+        controller.resume(&mut master, &[&kick]).unwrap();
+        controller.stop_logging(&mut master).unwrap();
+        master.get_features().unwrap();
+        {
+            let backend = slave_be.lock().unwrap();
+            assert!(backend.vring_started[0] && backend.vring_enabled[0]);
+            assert_eq!(backend.acked_features, features);
+        }
+
+        // The state is loaded while the vrings are stopped.
+        master.get_vring_base(0).unwrap();
+        MigrationController::load_device_state(&mut master, b"loaded state").unwrap();
+        drop(master);
+        handle.join().unwrap();
+        assert_eq!(slave_be.lock().unwrap().device_state, b"loaded state");
+    }
+
+    #[test]
+    fn test_vring_endian() {
+        let mbar = Arc::new(Barrier::new(2));
+        let sbar = mbar.clone();
+        let slave_be = Arc::new(Mutex::new(DummySlaveReqHandler::new()));
+        let (mut master, mut slave) = create_slave(
+            "/tmp/vhost_user_lib_unit_test_vring_endian",
+            slave_be.clone(),
+        );
+
+        thread::spawn(move || {
+            for _ in 0..6 {
+                slave.handle_request().unwrap();
+            }
+            assert_eq!(slave_be.lock().unwrap().vring_big_endian, [true, false]);
+            sbar.wait();
+        });
+
+        master.set_owner().unwrap();
+        master.get_features().unwrap();
+        master.set_features(VIRTIO_FEATURES).unwrap();
+        master.get_protocol_features().unwrap();
+        assert!(master.set_vring_endian(0, true).is_err());
+        master
+            .set_protocol_features(VhostUserProtocolFeatures::CROSS_ENDIAN)
+            .unwrap();
+
+        assert!(master.set_vring_endian(1, true).is_err());
+        master.set_vring_endian(0, true).unwrap();
+        mbar.wait();
+    }
+
+    #[test]
+    fn test_postcopy() {
+        let mbar = Arc::new(Barrier::new(2));
+        let sbar = mbar.clone();
+        let slave_be = Arc::new(Mutex::new(DummySlaveReqHandler::new()));
+        let (mut master, mut slave) =
+            create_slave("/tmp/vhost_user_lib_unit_test_postcopy", slave_be.clone());
+
+        thread::spawn(move || {
+            for _ in 0..5 {
+                slave.handle_request().unwrap();
+            }
+            // POSTCOPY_ADVISE fails if userfaultfd is unavailable.
+            slave.handle_request().unwrap();
+            if slave_be.lock().unwrap().uffd.is_some() {
+                slave.handle_request().unwrap();
+                assert!(slave_be.lock().unwrap().postcopy_listening);
+                slave.handle_request().unwrap();
+                assert!(!slave_be.lock().unwrap().postcopy_listening);
+                assert!(slave_be.lock().unwrap().uffd.is_none());
+            }
+            sbar.wait();
+        });
+
+        assert!(master.postcopy_advise().is_err());
+        assert!(master.postcopy_listen().is_err());
+        assert!(master.postcopy_end().is_err());
+
+        master.set_owner().unwrap();
+        master.get_features().unwrap();
+        master.set_features(VIRTIO_FEATURES).unwrap();
+        master.get_protocol_features().unwrap();
+        master
+            .set_protocol_features(VhostUserProtocolFeatures::PAGEFAULT)
+            .unwrap();
+
+        if let Ok(file) = master.postcopy_advise() {
+            assert!(file.as_raw_fd() >= 0);
+            master.postcopy_listen().unwrap();
+            master.postcopy_end().unwrap();
+        }
+
+        mbar.wait();
+    }
+
+    #[test]
+    fn test_dirty_log() {
+        let mbar = Arc::new(Barrier::new(2));
+        let sbar = mbar.clone();
+        let slave_be = Arc::new(Mutex::new(DummySlaveReqHandler::new()));
+        let (mut master, mut slave) =
+            create_slave("/tmp/vhost_user_lib_unit_test_dirty_log", slave_be.clone());
+
+        thread::spawn(move || {
+            for _ in 0..5 {
+                slave.handle_request().unwrap();
+            }
+            slave.handle_request().unwrap();
+            slave.handle_request().unwrap();
+            {
+                let backend = slave_be.lock().unwrap();
+                let log = backend.dirty_log.as_ref().unwrap();
+                assert_eq!(log.size(), 0x1000);
+                log.mark_dirty(0x3000, 0x10).unwrap();
+                assert!(backend.log_fd.is_some());
+            }
+            sbar.wait();
+        });
+
+        let file = vmm_sys_util::tempfile::TempFile::new().unwrap().into_file();
+        file.set_len(0x1000).unwrap();
+        let region = VhostUserDirtyLogRegion {
+            mmap_size: 0x1000,
+            mmap_offset: 0,
+            mmap_handle: file.as_raw_fd(),
+        };
+
+        master.set_owner().unwrap();
+        master.get_features().unwrap();
+        master.set_features(VIRTIO_FEATURES).unwrap();
+        master.get_protocol_features().unwrap();
+        master
+            .set_protocol_features(VhostUserProtocolFeatures::LOG_SHMFD)
+            .unwrap();
+
+        master.set_log_base(0, Some(region)).unwrap();
+        let eventfd = vmm_sys_util::eventfd::EventFd::new(0).unwrap();
+        master.set_log_fd(eventfd.as_raw_fd()).unwrap();
+
+        mbar.wait();
+        // The slave has logged page 3 through the shared memory region.
+        let mut byte = [0u8; 1];
+        std::os::unix::fs::FileExt::read_exact_at(&file, &mut byte, 0).unwrap();
+        assert_eq!(byte[0], 0x8);
+    }
+
+    #[test]
+    fn test_iotlb() {
+        let mbar = Arc::new(Barrier::new(2));
+        let sbar = mbar.clone();
+        let slave_be = Arc::new(Mutex::new(DummySlaveReqHandler::new()));
+        let (mut master, mut slave) =
+            create_slave("/tmp/vhost_user_lib_unit_test_iotlb", slave_be.clone());
+
+        thread::spawn(move || {
+            for _ in 0..8 {
+                slave.handle_request().unwrap();
+            }
+            let backend = slave_be.lock().unwrap();
+            assert_eq!(
+                backend
+                    .iotlb
+                    .translate(0x1800, 0x100, VhostUserIotlbAccess::RO),
+                None
+            );
+            sbar.wait();
+        });
+
+        let update = VhostUserIotlb::new(
+            0x1000,
+            0x1000,
+            0x7f00_0000_0000,
+            VhostUserIotlbAccess::RW,
+            VhostUserIotlbMsgType::Update,
+        );
+        master.set_owner().unwrap();
+        master.get_features().unwrap();
+        master
+            .set_features(VIRTIO_FEATURES & !VhostUserVirtioFeatures::IOMMU_PLATFORM.bits())
+            .unwrap();
+        assert!(master.send_iotlb_msg(&update).is_err());
+        master.reset_owner().unwrap();
+
+        master.set_owner().unwrap();
+        master.set_features(VIRTIO_FEATURES).unwrap();
+        let miss = VhostUserIotlb::new(
+            0x1000,
+            0,
+            0,
+            VhostUserIotlbAccess::RO,
+            VhostUserIotlbMsgType::Miss,
+        );
+        assert!(master.send_iotlb_msg(&miss).is_err());
+        master.send_iotlb_msg(&update).unwrap();
+        let invalidate = VhostUserIotlb::new(
+            0x1000,
+            0x1000,
+            0,
+            VhostUserIotlbAccess::NO_ACCESS,
+            VhostUserIotlbMsgType::Invalidate,
+        );
+        master.send_iotlb_msg(&invalidate).unwrap();
+        mbar.wait();
+    }
+
+    #[test]
+    fn test_mem_slots() {
+        let mbar = Arc::new(Barrier::new(2));
+        let sbar = mbar.clone();
+        let slave_be = Arc::new(Mutex::new(DummySlaveReqHandler::new()));
+        let (mut master, mut slave) =
+            create_slave("/tmp/vhost_user_lib_unit_test_mem_slots", slave_be.clone());
+
+        thread::spawn(move || {
+            for _ in 0..10 {
+                slave.handle_request().unwrap();
+            }
+            let backend = slave_be.lock().unwrap();
+            assert_eq!(backend.mem_regions.len(), 1);
+            assert_eq!({ backend.mem_regions[0].0.guest_phys_addr }, 0x10_0000);
+            sbar.wait();
+        });
+
+        let file = vmm_sys_util::tempfile::TempFile::new().unwrap().into_file();
+        file.set_len(0x2000).unwrap();
+        let region = |guest_phys_addr, mmap_offset| VhostUserMemoryRegionInfo {
+            guest_phys_addr,
+            memory_size: 0x1000,
+            userspace_addr: 0x7f00_0000_0000 + mmap_offset,
+            mmap_offset,
+            mmap_handle: file.as_raw_fd(),
+        };
+
+        master.set_owner().unwrap();
+        master.get_features().unwrap();
+        master.set_features(VIRTIO_FEATURES).unwrap();
+        master.get_protocol_features().unwrap();
+        assert!(master.get_max_mem_slots().is_err());
+        assert!(master.add_mem_region(&region(0, 0)).is_err());
+        master
+            .set_protocol_features(VhostUserProtocolFeatures::CONFIGURE_MEM_SLOTS)
+            .unwrap();
+
+        assert_eq!(master.get_max_mem_slots().unwrap(), MAX_MEM_SLOTS as u64);
+        master.add_mem_region(&region(0, 0)).unwrap();
+        master.add_mem_region(&region(0x10_0000, 0x1000)).unwrap();
+        master.remove_mem_region(&region(0, 0)).unwrap();
+        // Without REPLY_ACK, the master isn't told about failures to remove unknown regions.
+        master.remove_mem_region(&region(0x20_0000, 0)).unwrap();
+        mbar.wait();
+    }
+
+    #[test]
+    fn test_xen_mem_regions() {
+        let mbar = Arc::new(Barrier::new(2));
+        let sbar = mbar.clone();
+        let slave_be = Arc::new(Mutex::new(DummySlaveReqHandler::new()));
+        let (mut master, mut slave) =
+            create_slave("/tmp/vhost_user_lib_unit_test_xen_mmap", slave_be.clone());
+
+        thread::spawn(move || {
+            for _ in 0..6 {
+                slave.handle_request().unwrap();
+            }
+            {
+                let backend = slave_be.lock().unwrap();
+                assert_eq!(backend.xen_mmaps.len(), 1);
+                assert_eq!({ backend.xen_mmaps[0].flags }, 0x2);
+                assert_eq!({ backend.xen_mmaps[0].domid }, 1);
+            }
+            for _ in 0..2 {
+                slave.handle_request().unwrap();
+            }
+            {
+                let backend = slave_be.lock().unwrap();
+                assert_eq!(backend.xen_mmaps.len(), 2);
+                assert_eq!({ backend.xen_mmaps[1].flags }, 0x1);
+                assert!(backend.mem_regions.is_empty());
+            }
+            // Regular shared memory is sent with an empty Xen mapping.
+            slave.handle_request().unwrap();
+            let backend = slave_be.lock().unwrap();
+            assert_eq!(backend.xen_mmaps.len(), 1);
+            assert!(backend.xen_mmaps[0].is_shared_memory());
+            sbar.wait();
+        });
+
+        let file = vmm_sys_util::tempfile::TempFile::new().unwrap().into_file();
+        file.set_len(0x2000).unwrap();
+        let region = |guest_phys_addr, mmap_offset| VhostUserMemoryRegionInfo {
+            guest_phys_addr,
+            memory_size: 0x1000,
+            userspace_addr: 0x7f00_0000_0000 + mmap_offset,
+            mmap_offset,
+            mmap_handle: file.as_raw_fd(),
+        };
+        let grant = VhostUserXenMmap::new(VhostUserXenMmapFlags::GRANT, 1);
+        let foreign = VhostUserXenMmap::new(VhostUserXenMmapFlags::FOREIGN, 1);
+
+        master.set_owner().unwrap();
+        master.get_features().unwrap();
+        master.set_features(VIRTIO_FEATURES).unwrap();
+        master.get_protocol_features().unwrap();
+        assert!(master.set_mem_table_xen(&[region(0, 0)], &[grant]).is_err());
+        master
+            .set_protocol_features(
+                VhostUserProtocolFeatures::XEN_MMAP
+                    | VhostUserProtocolFeatures::CONFIGURE_MEM_SLOTS,
+            )
+            .unwrap();
+        // Regions and mappings must match, and mappings be valid.
+        assert!(master.set_mem_table_xen(&[region(0, 0)], &[]).is_err());
+        let invalid = VhostUserXenMmap::new(VhostUserXenMmapFlags::all(), 1);
+        assert!(master.add_mem_region_xen(&region(0, 0), invalid).is_err());
+
+        master.set_mem_table_xen(&[region(0, 0)], &[grant]).unwrap();
+        master
+            .add_mem_region_xen(&region(0x10_0000, 0x1000), foreign)
+            .unwrap();
+        master
+            .remove_mem_region(&region(0x10_0000, 0x1000))
+            .unwrap();
+        master.set_mem_table(&[region(0, 0)]).unwrap();
+        mbar.wait();
+    }
+
+    #[test]
+    fn test_mem_table_overflow() {
+        let mbar = Arc::new(Barrier::new(2));
+        let sbar = mbar.clone();
+        let slave_be = Arc::new(Mutex::new(DummySlaveReqHandler::new()));
+        let (mut master, mut slave) = create_slave(
+            "/tmp/vhost_user_lib_unit_test_mem_table_overflow",
+            slave_be.clone(),
+        );
+
+        thread::spawn(move || {
+            for _ in 0..16 {
+                slave.handle_request().unwrap();
+            }
+            // The regions beyond the capacity of SET_MEM_TABLE have been added.
+            let backend = slave_be.lock().unwrap();
+            assert_eq!(
+                backend.mem_regions.len(),
+                MAX_MEM_SLOTS - MAX_ATTACHED_FD_ENTRIES
+            );
+            sbar.wait();
+        });
+
+        let file = vmm_sys_util::tempfile::TempFile::new().unwrap().into_file();
+        file.set_len(0x1000).unwrap();
+        let regions: Vec<VhostUserMemoryRegionInfo> = (0..MAX_MEM_SLOTS as u64 + 1)
+            .map(|i| VhostUserMemoryRegionInfo {
+                guest_phys_addr: i * 0x10_0000,
+                memory_size: 0x1000,
+                userspace_addr: 0x7f00_0000_0000 + i * 0x10_0000,
+                mmap_offset: 0,
+                mmap_handle: file.as_raw_fd(),
+            })
+            .collect();
+        let overflowed = |max: usize| {
+            regions[max..]
+                .iter()
+                .map(|r| r.guest_phys_addr)
+                .collect::<Vec<u64>>()
+        };
+
+        master.set_owner().unwrap();
+        master.get_features().unwrap();
+        master.set_features(VIRTIO_FEATURES).unwrap();
+        master.get_protocol_features().unwrap();
+        match master.set_mem_table(&regions[..MAX_ATTACHED_FD_ENTRIES + 1]) {
+            Err(crate::Error::VhostUserProtocol(Error::TooManyMemRegions {
+                max,
+                overflowed: o,
+            })) => {
+                assert_eq!(max, MAX_ATTACHED_FD_ENTRIES);
+                assert_eq!(o, vec![regions[MAX_ATTACHED_FD_ENTRIES].guest_phys_addr]);
+            }
+            _ => panic!("SET_MEM_TABLE can't carry that many regions"),
+        }
+        master
+            .set_protocol_features(VhostUserProtocolFeatures::CONFIGURE_MEM_SLOTS)
+            .unwrap();
+
+        match master.set_mem_table(&regions) {
+            Err(crate::Error::VhostUserProtocol(Error::TooManyMemRegions {
+                max,
+                overflowed: o,
+            })) => {
+                assert_eq!(max, MAX_MEM_SLOTS);
+                assert_eq!(o, overflowed(MAX_MEM_SLOTS));
+            }
+            _ => panic!("the slave doesn't have that many memory slots"),
+        }
+        master.set_mem_table(&regions[..MAX_MEM_SLOTS]).unwrap();
+        mbar.wait();
+    }
+
+    struct DummyReconnectHandler {
+        reconnected: Arc<Mutex<usize>>,
+    }
+
+    impl VhostUserReconnectHandler for DummyReconnectHandler {
+        fn vring_base(&mut self, _queue_index: usize, last_base: u16) -> u16 {
+            last_base + 1
+        }
+
+        fn reconnected(&mut self, master: &mut Master) -> crate::Result<()> {
+            *self.reconnected.lock().unwrap() += 1;
+            master.set_vring_enable(0, true)
+        }
+    }
+
+    #[test]
+    fn test_reconnect() {
+        let path = "/tmp/vhost_user_lib_unit_test_reconnect";
+        let mbar = Arc::new(Barrier::new(2));
+        let sbar = mbar.clone();
+        let listener = Listener::new(path, true).unwrap();
+        let slave_be = Arc::new(Mutex::new(DummySlaveReqHandler::new()));
+        let be = slave_be.clone();
+
+        thread::spawn(move || {
+            let sock = listener.accept().unwrap().unwrap();
+            let mut slave =
+                SlaveReqHandler::new(Endpoint::<MasterReq>::from_stream(sock), be.clone());
+            for _ in 0..7 {
+                slave.handle_request().unwrap();
+            }
+            // Simulate a crash of the slave.
+            drop(slave);
+            *be.lock().unwrap() = DummySlaveReqHandler::new();
+            sbar.wait();
+
+            let sock = listener.accept().unwrap().unwrap();
+            let mut slave = SlaveReqHandler::new(Endpoint::<MasterReq>::from_stream(sock), be);
+            // Replayed requests, the retried request and the re-enabled vring.
+            for _ in 0..9 {
+                slave.handle_request().unwrap();
+            }
+            sbar.wait();
+        });
+
+        let reconnected = Arc::new(Mutex::new(0));
+        let handler = DummyReconnectHandler {
+            reconnected: reconnected.clone(),
+        };
+        let mut master = ReconnectingMaster::new(
+            MasterConnector::Client(path.to_string()),
+            1,
+            Box::new(handler),
+        )
+        .unwrap();
+        master.set_owner().unwrap();
+        master.get_features().unwrap();
+        master.set_features(VIRTIO_FEATURES).unwrap();
+        master.get_protocol_features().unwrap();
+        master
+            .set_protocol_features(VhostUserProtocolFeatures::MQ)
+            .unwrap();
+        master.set_vring_num(0, 128).unwrap();
+        master.set_vring_base(0, 2).unwrap();
+        mbar.wait();
+
+        // The broken connection is detected, and the request retried once reconnected.
+        let fd = vmm_sys_util::eventfd::EventFd::new(0).unwrap();
+        master.set_vring_call(0, &fd).unwrap();
+        mbar.wait();
+        assert_eq!(*reconnected.lock().unwrap(), 1);
+
+        let backend = slave_be.lock().unwrap();
+        assert!(backend.owned);
+        assert_eq!(backend.acked_features, VIRTIO_FEATURES);
+        assert_eq!(
+            backend.acked_protocol_features,
+            VhostUserProtocolFeatures::MQ.bits()
+        );
+        assert_eq!(backend.vring_num[0], 128);
+        assert_eq!(backend.vring_base[0], 3);
+        assert!(backend.call_fd[0].is_some());
+        assert!(backend.vring_enabled[0]);
+    }
+
+    #[test]
+    fn test_master_listener() {
+        let path = "/tmp/vhost_user_lib_unit_test_master_listener";
+        let listener = Listener::new(path, true).unwrap();
+        listener.set_nonblocking(true).unwrap();
+        let master_listener = MasterListener::new(listener, 1);
+        assert!(master_listener.accept().unwrap().is_none());
+
+        let slave_be = Arc::new(Mutex::new(DummySlaveReqHandler::new()));
+        let slave = SlaveReqHandler::connect(path, slave_be.clone()).unwrap();
+        let mut master = master_listener.accept().unwrap().unwrap();
+        let slave = loopback::SlaveThread::spawn(slave);
+
+        master.set_owner().unwrap();
+        assert_eq!(master.get_features().unwrap(), VIRTIO_FEATURES);
+        assert!(slave_be.lock().unwrap().owned);
+        drop(master);
+        slave.join().unwrap();
+    }
+
+    #[test]
+    fn test_reconnect_server() {
+        let path = "/tmp/vhost_user_lib_unit_test_reconnect_server";
+        let listener = Listener::new(path, true).unwrap();
+        listener.set_nonblocking(true).unwrap();
+        let slave_be = Arc::new(Mutex::new(DummySlaveReqHandler::new()));
+        let slave = SlaveReqHandler::connect(path, slave_be.clone()).unwrap();
+
+        let reconnected = Arc::new(Mutex::new(0));
+        let handler = DummyReconnectHandler {
+            reconnected: reconnected.clone(),
+        };
+        let mut master =
+            ReconnectingMaster::new(MasterConnector::Server(listener), 1, Box::new(handler))
+                .unwrap();
+        let slave = loopback::SlaveThread::spawn(slave);
+        master.set_owner().unwrap();
+        master.get_features().unwrap();
+        master.set_features(VIRTIO_FEATURES).unwrap();
+        master.get_protocol_features().unwrap();
+        master
+            .set_protocol_features(VhostUserProtocolFeatures::MQ)
+            .unwrap();
+        assert!(!master.accept().unwrap());
+
+        // The restarted slave connects again, and gets the device state replayed on accept.
+        let slave_be = Arc::new(Mutex::new(DummySlaveReqHandler::new()));
+        let new_slave = SlaveReqHandler::connect(path, slave_be.clone()).unwrap();
+        let new_slave = loopback::SlaveThread::spawn(new_slave);
+        assert!(master.accept().unwrap());
+        assert_eq!(*reconnected.lock().unwrap(), 1);
+        slave.join().unwrap();
+        drop(master);
+        new_slave.join().unwrap();
+
+        let backend = slave_be.lock().unwrap();
+        assert!(backend.owned);
+        assert_eq!(backend.acked_features, VIRTIO_FEATURES);
+        assert_eq!(
+            backend.acked_protocol_features,
+            VhostUserProtocolFeatures::MQ.bits()
+        );
+        assert!(backend.vring_enabled[0]);
+    }
+
+    #[derive(Default)]
+    struct DummyMasterReqHandler {
+        config_changed: bool,
+        vring_called: Option<u32>,
+        iotlb_miss: Option<u64>,
+        host_notifiers: Vec<(u8, u64, u64, bool)>,
+        shared_objects: Vec<[u8; 16]>,
+    }
+
+    impl VhostUserMasterReqHandler for DummyMasterReqHandler {
+        fn handle_iotlb_msg(&mut self, iotlb: &VhostUserIotlb) -> HandlerResult<u64> {
+            self.iotlb_miss = Some(iotlb.iova);
+            Ok(0)
+        }
+
+        fn handle_config_change(&mut self) -> HandlerResult<u64> {
+            self.config_changed = true;
+            Ok(0)
+        }
+
+        fn handle_vring_call(&mut self, vring: &VhostUserVringState) -> HandlerResult<u64> {
+            self.vring_called = Some(vring.index);
+            Ok(0)
+        }
+
+        fn handle_vring_host_notifier(
+            &mut self,
+            area: &VhostUserVringArea,
+            fd: Option<File>,
+        ) -> HandlerResult<u64> {
+            self.host_notifiers
+                .push((area.vring_index(), area.offset, area.size, fd.is_some()));
+            Ok(0)
+        }
+
+        fn shared_object_add(&mut self, shared: &VhostUserShared) -> HandlerResult<u64> {
+            self.shared_objects.push(shared.uuid);
+            Ok(0)
+        }
+
+        fn shared_object_remove(&mut self, shared: &VhostUserShared) -> HandlerResult<u64> {
+            let uuid = shared.uuid;
+            self.shared_objects.retain(|u| *u != uuid);
+            Ok(0)
+        }
+
+        fn shared_object_lookup(&mut self, shared: &VhostUserShared) -> HandlerResult<File> {
+            let uuid = shared.uuid;
+            if !self.shared_objects.contains(&uuid) {
+                return Err(IOError::from_raw_os_error(libc::ENOENT));
+            }
+            Ok(vmm_sys_util::tempfile::TempFile::new()?.into_file())
+        }
+    }
+
+    #[test]
+    fn test_slave_req_channel() {
+        let mbar = Arc::new(Barrier::new(2));
+        let sbar = mbar.clone();
+        let slave_be = Arc::new(Mutex::new(DummySlaveReqHandler::new()));
+        let (mut master, mut slave) =
+            create_slave("/tmp/vhost_user_lib_unit_test_slave_req", slave_be.clone());
+        let master_be = Arc::new(Mutex::new(DummyMasterReqHandler::default()));
+        let mut master_handler = MasterReqHandler::new(master_be.clone()).unwrap();
+
+        thread::spawn(move || {
+            for _ in 0..6 {
+                slave.handle_request().unwrap();
+            }
+            let mut sender = slave_be.lock().unwrap().slave_req.take().unwrap();
+            sender.send_config_change().unwrap();
+            sender.send_vring_call(1).unwrap();
+            let iotlb = VhostUserIotlb::new(
+                0x1000,
+                0,
+                0,
+                VhostUserIotlbAccess::RO,
+                VhostUserIotlbMsgType::Miss,
+            );
+            sender.send_iotlb_msg(&iotlb).unwrap();
+            let file = vmm_sys_util::tempfile::TempFile::new().unwrap().into_file();
+            assert!(sender
+                .set_vring_host_notifier(0, file.as_raw_fd(), 0, 0)
+                .is_err());
+            sender
+                .set_vring_host_notifier(0, file.as_raw_fd(), 0x1000, 0x1000)
+                .unwrap();
+            sender.remove_vring_host_notifier(0).unwrap();
+            sbar.wait();
+        });
+
+        assert!(master
+            .set_slave_request_fd(master_handler.get_tx_raw_fd())
+            .is_err());
+
+        master.set_owner().unwrap();
+        master.get_features().unwrap();
+        master.set_features(VIRTIO_FEATURES).unwrap();
+        master.get_protocol_features().unwrap();
+        master
+            .set_protocol_features(
+                VhostUserProtocolFeatures::SLAVE_REQ
+                    | VhostUserProtocolFeatures::CONFIG
+                    | VhostUserProtocolFeatures::INBAND_NOTIFICATIONS
+                    | VhostUserProtocolFeatures::SLAVE_SEND_FD
+                    | VhostUserProtocolFeatures::HOST_NOTIFIER,
+            )
+            .unwrap();
+        master
+            .set_slave_request_fd(master_handler.get_tx_raw_fd())
+            .unwrap();
+
+        for _ in 0..5 {
+            assert_eq!(master_handler.handle_request().unwrap(), 0);
+        }
+        mbar.wait();
+
+        let backend = master_be.lock().unwrap();
+        assert!(backend.config_changed);
+        assert_eq!(backend.vring_called, Some(1));
+        assert_eq!(backend.iotlb_miss, Some(0x1000));
+        assert_eq!(
+            backend.host_notifiers,
+            vec![(0, 0x1000, 0x1000, true), (0, 0, 0, false)]
+        );
+    }
+
+    #[test]
+    fn test_gpu_socket() {
+        struct Display {
+            cursor: Option<(u32, u32)>,
+        }
+        impl gpu::VhostUserGpuReqHandler for Display {
+            fn cursor_pos(&mut self, pos: &gpu::VhostUserGpuCursorPos) -> HandlerResult<()> {
+                self.cursor = Some((pos.x, pos.y));
+                Ok(())
+            }
+        }
+
+        let slave_be = Arc::new(Mutex::new(DummySlaveReqHandler::new()));
+        let (mut master, slave) = loopback::spawn_loopback(slave_be.clone(), 1).unwrap();
+        let display = Arc::new(Mutex::new(Display { cursor: None }));
+        let mut gpu_handler = gpu::GpuReqHandler::new(display.clone()).unwrap();
+
+        master.set_owner().unwrap();
+        master.set_gpu_socket(gpu_handler.get_tx_raw_fd()).unwrap();
+        // Get a reply to make sure GPU_SET_SOCKET has been handled.
+        master.get_features().unwrap();
+
+        let sender = slave_be.lock().unwrap().gpu.take().unwrap();
+        let pos = gpu::VhostUserGpuCursorPos {
+            scanout_id: 0,
+            x: 1,
+            y: 2,
+        };
+        sender.cursor_pos(&pos).unwrap();
+        gpu_handler.handle_request().unwrap();
+        assert_eq!(display.lock().unwrap().cursor, Some((1, 2)));
+
+        drop(master);
+        slave.join().unwrap();
+    }
+
+    #[test]
+    fn test_shared_object() {
+        let mbar = Arc::new(Barrier::new(2));
+        let sbar = mbar.clone();
+        let slave_be = Arc::new(Mutex::new(DummySlaveReqHandler::new()));
+        let file = vmm_sys_util::tempfile::TempFile::new().unwrap().into_file();
+        slave_be
+            .lock()
+            .unwrap()
+            .shared_objects
+            .push(([1; 16], file));
+        let (mut master, mut slave) = create_slave(
+            "/tmp/vhost_user_lib_unit_test_shared_object",
+            slave_be.clone(),
+        );
+        let master_be = Arc::new(Mutex::new(DummyMasterReqHandler::default()));
+        let mut master_handler = MasterReqHandler::new(master_be.clone()).unwrap();
+
+        thread::spawn(move || {
+            for _ in 0..8 {
+                slave.handle_request().unwrap();
+            }
+            let mut sender = slave_be.lock().unwrap().slave_req.take().unwrap();
+            let shared = VhostUserShared::new([2; 16]);
+            sender.shared_object_add(&shared).unwrap();
+            sender.shared_object_lookup(&shared).unwrap();
+            assert!(sender
+                .shared_object_lookup(&VhostUserShared::new([3; 16]))
+                .is_err());
+            sender.shared_object_remove(&shared).unwrap();
+            sbar.wait();
+        });
+
+        master.set_owner().unwrap();
+        master.get_features().unwrap();
+        master.set_features(VIRTIO_FEATURES).unwrap();
+        master.get_protocol_features().unwrap();
+        assert!(master
+            .get_shared_object(&VhostUserShared::new([1; 16]))
+            .is_err());
+        master
+            .set_protocol_features(
+                VhostUserProtocolFeatures::SLAVE_REQ | VhostUserProtocolFeatures::SHARED_OBJECT,
+            )
+            .unwrap();
+        master
+            .set_slave_request_fd(master_handler.get_tx_raw_fd())
+            .unwrap();
+
+        master
+            .get_shared_object(&VhostUserShared::new([1; 16]))
+            .unwrap();
+        assert!(master
+            .get_shared_object(&VhostUserShared::new([2; 16]))
+            .is_err());
+
+        assert_eq!(master_handler.handle_request().unwrap(), 0);
+        assert_eq!(master_handler.handle_request().unwrap(), 0);
+        assert!(master_handler.handle_request().is_err());
+        assert_eq!(master_handler.handle_request().unwrap(), 0);
+        mbar.wait();
+        assert!(master_be.lock().unwrap().shared_objects.is_empty());
+    }
+
+    #[test]
+    fn test_config_change_notifier() {
+        let slave_be = Arc::new(Mutex::new(DummySlaveReqHandler::new()));
+        let (mut master, mut slave) = create_slave(
+            "/tmp/vhost_user_lib_unit_test_config_change",
+            slave_be.clone(),
+        );
+        let event = vmm_sys_util::eventfd::EventFd::new(0).unwrap();
+        let notifier = ConfigChangeNotifier::new(event.try_clone().unwrap());
+        let master_be = Arc::new(Mutex::new(notifier));
+        let mut master_handler = MasterReqHandler::new(master_be).unwrap();
+
+        let slave_thread = thread::spawn(move || {
+            for _ in 0..6 {
+                slave.handle_request().unwrap();
+            }
+            let mut sender = slave_be.lock().unwrap().slave_req.take().unwrap();
+            // The master acks the notification, as REPLY_ACK has been negotiated.
+            sender.send_config_change().unwrap();
+        });
+
+        master.set_owner().unwrap();
+        master.get_features().unwrap();
+        master.set_features(VIRTIO_FEATURES).unwrap();
+        master.get_protocol_features().unwrap();
+        master
+            .set_protocol_features(
+                VhostUserProtocolFeatures::SLAVE_REQ
+                    | VhostUserProtocolFeatures::CONFIG
+                    | VhostUserProtocolFeatures::REPLY_ACK,
+            )
+            .unwrap();
+        master
+            .set_slave_request_fd(master_handler.get_tx_raw_fd())
+            .unwrap();
+
+        assert_eq!(master_handler.handle_request().unwrap(), 0);
+        slave_thread.join().unwrap();
+        assert_eq!(event.read().unwrap(), 1);
+    }
+
+    #[test]
+    fn test_master_req_thread() {
+        let (calls_tx, calls) = std::sync::mpsc::channel();
+        let mut dispatcher = MasterReqDispatcher::new();
+        let tx = calls_tx.clone();
+        dispatcher.set_config_change_handler(move || {
+            tx.send(0).unwrap();
+            Ok(0)
+        });
+        let tx = calls_tx.clone();
+        dispatcher.set_iotlb_handler(move |msg| {
+            tx.send(msg.iova).unwrap();
+            Ok(0)
+        });
+        dispatcher.set_vring_err_handler(move |vring| {
+            calls_tx.send(u64::from(vring.index)).unwrap();
+            Ok(0)
+        });
+        let master_handler = MasterReqHandler::new(Arc::new(Mutex::new(dispatcher))).unwrap();
+        // Safe because we check the return value, and the stream takes the ownership of the new
+        // file descriptor.
+        let sock = unsafe {
+            let fd = libc::dup(master_handler.get_tx_raw_fd());
+            assert!(fd >= 0);
+            std::os::unix::net::UnixStream::from_raw_fd(fd)
+        };
+        let mut sender = MasterReqSender::from_stream(sock);
+        sender.set_protocol_features(
+            (VhostUserProtocolFeatures::CONFIG
+                | VhostUserProtocolFeatures::INBAND_NOTIFICATIONS
+                | VhostUserProtocolFeatures::REPLY_ACK)
+                .bits(),
+        );
+        let thread = master_handler
+            .spawn("vhost-user-test-slave-req".to_string())
+            .unwrap();
+
+        assert_eq!(sender.send_config_change().unwrap(), 0);
+        assert_eq!(calls.recv().unwrap(), 0);
+        let miss = iotlb::IotlbCache::miss_msg(0x1000, VhostUserIotlbAccess::RO);
+        assert_eq!(sender.send_iotlb_msg(&miss).unwrap(), 0);
+        assert_eq!(calls.recv().unwrap(), 0x1000);
+        // Requests without a handler are rejected, without stopping the thread.
+        assert!(sender.send_vring_call(1).is_err());
+        assert_eq!(sender.send_vring_err(3).unwrap(), 0);
+        assert_eq!(calls.recv().unwrap(), 3);
+        assert!(!thread.is_finished());
+        thread.stop().unwrap();
+
+        // The thread exits on its own once the slave closes the channel.
+        let master_handler =
+            MasterReqHandler::new(Arc::new(Mutex::new(MasterReqDispatcher::new()))).unwrap();
+        // Safe for the same reasons as above.
+        let sock = unsafe {
+            let fd = libc::dup(master_handler.get_tx_raw_fd());
+            assert!(fd >= 0);
+            std::os::unix::net::UnixStream::from_raw_fd(fd)
+        };
+        let thread = master_handler
+            .spawn("vhost-user-test-slave-req".to_string())
+            .unwrap();
+        drop(sock);
+        while !thread.is_finished() {
+            thread::sleep(std::time::Duration::from_millis(1));
+        }
+        assert!(thread.stop().is_err());
+    }
+
+    #[test]
+    fn test_config() {
+        let mbar = Arc::new(Barrier::new(2));
+        let sbar = mbar.clone();
+        let slave_be = Arc::new(Mutex::new(DummySlaveReqHandler::new()));
+        let (mut master, mut slave) =
+            create_slave("/tmp/vhost_user_lib_unit_test_config", slave_be.clone());
+
+        thread::spawn(move || {
+            for _ in 0..7 {
+                slave.handle_request().unwrap();
+            }
+            sbar.wait();
+        });
+
+        let buf = [0u8; 4];
+        assert!(master
+            .get_config(
+                VHOST_USER_CONFIG_OFFSET,
+                4,
+                VhostUserConfigFlags::WRITABLE,
+                &buf
+            )
+            .is_err());
+
+        master.set_owner().unwrap();
+        master.get_features().unwrap();
+        master.set_features(VIRTIO_FEATURES).unwrap();
+        master.get_protocol_features().unwrap();
+        master
+            .set_protocol_features(VhostUserProtocolFeatures::CONFIG)
+            .unwrap();
+
+        assert!(master
+            .get_config(0, 4, VhostUserConfigFlags::WRITABLE, &buf)
+            .is_err());
+        assert!(master
+            .set_config(VHOST_USER_CONFIG_SIZE, VhostUserConfigFlags::WRITABLE, &buf)
+            .is_err());
+
+        let (reply, payload) = master
+            .get_config(
+                VHOST_USER_CONFIG_OFFSET,
+                4,
+                VhostUserConfigFlags::WRITABLE,
+                &buf,
+            )
+            .unwrap();
+        assert_eq!({ reply.offset }, VHOST_USER_CONFIG_OFFSET);
+        assert_eq!({ reply.size }, 4);
+        assert_eq!(payload, vec![0xa5; 4]);
+        master
+            .set_config(
+                VHOST_USER_CONFIG_OFFSET,
+                VhostUserConfigFlags::WRITABLE,
+                &[1, 2, 3, 4],
+            )
+            .unwrap();
+
+        mbar.wait();
+    }
 }
@@ -0,0 +1,651 @@
+// Copyright (C) 2020 Alibaba Cloud Computing. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! A vhost-user-net slave backend built on the daemon framework.
+//!
+//! Each queue pair of the device, made of a receive and a transmit virtqueue, forwards packets
+//! between the guest and a `NetTap`, which is either a queue of a TAP interface or a raw packet
+//! socket. Queue pairs are served by dedicated worker threads, which also wait for incoming
+//! packets on the TAP queues: backends must be registered to the worker threads with
+//! `NetBackend::register_taps()` before the daemon is started.
+//!
+//! The MAC address, the link status, the number of queue pairs and the MTU are exposed through
+//! the device configuration space. Offloads and mergeable receive buffers aren't supported.
+
+use std::cmp;
+use std::ffi::CString;
+use std::fs::File;
+use std::io;
+use std::os::raw::{c_int, c_short};
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use vm_memory::{Bytes, GuestMemoryMmap};
+use vmm_sys_util::epoll::EventSet;
+use vmm_sys_util::ioctl::{ioctl_with_mut_ref, ioctl_with_ref};
+
+use super::daemon::{
+    read_config_space, DescriptorChain, VhostUserBackend, Vring, VringEpollHandler,
+};
+use super::message::VhostUserProtocolFeatures;
+use crate::features::{DeviceFeatures, VirtioFeatures, VirtioNetFeatures};
+
+/// The device reports its maximum MTU in the configuration space.
+pub const VIRTIO_NET_F_MTU: u64 = 3;
+/// The device reports its MAC address in the configuration space.
+pub const VIRTIO_NET_F_MAC: u64 = 5;
+/// The device reports its link status in the configuration space.
+pub const VIRTIO_NET_F_STATUS: u64 = 16;
+/// The device supports multiple queue pairs.
+pub const VIRTIO_NET_F_MQ: u64 = 22;
+/// The device is compliant with the virtio 1.0 specification.
+pub const VIRTIO_F_VERSION_1: u64 = 32;
+
+/// Link is up, in the status field of the configuration space.
+pub const VIRTIO_NET_S_LINK_UP: u16 = 1;
+
+/// Size of the virtio-net header prepended to all packets, struct virtio_net_hdr_v1.
+pub const VIRTIO_NET_HDR_SIZE: usize = 12;
+
+// Size of the virtio-net configuration space: mac, status, max_virtqueue_pairs and mtu.
+const VIRTIO_NET_CONFIG_SIZE: usize = 12;
+// Largest packet, including the virtio-net header.
+const MAX_PACKET_SIZE: usize = VIRTIO_NET_HDR_SIZE + 65550;
+// Size of the virtqueues.
+const QUEUE_SIZE: usize = 256;
+// Smallest MTU of Ethernet devices.
+const MIN_MTU: u16 = 68;
+
+// Ethernet protocols and operation of the RARP packets announcing the guest.
+const ETH_P_IP: u16 = 0x0800;
+const ETH_P_RARP: u16 = 0x8035;
+const RARP_OP_REQUEST_REV: u16 = 3;
+
+// TUN/TAP interface flags and ioctls.
+const IFF_TAP: c_short = 0x0002;
+const IFF_NO_PI: c_short = 0x1000;
+const IFF_MULTI_QUEUE: c_short = 0x0100;
+const IFF_VNET_HDR: c_short = 0x4000;
+mod tun_ioctls {
+    use std::os::raw::{c_int, c_uint};
+
+    const TUNTAP: c_uint = 0x54;
+    ioctl_iow_nr!(TUNSETIFF, TUNTAP, 202, c_int);
+    ioctl_iow_nr!(TUNSETVNETHDRSZ, TUNTAP, 216, c_int);
+}
+use self::tun_ioctls::{TUNSETIFF, TUNSETVNETHDRSZ};
+
+// Same layout as struct ifreq, for the flags member of the union.
+#[repr(C)]
+struct IfReq {
+    ifr_name: [u8; libc::IFNAMSIZ],
+    ifr_flags: c_short,
+    _padding: [u8; 22],
+}
+
+/// Packet interface a queue pair of the device forwards packets to and from.
+pub struct NetTap {
+    file: File,
+    vnet_hdr: bool,
+}
+
+impl NetTap {
+    /// Open a queue of the TAP interface `name`, creating the interface if it doesn't exist.
+    ///
+    /// Devices with several queue pairs need a multi-queue TAP interface, with one queue opened
+    /// for each queue pair.
+    pub fn open(name: &str, multi_queue: bool) -> io::Result<Self> {
+        if name.len() >= libc::IFNAMSIZ {
+            return Err(io::Error::from_raw_os_error(libc::EINVAL));
+        }
+        let path = CString::new("/dev/net/tun").unwrap();
+        // Safe because the path is a valid C string and we check the return value.
+        let fd = unsafe {
+            libc::open(
+                path.as_ptr(),
+                libc::O_RDWR | libc::O_NONBLOCK | libc::O_CLOEXEC,
+            )
+        };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // Safe because we have just opened the file descriptor.
+        let file = unsafe { File::from_raw_fd(fd) };
+
+        let mut ifreq = IfReq {
+            ifr_name: [0u8; libc::IFNAMSIZ],
+            ifr_flags: IFF_TAP | IFF_NO_PI | IFF_VNET_HDR,
+            _padding: [0u8; 22],
+        };
+        ifreq.ifr_name[..name.len()].copy_from_slice(name.as_bytes());
+        if multi_queue {
+            ifreq.ifr_flags |= IFF_MULTI_QUEUE;
+        }
+        // These ioctls are called on a valid TAP fd and have their return value checked.
+        if unsafe { ioctl_with_mut_ref(&file, TUNSETIFF(), &mut ifreq) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let hdr_size = VIRTIO_NET_HDR_SIZE as c_int;
+        if unsafe { ioctl_with_ref(&file, TUNSETVNETHDRSZ(), &hdr_size) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(NetTap {
+            file,
+            vnet_hdr: true,
+        })
+    }
+
+    /// Use a raw packet socket, or any file descriptor transferring one packet per read and
+    /// write, as packet interface.
+    ///
+    /// Packets are exchanged without virtio-net header, and the file descriptor is switched to
+    /// non-blocking mode.
+    pub fn from_file(file: File) -> io::Result<Self> {
+        // Safe because the file descriptor is valid and we check the return values.
+        let flags = unsafe { libc::fcntl(file.as_raw_fd(), libc::F_GETFL) };
+        if flags < 0
+            || unsafe { libc::fcntl(file.as_raw_fd(), libc::F_SETFL, flags | libc::O_NONBLOCK) } < 0
+        {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(NetTap {
+            file,
+            vnet_hdr: false,
+        })
+    }
+
+    // Receive a packet into `buf`, prefixed with the virtio-net header.
+    fn recv(&self, buf: &mut [u8]) -> io::Result<usize> {
+        let start = if self.vnet_hdr {
+            0
+        } else {
+            for byte in buf[..VIRTIO_NET_HDR_SIZE].iter_mut() {
+                *byte = 0;
+            }
+            VIRTIO_NET_HDR_SIZE
+        };
+        let data = &mut buf[start..];
+        // Safe because the buffer is valid for its whole length and we check the return value.
+        let ret = unsafe {
+            libc::read(
+                self.file.as_raw_fd(),
+                data.as_mut_ptr() as *mut libc::c_void,
+                data.len(),
+            )
+        };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // Each packet goes into a single chain of buffers, as VIRTIO_NET_F_MRG_RXBUF isn't
+        // supported. The TAP driver doesn't fill the num_buffers field.
+        buf[10..VIRTIO_NET_HDR_SIZE].copy_from_slice(&1u16.to_le_bytes());
+        Ok(start + ret as usize)
+    }
+
+    // Send a packet prefixed with the virtio-net header.
+    fn send(&self, buf: &[u8]) -> io::Result<()> {
+        let data = if self.vnet_hdr {
+            buf
+        } else {
+            &buf[VIRTIO_NET_HDR_SIZE..]
+        };
+        // Safe because the buffer is valid for its whole length and we check the return value.
+        let ret = unsafe {
+            libc::write(
+                self.file.as_raw_fd(),
+                data.as_ptr() as *const libc::c_void,
+                data.len(),
+            )
+        };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+}
+
+impl AsRawFd for NetTap {
+    fn as_raw_fd(&self) -> RawFd {
+        self.file.as_raw_fd()
+    }
+}
+
+/// A vhost-user-net device backend.
+///
+/// Virtqueues `2 * n` and `2 * n + 1` are the receive and transmit virtqueues of the queue pair
+/// `n`, forwarding packets to and from the n-th `NetTap`.
+pub struct NetBackend {
+    taps: Vec<NetTap>,
+    mac: [u8; 6],
+    mtu: u16,
+    mem: Option<GuestMemoryMmap>,
+    busy_poll: Option<Duration>,
+}
+
+impl NetBackend {
+    /// Create a backend with one queue pair for each of the packet interfaces.
+    pub fn new(taps: Vec<NetTap>, mac: [u8; 6], mtu: u16) -> io::Result<Self> {
+        if taps.is_empty() || taps.len() > 32 {
+            return Err(io::Error::from_raw_os_error(libc::EINVAL));
+        }
+        Ok(NetBackend {
+            taps,
+            mac,
+            mtu,
+            mem: None,
+            busy_poll: None,
+        })
+    }
+
+    /// Keep polling kicked virtqueues for new buffers for `duration`, see
+    /// `VhostUserBackend::busy_poll_duration()`. Must be set before creating the daemon.
+    pub fn set_busy_poll_duration(&mut self, duration: Option<Duration>) {
+        self.busy_poll = duration;
+    }
+
+    /// Get the number of queue pairs of the device.
+    pub fn queue_pairs(&self) -> usize {
+        self.taps.len()
+    }
+
+    /// Register the packet interfaces to the worker threads of the daemon, as returned by
+    /// `Daemon::get_vring_workers()`, to process incoming packets.
+    pub fn register_taps(&self, workers: &[Arc<VringEpollHandler<NetBackend>>]) -> io::Result<()> {
+        if workers.len() != self.taps.len() {
+            return Err(io::Error::from_raw_os_error(libc::EINVAL));
+        }
+        for (pair, tap) in self.taps.iter().enumerate() {
+            // Incoming packets are left in the interface when the guest runs out of receive
+            // buffers, so wait for new packets only and retry when buffers are made available.
+            workers[pair].register_listener(
+                tap.as_raw_fd(),
+                EventSet::IN | EventSet::EDGE_TRIGGERED,
+                self.tap_event(pair),
+            )?;
+        }
+        Ok(())
+    }
+
+    fn tap_event(&self, pair: usize) -> u64 {
+        // Data num_queues is reserved for the exit event of the worker threads.
+        (self.num_queues() + 1 + pair) as u64
+    }
+
+    fn config_space(&self) -> [u8; VIRTIO_NET_CONFIG_SIZE] {
+        let mut config = [0u8; VIRTIO_NET_CONFIG_SIZE];
+        config[0..6].copy_from_slice(&self.mac);
+        config[6..8].copy_from_slice(&VIRTIO_NET_S_LINK_UP.to_le_bytes());
+        config[8..10].copy_from_slice(&(self.taps.len() as u16).to_le_bytes());
+        config[10..12].copy_from_slice(&self.mtu.to_le_bytes());
+        config
+    }
+
+    fn process_rx(&self, mem: &GuestMemoryMmap, tap: &NetTap, vring: &mut Vring) -> io::Result<()> {
+        let mut buf = vec![0u8; MAX_PACKET_SIZE];
+        let mut used = vring.used_writer(mem);
+        while let Some(chain) = used.pop_avail()? {
+            let len = match tap.recv(&mut buf) {
+                Ok(len) => len,
+                Err(e) => {
+                    // Give the buffers back for the next packet.
+                    used.vring().return_avail(&chain);
+                    if e.kind() == io::ErrorKind::WouldBlock {
+                        break;
+                    }
+                    return Err(e);
+                }
+            };
+            let written = Self::write_chain(mem, &chain, &buf[..len])?;
+            used.add_used(chain.head_index(), written as u32)?;
+        }
+        used.finish()
+    }
+
+    fn process_tx(&self, mem: &GuestMemoryMmap, tap: &NetTap, vring: &mut Vring) -> io::Result<()> {
+        let mut used = vring.used_writer(mem);
+        while let Some(chain) = used.pop_avail()? {
+            // Malformed packets are dropped, their chain being completed all the same so the
+            // guest gets its buffers back. So are packets the interface can't take.
+            if let Some(buf) = Self::read_tx_packet(mem, &chain) {
+                match tap.send(&buf) {
+                    Ok(()) => {}
+                    Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                    Err(e) => return Err(e),
+                }
+            }
+            used.add_used(chain.head_index(), 0)?;
+        }
+        used.finish()
+    }
+
+    // Read the packet of a tx chain, unless it's truncated, oversized or out of the guest memory.
+    fn read_tx_packet(mem: &GuestMemoryMmap, chain: &DescriptorChain) -> Option<Vec<u8>> {
+        let mut buf = Vec::new();
+        for desc in chain.readable() {
+            if buf.len() + desc.len() as usize > MAX_PACKET_SIZE {
+                return None;
+            }
+            let start = buf.len();
+            buf.resize(start + desc.len() as usize, 0);
+            mem.read_slice(&mut buf[start..], desc.addr()).ok()?;
+        }
+        if buf.len() < VIRTIO_NET_HDR_SIZE {
+            return None;
+        }
+        Some(buf)
+    }
+
+    // Write a received packet into the writable buffers of the chain. Packets too large for the
+    // buffers are truncated.
+    fn write_chain(
+        mem: &GuestMemoryMmap,
+        chain: &DescriptorChain,
+        buf: &[u8],
+    ) -> io::Result<usize> {
+        let mut written = 0;
+        for desc in chain.writable() {
+            if written == buf.len() {
+                break;
+            }
+            let len = cmp::min(desc.len() as usize, buf.len() - written);
+            mem.write_slice(&buf[written..written + len], desc.addr())
+                .map_err(|_| io::Error::from_raw_os_error(libc::EFAULT))?;
+            written += len;
+        }
+        Ok(written)
+    }
+}
+
+impl VhostUserBackend for NetBackend {
+    fn num_queues(&self) -> usize {
+        self.taps.len() * 2
+    }
+
+    fn max_queue_size(&self) -> usize {
+        QUEUE_SIZE
+    }
+
+    fn features(&self) -> u64 {
+        let features = VirtioNetFeatures::MTU
+            | VirtioNetFeatures::MAC
+            | VirtioNetFeatures::STATUS
+            | VirtioNetFeatures::MQ;
+        features.with_common(VirtioFeatures::VERSION_1 | VirtioFeatures::PROTOCOL_FEATURES)
+    }
+
+    fn protocol_features(&self) -> VhostUserProtocolFeatures {
+        VhostUserProtocolFeatures::MQ
+            | VhostUserProtocolFeatures::CONFIG
+            | VhostUserProtocolFeatures::RARP
+            | VhostUserProtocolFeatures::MTU
+    }
+
+    fn update_memory(&mut self, mem: GuestMemoryMmap) -> io::Result<()> {
+        self.mem = Some(mem);
+        Ok(())
+    }
+
+    fn get_config(&self, offset: u32, size: u32) -> Vec<u8> {
+        read_config_space(&self.config_space(), offset, size)
+    }
+
+    fn set_config(&mut self, _offset: u32, _buf: &[u8]) -> io::Result<()> {
+        // All fields of the configuration space are read only.
+        Err(io::Error::from_raw_os_error(libc::EPERM))
+    }
+
+    fn send_rarp(&mut self, mac: [u8; 6]) -> io::Result<()> {
+        // A reverse request from the guest, as QEMU announces guests after migration, padded to
+        // the minimal Ethernet frame size.
+        let mut buf = [0u8; VIRTIO_NET_HDR_SIZE + 60];
+        let frame = &mut buf[VIRTIO_NET_HDR_SIZE..];
+        frame[0..6].copy_from_slice(&[0xff; 6]);
+        frame[6..12].copy_from_slice(&mac);
+        frame[12..14].copy_from_slice(&ETH_P_RARP.to_be_bytes());
+        frame[14..16].copy_from_slice(&1u16.to_be_bytes());
+        frame[16..18].copy_from_slice(&ETH_P_IP.to_be_bytes());
+        frame[18] = 6;
+        frame[19] = 4;
+        frame[20..22].copy_from_slice(&RARP_OP_REQUEST_REV.to_be_bytes());
+        frame[22..28].copy_from_slice(&mac);
+        frame[32..38].copy_from_slice(&mac);
+        self.taps[0].send(&buf)
+    }
+
+    fn net_set_mtu(&mut self, mtu: u16) -> io::Result<()> {
+        if mtu < MIN_MTU {
+            return Err(io::Error::from_raw_os_error(libc::EINVAL));
+        }
+        self.mtu = mtu;
+        Ok(())
+    }
+
+    fn queues_per_thread(&self) -> Vec<u64> {
+        (0..self.taps.len()).map(|pair| 0x3 << (pair * 2)).collect()
+    }
+
+    fn busy_poll_duration(&self) -> Option<Duration> {
+        self.busy_poll
+    }
+
+    fn process_queue(
+        &self,
+        queue_index: u16,
+        vring: &mut Vring,
+        _thread_id: usize,
+    ) -> io::Result<()> {
+        let mem = match self.mem {
+            Some(ref mem) => mem,
+            None => return Ok(()),
+        };
+        let tap = &self.taps[queue_index as usize / 2];
+        if queue_index & 1 == 0 {
+            self.process_rx(mem, tap, vring)
+        } else {
+            self.process_tx(mem, tap, vring)
+        }
+    }
+
+    fn handle_event(
+        &self,
+        device_event: u16,
+        _evset: EventSet,
+        vrings: &[Arc<RwLock<Vring>>],
+        thread_id: usize,
+    ) -> io::Result<bool> {
+        // Incoming packets are processed by the receive virtqueue of the queue pair.
+        let queue_index = if u64::from(device_event) >= self.tap_event(0) {
+            (u64::from(device_event) - self.tap_event(0)) as u16 * 2
+        } else {
+            device_event
+        };
+        if let Some(vring) = vrings.get(queue_index as usize) {
+            let mut vring = vring.write().unwrap();
+            if vring.is_enabled() {
+                self.process_queue(queue_index, &mut vring, thread_id)?;
+            }
+        }
+        Ok(false)
+    }
+}
+
+#[cfg(all(test, feature = "vhost-user-master"))]
+mod tests {
+    use super::*;
+    use crate::backend::{
+        VhostFeatures, VhostMemory, VhostOwner, VhostUserMemoryRegionInfo, VhostVring,
+        VringConfigData,
+    };
+    use crate::vhost_user::message::{VhostUserConfigFlags, VHOST_USER_CONFIG_OFFSET};
+    use crate::vhost_user::{Daemon, Listener, Master, VhostUserMaster};
+    use std::os::unix::io::IntoRawFd;
+    use std::os::unix::net::UnixDatagram;
+    use std::thread;
+    use vm_memory::{FileOffset, GuestAddress};
+    use vmm_sys_util::eventfd::EventFd;
+    use vmm_sys_util::tempfile::TempFile;
+
+    const MAC: [u8; 6] = [0x52, 0x54, 0x00, 0x12, 0x34, 0x56];
+
+    // Guest physical addresses of the rings and buffers, in a single region mapped at VA_BASE.
+    const VA_BASE: u64 = 0x7f00_0000_0000;
+    const RING_ADDRS: [(u64, u64, u64); 2] = [(0x1000, 0x2000, 0x3000), (0x4000, 0x5000, 0x6000)];
+    const RX_BUF: u64 = 0x8000;
+    const TX_BUF: u64 = 0x9000;
+
+    // Make the buffer at `addr` available as the `idx`th descriptor and avail entry of the vring.
+    fn add_avail(mem: &GuestMemoryMmap, queue: usize, idx: u16, addr: u64, len: u32, write: bool) {
+        let (desc, avail, _) = RING_ADDRS[queue];
+        let desc = desc + 16 * u64::from(idx);
+        mem.write_obj(addr, GuestAddress(desc)).unwrap();
+        mem.write_obj(len, GuestAddress(desc + 8)).unwrap();
+        mem.write_obj(if write { 2u16 } else { 0u16 }, GuestAddress(desc + 12))
+            .unwrap();
+        mem.write_obj(idx, GuestAddress(avail + 4 + 2 * u64::from(idx)))
+            .unwrap();
+        mem.write_obj(idx + 1, GuestAddress(avail + 2)).unwrap();
+    }
+
+    #[test]
+    fn test_net_backend() {
+        let path = "/tmp/vhost_user_lib_unit_test_net_backend";
+        let (sock, peer) = UnixDatagram::pair().unwrap();
+        // Safe because we take the ownership of the socket.
+        let file = unsafe { File::from_raw_fd(sock.into_raw_fd()) };
+        let tap = NetTap::from_file(file).unwrap();
+        let backend = NetBackend::new(vec![tap], MAC, 1500).unwrap();
+        assert_eq!(backend.queues_per_thread(), vec![0x3]);
+        let backend = Arc::new(RwLock::new(backend));
+        let mut daemon = Daemon::new("test-net".to_string(), backend.clone()).unwrap();
+        backend
+            .read()
+            .unwrap()
+            .register_taps(&daemon.get_vring_workers())
+            .unwrap();
+
+        let file = TempFile::new().unwrap().into_file();
+        file.set_len(0x10000).unwrap();
+        let mem = GuestMemoryMmap::from_ranges_with_files(&[(
+            GuestAddress(0),
+            0x10000,
+            Some(FileOffset::new(file.try_clone().unwrap(), 0)),
+        )])
+        .unwrap();
+
+        let listener = Listener::new(path, true).unwrap();
+        let master_thread = thread::spawn(move || {
+            let mut master = Master::connect(path, 2).unwrap();
+            master.set_owner().unwrap();
+            let features = master.get_features().unwrap();
+            assert_ne!(features & (1 << VIRTIO_NET_F_MAC), 0);
+            master.set_features(features).unwrap();
+            master.get_protocol_features().unwrap();
+            master
+                .set_protocol_features(
+                    VhostUserProtocolFeatures::MQ
+                        | VhostUserProtocolFeatures::CONFIG
+                        | VhostUserProtocolFeatures::RARP
+                        | VhostUserProtocolFeatures::MTU,
+                )
+                .unwrap();
+            master.net_set_mtu(1400).unwrap();
+            assert_eq!(master.get_queue_num().unwrap(), 2);
+            let (_, config) = master
+                .get_config(
+                    VHOST_USER_CONFIG_OFFSET,
+                    VIRTIO_NET_CONFIG_SIZE as u32,
+                    VhostUserConfigFlags::WRITABLE,
+                    &[0u8; VIRTIO_NET_CONFIG_SIZE],
+                )
+                .unwrap();
+            assert_eq!(&config[0..6], &MAC);
+            assert_eq!(&config[8..12], &[1, 0, 0x78, 0x05]);
+
+            let region = VhostUserMemoryRegionInfo {
+                guest_phys_addr: 0,
+                memory_size: 0x10000,
+                userspace_addr: VA_BASE,
+                mmap_offset: 0,
+                mmap_handle: file.as_raw_fd(),
+            };
+            master.set_mem_table(&[region]).unwrap();
+
+            let mut calls = Vec::new();
+            for (queue, &(desc, avail, used)) in RING_ADDRS.iter().enumerate() {
+                master.set_vring_num(queue, 16).unwrap();
+                let config = VringConfigData {
+                    queue_max_size: QUEUE_SIZE as u16,
+                    queue_size: 16,
+                    flags: 0,
+                    desc_table_addr: VA_BASE + desc,
+                    used_ring_addr: VA_BASE + used,
+                    avail_ring_addr: VA_BASE + avail,
+                    log_addr: None,
+                };
+                master.set_vring_addr(queue, &config).unwrap();
+                master.set_vring_base(queue, 0).unwrap();
+                let call = EventFd::new(0).unwrap();
+                master.set_vring_call(queue, &call).unwrap();
+                master.set_vring_enable(queue, true).unwrap();
+                let kick = EventFd::new(0).unwrap();
+                master.set_vring_kick(queue, &kick).unwrap();
+                calls.push((call, kick));
+            }
+
+            // Send a packet from the guest.
+            mem.write_slice(&[0u8; VIRTIO_NET_HDR_SIZE], GuestAddress(TX_BUF))
+                .unwrap();
+            mem.write_slice(b"hello", GuestAddress(TX_BUF + VIRTIO_NET_HDR_SIZE as u64))
+                .unwrap();
+            add_avail(&mem, 1, 0, TX_BUF, VIRTIO_NET_HDR_SIZE as u32 + 5, false);
+            calls[1].1.write(1).unwrap();
+            assert_eq!(calls[1].0.read().unwrap(), 1);
+            let mut buf = [0u8; 16];
+            assert_eq!(peer.recv(&mut buf).unwrap(), 5);
+            assert_eq!(&buf[..5], b"hello");
+            let used_idx: u16 = mem.read_obj(GuestAddress(RING_ADDRS[1].2 + 2)).unwrap();
+            assert_eq!(used_idx, 1);
+
+            // Drop a packet shorter than its header, giving the buffer back to the guest.
+            add_avail(&mem, 1, 1, TX_BUF, VIRTIO_NET_HDR_SIZE as u32 - 1, false);
+            calls[1].1.write(1).unwrap();
+            assert_eq!(calls[1].0.read().unwrap(), 1);
+            let used_idx: u16 = mem.read_obj(GuestAddress(RING_ADDRS[1].2 + 2)).unwrap();
+            assert_eq!(used_idx, 2);
+            peer.set_nonblocking(true).unwrap();
+            assert_eq!(
+                peer.recv(&mut buf).unwrap_err().kind(),
+                io::ErrorKind::WouldBlock
+            );
+            peer.set_nonblocking(false).unwrap();
+
+            // Receive a packet into the guest.
+            add_avail(&mem, 0, 0, RX_BUF, 0x800, true);
+            calls[0].1.write(1).unwrap();
+            peer.send(b"world").unwrap();
+            assert_eq!(calls[0].0.read().unwrap(), 1);
+            let len: u32 = mem.read_obj(GuestAddress(RING_ADDRS[0].2 + 8)).unwrap();
+            assert_eq!(len as usize, VIRTIO_NET_HDR_SIZE + 5);
+            let mut buf = [0u8; VIRTIO_NET_HDR_SIZE + 5];
+            mem.read_slice(&mut buf, GuestAddress(RX_BUF)).unwrap();
+            assert_eq!(&buf[10..12], &[1, 0]);
+            assert_eq!(&buf[VIRTIO_NET_HDR_SIZE..], b"world");
+
+            // Announce the guest.
+            master.send_rarp(&MAC).unwrap();
+            let mut buf = [0u8; 64];
+            assert_eq!(peer.recv(&mut buf).unwrap(), 60);
+            assert_eq!(&buf[0..6], &[0xff; 6]);
+            assert_eq!(&buf[6..12], &MAC);
+            assert_eq!(&buf[12..14], &[0x80, 0x35]);
+            assert_eq!(&buf[20..22], &[0, 3]);
+            assert_eq!(&buf[32..38], &MAC);
+        });
+
+        daemon.start(listener).unwrap();
+        master_thread.join().unwrap();
+        assert!(daemon.wait().is_err());
+    }
+}
@@ -0,0 +1,431 @@
+// Copyright (C) 2020 Alibaba Cloud Computing. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Validation of vhost-user messages, independent of the connection they are received from.
+//!
+//! The endpoints check every received request with these functions before handling it, so the
+//! whole message is known to be well formed: the header flags, the size of the body and of its
+//! variable payload, the number of attached file descriptors and the invariants of the message
+//! structures. `parse_msg()` and `parse_slave_msg()` take the raw bytes of the message, so
+//! fuzzers and unit tests can exercise the parser without setting up sockets.
+//! `parse_raw_msg()` and `parse_raw_slave_msg()` take the header and the body in a single
+//! buffer, as messages are laid out on the wire, so each input of a fuzzing corpus is a whole
+//! message. The fuzz target in `fuzz/` feeds arbitrary inputs to both directions.
+
+use std::mem;
+use std::ptr;
+
+use super::message::*;
+use super::{Error, Result};
+
+// Size of the message header on the wire.
+const HEADER_SIZE: usize = 12;
+
+/// Parse a request from the master to the slave, given the raw bytes of its header and body, and
+/// the number of file descriptors attached to it.
+///
+/// # Return:
+/// * - the request code on success.
+/// * - InvalidMessage: the header, the body or the payload of the request is malformed.
+/// * - IncorrectFds: the number of attached file descriptors doesn't match the request.
+pub fn parse_msg(header: &[u8], body: &[u8], num_fds: usize) -> Result<MasterReq> {
+    let (request, flags, size) = decode_header(header)?;
+    if request == 0 || request >= MasterReq::MAX_CMD as u32 {
+        return Err(Error::InvalidMessage);
+    }
+    // Safe because MasterReq is repr(u32), and the value is within its range.
+    let code = unsafe { mem::transmute::<u32, MasterReq>(request) };
+    check_flags(flags)?;
+    check_msg(&VhostUserMsgHeader::new(code, flags, size), body, num_fds)?;
+    Ok(code)
+}
+
+/// Parse a request from the slave to the master, given the raw bytes of its header and body, and
+/// the number of file descriptors attached to it.
+///
+/// # Return:
+/// * - the request code on success.
+/// * - InvalidMessage: the header, the body or the payload of the request is malformed.
+/// * - IncorrectFds: the number of attached file descriptors doesn't match the request.
+pub fn parse_slave_msg(header: &[u8], body: &[u8], num_fds: usize) -> Result<SlaveReq> {
+    let (request, flags, size) = decode_header(header)?;
+    if request == 0 || request >= SlaveReq::MAX_CMD as u32 {
+        return Err(Error::InvalidMessage);
+    }
+    // Safe because SlaveReq is repr(u32), and the value is within its range.
+    let code = unsafe { mem::transmute::<u32, SlaveReq>(request) };
+    check_flags(flags)?;
+    check_slave_msg(&VhostUserMsgHeader::new(code, flags, size), body, num_fds)?;
+    Ok(code)
+}
+
+/// Parse a request from the master to the slave laid out in a single buffer, the header being
+/// followed by the body, with `num_fds` file descriptors attached.
+///
+/// # Return:
+/// * - the request code on success.
+/// * - InvalidMessage: the buffer is shorter than the header, or the message is malformed.
+/// * - IncorrectFds: the number of attached file descriptors doesn't match the request.
+pub fn parse_raw_msg(msg: &[u8], num_fds: usize) -> Result<MasterReq> {
+    let (header, body) = split_msg(msg)?;
+    parse_msg(header, body, num_fds)
+}
+
+/// Parse a request from the slave to the master laid out in a single buffer, the header being
+/// followed by the body, with `num_fds` file descriptors attached.
+///
+/// # Return:
+/// * - the request code on success.
+/// * - InvalidMessage: the buffer is shorter than the header, or the message is malformed.
+/// * - IncorrectFds: the number of attached file descriptors doesn't match the request.
+pub fn parse_raw_slave_msg(msg: &[u8], num_fds: usize) -> Result<SlaveReq> {
+    let (header, body) = split_msg(msg)?;
+    parse_slave_msg(header, body, num_fds)
+}
+
+/// Check a request received from the master.
+pub(super) fn check_msg(
+    hdr: &VhostUserMsgHeader<MasterReq>,
+    body: &[u8],
+    num_fds: usize,
+) -> Result<()> {
+    check_header(hdr, body, num_fds)?;
+    match hdr.get_code() {
+        MasterReq::SET_OWNER
+        | MasterReq::RESET_OWNER
+        | MasterReq::GET_FEATURES
+        | MasterReq::GET_PROTOCOL_FEATURES
+        | MasterReq::GET_QUEUE_NUM
+        | MasterReq::POSTCOPY_ADVISE
+        | MasterReq::POSTCOPY_LISTEN
+        | MasterReq::POSTCOPY_END
+        | MasterReq::RESET_DEVICE
+        | MasterReq::GET_MAX_MEM_SLOTS
+        | MasterReq::GET_STATUS
+        | MasterReq::CHECK_DEVICE_STATE
+        | MasterReq::GET_SHMEM_CONFIG => check_empty(body, num_fds, 0),
+        MasterReq::SET_LOG_FD | MasterReq::SET_SLAVE_REQ_FD | MasterReq::GPU_SET_SOCKET => {
+            check_empty(body, num_fds, 1)
+        }
+        MasterReq::SET_FEATURES
+        | MasterReq::SET_PROTOCOL_FEATURES
+        | MasterReq::SEND_RARP
+        | MasterReq::NET_SET_MTU
+        | MasterReq::SET_STATUS => check_body::<VhostUserU64>(body, num_fds, 0).map(|_| ()),
+        MasterReq::SET_VRING_KICK | MasterReq::SET_VRING_CALL | MasterReq::SET_VRING_ERR => {
+            // Bits (0-7) of the payload contain the vring index, bit 8 tells that no file
+            // descriptor is attached.
+            let msg = check_prefix::<VhostUserU64>(body)?;
+            if msg.value & !(VHOST_USER_VRING_NOFD_MASK | 0xff) != 0 || body.len() != 8 {
+                return Err(Error::InvalidMessage);
+            }
+            check_fds(
+                num_fds,
+                (msg.value & VHOST_USER_VRING_NOFD_MASK == 0) as usize,
+            )
+        }
+        MasterReq::SET_VRING_NUM
+        | MasterReq::SET_VRING_BASE
+        | MasterReq::GET_VRING_BASE
+        | MasterReq::SET_VRING_ENABLE
+        | MasterReq::SET_VRING_ENDIAN
+        | MasterReq::VRING_KICK => check_body::<VhostUserVringState>(body, num_fds, 0).map(|_| ()),
+        MasterReq::SET_VRING_ADDR => check_body::<VhostUserVringAddr>(body, num_fds, 0).map(|_| ()),
+        MasterReq::SET_MEM_TABLE => check_mem_table(body, num_fds),
+        MasterReq::GET_CONFIG | MasterReq::SET_CONFIG => check_config(body, num_fds),
+        MasterReq::SET_LOG_BASE => check_body::<VhostUserLog>(body, num_fds, 1).map(|_| ()),
+        MasterReq::IOTLB_MSG => check_body::<VhostUserIotlb>(body, num_fds, 0).map(|_| ()),
+        MasterReq::GET_INFLIGHT_FD => check_body::<VhostUserInflight>(body, num_fds, 0).map(|_| ()),
+        MasterReq::SET_INFLIGHT_FD => check_body::<VhostUserInflight>(body, num_fds, 1).map(|_| ()),
+        MasterReq::ADD_MEM_REG => check_single_mem_region(body, num_fds, 1),
+        MasterReq::REM_MEM_REG => check_single_mem_region(body, num_fds, 0),
+        MasterReq::GET_SHARED_OBJECT => check_body::<VhostUserShared>(body, num_fds, 0).map(|_| ()),
+        MasterReq::SET_DEVICE_STATE_FD => {
+            check_body::<VhostUserTransferDeviceState>(body, num_fds, 1).map(|_| ())
+        }
+        // Crypto sessions aren't supported.
+        _ => Err(Error::InvalidMessage),
+    }
+}
+
+/// Check a request received from the slave.
+pub(super) fn check_slave_msg(
+    hdr: &VhostUserMsgHeader<SlaveReq>,
+    body: &[u8],
+    num_fds: usize,
+) -> Result<()> {
+    check_header(hdr, body, num_fds)?;
+    match hdr.get_code() {
+        SlaveReq::IOTLB_MSG => check_body::<VhostUserIotlb>(body, num_fds, 0).map(|_| ()),
+        SlaveReq::CONFIG_CHANGE_MSG => check_empty(body, num_fds, 0),
+        SlaveReq::VRING_HOST_NOTIFIER_MSG => {
+            let msg = check_prefix::<VhostUserVringArea>(body)?;
+            check_body::<VhostUserVringArea>(body, num_fds, msg.has_fd() as usize).map(|_| ())
+        }
+        SlaveReq::VRING_CALL | SlaveReq::VRING_ERR => {
+            check_body::<VhostUserVringState>(body, num_fds, 0).map(|_| ())
+        }
+        SlaveReq::SHARED_OBJECT_ADD
+        | SlaveReq::SHARED_OBJECT_REMOVE
+        | SlaveReq::SHARED_OBJECT_LOOKUP => {
+            check_body::<VhostUserShared>(body, num_fds, 0).map(|_| ())
+        }
+        SlaveReq::FS_MAP | SlaveReq::FS_IO => {
+            check_body::<VhostUserFSSlaveMsg>(body, num_fds, 1).map(|_| ())
+        }
+        SlaveReq::FS_UNMAP | SlaveReq::FS_SYNC => {
+            check_body::<VhostUserFSSlaveMsg>(body, num_fds, 0).map(|_| ())
+        }
+        _ => Err(Error::InvalidMessage),
+    }
+}
+
+fn split_msg(msg: &[u8]) -> Result<(&[u8], &[u8])> {
+    if msg.len() < HEADER_SIZE {
+        return Err(Error::InvalidMessage);
+    }
+    Ok(msg.split_at(HEADER_SIZE))
+}
+
+// Split the raw header into the request code, the flags and the size fields.
+fn decode_header(header: &[u8]) -> Result<(u32, u32, u32)> {
+    if header.len() != HEADER_SIZE {
+        return Err(Error::InvalidMessage);
+    }
+    let field = |i: usize| {
+        let mut buf = [0u8; 4];
+        buf.copy_from_slice(&header[i * 4..(i + 1) * 4]);
+        u32::from_ne_bytes(buf)
+    };
+    Ok((field(0), field(1), field(2)))
+}
+
+// VhostUserMsgHeader::new() drops the version and the reserved bits, so check them on the raw
+// flags.
+fn check_flags(flags: u32) -> Result<()> {
+    if flags & VhostUserHeaderFlag::VERSION.bits() != 0x1
+        || flags & VhostUserHeaderFlag::RESERVED_BITS.bits() != 0
+    {
+        return Err(Error::InvalidMessage);
+    }
+    Ok(())
+}
+
+fn check_header<R: Req>(hdr: &VhostUserMsgHeader<R>, body: &[u8], num_fds: usize) -> Result<()> {
+    if !hdr.is_valid_with_max_size(MAX_JUMBO_MSG_SIZE)
+        || hdr.is_reply()
+        || hdr.get_size() as usize != body.len()
+    {
+        return Err(Error::InvalidMessage);
+    }
+    if num_fds > MAX_ATTACHED_FD_ENTRIES {
+        return Err(Error::IncorrectFds);
+    }
+    Ok(())
+}
+
+fn check_fds(num_fds: usize, expected: usize) -> Result<()> {
+    if num_fds != expected {
+        return Err(Error::IncorrectFds);
+    }
+    Ok(())
+}
+
+fn check_empty(body: &[u8], num_fds: usize, expected_fds: usize) -> Result<()> {
+    if !body.is_empty() {
+        return Err(Error::InvalidMessage);
+    }
+    check_fds(num_fds, expected_fds)
+}
+
+// Decode and validate the structure at the start of the body, which may be followed by a
+// variable payload.
+fn check_prefix<T: VhostUserMsgValidator>(body: &[u8]) -> Result<T> {
+    if body.len() < mem::size_of::<T>() {
+        return Err(Error::InvalidMessage);
+    }
+    // Safe because the body holds a whole T, and message structures are plain data.
+    let msg = unsafe { ptr::read_unaligned(body.as_ptr() as *const T) };
+    if !msg.is_valid() {
+        return Err(Error::InvalidMessage);
+    }
+    Ok(msg)
+}
+
+// Decode and validate a body made of a single structure.
+fn check_body<T: VhostUserMsgValidator>(
+    body: &[u8],
+    num_fds: usize,
+    expected_fds: usize,
+) -> Result<T> {
+    if body.len() != mem::size_of::<T>() {
+        return Err(Error::InvalidMessage);
+    }
+    let msg = check_prefix::<T>(body)?;
+    check_fds(num_fds, expected_fds)?;
+    Ok(msg)
+}
+
+// SET_MEM_TABLE carries one region descriptor, and one file descriptor, per memory region. The
+// descriptors are followed by their Xen mapping once VHOST_USER_PROTOCOL_F_XEN_MMAP has been
+// negotiated, which the endpoint checks against the negotiated features.
+fn check_mem_table(body: &[u8], num_fds: usize) -> Result<()> {
+    let msg = check_prefix::<VhostUserMemory>(body)?;
+    let hdr_size = mem::size_of::<VhostUserMemory>();
+    let num_regions = msg.num_regions as usize;
+    if body.len() == hdr_size + num_regions * mem::size_of::<VhostUserMemoryRegion>() {
+        check_mem_regions::<VhostUserMemoryRegion>(&body[hdr_size..], num_regions)?;
+    } else if body.len() == hdr_size + num_regions * mem::size_of::<VhostUserXenMemoryRegion>() {
+        check_mem_regions::<VhostUserXenMemoryRegion>(&body[hdr_size..], num_regions)?;
+    } else {
+        return Err(Error::InvalidMessage);
+    }
+    check_fds(num_fds, num_regions)
+}
+
+fn check_mem_regions<T: VhostUserMsgValidator>(payload: &[u8], num_regions: usize) -> Result<()> {
+    for i in 0..num_regions {
+        check_prefix::<T>(&payload[i * mem::size_of::<T>()..])?;
+    }
+    Ok(())
+}
+
+// ADD_MEM_REG and REM_MEM_REG carry a single region descriptor, followed by its Xen mapping once
+// VHOST_USER_PROTOCOL_F_XEN_MMAP has been negotiated.
+fn check_single_mem_region(body: &[u8], num_fds: usize, expected_fds: usize) -> Result<()> {
+    if body.len() == mem::size_of::<VhostUserXenSingleMemoryRegion>() {
+        check_body::<VhostUserXenSingleMemoryRegion>(body, num_fds, expected_fds).map(|_| ())
+    } else {
+        check_body::<VhostUserSingleMemoryRegion>(body, num_fds, expected_fds).map(|_| ())
+    }
+}
+
+// GET_CONFIG and SET_CONFIG carry the configuration space range after the descriptor.
+fn check_config(body: &[u8], num_fds: usize) -> Result<()> {
+    let msg = check_prefix::<VhostUserConfig>(body)?;
+    if body.len() - mem::size_of::<VhostUserConfig>() != msg.size as usize {
+        return Err(Error::InvalidMessage);
+    }
+    check_fds(num_fds, 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn header(request: u32, flags: u32, size: usize) -> Vec<u8> {
+        let mut buf = Vec::new();
+        buf.extend_from_slice(&request.to_ne_bytes());
+        buf.extend_from_slice(&flags.to_ne_bytes());
+        buf.extend_from_slice(&(size as u32).to_ne_bytes());
+        buf
+    }
+
+    fn bytes<T>(msg: &T) -> Vec<u8> {
+        // Safe because message structures are plain data.
+        unsafe { std::slice::from_raw_parts(msg as *const T as *const u8, mem::size_of::<T>()) }
+            .to_vec()
+    }
+
+    #[test]
+    fn test_parse_header() {
+        let code = MasterReq::SET_OWNER as u32;
+        assert_eq!(
+            parse_msg(&header(code, 0x1, 0), &[], 0).unwrap(),
+            MasterReq::SET_OWNER
+        );
+        // Truncated header.
+        assert!(parse_msg(&header(code, 0x1, 0)[..8], &[], 0).is_err());
+        // Unknown codes.
+        assert!(parse_msg(&header(0, 0x1, 0), &[], 0).is_err());
+        assert!(parse_msg(&header(MasterReq::MAX_CMD as u32, 0x1, 0), &[], 0).is_err());
+        assert!(parse_msg(&header(0xffff_ffff, 0x1, 0), &[], 0).is_err());
+        // Bad version, reserved bits and replies.
+        assert!(parse_msg(&header(code, 0x2, 0), &[], 0).is_err());
+        assert!(parse_msg(&header(code, 0x11, 0), &[], 0).is_err());
+        assert!(parse_msg(&header(code, 0x5, 0), &[], 0).is_err());
+        // Size mismatch and unexpected file descriptors.
+        assert!(parse_msg(&header(code, 0x1, 8), &[0; 8], 0).is_err());
+        assert!(parse_msg(&header(code, 0x1, 1), &[], 0).is_err());
+        match parse_msg(&header(code, 0x1, 0), &[], 1) {
+            Err(Error::IncorrectFds) => {}
+            _ => panic!("expected unexpected file descriptors"),
+        }
+    }
+
+    #[test]
+    fn test_parse_bodies() {
+        let code = MasterReq::SET_VRING_CALL as u32;
+        let body = bytes(&VhostUserU64::new(1));
+        assert!(parse_msg(&header(code, 0x9, 8), &body, 1).is_ok());
+        assert!(parse_msg(&header(code, 0x1, 8), &body, 0).is_err());
+        let body = bytes(&VhostUserU64::new(1 | VHOST_USER_VRING_NOFD_MASK));
+        assert!(parse_msg(&header(code, 0x1, 8), &body, 0).is_ok());
+        let body = bytes(&VhostUserU64::new(0x200));
+        assert!(parse_msg(&header(code, 0x1, 8), &body, 1).is_err());
+
+        let code = MasterReq::SET_MEM_TABLE as u32;
+        let mut body = bytes(&VhostUserMemory::new(2));
+        let region = VhostUserMemoryRegion::new(0, 0x1000, 0x1000, 0);
+        body.extend_from_slice(&bytes(&region));
+        assert!(parse_msg(&header(code, 0x1, body.len()), &body, 2).is_err());
+        body.extend_from_slice(&bytes(&region));
+        assert!(parse_msg(&header(code, 0x1, body.len()), &body, 1).is_err());
+        assert!(parse_msg(&header(code, 0x1, body.len()), &body, 2).is_ok());
+
+        // Regions followed by their Xen mapping.
+        let mut body = bytes(&VhostUserMemory::new(1));
+        let mut region = VhostUserXenMemoryRegion::new(
+            region,
+            VhostUserXenMmap::new(VhostUserXenMmapFlags::GRANT, 1),
+        );
+        body.extend_from_slice(&bytes(&region));
+        assert!(parse_msg(&header(code, 0x1, body.len()), &body, 1).is_ok());
+        region.xen_mmap.flags = 0x4;
+        let mut body = bytes(&VhostUserMemory::new(1));
+        body.extend_from_slice(&bytes(&region));
+        assert!(parse_msg(&header(code, 0x1, body.len()), &body, 1).is_err());
+
+        let code = MasterReq::ADD_MEM_REG as u32;
+        let body = bytes(&VhostUserXenSingleMemoryRegion::new(
+            VhostUserMemoryRegion::new(0, 0x1000, 0x1000, 0),
+            VhostUserXenMmap::new(VhostUserXenMmapFlags::FOREIGN, 1),
+        ));
+        assert!(parse_msg(&header(code, 0x1, body.len()), &body, 1).is_ok());
+        assert!(parse_msg(&header(code, 0x1, body.len() - 4), &body[..44], 1).is_err());
+
+        let code = MasterReq::GET_CONFIG as u32;
+        let config =
+            VhostUserConfig::new(VHOST_USER_CONFIG_OFFSET, 4, VhostUserConfigFlags::WRITABLE);
+        let mut body = bytes(&config);
+        assert!(parse_msg(&header(code, 0x1, body.len()), &body, 0).is_err());
+        body.extend_from_slice(&[0; 4]);
+        assert!(parse_msg(&header(code, 0x1, body.len()), &body, 0).is_ok());
+
+        let code = SlaveReq::VRING_HOST_NOTIFIER_MSG as u32;
+        let body = bytes(&VhostUserVringArea::new(0, 0x1000, 0));
+        assert!(parse_slave_msg(&header(code, 0x1, body.len()), &body, 1).is_ok());
+        assert!(parse_slave_msg(&header(code, 0x1, body.len()), &body, 0).is_err());
+        let body = bytes(&VhostUserVringArea::new_nofd(0));
+        assert!(parse_slave_msg(&header(code, 0x1, body.len()), &body, 0).is_ok());
+        assert!(parse_slave_msg(&header(SlaveReq::MAX_CMD as u32, 0x1, 0), &[], 0).is_err());
+    }
+
+    #[test]
+    fn test_parse_raw_msg() {
+        let mut msg = header(MasterReq::SET_FEATURES as u32, 0x1, 8);
+        assert!(parse_raw_msg(&msg[..11], 0).is_err());
+        assert!(parse_raw_msg(&msg, 0).is_err());
+        msg.extend_from_slice(&bytes(&VhostUserU64::new(0x1)));
+        assert_eq!(parse_raw_msg(&msg, 0).unwrap(), MasterReq::SET_FEATURES);
+        msg.push(0);
+        assert!(parse_raw_msg(&msg, 0).is_err());
+
+        let msg = header(SlaveReq::CONFIG_CHANGE_MSG as u32, 0x1, 0);
+        assert_eq!(
+            parse_raw_slave_msg(&msg, 0).unwrap(),
+            SlaveReq::CONFIG_CHANGE_MSG
+        );
+        assert!(parse_raw_slave_msg(&msg, 1).is_err());
+    }
+}
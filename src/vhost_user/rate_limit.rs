@@ -0,0 +1,109 @@
+// Copyright (C) 2020 Alibaba Cloud Computing. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Token bucket limiting the rate of the requests from the master.
+//!
+//! A misbehaving master may flood the slave with expensive requests, such as SET_MEM_TABLE which
+//! remaps the guest memory, or SET_CONFIG. Each limited request takes a token from the bucket,
+//! which holds up to `burst` tokens and gets a new token every refill interval. Requests arriving
+//! while the bucket is empty are handled according to the `RateLimitPolicy`.
+
+use std::time::{Duration, Instant};
+
+/// What the slave does with the requests beyond the rate limit.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RateLimitPolicy {
+    /// Fail the request with `Error::RateLimited`, and keep serving the master.
+    Reject,
+    /// Fail the request with `Error::RateLimited`, and consider the connection broken.
+    Disconnect,
+}
+
+/// Token bucket allowing bursts of requests, and a sustained rate of one request per refill
+/// interval.
+#[derive(Clone, Debug)]
+pub struct RateLimiter {
+    burst: u32,
+    tokens: u32,
+    refill_interval: Duration,
+    last_refill: Instant,
+}
+
+impl RateLimiter {
+    /// Create a full bucket of `burst` tokens, getting a new token every `refill_interval`.
+    pub fn new(burst: u32, refill_interval: Duration) -> Self {
+        RateLimiter {
+            burst,
+            tokens: burst,
+            refill_interval,
+            last_refill: Instant::now(),
+        }
+    }
+
+    /// Take a token, if there's any left.
+    pub fn try_acquire(&mut self) -> bool {
+        self.try_acquire_at(Instant::now())
+    }
+
+    fn try_acquire_at(&mut self, now: Instant) -> bool {
+        self.refill(now);
+        if self.tokens == 0 {
+            return false;
+        }
+        self.tokens -= 1;
+        true
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.last_refill);
+        let new_tokens = match self.refill_interval.as_nanos() {
+            0 => u128::MAX,
+            interval => elapsed.as_nanos() / interval,
+        };
+        if new_tokens == 0 {
+            return;
+        }
+        // Only count the time since the last token for the next one, unless the bucket is full.
+        if new_tokens >= u128::from(self.burst - self.tokens) {
+            self.tokens = self.burst;
+            self.last_refill = now;
+        } else {
+            self.tokens += new_tokens as u32;
+            self.last_refill += self.refill_interval * new_tokens as u32;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rate_limiter() {
+        let mut limiter = RateLimiter::new(2, Duration::from_millis(10));
+        let start = limiter.last_refill;
+        assert!(limiter.try_acquire_at(start));
+        assert!(limiter.try_acquire_at(start));
+        assert!(!limiter.try_acquire_at(start + Duration::from_millis(9)));
+
+        // One token per interval, the remaining time counting for the next token.
+        assert!(limiter.try_acquire_at(start + Duration::from_millis(15)));
+        assert!(!limiter.try_acquire_at(start + Duration::from_millis(19)));
+        assert!(limiter.try_acquire_at(start + Duration::from_millis(20)));
+
+        // The bucket doesn't hold more than the burst.
+        let later = start + Duration::from_secs(1);
+        assert!(limiter.try_acquire_at(later));
+        assert!(limiter.try_acquire_at(later));
+        assert!(!limiter.try_acquire_at(later));
+    }
+
+    #[test]
+    fn test_rate_limiter_no_refill_interval() {
+        let mut limiter = RateLimiter::new(1, Duration::from_secs(0));
+        let start = limiter.last_refill;
+        assert!(limiter.try_acquire_at(start));
+        assert!(limiter.try_acquire_at(start));
+        assert!(!RateLimiter::new(0, Duration::from_secs(1)).try_acquire());
+    }
+}
@@ -0,0 +1,726 @@
+// Copyright (C) 2020 Alibaba Cloud Computing. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Vhost-user master automatically reconnecting to restarted slaves.
+//!
+//! Vhost-user slaves are separate processes which may crash or be restarted while the VMM keeps
+//! running. The `ReconnectingMaster` wraps a `Master` and records the device state configured
+//! through it. When a request fails because the connection to the slave is broken, it
+//! re-establishes the connection, replays the recorded state to the new slave and retries the
+//! failed request.
+//!
+//! The following state is replayed, in this order:
+//! . ownership of the session
+//! . acked virtio features and vhost-user protocol features
+//! . number of queues queried from the slave
+//! . memory table, including memory slots added and removed since
+//! . inflight I/O tracking buffer
+//! . vring size, addresses, base, call/err/kick eventfds
+//! . virtio device status
+//!
+//! Vrings are not re-enabled and the slave communication channel is not re-established, because
+//! they depend on the VMM. `VhostUserReconnectHandler::reconnected()` is invoked once the state
+//! has been replayed for the VMM to complete the setup.
+//!
+//! With `MasterConnector::Server`, the master listens for the slave to connect instead. The slave
+//! is then expected to connect again once restarted, which `ReconnectingMaster::accept()` handles
+//! without waiting for a request to fail.
+//!
+//! The recorded state, except for the file descriptors, may also be saved as a `SessionSnapshot`
+//! with `ReconnectingMaster::snapshot()`, to set up a new slave the same way with
+//! `ReconnectingMaster::restore()` after a VMM has been restored from a snapshot.
+
+use std::convert::TryFrom;
+use std::fs::File;
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::thread;
+use std::time::Duration;
+
+use vmm_sys_util::eventfd::EventFd;
+
+use super::connection::Listener;
+use super::message::*;
+use super::{
+    Error as VhostUserError, Master, MemoryRegionSnapshot, SessionSnapshot, VhostUserMaster,
+    VringSnapshot,
+};
+use crate::backend::{
+    VhostFeatures, VhostMemory, VhostOwner, VhostUserDirtyLogRegion, VhostUserMemoryRegionInfo,
+    VhostVring, VringConfigData,
+};
+use crate::{Error, Result};
+
+/// How the master gets connected to the slave.
+pub enum MasterConnector {
+    /// Connect to the Unix domain socket the slave listens on.
+    Client(String),
+    /// Wait for the slave to connect to the Unix domain socket the master listens on.
+    Server(Listener),
+}
+
+impl MasterConnector {
+    fn connect(&self, max_queue_num: u64) -> Result<Master> {
+        match self {
+            MasterConnector::Client(path) => Master::connect(path, max_queue_num),
+            MasterConnector::Server(listener) => match listener.accept()? {
+                Some(sock) => Ok(Master::from_stream(sock, max_queue_num)),
+                None => Err(Error::VhostUserProtocol(VhostUserError::SocketRetry(
+                    std::io::Error::from_raw_os_error(libc::EAGAIN),
+                ))),
+            },
+        }
+    }
+}
+
+/// Callbacks to notify the VMM of reconnections to the slave.
+pub trait VhostUserReconnectHandler {
+    /// The connection to the slave has been found broken, before trying to reconnect.
+    fn disconnected(&mut self) {}
+
+    /// Get the base to restart the vring from with the new slave.
+    ///
+    /// `last_base` is the base last set or retrieved by the master. The VMM may know better, for
+    /// instance from the inflight I/O tracking buffer or the used ring index.
+    fn vring_base(&mut self, _queue_index: usize, last_base: u16) -> u16 {
+        last_base
+    }
+
+    /// The connection has been re-established and the recorded state has been replayed.
+    ///
+    /// The VMM should re-enable the vrings and set up the optional slave communication channel
+    /// with the new slave.
+    fn reconnected(&mut self, master: &mut Master) -> Result<()>;
+}
+
+#[derive(Default)]
+struct VringState {
+    big_endian: Option<bool>,
+    num: Option<u16>,
+    config: Option<VringConfigData>,
+    base: Option<u16>,
+    call: Option<EventFd>,
+    err: Option<EventFd>,
+    kick: Option<EventFd>,
+    inband: bool,
+    // only recorded for snapshots, the VMM enables the vrings after reconnections
+    enabled: bool,
+}
+
+// Device state configured through the master, to be replayed on reconnection.
+#[derive(Default)]
+struct DeviceState {
+    owned: bool,
+    features: Option<u64>,
+    protocol_features: Option<VhostUserProtocolFeatures>,
+    queue_num_queried: bool,
+    mtu: Option<u16>,
+    mem_regions: Vec<VhostUserMemoryRegionInfo>,
+    // Xen mapping of each memory region, at the same index
+    xen_mmaps: Vec<VhostUserXenMmap>,
+    inflight: Option<(VhostUserInflight, File)>,
+    vrings: Vec<VringState>,
+    status: Option<u8>,
+}
+
+impl DeviceState {
+    fn vring(&mut self, queue_index: usize) -> &mut VringState {
+        if self.vrings.len() <= queue_index {
+            self.vrings
+                .resize_with(queue_index + 1, VringState::default);
+        }
+        &mut self.vrings[queue_index]
+    }
+}
+
+/// A vhost-user master reconnecting to the slave when the connection is broken.
+pub struct ReconnectingMaster {
+    connector: MasterConnector,
+    master: Master,
+    max_queue_num: u64,
+    state: DeviceState,
+    handler: Box<dyn VhostUserReconnectHandler + Send>,
+    retry_count: u32,
+    retry_interval: Duration,
+    timeout: Option<Duration>,
+}
+
+impl ReconnectingMaster {
+    /// Connect to the slave through `connector`.
+    ///
+    /// # Arguments
+    /// * `connector` - how to connect to the slave, on startup and on reconnection
+    /// * `max_queue_num` - maximum number of queues of the device
+    /// * `handler` - callbacks notified of reconnections
+    pub fn new(
+        connector: MasterConnector,
+        max_queue_num: u64,
+        handler: Box<dyn VhostUserReconnectHandler + Send>,
+    ) -> Result<Self> {
+        let master = connector.connect(max_queue_num)?;
+        Ok(ReconnectingMaster {
+            connector,
+            master,
+            max_queue_num,
+            state: DeviceState::default(),
+            handler,
+            retry_count: 10,
+            retry_interval: Duration::from_millis(100),
+            timeout: None,
+        })
+    }
+
+    /// Set how long to wait for the slave on each request, as `Master::set_timeout()` does,
+    /// including on the connections re-established later.
+    pub fn set_timeout(&mut self, timeout: Option<Duration>) -> Result<()> {
+        self.master.set_timeout(timeout)?;
+        self.timeout = timeout;
+        Ok(())
+    }
+
+    /// Change how many times, and at which interval, connecting to the slave is attempted on
+    /// reconnection.
+    pub fn set_retry_policy(&mut self, retry_count: u32, retry_interval: Duration) {
+        self.retry_count = retry_count;
+        self.retry_interval = retry_interval;
+    }
+
+    /// Get the master endpoint currently connected to the slave.
+    ///
+    /// Requests sent through the returned endpoint are not recorded, nor retried on failure.
+    pub fn master(&mut self) -> &mut Master {
+        &mut self.master
+    }
+
+    /// Re-establish the connection to the slave and replay the recorded device state.
+    pub fn reconnect(&mut self) -> Result<()> {
+        self.handler.disconnected();
+
+        let mut retry_count = self.retry_count;
+        self.master = loop {
+            match self.connector.connect(self.max_queue_num) {
+                Ok(master) => break master,
+                Err(_) if retry_count > 0 => {
+                    retry_count -= 1;
+                    thread::sleep(self.retry_interval);
+                }
+                Err(e) => return Err(e),
+            }
+        };
+        self.resume()
+    }
+
+    /// Switch to the slave connecting to the listener of a master in server mode, if any.
+    ///
+    /// A restarted slave connects again to the master before the broken connection is detected by
+    /// a failed request. The VMM may call it whenever the listener is readable, to replay the
+    /// recorded device state to the new slave right away, like QEMU does with `server=on`.
+    ///
+    /// # Return:
+    /// * - true: a new slave has been accepted and set up.
+    /// * - false: no slave is connecting, or the master is in client mode.
+    pub fn accept(&mut self) -> Result<bool> {
+        let master = match self.connector {
+            MasterConnector::Server(ref listener) => match listener.accept()? {
+                Some(sock) => Master::from_stream(sock, self.max_queue_num),
+                None => return Ok(false),
+            },
+            MasterConnector::Client(_) => return Ok(false),
+        };
+        self.handler.disconnected();
+        self.master = master;
+        self.resume()?;
+        Ok(true)
+    }
+
+    /// Take a snapshot of the device state configured through the master.
+    pub fn snapshot(&self) -> SessionSnapshot {
+        let state = &self.state;
+        SessionSnapshot {
+            features: state.features,
+            protocol_features: state.protocol_features.map(|features| features.bits()),
+            regions: state
+                .mem_regions
+                .iter()
+                .map(|region| MemoryRegionSnapshot {
+                    guest_phys_addr: region.guest_phys_addr,
+                    memory_size: region.memory_size,
+                    userspace_addr: region.userspace_addr,
+                    mmap_offset: region.mmap_offset,
+                })
+                .collect(),
+            vrings: state
+                .vrings
+                .iter()
+                .map(|vring| VringSnapshot {
+                    size: vring.num,
+                    config: vring.config,
+                    base: vring.base.map(u32::from),
+                    enabled: vring.enabled,
+                })
+                .collect(),
+            status: state.status,
+        }
+    }
+
+    /// Set up the slave from `snapshot`, replacing the recorded device state, as if the slave had
+    /// reconnected.
+    ///
+    /// `mem_fds` are the file descriptors backing the memory regions of the snapshot, in the same
+    /// order. As after reconnections, `VhostUserReconnectHandler::reconnected()` is invoked for the
+    /// VMM to complete the setup, here by also setting the vring eventfds and enabling the vrings.
+    pub fn restore(&mut self, snapshot: &SessionSnapshot, mem_fds: &[RawFd]) -> Result<()> {
+        if mem_fds.len() != snapshot.regions.len() {
+            return Err(VhostUserError::InvalidParam.into());
+        }
+        let protocol_features = match snapshot.protocol_features {
+            Some(bits) => Some(
+                VhostUserProtocolFeatures::from_bits(bits).ok_or(VhostUserError::InvalidParam)?,
+            ),
+            None => None,
+        };
+        let mut vrings = Vec::new();
+        for vring in snapshot.vrings.iter() {
+            let base = match vring.base {
+                Some(base) => Some(u16::try_from(base).map_err(|_| VhostUserError::InvalidParam)?),
+                None => None,
+            };
+            vrings.push(VringState {
+                num: vring.size,
+                config: vring.config,
+                base,
+                enabled: vring.enabled,
+                ..Default::default()
+            });
+        }
+        self.state = DeviceState {
+            owned: true,
+            features: snapshot.features,
+            protocol_features,
+            queue_num_queried: protocol_features
+                .is_some_and(|features| features.contains(VhostUserProtocolFeatures::MQ)),
+            mem_regions: snapshot
+                .regions
+                .iter()
+                .zip(mem_fds)
+                .map(|(region, &fd)| VhostUserMemoryRegionInfo {
+                    guest_phys_addr: region.guest_phys_addr,
+                    memory_size: region.memory_size,
+                    userspace_addr: region.userspace_addr,
+                    mmap_offset: region.mmap_offset,
+                    mmap_handle: fd,
+                })
+                .collect(),
+            xen_mmaps: vec![VhostUserXenMmap::default(); snapshot.regions.len()],
+            vrings,
+            status: snapshot.status,
+            ..Default::default()
+        };
+        self.resume()
+    }
+
+    // Set up the slave just connected from the recorded device state.
+    fn resume(&mut self) -> Result<()> {
+        self.master.set_timeout(self.timeout)?;
+        self.replay()?;
+        self.handler.reconnected(&mut self.master)
+    }
+
+    fn replay(&mut self) -> Result<()> {
+        let master = &mut self.master;
+        let state = &mut self.state;
+
+        if state.owned {
+            master.set_owner()?;
+        }
+        if let Some(features) = state.features {
+            if features & !master.get_features()? != 0 {
+                return Err(VhostUserError::FeatureMismatch.into());
+            }
+            master.set_features(features)?;
+        }
+        if let Some(features) = state.protocol_features {
+            if !master.get_protocol_features()?.contains(features) {
+                return Err(VhostUserError::FeatureMismatch.into());
+            }
+            master.set_protocol_features(features)?;
+        }
+        if state.queue_num_queried {
+            master.get_queue_num()?;
+        }
+        if let Some(mtu) = state.mtu {
+            master.net_set_mtu(mtu)?;
+        }
+        if !state.mem_regions.is_empty() {
+            // Regions beyond the capacity of SET_MEM_TABLE are added as memory slots.
+            if state
+                .xen_mmaps
+                .iter()
+                .all(|xen_mmap| xen_mmap.is_shared_memory())
+            {
+                master.set_mem_table(&state.mem_regions)?;
+            } else {
+                master.set_mem_table_xen(&state.mem_regions, &state.xen_mmaps)?;
+            }
+        }
+        if let Some((ref inflight, ref file)) = state.inflight {
+            master.set_inflight_fd(inflight, file.as_raw_fd())?;
+        }
+
+        for (queue_index, vring) in state.vrings.iter_mut().enumerate() {
+            if let Some(big_endian) = vring.big_endian {
+                master.set_vring_endian(queue_index, big_endian)?;
+            }
+            if let Some(num) = vring.num {
+                master.set_vring_num(queue_index, num)?;
+            }
+            if let Some(ref config) = vring.config {
+                master.set_vring_addr(queue_index, config)?;
+            }
+            if let Some(base) = vring.base {
+                let base = self.handler.vring_base(queue_index, base);
+                master.set_vring_base(queue_index, base)?;
+                vring.base = Some(base);
+            }
+            if let Some(ref fd) = vring.call {
+                master.set_vring_call(queue_index, fd)?;
+            }
+            if let Some(ref fd) = vring.err {
+                master.set_vring_err(queue_index, fd)?;
+            }
+            if let Some(ref fd) = vring.kick {
+                master.set_vring_kick(queue_index, fd)?;
+            }
+            if vring.inband {
+                master.set_vring_inband(queue_index)?;
+            }
+        }
+        if let Some(status) = state.status {
+            master.set_status(status)?;
+        }
+        Ok(())
+    }
+
+    // Send a request, reconnecting and retrying once if the connection is broken.
+    fn call<T, F>(&mut self, mut f: F) -> Result<T>
+    where
+        F: FnMut(&mut Master) -> Result<T>,
+    {
+        match f(&mut self.master) {
+            Err(Error::VhostUserProtocol(ref e)) if is_disconnected(e) => {
+                self.reconnect()?;
+                f(&mut self.master)
+            }
+            res => res,
+        }
+    }
+}
+
+// Other errors returned by should_reconnect() come from failed requests on a working connection,
+// which won't be fixed by replaying the same requests.
+fn is_disconnected(err: &VhostUserError) -> bool {
+    matches!(
+        err,
+        VhostUserError::SocketBroken(_) | VhostUserError::PartialMessage
+    )
+}
+
+fn clone_eventfd(fd: &EventFd) -> Result<EventFd> {
+    fd.try_clone().map_err(Error::IOError)
+}
+
+impl VhostOwner for ReconnectingMaster {
+    fn set_owner(&mut self) -> Result<()> {
+        self.call(|m| m.set_owner())?;
+        self.state.owned = true;
+        Ok(())
+    }
+
+    fn reset_owner(&mut self) -> Result<()> {
+        self.call(|m| m.reset_owner())?;
+        self.state = DeviceState::default();
+        Ok(())
+    }
+}
+
+impl VhostFeatures for ReconnectingMaster {
+    fn get_features(&mut self) -> Result<u64> {
+        self.call(|m| m.get_features())
+    }
+
+    fn set_features(&mut self, features: u64) -> Result<()> {
+        self.call(|m| m.set_features(features))?;
+        self.state.features = Some(features);
+        Ok(())
+    }
+}
+
+impl VhostMemory for ReconnectingMaster {
+    fn set_mem_table(&mut self, regions: &[VhostUserMemoryRegionInfo]) -> Result<()> {
+        self.call(|m| m.set_mem_table(regions))?;
+        self.state.mem_regions = regions.to_vec();
+        self.state.xen_mmaps = vec![VhostUserXenMmap::default(); regions.len()];
+        Ok(())
+    }
+
+    fn set_log_base(&mut self, base: u64, region: Option<VhostUserDirtyLogRegion>) -> Result<()> {
+        self.call(|m| m.set_log_base(base, region))
+    }
+
+    fn set_log_fd(&mut self, fd: RawFd) -> Result<()> {
+        self.call(|m| m.set_log_fd(fd))
+    }
+}
+
+impl VhostVring for ReconnectingMaster {
+    fn set_vring_num(&mut self, queue_index: usize, num: u16) -> Result<()> {
+        self.call(|m| m.set_vring_num(queue_index, num))?;
+        self.state.vring(queue_index).num = Some(num);
+        Ok(())
+    }
+
+    fn set_vring_addr(&mut self, queue_index: usize, config_data: &VringConfigData) -> Result<()> {
+        self.call(|m| m.set_vring_addr(queue_index, config_data))?;
+        self.state.vring(queue_index).config = Some(*config_data);
+        Ok(())
+    }
+
+    fn set_vring_base(&mut self, queue_index: usize, base: u16) -> Result<()> {
+        self.call(|m| m.set_vring_base(queue_index, base))?;
+        self.state.vring(queue_index).base = Some(base);
+        Ok(())
+    }
+
+    fn get_vring_base(&mut self, queue_index: usize) -> Result<u32> {
+        let base = self.call(|m| m.get_vring_base(queue_index))?;
+        self.state.vring(queue_index).base = Some(base as u16);
+        Ok(base)
+    }
+
+    fn set_vring_call(&mut self, queue_index: usize, fd: &EventFd) -> Result<()> {
+        let fd = clone_eventfd(fd)?;
+        self.call(|m| m.set_vring_call(queue_index, &fd))?;
+        self.state.vring(queue_index).call = Some(fd);
+        Ok(())
+    }
+
+    fn set_vring_kick(&mut self, queue_index: usize, fd: &EventFd) -> Result<()> {
+        let fd = clone_eventfd(fd)?;
+        self.call(|m| m.set_vring_kick(queue_index, &fd))?;
+        let vring = self.state.vring(queue_index);
+        vring.kick = Some(fd);
+        vring.inband = false;
+        Ok(())
+    }
+
+    fn set_vring_err(&mut self, queue_index: usize, fd: &EventFd) -> Result<()> {
+        let fd = clone_eventfd(fd)?;
+        self.call(|m| m.set_vring_err(queue_index, &fd))?;
+        self.state.vring(queue_index).err = Some(fd);
+        Ok(())
+    }
+}
+
+impl VhostUserMaster for ReconnectingMaster {
+    fn get_protocol_features(&mut self) -> Result<VhostUserProtocolFeatures> {
+        self.call(|m| m.get_protocol_features())
+    }
+
+    fn set_protocol_features(&mut self, features: VhostUserProtocolFeatures) -> Result<()> {
+        self.call(|m| m.set_protocol_features(features))?;
+        self.state.protocol_features = Some(features);
+        Ok(())
+    }
+
+    fn get_queue_num(&mut self) -> Result<u64> {
+        let num = self.call(|m| m.get_queue_num())?;
+        self.state.queue_num_queried = true;
+        Ok(num)
+    }
+
+    fn set_vring_enable(&mut self, queue_index: usize, enable: bool) -> Result<()> {
+        self.call(|m| m.set_vring_enable(queue_index, enable))?;
+        self.state.vring(queue_index).enabled = enable;
+        Ok(())
+    }
+
+    fn set_vring_endian(&mut self, queue_index: usize, big_endian: bool) -> Result<()> {
+        self.call(|m| m.set_vring_endian(queue_index, big_endian))?;
+        self.state.vring(queue_index).big_endian = Some(big_endian);
+        Ok(())
+    }
+
+    fn send_rarp(&mut self, mac: &[u8; 6]) -> Result<()> {
+        self.call(|m| m.send_rarp(mac))
+    }
+
+    fn net_set_mtu(&mut self, mtu: u16) -> Result<()> {
+        self.call(|m| m.net_set_mtu(mtu))?;
+        self.state.mtu = Some(mtu);
+        Ok(())
+    }
+
+    fn get_config(
+        &mut self,
+        offset: u32,
+        size: u32,
+        flags: VhostUserConfigFlags,
+        buf: &[u8],
+    ) -> Result<(VhostUserConfig, VhostUserConfigPayload)> {
+        self.call(|m| m.get_config(offset, size, flags, buf))
+    }
+
+    fn set_config(&mut self, offset: u32, flags: VhostUserConfigFlags, buf: &[u8]) -> Result<()> {
+        self.call(|m| m.set_config(offset, flags, buf))
+    }
+
+    fn set_slave_request_fd(&mut self, fd: RawFd) -> Result<()> {
+        self.call(|m| m.set_slave_request_fd(fd))
+    }
+
+    fn set_gpu_socket(&mut self, fd: RawFd) -> Result<()> {
+        self.call(|m| m.set_gpu_socket(fd))
+    }
+
+    fn get_inflight_fd(
+        &mut self,
+        inflight: &VhostUserInflight,
+    ) -> Result<(VhostUserInflight, File)> {
+        self.call(|m| m.get_inflight_fd(inflight))
+    }
+
+    fn set_inflight_fd(&mut self, inflight: &VhostUserInflight, fd: RawFd) -> Result<()> {
+        self.call(|m| m.set_inflight_fd(inflight, fd))?;
+        // Keep the buffer alive, it's the only way for the new slave to recover inflight I/Os.
+        // Safe because fcntl() doesn't touch memory, and the result is checked.
+        let dup = unsafe { libc::fcntl(fd, libc::F_DUPFD_CLOEXEC, 0) };
+        if dup < 0 {
+            return Err(Error::IOError(std::io::Error::last_os_error()));
+        }
+        // Safe because we own the duplicated file descriptor.
+        let file = unsafe { File::from_raw_fd(dup) };
+        self.state.inflight = Some((*inflight, file));
+        Ok(())
+    }
+
+    fn postcopy_advise(&mut self) -> Result<File> {
+        self.call(|m| m.postcopy_advise())
+    }
+
+    fn postcopy_listen(&mut self) -> Result<()> {
+        self.call(|m| m.postcopy_listen())
+    }
+
+    fn postcopy_end(&mut self) -> Result<()> {
+        self.call(|m| m.postcopy_end())
+    }
+
+    fn send_iotlb_msg(&mut self, iotlb: &VhostUserIotlb) -> Result<()> {
+        self.call(|m| m.send_iotlb_msg(iotlb))
+    }
+
+    fn reset_device(&mut self) -> Result<()> {
+        self.call(|m| m.reset_device())?;
+        let state = &mut self.state;
+        state.features = None;
+        state.inflight = None;
+        state.vrings.clear();
+        state.status = None;
+        Ok(())
+    }
+
+    fn set_status(&mut self, status: u8) -> Result<()> {
+        self.call(|m| m.set_status(status))?;
+        self.state.status = Some(status);
+        Ok(())
+    }
+
+    fn get_status(&mut self) -> Result<u8> {
+        self.call(|m| m.get_status())
+    }
+
+    fn get_shared_object(&mut self, shared: &VhostUserShared) -> Result<File> {
+        self.call(|m| m.get_shared_object(shared))
+    }
+
+    fn set_device_state_fd(
+        &mut self,
+        direction: VhostTransferStateDirection,
+        phase: VhostTransferStatePhase,
+        fd: RawFd,
+    ) -> Result<Option<File>> {
+        self.call(|m| m.set_device_state_fd(direction, phase, fd))
+    }
+
+    fn check_device_state(&mut self) -> Result<()> {
+        self.call(|m| m.check_device_state())
+    }
+
+    fn get_shared_memory_regions(&mut self) -> Result<Vec<u64>> {
+        self.call(|m| m.get_shared_memory_regions())
+    }
+
+    fn set_mem_table_xen(
+        &mut self,
+        regions: &[VhostUserMemoryRegionInfo],
+        xen_mmaps: &[VhostUserXenMmap],
+    ) -> Result<()> {
+        self.call(|m| m.set_mem_table_xen(regions, xen_mmaps))?;
+        self.state.mem_regions = regions.to_vec();
+        self.state.xen_mmaps = xen_mmaps.to_vec();
+        Ok(())
+    }
+
+    fn add_mem_region_xen(
+        &mut self,
+        region: &VhostUserMemoryRegionInfo,
+        xen_mmap: VhostUserXenMmap,
+    ) -> Result<()> {
+        self.call(|m| m.add_mem_region_xen(region, xen_mmap))?;
+        self.state.mem_regions.push(*region);
+        self.state.xen_mmaps.push(xen_mmap);
+        Ok(())
+    }
+
+    fn set_vring_inband(&mut self, queue_index: usize) -> Result<()> {
+        self.call(|m| m.set_vring_inband(queue_index))?;
+        let vring = self.state.vring(queue_index);
+        vring.call = None;
+        vring.err = None;
+        vring.kick = None;
+        vring.inband = true;
+        Ok(())
+    }
+
+    fn vring_kick(&mut self, queue_index: usize) -> Result<()> {
+        self.call(|m| m.vring_kick(queue_index))
+    }
+
+    fn get_max_mem_slots(&mut self) -> Result<u64> {
+        self.call(|m| m.get_max_mem_slots())
+    }
+
+    fn add_mem_region(&mut self, region: &VhostUserMemoryRegionInfo) -> Result<()> {
+        self.call(|m| m.add_mem_region(region))?;
+        self.state.mem_regions.push(*region);
+        self.state.xen_mmaps.push(VhostUserXenMmap::default());
+        Ok(())
+    }
+
+    fn remove_mem_region(&mut self, region: &VhostUserMemoryRegionInfo) -> Result<()> {
+        self.call(|m| m.remove_mem_region(region))?;
+        let state = &mut self.state;
+        while let Some(index) = state.mem_regions.iter().position(|r| {
+            r.guest_phys_addr == region.guest_phys_addr && r.memory_size == region.memory_size
+        }) {
+            state.mem_regions.remove(index);
+            state.xen_mmaps.remove(index);
+        }
+        Ok(())
+    }
+}
+
+impl AsRawFd for ReconnectingMaster {
+    fn as_raw_fd(&self) -> RawFd {
+        self.master.as_raw_fd()
+    }
+}
@@ -0,0 +1,326 @@
+// Copyright (C) 2020 Alibaba Cloud Computing. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! A generic vhost-user slave backend for devices with a single request virtqueue.
+//!
+//! Many simple devices, like virtio-rng, virtio-i2c or virtio-scmi, have a single virtqueue on
+//! which each descriptor chain carries a request in its readable buffers and receives the
+//! response in its writable buffers. `RequestBackend` takes care of the virtqueue and of the
+//! guest memory, and delegates the device logic to three closures:
+//!
+//! * `decode` parses the readable part of a chain, given the size of its writable part, into a
+//!   request;
+//! * `handle` processes the request into a response, and may keep the state of the device;
+//! * `encode` serializes the response into the bytes written to the writable part of the chain.
+//!
+//! Chains whose request can't be decoded are returned to the driver without response.
+
+use std::cmp;
+use std::io::{self, Read, Write};
+use std::sync::Mutex;
+
+use vm_memory::GuestMemoryMmap;
+
+use super::daemon::{read_config_space, DescriptorChain, VhostUserBackend, Vring};
+use super::message::VhostUserProtocolFeatures;
+use crate::features::VirtioFeatures;
+
+// Size of the virtqueue.
+const QUEUE_SIZE: usize = 128;
+// Largest request accepted from the driver.
+const MAX_REQUEST_SIZE: usize = 64 * 1024;
+
+type DecodeFn<Q> = dyn Fn(&[u8], usize) -> io::Result<Q> + Send + Sync;
+type HandleFn<Q, P> = dyn FnMut(Q) -> P + Send;
+type EncodeFn<P> = dyn Fn(P) -> Vec<u8> + Send + Sync;
+
+/// A backend serving a single request virtqueue with user supplied closures.
+///
+/// `Q` is the type of the requests, and `P` the type of the responses.
+pub struct RequestBackend<Q, P> {
+    features: u64,
+    config: Vec<u8>,
+    decode: Box<DecodeFn<Q>>,
+    handle: Mutex<Box<HandleFn<Q, P>>>,
+    encode: Box<EncodeFn<P>>,
+    mem: Option<GuestMemoryMmap>,
+}
+
+impl<Q: 'static, P: 'static> RequestBackend<Q, P> {
+    /// Create a backend decoding, handling and encoding requests with the given closures.
+    pub fn new<D, H, E>(decode: D, handle: H, encode: E) -> Self
+    where
+        D: Fn(&[u8], usize) -> io::Result<Q> + Send + Sync + 'static,
+        H: FnMut(Q) -> P + Send + 'static,
+        E: Fn(P) -> Vec<u8> + Send + Sync + 'static,
+    {
+        RequestBackend {
+            features: 0,
+            config: Vec::new(),
+            decode: Box::new(decode),
+            handle: Mutex::new(Box::new(handle)),
+            encode: Box::new(encode),
+            mem: None,
+        }
+    }
+
+    /// Set the virtio features specific to the device type, offered together with the common
+    /// VERSION_1 and PROTOCOL_FEATURES features.
+    pub fn set_features(&mut self, features: u64) {
+        self.features = features;
+    }
+
+    /// Set the read only configuration space of the device.
+    pub fn set_config_space(&mut self, config: Vec<u8>) {
+        self.config = config;
+    }
+
+    // Process a request, returning the number of bytes written into the chain.
+    fn process_request(&self, mem: &GuestMemoryMmap, chain: &DescriptorChain) -> io::Result<u32> {
+        let mut reader = chain.reader(mem);
+        // Oversized requests get no response either.
+        if reader.available_bytes() > MAX_REQUEST_SIZE {
+            return Ok(0);
+        }
+        let mut request = Vec::new();
+        reader.read_to_end(&mut request)?;
+        let mut writer = chain.writer(mem);
+        let request = match (self.decode)(&request, writer.available_bytes()) {
+            Ok(request) => request,
+            Err(_) => return Ok(0),
+        };
+        let response = (self.handle.lock().unwrap())(request);
+        let response = (self.encode)(response);
+
+        // Responses too large for the buffers are truncated.
+        let len = cmp::min(response.len(), writer.available_bytes());
+        writer.write_all(&response[..len])?;
+        Ok(len as u32)
+    }
+}
+
+impl<Q: 'static, P: 'static> VhostUserBackend for RequestBackend<Q, P> {
+    fn num_queues(&self) -> usize {
+        1
+    }
+
+    fn max_queue_size(&self) -> usize {
+        QUEUE_SIZE
+    }
+
+    fn features(&self) -> u64 {
+        self.features | (VirtioFeatures::VERSION_1 | VirtioFeatures::PROTOCOL_FEATURES).bits()
+    }
+
+    fn protocol_features(&self) -> VhostUserProtocolFeatures {
+        VhostUserProtocolFeatures::CONFIG
+    }
+
+    fn update_memory(&mut self, mem: GuestMemoryMmap) -> io::Result<()> {
+        self.mem = Some(mem);
+        Ok(())
+    }
+
+    fn get_config(&self, offset: u32, size: u32) -> Vec<u8> {
+        read_config_space(&self.config, offset, size)
+    }
+
+    fn set_config(&mut self, _offset: u32, _buf: &[u8]) -> io::Result<()> {
+        Err(io::Error::from_raw_os_error(libc::EPERM))
+    }
+
+    fn process_queue(
+        &self,
+        _queue_index: u16,
+        vring: &mut Vring,
+        _thread_id: usize,
+    ) -> io::Result<()> {
+        let mem = match self.mem {
+            Some(ref mem) => mem,
+            None => return Ok(()),
+        };
+        let mut used = false;
+        while let Some(chain) = vring.pop_avail(mem)? {
+            let len = self.process_request(mem, &chain)?;
+            vring.add_used(mem, chain.head_index(), len)?;
+            used = true;
+        }
+        if used {
+            vring.notify_used_queue(mem)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::{
+        VhostFeatures, VhostMemory, VhostOwner, VhostUserMemoryRegionInfo, VhostVring,
+        VringConfigData,
+    };
+    use crate::vhost_user::message::{VhostUserConfigFlags, VHOST_USER_CONFIG_OFFSET};
+    use crate::vhost_user::{Daemon, Listener, Master, VhostUserMaster};
+    use std::os::unix::io::AsRawFd;
+    use std::sync::{Arc, RwLock};
+    use std::thread;
+    use vm_memory::{Bytes, FileOffset, GuestAddress};
+    use vmm_sys_util::eventfd::EventFd;
+    use vmm_sys_util::tempfile::TempFile;
+
+    // Guest physical addresses of the ring and buffers, in a single region mapped at VA_BASE.
+    const VA_BASE: u64 = 0x7f00_0000_0000;
+    const DESC_ADDR: u64 = 0x1000;
+    const AVAIL_ADDR: u64 = 0x2000;
+    const USED_ADDR: u64 = 0x3000;
+    const REQ_BUF: u64 = 0x8000;
+    const RESP_BUF: u64 = 0x9000;
+
+    // Write the descriptor `index` of the ring.
+    fn write_desc(mem: &GuestMemoryMmap, index: u16, addr: u64, len: u32, flags: u16, next: u16) {
+        let desc = DESC_ADDR + u64::from(index) * 16;
+        mem.write_obj(addr, GuestAddress(desc)).unwrap();
+        mem.write_obj(len, GuestAddress(desc + 8)).unwrap();
+        mem.write_obj(flags, GuestAddress(desc + 12)).unwrap();
+        mem.write_obj(next, GuestAddress(desc + 14)).unwrap();
+    }
+
+    // Make the chain headed by `head` available at position `slot` of the avail ring.
+    fn add_avail(mem: &GuestMemoryMmap, slot: u16, head: u16) {
+        mem.write_obj(head, GuestAddress(AVAIL_ADDR + 4 + u64::from(slot) * 2))
+            .unwrap();
+        mem.write_obj(slot + 1, GuestAddress(AVAIL_ADDR + 2))
+            .unwrap();
+    }
+
+    #[test]
+    fn test_request_backend() {
+        let path = "/tmp/vhost_user_lib_unit_test_request_backend";
+        // A device reversing the requests, and counting them in its responses.
+        let mut count = 0u8;
+        let mut backend = RequestBackend::new(
+            |req: &[u8], size: usize| {
+                if req.is_empty() || size == 0 {
+                    return Err(io::Error::from_raw_os_error(libc::EINVAL));
+                }
+                Ok(req.to_vec())
+            },
+            move |mut req: Vec<u8>| {
+                count += 1;
+                req.reverse();
+                (count, req)
+            },
+            |(count, mut resp): (u8, Vec<u8>)| {
+                resp.insert(0, count);
+                resp
+            },
+        );
+        backend.set_features(1 << 3);
+        backend.set_config_space(vec![1, 2, 3, 4]);
+        let backend = Arc::new(RwLock::new(backend));
+        let mut daemon = Daemon::new("test-request".to_string(), backend).unwrap();
+
+        let file = TempFile::new().unwrap().into_file();
+        file.set_len(0x10000).unwrap();
+        let mem = GuestMemoryMmap::from_ranges_with_files(&[(
+            GuestAddress(0),
+            0x10000,
+            Some(FileOffset::new(file.try_clone().unwrap(), 0)),
+        )])
+        .unwrap();
+
+        let listener = Listener::new(path, true).unwrap();
+        let master_thread = thread::spawn(move || {
+            let mut master = Master::connect(path, 1).unwrap();
+            master.set_owner().unwrap();
+            let features = master.get_features().unwrap();
+            assert_ne!(features & (1 << 3), 0);
+            master.set_features(features).unwrap();
+            master.get_protocol_features().unwrap();
+            master
+                .set_protocol_features(VhostUserProtocolFeatures::CONFIG)
+                .unwrap();
+            let (_, config) = master
+                .get_config(
+                    VHOST_USER_CONFIG_OFFSET + 2,
+                    4,
+                    VhostUserConfigFlags::WRITABLE,
+                    &[0u8; 4],
+                )
+                .unwrap();
+            assert_eq!(&config[..], &[3, 4, 0, 0]);
+
+            let region = VhostUserMemoryRegionInfo {
+                guest_phys_addr: 0,
+                memory_size: 0x10000,
+                userspace_addr: VA_BASE,
+                mmap_offset: 0,
+                mmap_handle: file.as_raw_fd(),
+            };
+            master.set_mem_table(&[region]).unwrap();
+            master.set_vring_num(0, 16).unwrap();
+            let config = VringConfigData {
+                queue_max_size: QUEUE_SIZE as u16,
+                queue_size: 16,
+                flags: 0,
+                desc_table_addr: VA_BASE + DESC_ADDR,
+                used_ring_addr: VA_BASE + USED_ADDR,
+                avail_ring_addr: VA_BASE + AVAIL_ADDR,
+                log_addr: None,
+            };
+            master.set_vring_addr(0, &config).unwrap();
+            master.set_vring_base(0, 0).unwrap();
+            let call = EventFd::new(0).unwrap();
+            master.set_vring_call(0, &call).unwrap();
+            master.set_vring_enable(0, true).unwrap();
+            let kick = EventFd::new(0).unwrap();
+            master.set_vring_kick(0, &kick).unwrap();
+
+            // The response is written into the writable descriptor of the chain.
+            mem.write_slice(b"abc", GuestAddress(REQ_BUF)).unwrap();
+            write_desc(&mem, 0, REQ_BUF, 3, 1, 1);
+            write_desc(&mem, 1, RESP_BUF, 0x100, 2, 0);
+            add_avail(&mem, 0, 0);
+            kick.write(1).unwrap();
+            assert_eq!(call.read().unwrap(), 1);
+            let len: u32 = mem.read_obj(GuestAddress(USED_ADDR + 8)).unwrap();
+            assert_eq!(len, 4);
+            let mut buf = [0u8; 4];
+            mem.read_slice(&mut buf, GuestAddress(RESP_BUF)).unwrap();
+            assert_eq!(&buf, &[1, b'c', b'b', b'a']);
+
+            // Responses larger than the writable descriptors are truncated.
+            write_desc(&mem, 2, REQ_BUF, 3, 1, 3);
+            write_desc(&mem, 3, RESP_BUF, 2, 2, 0);
+            add_avail(&mem, 1, 2);
+            kick.write(1).unwrap();
+            assert_eq!(call.read().unwrap(), 1);
+            let len: u32 = mem.read_obj(GuestAddress(USED_ADDR + 16)).unwrap();
+            assert_eq!(len, 2);
+
+            // Requests which can't be decoded get no response.
+            write_desc(&mem, 4, REQ_BUF, 3, 0, 0);
+            add_avail(&mem, 2, 4);
+            kick.write(1).unwrap();
+            assert_eq!(call.read().unwrap(), 1);
+            let id: u32 = mem.read_obj(GuestAddress(USED_ADDR + 20)).unwrap();
+            let len: u32 = mem.read_obj(GuestAddress(USED_ADDR + 24)).unwrap();
+            assert_eq!((id, len), (4, 0));
+
+            // Neither do requests larger than MAX_REQUEST_SIZE.
+            write_desc(&mem, 5, 0, 0x8001, 1, 6);
+            write_desc(&mem, 6, 0, 0x8001, 1, 7);
+            write_desc(&mem, 7, RESP_BUF, 0x100, 2, 0);
+            add_avail(&mem, 3, 5);
+            kick.write(1).unwrap();
+            assert_eq!(call.read().unwrap(), 1);
+            let id: u32 = mem.read_obj(GuestAddress(USED_ADDR + 28)).unwrap();
+            let len: u32 = mem.read_obj(GuestAddress(USED_ADDR + 32)).unwrap();
+            assert_eq!((id, len), (5, 0));
+        });
+
+        daemon.start(listener).unwrap();
+        master_thread.join().unwrap();
+        assert!(daemon.wait().is_err());
+    }
+}
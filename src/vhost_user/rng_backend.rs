@@ -0,0 +1,219 @@
+// Copyright (C) 2020 Alibaba Cloud Computing. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! A vhost-user-rng slave backend built on the daemon framework.
+//!
+//! The device has a single virtqueue, on which the driver makes writable buffers available.
+//! Each buffer is filled with bytes read from the entropy source of the backend, usually
+//! `/dev/urandom`, and is returned to the driver with the number of bytes read.
+
+use std::cmp;
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::path::Path;
+use std::sync::Mutex;
+
+use vm_memory::GuestMemoryMmap;
+
+use super::daemon::{DescriptorChain, VhostUserBackend, Vring};
+use super::message::VhostUserProtocolFeatures;
+use crate::features::VirtioFeatures;
+
+// Size of the virtqueue.
+const QUEUE_SIZE: usize = 128;
+// Largest number of bytes read from the source for a buffer.
+const MAX_CHUNK_SIZE: usize = 4096;
+
+/// A backend filling the buffers of the guest with bytes read from an entropy source.
+pub struct RngBackend {
+    source: Mutex<Box<dyn Read + Send>>,
+    mem: Option<GuestMemoryMmap>,
+}
+
+impl RngBackend {
+    /// Create a backend reading the entropy from `source`.
+    pub fn new<R: Read + Send + 'static>(source: R) -> Self {
+        RngBackend {
+            source: Mutex::new(Box::new(source)),
+            mem: None,
+        }
+    }
+
+    /// Create a backend reading the entropy from the file at `path`.
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        Ok(Self::new(File::open(path)?))
+    }
+
+    // Fill the buffers of the chain, returning the number of bytes written.
+    fn fill_chain(&self, mem: &GuestMemoryMmap, chain: &DescriptorChain) -> io::Result<u32> {
+        let mut writer = chain.writer(mem);
+        let mut buf = [0u8; MAX_CHUNK_SIZE];
+        let len = cmp::min(writer.available_bytes(), MAX_CHUNK_SIZE);
+        // A failing source is reported to the driver as a short buffer.
+        let count = self
+            .source
+            .lock()
+            .unwrap()
+            .read(&mut buf[..len])
+            .unwrap_or(0);
+        writer.write_all(&buf[..count])?;
+        Ok(count as u32)
+    }
+}
+
+impl VhostUserBackend for RngBackend {
+    fn num_queues(&self) -> usize {
+        1
+    }
+
+    fn max_queue_size(&self) -> usize {
+        QUEUE_SIZE
+    }
+
+    fn features(&self) -> u64 {
+        (VirtioFeatures::VERSION_1 | VirtioFeatures::PROTOCOL_FEATURES).bits()
+    }
+
+    fn protocol_features(&self) -> VhostUserProtocolFeatures {
+        VhostUserProtocolFeatures::empty()
+    }
+
+    fn update_memory(&mut self, mem: GuestMemoryMmap) -> io::Result<()> {
+        self.mem = Some(mem);
+        Ok(())
+    }
+
+    fn process_queue(
+        &self,
+        _queue_index: u16,
+        vring: &mut Vring,
+        _thread_id: usize,
+    ) -> io::Result<()> {
+        let mem = match self.mem {
+            Some(ref mem) => mem,
+            None => return Ok(()),
+        };
+        let mut used = false;
+        while let Some(chain) = vring.pop_avail(mem)? {
+            let len = self.fill_chain(mem, &chain)?;
+            vring.add_used(mem, chain.head_index(), len)?;
+            used = true;
+        }
+        if used {
+            vring.notify_used_queue(mem)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::{
+        VhostFeatures, VhostMemory, VhostOwner, VhostUserMemoryRegionInfo, VhostVring,
+        VringConfigData,
+    };
+    use crate::vhost_user::{Daemon, Listener, Master, VhostUserMaster};
+    use std::io::Cursor;
+    use std::os::unix::io::AsRawFd;
+    use std::sync::{Arc, RwLock};
+    use std::thread;
+    use vm_memory::{Bytes, FileOffset, GuestAddress};
+    use vmm_sys_util::eventfd::EventFd;
+    use vmm_sys_util::tempfile::TempFile;
+
+    // Guest physical addresses of the ring and buffers, in a single region mapped at VA_BASE.
+    const VA_BASE: u64 = 0x7f00_0000_0000;
+    const DESC_ADDR: u64 = 0x1000;
+    const AVAIL_ADDR: u64 = 0x2000;
+    const USED_ADDR: u64 = 0x3000;
+    const BUF_ADDR: u64 = 0x8000;
+
+    // Make the writable buffer at `addr` available as descriptor `index` of the vring.
+    fn add_avail(mem: &GuestMemoryMmap, index: u16, addr: u64, len: u32) {
+        let desc = DESC_ADDR + u64::from(index) * 16;
+        mem.write_obj(addr, GuestAddress(desc)).unwrap();
+        mem.write_obj(len, GuestAddress(desc + 8)).unwrap();
+        mem.write_obj(2u16, GuestAddress(desc + 12)).unwrap();
+        mem.write_obj(index, GuestAddress(AVAIL_ADDR + 4 + u64::from(index) * 2))
+            .unwrap();
+        mem.write_obj(index + 1, GuestAddress(AVAIL_ADDR + 2))
+            .unwrap();
+    }
+
+    #[test]
+    fn test_rng_backend() {
+        let path = "/tmp/vhost_user_lib_unit_test_rng_backend";
+        let source: Vec<u8> = (0..24).collect();
+        let backend = Arc::new(RwLock::new(RngBackend::new(Cursor::new(source))));
+        let mut daemon = Daemon::new("test-rng".to_string(), backend).unwrap();
+
+        let file = TempFile::new().unwrap().into_file();
+        file.set_len(0x10000).unwrap();
+        let mem = GuestMemoryMmap::from_ranges_with_files(&[(
+            GuestAddress(0),
+            0x10000,
+            Some(FileOffset::new(file.try_clone().unwrap(), 0)),
+        )])
+        .unwrap();
+
+        let listener = Listener::new(path, true).unwrap();
+        let master_thread = thread::spawn(move || {
+            let mut master = Master::connect(path, 1).unwrap();
+            master.set_owner().unwrap();
+            let features = master.get_features().unwrap();
+            master.set_features(features).unwrap();
+            master.get_protocol_features().unwrap();
+            master
+                .set_protocol_features(VhostUserProtocolFeatures::empty())
+                .unwrap();
+
+            let region = VhostUserMemoryRegionInfo {
+                guest_phys_addr: 0,
+                memory_size: 0x10000,
+                userspace_addr: VA_BASE,
+                mmap_offset: 0,
+                mmap_handle: file.as_raw_fd(),
+            };
+            master.set_mem_table(&[region]).unwrap();
+            master.set_vring_num(0, 16).unwrap();
+            let config = VringConfigData {
+                queue_max_size: QUEUE_SIZE as u16,
+                queue_size: 16,
+                flags: 0,
+                desc_table_addr: VA_BASE + DESC_ADDR,
+                used_ring_addr: VA_BASE + USED_ADDR,
+                avail_ring_addr: VA_BASE + AVAIL_ADDR,
+                log_addr: None,
+            };
+            master.set_vring_addr(0, &config).unwrap();
+            master.set_vring_base(0, 0).unwrap();
+            let call = EventFd::new(0).unwrap();
+            master.set_vring_call(0, &call).unwrap();
+            master.set_vring_enable(0, true).unwrap();
+            let kick = EventFd::new(0).unwrap();
+            master.set_vring_kick(0, &kick).unwrap();
+
+            add_avail(&mem, 0, BUF_ADDR, 16);
+            kick.write(1).unwrap();
+            assert_eq!(call.read().unwrap(), 1);
+            let len: u32 = mem.read_obj(GuestAddress(USED_ADDR + 8)).unwrap();
+            assert_eq!(len, 16);
+            let mut buf = [0u8; 16];
+            mem.read_slice(&mut buf, GuestAddress(BUF_ADDR)).unwrap();
+            assert_eq!(buf[0], 0);
+            assert_eq!(buf[15], 15);
+
+            // Buffers are filled with what remains once the source is exhausted.
+            add_avail(&mem, 1, BUF_ADDR, 16);
+            kick.write(1).unwrap();
+            assert_eq!(call.read().unwrap(), 1);
+            let len: u32 = mem.read_obj(GuestAddress(USED_ADDR + 16)).unwrap();
+            assert_eq!(len, 8);
+        });
+
+        daemon.start(listener).unwrap();
+        master_thread.join().unwrap();
+        assert!(daemon.wait().is_err());
+    }
+}
@@ -5,14 +5,23 @@
 
 use std::sync::{Arc, Mutex};
 
-use super::connection::{Endpoint, Listener};
+use super::connection::{Endpoint, Listener, PeerCredentials};
 use super::message::*;
 use super::{Result, SlaveReqHandler, VhostUserSlaveReqHandler};
 
+type PeerCheck = Box<dyn Fn(&PeerCredentials) -> bool + Send>;
+
 /// Vhost-user slave side connection listener.
+///
+/// The listener may accept a new connection once the master has disconnected, for instance
+/// because it has been restarted. The protocol state of the backend is then reset by
+/// `VhostUserSlaveReqHandler::reset_session()` before serving the new master.
 pub struct SlaveListener<S: VhostUserSlaveReqHandler> {
     listener: Listener,
-    backend: Option<Arc<Mutex<S>>>,
+    backend: Arc<Mutex<S>>,
+    connected: bool,
+    preserve_inflight: bool,
+    peer_check: Option<PeerCheck>,
 }
 
 /// Sets up a listener for incoming master connections, and handles construction
@@ -22,23 +31,57 @@ impl<S: VhostUserSlaveReqHandler> SlaveListener<S> {
     pub fn new(listener: Listener, backend: Arc<Mutex<S>>) -> Result<Self> {
         Ok(SlaveListener {
             listener,
-            backend: Some(backend),
+            backend,
+            connected: false,
+            preserve_inflight: false,
+            peer_check: None,
         })
     }
 
     /// Accept an incoming connection from the master, returning Some(Slave) on
     /// success, or None if the socket is nonblocking and no incoming connection
     /// was detected
+    ///
+    /// The slave returned for the previous connection, if any, should have been dropped.
+    /// Connections from peers rejected by the check set with `set_peer_check()` are closed,
+    /// and the next connection is waited for.
     pub fn accept(&mut self) -> Result<Option<SlaveReqHandler<S>>> {
-        if let Some(fd) = self.listener.accept()? {
+        while let Some(fd) = self.listener.accept()? {
+            if let Some(ref check) = self.peer_check {
+                if !check(&PeerCredentials::from_stream(&fd)?) {
+                    continue;
+                }
+            }
+            if self.connected {
+                self.backend
+                    .lock()
+                    .unwrap()
+                    .reset_session(self.preserve_inflight)?;
+            }
+            self.connected = true;
             return Ok(Some(SlaveReqHandler::new(
                 Endpoint::<MasterReq>::from_stream(fd),
-                self.backend.take().unwrap(),
+                self.backend.clone(),
             )));
         }
         Ok(None)
     }
 
+    /// Only accept connections from masters whose credentials pass `check`, to restrict which
+    /// processes may drive the backend.
+    pub fn set_peer_check<F>(&mut self, check: F)
+    where
+        F: Fn(&PeerCredentials) -> bool + Send + 'static,
+    {
+        self.peer_check = Some(Box::new(check));
+    }
+
+    /// Keep the inflight I/O tracking buffer when accepting a new connection, so the new master
+    /// may resubmit the I/Os which were inflight when the previous master disconnected.
+    pub fn set_preserve_inflight(&mut self, preserve: bool) {
+        self.preserve_inflight = preserve;
+    }
+
     /// Change blocking status on the listener.
     pub fn set_nonblocking(&self, block: bool) -> Result<()> {
         self.listener.set_nonblocking(block)
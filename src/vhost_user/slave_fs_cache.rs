@@ -1,40 +1,25 @@
 // Copyright (C) 2020 Alibaba Cloud Computing. All rights reserved.
 // SPDX-License-Identifier: Apache-2.0
 
-use super::connection::Endpoint;
 use super::message::*;
-use super::{Error, HandlerResult, Result, VhostUserMasterReqHandler};
+use super::{HandlerResult, MasterReqSender, Result, VhostUserMasterReqHandler};
 use std::io;
-use std::mem;
 use std::os::unix::io::RawFd;
 use std::os::unix::net::UnixStream;
-use std::sync::{Arc, Mutex};
-
-struct SlaveFsCacheReqInternal {
-    sock: Endpoint<SlaveReq>,
-}
 
 /// A vhost-user slave endpoint which sends fs cache requests to the master
 #[derive(Clone)]
 pub struct SlaveFsCacheReq {
-    // underlying Unix domain socket for communication
-    node: Arc<Mutex<SlaveFsCacheReqInternal>>,
-
-    // whether the endpoint has encountered any failure
-    error: Option<i32>,
+    // underlying communication channel to the master
+    sender: MasterReqSender,
 }
 
 impl SlaveFsCacheReq {
-    fn new(ep: Endpoint<SlaveReq>) -> Self {
-        SlaveFsCacheReq {
-            node: Arc::new(Mutex::new(SlaveFsCacheReqInternal { sock: ep })),
-            error: None,
-        }
-    }
-
     /// Create a new instance.
     pub fn from_stream(sock: UnixStream) -> Self {
-        Self::new(Endpoint::<SlaveReq>::from_stream(sock))
+        SlaveFsCacheReq {
+            sender: MasterReqSender::from_stream(sock),
+        }
     }
 
     fn send_message(
@@ -43,39 +28,18 @@ impl SlaveFsCacheReq {
         fs: &VhostUserFSSlaveMsg,
         fds: Option<&[RawFd]>,
     ) -> Result<u64> {
-        self.check_state()?;
-
-        let len = mem::size_of::<VhostUserFSSlaveMsg>();
-        let mut hdr = VhostUserMsgHeader::new(flags, 0, len as u32);
-        hdr.set_need_reply(true);
-        self.node.lock().unwrap().sock.send_message(&hdr, fs, fds)?;
-
-        self.wait_for_ack(&hdr)
-    }
-
-    fn wait_for_ack(&mut self, hdr: &VhostUserMsgHeader<SlaveReq>) -> Result<u64> {
-        self.check_state()?;
-        let (reply, body, rfds) = self.node.lock().unwrap().sock.recv_body::<VhostUserU64>()?;
-        if !reply.is_reply_for(&hdr) || rfds.is_some() || !body.is_valid() {
-            Endpoint::<SlaveReq>::close_rfds(rfds);
-            return Err(Error::InvalidMessage);
-        }
-        if body.value != 0 {
-            return Err(Error::MasterInternalError);
-        }
-        Ok(0)
-    }
-
-    fn check_state(&self) -> Result<u64> {
-        match self.error {
-            Some(e) => Err(Error::SocketBroken(std::io::Error::from_raw_os_error(e))),
-            None => Ok(0),
-        }
+        self.sender.send_message(flags, fs, fds, true)
     }
 
     /// Mark endpoint as failed with specified error code.
     pub fn set_failed(&mut self, error: i32) {
-        self.error = Some(error);
+        self.sender.set_failed(error);
+    }
+}
+
+impl From<MasterReqSender> for SlaveFsCacheReq {
+    fn from(sender: MasterReqSender) -> Self {
+        SlaveFsCacheReq { sender }
     }
 }
 
@@ -91,4 +55,16 @@ impl VhostUserMasterReqHandler for SlaveFsCacheReq {
         self.send_message(SlaveReq::FS_UNMAP, fs, None)
             .or_else(|e| Err(io::Error::new(io::ErrorKind::Other, format!("{}", e))))
     }
+
+    /// Handle virtio-fs sync file requests from the slave.
+    fn fs_slave_sync(&mut self, fs: &VhostUserFSSlaveMsg) -> HandlerResult<u64> {
+        self.send_message(SlaveReq::FS_SYNC, fs, None)
+            .map_err(|e| io::Error::other(format!("{}", e)))
+    }
+
+    /// Handle virtio-fs file IO requests from the slave.
+    fn fs_slave_io(&mut self, fs: &VhostUserFSSlaveMsg, fd: RawFd) -> HandlerResult<u64> {
+        self.send_message(SlaveReq::FS_IO, fs, Some(&[fd]))
+            .map_err(|e| io::Error::other(format!("{}", e)))
+    }
 }
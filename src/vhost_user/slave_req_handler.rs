@@ -3,16 +3,21 @@
 
 //! Traits and Structs to handle vhost-user requests from the master to the slave.
 
+use std::fs::File;
 use std::mem;
-use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::os::unix::io::{AsRawFd, FromRawFd, IntoRawFd, RawFd};
 use std::os::unix::net::UnixStream;
-use std::slice;
 use std::sync::{Arc, Mutex};
 
+use vmm_sys_util::eventfd::EventFd;
+
 use super::connection::Endpoint;
+use super::gpu::GpuReqSender;
 use super::message::*;
-use super::slave_fs_cache::SlaveFsCacheReq;
-use super::{Error, Result};
+use super::parser;
+use super::{Error, MasterReqSender, RateLimitPolicy, RateLimiter, Result, SharedMemoryHook};
+
+type RequestFilter = Box<dyn FnMut(MasterReq, u32, usize) -> bool + Send>;
 
 /// Trait to handle vhost-user requests from the master to the slave.
 #[allow(missing_docs)]
@@ -42,6 +47,9 @@ pub trait VhostUserSlaveReqHandler {
     fn set_protocol_features(&mut self, features: u64) -> Result<()>;
     fn get_queue_num(&mut self) -> Result<u64>;
     fn set_vring_enable(&mut self, index: u32, enable: bool) -> Result<()>;
+    fn set_vring_endian(&mut self, index: u32, big_endian: bool) -> Result<()>;
+    fn send_rarp(&mut self, mac: [u8; 6]) -> Result<()>;
+    fn net_set_mtu(&mut self, mtu: u16) -> Result<()>;
     fn get_config(
         &mut self,
         offset: u32,
@@ -49,12 +57,114 @@ pub trait VhostUserSlaveReqHandler {
         flags: VhostUserConfigFlags,
     ) -> Result<Vec<u8>>;
     fn set_config(&mut self, offset: u32, buf: &[u8], flags: VhostUserConfigFlags) -> Result<()>;
-    fn set_slave_req_fd(&mut self, _vu_req: SlaveFsCacheReq) {}
+    fn set_slave_req_fd(&mut self, _vu_req: MasterReqSender) {}
+    fn set_gpu_socket(&mut self, _gpu: GpuReqSender) {}
+    fn get_inflight_fd(
+        &mut self,
+        inflight: &VhostUserInflight,
+    ) -> Result<(VhostUserInflight, File)>;
+    fn set_inflight_fd(&mut self, inflight: &VhostUserInflight, file: File) -> Result<()>;
+    fn postcopy_advise(&mut self) -> Result<File>;
+    fn postcopy_listen(&mut self) -> Result<()>;
+    fn postcopy_end(&mut self) -> Result<()>;
+    fn set_log_base(&mut self, log: &VhostUserLog, file: File) -> Result<()>;
+    fn set_log_fd(&mut self, file: File) -> Result<()>;
+    fn iotlb_msg(&mut self, iotlb: &VhostUserIotlb) -> Result<()>;
+    fn get_max_mem_slots(&mut self) -> Result<u64>;
+    fn add_mem_region(&mut self, region: &VhostUserSingleMemoryRegion, file: File) -> Result<()>;
+    fn remove_mem_region(&mut self, region: &VhostUserSingleMemoryRegion) -> Result<()>;
+    fn reset_session(&mut self, preserve_inflight: bool) -> Result<()>;
+    fn reset_device(&mut self) -> Result<()>;
+    fn set_status(&mut self, status: u8) -> Result<()>;
+    fn get_status(&mut self) -> Result<u8>;
+    fn get_shared_object(&mut self, shared: &VhostUserShared) -> Result<File>;
+    fn set_device_state_fd(
+        &mut self,
+        direction: VhostTransferStateDirection,
+        phase: VhostTransferStatePhase,
+        file: File,
+    ) -> Result<Option<File>>;
+    fn check_device_state(&mut self) -> Result<()>;
+    fn get_shared_memory_regions(&mut self) -> Result<Vec<u64>>;
+    fn set_mem_table_xen(&mut self, ctx: &[VhostUserXenMemoryRegion], fds: &[RawFd]) -> Result<()>;
+    fn add_mem_region_xen(
+        &mut self,
+        region: &VhostUserXenSingleMemoryRegion,
+        file: File,
+    ) -> Result<()>;
+}
+
+/// State of the vhost-user session between the master and the slave.
+///
+/// The session goes through the states in order: requests are only accepted once the session has
+/// reached the state they depend on, and out-of-order requests are rejected with
+/// `Error::OutOfOrderRequest` before reaching the backend.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum SessionState {
+    /// The master hasn't taken the ownership of the session yet. Only requests to query the
+    /// slave's capabilities and to establish the session are accepted.
+    Init,
+    /// The master has taken the ownership of the session by SET_OWNER.
+    Owned,
+    /// The virtio features have been negotiated by SET_FEATURES, so vrings may be configured.
+    FeaturesNegotiated,
+    /// The guest memory has been provided by SET_MEM_TABLE or ADD_MEM_REG, so vring addresses
+    /// may be translated.
+    MemTableSet,
+    /// At least one vring has been started by SET_VRING_KICK.
+    Running,
+}
+
+impl SessionState {
+    // Get the state the session has to reach before accepting the request.
+    fn required_by(code: MasterReq) -> SessionState {
+        match code {
+            // Masters such as QEMU set the call and error notifiers of the vrings before
+            // negotiating the features.
+            MasterReq::SET_VRING_CALL
+            | MasterReq::SET_VRING_ERR
+            | MasterReq::SET_FEATURES
+            | MasterReq::GET_INFLIGHT_FD
+            | MasterReq::SET_INFLIGHT_FD
+            | MasterReq::POSTCOPY_ADVISE
+            | MasterReq::POSTCOPY_LISTEN
+            | MasterReq::POSTCOPY_END
+            | MasterReq::GPU_SET_SOCKET
+            | MasterReq::RESET_DEVICE => SessionState::Owned,
+            MasterReq::SET_MEM_TABLE
+            | MasterReq::ADD_MEM_REG
+            | MasterReq::REM_MEM_REG
+            | MasterReq::SET_LOG_BASE
+            | MasterReq::SET_LOG_FD
+            | MasterReq::SET_VRING_NUM
+            | MasterReq::SET_VRING_BASE
+            | MasterReq::GET_VRING_BASE
+            | MasterReq::SET_VRING_ENABLE
+            | MasterReq::SET_VRING_ENDIAN
+            | MasterReq::SEND_RARP
+            | MasterReq::NET_SET_MTU
+            | MasterReq::GET_SHARED_OBJECT
+            | MasterReq::SET_DEVICE_STATE_FD
+            | MasterReq::CHECK_DEVICE_STATE
+            | MasterReq::IOTLB_MSG => SessionState::FeaturesNegotiated,
+            MasterReq::SET_VRING_ADDR | MasterReq::SET_VRING_KICK => SessionState::MemTableSet,
+            MasterReq::VRING_KICK => SessionState::Running,
+            _ => SessionState::Init,
+        }
+    }
 }
 
 /// A vhost-user slave endpoint which relays all received requests from the
 /// master to the virtio backend device object.
 ///
+/// The virtio and protocol features acked by the master are checked against the ones offered by
+/// the backend's `get_features()` and `get_protocol_features()`: acking features which haven't
+/// been offered fails with `Error::FeatureMismatch`, without reaching the backend.
+///
+/// Once the INBAND_NOTIFICATIONS protocol feature has been negotiated, vrings started without
+/// kick file descriptor get one created by the handler, which signals it on the VRING_KICK
+/// requests, so the backend handles kicks the same way.
+///
 /// The lifetime of the SlaveReqHandler object should be the same as the underline Unix Domain
 /// Socket, so it gets simpler to recover from disconnect.
 pub struct SlaveReqHandler<S: VhostUserSlaveReqHandler> {
@@ -70,8 +180,24 @@ pub struct SlaveReqHandler<S: VhostUserSlaveReqHandler> {
 
     // sending ack for messages without payload
     reply_ack_enabled: bool,
+    // whether a reply has been sent for the request being handled
+    replied: bool,
+    // whether the master has switched to postcopy mode
+    postcopy_listening: bool,
+    // progress of the session, to reject out-of-order requests
+    state: SessionState,
     // whether the endpoint has encountered any failure
     error: Option<i32>,
+    // requests and number of the attached fds which haven't been consumed, if tracked
+    fd_leaks: Option<Vec<(MasterReq, usize)>>,
+    // eventfds signaled on the VRING_KICK requests, for vrings kicked in-band
+    inband_kicks: Vec<(u8, EventFd)>,
+    // buffer receiving the body and payload of the requests
+    rbuf: Vec<u8>,
+    // policy deciding which requests are handled
+    filter: Option<RequestFilter>,
+    // limited requests, all of them if empty, with their rate limit
+    rate_limit: Option<(Vec<MasterReq>, RateLimiter, RateLimitPolicy)>,
 }
 
 impl<S: VhostUserSlaveReqHandler> SlaveReqHandler<S> {
@@ -85,7 +211,15 @@ impl<S: VhostUserSlaveReqHandler> SlaveReqHandler<S> {
             protocol_features: VhostUserProtocolFeatures::empty(),
             acked_protocol_features: 0,
             reply_ack_enabled: false,
+            replied: false,
+            postcopy_listening: false,
+            state: SessionState::Init,
             error: None,
+            fd_leaks: None,
+            inband_kicks: Vec::new(),
+            rbuf: Vec::new(),
+            filter: None,
+            rate_limit: None,
         }
     }
 
@@ -98,6 +232,76 @@ impl<S: VhostUserSlaveReqHandler> SlaveReqHandler<S> {
         Ok(Self::new(Endpoint::<MasterReq>::connect(path)?, backend))
     }
 
+    /// Create a new vhost-user slave endpoint from a connected Unix domain socket, such as one
+    /// end of a socketpair() or a socket passed by systemd socket activation.
+    ///
+    /// Sockets of type SOCK_SEQPACKET may be passed as well, wrapped into a `UnixStream`.
+    pub fn from_stream(sock: UnixStream, backend: Arc<Mutex<S>>) -> Self {
+        Self::new(Endpoint::<MasterReq>::from_stream(sock), backend)
+    }
+
+    /// Transfer the file descriptors attached to the requests and replies with `hook` instead
+    /// of the socket, for transports which can't pass file descriptors such as vsock.
+    pub fn set_shared_memory_hook(&mut self, hook: Box<dyn SharedMemoryHook>) {
+        self.main_sock.set_shared_memory_hook(hook);
+    }
+
+    /// Accept requests whose body and payload take up to `size` bytes, instead of MAX_MSG_SIZE,
+    /// for masters sending bigger messages.
+    ///
+    /// Bigger requests fail with `InvalidMessage` before their body is received. `size` may not
+    /// exceed MAX_JUMBO_MSG_SIZE.
+    pub fn set_max_msg_size(&mut self, size: usize) -> Result<()> {
+        self.main_sock.set_max_msg_size(size)
+    }
+
+    /// Get the current state of the session.
+    pub fn session_state(&self) -> SessionState {
+        self.state
+    }
+
+    /// Only handle the requests passing `filter`, which gets the type, the payload size and the
+    /// number of file descriptors attached to each request before it's parsed.
+    ///
+    /// This adds a line of defense to backends exposed to untrusted masters, for instance to
+    /// deny requests they never expect or oversized ones. Rejected requests fail with
+    /// `Error::RejectedRequest`, after the failure has been acked when REPLY_ACK is negotiated.
+    pub fn set_request_filter<F>(&mut self, filter: F)
+    where
+        F: FnMut(MasterReq, u32, usize) -> bool + Send + 'static,
+    {
+        self.filter = Some(Box::new(filter));
+    }
+
+    /// Limit the rate of the `requests`, or of all requests if empty, with the token bucket
+    /// `limiter`.
+    ///
+    /// Requests beyond the limit fail with `Error::RateLimited`, after the failure has been acked
+    /// when REPLY_ACK is negotiated. With `RateLimitPolicy::Disconnect`, the handler is then marked
+    /// as failed, so the caller drops the connection to the master.
+    pub fn set_rate_limit(
+        &mut self,
+        requests: &[MasterReq],
+        limiter: RateLimiter,
+        policy: RateLimitPolicy,
+    ) {
+        self.rate_limit = Some((requests.to_vec(), limiter, policy));
+    }
+
+    /// Enable or disable the tracking of the file descriptors received from the master which
+    /// haven't been consumed by the request handlers, such as the ones attached to rejected
+    /// requests. Those file descriptors are always closed, this is meant to debug masters and
+    /// backends.
+    pub fn set_fd_leak_check(&mut self, enabled: bool) {
+        self.fd_leaks = if enabled { Some(Vec::new()) } else { None };
+    }
+
+    /// Get the requests and number of attached file descriptors which haven't been consumed
+    /// since the tracking has been enabled with `set_fd_leak_check()`.
+    pub fn fd_leaks(&self) -> &[(MasterReq, usize)] {
+        self.fd_leaks.as_ref().map_or(&[], |leaks| leaks.as_slice())
+    }
+
     /// Mark endpoint as failed with specified error code.
     pub fn set_failed(&mut self, error: i32) {
         self.error = Some(error);
@@ -122,26 +326,111 @@ impl<S: VhostUserSlaveReqHandler> SlaveReqHandler<S> {
         //   message header
         // . validate message body and optional payload
         let (hdr, rfds) = self.main_sock.recv_header()?;
-        let rfds = self.check_attached_rfds(&hdr, rfds)?;
-        let (size, buf) = match hdr.get_size() {
-            0 => (0, vec![0u8; 0]),
-            len => {
-                let (size2, rbuf) = self.main_sock.recv_data(len as usize)?;
-                if size2 != len as usize {
-                    return Err(Error::InvalidMessage);
-                }
-                (size2, rbuf)
+        // The receive buffer is reused from one request to the next, and the handlers get typed
+        // views over it, so requests are handled without allocating or copying their payload.
+        let mut buf = mem::take(&mut self.rbuf);
+        let len = hdr.get_size() as usize;
+        match self.main_sock.recv_data_into(&mut buf, len) {
+            Ok(size2) if size2 == len => {}
+            res => {
+                self.rbuf = buf;
+                Endpoint::<MasterReq>::close_rfds(rfds);
+                return Err(res.err().unwrap_or(Error::InvalidMessage));
             }
+        }
+
+        let res = self.process_request(hdr, rfds, &buf);
+        self.rbuf = buf;
+        res
+    }
+
+    /// Change blocking status on the connection to the master.
+    ///
+    /// In non-blocking mode, requests are received with `handle_readable()` whenever the
+    /// connection, available through `as_raw_fd()`, becomes readable.
+    pub fn set_nonblocking(&self, nonblocking: bool) -> Result<()> {
+        self.main_sock.set_nonblocking(nonblocking)
+    }
+
+    /// Receive the data available on the connection to the master, and handle the pending request
+    /// once it has been completely received.
+    ///
+    /// This is meant for slaves monitoring the connection in their own event loop, after switching
+    /// it to non-blocking mode. Partially received requests are buffered until the rest of the
+    /// request is readable. Only one request is handled per call, so callers should call again
+    /// until false is returned.
+    ///
+    /// # Return:
+    /// * - true if a request has been handled.
+    /// * - false if no complete request is available yet.
+    pub fn handle_readable(&mut self) -> Result<bool> {
+        // Return error if the endpoint is already in failed state.
+        self.check_state()?;
+
+        let (hdr, buf, rfds) = match self.main_sock.handle_readable()? {
+            Some(msg) => msg,
+            None => return Ok(false),
         };
+        self.process_request(hdr, rfds, &buf)?;
+        Ok(true)
+    }
+
+    // Handle a request, and acknowledge it if the master asked for an ack and no reply has been
+    // sent while handling it, so failures are reported to the master as well.
+    fn process_request(
+        &mut self,
+        hdr: VhostUserMsgHeader<MasterReq>,
+        rfds: Option<Vec<RawFd>>,
+        buf: &[u8],
+    ) -> Result<()> {
+        // Decide before handling the request, which may renegotiate REPLY_ACK, as the master
+        // decides with the features negotiated when sending the request.
+        let need_ack = self.reply_ack_enabled && hdr.is_need_reply() && !hdr.get_code().has_reply();
+        self.replied = false;
+        let mut rfds = rfds;
+        let res = self.dispatch_request(hdr, &mut rfds, buf);
+        // The handlers take the file descriptors they consume, close the others so they don't
+        // leak to the slave whatever the outcome of the request.
+        if let Some(fds) = rfds {
+            if let Some(ref mut leaks) = self.fd_leaks {
+                leaks.push((hdr.get_code(), fds.len()));
+            }
+            Endpoint::<MasterReq>::close_rfds(Some(fds));
+        }
+        if need_ack && !self.replied {
+            let ack = self.send_ack(&hdr, &res);
+            return res.and(ack);
+        }
+        res
+    }
+
+    fn dispatch_request(
+        &mut self,
+        hdr: VhostUserMsgHeader<MasterReq>,
+        rfds: &mut Option<Vec<RawFd>>,
+        buf: &[u8],
+    ) -> Result<()> {
+        let size = buf.len();
+        let num_fds = rfds.as_ref().map_or(0, |fds| fds.len());
+        if let Some(ref mut filter) = self.filter {
+            if !filter(hdr.get_code(), hdr.get_size(), num_fds) {
+                return Err(Error::RejectedRequest(hdr.get_code()));
+            }
+        }
+        self.check_rate_limit(&hdr)?;
+        parser::check_msg(&hdr, buf, num_fds)?;
+        self.check_request_order(&hdr)?;
 
         match hdr.get_code() {
             MasterReq::SET_OWNER => {
                 self.check_request_size(&hdr, size, 0)?;
                 self.backend.lock().unwrap().set_owner()?;
+                self.state = SessionState::Owned;
             }
             MasterReq::RESET_OWNER => {
                 self.check_request_size(&hdr, size, 0)?;
                 self.backend.lock().unwrap().reset_owner()?;
+                self.state = SessionState::Init;
             }
             MasterReq::GET_FEATURES => {
                 self.check_request_size(&hdr, size, 0)?;
@@ -152,17 +441,27 @@ impl<S: VhostUserSlaveReqHandler> SlaveReqHandler<S> {
                 self.update_reply_ack_flag();
             }
             MasterReq::SET_FEATURES => {
-                let msg = self.extract_request_body::<VhostUserU64>(&hdr, size, &buf)?;
-                self.backend.lock().unwrap().set_features(msg.value)?;
+                let msg = self.extract_request_body::<VhostUserU64>(&hdr, size, buf)?;
+                let mut backend = self.backend.lock().unwrap();
+                // Only the features offered by the backend may be acked.
+                if msg.value & !backend.get_features()? != 0 {
+                    return Err(Error::FeatureMismatch);
+                }
+                backend.set_features(msg.value)?;
+                drop(backend);
+                self.advance_state(SessionState::FeaturesNegotiated);
                 self.acked_virtio_features = msg.value;
                 self.update_reply_ack_flag();
             }
             MasterReq::SET_MEM_TABLE => {
-                let res = self.set_mem_table(&hdr, size, &buf, rfds);
+                let res = self.set_mem_table(&hdr, size, buf, rfds);
+                if res.is_ok() {
+                    self.advance_state(SessionState::MemTableSet);
+                }
                 self.send_ack_message(&hdr, res)?;
             }
             MasterReq::SET_VRING_NUM => {
-                let msg = self.extract_request_body::<VhostUserVringState>(&hdr, size, &buf)?;
+                let msg = self.extract_request_body::<VhostUserVringState>(&hdr, size, buf)?;
                 let res = self
                     .backend
                     .lock()
@@ -171,7 +470,7 @@ impl<S: VhostUserSlaveReqHandler> SlaveReqHandler<S> {
                 self.send_ack_message(&hdr, res)?;
             }
             MasterReq::SET_VRING_ADDR => {
-                let msg = self.extract_request_body::<VhostUserVringAddr>(&hdr, size, &buf)?;
+                let msg = self.extract_request_body::<VhostUserVringAddr>(&hdr, size, buf)?;
                 let flags = match VhostUserVringAddrFlags::from_bits(msg.flags) {
                     Some(val) => val,
                     None => return Err(Error::InvalidMessage),
@@ -187,7 +486,7 @@ impl<S: VhostUserSlaveReqHandler> SlaveReqHandler<S> {
                 self.send_ack_message(&hdr, res)?;
             }
             MasterReq::SET_VRING_BASE => {
-                let msg = self.extract_request_body::<VhostUserVringState>(&hdr, size, &buf)?;
+                let msg = self.extract_request_body::<VhostUserVringState>(&hdr, size, buf)?;
                 let res = self
                     .backend
                     .lock()
@@ -196,25 +495,32 @@ impl<S: VhostUserSlaveReqHandler> SlaveReqHandler<S> {
                 self.send_ack_message(&hdr, res)?;
             }
             MasterReq::GET_VRING_BASE => {
-                let msg = self.extract_request_body::<VhostUserVringState>(&hdr, size, &buf)?;
+                let msg = self.extract_request_body::<VhostUserVringState>(&hdr, size, buf)?;
                 let reply = self.backend.lock().unwrap().get_vring_base(msg.index)?;
                 self.send_reply_message(&hdr, &reply)?;
             }
             MasterReq::SET_VRING_CALL => {
                 self.check_request_size(&hdr, size, mem::size_of::<VhostUserU64>())?;
-                let (index, rfds) = self.handle_vring_fd_request(&buf, rfds)?;
+                let (index, rfds) = self.handle_vring_fd_request(buf, rfds)?;
                 let res = self.backend.lock().unwrap().set_vring_call(index, rfds);
                 self.send_ack_message(&hdr, res)?;
             }
             MasterReq::SET_VRING_KICK => {
                 self.check_request_size(&hdr, size, mem::size_of::<VhostUserU64>())?;
-                let (index, rfds) = self.handle_vring_fd_request(&buf, rfds)?;
+                let (index, rfds) = self.handle_vring_fd_request(buf, rfds)?;
+                let rfds = match rfds {
+                    None if self.is_inband_notifications() => Some(self.add_inband_kick(index)?),
+                    rfds => rfds,
+                };
                 let res = self.backend.lock().unwrap().set_vring_kick(index, rfds);
+                if res.is_ok() {
+                    self.advance_state(SessionState::Running);
+                }
                 self.send_ack_message(&hdr, res)?;
             }
             MasterReq::SET_VRING_ERR => {
                 self.check_request_size(&hdr, size, mem::size_of::<VhostUserU64>())?;
-                let (index, rfds) = self.handle_vring_fd_request(&buf, rfds)?;
+                let (index, rfds) = self.handle_vring_fd_request(buf, rfds)?;
                 let res = self.backend.lock().unwrap().set_vring_err(index, rfds);
                 self.send_ack_message(&hdr, res)?;
             }
@@ -227,11 +533,13 @@ impl<S: VhostUserSlaveReqHandler> SlaveReqHandler<S> {
                 self.update_reply_ack_flag();
             }
             MasterReq::SET_PROTOCOL_FEATURES => {
-                let msg = self.extract_request_body::<VhostUserU64>(&hdr, size, &buf)?;
-                self.backend
-                    .lock()
-                    .unwrap()
-                    .set_protocol_features(msg.value)?;
+                let msg = self.extract_request_body::<VhostUserU64>(&hdr, size, buf)?;
+                let mut backend = self.backend.lock().unwrap();
+                if msg.value & !backend.get_protocol_features()?.bits() != 0 {
+                    return Err(Error::FeatureMismatch);
+                }
+                backend.set_protocol_features(msg.value)?;
+                drop(backend);
                 self.acked_protocol_features = msg.value;
                 self.update_reply_ack_flag();
             }
@@ -245,7 +553,7 @@ impl<S: VhostUserSlaveReqHandler> SlaveReqHandler<S> {
                 self.send_reply_message(&hdr, &msg)?;
             }
             MasterReq::SET_VRING_ENABLE => {
-                let msg = self.extract_request_body::<VhostUserVringState>(&hdr, size, &buf)?;
+                let msg = self.extract_request_body::<VhostUserVringState>(&hdr, size, buf)?;
                 if self.acked_protocol_features & VhostUserProtocolFeatures::MQ.bits() == 0
                     && msg.index > 0
                 {
@@ -264,18 +572,59 @@ impl<S: VhostUserSlaveReqHandler> SlaveReqHandler<S> {
                     .set_vring_enable(msg.index, enable);
                 self.send_ack_message(&hdr, res)?;
             }
+            MasterReq::SET_VRING_ENDIAN => {
+                if self.acked_protocol_features & VhostUserProtocolFeatures::CROSS_ENDIAN.bits()
+                    == 0
+                {
+                    return Err(Error::InvalidOperation);
+                }
+                let msg = self.extract_request_body::<VhostUserVringState>(&hdr, size, buf)?;
+                let big_endian = match msg.num {
+                    VHOST_USER_VRING_BIG_ENDIAN => true,
+                    VHOST_USER_VRING_LITTLE_ENDIAN => false,
+                    _ => return Err(Error::InvalidParam),
+                };
+                let res = self
+                    .backend
+                    .lock()
+                    .unwrap()
+                    .set_vring_endian(msg.index, big_endian);
+                self.send_ack_message(&hdr, res)?;
+            }
+            MasterReq::SEND_RARP => {
+                if self.acked_protocol_features & VhostUserProtocolFeatures::RARP.bits() == 0 {
+                    return Err(Error::InvalidOperation);
+                }
+                let msg = self.extract_request_body::<VhostUserU64>(&hdr, size, buf)?;
+                let mut mac = [0u8; 6];
+                mac.copy_from_slice(&msg.value.to_le_bytes()[..6]);
+                let res = self.backend.lock().unwrap().send_rarp(mac);
+                self.send_ack_message(&hdr, res)?;
+            }
+            MasterReq::NET_SET_MTU => {
+                if self.acked_protocol_features & VhostUserProtocolFeatures::MTU.bits() == 0 {
+                    return Err(Error::InvalidOperation);
+                }
+                let msg = self.extract_request_body::<VhostUserU64>(&hdr, size, buf)?;
+                if msg.value > u64::from(u16::MAX) {
+                    return Err(Error::InvalidParam);
+                }
+                let res = self.backend.lock().unwrap().net_set_mtu(msg.value as u16);
+                self.send_ack_message(&hdr, res)?;
+            }
             MasterReq::GET_CONFIG => {
                 if self.acked_protocol_features & VhostUserProtocolFeatures::CONFIG.bits() == 0 {
                     return Err(Error::InvalidOperation);
                 }
-                self.get_config(&hdr, &buf)?;
+                self.check_request_size(&hdr, size, hdr.get_size() as usize)?;
+                self.get_config(&hdr, buf)?;
             }
             MasterReq::SET_CONFIG => {
                 if self.acked_protocol_features & VhostUserProtocolFeatures::CONFIG.bits() == 0 {
                     return Err(Error::InvalidOperation);
                 }
                 self.check_request_size(&hdr, size, hdr.get_size() as usize)?;
-                self.set_config(&hdr, size, &buf)?;
+                self.set_config(&hdr, size, buf)?;
             }
             MasterReq::SET_SLAVE_REQ_FD => {
                 if self.acked_protocol_features & VhostUserProtocolFeatures::SLAVE_REQ.bits() == 0 {
@@ -283,6 +632,288 @@ impl<S: VhostUserSlaveReqHandler> SlaveReqHandler<S> {
                 }
                 self.set_slave_req_fd(&hdr, rfds)?;
             }
+            MasterReq::GPU_SET_SOCKET => {
+                self.set_gpu_socket(&hdr, rfds)?;
+            }
+            MasterReq::GET_INFLIGHT_FD => {
+                if self.acked_protocol_features & VhostUserProtocolFeatures::INFLIGHT_SHMFD.bits()
+                    == 0
+                {
+                    return Err(Error::InvalidOperation);
+                }
+                let msg = self.extract_request_body::<VhostUserInflight>(&hdr, size, buf)?;
+                let (inflight, file) = self.backend.lock().unwrap().get_inflight_fd(msg)?;
+                let reply_hdr = self.new_reply_header::<VhostUserInflight>(&hdr, 0)?;
+                self.main_sock
+                    .send_message(&reply_hdr, &inflight, Some(&[file.as_raw_fd()]))?;
+            }
+            MasterReq::SET_INFLIGHT_FD => {
+                if self.acked_protocol_features & VhostUserProtocolFeatures::INFLIGHT_SHMFD.bits()
+                    == 0
+                {
+                    return Err(Error::InvalidOperation);
+                }
+                let file = match Endpoint::<MasterReq>::take_single_file(rfds.take()) {
+                    Some(file) => file,
+                    None => return Err(Error::IncorrectFds),
+                };
+                let msg = self.extract_request_body::<VhostUserInflight>(&hdr, size, buf)?;
+                let res = self.backend.lock().unwrap().set_inflight_fd(msg, file);
+                self.send_ack_message(&hdr, res)?;
+            }
+            MasterReq::POSTCOPY_ADVISE => {
+                if self.acked_protocol_features & VhostUserProtocolFeatures::PAGEFAULT.bits() == 0 {
+                    return Err(Error::InvalidOperation);
+                }
+                self.check_request_size(&hdr, size, 0)?;
+                let res = self.backend.lock().unwrap().postcopy_advise();
+                let reply_hdr = self.new_reply_header::<VhostUserU64>(&hdr, 0)?;
+                match res {
+                    Ok(file) => {
+                        let msg = VhostUserU64::new(0);
+                        self.main_sock
+                            .send_message(&reply_hdr, &msg, Some(&[file.as_raw_fd()]))?;
+                    }
+                    Err(e) => {
+                        let msg = VhostUserU64::new(e.reply_code());
+                        self.main_sock.send_message(&reply_hdr, &msg, None)?;
+                    }
+                }
+            }
+            MasterReq::POSTCOPY_LISTEN => {
+                if self.acked_protocol_features & VhostUserProtocolFeatures::PAGEFAULT.bits() == 0 {
+                    return Err(Error::InvalidOperation);
+                }
+                self.check_request_size(&hdr, size, 0)?;
+                let res = self.backend.lock().unwrap().postcopy_listen();
+                if res.is_ok() {
+                    self.postcopy_listening = true;
+                }
+                self.send_mandatory_ack(&hdr, res)?;
+            }
+            MasterReq::POSTCOPY_END => {
+                if self.acked_protocol_features & VhostUserProtocolFeatures::PAGEFAULT.bits() == 0 {
+                    return Err(Error::InvalidOperation);
+                }
+                self.check_request_size(&hdr, size, 0)?;
+                let res = self.backend.lock().unwrap().postcopy_end();
+                self.postcopy_listening = false;
+                self.send_mandatory_ack(&hdr, res)?;
+            }
+            MasterReq::SET_LOG_BASE => {
+                if self.acked_protocol_features & VhostUserProtocolFeatures::LOG_SHMFD.bits() == 0 {
+                    return Err(Error::InvalidOperation);
+                }
+                let file = match Endpoint::<MasterReq>::take_single_file(rfds.take()) {
+                    Some(file) => file,
+                    None => return Err(Error::IncorrectFds),
+                };
+                let msg = self.extract_request_body::<VhostUserLog>(&hdr, size, buf)?;
+                let res = self.backend.lock().unwrap().set_log_base(msg, file);
+                self.send_mandatory_ack(&hdr, res)?;
+            }
+            MasterReq::SET_LOG_FD => {
+                let file = match Endpoint::<MasterReq>::take_single_file(rfds.take()) {
+                    Some(file) => file,
+                    None => return Err(Error::IncorrectFds),
+                };
+                self.check_request_size(&hdr, size, 0)?;
+                let res = self.backend.lock().unwrap().set_log_fd(file);
+                self.send_ack_message(&hdr, res)?;
+            }
+            MasterReq::IOTLB_MSG => {
+                if self.acked_virtio_features & VhostUserVirtioFeatures::IOMMU_PLATFORM.bits() == 0
+                {
+                    return Err(Error::InvalidOperation);
+                }
+                let msg = self.extract_request_body::<VhostUserIotlb>(&hdr, size, buf)?;
+                let res = self.backend.lock().unwrap().iotlb_msg(msg);
+                self.send_mandatory_ack(&hdr, res)?;
+            }
+            MasterReq::RESET_DEVICE => {
+                if self.acked_protocol_features & VhostUserProtocolFeatures::RESET_DEVICE.bits()
+                    == 0
+                {
+                    return Err(Error::InvalidOperation);
+                }
+                self.check_request_size(&hdr, size, 0)?;
+                let res = self.backend.lock().unwrap().reset_device();
+                if res.is_ok() {
+                    // The features have to be negotiated again to restart the device.
+                    self.state = SessionState::Owned;
+                }
+                self.send_ack_message(&hdr, res)?;
+            }
+            MasterReq::SET_STATUS => {
+                if self.acked_protocol_features & VhostUserProtocolFeatures::STATUS.bits() == 0 {
+                    return Err(Error::InvalidOperation);
+                }
+                let msg = self.extract_request_body::<VhostUserU64>(&hdr, size, buf)?;
+                if msg.value > u64::from(u8::MAX) {
+                    return Err(Error::InvalidMessage);
+                }
+                let res = self.backend.lock().unwrap().set_status(msg.value as u8);
+                self.send_ack_message(&hdr, res)?;
+            }
+            MasterReq::GET_STATUS => {
+                if self.acked_protocol_features & VhostUserProtocolFeatures::STATUS.bits() == 0 {
+                    return Err(Error::InvalidOperation);
+                }
+                self.check_request_size(&hdr, size, 0)?;
+                let status = self.backend.lock().unwrap().get_status()?;
+                let msg = VhostUserU64::new(u64::from(status));
+                self.send_reply_message(&hdr, &msg)?;
+            }
+            MasterReq::GET_SHARED_OBJECT => {
+                if self.acked_protocol_features & VhostUserProtocolFeatures::SHARED_OBJECT.bits()
+                    == 0
+                {
+                    return Err(Error::InvalidOperation);
+                }
+                let msg = self.extract_request_body::<VhostUserShared>(&hdr, size, buf)?;
+                let res = self.backend.lock().unwrap().get_shared_object(msg);
+                let reply_hdr = self.new_reply_header::<VhostUserU64>(&hdr, 0)?;
+                match res {
+                    Ok(file) => {
+                        let msg = VhostUserU64::new(0);
+                        self.main_sock
+                            .send_message(&reply_hdr, &msg, Some(&[file.as_raw_fd()]))?;
+                    }
+                    Err(e) => {
+                        let msg = VhostUserU64::new(e.reply_code());
+                        self.main_sock.send_message(&reply_hdr, &msg, None)?;
+                    }
+                }
+            }
+            MasterReq::SET_DEVICE_STATE_FD => {
+                if self.acked_protocol_features & VhostUserProtocolFeatures::DEVICE_STATE.bits()
+                    == 0
+                {
+                    return Err(Error::InvalidOperation);
+                }
+                let file = match Endpoint::<MasterReq>::take_single_file(rfds.take()) {
+                    Some(file) => file,
+                    None => return Err(Error::IncorrectFds),
+                };
+                let msg =
+                    self.extract_request_body::<VhostUserTransferDeviceState>(&hdr, size, buf)?;
+                // Both are valid, the message has been checked by its validator.
+                let (direction, phase) = (msg.direction().unwrap(), msg.phase().unwrap());
+                let res = self
+                    .backend
+                    .lock()
+                    .unwrap()
+                    .set_device_state_fd(direction, phase, file);
+                // The request is always acknowledged, with the file descriptor to be used
+                // instead of the master's one if the backend returns one.
+                let reply_hdr = self.new_reply_header::<VhostUserU64>(&hdr, 0)?;
+                match res {
+                    Ok(Some(file)) => {
+                        let msg = VhostUserU64::new(0);
+                        self.main_sock
+                            .send_message(&reply_hdr, &msg, Some(&[file.as_raw_fd()]))?;
+                    }
+                    Ok(None) => {
+                        let msg = VhostUserU64::new(VHOST_USER_DEVICE_STATE_NOFD_MASK);
+                        self.main_sock.send_message(&reply_hdr, &msg, None)?;
+                    }
+                    Err(e) => {
+                        let msg =
+                            VhostUserU64::new(e.reply_code() | VHOST_USER_DEVICE_STATE_NOFD_MASK);
+                        self.main_sock.send_message(&reply_hdr, &msg, None)?;
+                    }
+                }
+            }
+            MasterReq::CHECK_DEVICE_STATE => {
+                if self.acked_protocol_features & VhostUserProtocolFeatures::DEVICE_STATE.bits()
+                    == 0
+                {
+                    return Err(Error::InvalidOperation);
+                }
+                self.check_request_size(&hdr, size, 0)?;
+                let res = self.backend.lock().unwrap().check_device_state();
+                self.send_mandatory_ack(&hdr, res)?;
+            }
+            MasterReq::GET_SHMEM_CONFIG => {
+                if self.acked_protocol_features & VhostUserProtocolFeatures::SHMEM.bits() == 0 {
+                    return Err(Error::InvalidOperation);
+                }
+                self.check_request_size(&hdr, size, 0)?;
+                let sizes = self.backend.lock().unwrap().get_shared_memory_regions()?;
+                let msg = VhostUserShMemConfig::new(&sizes).ok_or(Error::InvalidParam)?;
+                self.send_reply_message(&hdr, &msg)?;
+            }
+            MasterReq::VRING_KICK => {
+                if !self.is_inband_notifications() {
+                    return Err(Error::InvalidOperation);
+                }
+                let msg = self.extract_request_body::<VhostUserVringState>(&hdr, size, buf)?;
+                let res = match self
+                    .inband_kicks
+                    .iter()
+                    .find(|kick| msg.index == kick.0 as u32)
+                {
+                    Some((_, kick)) => kick.write(1).map_err(Error::ReqHandlerError),
+                    None => Err(Error::InvalidParam),
+                };
+                self.send_ack_message(&hdr, res)?;
+            }
+            MasterReq::GET_MAX_MEM_SLOTS => {
+                if self.acked_protocol_features
+                    & VhostUserProtocolFeatures::CONFIGURE_MEM_SLOTS.bits()
+                    == 0
+                {
+                    return Err(Error::InvalidOperation);
+                }
+                self.check_request_size(&hdr, size, 0)?;
+                let num = self.backend.lock().unwrap().get_max_mem_slots()?;
+                let msg = VhostUserU64::new(num);
+                self.send_reply_message(&hdr, &msg)?;
+            }
+            MasterReq::ADD_MEM_REG => {
+                if self.acked_protocol_features
+                    & VhostUserProtocolFeatures::CONFIGURE_MEM_SLOTS.bits()
+                    == 0
+                {
+                    return Err(Error::InvalidOperation);
+                }
+                let file = match Endpoint::<MasterReq>::take_single_file(rfds.take()) {
+                    Some(file) => file,
+                    None => return Err(Error::IncorrectFds),
+                };
+                let res = if self.is_xen_mmap_acked() {
+                    let msg = self
+                        .extract_request_body::<VhostUserXenSingleMemoryRegion>(&hdr, size, buf)?;
+                    self.backend.lock().unwrap().add_mem_region_xen(msg, file)
+                } else {
+                    let msg =
+                        self.extract_request_body::<VhostUserSingleMemoryRegion>(&hdr, size, buf)?;
+                    self.backend.lock().unwrap().add_mem_region(msg, file)
+                };
+                if res.is_ok() {
+                    self.advance_state(SessionState::MemTableSet);
+                }
+                self.send_mem_slot_ack(&hdr, res)?;
+            }
+            MasterReq::REM_MEM_REG => {
+                if self.acked_protocol_features
+                    & VhostUserProtocolFeatures::CONFIGURE_MEM_SLOTS.bits()
+                    == 0
+                {
+                    return Err(Error::InvalidOperation);
+                }
+                // Regions are removed whatever their mapping.
+                let msg = if self.is_xen_mmap_acked() {
+                    let msg = self
+                        .extract_request_body::<VhostUserXenSingleMemoryRegion>(&hdr, size, buf)?;
+                    let region = msg.region.region;
+                    VhostUserSingleMemoryRegion { padding: 0, region }
+                } else {
+                    *self.extract_request_body::<VhostUserSingleMemoryRegion>(&hdr, size, buf)?
+                };
+                let res = self.backend.lock().unwrap().remove_mem_region(&msg);
+                self.send_mem_slot_ack(&hdr, res)?;
+            }
             _ => {
                 return Err(Error::InvalidMessage);
             }
@@ -295,62 +926,66 @@ impl<S: VhostUserSlaveReqHandler> SlaveReqHandler<S> {
         hdr: &VhostUserMsgHeader<MasterReq>,
         size: usize,
         buf: &[u8],
-        rfds: Option<Vec<RawFd>>,
+        rfds: &mut Option<Vec<RawFd>>,
     ) -> Result<()> {
-        self.check_request_size(&hdr, size, hdr.get_size() as usize)?;
+        self.check_request_size(hdr, size, hdr.get_size() as usize)?;
 
-        // check message size is consistent
-        let hdrsize = mem::size_of::<VhostUserMemory>();
-        if size < hdrsize {
-            Endpoint::<MasterReq>::close_rfds(rfds);
-            return Err(Error::InvalidMessage);
-        }
-        let msg = unsafe { &*(buf.as_ptr() as *const VhostUserMemory) };
-        if !msg.is_valid() {
-            Endpoint::<MasterReq>::close_rfds(rfds);
-            return Err(Error::InvalidMessage);
+        if self.is_xen_mmap_acked() {
+            let regions =
+                Self::extract_mem_regions::<VhostUserXenMemoryRegion>(&buf[..size], rfds)?;
+            let fds = rfds.take().unwrap_or_default();
+            return self
+                .backend
+                .lock()
+                .unwrap()
+                .set_mem_table_xen(&regions, &fds);
         }
-        if size != hdrsize + msg.num_regions as usize * mem::size_of::<VhostUserMemoryRegion>() {
-            Endpoint::<MasterReq>::close_rfds(rfds);
+        let regions = Self::extract_mem_regions::<VhostUserMemoryRegion>(&buf[..size], rfds)?;
+        let fds = rfds.take().unwrap_or_default();
+        self.backend.lock().unwrap().set_mem_table(&regions, &fds)
+    }
+
+    fn extract_mem_regions<R: VhostUserMsgPlain + VhostUserMsgValidator + Copy>(
+        buf: &[u8],
+        rfds: &Option<Vec<RawFd>>,
+    ) -> Result<Vec<R>> {
+        // check message size is consistent
+        let view = match VhostUserMsgView::<VhostUserMemory, R>::from_bytes(buf) {
+            Some(view) => view,
+            None => return Err(Error::InvalidMessage),
+        };
+        let msg = view.body();
+        if !msg.is_valid() || view.payload().len() != msg.num_regions as usize {
             return Err(Error::InvalidMessage);
         }
 
         // validate number of fds matching number of memory regions
-        let fds = match rfds {
-            None => return Err(Error::InvalidMessage),
-            Some(fds) => {
-                if fds.len() != msg.num_regions as usize {
-                    Endpoint::<MasterReq>::close_rfds(Some(fds));
-                    return Err(Error::InvalidMessage);
-                }
-                fds
-            }
-        };
+        match *rfds {
+            Some(ref fds) if fds.len() == msg.num_regions as usize => {}
+            _ => return Err(Error::InvalidMessage),
+        }
 
         // Validate memory regions
-        let regions = unsafe {
-            slice::from_raw_parts(
-                buf.as_ptr().add(hdrsize) as *const VhostUserMemoryRegion,
-                msg.num_regions as usize,
-            )
-        };
+        let regions = view.payload();
         for region in regions.iter() {
             if !region.is_valid() {
-                Endpoint::<MasterReq>::close_rfds(Some(fds));
                 return Err(Error::InvalidMessage);
             }
         }
+        Ok(regions.to_vec())
+    }
 
-        self.backend.lock().unwrap().set_mem_table(&regions, &fds)
+    fn is_xen_mmap_acked(&self) -> bool {
+        self.acked_protocol_features & VhostUserProtocolFeatures::XEN_MMAP.bits() != 0
     }
 
     fn get_config(&mut self, hdr: &VhostUserMsgHeader<MasterReq>, buf: &[u8]) -> Result<()> {
-        let msg = unsafe { &*(buf.as_ptr() as *const VhostUserConfig) };
-        if !msg.is_valid() {
-            return Err(Error::InvalidMessage);
-        }
-        let payload_offset = mem::size_of::<VhostUserConfig>();
-        if buf.len() - payload_offset != msg.size as usize {
+        let view = match VhostUserMsgView::<VhostUserConfig, u8>::from_bytes(buf) {
+            Some(view) => view,
+            None => return Err(Error::InvalidMessage),
+        };
+        let msg = view.body();
+        if !msg.is_valid() || view.payload().len() != msg.size as usize {
             return Err(Error::InvalidMessage);
         }
         let flags = match VhostUserConfigFlags::from_bits(msg.flags) {
@@ -369,15 +1004,15 @@ impl<S: VhostUserSlaveReqHandler> SlaveReqHandler<S> {
         match res {
             Ok(ref buf) if buf.len() == msg.size as usize => {
                 let reply = VhostUserConfig::new(msg.offset, buf.len() as u32, flags);
-                self.send_reply_with_payload(&hdr, &reply, buf.as_slice())?;
+                self.send_reply_with_payload(hdr, &reply, buf.as_slice())?;
             }
             Ok(_) => {
                 let reply = VhostUserConfig::new(msg.offset, 0, flags);
-                self.send_reply_message(&hdr, &reply)?;
+                self.send_reply_message(hdr, &reply)?;
             }
             Err(_) => {
                 let reply = VhostUserConfig::new(msg.offset, 0, flags);
-                self.send_reply_message(&hdr, &reply)?;
+                self.send_reply_message(hdr, &reply)?;
             }
         }
         Ok(())
@@ -389,54 +1024,73 @@ impl<S: VhostUserSlaveReqHandler> SlaveReqHandler<S> {
         size: usize,
         buf: &[u8],
     ) -> Result<()> {
-        if size < mem::size_of::<VhostUserConfig>() {
-            return Err(Error::InvalidMessage);
-        }
-        let msg = unsafe { &*(buf.as_ptr() as *const VhostUserConfig) };
-        if !msg.is_valid() {
-            return Err(Error::InvalidMessage);
-        }
-        if size - mem::size_of::<VhostUserConfig>() != msg.size as usize {
+        let view = match VhostUserMsgView::<VhostUserConfig, u8>::from_bytes(&buf[..size]) {
+            Some(view) => view,
+            None => return Err(Error::InvalidMessage),
+        };
+        let msg = view.body();
+        if !msg.is_valid() || view.payload().len() != msg.size as usize {
             return Err(Error::InvalidMessage);
         }
-        let flags: VhostUserConfigFlags;
-        match VhostUserConfigFlags::from_bits(msg.flags) {
-            Some(val) => flags = val,
+        let flags = match VhostUserConfigFlags::from_bits(msg.flags) {
+            Some(val) => val,
             None => return Err(Error::InvalidMessage),
-        }
+        };
 
         let res = self
             .backend
             .lock()
             .unwrap()
-            .set_config(msg.offset, buf, flags);
-        self.send_ack_message(&hdr, res)?;
+            .set_config(msg.offset, view.payload(), flags);
+        self.send_ack_message(hdr, res)?;
         Ok(())
     }
 
     fn set_slave_req_fd(
         &mut self,
         hdr: &VhostUserMsgHeader<MasterReq>,
-        rfds: Option<Vec<RawFd>>,
+        rfds: &mut Option<Vec<RawFd>>,
     ) -> Result<()> {
-        if let Some(fds) = rfds {
-            if fds.len() == 1 {
-                let sock = unsafe { UnixStream::from_raw_fd(fds[0]) };
-                let vu_req = SlaveFsCacheReq::from_stream(sock);
-                self.backend.lock().unwrap().set_slave_req_fd(vu_req);
-                self.send_ack_message(&hdr, Ok(()))
-            } else {
-                Err(Error::InvalidMessage)
+        let fd = match rfds.take() {
+            Some(ref fds) if fds.len() == 1 => fds[0],
+            fds => {
+                *rfds = fds;
+                return Err(Error::InvalidMessage);
             }
-        } else {
-            Err(Error::InvalidMessage)
+        };
+        let sock = unsafe { UnixStream::from_raw_fd(fd) };
+        let mut vu_req = MasterReqSender::from_stream(sock);
+        let mut features = self.acked_protocol_features;
+        if !self.reply_ack_enabled {
+            features &= !VhostUserProtocolFeatures::REPLY_ACK.bits();
         }
+        vu_req.set_protocol_features(features);
+        self.backend.lock().unwrap().set_slave_req_fd(vu_req);
+        self.send_ack_message(hdr, Ok(()))
+    }
+
+    fn set_gpu_socket(
+        &mut self,
+        hdr: &VhostUserMsgHeader<MasterReq>,
+        rfds: &mut Option<Vec<RawFd>>,
+    ) -> Result<()> {
+        let fd = match rfds.take() {
+            Some(ref fds) if fds.len() == 1 => fds[0],
+            fds => {
+                *rfds = fds;
+                return Err(Error::InvalidMessage);
+            }
+        };
+        let sock = unsafe { UnixStream::from_raw_fd(fd) };
+        let gpu = GpuReqSender::from_stream(sock);
+        self.backend.lock().unwrap().set_gpu_socket(gpu);
+        self.send_ack_message(hdr, Ok(()))
     }
 
     fn handle_vring_fd_request(
         &mut self,
         buf: &[u8],
-        rfds: Option<Vec<RawFd>>,
+        rfds: &mut Option<Vec<RawFd>>,
     ) -> Result<(u8, Option<RawFd>)> {
         let msg = unsafe { &*(buf.as_ptr() as *const VhostUserU64) };
         if !msg.is_valid() {
@@ -447,28 +1101,50 @@ impl<S: VhostUserSlaveReqHandler> SlaveReqHandler<S> {
         // invalid FD flag. This flag is set when there is no file descriptor
         // in the ancillary data. This signals that polling will be used
         // instead of waiting for the call.
-        let nofd = match msg.value & 0x100u64 {
-            0x100u64 => true,
-            _ => false,
-        };
+        let nofd = matches!(msg.value & 0x100u64, 0x100u64);
 
-        let mut rfd = None;
-        match rfds {
-            Some(fds) => {
-                if !nofd && fds.len() == 1 {
-                    rfd = Some(fds[0]);
-                } else if (nofd && !fds.is_empty()) || (!nofd && fds.len() != 1) {
-                    Endpoint::<MasterReq>::close_rfds(Some(fds));
-                    return Err(Error::InvalidMessage);
-                }
-            }
-            None => {
-                if !nofd {
-                    return Err(Error::InvalidMessage);
+        let num_fds = rfds.as_ref().map_or(0, |fds| fds.len());
+        if num_fds != if nofd { 0 } else { 1 } {
+            return Err(Error::InvalidMessage);
+        }
+        let rfd = rfds.take().and_then(|fds| fds.first().cloned());
+        Ok((msg.value as u8, rfd))
+    }
+
+    fn is_inband_notifications(&self) -> bool {
+        self.acked_protocol_features & VhostUserProtocolFeatures::INBAND_NOTIFICATIONS.bits() != 0
+    }
+
+    // Create the eventfd to pass to the backend as the kick eventfd of a vring kicked in-band,
+    // which is signaled on the VRING_KICK requests.
+    fn add_inband_kick(&mut self, index: u8) -> Result<RawFd> {
+        let kick = EventFd::new(libc::EFD_CLOEXEC).map_err(Error::ReqHandlerError)?;
+        let fd = kick
+            .try_clone()
+            .map_err(Error::ReqHandlerError)?
+            .into_raw_fd();
+        self.inband_kicks.retain(|kick| kick.0 != index);
+        self.inband_kicks.push((index, kick));
+        Ok(fd)
+    }
+
+    fn check_rate_limit(&mut self, hdr: &VhostUserMsgHeader<MasterReq>) -> Result<()> {
+        let code = hdr.get_code();
+        let policy = match self.rate_limit {
+            Some((ref requests, ref mut limiter, policy))
+                if requests.is_empty() || requests.contains(&code) =>
+            {
+                if limiter.try_acquire() {
+                    return Ok(());
                 }
+                policy
             }
+            _ => return Ok(()),
+        };
+        if policy == RateLimitPolicy::Disconnect {
+            self.set_failed(libc::ECONNABORTED);
         }
-        Ok((msg.value as u8, rfd))
+        Err(Error::RateLimited(code))
     }
 
     fn check_state(&self) -> Result<()> {
@@ -478,6 +1154,22 @@ impl<S: VhostUserSlaveReqHandler> SlaveReqHandler<S> {
         }
     }
 
+    fn check_request_order(&self, hdr: &VhostUserMsgHeader<MasterReq>) -> Result<()> {
+        let code = hdr.get_code();
+        if self.state < SessionState::required_by(code)
+            || (code == MasterReq::SET_OWNER && self.state != SessionState::Init)
+        {
+            return Err(Error::OutOfOrderRequest(code));
+        }
+        Ok(())
+    }
+
+    fn advance_state(&mut self, state: SessionState) {
+        if self.state < state {
+            self.state = state;
+        }
+    }
+
     fn check_request_size(
         &self,
         hdr: &VhostUserMsgHeader<MasterReq>,
@@ -494,31 +1186,6 @@ impl<S: VhostUserSlaveReqHandler> SlaveReqHandler<S> {
         Ok(())
     }
 
-    fn check_attached_rfds(
-        &self,
-        hdr: &VhostUserMsgHeader<MasterReq>,
-        rfds: Option<Vec<RawFd>>,
-    ) -> Result<Option<Vec<RawFd>>> {
-        match hdr.get_code() {
-            MasterReq::SET_MEM_TABLE => Ok(rfds),
-            MasterReq::SET_VRING_CALL => Ok(rfds),
-            MasterReq::SET_VRING_KICK => Ok(rfds),
-            MasterReq::SET_VRING_ERR => Ok(rfds),
-            MasterReq::SET_LOG_BASE => Ok(rfds),
-            MasterReq::SET_LOG_FD => Ok(rfds),
-            MasterReq::SET_SLAVE_REQ_FD => Ok(rfds),
-            MasterReq::SET_INFLIGHT_FD => Ok(rfds),
-            _ => {
-                if rfds.is_some() {
-                    Endpoint::<MasterReq>::close_rfds(rfds);
-                    Err(Error::InvalidMessage)
-                } else {
-                    Ok(rfds)
-                }
-            }
-        }
-    }
-
     fn extract_request_body<'a, T: Sized + VhostUserMsgValidator>(
         &self,
         hdr: &VhostUserMsgHeader<MasterReq>,
@@ -536,19 +1203,14 @@ impl<S: VhostUserSlaveReqHandler> SlaveReqHandler<S> {
     fn update_reply_ack_flag(&mut self) {
         let vflag = VhostUserVirtioFeatures::PROTOCOL_FEATURES.bits();
         let pflag = VhostUserProtocolFeatures::REPLY_ACK;
-        if (self.virtio_features & vflag) != 0
+        self.reply_ack_enabled = (self.virtio_features & vflag) != 0
             && (self.acked_virtio_features & vflag) != 0
             && self.protocol_features.contains(pflag)
-            && (self.acked_protocol_features & pflag.bits()) != 0
-        {
-            self.reply_ack_enabled = true;
-        } else {
-            self.reply_ack_enabled = false;
-        }
+            && (self.acked_protocol_features & pflag.bits()) != 0;
     }
 
     fn new_reply_header<T: Sized>(
-        &self,
+        &mut self,
         req: &VhostUserMsgHeader<MasterReq>,
         payload_size: usize,
     ) -> Result<VhostUserMsgHeader<MasterReq>> {
@@ -556,6 +1218,7 @@ impl<S: VhostUserSlaveReqHandler> SlaveReqHandler<S> {
             return Err(Error::InvalidParam);
         }
         self.check_state()?;
+        self.replied = true;
         Ok(VhostUserMsgHeader::new(
             req.get_code(),
             VhostUserHeaderFlag::REPLY.bits(),
@@ -568,18 +1231,45 @@ impl<S: VhostUserSlaveReqHandler> SlaveReqHandler<S> {
         req: &VhostUserMsgHeader<MasterReq>,
         res: Result<()>,
     ) -> Result<()> {
-        if self.reply_ack_enabled {
-            let hdr = self.new_reply_header::<VhostUserU64>(req, 0)?;
-            let val = match res {
-                Ok(_) => 0,
-                Err(_) => 1,
-            };
-            let msg = VhostUserU64::new(val);
-            self.main_sock.send_message(&hdr, &msg, None)?;
+        if self.reply_ack_enabled && req.is_need_reply() {
+            self.send_ack(req, &res)?;
         }
         Ok(())
     }
 
+    // Postcopy, SET_LOG_BASE, IOTLB_MSG and CHECK_DEVICE_STATE requests are always acknowledged,
+    // no matter whether REPLY_ACK has been negotiated.
+    fn send_mandatory_ack(
+        &mut self,
+        req: &VhostUserMsgHeader<MasterReq>,
+        res: Result<()>,
+    ) -> Result<()> {
+        self.send_ack(req, &res)
+    }
+
+    // Failures are acked with the errno value matching the error, for the master to tell why the
+    // request failed.
+    fn send_ack(&mut self, req: &VhostUserMsgHeader<MasterReq>, res: &Result<()>) -> Result<()> {
+        let hdr = self.new_reply_header::<VhostUserU64>(req, 0)?;
+        let msg = VhostUserU64::new(res.as_ref().map_or_else(|e| e.reply_code(), |_| 0));
+        self.main_sock.send_message(&hdr, &msg, None)?;
+        Ok(())
+    }
+
+    // Once the master has switched to postcopy mode, memory slot updates are always
+    // acknowledged so the master knows when the new regions are registered for page faults.
+    fn send_mem_slot_ack(
+        &mut self,
+        req: &VhostUserMsgHeader<MasterReq>,
+        res: Result<()>,
+    ) -> Result<()> {
+        if self.postcopy_listening {
+            self.send_mandatory_ack(req, res)
+        } else {
+            self.send_ack_message(req, res)
+        }
+    }
+
     fn send_reply_message<T>(
         &mut self,
         req: &VhostUserMsgHeader<MasterReq>,
@@ -0,0 +1,272 @@
+// Copyright (C) 2020 Alibaba Cloud Computing. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Snapshots of the control plane state of vhost-user sessions.
+//!
+//! A `SessionSnapshot` records what the master has configured on the slave: the acked features,
+//! the memory table and the vring configuration. It is taken from the master side with
+//! `ReconnectingMaster::snapshot()` or from the slave side with `Daemon::snapshot()`, and may be
+//! saved by a VMM together with a snapshot of the guest, as the bytes returned by `to_bytes()`.
+//!
+//! Restoring a snapshot replays the requests which configured the session, either to a new slave
+//! with `ReconnectingMaster::restore()` or into a new daemon with `Daemon::restore()`. File
+//! descriptors can't be part of a snapshot, so the files backing the memory regions are passed to
+//! the restore functions, and the vring eventfds are set once the session has been restored.
+//! Snapshots are meant to be taken while the vrings are stopped, as the descriptor chains in
+//! flight aren't recorded.
+
+use std::convert::TryInto;
+
+use super::{Error, Result};
+use crate::backend::VringConfigData;
+
+// Magic number and version at the beginning of snapshots.
+const SNAPSHOT_MAGIC: u32 = 0x5355_5356;
+const SNAPSHOT_VERSION: u32 = 1;
+
+/// Memory region of a session snapshot, without the file descriptor backing it.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct MemoryRegionSnapshot {
+    /// Guest physical address of the memory region.
+    pub guest_phys_addr: u64,
+    /// Size of the memory region.
+    pub memory_size: u64,
+    /// Virtual address of the memory region in the master.
+    pub userspace_addr: u64,
+    /// Offset of the memory region in the file backing it.
+    pub mmap_offset: u64,
+}
+
+/// Configuration of a vring in a session snapshot.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct VringSnapshot {
+    /// Size set by SET_VRING_NUM.
+    pub size: Option<u16>,
+    /// Addresses set by SET_VRING_ADDR, as virtual addresses of the master.
+    pub config: Option<VringConfigData>,
+    /// Base set by SET_VRING_BASE or returned by GET_VRING_BASE.
+    pub base: Option<u32>,
+    /// Whether the vring has been enabled by SET_VRING_ENABLE.
+    pub enabled: bool,
+}
+
+/// Control plane state of a vhost-user session.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct SessionSnapshot {
+    /// Virtio features acked by SET_FEATURES.
+    pub features: Option<u64>,
+    /// Vhost-user protocol features acked by SET_PROTOCOL_FEATURES.
+    pub protocol_features: Option<u64>,
+    /// Memory table, in the order the regions have been sent.
+    pub regions: Vec<MemoryRegionSnapshot>,
+    /// Configuration of the vrings, indexed by queue.
+    pub vrings: Vec<VringSnapshot>,
+    /// Virtio device status set by SET_STATUS.
+    pub status: Option<u8>,
+}
+
+impl SessionSnapshot {
+    /// Serialize the snapshot into bytes, in little endian.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut buf = Vec::new();
+        put_u32(&mut buf, SNAPSHOT_MAGIC);
+        put_u32(&mut buf, SNAPSHOT_VERSION);
+        put_option(&mut buf, self.features, put_u64);
+        put_option(&mut buf, self.protocol_features, put_u64);
+        put_u32(&mut buf, self.regions.len() as u32);
+        for region in self.regions.iter() {
+            put_u64(&mut buf, region.guest_phys_addr);
+            put_u64(&mut buf, region.memory_size);
+            put_u64(&mut buf, region.userspace_addr);
+            put_u64(&mut buf, region.mmap_offset);
+        }
+        put_u32(&mut buf, self.vrings.len() as u32);
+        for vring in self.vrings.iter() {
+            put_option(&mut buf, vring.size, |buf, size| {
+                buf.extend_from_slice(&size.to_le_bytes())
+            });
+            put_option(&mut buf, vring.config, |buf, config| {
+                buf.extend_from_slice(&config.queue_max_size.to_le_bytes());
+                buf.extend_from_slice(&config.queue_size.to_le_bytes());
+                put_u32(buf, config.flags);
+                put_u64(buf, config.desc_table_addr);
+                put_u64(buf, config.used_ring_addr);
+                put_u64(buf, config.avail_ring_addr);
+                put_option(buf, config.log_addr, put_u64);
+            });
+            put_option(&mut buf, vring.base, put_u32);
+            buf.push(vring.enabled as u8);
+        }
+        put_option(&mut buf, self.status, |buf, status| buf.push(status));
+        buf
+    }
+
+    /// Deserialize a snapshot from bytes produced by `to_bytes()`.
+    ///
+    /// Fails with `Error::InvalidMessage` if the bytes aren't a snapshot of a supported version.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        let mut reader = Reader { bytes };
+        if reader.u32()? != SNAPSHOT_MAGIC || reader.u32()? != SNAPSHOT_VERSION {
+            return Err(Error::InvalidMessage);
+        }
+        let features = reader.option(Reader::u64)?;
+        let protocol_features = reader.option(Reader::u64)?;
+        let mut regions = Vec::new();
+        for _ in 0..reader.u32()? {
+            regions.push(MemoryRegionSnapshot {
+                guest_phys_addr: reader.u64()?,
+                memory_size: reader.u64()?,
+                userspace_addr: reader.u64()?,
+                mmap_offset: reader.u64()?,
+            });
+        }
+        let mut vrings = Vec::new();
+        for _ in 0..reader.u32()? {
+            let size = reader.option(Reader::u16)?;
+            let config = reader.option(|reader| {
+                Ok(VringConfigData {
+                    queue_max_size: reader.u16()?,
+                    queue_size: reader.u16()?,
+                    flags: reader.u32()?,
+                    desc_table_addr: reader.u64()?,
+                    used_ring_addr: reader.u64()?,
+                    avail_ring_addr: reader.u64()?,
+                    log_addr: reader.option(Reader::u64)?,
+                })
+            })?;
+            let base = reader.option(Reader::u32)?;
+            let enabled = reader.bool()?;
+            vrings.push(VringSnapshot {
+                size,
+                config,
+                base,
+                enabled,
+            });
+        }
+        let status = reader.option(Reader::u8)?;
+        if !reader.bytes.is_empty() {
+            return Err(Error::InvalidMessage);
+        }
+        Ok(SessionSnapshot {
+            features,
+            protocol_features,
+            regions,
+            vrings,
+            status,
+        })
+    }
+}
+
+fn put_u32(buf: &mut Vec<u8>, val: u32) {
+    buf.extend_from_slice(&val.to_le_bytes());
+}
+
+fn put_u64(buf: &mut Vec<u8>, val: u64) {
+    buf.extend_from_slice(&val.to_le_bytes());
+}
+
+// Optional values are prefixed by a byte telling whether they are set.
+fn put_option<T, F: FnOnce(&mut Vec<u8>, T)>(buf: &mut Vec<u8>, val: Option<T>, put: F) {
+    match val {
+        Some(val) => {
+            buf.push(1);
+            put(buf, val);
+        }
+        None => buf.push(0),
+    }
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn take<const N: usize>(&mut self) -> Result<[u8; N]> {
+        if self.bytes.len() < N {
+            return Err(Error::InvalidMessage);
+        }
+        let (val, rest) = self.bytes.split_at(N);
+        self.bytes = rest;
+        Ok(val.try_into().unwrap())
+    }
+
+    fn u8(&mut self) -> Result<u8> {
+        Ok(self.take::<1>()?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16> {
+        self.take().map(u16::from_le_bytes)
+    }
+
+    fn u32(&mut self) -> Result<u32> {
+        self.take().map(u32::from_le_bytes)
+    }
+
+    fn u64(&mut self) -> Result<u64> {
+        self.take().map(u64::from_le_bytes)
+    }
+
+    fn bool(&mut self) -> Result<bool> {
+        match self.u8()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            _ => Err(Error::InvalidMessage),
+        }
+    }
+
+    fn option<T, F: FnOnce(&mut Self) -> Result<T>>(&mut self, get: F) -> Result<Option<T>> {
+        if self.bool()? {
+            get(self).map(Some)
+        } else {
+            Ok(None)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_session_snapshot_bytes() {
+        let snapshot = SessionSnapshot {
+            features: Some(0x1_4000_0000),
+            protocol_features: None,
+            regions: vec![MemoryRegionSnapshot {
+                guest_phys_addr: 0,
+                memory_size: 0x10_0000,
+                userspace_addr: 0x7f00_0000_0000,
+                mmap_offset: 0x1000,
+            }],
+            vrings: vec![
+                VringSnapshot {
+                    size: Some(256),
+                    config: Some(VringConfigData {
+                        queue_max_size: 256,
+                        queue_size: 256,
+                        flags: 0,
+                        desc_table_addr: 0x7f00_0000_1000,
+                        used_ring_addr: 0x7f00_0000_3000,
+                        avail_ring_addr: 0x7f00_0000_2000,
+                        log_addr: None,
+                    }),
+                    base: Some(42),
+                    enabled: true,
+                },
+                VringSnapshot::default(),
+            ],
+            status: Some(0xf),
+        };
+        let bytes = snapshot.to_bytes();
+        assert_eq!(SessionSnapshot::from_bytes(&bytes).unwrap(), snapshot);
+
+        // Truncated, extended and foreign snapshots are rejected.
+        assert!(SessionSnapshot::from_bytes(&bytes[..bytes.len() - 1]).is_err());
+        let mut extended = bytes.clone();
+        extended.push(0);
+        assert!(SessionSnapshot::from_bytes(&extended).is_err());
+        let mut foreign = bytes;
+        foreign[4] = 2;
+        assert!(SessionSnapshot::from_bytes(&foreign).is_err());
+    }
+}
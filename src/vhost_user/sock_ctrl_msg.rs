@@ -111,7 +111,11 @@ fn get_next_cmsg(msghdr: &msghdr, cmsg: &cmsghdr, cmsg_ptr: *mut cmsghdr) -> *mu
     }
 }
 
-const CMSG_BUFFER_INLINE_CAPACITY: usize = CMSG_SPACE!(size_of::<RawFd>() * 32);
+/// Maximum number of file descriptors the kernel passes with a single SCM_RIGHTS control
+/// message, SCM_MAX_FD.
+pub const SCM_MAX_FD: usize = 253;
+
+const CMSG_BUFFER_INLINE_CAPACITY: usize = CMSG_SPACE!(size_of::<RawFd>() * SCM_MAX_FD);
 
 enum CmsgBuffer {
     Inline([u64; (CMSG_BUFFER_INLINE_CAPACITY + 7) / 8]),
@@ -149,19 +153,31 @@ impl CmsgBuffer {
     }
 }
 
+const IOVEC_INLINE_CAPACITY: usize = 4;
+
 fn raw_sendmsg<D: IntoIovec>(fd: RawFd, out_data: &[D], out_fds: &[RawFd]) -> Result<usize> {
     let cmsg_capacity = CMSG_SPACE!(size_of::<RawFd>() * out_fds.len());
     let mut cmsg_buffer = CmsgBuffer::with_capacity(cmsg_capacity);
 
-    let mut iovecs = Vec::with_capacity(out_data.len());
-    for data in out_data {
-        iovecs.push(iovec {
-            iov_base: data.as_ptr() as *mut c_void,
-            iov_len: data.size(),
-        });
+    let empty = iovec {
+        iov_base: null_mut(),
+        iov_len: 0,
+    };
+    // Vhost-user messages are sent with a few vectors at most, which are kept on the stack.
+    let mut inline_iovecs = [empty; IOVEC_INLINE_CAPACITY];
+    let mut heap_iovecs;
+    let iovecs = if out_data.len() <= IOVEC_INLINE_CAPACITY {
+        &mut inline_iovecs[..out_data.len()]
+    } else {
+        heap_iovecs = vec![empty; out_data.len()];
+        &mut heap_iovecs[..]
+    };
+    for (iov, data) in iovecs.iter_mut().zip(out_data) {
+        iov.iov_base = data.as_ptr() as *mut c_void;
+        iov.iov_len = data.size();
     }
 
-    let mut msg = new_msghdr(&mut iovecs);
+    let mut msg = new_msghdr(iovecs);
 
     if !out_fds.is_empty() {
         let cmsg = cmsghdr {
@@ -208,9 +224,12 @@ fn raw_recvmsg(fd: RawFd, iovecs: &mut [iovec], in_fds: &mut [RawFd]) -> Result<
         set_msg_controllen(&mut msg, cmsg_capacity);
     }
 
+    // Received file descriptors are close-on-exec, so they don't leak into the processes spawned
+    // before the receiver gets to take ownership of them.
+    let flags = libc::MSG_WAITALL | libc::MSG_CMSG_CLOEXEC;
     // Safe because the msghdr was properly constructed from valid (or null) pointers of the
     // indicated length and we check the return value.
-    let total_read = unsafe { recvmsg(fd, &mut msg, libc::MSG_WAITALL) };
+    let total_read = unsafe { recvmsg(fd, &mut msg, flags) };
 
     if total_read == -1 {
         return Err(Error::last());
@@ -230,6 +249,12 @@ fn raw_recvmsg(fd: RawFd, iovecs: &mut [iovec], in_fds: &mut [RawFd]) -> Result<
 
         if cmsg.cmsg_level == SOL_SOCKET && cmsg.cmsg_type == SCM_RIGHTS {
             let fd_count = (cmsg.cmsg_len - CMSG_LEN!(0)) as usize / size_of::<RawFd>();
+            // The control buffer is sized for in_fds, so this only guards against a bogus
+            // header.
+            if in_fds_count + fd_count > in_fds.len() {
+                close_fds(&in_fds[..in_fds_count]);
+                return Err(Error::new(libc::EMSGSIZE));
+            }
             unsafe {
                 copy_nonoverlapping(
                     CMSG_DATA(cmsg_ptr),
@@ -243,9 +268,23 @@ fn raw_recvmsg(fd: RawFd, iovecs: &mut [iovec], in_fds: &mut [RawFd]) -> Result<
         cmsg_ptr = get_next_cmsg(&msg, &cmsg, cmsg_ptr);
     }
 
+    // The kernel discards the file descriptors which don't fit into the control buffer, so the
+    // message can't be handled.
+    if msg.msg_flags & libc::MSG_CTRUNC != 0 {
+        close_fds(&in_fds[..in_fds_count]);
+        return Err(Error::new(libc::EMSGSIZE));
+    }
+
     Ok((total_read as usize, in_fds_count))
 }
 
+fn close_fds(fds: &[RawFd]) {
+    for fd in fds {
+        // Safe because the file descriptors have just been received, and are owned by us.
+        unsafe { libc::close(*fd) };
+    }
+}
+
 /// Trait for file descriptors can send and receive socket control messages via `sendmsg` and
 /// `recvmsg`.
 pub trait ScmSocket {
@@ -314,6 +353,9 @@ pub trait ScmSocket {
     ///           returned tuple. The caller owns these file descriptors, but they will not be
     ///           closed on drop like a `File`-like type would be. It is recommended that each valid
     ///           file descriptor gets wrapped in a drop type that closes it after this returns.
+    ///
+    /// Fails with EMSGSIZE, once the received file descriptors have been closed, if more file
+    /// descriptors than `fds` can hold are attached to the data.
     fn recv_with_fds(&self, iovecs: &mut [iovec], fds: &mut [RawFd]) -> Result<(usize, usize)> {
         raw_recvmsg(self.socket_fd(), iovecs, fds)
     }
@@ -334,8 +376,11 @@ impl ScmSocket for UnixStream {
 /// Trait for types that can be converted into an `iovec` that can be referenced by a syscall for
 /// the lifetime of this object.
 ///
-/// This trait is unsafe because interfaces that use this trait depend on the base pointer and size
-/// being accurate.
+/// # Safety
+///
+/// Interfaces that use this trait pass the base pointer and size to the kernel as is, so
+/// implementors must return the address and the length in bytes of a memory area which stays
+/// valid, and isn't mutated through another reference, for the lifetime of the object.
 pub unsafe trait IntoIovec {
     /// Gets the base pointer of this `iovec`.
     fn as_ptr(&self) -> *const c_void;
@@ -453,6 +498,8 @@ mod tests {
         assert_ne!(file.as_raw_fd(), s1.as_raw_fd());
         assert_ne!(file.as_raw_fd(), s2.as_raw_fd());
         assert_ne!(file.as_raw_fd(), evt.as_raw_fd());
+        let flags = unsafe { libc::fcntl(file.as_raw_fd(), libc::F_GETFD) };
+        assert_ne!(flags & libc::FD_CLOEXEC, 0);
 
         file.write(unsafe { from_raw_parts(&1203u64 as *const u64 as *const u8, 8) })
             .expect("failed to write to sent fd");
@@ -496,4 +543,24 @@ mod tests {
 
         assert_eq!(evt.read().expect("failed to read from eventfd"), 1203);
     }
+
+    #[test]
+    fn recv_too_many_fds() {
+        let (s1, s2) = UnixDatagram::pair().expect("failed to create socket pair");
+
+        let evt = EventFd::new(0).expect("failed to create eventfd");
+        s1.send_with_fds(&[[237].as_ref()], &[evt.as_raw_fd(), evt.as_raw_fd()])
+            .expect("failed to send fds");
+
+        let mut files = [0; 1];
+        let mut buf = [0u8];
+        let mut iovecs = [iovec {
+            iov_base: buf.as_mut_ptr() as *mut c_void,
+            iov_len: buf.len(),
+        }];
+        let err = s2
+            .recv_with_fds(&mut iovecs[..], &mut files)
+            .expect_err("truncated fds received");
+        assert_eq!(err.errno(), libc::EMSGSIZE);
+    }
 }
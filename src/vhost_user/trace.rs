@@ -0,0 +1,116 @@
+// Copyright (C) 2020 Alibaba Cloud Computing. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Trace the vhost-user messages.
+//!
+//! With the `trace` feature, every message sent or received with the whole message protocol is
+//! reported at the trace level of the `log` crate, under the `vhost_user` target, with its
+//! request code, size, number of attached file descriptors and direction. Replies also report
+//! the time elapsed since the last message was sent, which is the latency of the request they
+//! answer. Without the feature, tracing compiles to nothing.
+
+#[cfg(feature = "trace")]
+use std::time::Instant;
+
+use super::message::{Req, VhostUserMsgHeader};
+
+/// Direction of a traced message.
+#[derive(Clone, Copy, Debug)]
+pub(super) enum Direction {
+    Send,
+    Recv,
+}
+
+/// Tracer of the messages of an endpoint.
+#[derive(Default)]
+pub(super) struct Tracer {
+    #[cfg(feature = "trace")]
+    sent_at: Option<Instant>,
+}
+
+impl Tracer {
+    /// Report a message sent or received on the endpoint.
+    #[cfg(feature = "trace")]
+    pub fn message<R: Req>(&mut self, dir: Direction, hdr: &VhostUserMsgHeader<R>, fds: usize) {
+        let now = Instant::now();
+        let latency = match dir {
+            Direction::Send => {
+                self.sent_at = Some(now);
+                None
+            }
+            Direction::Recv if hdr.is_reply() => self.sent_at.map(|sent_at| now - sent_at),
+            Direction::Recv => None,
+        };
+        trace!(
+            target: "vhost_user",
+            "dir={:?} request={:?} reply={} need_reply={} size={} fds={} latency={:?}",
+            dir,
+            hdr.get_code(),
+            hdr.is_reply(),
+            hdr.is_need_reply(),
+            hdr.get_size(),
+            fds,
+            latency
+        );
+    }
+
+    /// Report a message sent or received on the endpoint.
+    #[cfg(not(feature = "trace"))]
+    #[inline(always)]
+    pub fn message<R: Req>(&mut self, _dir: Direction, _hdr: &VhostUserMsgHeader<R>, _fds: usize) {}
+}
+
+#[cfg(all(test, feature = "trace"))]
+mod tests {
+    use super::*;
+    use crate::vhost_user::connection::Endpoint;
+    use crate::vhost_user::message::MasterReq;
+    use log::{Level, Log, Metadata, Record};
+    use std::os::unix::net::UnixStream;
+    use std::sync::Mutex;
+
+    struct TestLogger {
+        events: Mutex<Vec<String>>,
+    }
+
+    impl Log for TestLogger {
+        fn enabled(&self, metadata: &Metadata) -> bool {
+            metadata.level() <= Level::Trace && metadata.target() == "vhost_user"
+        }
+
+        fn log(&self, record: &Record) {
+            if self.enabled(record.metadata()) {
+                self.events.lock().unwrap().push(record.args().to_string());
+            }
+        }
+
+        fn flush(&self) {}
+    }
+
+    static LOGGER: TestLogger = TestLogger {
+        events: Mutex::new(Vec::new()),
+    };
+
+    #[test]
+    fn test_trace_messages() {
+        log::set_logger(&LOGGER).unwrap();
+        log::set_max_level(log::LevelFilter::Trace);
+
+        let (master, slave) = UnixStream::pair().unwrap();
+        let mut master = Endpoint::<MasterReq>::from_stream(master);
+        let mut slave = Endpoint::<MasterReq>::from_stream(slave);
+        let mut hdr = VhostUserMsgHeader::new(MasterReq::GET_MAX_MEM_SLOTS, 0, 0);
+        hdr.set_need_reply(true);
+        master.send_header(&hdr, None).unwrap();
+        slave.recv_header().unwrap();
+
+        // Other tests may trace messages concurrently.
+        let events = LOGGER.events.lock().unwrap();
+        assert!(events.iter().any(|event| event
+            == "dir=Send request=GET_MAX_MEM_SLOTS reply=false need_reply=true size=0 fds=0 \
+                latency=None"));
+        assert!(events
+            .iter()
+            .any(|event| event.starts_with("dir=Recv request=GET_MAX_MEM_SLOTS reply=false")));
+    }
+}
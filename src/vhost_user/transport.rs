@@ -0,0 +1,110 @@
+// Copyright (C) 2020 Alibaba Cloud Computing. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Transports for the vhost-user control channel other than unix domain sockets.
+//!
+//! The control channel may run over AF_VSOCK, for backends running in another virtual machine
+//! or on another host. Such sockets can't pass file descriptors, so the objects attached to the
+//! messages, such as the guest memory regions, are transferred by a `SharedMemoryHook` instead,
+//! which negotiates the shared memory with the peer by its own means.
+
+use std::io;
+use std::mem;
+use std::os::unix::io::{AsRawFd, FromRawFd, IntoRawFd, RawFd};
+use std::os::unix::net::{UnixListener, UnixStream};
+
+/// Transfer the file descriptors attached to the vhost-user messages, for transports which
+/// can't pass them along with the messages.
+///
+/// The hook is called once for every message sent or received with the whole message
+/// protocol, including messages without file descriptors, so both ends stay in step.
+pub trait SharedMemoryHook: Send {
+    /// Transfer the file descriptors attached to the next message sent to the peer.
+    fn send_fds(&mut self, fds: &[RawFd]) -> io::Result<()>;
+
+    /// Get the file descriptors attached to the next message received from the peer, which
+    /// are owned by the caller.
+    fn recv_fds(&mut self) -> io::Result<Vec<RawFd>>;
+}
+
+// Build the address of `port` on the context `cid`.
+fn vsock_addr(cid: u32, port: u32) -> libc::sockaddr_vm {
+    // Safe because sockaddr_vm is a plain data structure.
+    let mut addr: libc::sockaddr_vm = unsafe { mem::zeroed() };
+    addr.svm_family = libc::AF_VSOCK as libc::sa_family_t;
+    addr.svm_cid = cid;
+    addr.svm_port = port;
+    addr
+}
+
+// Create an AF_VSOCK stream socket. UnixStream is only used to own the file descriptor, since
+// the standard library has no type for such sockets.
+fn vsock_socket() -> io::Result<UnixStream> {
+    // Safe because we check the return value, and take the ownership of the new socket.
+    unsafe {
+        let fd = libc::socket(libc::AF_VSOCK, libc::SOCK_STREAM | libc::SOCK_CLOEXEC, 0);
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(UnixStream::from_raw_fd(fd))
+    }
+}
+
+/// Connect to `port` on the context `cid`.
+pub(super) fn vsock_connect(cid: u32, port: u32) -> io::Result<UnixStream> {
+    let sock = vsock_socket()?;
+    let addr = vsock_addr(cid, port);
+    // Safe because the address is a valid sockaddr_vm, and we check the return value.
+    let ret = unsafe {
+        libc::connect(
+            sock.as_raw_fd(),
+            &addr as *const libc::sockaddr_vm as *const libc::sockaddr,
+            mem::size_of::<libc::sockaddr_vm>() as libc::socklen_t,
+        )
+    };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(sock)
+}
+
+/// Listen for connections on `port` of the local context.
+pub(super) fn vsock_listen(port: u32) -> io::Result<UnixListener> {
+    let sock = vsock_socket()?;
+    let fd = sock.into_raw_fd();
+    // Safe because we own the new socket.
+    let listener = unsafe { UnixListener::from_raw_fd(fd) };
+    let addr = vsock_addr(libc::VMADDR_CID_ANY, port);
+    // Safe because the address is a valid sockaddr_vm, and we check the return values.
+    unsafe {
+        if libc::bind(
+            fd,
+            &addr as *const libc::sockaddr_vm as *const libc::sockaddr,
+            mem::size_of::<libc::sockaddr_vm>() as libc::socklen_t,
+        ) < 0
+            || libc::listen(fd, 128) < 0
+        {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(listener)
+}
+
+/// Accept a connection on a vsock listener, which `UnixListener::accept()` rejects because of
+/// the address family of the peer.
+pub(super) fn vsock_accept(fd: RawFd) -> io::Result<UnixStream> {
+    // Safe because we don't ask for the address of the peer, and we check the return value.
+    let ret = unsafe {
+        libc::accept4(
+            fd,
+            std::ptr::null_mut(),
+            std::ptr::null_mut(),
+            libc::SOCK_CLOEXEC,
+        )
+    };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // Safe because we own the new connection.
+    Ok(unsafe { UnixStream::from_raw_fd(ret) })
+}
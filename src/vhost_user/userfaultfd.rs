@@ -0,0 +1,182 @@
+// Copyright (C) 2020 Alibaba Cloud Computing. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Helper to manage userfaultfd objects for vhost-user postcopy live migration.
+//!
+//! When the VHOST_USER_PROTOCOL_F_PAGEFAULT protocol feature has been negotiated, the master
+//! sends a VHOST_USER_POSTCOPY_ADVISE request and the slave replies with a userfaultfd file
+//! descriptor. The slave then registers its guest memory mappings with the userfaultfd, so that
+//! accesses to pages which haven't been migrated yet are reported to the master, which fetches
+//! them from the source host.
+
+use std::fs::File;
+use std::io::{Error as IOError, Result as IOResult};
+use std::os::unix::io::{AsRawFd, FromRawFd, IntoRawFd, RawFd};
+
+use libc;
+use vmm_sys_util::ioctl::ioctl_with_mut_ref;
+
+/// Version of the userfaultfd API.
+pub const UFFD_API: u64 = 0xAA;
+
+/// Register memory ranges to track missing pages.
+pub const UFFDIO_REGISTER_MODE_MISSING: u64 = 0x1;
+
+// Bindings for the userfaultfd ioctls, see include/uapi/linux/userfaultfd.h.
+#[allow(missing_docs)]
+mod bindings {
+    const UFFDIO: u32 = 0xAA;
+
+    #[repr(C)]
+    #[derive(Default)]
+    pub struct UffdioApi {
+        pub api: u64,
+        pub features: u64,
+        pub ioctls: u64,
+    }
+
+    #[repr(C)]
+    #[derive(Default)]
+    pub struct UffdioRange {
+        pub start: u64,
+        pub len: u64,
+    }
+
+    #[repr(C)]
+    #[derive(Default)]
+    pub struct UffdioRegister {
+        pub range: UffdioRange,
+        pub mode: u64,
+        pub ioctls: u64,
+    }
+
+    ioctl_iowr_nr!(UFFDIO_API, UFFDIO, 0x3f, UffdioApi);
+    ioctl_iowr_nr!(UFFDIO_REGISTER, UFFDIO, 0x00, UffdioRegister);
+    ioctl_ior_nr!(UFFDIO_UNREGISTER, UFFDIO, 0x01, UffdioRange);
+}
+
+use self::bindings::*;
+
+/// A userfaultfd object to track page faults on registered memory ranges.
+pub struct Userfaultfd {
+    file: File,
+    features: u64,
+}
+
+impl Userfaultfd {
+    /// Create a new userfaultfd object and negotiate the API version with the kernel.
+    pub fn new() -> IOResult<Self> {
+        let flags = libc::O_CLOEXEC | libc::O_NONBLOCK;
+        // Safe because the syscall doesn't touch any memory and the return value is checked.
+        let fd = unsafe { libc::syscall(libc::SYS_userfaultfd, flags) };
+        if fd < 0 {
+            return Err(IOError::last_os_error());
+        }
+        // Safe because we have just created the file descriptor and own it.
+        let file = unsafe { File::from_raw_fd(fd as RawFd) };
+
+        let mut api = UffdioApi {
+            api: UFFD_API,
+            ..Default::default()
+        };
+        // Safe because the kernel only writes to the UffdioApi object we pass in.
+        let ret = unsafe { ioctl_with_mut_ref(&file, UFFDIO_API(), &mut api) };
+        if ret < 0 {
+            return Err(IOError::last_os_error());
+        }
+
+        Ok(Userfaultfd {
+            file,
+            features: api.features,
+        })
+    }
+
+    /// Get the userfaultfd features supported by the kernel.
+    pub fn features(&self) -> u64 {
+        self.features
+    }
+
+    /// Register the memory range `[addr, addr + len)` to track missing pages.
+    ///
+    /// Both `addr` and `len` must be page aligned. Return the bitmask of ioctls supported on the
+    /// registered range.
+    pub fn register(&self, addr: u64, len: u64) -> IOResult<u64> {
+        let mut reg = UffdioRegister {
+            range: UffdioRange { start: addr, len },
+            mode: UFFDIO_REGISTER_MODE_MISSING,
+            ioctls: 0,
+        };
+        // Safe because the kernel only writes to the UffdioRegister object we pass in.
+        let ret = unsafe { ioctl_with_mut_ref(&self.file, UFFDIO_REGISTER(), &mut reg) };
+        if ret < 0 {
+            return Err(IOError::last_os_error());
+        }
+        Ok(reg.ioctls)
+    }
+
+    /// Unregister the memory range `[addr, addr + len)` from the userfaultfd.
+    pub fn unregister(&self, addr: u64, len: u64) -> IOResult<()> {
+        let mut range = UffdioRange { start: addr, len };
+        // Safe because the kernel only reads the UffdioRange object we pass in.
+        let ret = unsafe { ioctl_with_mut_ref(&self.file, UFFDIO_UNREGISTER(), &mut range) };
+        if ret < 0 {
+            return Err(IOError::last_os_error());
+        }
+        Ok(())
+    }
+
+    /// Duplicate the underlying file descriptor, so it can be sent to the master.
+    pub fn try_clone_file(&self) -> IOResult<File> {
+        self.file.try_clone()
+    }
+}
+
+impl AsRawFd for Userfaultfd {
+    fn as_raw_fd(&self) -> RawFd {
+        self.file.as_raw_fd()
+    }
+}
+
+impl IntoRawFd for Userfaultfd {
+    fn into_raw_fd(self) -> RawFd {
+        self.file.into_raw_fd()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::ptr::null_mut;
+
+    #[test]
+    fn test_userfaultfd_register() {
+        let uffd = match Userfaultfd::new() {
+            Ok(uffd) => uffd,
+            // userfaultfd may be disabled for unprivileged users.
+            Err(_) => return,
+        };
+        assert!(uffd.as_raw_fd() >= 0);
+
+        let len = 0x4000;
+        let addr = unsafe {
+            libc::mmap(
+                null_mut(),
+                len,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+                -1,
+                0,
+            )
+        };
+        assert_ne!(addr, libc::MAP_FAILED);
+
+        assert!(uffd.register(addr as u64 + 1, len as u64).is_err());
+        let ioctls = uffd.register(addr as u64, len as u64).unwrap();
+        assert_ne!(ioctls, 0);
+        uffd.unregister(addr as u64, len as u64).unwrap();
+
+        let file = uffd.try_clone_file().unwrap();
+        assert_ne!(file.as_raw_fd(), uffd.as_raw_fd());
+        unsafe { libc::munmap(addr, len) };
+    }
+}
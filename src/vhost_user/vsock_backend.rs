@@ -0,0 +1,1057 @@
+// Copyright (C) 2020 Alibaba Cloud Computing. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! A vhost-user-vsock slave backend built on the daemon framework.
+//!
+//! The backend is the userspace counterpart of the in-kernel vhost-vsock driver controlled
+//! through `VhostVsock`. Stream sockets of the guest are connected to Unix sockets of the host,
+//! following the hybrid vsock convention:
+//!
+//! * a connection of the guest to the port `P` of the host is forwarded to the Unix socket
+//!   listening at `<uds_path>_P`;
+//! * host applications connect to the Unix socket listening at `<uds_path>` and write
+//!   `CONNECT <port>\n` to connect to the port of the guest. Once the guest accepts the
+//!   connection, `OK <host port>\n` is written back and the Unix socket carries the stream.
+//!
+//! All the virtqueues are served by a single worker thread, which also waits for the Unix
+//! sockets: backends must be registered to the worker thread with
+//! `VsockBackend::register_sockets()` before the daemon is started. The guest CID is exposed
+//! through the device configuration space. Sequential packet sockets aren't supported.
+
+use std::cmp;
+use std::collections::{HashMap, VecDeque};
+use std::io::{self, Read, Write};
+use std::net::Shutdown;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::sync::{Arc, Mutex, RwLock};
+
+use vm_memory::{Bytes, GuestMemoryMmap};
+use vmm_sys_util::epoll::{ControlOperation, Epoll, EpollEvent, EventSet};
+
+use super::daemon::{
+    read_config_space, DescriptorChain, VhostUserBackend, Vring, VringEpollHandler,
+};
+use super::message::VhostUserProtocolFeatures;
+use crate::features::{DeviceFeatures, VirtioFeatures, VirtioVsockFeatures};
+
+/// CID of the host.
+pub const VSOCK_HOST_CID: u64 = 2;
+/// Size of the header of all packets, struct virtio_vsock_hdr.
+pub const VSOCK_PKT_HDR_SIZE: usize = 44;
+
+// Packet types and operations.
+const VSOCK_TYPE_STREAM: u16 = 1;
+const VSOCK_OP_REQUEST: u16 = 1;
+const VSOCK_OP_RESPONSE: u16 = 2;
+const VSOCK_OP_RST: u16 = 3;
+const VSOCK_OP_SHUTDOWN: u16 = 4;
+const VSOCK_OP_RW: u16 = 5;
+const VSOCK_OP_CREDIT_UPDATE: u16 = 6;
+const VSOCK_OP_CREDIT_REQUEST: u16 = 7;
+// Flags of shutdown packets.
+const VSOCK_SHUTDOWN_RCV: u32 = 1;
+const VSOCK_SHUTDOWN_SEND: u32 = 2;
+
+// Size of the virtio-vsock configuration space: guest_cid.
+const VIRTIO_VSOCK_CONFIG_SIZE: usize = 8;
+// Largest data payload of a packet.
+const MAX_PKT_DATA_SIZE: usize = 64 * 1024;
+// Data of the guest the backend buffers for each connection until the host takes it.
+const CONN_BUF_SIZE: u32 = 256 * 1024;
+// First port of the host allocated to connections initiated by the host.
+const FIRST_HOST_PORT: u32 = 1 << 30;
+// Longest CONNECT command of host applications.
+const MAX_CONNECT_CMD_SIZE: usize = 32;
+// Size of the virtqueues.
+const QUEUE_SIZE: usize = 256;
+// Indexes of the receive, transmit and event virtqueues.
+const RX_QUEUE: u16 = 0;
+const TX_QUEUE: u16 = 1;
+// Maximum number of events to fetch from the sockets at once.
+const EPOLL_EVENTS_LEN: usize = 32;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+struct PacketHeader {
+    src_cid: u64,
+    dst_cid: u64,
+    src_port: u32,
+    dst_port: u32,
+    len: u32,
+    type_: u16,
+    op: u16,
+    flags: u32,
+    buf_alloc: u32,
+    fwd_cnt: u32,
+}
+
+impl PacketHeader {
+    fn from_bytes(buf: &[u8]) -> Self {
+        let u16_at = |o: usize| u16::from_le_bytes([buf[o], buf[o + 1]]);
+        let u32_at = |o: usize| {
+            let mut bytes = [0u8; 4];
+            bytes.copy_from_slice(&buf[o..o + 4]);
+            u32::from_le_bytes(bytes)
+        };
+        let u64_at = |o: usize| {
+            let mut bytes = [0u8; 8];
+            bytes.copy_from_slice(&buf[o..o + 8]);
+            u64::from_le_bytes(bytes)
+        };
+        PacketHeader {
+            src_cid: u64_at(0),
+            dst_cid: u64_at(8),
+            src_port: u32_at(16),
+            dst_port: u32_at(20),
+            len: u32_at(24),
+            type_: u16_at(28),
+            op: u16_at(30),
+            flags: u32_at(32),
+            buf_alloc: u32_at(36),
+            fwd_cnt: u32_at(40),
+        }
+    }
+
+    fn to_bytes(self, buf: &mut [u8]) {
+        buf[0..8].copy_from_slice(&self.src_cid.to_le_bytes());
+        buf[8..16].copy_from_slice(&self.dst_cid.to_le_bytes());
+        buf[16..20].copy_from_slice(&self.src_port.to_le_bytes());
+        buf[20..24].copy_from_slice(&self.dst_port.to_le_bytes());
+        buf[24..28].copy_from_slice(&self.len.to_le_bytes());
+        buf[28..30].copy_from_slice(&self.type_.to_le_bytes());
+        buf[30..32].copy_from_slice(&self.op.to_le_bytes());
+        buf[32..36].copy_from_slice(&self.flags.to_le_bytes());
+        buf[36..40].copy_from_slice(&self.buf_alloc.to_le_bytes());
+        buf[40..44].copy_from_slice(&self.fwd_cnt.to_le_bytes());
+    }
+}
+
+// Connections are identified by the port of the host and the port of the guest.
+type ConnKey = (u32, u32);
+
+// Stream connection between a socket of the guest and a Unix socket of the host.
+struct Connection {
+    stream: UnixStream,
+    // The connection is initiated by the host and waits for the guest to accept it.
+    connecting: bool,
+    // The host has closed its socket, so no more data is sent to the guest.
+    host_shutdown: bool,
+    // Data of the guest the host hasn't taken yet.
+    tx_buf: Vec<u8>,
+    // Bytes of data forwarded to the host, and last value reported to the guest.
+    fwd_cnt: u32,
+    last_fwd_cnt: u32,
+    // Bytes of data sent to the guest, and receive buffer of the guest.
+    rx_cnt: u32,
+    peer_buf_alloc: u32,
+    peer_fwd_cnt: u32,
+}
+
+impl Connection {
+    fn new(stream: UnixStream, connecting: bool) -> Self {
+        Connection {
+            stream,
+            connecting,
+            host_shutdown: false,
+            tx_buf: Vec::new(),
+            fwd_cnt: 0,
+            last_fwd_cnt: 0,
+            rx_cnt: 0,
+            peer_buf_alloc: 0,
+            peer_fwd_cnt: 0,
+        }
+    }
+
+    // Bytes of data the guest can receive.
+    fn peer_credit(&self) -> u32 {
+        self.peer_buf_alloc
+            .saturating_sub(self.rx_cnt.wrapping_sub(self.peer_fwd_cnt))
+    }
+
+    // Forward data of the guest to the host, keeping what the host can't take yet.
+    fn send(&mut self, data: &[u8]) -> io::Result<()> {
+        if !self.tx_buf.is_empty() {
+            self.tx_buf.extend_from_slice(data);
+            return Ok(());
+        }
+        let len = match self.stream.write(data) {
+            Ok(len) => len,
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => 0,
+            Err(e) => return Err(e),
+        };
+        self.fwd_cnt = self.fwd_cnt.wrapping_add(len as u32);
+        self.tx_buf.extend_from_slice(&data[len..]);
+        Ok(())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        while !self.tx_buf.is_empty() {
+            let len = match self.stream.write(&self.tx_buf) {
+                Ok(len) => len,
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) => return Err(e),
+            };
+            self.fwd_cnt = self.fwd_cnt.wrapping_add(len as u32);
+            self.tx_buf.drain(..len);
+        }
+        Ok(())
+    }
+}
+
+// Control packet to be sent to the guest, filled with the state of the connection when sent.
+struct ControlPacket {
+    key: ConnKey,
+    op: u16,
+}
+
+// Host applications waiting to send their CONNECT command.
+struct PendingStream {
+    stream: UnixStream,
+    cmd: Vec<u8>,
+}
+
+struct VsockState {
+    epoll: Epoll,
+    listener: UnixListener,
+    pending: HashMap<RawFd, PendingStream>,
+    conns: HashMap<ConnKey, Connection>,
+    conn_fds: HashMap<RawFd, ConnKey>,
+    control: VecDeque<ControlPacket>,
+    next_host_port: u32,
+}
+
+impl VsockState {
+    // The sockets are watched for new events only, as the sockets closed by the host stay ready
+    // until the guest resets the connection.
+    fn watch(&self, fd: RawFd, events: EventSet, op: ControlOperation) -> io::Result<()> {
+        let events = events | EventSet::EDGE_TRIGGERED;
+        self.epoll.ctl(op, fd, EpollEvent::new(events, fd as u64))
+    }
+
+    fn add_conn(&mut self, key: ConnKey, conn: Connection) -> io::Result<()> {
+        let fd = conn.stream.as_raw_fd();
+        self.watch(fd, EventSet::IN, ControlOperation::Add)?;
+        self.conn_fds.insert(fd, key);
+        self.conns.insert(key, conn);
+        Ok(())
+    }
+
+    fn remove_conn(&mut self, key: ConnKey) {
+        if let Some(conn) = self.conns.remove(&key) {
+            let fd = conn.stream.as_raw_fd();
+            // The socket is closed right after, which removes it from the epoll set anyway.
+            let _ = self.watch(fd, EventSet::empty(), ControlOperation::Delete);
+            self.conn_fds.remove(&fd);
+        }
+    }
+
+    // Reset the connection, and tell the guest.
+    fn reset_conn(&mut self, key: ConnKey) {
+        self.remove_conn(key);
+        self.push_control(key, VSOCK_OP_RST);
+    }
+
+    fn push_control(&mut self, key: ConnKey, op: u16) {
+        self.control.push_back(ControlPacket { key, op });
+    }
+
+    // Handle the events of the Unix sockets of the host.
+    fn process_events(&mut self) -> io::Result<()> {
+        let mut events = vec![EpollEvent::default(); EPOLL_EVENTS_LEN];
+        loop {
+            let count = match self.epoll.wait(0, &mut events) {
+                Ok(count) => count,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            };
+            if count == 0 {
+                return Ok(());
+            }
+            for event in events.iter().take(count) {
+                let fd = event.data() as RawFd;
+                if fd == self.listener.as_raw_fd() {
+                    self.accept()?;
+                } else if self.pending.contains_key(&fd) {
+                    self.read_connect_cmd(fd)?;
+                } else if let Some(&key) = self.conn_fds.get(&fd) {
+                    if event.event_set().contains(EventSet::OUT) {
+                        self.flush_conn(key)?;
+                    }
+                }
+            }
+        }
+    }
+
+    fn accept(&mut self) -> io::Result<()> {
+        loop {
+            let stream = match self.listener.accept() {
+                Ok((stream, _)) => stream,
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(()),
+                Err(e) => return Err(e),
+            };
+            stream.set_nonblocking(true)?;
+            let fd = stream.as_raw_fd();
+            self.watch(fd, EventSet::IN, ControlOperation::Add)?;
+            self.pending.insert(
+                fd,
+                PendingStream {
+                    stream,
+                    cmd: Vec::new(),
+                },
+            );
+        }
+    }
+
+    // Read the CONNECT command of a host application, and forward the connection request to the
+    // guest once complete. Invalid commands close the Unix socket.
+    fn read_connect_cmd(&mut self, fd: RawFd) -> io::Result<()> {
+        let mut buf = [0u8; MAX_CONNECT_CMD_SIZE];
+        let pending = self.pending.get_mut(&fd).unwrap();
+        let port = match pending.stream.read(&mut buf) {
+            Ok(0) => None,
+            Ok(len) => {
+                pending.cmd.extend_from_slice(&buf[..len]);
+                if !pending.cmd.contains(&b'\n') {
+                    if pending.cmd.len() <= MAX_CONNECT_CMD_SIZE {
+                        return Ok(());
+                    }
+                    None
+                } else {
+                    std::str::from_utf8(&pending.cmd)
+                        .ok()
+                        .and_then(|cmd| cmd.trim_end().strip_prefix("CONNECT "))
+                        .and_then(|port| port.parse::<u32>().ok())
+                }
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(()),
+            Err(_) => None,
+        };
+        let pending = self.pending.remove(&fd).unwrap();
+        self.watch(fd, EventSet::empty(), ControlOperation::Delete)?;
+        if let Some(port) = port {
+            let key = (self.next_host_port, port);
+            self.next_host_port = self.next_host_port.wrapping_add(1).max(FIRST_HOST_PORT);
+            self.add_conn(key, Connection::new(pending.stream, true))?;
+            self.push_control(key, VSOCK_OP_REQUEST);
+        }
+        Ok(())
+    }
+
+    fn flush_conn(&mut self, key: ConnKey) -> io::Result<()> {
+        let conn = self.conns.get_mut(&key).unwrap();
+        if conn.flush().is_err() {
+            self.reset_conn(key);
+            return Ok(());
+        }
+        if conn.tx_buf.is_empty() {
+            let fd = conn.stream.as_raw_fd();
+            self.watch(fd, EventSet::IN, ControlOperation::Modify)?;
+        }
+        self.update_credit(key);
+        Ok(())
+    }
+
+    // Tell the guest about the space freed in the buffer of the connection, once half of it has
+    // been forwarded to the host.
+    fn update_credit(&mut self, key: ConnKey) {
+        if let Some(conn) = self.conns.get(&key) {
+            if conn.fwd_cnt.wrapping_sub(conn.last_fwd_cnt) >= CONN_BUF_SIZE / 2 {
+                self.push_control(key, VSOCK_OP_CREDIT_UPDATE);
+            }
+        }
+    }
+
+    // Handle a packet sent by the guest.
+    fn process_tx_packet(
+        &mut self,
+        hdr: &PacketHeader,
+        data: &[u8],
+        guest_cid: u64,
+        uds_path: &str,
+    ) {
+        let key = (hdr.dst_port, hdr.src_port);
+        if hdr.src_cid != guest_cid
+            || hdr.dst_cid != VSOCK_HOST_CID
+            || hdr.type_ != VSOCK_TYPE_STREAM
+        {
+            if hdr.op != VSOCK_OP_RST {
+                self.reset_conn(key);
+            }
+            return;
+        }
+        if let Some(conn) = self.conns.get_mut(&key) {
+            conn.peer_buf_alloc = hdr.buf_alloc;
+            conn.peer_fwd_cnt = hdr.fwd_cnt;
+        }
+
+        match hdr.op {
+            VSOCK_OP_REQUEST => {
+                if self.conns.contains_key(&key) {
+                    self.reset_conn(key);
+                    return;
+                }
+                let path = format!("{}_{}", uds_path, hdr.dst_port);
+                let stream = match UnixStream::connect(path) {
+                    Ok(stream) if stream.set_nonblocking(true).is_ok() => stream,
+                    _ => {
+                        self.push_control(key, VSOCK_OP_RST);
+                        return;
+                    }
+                };
+                let mut conn = Connection::new(stream, false);
+                conn.peer_buf_alloc = hdr.buf_alloc;
+                conn.peer_fwd_cnt = hdr.fwd_cnt;
+                if self.add_conn(key, conn).is_err() {
+                    self.reset_conn(key);
+                    return;
+                }
+                self.push_control(key, VSOCK_OP_RESPONSE);
+            }
+            VSOCK_OP_RESPONSE => match self.conns.get_mut(&key) {
+                Some(conn) if conn.connecting => {
+                    conn.connecting = false;
+                    // The reply isn't data of the guest, and fits in the empty socket buffer.
+                    let reply = format!("OK {}\n", key.0);
+                    if conn.stream.write(reply.as_bytes()).ok() != Some(reply.len()) {
+                        self.reset_conn(key);
+                    }
+                }
+                _ => self.reset_conn(key),
+            },
+            VSOCK_OP_RW => {
+                let conn = match self.conns.get_mut(&key) {
+                    Some(conn) if !conn.connecting => conn,
+                    _ => {
+                        self.reset_conn(key);
+                        return;
+                    }
+                };
+                let was_empty = conn.tx_buf.is_empty();
+                // The guest doesn't respect the credit of the connection otherwise.
+                if conn.tx_buf.len() + data.len() > CONN_BUF_SIZE as usize
+                    || conn.send(data).is_err()
+                {
+                    self.reset_conn(key);
+                    return;
+                }
+                if was_empty && !conn.tx_buf.is_empty() {
+                    let fd = conn.stream.as_raw_fd();
+                    if self
+                        .watch(fd, EventSet::IN | EventSet::OUT, ControlOperation::Modify)
+                        .is_err()
+                    {
+                        self.reset_conn(key);
+                        return;
+                    }
+                }
+                self.update_credit(key);
+            }
+            VSOCK_OP_CREDIT_UPDATE => {
+                if !self.conns.contains_key(&key) {
+                    self.reset_conn(key);
+                }
+            }
+            VSOCK_OP_CREDIT_REQUEST => {
+                if self.conns.contains_key(&key) {
+                    self.push_control(key, VSOCK_OP_CREDIT_UPDATE);
+                } else {
+                    self.reset_conn(key);
+                }
+            }
+            VSOCK_OP_SHUTDOWN => {
+                let both = VSOCK_SHUTDOWN_RCV | VSOCK_SHUTDOWN_SEND;
+                match self.conns.get_mut(&key) {
+                    // Close the connection once the guest won't send nor receive anymore.
+                    Some(_) if hdr.flags & both == both => self.reset_conn(key),
+                    Some(conn) if hdr.flags & VSOCK_SHUTDOWN_SEND != 0 => {
+                        if conn.tx_buf.is_empty() {
+                            let _ = conn.stream.shutdown(Shutdown::Write);
+                        }
+                    }
+                    Some(_) => {}
+                    None => self.reset_conn(key),
+                }
+            }
+            VSOCK_OP_RST => self.remove_conn(key),
+            _ => self.reset_conn(key),
+        }
+    }
+
+    // Build the next packet to be sent to the guest into `buf`, returning its size.
+    fn next_rx_packet(&mut self, guest_cid: u64, buf: &mut [u8]) -> Option<usize> {
+        let mut hdr = PacketHeader {
+            src_cid: VSOCK_HOST_CID,
+            dst_cid: guest_cid,
+            type_: VSOCK_TYPE_STREAM,
+            buf_alloc: CONN_BUF_SIZE,
+            ..Default::default()
+        };
+        if let Some(packet) = self.control.pop_front() {
+            hdr.src_port = packet.key.0;
+            hdr.dst_port = packet.key.1;
+            hdr.op = packet.op;
+            if let Some(conn) = self.conns.get_mut(&packet.key) {
+                hdr.fwd_cnt = conn.fwd_cnt;
+                conn.last_fwd_cnt = conn.fwd_cnt;
+            }
+            hdr.to_bytes(&mut buf[..VSOCK_PKT_HDR_SIZE]);
+            return Some(VSOCK_PKT_HDR_SIZE);
+        }
+
+        // Forward the data received from the host, within the credit of the guest.
+        let data_size = cmp::min(buf.len() - VSOCK_PKT_HDR_SIZE, MAX_PKT_DATA_SIZE);
+        let mut closed = None;
+        for (&key, conn) in self.conns.iter_mut() {
+            if conn.connecting || conn.host_shutdown || conn.peer_credit() == 0 {
+                continue;
+            }
+            let len = cmp::min(data_size, conn.peer_credit() as usize);
+            let data = &mut buf[VSOCK_PKT_HDR_SIZE..VSOCK_PKT_HDR_SIZE + len];
+            match conn.stream.read(data) {
+                Ok(0) => {
+                    conn.host_shutdown = true;
+                    hdr.op = VSOCK_OP_SHUTDOWN;
+                    hdr.flags = VSOCK_SHUTDOWN_RCV | VSOCK_SHUTDOWN_SEND;
+                }
+                Ok(len) => {
+                    conn.rx_cnt = conn.rx_cnt.wrapping_add(len as u32);
+                    hdr.op = VSOCK_OP_RW;
+                    hdr.len = len as u32;
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => continue,
+                Err(_) => {
+                    closed = Some(key);
+                    break;
+                }
+            }
+            hdr.src_port = key.0;
+            hdr.dst_port = key.1;
+            hdr.fwd_cnt = conn.fwd_cnt;
+            conn.last_fwd_cnt = conn.fwd_cnt;
+            hdr.to_bytes(&mut buf[..VSOCK_PKT_HDR_SIZE]);
+            return Some(VSOCK_PKT_HDR_SIZE + hdr.len as usize);
+        }
+        if let Some(key) = closed {
+            self.reset_conn(key);
+            return self.next_rx_packet(guest_cid, buf);
+        }
+        None
+    }
+}
+
+/// A vhost-user-vsock device backend.
+///
+/// Virtqueues 0, 1 and 2 are the receive, transmit and event virtqueues of the device.
+pub struct VsockBackend {
+    guest_cid: u64,
+    uds_path: String,
+    state: Mutex<VsockState>,
+    mem: Option<GuestMemoryMmap>,
+}
+
+impl VsockBackend {
+    /// Create a backend for a guest of CID `guest_cid`, listening at `uds_path` for connections
+    /// of host applications and connecting to `<uds_path>_<port>` on behalf of the guest.
+    pub fn new(guest_cid: u64, uds_path: &str) -> io::Result<Self> {
+        if guest_cid <= VSOCK_HOST_CID || guest_cid >= u64::from(u32::MAX) {
+            return Err(io::Error::from_raw_os_error(libc::EINVAL));
+        }
+        let listener = UnixListener::bind(uds_path)?;
+        listener.set_nonblocking(true)?;
+        let state = VsockState {
+            epoll: Epoll::new()?,
+            listener,
+            pending: HashMap::new(),
+            conns: HashMap::new(),
+            conn_fds: HashMap::new(),
+            control: VecDeque::new(),
+            next_host_port: FIRST_HOST_PORT,
+        };
+        state.watch(
+            state.listener.as_raw_fd(),
+            EventSet::IN,
+            ControlOperation::Add,
+        )?;
+        Ok(VsockBackend {
+            guest_cid,
+            uds_path: uds_path.to_string(),
+            state: Mutex::new(state),
+            mem: None,
+        })
+    }
+
+    /// Get the CID of the guest.
+    pub fn guest_cid(&self) -> u64 {
+        self.guest_cid
+    }
+
+    /// Register the Unix sockets to the worker thread of the daemon, as returned by
+    /// `Daemon::get_vring_workers()`, to process incoming connections and data.
+    pub fn register_sockets(
+        &self,
+        workers: &[Arc<VringEpollHandler<VsockBackend>>],
+    ) -> io::Result<()> {
+        if workers.len() != 1 {
+            return Err(io::Error::from_raw_os_error(libc::EINVAL));
+        }
+        // The sockets are watched by an epoll set of their own, which gets ready anew on each
+        // event of the sockets.
+        let state = self.state.lock().unwrap();
+        workers[0].register_listener(
+            state.epoll.as_raw_fd(),
+            EventSet::IN | EventSet::EDGE_TRIGGERED,
+            self.sockets_event(),
+        )
+    }
+
+    fn sockets_event(&self) -> u64 {
+        // Data num_queues is reserved for the exit event of the worker thread.
+        (self.num_queues() + 1) as u64
+    }
+
+    fn config_space(&self) -> [u8; VIRTIO_VSOCK_CONFIG_SIZE] {
+        self.guest_cid.to_le_bytes()
+    }
+
+    fn process_rx(&self, mem: &GuestMemoryMmap, vring: &mut Vring) -> io::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        let mut buf = vec![0u8; VSOCK_PKT_HDR_SIZE + MAX_PKT_DATA_SIZE];
+        let mut used = false;
+        while let Some(chain) = vring.pop_avail(mem)? {
+            let size: usize = chain.writable().map(|desc| desc.len() as usize).sum();
+            if size < VSOCK_PKT_HDR_SIZE {
+                // The buffers can't take any packet, hand them back to the guest unused.
+                vring.add_used(mem, chain.head_index(), 0)?;
+                used = true;
+                continue;
+            }
+            let size = cmp::min(size, buf.len());
+            let len = match state.next_rx_packet(self.guest_cid, &mut buf[..size]) {
+                Some(len) => len,
+                None => {
+                    // Give the buffers back for the next packet.
+                    vring.return_avail(&chain);
+                    break;
+                }
+            };
+            Self::write_chain(mem, &chain, &buf[..len])?;
+            vring.add_used(mem, chain.head_index(), len as u32)?;
+            used = true;
+        }
+        if used {
+            vring.notify_used_queue(mem)?;
+        }
+        Ok(())
+    }
+
+    fn process_tx(&self, mem: &GuestMemoryMmap, vring: &mut Vring) -> io::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        let mut used = false;
+        while let Some(chain) = vring.pop_avail(mem)? {
+            // Malformed packets are dropped, their chain being completed all the same so the
+            // guest gets its buffers back.
+            if let Some(buf) = Self::read_tx_packet(mem, &chain) {
+                let hdr = PacketHeader::from_bytes(&buf);
+                let data = &buf[VSOCK_PKT_HDR_SIZE..];
+                state.process_tx_packet(
+                    &hdr,
+                    &data[..hdr.len as usize],
+                    self.guest_cid,
+                    &self.uds_path,
+                );
+            }
+            vring.add_used(mem, chain.head_index(), 0)?;
+            used = true;
+        }
+        if used {
+            vring.notify_used_queue(mem)?;
+        }
+        Ok(())
+    }
+
+    // Read the packet of a tx chain, unless it's truncated, oversized or out of the guest memory.
+    fn read_tx_packet(mem: &GuestMemoryMmap, chain: &DescriptorChain) -> Option<Vec<u8>> {
+        let mut buf = Vec::new();
+        for desc in chain.readable() {
+            if buf.len() + desc.len() as usize > VSOCK_PKT_HDR_SIZE + MAX_PKT_DATA_SIZE {
+                return None;
+            }
+            let start = buf.len();
+            buf.resize(start + desc.len() as usize, 0);
+            mem.read_slice(&mut buf[start..], desc.addr()).ok()?;
+        }
+        if buf.len() < VSOCK_PKT_HDR_SIZE
+            || PacketHeader::from_bytes(&buf).len as usize > buf.len() - VSOCK_PKT_HDR_SIZE
+        {
+            return None;
+        }
+        Some(buf)
+    }
+
+    // Write a packet into the writable buffers of the chain, which are large enough.
+    fn write_chain(mem: &GuestMemoryMmap, chain: &DescriptorChain, buf: &[u8]) -> io::Result<()> {
+        let mut written = 0;
+        for desc in chain.writable() {
+            if written == buf.len() {
+                break;
+            }
+            let len = cmp::min(desc.len() as usize, buf.len() - written);
+            mem.write_slice(&buf[written..written + len], desc.addr())
+                .map_err(|_| io::Error::from_raw_os_error(libc::EFAULT))?;
+            written += len;
+        }
+        Ok(())
+    }
+
+    fn process_vring(
+        &self,
+        queue_index: u16,
+        vrings: &[Arc<RwLock<Vring>>],
+        thread_id: usize,
+    ) -> io::Result<()> {
+        let mut vring = vrings[queue_index as usize].write().unwrap();
+        if vring.is_enabled() {
+            self.process_queue(queue_index, &mut vring, thread_id)?;
+        }
+        Ok(())
+    }
+}
+
+impl VhostUserBackend for VsockBackend {
+    fn num_queues(&self) -> usize {
+        3
+    }
+
+    fn max_queue_size(&self) -> usize {
+        QUEUE_SIZE
+    }
+
+    fn features(&self) -> u64 {
+        VirtioVsockFeatures::STREAM
+            .with_common(VirtioFeatures::VERSION_1 | VirtioFeatures::PROTOCOL_FEATURES)
+    }
+
+    fn protocol_features(&self) -> VhostUserProtocolFeatures {
+        // MQ lets the master enable the transmit and event virtqueues.
+        VhostUserProtocolFeatures::MQ | VhostUserProtocolFeatures::CONFIG
+    }
+
+    fn update_memory(&mut self, mem: GuestMemoryMmap) -> io::Result<()> {
+        self.mem = Some(mem);
+        Ok(())
+    }
+
+    fn get_config(&self, offset: u32, size: u32) -> Vec<u8> {
+        read_config_space(&self.config_space(), offset, size)
+    }
+
+    fn set_config(&mut self, _offset: u32, _buf: &[u8]) -> io::Result<()> {
+        // The guest CID is read only.
+        Err(io::Error::from_raw_os_error(libc::EPERM))
+    }
+
+    fn queues_per_thread(&self) -> Vec<u64> {
+        vec![0x7]
+    }
+
+    fn process_queue(
+        &self,
+        queue_index: u16,
+        vring: &mut Vring,
+        _thread_id: usize,
+    ) -> io::Result<()> {
+        let mem = match self.mem {
+            Some(ref mem) => mem,
+            None => return Ok(()),
+        };
+        // The buffers of the event virtqueue are kept for transport reset events, which are
+        // never sent as connections aren't migrated.
+        match queue_index {
+            RX_QUEUE => self.process_rx(mem, vring),
+            TX_QUEUE => self.process_tx(mem, vring),
+            _ => Ok(()),
+        }
+    }
+
+    fn handle_event(
+        &self,
+        device_event: u16,
+        _evset: EventSet,
+        vrings: &[Arc<RwLock<Vring>>],
+        thread_id: usize,
+    ) -> io::Result<bool> {
+        // Packets of the guest and events of the sockets may both produce packets for the
+        // receive virtqueue.
+        if u64::from(device_event) == self.sockets_event() {
+            self.state.lock().unwrap().process_events()?;
+        } else if device_event == TX_QUEUE {
+            self.process_vring(TX_QUEUE, vrings, thread_id)?;
+        } else if device_event != RX_QUEUE {
+            return Ok(false);
+        }
+        self.process_vring(RX_QUEUE, vrings, thread_id)?;
+        Ok(false)
+    }
+}
+
+#[cfg(all(test, feature = "vhost-user-master"))]
+mod tests {
+    use super::*;
+    use crate::backend::{
+        VhostFeatures, VhostMemory, VhostOwner, VhostUserMemoryRegionInfo, VhostVring,
+        VringConfigData,
+    };
+    use crate::vhost_user::message::{VhostUserConfigFlags, VHOST_USER_CONFIG_OFFSET};
+    use crate::vhost_user::{Daemon, Listener, Master, VhostUserMaster};
+    use std::fs;
+    use std::thread;
+    use vm_memory::{FileOffset, GuestAddress};
+    use vmm_sys_util::eventfd::EventFd;
+    use vmm_sys_util::tempfile::TempFile;
+
+    const GUEST_CID: u64 = 3;
+
+    // Guest physical addresses of the rings and buffers, in a single region mapped at VA_BASE.
+    const VA_BASE: u64 = 0x7f00_0000_0000;
+    const RING_ADDRS: [(u64, u64, u64); 3] = [
+        (0x1000, 0x2000, 0x3000),
+        (0x4000, 0x5000, 0x6000),
+        (0x7000, 0x8000, 0x9000),
+    ];
+    const RX_BUF: u64 = 0xa000;
+    const TX_BUF: u64 = 0xc000;
+
+    // Make the buffer at `addr` available as the only descriptor of the vring, at the position
+    // `idx` of the available ring.
+    fn add_avail(mem: &GuestMemoryMmap, queue: usize, addr: u64, len: u32, write: bool, idx: u16) {
+        let (desc, avail, _) = RING_ADDRS[queue];
+        mem.write_obj(addr, GuestAddress(desc)).unwrap();
+        mem.write_obj(len, GuestAddress(desc + 8)).unwrap();
+        mem.write_obj(if write { 2u16 } else { 0u16 }, GuestAddress(desc + 12))
+            .unwrap();
+        mem.write_obj(
+            0u16,
+            GuestAddress(avail + 4 + 2 * u64::from((idx - 1) % 16)),
+        )
+        .unwrap();
+        mem.write_obj(idx, GuestAddress(avail + 2)).unwrap();
+    }
+
+    // Send a packet from the guest port `src_port` to the host port `dst_port`.
+    fn send_packet(
+        mem: &GuestMemoryMmap,
+        calls: &[(EventFd, EventFd)],
+        idx: u16,
+        op: u16,
+        ports: (u32, u32),
+        data: &[u8],
+    ) {
+        let hdr = PacketHeader {
+            src_cid: GUEST_CID,
+            dst_cid: VSOCK_HOST_CID,
+            src_port: ports.0,
+            dst_port: ports.1,
+            len: data.len() as u32,
+            type_: VSOCK_TYPE_STREAM,
+            op,
+            buf_alloc: 0x1000,
+            ..Default::default()
+        };
+        let mut buf = vec![0u8; VSOCK_PKT_HDR_SIZE];
+        hdr.to_bytes(&mut buf);
+        buf.extend_from_slice(data);
+        mem.write_slice(&buf, GuestAddress(TX_BUF)).unwrap();
+        add_avail(mem, 1, TX_BUF, buf.len() as u32, false, idx);
+        calls[1].1.write(1).unwrap();
+        assert_eq!(calls[1].0.read().unwrap(), 1);
+    }
+
+    // Receive the packet the device has written into the buffer at the position `idx` of the
+    // used ring.
+    fn recv_packet(
+        mem: &GuestMemoryMmap,
+        calls: &[(EventFd, EventFd)],
+        idx: u16,
+    ) -> (PacketHeader, Vec<u8>) {
+        assert_eq!(calls[0].0.read().unwrap(), 1);
+        let used = RING_ADDRS[0].2;
+        let used_idx: u16 = mem.read_obj(GuestAddress(used + 2)).unwrap();
+        assert_eq!(used_idx, idx);
+        let len: u32 = mem
+            .read_obj(GuestAddress(used + 4 + 8 * u64::from(idx - 1) + 4))
+            .unwrap();
+        let mut buf = vec![0u8; len as usize];
+        mem.read_slice(&mut buf, GuestAddress(RX_BUF)).unwrap();
+        let hdr = PacketHeader::from_bytes(&buf);
+        assert_eq!(hdr.src_cid, VSOCK_HOST_CID);
+        assert_eq!(hdr.dst_cid, GUEST_CID);
+        assert_eq!(hdr.buf_alloc, CONN_BUF_SIZE);
+        (hdr, buf.split_off(VSOCK_PKT_HDR_SIZE))
+    }
+
+    fn wait_rx_packet(
+        mem: &GuestMemoryMmap,
+        calls: &[(EventFd, EventFd)],
+        idx: u16,
+    ) -> (PacketHeader, Vec<u8>) {
+        add_avail(mem, 0, RX_BUF, 0x2000, true, idx);
+        calls[0].1.write(1).unwrap();
+        recv_packet(mem, calls, idx)
+    }
+
+    #[test]
+    fn test_vsock_backend() {
+        let path = "/tmp/vhost_user_lib_unit_test_vsock_backend";
+        let uds_path = "/tmp/vhost_user_lib_unit_test_vsock_backend_uds";
+        let _ = fs::remove_file(uds_path);
+        let _ = fs::remove_file(format!("{}_1234", uds_path));
+        assert!(VsockBackend::new(VSOCK_HOST_CID, uds_path).is_err());
+        let backend = VsockBackend::new(GUEST_CID, uds_path).unwrap();
+        assert_eq!(backend.guest_cid(), GUEST_CID);
+        let host_listener = UnixListener::bind(format!("{}_1234", uds_path)).unwrap();
+        let backend = Arc::new(RwLock::new(backend));
+        let mut daemon = Daemon::new("test-vsock".to_string(), backend.clone()).unwrap();
+        backend
+            .read()
+            .unwrap()
+            .register_sockets(&daemon.get_vring_workers())
+            .unwrap();
+
+        let file = TempFile::new().unwrap().into_file();
+        file.set_len(0x10000).unwrap();
+        let mem = GuestMemoryMmap::from_ranges_with_files(&[(
+            GuestAddress(0),
+            0x10000,
+            Some(FileOffset::new(file.try_clone().unwrap(), 0)),
+        )])
+        .unwrap();
+
+        let listener = Listener::new(path, true).unwrap();
+        let master_thread = thread::spawn(move || {
+            let mut master = Master::connect(path, 3).unwrap();
+            master.set_owner().unwrap();
+            let features = master.get_features().unwrap();
+            master.set_features(features).unwrap();
+            master.get_protocol_features().unwrap();
+            master
+                .set_protocol_features(
+                    VhostUserProtocolFeatures::MQ | VhostUserProtocolFeatures::CONFIG,
+                )
+                .unwrap();
+            assert_eq!(master.get_queue_num().unwrap(), 3);
+            let (_, config) = master
+                .get_config(
+                    VHOST_USER_CONFIG_OFFSET,
+                    VIRTIO_VSOCK_CONFIG_SIZE as u32,
+                    VhostUserConfigFlags::WRITABLE,
+                    &[0u8; VIRTIO_VSOCK_CONFIG_SIZE],
+                )
+                .unwrap();
+            assert_eq!(&config[..], &GUEST_CID.to_le_bytes());
+
+            let region = VhostUserMemoryRegionInfo {
+                guest_phys_addr: 0,
+                memory_size: 0x10000,
+                userspace_addr: VA_BASE,
+                mmap_offset: 0,
+                mmap_handle: file.as_raw_fd(),
+            };
+            master.set_mem_table(&[region]).unwrap();
+
+            let mut calls = Vec::new();
+            for (queue, &(desc, avail, used)) in RING_ADDRS.iter().enumerate() {
+                master.set_vring_num(queue, 16).unwrap();
+                let config = VringConfigData {
+                    queue_max_size: QUEUE_SIZE as u16,
+                    queue_size: 16,
+                    flags: 0,
+                    desc_table_addr: VA_BASE + desc,
+                    used_ring_addr: VA_BASE + used,
+                    avail_ring_addr: VA_BASE + avail,
+                    log_addr: None,
+                };
+                master.set_vring_addr(queue, &config).unwrap();
+                master.set_vring_base(queue, 0).unwrap();
+                let call = EventFd::new(0).unwrap();
+                master.set_vring_call(queue, &call).unwrap();
+                master.set_vring_enable(queue, true).unwrap();
+                let kick = EventFd::new(0).unwrap();
+                master.set_vring_kick(queue, &kick).unwrap();
+                calls.push((call, kick));
+            }
+
+            // Connect the guest to the port 1234 of the host.
+            add_avail(&mem, 0, RX_BUF, 0x2000, true, 1);
+            calls[0].1.write(1).unwrap();
+            send_packet(&mem, &calls, 1, VSOCK_OP_REQUEST, (40000, 1234), &[]);
+            let (hdr, _) = recv_packet(&mem, &calls, 1);
+            assert_eq!(hdr.op, VSOCK_OP_RESPONSE);
+            assert_eq!((hdr.src_port, hdr.dst_port), (1234, 40000));
+            let (mut host, _) = host_listener.accept().unwrap();
+
+            // Exchange data in both directions.
+            send_packet(&mem, &calls, 2, VSOCK_OP_RW, (40000, 1234), b"hello");
+            let mut buf = [0u8; 5];
+            host.read_exact(&mut buf).unwrap();
+            assert_eq!(&buf, b"hello");
+            host.write_all(b"world").unwrap();
+            let (hdr, data) = wait_rx_packet(&mem, &calls, 2);
+            assert_eq!(hdr.op, VSOCK_OP_RW);
+            assert_eq!(hdr.fwd_cnt, 5);
+            assert_eq!(&data, b"world");
+
+            // Connect a host application to the port 5000 of the guest.
+            let mut app = UnixStream::connect(uds_path).unwrap();
+            app.write_all(b"CONNECT 5000\n").unwrap();
+            let (hdr, _) = wait_rx_packet(&mem, &calls, 3);
+            assert_eq!(hdr.op, VSOCK_OP_REQUEST);
+            assert_eq!((hdr.src_port, hdr.dst_port), (FIRST_HOST_PORT, 5000));
+            send_packet(
+                &mem,
+                &calls,
+                3,
+                VSOCK_OP_RESPONSE,
+                (5000, FIRST_HOST_PORT),
+                &[],
+            );
+            let mut buf = [0u8; 14];
+            app.read_exact(&mut buf).unwrap();
+            assert_eq!(&buf, b"OK 1073741824\n");
+
+            // Data sent to unknown connections resets them.
+            send_packet(&mem, &calls, 4, VSOCK_OP_RW, (40001, 1234), b"lost");
+            let (hdr, _) = wait_rx_packet(&mem, &calls, 4);
+            assert_eq!(hdr.op, VSOCK_OP_RST);
+            assert_eq!((hdr.src_port, hdr.dst_port), (1234, 40001));
+
+            // Closing the socket of the host shuts the connection down.
+            drop(host);
+            let (hdr, _) = wait_rx_packet(&mem, &calls, 5);
+            assert_eq!(hdr.op, VSOCK_OP_SHUTDOWN);
+            assert_eq!(hdr.flags, VSOCK_SHUTDOWN_RCV | VSOCK_SHUTDOWN_SEND);
+            assert_eq!((hdr.src_port, hdr.dst_port), (1234, 40000));
+            send_packet(&mem, &calls, 5, VSOCK_OP_RST, (40000, 1234), &[]);
+
+            // Malformed chains are dropped, but still given back to the guest.
+            add_avail(&mem, 1, TX_BUF, 8, false, 6);
+            calls[1].1.write(1).unwrap();
+            assert_eq!(calls[1].0.read().unwrap(), 1);
+            let used_idx: u16 = mem.read_obj(GuestAddress(RING_ADDRS[1].2 + 2)).unwrap();
+            assert_eq!(used_idx, 6);
+            add_avail(&mem, 0, RX_BUF, 8, true, 6);
+            calls[0].1.write(1).unwrap();
+            assert_eq!(calls[0].0.read().unwrap(), 1);
+            let used = RING_ADDRS[0].2;
+            let used_idx: u16 = mem.read_obj(GuestAddress(used + 2)).unwrap();
+            assert_eq!(used_idx, 6);
+            let len: u32 = mem.read_obj(GuestAddress(used + 4 + 8 * 5 + 4)).unwrap();
+            assert_eq!(len, 0);
+        });
+
+        daemon.start(listener).unwrap();
+        master_thread.join().unwrap();
+        assert!(daemon.wait().is_err());
+        let _ = fs::remove_file(uds_path);
+        let _ = fs::remove_file(format!("{}_1234", uds_path));
+    }
+}